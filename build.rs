@@ -0,0 +1,51 @@
+use std::path::Path;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+///
+/// Embeds the git commit and the build time, read by `build_info`.
+///
+/// `GIT_COMMIT` overrides the commit for builds without the `.git` directory, and
+/// `SOURCE_DATE_EPOCH` the build time for reproducible builds.
+///
+fn main() {
+    let git_commit = match std::env::var("GIT_COMMIT") {
+        Ok(commit) if !commit.trim().is_empty() => commit.trim().to_string(),
+        _ => git(&["rev-parse", "HEAD"]).unwrap_or_else(|| "unknown".to_string()),
+    };
+
+    let build_timestamp = match std::env::var("SOURCE_DATE_EPOCH") {
+        Ok(value) => value.trim().parse::<u64>().ok(),
+        Err(_) => None,
+    }
+    .unwrap_or_else(|| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or_default()
+    });
+
+    println!("cargo:rustc-env=BUILD_GIT_COMMIT={}", git_commit);
+    println!("cargo:rustc-env=BUILD_TIMESTAMP={}", build_timestamp);
+
+    // Runs again when a commit is checked out or made, not on every build.
+    println!("cargo:rerun-if-env-changed=GIT_COMMIT");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    println!("cargo:rerun-if-changed=build.rs");
+    if Path::new(".git/HEAD").exists() {
+        println!("cargo:rerun-if-changed=.git/HEAD");
+        if let Some(head_ref) = git(&["symbolic-ref", "-q", "HEAD"]) {
+            println!("cargo:rerun-if-changed=.git/{}", head_ref);
+        }
+        println!("cargo:rerun-if-changed=.git/packed-refs");
+    }
+}
+
+fn git(args: &[&str]) -> Option<String> {
+    let output = Command::new("git").args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let value = String::from_utf8(output.stdout).ok()?;
+    Some(value.trim().to_string()).filter(|value| !value.is_empty())
+}
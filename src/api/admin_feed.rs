@@ -0,0 +1,150 @@
+use chrono::Utc;
+use futures_util::future::select_all;
+use racoon::core::request::Request;
+use racoon::core::response::Response;
+use racoon::core::websocket::WebSocket;
+use serde_json::{json, Value};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::watch;
+
+use crate::api::lifecycle::LifecycleEvent;
+use crate::api::views::QUEUE_WINDOW_MINUTES;
+use crate::api::ws_clients::{self, Payload, WsConnection};
+use crate::api::{bp_routing, canary, shortcuts};
+use crate::db::models::BackgroundRemoverTask;
+use crate::utils::envelope_utils::ApiEnvelope;
+use crate::SharedContext;
+
+///
+/// Live feed for the admin dashboard. Streams every task lifecycle event as `task_event`, BP
+/// health as `bp_health` whenever a BP connection is established or lost, and a `snapshot` of
+/// queue depth, BP health and websocket counts every `ADMIN_FEED_INTERVAL_SECS`, starting right
+/// after connecting. Requires `Authorization: Token <ADMIN_AUTH_TOKEN>` on the upgrade request.
+///
+pub async fn admin_feed_ws(request: Request) -> Response {
+    if !shortcuts::is_admin(&request) {
+        return shortcuts::unauthorized();
+    }
+
+    let (websocket, connected) = WebSocket::from(&request).await;
+    if !connected {
+        return websocket.bad_request().await;
+    }
+
+    let shared_context: &SharedContext = request.context().expect("SharedContext is missing.");
+    let connection = WsConnection::new(websocket.clone(), shortcuts::client_ip(&request).await);
+    let publisher = tokio::spawn(publish(shared_context.clone(), connection.clone()));
+
+    // Messages from the dashboard are only keep-alives.
+    while websocket.message().await.is_some() {
+        connection.touch();
+    }
+
+    connection.close();
+    publisher.abort();
+    websocket.exit()
+}
+
+///
+/// Queues feed messages for connection until it is closed or falls behind.
+///
+async fn publish(shared_context: SharedContext, connection: WsConnection) {
+    let mut events = shared_context.lifecycle.subscribe();
+    let mut connection_states = bp_connection_states(&shared_context);
+    let mut snapshots = tokio::time::interval(shared_context.config.load().admin_feed_interval);
+
+    loop {
+        let queued = tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) => connection.send_payload(task_event(&event)),
+                Err(RecvError::Lagged(skipped)) => connection.send_payload(message(
+                    "events_skipped",
+                    json!({ "count": skipped }),
+                )),
+                Err(RecvError::Closed) => break,
+            },
+            _ = bp_connection_changed(&mut connection_states) => {
+                connection.send_payload(message("bp_health", bp_health(&shared_context)))
+            }
+            _ = snapshots.tick() => connection.send_payload(snapshot(&shared_context).await),
+        };
+
+        if !queued {
+            break;
+        }
+    }
+}
+
+fn task_event(event: &LifecycleEvent) -> Payload {
+    message("task_event", json!(event))
+}
+
+fn message(status_code: &str, data: Value) -> Payload {
+    ws_clients::payload(&ApiEnvelope::success(status_code).data(data).to_value())
+}
+
+///
+/// Connection state receivers of the public, standby, canary and dedicated BP connections.
+///
+fn bp_connection_states(shared_context: &SharedContext) -> Vec<watch::Receiver<bool>> {
+    let mut clients: Vec<_> = shared_context
+        .public_bp_servers
+        .clients()
+        .cloned()
+        .collect();
+    if let Some(canary) = &shared_context.canary {
+        clients.push(canary.client.clone());
+    }
+    clients.extend(shared_context.dedicated_bp_servers.clients().cloned());
+
+    clients
+        .iter()
+        .map(|client| client.subscribe_connection_state())
+        .collect()
+}
+
+///
+/// Completes when any BP connection is established or lost.
+///
+async fn bp_connection_changed(receivers: &mut [watch::Receiver<bool>]) {
+    let changes = receivers
+        .iter_mut()
+        .map(|receiver| Box::pin(receiver.changed()));
+
+    if select_all(changes).await.0.is_err() {
+        // Clients live as long as the process, so this only happens while shutting down.
+        std::future::pending::<()>().await;
+    }
+}
+
+fn bp_health(shared_context: &SharedContext) -> Value {
+    let config = shared_context.config.load();
+    json!({
+        "public": shared_context.public_bp_servers.describe(&config),
+        "canary": canary::describe(shared_context),
+        "dedicated_bp_servers": bp_routing::describe(shared_context),
+    })
+}
+
+async fn snapshot(shared_context: &SharedContext) -> Payload {
+    let since = Utc::now() - chrono::Duration::minutes(QUEUE_WINDOW_MINUTES);
+    let queue_depth =
+        match BackgroundRemoverTask::count_processing(shared_context.db_wrapper.clone(), &since)
+            .await
+        {
+            Ok(queue_depth) => Some(queue_depth),
+            Err(error) => {
+                log::error!("Failed to count processing tasks. Error: {}", error);
+                None
+            }
+        };
+
+    message(
+        "snapshot",
+        json!({
+            "queue_depth": queue_depth,
+            "bp": bp_health(shared_context),
+            "websocket": shared_context.ws_clients.stats().await,
+        }),
+    )
+}
@@ -0,0 +1,803 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::path::PathBuf;
+
+use racoon::core::headers::HeaderValue;
+use racoon::core::request::Request;
+use racoon::core::response::status::ResponseStatus;
+use racoon::core::response::{HttpResponse, JsonResponse, Response};
+
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use crate::api::error_codes::ErrorCode;
+use crate::api::{bp_routing, canary, scaling, shortcuts};
+use crate::db::models::{
+    BackgroundRemoverTask, BpMessage, DailyTaskStats, ManifestFile, RevisionComparison,
+    ScheduledJobRun, StageTimingStats, TaskExportRow, TaskRevision,
+};
+use crate::jobs::media_gc;
+use crate::metrics;
+use crate::utils::envelope_utils::ApiEnvelope;
+use crate::utils::query_utils::QueryError;
+use crate::utils::throttle_utils::DeleteThrottle;
+use crate::utils::{export_utils, image_utils, path_utils};
+use crate::SharedContext;
+
+/// Number of rows fetched from the database per export batch.
+const EXPORT_BATCH_SIZE: i64 = 1000;
+
+///
+/// Triggers orphaned media garbage collection. Pass `?dry_run=true` to only report what would be
+/// removed.
+///
+pub async fn media_gc_view(request: Request) -> Response {
+    if !shortcuts::is_admin(&request) {
+        return shortcuts::unauthorized();
+    }
+
+    if request.method != "POST" {
+        return HttpResponse::ok().body("This request method is not supported.");
+    }
+
+    let shared_context: &SharedContext = request.context().expect("SharedContext is missing.");
+    let dry_run = match request.query_params.value("dry_run") {
+        Some(value) => value == "true",
+        None => false,
+    };
+
+    // Runs requested by an admin are not throttled, so they finish within the request.
+    let retention_days = shared_context.config.load().media_retention_days;
+    match media_gc::collect_orphaned_media(
+        shared_context.db_wrapper.clone(),
+        retention_days,
+        dry_run,
+        DeleteThrottle::default(),
+    )
+    .await
+    {
+        Ok(report) => {
+            JsonResponse::ok().body(ApiEnvelope::success("media_gc").data(report).to_value())
+        }
+        Err(error) => {
+            log::error!("Media GC failed. Error: {}", error);
+
+            JsonResponse::internal_server_error()
+                .body(ApiEnvelope::failed(ErrorCode::InternalServerError).to_value())
+        }
+    }
+}
+
+///
+/// Query parameters of `export_tasks_view`.
+///
+#[derive(Deserialize)]
+struct ExportQuery {
+    from: String,
+    to: String,
+    format: Option<ExportFormat>,
+}
+
+#[derive(PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ExportFormat {
+    Csv,
+    Ndjson,
+}
+
+///
+/// Parses `from` or `to` with `export_utils::parse_datetime_param`, answering `bad_query` when
+/// invalid.
+///
+fn datetime_param(name: &str, value: &str, end_of_day: bool) -> Result<DateTime<Utc>, Response> {
+    export_utils::parse_datetime_param(value, end_of_day).ok_or_else(|| {
+        shortcuts::bad_query(QueryError::field(
+            name,
+            "Must be a YYYY-MM-DD date or RFC 3339 datetime.",
+        ))
+    })
+}
+
+///
+/// Exports tasks created within `?from=&to=` as CSV or NDJSON (`?format=csv|ndjson`) for
+/// analytics. Heavy columns such as paths and logs are left out.
+///
+/// Rows are fetched in batches with a keyset cursor on `task_id` so large windows don't hold long
+/// running queries or use `OFFSET` scans.
+///
+pub async fn export_tasks_view(request: Request) -> Response {
+    if !shortcuts::is_admin(&request) {
+        return shortcuts::unauthorized();
+    }
+
+    let shared_context: &SharedContext = request.context().expect("SharedContext is missing.");
+
+    let query: ExportQuery = match shortcuts::query(&request) {
+        Ok(query) => query,
+        Err(response) => return response,
+    };
+
+    let from = match datetime_param("from", &query.from, false) {
+        Ok(from) => from,
+        Err(response) => return response,
+    };
+    let to = match datetime_param("to", &query.to, true) {
+        Ok(to) if from <= to => to,
+        Ok(_) => {
+            return shortcuts::bad_query(QueryError::field("to", "Must not be before from."));
+        }
+        Err(response) => return response,
+    };
+
+    let is_csv = query.format != Some(ExportFormat::Ndjson);
+
+    let mut body = String::new();
+    if is_csv {
+        body.push_str(export_utils::CSV_HEADER);
+        body.push('\n');
+    }
+
+    let mut last_task_id = 0;
+    loop {
+        let rows = match TaskExportRow::fetch_window_batch(
+            shared_context.db_wrapper.clone(),
+            &from,
+            &to,
+            last_task_id,
+            EXPORT_BATCH_SIZE,
+        )
+        .await
+        {
+            Ok(rows) => rows,
+            Err(error) => {
+                log::error!("Failed to fetch export rows. Error: {}", error);
+
+                return JsonResponse::internal_server_error()
+                    .body(ApiEnvelope::failed(ErrorCode::InternalServerError).to_value());
+            }
+        };
+
+        let last = match rows.last() {
+            Some(row) => row.task_id,
+            None => break,
+        };
+
+        for row in &rows {
+            if is_csv {
+                body.push_str(&export_utils::to_csv_line(row));
+            } else {
+                match export_utils::to_ndjson_line(row) {
+                    Ok(line) => body.push_str(&line),
+                    Err(error) => log::error!("Failed to serialize. Error: {}", error),
+                }
+            }
+        }
+
+        last_task_id = last;
+    }
+
+    let mut response = HttpResponse::ok().body(body);
+    let headers = response.get_headers();
+    if is_csv {
+        headers.set("Content-Type", "text/csv; charset=utf-8");
+    } else {
+        headers.set("Content-Type", "application/x-ndjson");
+    }
+    response
+}
+
+///
+/// Query parameters of `stats_view`.
+///
+#[derive(Deserialize)]
+struct StatsQuery {
+    from: Option<String>,
+    to: Option<String>,
+}
+
+///
+/// Returns task counts between `?from=&to=` (defaults to the last 30 days) grouped by day,
+/// country and status. Served from the `daily_task_stats` rollup table.
+///
+/// Also returns durations of each `TaskStage` of the tasks created meanwhile, aggregated from
+/// their `timings` column.
+///
+pub async fn stats_view(request: Request) -> Response {
+    if !shortcuts::is_admin(&request) {
+        return shortcuts::unauthorized();
+    }
+
+    let shared_context: &SharedContext = request.context().expect("SharedContext is missing.");
+
+    let query: StatsQuery = match shortcuts::query(&request) {
+        Ok(query) => query,
+        Err(response) => return response,
+    };
+
+    let to = match &query.to {
+        Some(value) => match datetime_param("to", value, true) {
+            Ok(to) => to.date_naive(),
+            Err(response) => return response,
+        },
+        None => Utc::now().date_naive(),
+    };
+    let from = match &query.from {
+        Some(value) => match datetime_param("from", value, false) {
+            Ok(from) => from.date_naive(),
+            Err(response) => return response,
+        },
+        None => to - chrono::Duration::days(29),
+    };
+    if from > to {
+        return shortcuts::bad_query(QueryError::field("to", "Must not be before from."));
+    }
+
+    let rows = match DailyTaskStats::fetch_range(shared_context.db_wrapper.clone(), from, to).await
+    {
+        Ok(rows) => rows,
+        Err(error) => {
+            log::error!("Failed to fetch task stats. Error: {}", error);
+
+            return JsonResponse::internal_server_error()
+                .body(ApiEnvelope::failed(ErrorCode::InternalServerError).to_value());
+        }
+    };
+
+    let stage_rows =
+        match StageTimingStats::fetch_range(shared_context.db_wrapper.clone(), from, to).await {
+            Ok(rows) => rows,
+            Err(error) => {
+                log::error!("Failed to fetch task stage timings. Error: {}", error);
+
+                return JsonResponse::internal_server_error()
+                    .body(ApiEnvelope::failed(ErrorCode::InternalServerError).to_value());
+            }
+        };
+
+    let mut total = 0;
+    let mut by_day: BTreeMap<String, i64> = BTreeMap::new();
+    let mut by_country: BTreeMap<String, i64> = BTreeMap::new();
+    let mut by_status: BTreeMap<String, i64> = BTreeMap::new();
+
+    for row in &rows {
+        total += row.total;
+        *by_day.entry(row.day.to_string()).or_default() += row.total;
+        *by_country.entry(row.country.clone()).or_default() += row.total;
+        *by_status.entry(row.status.clone()).or_default() += row.total;
+    }
+
+    let timings: BTreeMap<&str, Value> = stage_rows
+        .iter()
+        .map(|row| {
+            (
+                row.stage.as_str(),
+                json!({
+                    "count": row.count,
+                    "avg_ms": row.avg_ms.round(),
+                    "p50_ms": row.p50_ms.round(),
+                    "p95_ms": row.p95_ms.round(),
+                }),
+            )
+        })
+        .collect();
+
+    JsonResponse::ok().body(
+        ApiEnvelope::success("stats")
+            .data(json!({
+                "from": from.to_string(),
+                "to": to.to_string(),
+                "total": total,
+                "by_day": by_day,
+                "by_country": by_country,
+                "by_status": by_status,
+                "timings": timings,
+            }))
+            .to_value(),
+    )
+}
+
+///
+/// Returns runtime state useful for debugging and capacity planning.
+///
+pub async fn debug_view(request: Request) -> Response {
+    if !shortcuts::is_admin(&request) {
+        return shortcuts::unauthorized();
+    }
+
+    let shared_context: &SharedContext = request.context().expect("SharedContext is missing.");
+    let ws_stats = shared_context.ws_clients.stats().await;
+    let db_wrapper = &shared_context.db_wrapper;
+
+    JsonResponse::ok().body(
+        ApiEnvelope::success("debug")
+            .data(json!({
+                "websocket": ws_stats,
+                "db_pool": {
+                    "size": db_wrapper.pool.size(),
+                    "idle": db_wrapper.pool.num_idle(),
+                    "closed": db_wrapper.pool.is_closed(),
+                },
+                "public_bp_servers": shared_context
+                    .public_bp_servers
+                    .describe(&shared_context.config.load()),
+                "canary": canary::describe(shared_context),
+                "dedicated_bp_servers": bp_routing::describe(shared_context),
+            }))
+            .to_value(),
+    )
+}
+
+///
+/// Returns scheduled jobs with their schedule and runs on this replica, and the last run of each
+/// cluster job across all replicas.
+///
+pub async fn jobs_view(request: Request) -> Response {
+    if !shortcuts::is_admin(&request) {
+        return shortcuts::unauthorized();
+    }
+
+    let shared_context: &SharedContext = request.context().expect("SharedContext is missing.");
+    let cluster_runs = match ScheduledJobRun::fetch_all(shared_context.db_wrapper.clone()).await {
+        Ok(runs) => runs,
+        Err(error) => {
+            log::error!("Failed to fetch scheduled job runs. Error: {}", error);
+
+            return JsonResponse::internal_server_error()
+                .body(ApiEnvelope::failed(ErrorCode::InternalServerError).to_value());
+        }
+    };
+
+    JsonResponse::ok().body(
+        ApiEnvelope::success("jobs")
+            .data(json!({
+                "jobs": shared_context.scheduler.statuses(),
+                "cluster_runs": cluster_runs,
+            }))
+            .to_value(),
+    )
+}
+
+///
+/// Exposes metrics in Prometheus text format.
+///
+pub async fn metrics_view(request: Request) -> Response {
+    if !shortcuts::is_admin(&request) {
+        return shortcuts::unauthorized();
+    }
+
+    let shared_context: &SharedContext = request.context().expect("SharedContext is missing.");
+    let ws_stats = shared_context.ws_clients.stats().await;
+
+    let mut body = String::new();
+    metrics::render_gauge(
+        &mut body,
+        "ws_connections_active",
+        "Websocket connections which sent a message within the stale window.",
+        ws_stats.active as f64,
+    );
+    metrics::render_gauge(
+        &mut body,
+        "ws_connections_stale",
+        "Websocket connections without any message within the stale window.",
+        ws_stats.stale as f64,
+    );
+    metrics::render_gauge(
+        &mut body,
+        "ws_task_groups",
+        "Task groups with at least one websocket connection.",
+        ws_stats.task_groups as f64,
+    );
+    metrics::render_gauge(
+        &mut body,
+        "image_memory_reserved_bytes",
+        "Estimated memory reserved by image jobs within the memory budget.",
+        shared_context.image_workers.reserved_memory() as f64,
+    );
+    match scaling::signal(shared_context).await {
+        Ok(signal) => {
+            metrics::render_gauge(
+                &mut body,
+                "bp_queue_depth",
+                "Tasks processing on any replica.",
+                signal.queue_depth as f64,
+            );
+            metrics::render_gauge(
+                &mut body,
+                "bp_in_flight_tasks",
+                "Tasks this instance sent to BP servers and is waiting on.",
+                signal.in_flight as f64,
+            );
+            metrics::render_gauge(
+                &mut body,
+                "bp_average_wait_seconds",
+                "Rolling average from sending a task to its result.",
+                signal.average_wait_secs,
+            );
+            metrics::render_gauge(
+                &mut body,
+                "bp_servers_connected",
+                "Public BP servers this instance is connected to.",
+                signal.bp_servers_connected as f64,
+            );
+        }
+        Err(error) => log::error!("Failed to count processing tasks. Error: {}", error),
+    }
+    shared_context.metrics.render_counters(&mut body);
+    shared_context.metrics.render_histograms(&mut body);
+
+    let mut response = HttpResponse::ok().body(body);
+    response
+        .get_headers()
+        .set("Content-Type", "text/plain; version=0.0.4");
+    response
+}
+
+///
+/// Query parameters of `compare_revisions_view`.
+///
+#[derive(Deserialize)]
+struct CompareQuery {
+    task_id: Uuid,
+    a: i32,
+    b: i32,
+}
+
+///
+/// Compares outputs of two revisions of a task for QA: `?task_id=&a=&b=`, where revision `0` is
+/// the task's own outputs. Computes IoU of the masks and a pixel diff heatmap of the processed
+/// images and stores the scores in `revision_comparison`.
+///
+pub async fn compare_revisions_view(request: Request) -> Response {
+    if !shortcuts::is_admin(&request) {
+        return shortcuts::unauthorized();
+    }
+
+    if request.method != "POST" {
+        return HttpResponse::ok().body("This request method is not supported.");
+    }
+
+    let shared_context: &SharedContext = request.context().expect("SharedContext is missing.");
+
+    let query: CompareQuery = match shortcuts::query(&request) {
+        Ok(query) => query,
+        Err(response) => return response,
+    };
+    let (task_key, revision_a, revision_b) = (query.task_id, query.a, query.b);
+
+    let media_root = match env::var("MEDIA_ROOT") {
+        Ok(path) => PathBuf::from(path),
+        Err(_) => {
+            log::error!("MEDIA_ROOT environment variable is missing.");
+            return JsonResponse::internal_server_error()
+                .body(ApiEnvelope::failed(ErrorCode::InternalServerError).to_value());
+        }
+    };
+
+    let mut outputs = vec![];
+    for revision in [revision_a, revision_b] {
+        match revision_outputs(shared_context, &task_key, revision).await {
+            Some((mask, processed)) => outputs.push((
+                path_utils::file_path_from_relative_url(media_root.clone(), PathBuf::from(mask)),
+                path_utils::file_path_from_relative_url(
+                    media_root.clone(),
+                    PathBuf::from(processed),
+                ),
+            )),
+            None => {
+                return JsonResponse::not_found().body(
+                    ApiEnvelope::failed(ErrorCode::NotFound)
+                        .message(format!("Revision {} has no outputs.", revision))
+                        .to_value(),
+                );
+            }
+        }
+    }
+
+    let tenant =
+        match BackgroundRemoverTask::fetch(shared_context.db_wrapper.clone(), &task_key).await {
+            Ok(task) => task.tenant,
+            Err(error) => {
+                log::error!("Failed to fetch task. Error: {}", error);
+                return JsonResponse::internal_server_error()
+                    .body(ApiEnvelope::failed(ErrorCode::InternalServerError).to_value());
+            }
+        };
+
+    let heatmap_filename = format!("{}-{}.png", revision_a, revision_b);
+    let heatmap_path = match path_utils::generate_save_path(
+        tenant.as_deref(),
+        path_utils::ForImage::ComparisonImage(&task_key, &heatmap_filename),
+    )
+    .await
+    {
+        Ok(path) => path,
+        Err(error) => {
+            log::error!("Failed to generate heatmap path. Error: {}", error);
+            return JsonResponse::internal_server_error()
+                .body(ApiEnvelope::failed(ErrorCode::InternalServerError).to_value());
+        }
+    };
+
+    // Decoding full size images is CPU heavy, so it runs on the image workers.
+    let (output_a, output_b) = (outputs[0].clone(), outputs[1].clone());
+    let heatmap_save_path = heatmap_path.clone();
+    let memory = [&output_a.0, &output_b.0, &output_a.1, &output_b.1]
+        .into_iter()
+        .map(image_utils::estimate_file_memory)
+        .sum();
+    let result = shared_context
+        .image_workers
+        .run(memory, move || {
+            image_utils::compare_outputs(
+                &output_a.0,
+                &output_b.0,
+                &output_a.1,
+                &output_b.1,
+                &heatmap_save_path,
+            )
+        })
+        .await;
+
+    let scores = match result {
+        Ok(Ok(scores)) => scores,
+        Ok(Err(error)) => {
+            log::error!("Failed to compare revisions. Error: {}", error);
+            return JsonResponse::internal_server_error().body(
+                ApiEnvelope::failed(ErrorCode::ComparisonFailed)
+                    .message(error.to_string())
+                    .to_value(),
+            );
+        }
+        Err(error) if error.kind() == std::io::ErrorKind::TimedOut => {
+            log::error!("Comparison timed out. Error: {}", error);
+            return JsonResponse::internal_server_error().body(
+                ApiEnvelope::failed(ErrorCode::ComparisonTimeout)
+                    .message(error.to_string())
+                    .to_value(),
+            );
+        }
+        Err(error) => {
+            log::error!("Comparison task failed. Error: {}", error);
+            return JsonResponse::internal_server_error()
+                .body(ApiEnvelope::failed(ErrorCode::InternalServerError).to_value());
+        }
+    };
+
+    let comparison = RevisionComparison {
+        task_key,
+        revision_a,
+        revision_b,
+        mask_iou: scores.mask_iou,
+        mean_pixel_diff: scores.mean_pixel_diff,
+        heatmap_path: path_utils::relative_media_url_from_full_path(&media_root, &heatmap_path)
+            .to_string_lossy()
+            .to_string(),
+    };
+
+    if let Err(error) =
+        RevisionComparison::insert(shared_context.db_wrapper.clone(), &comparison).await
+    {
+        log::error!("Failed to store comparison scores. Error: {}", error);
+    }
+
+    JsonResponse::ok().body(
+        ApiEnvelope::success("revision_comparison")
+            .data(json!({
+                "task_key": task_key,
+                "a": revision_a,
+                "b": revision_b,
+                "mask_iou": scores.mask_iou,
+                "mean_pixel_diff": scores.mean_pixel_diff,
+                "heatmap": comparison.heatmap_path,
+            }))
+            .to_value(),
+    )
+}
+
+///
+/// Returns relative `(mask_image_path, processed_image_path)` of a revision. Revision `0` is the
+/// task's own outputs.
+///
+async fn revision_outputs(
+    shared_context: &SharedContext,
+    task_key: &Uuid,
+    revision: i32,
+) -> Option<(String, String)> {
+    let db_wrapper = shared_context.db_wrapper.clone();
+
+    if revision == 0 {
+        let task = BackgroundRemoverTask::fetch(db_wrapper, task_key)
+            .await
+            .ok()?;
+        return Some((task.mask_image_path?, task.processed_image_path?));
+    }
+
+    let revision = TaskRevision::fetch_by_number(db_wrapper, task_key, revision)
+        .await
+        .ok()?;
+    Some((revision.mask_image_path?, revision.processed_image_path?))
+}
+
+///
+/// Everything known about a single task, for answering why it is stuck: the full serialization,
+/// whether each stored file still exists on disk, dispatch attempts summarized from the event
+/// log, the event log itself, archived BP messages and the BP worker identity.
+///
+pub async fn task_inspection_view(request: Request) -> Response {
+    if !shortcuts::is_admin(&request) {
+        return shortcuts::unauthorized();
+    }
+
+    let shared_context: &SharedContext = request.context().expect("SharedContext is missing.");
+    let task_key = match Uuid::parse_str(request.path_params.value("task_id").unwrap()) {
+        Ok(task_key) => task_key,
+        Err(_) => {
+            return JsonResponse::bad_request().body(
+                ApiEnvelope::failed(ErrorCode::BadQuery)
+                    .message("Not a valid task id format.")
+                    .to_value(),
+            );
+        }
+    };
+
+    let db_wrapper = shared_context.db_wrapper.clone();
+    let instance = match BackgroundRemoverTask::fetch(db_wrapper.clone(), &task_key).await {
+        Ok(instance) => instance,
+        Err(sqlx::Error::RowNotFound) => {
+            return JsonResponse::not_found().body(
+                ApiEnvelope::failed(ErrorCode::NotFound)
+                    .message("Invalid task id.")
+                    .to_value(),
+            );
+        }
+        Err(error) => {
+            log::error!("Failed to fetch task. Error: {}", error);
+            return JsonResponse::internal_server_error()
+                .body(ApiEnvelope::failed(ErrorCode::InternalServerError).to_value());
+        }
+    };
+
+    let revisions = match TaskRevision::fetch_by_task(db_wrapper.clone(), &task_key).await {
+        Ok(revisions) => revisions,
+        Err(error) => {
+            log::error!("Failed to fetch task revisions. Error: {}", error);
+            return JsonResponse::internal_server_error()
+                .body(ApiEnvelope::failed(ErrorCode::InternalServerError).to_value());
+        }
+    };
+
+    // Refinement messages carry the revision key.
+    let mut message_keys = vec![task_key];
+    message_keys.extend(revisions.iter().map(|revision| revision.key));
+    let bp_messages = match BpMessage::fetch_by_task_keys(db_wrapper, &message_keys).await {
+        Ok(bp_messages) => bp_messages,
+        Err(error) => {
+            log::error!("Failed to fetch archived BP messages. Error: {}", error);
+            return JsonResponse::internal_server_error()
+                .body(ApiEnvelope::failed(ErrorCode::InternalServerError).to_value());
+        }
+    };
+
+    let serialized = match instance.serialize_full() {
+        Ok(serialized) => serialized,
+        Err(error) => {
+            log::error!("Failed to serialize task. Error: {}", error);
+            return JsonResponse::internal_server_error()
+                .body(ApiEnvelope::failed(ErrorCode::InternalServerError).to_value());
+        }
+    };
+
+    let media_root = env::var("MEDIA_ROOT").ok().map(PathBuf::from);
+    let files = json!({
+        "original_image": file_status(&media_root, Some(&instance.original_image_path)).await,
+        "preview_original_image":
+            file_status(&media_root, instance.preview_original_image_path.as_ref()).await,
+        "processed_image":
+            file_status(&media_root, instance.output_path(ManifestFile::PROCESSED)).await,
+        "preview_processed_image":
+            file_status(&media_root, instance.output_path(ManifestFile::PREVIEW_PROCESSED)).await,
+        "mask_image": file_status(&media_root, instance.output_path(ManifestFile::MASK)).await,
+    });
+
+    let mut revision_files = vec![];
+    for revision in &revisions {
+        revision_files.push(json!({
+            "revision": revision.revision,
+            "kind": revision.kind,
+            "processing": revision.processing,
+            "correction_image":
+                file_status(&media_root, revision.correction_image_path.as_ref()).await,
+            "processed_image":
+                file_status(&media_root, revision.processed_image_path.as_ref()).await,
+            "preview_processed_image":
+                file_status(&media_root, revision.preview_processed_image_path.as_ref()).await,
+            "mask_image": file_status(&media_root, revision.mask_image_path.as_ref()).await,
+        }));
+    }
+
+    // The public connection tasks are currently sent to, a standby after failover.
+    let public_bp_server = shared_context
+        .public_bp_servers
+        .pick(&shared_context.config.load());
+
+    JsonResponse::ok().body(
+        ApiEnvelope::success("task_inspection")
+            .data(json!({
+                "task": serialized,
+                "files": files,
+                "manifest": instance.manifest,
+                "revisions": revision_files,
+                "attempts": {
+                    "bp_request_id": instance.bp_request_id,
+                    "completed_request_id": instance.completed_request_id,
+                    "events": count_log_events(instance.logs.as_ref()),
+                },
+                "logs": instance.logs,
+                "bp_messages": bp_messages,
+                "bp_worker": {
+                    "worker_id": instance.bp_worker_id,
+                    "model_version": instance.bp_model_version,
+                    "connected": public_bp_server.is_connected(),
+                    "draining": public_bp_server.is_draining(),
+                    "current": public_bp_server.server_identity(),
+                },
+            }))
+            .to_value(),
+    )
+}
+
+///
+/// Relative path of a stored file and whether it exists under `MEDIA_ROOT`. `exists` is `null`
+/// when `MEDIA_ROOT` is missing.
+///
+async fn file_status(media_root: &Option<PathBuf>, path: Option<&String>) -> Value {
+    let path = match path {
+        Some(path) => path,
+        None => return Value::Null,
+    };
+
+    let exists = match media_root {
+        Some(media_root) => {
+            let file_path =
+                path_utils::file_path_from_relative_url(media_root.clone(), PathBuf::from(path));
+            Some(tokio::fs::try_exists(file_path).await.unwrap_or(false))
+        }
+        None => None,
+    };
+
+    json!({
+        "path": path,
+        "exists": exists,
+    })
+}
+
+///
+/// Number of task log entries per `event` and `status_code`, e.g. retries per operation.
+///
+fn count_log_events(logs: Option<&Value>) -> BTreeMap<String, BTreeMap<String, u64>> {
+    let mut counts: BTreeMap<String, BTreeMap<String, u64>> = BTreeMap::new();
+
+    let entries = match logs.and_then(|logs| logs.as_array()) {
+        Some(entries) => entries,
+        None => return counts,
+    };
+
+    for entry in entries {
+        let read = |name: &str| {
+            entry
+                .get(name)
+                .and_then(|value| value.as_str())
+                .unwrap_or("unknown")
+                .to_string()
+        };
+
+        *counts
+            .entry(read("event"))
+            .or_default()
+            .entry(read("status_code"))
+            .or_default() += 1;
+    }
+    counts
+}
@@ -0,0 +1,178 @@
+use std::env;
+
+use uuid::Uuid;
+
+use crate::db::models::{BackgroundRemoverTask, TaskGroupNotification};
+use crate::utils::{retry_utils, template_utils};
+use crate::SharedContext;
+
+/// Sends of a completion email before it is given up.
+const MAX_ATTEMPTS: i32 = 5;
+
+/// Failed completion emails retried per run of the outbox job.
+const RETRY_BATCH_SIZE: i64 = 20;
+
+const DEFAULT_SUBJECT: &str = "Your background removal batch is ready";
+
+const DEFAULT_BODY: &str = "Hello,
+
+All {total} images of your batch have been processed: {processed} succeeded and {failed} failed.
+
+Review the results at {link}
+";
+
+///
+/// Called whenever a task got its result. Sends the completion email of its task group when this
+/// was the last unfinished task and an email was registered on upload.
+///
+/// Templates are read from the files at `BATCH_EMAIL_SUBJECT_PATH` and `BATCH_EMAIL_BODY_PATH` on
+/// every email, so they can be edited without restart. Both support the placeholders
+/// `{task_group}`, `{total}`, `{processed}`, `{failed}` and `{link}`.
+///
+pub async fn task_finished(shared_context: SharedContext, key: Uuid) {
+    if shared_context.mailer.is_none() {
+        return;
+    }
+
+    let db_wrapper = shared_context.db_wrapper.clone();
+    match TaskGroupNotification::claim_if_finished(db_wrapper, &key).await {
+        Ok(Some(notification)) => send(&shared_context, &notification).await,
+        Ok(None) => {}
+        Err(error) => eprintln!("Failed to claim task group notification. Error: {}", error),
+    }
+}
+
+///
+/// Sends completion emails again whose sending failed, up to `MAX_ATTEMPTS` times per group.
+/// Called by the outbox job.
+///
+pub async fn retry_failed(shared_context: &SharedContext) {
+    if shared_context.mailer.is_none() {
+        return;
+    }
+
+    let db_wrapper = shared_context.db_wrapper.clone();
+    match TaskGroupNotification::claim_failed(db_wrapper, MAX_ATTEMPTS, RETRY_BATCH_SIZE).await {
+        Ok(notifications) => {
+            for notification in notifications {
+                send(shared_context, &notification).await;
+            }
+        }
+        Err(error) => eprintln!("Failed to claim failed notifications. Error: {}", error),
+    }
+}
+
+///
+/// Sends the claimed completion email of `notification`. The claim is released on failure, so
+/// `retry_failed` sends it again.
+///
+async fn send(shared_context: &SharedContext, notification: &TaskGroupNotification) {
+    let db_wrapper = shared_context.db_wrapper.clone();
+    if let Err(error) = try_send(shared_context, notification).await {
+        eprintln!(
+            "Failed to send completion email of task group {} (attempt {}). Error: {}",
+            notification.task_group,
+            notification.failed_attempts + 1,
+            error
+        );
+        shared_context
+            .metrics
+            .increment("batch_emails_failed_total");
+
+        if let Err(error) =
+            TaskGroupNotification::release_failed(db_wrapper, &notification.task_group).await
+        {
+            eprintln!(
+                "Failed to release task group notification. Error: {}",
+                error
+            );
+        }
+        return;
+    }
+
+    shared_context.metrics.increment("batch_emails_sent_total");
+}
+
+async fn try_send(
+    shared_context: &SharedContext,
+    notification: &TaskGroupNotification,
+) -> Result<(), String> {
+    let mailer = match &shared_context.mailer {
+        Some(mailer) => mailer.clone(),
+        None => return Err("Email notifications are not enabled.".to_string()),
+    };
+
+    let db_wrapper = shared_context.db_wrapper.clone();
+    let (total, processed, _, failed) =
+        BackgroundRemoverTask::count_by_task_group(db_wrapper, &notification.task_group)
+            .await
+            .map_err(|error| format!("Failed to count tasks of task group. Error: {}", error))?;
+
+    let task_group = notification.task_group.to_string();
+    let (total, processed, failed) = (total.to_string(), processed.to_string(), failed.to_string());
+    let link = group_link(&task_group);
+    let values = [
+        ("task_group", task_group.as_str()),
+        ("total", total.as_str()),
+        ("processed", processed.as_str()),
+        ("failed", failed.as_str()),
+        ("link", link.as_str()),
+    ];
+
+    let subject = template_utils::render(
+        &read_template("BATCH_EMAIL_SUBJECT_PATH", DEFAULT_SUBJECT).await,
+        &values,
+    );
+    let body = template_utils::render(
+        &read_template("BATCH_EMAIL_BODY_PATH", DEFAULT_BODY).await,
+        &values,
+    );
+
+    let policy = shared_context.config.load().webhook_retry;
+    let mut failed = vec![];
+    let result = retry_utils::retry(
+        &policy,
+        |_| true,
+        &mut failed,
+        |_| mailer.send(&notification.email, subject.trim(), &body),
+    )
+    .await;
+
+    for attempt in &failed {
+        eprintln!(
+            "Retrying completion email of task group {} after attempt {} in {:?}. Error: {}",
+            task_group, attempt.attempt, attempt.delay, attempt.error
+        );
+    }
+
+    result.map_err(|error| error.to_string())
+}
+
+///
+/// Link to the task group in the email. `BATCH_EMAIL_LINK` with a `{task_group}` placeholder, e.g.
+/// a page of the frontend, defaulting to the task group summary endpoint.
+///
+fn group_link(task_group: &str) -> String {
+    match env::var("BATCH_EMAIL_LINK") {
+        Ok(template) => template_utils::render(&template, &[("task_group", task_group)]),
+        Err(_) => {
+            let host = env::var("HOST").unwrap_or_default();
+            format!("https://{}/v1/task-groups/{}/", host, task_group)
+        }
+    }
+}
+
+async fn read_template(name: &str, default: &str) -> String {
+    let path = match env::var(name) {
+        Ok(path) => path,
+        Err(_) => return default.to_string(),
+    };
+
+    match tokio::fs::read_to_string(&path).await {
+        Ok(template) => template,
+        Err(error) => {
+            eprintln!("Failed to read {} at {}. Error: {}", name, path, error);
+            default.to_string()
+        }
+    }
+}
@@ -0,0 +1,338 @@
+use std::sync::Arc;
+
+use chrono::{DateTime, Datelike, Utc};
+use racoon::core::headers::HeaderValue;
+use racoon::core::request::Request;
+use racoon::core::response::{HttpResponse, JsonResponse, Response};
+use serde_json::{json, Value};
+
+use crate::api::error_codes::ErrorCode;
+use crate::api::{shortcuts, upload_signatures};
+use crate::db::models::{ApiKeyUsage, BillingPlan, Organization};
+use crate::db::DBWrapper;
+use crate::secrets;
+use crate::utils::billing_utils::{self, BillingAccount};
+use crate::utils::envelope_utils::ApiEnvelope;
+use crate::SharedContext;
+
+/// Webhooks signed further than this from now are rejected.
+const WEBHOOK_TOLERANCE_SECS: u64 = 300;
+
+/// Stripe events are a few kilobytes. Larger bodies are rejected unread.
+const MAX_WEBHOOK_BODY_SIZE: usize = 1024 * 1024;
+
+/// Subscription statuses whose key may upload. Other statuses, e.g. `unpaid`, block uploads.
+const UPLOADING_STATUSES: [&str; 3] = ["active", "trialing", "past_due"];
+
+///
+/// Receives Stripe subscription events. Created and updated subscriptions set the plan of the
+/// organization in their `organization_id` metadata, or else of the upload signing key id in
+/// their `key_id` metadata, with the quota and priority of their price lookup key in
+/// `BILLING_PLANS`. Deleted subscriptions remove the plan. Other events are acknowledged and
+/// ignored.
+///
+pub async fn stripe_webhook_view(request: Request) -> Response {
+    if request.method != "POST" {
+        return HttpResponse::ok().body("This request method is not supported.");
+    }
+
+    let shared_context: &SharedContext = request.context().expect("SharedContext is missing.");
+    let webhook_secret = match shared_context.secrets.get(secrets::STRIPE_WEBHOOK_SECRET) {
+        Some(secret) if !secret.is_empty() => secret,
+        _ => {
+            return JsonResponse::not_found().body(
+                ApiEnvelope::failed(ErrorCode::NotFound)
+                    .message("Billing is not enabled.")
+                    .to_value(),
+            )
+        }
+    };
+
+    let body = match shortcuts::read_body(&request, MAX_WEBHOOK_BODY_SIZE).await {
+        Some(body) => body,
+        None => {
+            return JsonResponse::bad_request().body(
+                ApiEnvelope::failed(ErrorCode::InvalidBody)
+                    .message("Body is missing or too large.")
+                    .to_value(),
+            )
+        }
+    };
+
+    let signature = request
+        .headers
+        .value("Stripe-Signature")
+        .map(|value| value.as_str().to_string())
+        .unwrap_or_default();
+    if let Err(error) = billing_utils::verify_stripe_signature(
+        &webhook_secret,
+        &signature,
+        &body,
+        Utc::now().timestamp(),
+        WEBHOOK_TOLERANCE_SECS,
+    ) {
+        shared_context
+            .metrics
+            .increment("stripe_webhook_rejections_total");
+        return JsonResponse::unauthorized().body(
+            ApiEnvelope::failed(ErrorCode::InvalidSignature)
+                .message(error)
+                .to_value(),
+        );
+    }
+
+    let event: Value = match serde_json::from_slice(&body) {
+        Ok(event) => event,
+        Err(error) => {
+            return JsonResponse::bad_request().body(
+                ApiEnvelope::failed(ErrorCode::InvalidBody)
+                    .message(error.to_string())
+                    .to_value(),
+            )
+        }
+    };
+
+    let event_type = event["type"].as_str().unwrap_or_default();
+    let subscription = &event["data"]["object"];
+    // Stripe doesn't deliver events in order, so events older than the stored plan are skipped.
+    let event_created = event["created"]
+        .as_i64()
+        .and_then(|created| DateTime::from_timestamp(created, 0));
+    let db_wrapper = shared_context.db_wrapper.clone();
+    let result = match event_type {
+        "customer.subscription.created" | "customer.subscription.updated" => {
+            let config = shared_context.config.load();
+            match billing_utils::subscription_plan(
+                subscription,
+                &config.billing_plans,
+                event_created,
+            ) {
+                Some(plan) => match BillingPlan::upsert(db_wrapper, &plan).await {
+                    Ok(true) => {
+                        log::info!(
+                            "Billing plan of {} is now {:?} ({}).",
+                            plan.key_id,
+                            plan.plan,
+                            plan.status
+                        );
+                        Ok(())
+                    }
+                    Ok(false) => {
+                        log::info!(
+                            "Ignoring {} of subscription {} older than its stored plan.",
+                            event_type,
+                            plan.stripe_subscription_id
+                        );
+                        Ok(())
+                    }
+                    Err(error) => Err(error),
+                },
+                None => {
+                    eprintln!(
+                        "Ignoring {} of subscription {} without billing account metadata.",
+                        event_type, subscription["id"]
+                    );
+                    Ok(())
+                }
+            }
+        }
+        "customer.subscription.deleted" => match subscription["id"].as_str() {
+            Some(subscription_id) => {
+                BillingPlan::delete_by_subscription(db_wrapper, subscription_id, event_created)
+                    .await
+                    .map(|_| ())
+            }
+            None => Ok(()),
+        },
+        _ => Ok(()),
+    };
+
+    // Stripe retries failed webhooks, so storage errors are reported as failures.
+    if let Err(error) = result {
+        eprintln!("Failed to store billing plan. Error: {}", error);
+        return JsonResponse::internal_server_error()
+            .body(ApiEnvelope::failed(ErrorCode::InternalServerError).to_value());
+    }
+
+    JsonResponse::ok().body(ApiEnvelope::success("webhook_received").to_value())
+}
+
+///
+/// Start of the current calendar month (UTC), when quotas reset.
+///
+pub fn period_start() -> DateTime<Utc> {
+    Utc::now()
+        .date_naive()
+        .with_day(1)
+        .and_then(|day| day.and_hms_opt(0, 0, 0))
+        .map(|start| start.and_utc())
+        .unwrap_or_else(Utc::now)
+}
+
+///
+/// Account billed for the tasks of upload signing key `key_id`: its organization, if any.
+///
+pub async fn billing_account(
+    db_wrapper: Arc<DBWrapper>,
+    key_id: &str,
+) -> Result<BillingAccount, sqlx::Error> {
+    let account = match Organization::id_of_api_key(db_wrapper, key_id).await? {
+        Some(organization_id) => BillingAccount::Organization(organization_id),
+        None => BillingAccount::Key(key_id.to_string()),
+    };
+    Ok(account)
+}
+
+///
+/// Why `check_quota` rejected an upload.
+///
+#[derive(Debug)]
+pub enum QuotaError {
+    /// The subscription has this status, which blocks uploads.
+    PaymentRequired(String),
+    /// The monthly quota, in tasks, is used up.
+    QuotaExceeded(i64),
+}
+
+impl QuotaError {
+    pub fn status_code(&self) -> &'static str {
+        match self {
+            QuotaError::PaymentRequired(_) => ErrorCode::PaymentRequired.as_str(),
+            QuotaError::QuotaExceeded(_) => ErrorCode::QuotaExceeded.as_str(),
+        }
+    }
+
+    pub fn response(&self) -> Response {
+        match self {
+            QuotaError::PaymentRequired(status) => {
+                JsonResponse::with_status(402, "Payment Required").body(
+                    ApiEnvelope::failed(self.status_code())
+                        .message(format!("The subscription is {}.", status))
+                        .to_value(),
+                )
+            }
+            QuotaError::QuotaExceeded(monthly_quota) => {
+                JsonResponse::with_status(429, "Too Many Requests").body(
+                    ApiEnvelope::failed(self.status_code())
+                        .message("The monthly quota of the plan is used up.")
+                        .field("monthly_quota", monthly_quota)
+                        .to_value(),
+                )
+            }
+        }
+    }
+}
+
+///
+/// Checks the plan of `account` before an upload. Returns the priority of its tasks, or why the
+/// upload is rejected when the subscription is not paid or the monthly quota is used up. Keys of
+/// an organization share its plan and quota. Accounts without a plan upload without limit.
+///
+pub async fn check_quota(
+    shared_context: &SharedContext,
+    account: &BillingAccount,
+) -> Result<i32, QuotaError> {
+    let db_wrapper = shared_context.db_wrapper.clone();
+
+    // Uploads don't fail because billing is unavailable.
+    let plan = match BillingPlan::fetch(db_wrapper.clone(), &account.plan_key()).await {
+        Ok(Some(plan)) => plan,
+        Ok(None) => return Ok(0),
+        Err(error) => {
+            eprintln!("Failed to fetch billing plan. Error: {}", error);
+            return Ok(0);
+        }
+    };
+
+    if !UPLOADING_STATUSES.contains(&plan.status.as_str()) {
+        return Err(QuotaError::PaymentRequired(plan.status));
+    }
+
+    let monthly_quota = match plan.monthly_quota {
+        Some(monthly_quota) => monthly_quota,
+        None => return Ok(plan.priority),
+    };
+    let used = match ApiKeyUsage::count_since(db_wrapper, account, period_start()).await {
+        Ok(used) => used,
+        Err(error) => {
+            eprintln!("Failed to count api key usage. Error: {}", error);
+            return Ok(plan.priority);
+        }
+    };
+
+    if used >= monthly_quota {
+        shared_context.metrics.increment("quota_rejections_total");
+        return Err(QuotaError::QuotaExceeded(monthly_quota));
+    }
+    Ok(plan.priority)
+}
+
+///
+/// Plan and usage of the current month of `account`, as returned by the usage endpoints.
+///
+pub async fn account_usage(
+    db_wrapper: Arc<DBWrapper>,
+    account: &BillingAccount,
+) -> Result<Value, sqlx::Error> {
+    let plan = BillingPlan::fetch(db_wrapper.clone(), &account.plan_key()).await?;
+    let period_start = period_start();
+    let used = ApiKeyUsage::count_since(db_wrapper, account, period_start).await?;
+
+    let monthly_quota = plan.as_ref().and_then(|plan| plan.monthly_quota);
+    Ok(json!({
+        "plan": plan.as_ref().and_then(|plan| plan.plan.clone()),
+        "subscription_status": plan.as_ref().map(|plan| plan.status.clone()),
+        "monthly_quota": monthly_quota,
+        "priority": plan.as_ref().map(|plan| plan.priority).unwrap_or(0),
+        "period_start": period_start.to_rfc3339(),
+        "used": used,
+        "remaining": monthly_quota.map(|quota| (quota - used).max(0)),
+    }))
+}
+
+///
+/// Returns the plan and usage of the current month of the key signing the request, or of its
+/// organization when it has one. Requests are signed like uploads, with the SHA-256 of the empty
+/// body.
+///
+pub async fn usage_view(request: Request) -> Response {
+    let shared_context: &SharedContext = request.context().expect("SharedContext is missing.");
+
+    let key_id = match upload_signatures::key_id(&request) {
+        Some(key_id) => key_id,
+        None => {
+            return JsonResponse::unauthorized().body(
+                ApiEnvelope::failed(ErrorCode::InvalidSignature)
+                    .message("Usage requests must be signed.")
+                    .to_value(),
+            )
+        }
+    };
+    if let Err(response) = upload_signatures::verify(&request, shared_context).await {
+        return response;
+    }
+
+    let db_wrapper = shared_context.db_wrapper.clone();
+    let usage = match billing_account(db_wrapper.clone(), &key_id).await {
+        Ok(account) => account_usage(db_wrapper, &account)
+            .await
+            .map(|usage| (account, usage)),
+        Err(error) => Err(error),
+    };
+
+    let (account, mut usage) = match usage {
+        Ok(usage) => usage,
+        Err(error) => {
+            eprintln!("Failed to fetch usage. Error: {}", error);
+            return JsonResponse::internal_server_error()
+                .body(ApiEnvelope::failed(ErrorCode::InternalServerError).to_value());
+        }
+    };
+
+    usage["key_id"] = Value::from(key_id);
+    usage["organization_id"] = match account {
+        BillingAccount::Organization(organization_id) => Value::from(organization_id),
+        BillingAccount::Key(_) => Value::Null,
+    };
+    JsonResponse::ok().body(ApiEnvelope::success("usage").data(usage).to_value())
+}
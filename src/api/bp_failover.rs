@@ -0,0 +1,248 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use crate::clients::bp_request_client::BPRequestClient;
+use crate::config::AppConfig;
+use crate::utils::health_utils::HealthScore;
+
+/// Tasks awaiting their response tracked per endpoint. Beyond this, tasks sent longer than
+/// `PENDING_MAX_AGE` ago are forgotten, e.g. when their response never arrived.
+const MAX_PENDING: usize = 10_000;
+
+const PENDING_MAX_AGE: Duration = Duration::from_secs(10 * 60);
+
+struct EndpointState {
+    score: HealthScore,
+    /// Send times of tasks awaiting their response, by task id.
+    pending: HashMap<String, Instant>,
+    /// When the endpoint was failed over from for its health.
+    tripped_at: Option<Instant>,
+}
+
+struct BpEndpoint {
+    host: String,
+    client: Arc<BPRequestClient>,
+    state: Mutex<EndpointState>,
+}
+
+///
+/// Public BP endpoints in failover order: `BP_SERVER_HOST`, then the warm standbys of
+/// `BP_SERVER_BACKUP_HOSTS`. Every endpoint is kept connected; tasks go to the first one which is
+/// connected, not draining and healthy. An endpoint is unhealthy when its recent sends and
+/// responses fail too often or its responses take too long, see `AppConfig::bp_failover_*`.
+/// Unhealthy endpoints get no tasks for `AppConfig::bp_failback_after`, then are tried again
+/// with a fresh score, so traffic fails back to earlier endpoints automatically.
+///
+pub struct PublicBpServers {
+    endpoints: Vec<BpEndpoint>,
+}
+
+impl PublicBpServers {
+    ///
+    /// `endpoints` are hosts and their connections in failover order, the primary first.
+    ///
+    pub fn new(endpoints: Vec<(String, Arc<BPRequestClient>)>) -> Self {
+        Self {
+            endpoints: endpoints
+                .into_iter()
+                .map(|(host, client)| BpEndpoint {
+                    host,
+                    client,
+                    state: Mutex::new(EndpointState {
+                        score: HealthScore::new(),
+                        pending: HashMap::new(),
+                        tripped_at: None,
+                    }),
+                })
+                .collect(),
+        }
+    }
+
+    ///
+    /// Connections of the warm standbys, in failover order.
+    ///
+    pub fn backups(&self) -> impl Iterator<Item = &Arc<BPRequestClient>> {
+        self.endpoints
+            .iter()
+            .skip(1)
+            .map(|endpoint| &endpoint.client)
+    }
+
+    pub fn clients(&self) -> impl Iterator<Item = &Arc<BPRequestClient>> {
+        self.endpoints.iter().map(|endpoint| &endpoint.client)
+    }
+
+    pub fn any_connected(&self) -> bool {
+        self.endpoints
+            .iter()
+            .any(|endpoint| endpoint.client.is_connected())
+    }
+
+    ///
+    /// Connection new tasks are sent to. When no endpoint is available, the first connected
+    /// one is used regardless of its health, and the primary when none is connected.
+    ///
+    pub fn pick(&self, config: &AppConfig) -> Arc<BPRequestClient> {
+        let connected = self
+            .endpoints
+            .iter()
+            .filter(|endpoint| endpoint.client.is_connected() && !endpoint.client.is_draining());
+
+        let mut fallback = None;
+        for endpoint in connected {
+            fallback.get_or_insert(endpoint);
+
+            let mut state = match endpoint.state.lock() {
+                Ok(state) => state,
+                Err(_) => return endpoint.client.clone(),
+            };
+            match state.tripped_at {
+                Some(tripped_at) if tripped_at.elapsed() < config.bp_failback_after => continue,
+                Some(_) => {
+                    println!("Trying BP server {} again after failover.", endpoint.host);
+                    state.tripped_at = None;
+                    state.score = HealthScore::new();
+                }
+                None => {}
+            }
+            return endpoint.client.clone();
+        }
+
+        match fallback {
+            Some(endpoint) => endpoint.client.clone(),
+            None => self.endpoints[0].client.clone(),
+        }
+    }
+
+    ///
+    /// Whether `client` is a standby rather than the primary.
+    ///
+    pub fn is_backup(&self, client: &BPRequestClient) -> bool {
+        self.backups()
+            .any(|backup| std::ptr::eq(backup.as_ref(), client))
+    }
+
+    ///
+    /// Records whether sending task `task_id` to `client` succeeded. Connections other than the
+    /// public ones are ignored.
+    ///
+    pub fn record_send(
+        &self,
+        config: &AppConfig,
+        client: &BPRequestClient,
+        task_id: &Uuid,
+        succeeded: bool,
+    ) {
+        self.update(config, client, |state| {
+            state.score.record(succeeded, None);
+            if !succeeded {
+                return;
+            }
+
+            if state.pending.len() >= MAX_PENDING {
+                state
+                    .pending
+                    .retain(|_, sent_at| sent_at.elapsed() < PENDING_MAX_AGE);
+            }
+            state.pending.insert(task_id.to_string(), Instant::now());
+        });
+    }
+
+    ///
+    /// Records the outcome of a task from a `message` received on `client`, with the time since
+    /// it was sent. Progress and other messages are ignored.
+    ///
+    pub fn record_response(&self, config: &AppConfig, client: &BPRequestClient, message: &Value) {
+        let succeeded = match message.get("status").and_then(|status| status.as_str()) {
+            Some("success") => true,
+            Some("failed") => false,
+            _ => return,
+        };
+        let task_id = match message.get("task_id").and_then(|task_id| task_id.as_str()) {
+            Some(task_id) => task_id,
+            None => return,
+        };
+
+        self.update(config, client, |state| {
+            let latency = state
+                .pending
+                .remove(task_id)
+                .map(|sent_at| sent_at.elapsed());
+            state.score.record(succeeded, latency);
+        });
+    }
+
+    fn update(
+        &self,
+        config: &AppConfig,
+        client: &BPRequestClient,
+        change: impl FnOnce(&mut EndpointState),
+    ) {
+        let endpoint = match self
+            .endpoints
+            .iter()
+            .find(|endpoint| std::ptr::eq(endpoint.client.as_ref(), client))
+        {
+            Some(endpoint) => endpoint,
+            None => return,
+        };
+        let mut state = match endpoint.state.lock() {
+            Ok(state) => state,
+            Err(_) => return,
+        };
+
+        change(&mut state);
+        if state.tripped_at.is_none() && !state.score.is_healthy(&config.bp_failover_thresholds()) {
+            let summary = state.score.summary();
+            eprintln!(
+                "Failing over from BP server {}. Error rate: {}%, latency: {:?} ms.",
+                endpoint.host, summary.error_percent, summary.latency_millis
+            );
+            state.tripped_at = Some(Instant::now());
+        }
+    }
+
+    ///
+    /// Endpoints and their health for the admin debug endpoint.
+    ///
+    pub fn describe(&self, config: &AppConfig) -> Value {
+        let endpoints: Vec<Value> = self
+            .endpoints
+            .iter()
+            .enumerate()
+            .map(|(order, endpoint)| {
+                let (health, failing_back_in) = match endpoint.state.lock() {
+                    Ok(state) => (
+                        json!(state.score.summary()),
+                        state.tripped_at.map(|tripped_at| {
+                            config
+                                .bp_failback_after
+                                .saturating_sub(tripped_at.elapsed())
+                                .as_secs()
+                        }),
+                    ),
+                    Err(_) => (Value::Null, None),
+                };
+
+                json!({
+                    "host": endpoint.host,
+                    "order": order,
+                    "connected": endpoint.client.is_connected(),
+                    "draining": endpoint.client.is_draining(),
+                    "health": health,
+                    "failing_back_in_secs": failing_back_in,
+                    "current": endpoint.client.server_identity(),
+                })
+            })
+            .collect();
+
+        json!({
+            "active": self.pick(config).address(),
+            "endpoints": endpoints,
+        })
+    }
+}
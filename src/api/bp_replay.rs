@@ -0,0 +1,147 @@
+use std::path::PathBuf;
+
+use racoon::core::request::Request;
+use racoon::core::response::{HttpResponse, JsonResponse, Response};
+
+use serde::Serialize;
+use tej_protoc::protoc::File;
+use uuid::Uuid;
+
+use crate::api::error_codes::ErrorCode;
+use crate::api::{shortcuts, task};
+use crate::db::models::BackgroundRemoverTask;
+use crate::utils::envelope_utils::ApiEnvelope;
+use crate::utils::replay_utils::{self, RecordedFrame};
+use crate::SharedContext;
+
+///
+/// Outcome of replaying one recorded frame.
+///
+#[derive(Debug, Serialize)]
+pub struct ReplayedFrame {
+    /// File name of the frame in the replay directory.
+    pub frame: String,
+    pub task_id: Option<Uuid>,
+    pub files: usize,
+    /// Whether handling the frame exceeded `BP_RESPONSE_TIMEOUT_SECS`.
+    pub timed_out: bool,
+    /// State of the task once the frame was handled. `None` if the task doesn't exist.
+    pub processing: Option<bool>,
+    pub result_status: Option<String>,
+}
+
+///
+/// Replays frames recorded from BP servers through the response handlers: `POST
+/// /v1/admin/bp-replay/?dir=<path>`, by default `BP_RECORD_DIR`. Frames are handled one at a time
+/// in file name order, exactly as if received from a BP server, so they update the tasks they
+/// name. Only built with the `bp-replay` feature.
+///
+pub async fn bp_replay_view(request: Request) -> Response {
+    if !shortcuts::is_admin(&request) {
+        return shortcuts::unauthorized();
+    }
+
+    if request.method != "POST" {
+        return HttpResponse::ok().body("This request method is not supported.");
+    }
+
+    let shared_context: &SharedContext = request.context().expect("SharedContext is missing.");
+    let dir = match request.query_params.value("dir") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => match replay_utils::record_dir() {
+            Some(dir) => dir,
+            None => {
+                return JsonResponse::bad_request().body(
+                    ApiEnvelope::failed(ErrorCode::ReplayDirMissing)
+                        .message("Pass ?dir= or set BP_RECORD_DIR.")
+                        .to_value(),
+                );
+            }
+        },
+    };
+
+    let frames = match replay_utils::read_frames(&dir) {
+        Ok(frames) => frames,
+        Err(error) => {
+            return JsonResponse::bad_request().body(
+                ApiEnvelope::failed(ErrorCode::InvalidFrames)
+                    .message(error.to_string())
+                    .to_value(),
+            );
+        }
+    };
+
+    let mut replayed = vec![];
+    for (path, frame) in frames {
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+
+        match replay_frame(shared_context, name.clone(), &frame).await {
+            Ok(result) => replayed.push(result),
+            Err(message) => {
+                return JsonResponse::bad_request().body(
+                    ApiEnvelope::failed(ErrorCode::InvalidFrames)
+                        .message(format!("{}: {}", name, message))
+                        .data(replayed)
+                        .to_value(),
+                );
+            }
+        }
+    }
+
+    JsonResponse::ok().body(ApiEnvelope::success("bp_replay").data(replayed).to_value())
+}
+
+///
+/// Hands `frame` to the response handler, with the same timeout as frames received from BP
+/// servers, and reads back the task it names.
+///
+async fn replay_frame(
+    shared_context: &SharedContext,
+    name: String,
+    frame: &RecordedFrame,
+) -> Result<ReplayedFrame, String> {
+    let files: Vec<File> = frame
+        .decode_files()?
+        .into_iter()
+        .map(|(name, data)| File::new(name, data))
+        .collect();
+    let file_count = files.len();
+    let task_id = frame
+        .message
+        .get("task_id")
+        .and_then(|task_id| task_id.as_str())
+        .and_then(|task_id| Uuid::parse_str(task_id).ok());
+
+    let result = tokio::time::timeout(
+        shared_context.config.load().bp_response_timeout,
+        task::handle_response_received_from_bp_server(
+            shared_context.clone(),
+            files,
+            frame.message.clone(),
+        ),
+    )
+    .await;
+    let timed_out = result.is_err();
+    if timed_out {
+        task::handle_response_timeout(shared_context.clone(), &frame.message).await;
+    }
+
+    let instance = match task_id {
+        Some(key) => BackgroundRemoverTask::fetch(shared_context.db_wrapper.clone(), &key)
+            .await
+            .ok(),
+        None => None,
+    };
+
+    Ok(ReplayedFrame {
+        frame: name,
+        task_id,
+        files: file_count,
+        timed_out,
+        processing: instance.as_ref().and_then(|instance| instance.processing),
+        result_status: instance.and_then(|instance| instance.result_status),
+    })
+}
@@ -0,0 +1,105 @@
+use std::sync::Arc;
+
+use serde_json::{json, Value};
+
+use crate::clients::bp_request_client::BPRequestClient;
+use crate::db::models::BackgroundRemoverTask;
+use crate::utils::routing_utils::DedicatedRoute;
+use crate::SharedContext;
+
+///
+/// BP connections dedicated to tenants, so their latency doesn't depend on public traffic.
+/// Configured per upload signing key id in `DEDICATED_BP_SERVERS`; every other task goes to the
+/// public BP server.
+///
+pub struct DedicatedBpServers {
+    servers: Vec<(DedicatedRoute, Arc<BPRequestClient>)>,
+}
+
+impl DedicatedBpServers {
+    pub fn new(servers: Vec<(DedicatedRoute, Arc<BPRequestClient>)>) -> Self {
+        Self { servers }
+    }
+
+    ///
+    /// Dedicated connection of upload signing key `key_id`, if it is pinned to one.
+    ///
+    pub fn client(&self, key_id: &str) -> Option<&Arc<BPRequestClient>> {
+        self.servers
+            .iter()
+            .find(|(route, _)| route.key_ids.iter().any(|pinned| pinned == key_id))
+            .map(|(_, client)| client)
+    }
+
+    pub fn clients(&self) -> impl Iterator<Item = &Arc<BPRequestClient>> {
+        self.servers.iter().map(|(_, client)| client)
+    }
+}
+
+///
+/// BP connection `task` is dispatched to: the dedicated connection of its api key, or the public
+/// one picked by `PublicBpServers::pick`. While the dedicated connection is down, tasks only fall
+/// back to the public connection when `AppConfig::dedicated_bp_fallback` is set.
+///
+pub fn client_for(
+    shared_context: &SharedContext,
+    task: &BackgroundRemoverTask,
+) -> Arc<BPRequestClient> {
+    let config = shared_context.config.load();
+    let public = || {
+        let client = shared_context.public_bp_servers.pick(&config);
+        if shared_context.public_bp_servers.is_backup(&client) {
+            shared_context
+                .metrics
+                .increment("bp_failover_dispatches_total");
+        }
+        client
+    };
+
+    let dedicated = match task
+        .api_key_id
+        .as_deref()
+        .and_then(|key_id| shared_context.dedicated_bp_servers.client(key_id))
+    {
+        Some(dedicated) => dedicated,
+        None => return public(),
+    };
+
+    let available = dedicated.is_connected() && !dedicated.is_draining();
+    if !available && config.dedicated_bp_fallback {
+        shared_context
+            .metrics
+            .increment("dedicated_bp_fallbacks_total");
+        return public();
+    }
+
+    shared_context
+        .metrics
+        .increment("dedicated_bp_dispatches_total");
+    dedicated.clone()
+}
+
+///
+/// Dedicated connections for the admin debug endpoint.
+///
+pub fn describe(shared_context: &SharedContext) -> Value {
+    let servers: Vec<Value> = shared_context
+        .dedicated_bp_servers
+        .servers
+        .iter()
+        .map(|(route, client)| {
+            json!({
+                "host": route.host,
+                "key_ids": route.key_ids,
+                "connected": client.is_connected(),
+                "draining": client.is_draining(),
+                "current": client.server_identity(),
+            })
+        })
+        .collect();
+
+    json!({
+        "fallback": shared_context.config.load().dedicated_bp_fallback,
+        "servers": servers,
+    })
+}
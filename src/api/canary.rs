@@ -0,0 +1,328 @@
+use std::env;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde_json::{json, Value};
+use tej_protoc::protoc::File;
+use uuid::Uuid;
+
+use crate::api::processing_times::ProcessingTimes;
+use crate::api::task;
+use crate::clients::bp_request_client::BPRequestClient;
+use crate::db::models::{BackgroundRemoverTask, CanaryResult, ManifestFile};
+use crate::utils::{image_utils, path_utils, save_utils};
+use crate::SharedContext;
+
+/// A task is mirrored at most once within this window, however often it is sent to the primary
+/// BP server, so its canary result is compared with a single run.
+const MIRROR_LEASE_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+///
+/// Second BP connection running a candidate model. A share of tasks is mirrored to it; its
+/// outputs are stored under the task `canary` directory and never served to users.
+///
+/// The mirrored share is `AppConfig::canary_percent` and may change without restart.
+///
+pub struct Canary {
+    pub client: Arc<BPRequestClient>,
+    processing_times: ProcessingTimes,
+}
+
+impl Canary {
+    pub fn new(client: Arc<BPRequestClient>, fallback: Duration) -> Self {
+        Self {
+            client,
+            processing_times: ProcessingTimes::new(fallback),
+        }
+    }
+
+    ///
+    /// Whether task `key` is mirrored when `percent` of tasks are. Decided from the key so every
+    /// stage agrees without keeping state.
+    ///
+    pub fn is_sampled(&self, key: &Uuid, percent: u32) -> bool {
+        (key.as_u128() % 100) < percent.min(100) as u128
+    }
+}
+
+///
+/// Mirrors `task` to the canary BP server in the background if it is sampled and wasn't mirrored
+/// within `MIRROR_LEASE_TTL`, by any replica, so the canary never delays the primary dispatch.
+/// Failures only affect metrics.
+///
+pub fn dispatch(shared_context: &SharedContext, instance: &BackgroundRemoverTask) {
+    let percent = shared_context.config.load().canary_percent;
+    match &shared_context.canary {
+        Some(canary) if canary.is_sampled(&instance.key, percent) => {}
+        _ => return,
+    };
+
+    tokio::spawn(mirror(shared_context.clone(), instance.key));
+}
+
+async fn mirror(shared_context: SharedContext, key: Uuid) {
+    let canary = match &shared_context.canary {
+        Some(canary) => canary,
+        None => return,
+    };
+
+    let lease = shared_context
+        .locks
+        .claim(&format!("canary:{}", key), MIRROR_LEASE_TTL)
+        .await;
+    if lease.is_held() {
+        return;
+    }
+
+    let instance = match BackgroundRemoverTask::fetch(shared_context.db_wrapper.clone(), &key).await
+    {
+        Ok(instance) => instance,
+        Err(error) => {
+            lease.release().await;
+            eprintln!("Failed to fetch task to mirror. Error: {}", error);
+            shared_context.metrics.increment("canary_failures_total");
+            return;
+        }
+    };
+
+    let config = shared_context.config.load_full();
+    let image_workers = &shared_context.image_workers;
+    let request_id = Uuid::new_v4();
+    let client = canary.client.clone();
+    match task::send(client, &config, image_workers, &instance, &request_id).await {
+        Ok(()) => {
+            canary.processing_times.start(instance.key);
+            shared_context.metrics.increment("canary_dispatched_total");
+        }
+        Err(error) => {
+            lease.release().await;
+            eprintln!("Failed to send task to canary bp server. Error: {}", error);
+            shared_context.metrics.increment("canary_failures_total");
+        }
+    }
+}
+
+///
+/// Records how long the primary BP server took for a mirrored task.
+///
+pub async fn record_primary_latency(shared_context: &SharedContext, key: &Uuid, took: Duration) {
+    let percent = shared_context.config.load().canary_percent;
+    match &shared_context.canary {
+        Some(canary) if canary.is_sampled(key, percent) => {}
+        _ => return,
+    };
+
+    shared_context.metrics.add(
+        "canary_primary_latency_millis_total",
+        took.as_millis() as u64,
+    );
+
+    let db_wrapper = shared_context.db_wrapper.clone();
+    if let Err(error) = CanaryResult::upsert_primary(db_wrapper, key, took.as_millis() as i64).await
+    {
+        eprintln!("Failed to record primary latency. Error: {}", error);
+        return;
+    }
+
+    score(shared_context, key).await;
+}
+
+///
+/// Handles messages received from the canary BP server.
+///
+pub async fn handle_canary_response(
+    shared_context: SharedContext,
+    files: Vec<File>,
+    message: Value,
+) {
+    let canary = match &shared_context.canary {
+        Some(canary) => canary,
+        None => return,
+    };
+
+    let key = match message
+        .get("task_id")
+        .and_then(|task_id| task_id.as_str())
+        .and_then(|task_id| Uuid::parse_str(task_id).ok())
+    {
+        Some(key) => key,
+        None => return,
+    };
+
+    match message.get("status").and_then(|status| status.as_str()) {
+        Some("success") => {}
+        Some("failed") => {
+            canary.processing_times.cancel(&key);
+            shared_context.metrics.increment("canary_failures_total");
+            return;
+        }
+        // Intermediate statuses are not interesting for evaluation.
+        _ => return,
+    }
+
+    let took = match canary.processing_times.finish(&key) {
+        Some(took) => took,
+        None => return,
+    };
+
+    let tenant = match BackgroundRemoverTask::fetch(shared_context.db_wrapper.clone(), &key).await {
+        Ok(task) => task.tenant,
+        Err(error) => {
+            eprintln!("Failed to fetch task of canary result. Error: {}", error);
+            shared_context.metrics.increment("canary_failures_total");
+            return;
+        }
+    };
+
+    let media_root = match env::var("MEDIA_ROOT") {
+        Ok(media_root) => PathBuf::from(media_root),
+        Err(_) => {
+            eprintln!("MEDIA_ROOT environment variable is missing.");
+            return;
+        }
+    };
+
+    // Stored relative to `MEDIA_ROOT`, like the media paths of tasks, so it survives a move of
+    // the media.
+    let mask_path = match save_canary_files(tenant.as_deref(), &key, &files).await {
+        Ok(path) => path_utils::relative_media_url_from_full_path(&media_root, &path),
+        Err(error) => {
+            eprintln!("Failed to save canary files. Error: {}", error);
+            shared_context.metrics.increment("canary_failures_total");
+            return;
+        }
+    };
+
+    shared_context.metrics.increment("canary_results_total");
+    shared_context
+        .metrics
+        .add("canary_latency_millis_total", took.as_millis() as u64);
+
+    if let Err(error) = CanaryResult::upsert_canary(
+        shared_context.db_wrapper.clone(),
+        &key,
+        took.as_millis() as i64,
+        &mask_path.to_string_lossy(),
+    )
+    .await
+    {
+        eprintln!("Failed to record canary result. Error: {}", error);
+        return;
+    }
+
+    score(&shared_context, &key).await;
+}
+
+///
+/// Saves canary outputs to `<task>/canary/`. Returns the absolute mask path.
+///
+async fn save_canary_files(
+    tenant: Option<&str>,
+    key: &Uuid,
+    files: &[File],
+) -> std::io::Result<PathBuf> {
+    if files.len() < 2 {
+        return Err(std::io::Error::other(format!(
+            "Minimum 2 files required. But received {}.",
+            files.len()
+        )));
+    }
+    save_utils::validate_bp_images(files)?;
+
+    let transparent_filename = path_utils::content_filename(&files[0].data, "png");
+    let mask_filename = path_utils::content_filename(&files[1].data, "png");
+    let transparent_path = path_utils::generate_save_path(
+        tenant,
+        path_utils::ForImage::CanaryImage(key, &transparent_filename),
+    )
+    .await?;
+    let mask_path = path_utils::generate_save_path(
+        tenant,
+        path_utils::ForImage::CanaryImage(key, &mask_filename),
+    )
+    .await?;
+
+    tokio::fs::write(&transparent_path, &files[0].data).await?;
+    tokio::fs::write(&mask_path, &files[1].data).await?;
+    Ok(mask_path)
+}
+
+///
+/// Computes mask IoU of the primary and canary outputs once both latencies are recorded.
+///
+async fn score(shared_context: &SharedContext, key: &Uuid) {
+    let db_wrapper = shared_context.db_wrapper.clone();
+    let result = match CanaryResult::fetch(db_wrapper.clone(), key).await {
+        Ok(result) => result,
+        Err(_) => return,
+    };
+
+    let canary_mask_path = match (
+        &result.primary_millis,
+        &result.canary_mask_path,
+        result.mask_iou,
+    ) {
+        (Some(_), Some(canary_mask_path), None) => PathBuf::from(canary_mask_path),
+        _ => return,
+    };
+
+    let task = match BackgroundRemoverTask::fetch(db_wrapper.clone(), key).await {
+        Ok(task) => task,
+        Err(_) => return,
+    };
+
+    let primary_mask_path = task.output_path(ManifestFile::MASK);
+    let (media_root, primary_mask_path) = match (env::var("MEDIA_ROOT"), primary_mask_path) {
+        (Ok(media_root), Some(path)) => (PathBuf::from(media_root), PathBuf::from(path)),
+        _ => return,
+    };
+    let primary_mask_path =
+        path_utils::file_path_from_relative_url(media_root.clone(), primary_mask_path);
+    // Results stored before paths were relative keep their absolute path, which replaces the
+    // media root when joined.
+    let canary_mask_path = path_utils::file_path_from_relative_url(media_root, canary_mask_path);
+
+    let memory = image_utils::estimate_file_memory(&primary_mask_path)
+        + image_utils::estimate_file_memory(&canary_mask_path);
+    let mask_iou = shared_context
+        .image_workers
+        .run(memory, move || -> image::ImageResult<f64> {
+            Ok(image_utils::mask_iou(
+                &image_utils::open(&primary_mask_path)?.to_luma8(),
+                &image_utils::open(&canary_mask_path)?.to_luma8(),
+            ))
+        })
+        .await;
+
+    match mask_iou {
+        Ok(Ok(mask_iou)) => {
+            shared_context.metrics.increment("canary_scored_total");
+            shared_context
+                .metrics
+                .add("canary_mask_iou_permille_total", (mask_iou * 1000.0) as u64);
+
+            if let Err(error) = CanaryResult::set_mask_iou(db_wrapper, key, mask_iou).await {
+                eprintln!("Failed to store canary score. Error: {}", error);
+            }
+        }
+        Ok(Err(error)) => eprintln!("Failed to score canary result. Error: {}", error),
+        Err(error) => eprintln!("Canary scoring task failed. Error: {}", error),
+    }
+}
+
+///
+/// Canary state for the admin debug endpoint.
+///
+pub fn describe(shared_context: &SharedContext) -> Value {
+    match &shared_context.canary {
+        Some(canary) => json!({
+            "enabled": true,
+            "percent": shared_context.config.load().canary_percent,
+            "connected": canary.client.is_connected(),
+            "draining": canary.client.is_draining(),
+            "average_millis": canary.processing_times.average().as_millis() as u64,
+        }),
+        None => json!({ "enabled": false }),
+    }
+}
@@ -0,0 +1,129 @@
+use std::time::Duration;
+
+use racoon::core::request::Request;
+use racoon::core::response::{HttpResponse, JsonResponse, Response};
+
+use serde_json::json;
+
+use crate::api::error_codes::ErrorCode;
+use crate::api::shortcuts;
+use crate::utils::chaos_utils::{self, Faults};
+use crate::utils::envelope_utils::ApiEnvelope;
+use crate::SharedContext;
+
+///
+/// Injects faults for resilience testing: `GET /v1/admin/chaos/` returns the injected faults,
+/// `POST /v1/admin/chaos/` changes them with query parameters `db_error_percent`,
+/// `disk_write_delay_ms` and `ws_drop_percent`. Omitted parameters keep their value and
+/// `?reset=true` turns every fault off. `?bp_disconnect=public|canary|dedicated|all` drops the
+/// BP connections once; they reconnect as after a real disconnect. Only built with the `chaos`
+/// feature.
+///
+pub async fn chaos_view(request: Request) -> Response {
+    if !shortcuts::is_admin(&request) {
+        return shortcuts::unauthorized();
+    }
+
+    match request.method.as_str() {
+        "GET" => JsonResponse::ok().body(
+            ApiEnvelope::success("chaos")
+                .data(json!({ "faults": chaos_utils::faults() }))
+                .to_value(),
+        ),
+        "POST" => {
+            let shared_context: &SharedContext =
+                request.context().expect("SharedContext is missing.");
+            let query_params = &request.query_params;
+
+            let mut faults = match query_params.value("reset") {
+                Some(value) if value == "true" => Faults::NONE,
+                _ => chaos_utils::faults(),
+            };
+
+            if let Some(value) = query_params.value("db_error_percent") {
+                match chaos_utils::parse_percent(value) {
+                    Some(percent) => faults.db_error_percent = percent,
+                    None => return invalid_parameter("db_error_percent"),
+                }
+            }
+            if let Some(value) = query_params.value("disk_write_delay_ms") {
+                match value.trim().parse::<u64>() {
+                    Ok(millis) => faults.disk_write_delay = Duration::from_millis(millis),
+                    Err(_) => return invalid_parameter("disk_write_delay_ms"),
+                }
+            }
+            if let Some(value) = query_params.value("ws_drop_percent") {
+                match chaos_utils::parse_percent(value) {
+                    Some(percent) => faults.ws_drop_percent = percent,
+                    None => return invalid_parameter("ws_drop_percent"),
+                }
+            }
+
+            let disconnected = match query_params.value("bp_disconnect") {
+                Some(target) => match disconnect_bp(shared_context, target) {
+                    Some(disconnected) => disconnected,
+                    None => return invalid_parameter("bp_disconnect"),
+                },
+                None => vec![],
+            };
+
+            chaos_utils::set_faults(faults);
+            log::warn!(
+                "Injected faults: {:?}. Disconnected BP servers: {:?}",
+                faults,
+                disconnected
+            );
+
+            JsonResponse::ok().body(
+                ApiEnvelope::success("chaos")
+                    .data(json!({
+                        "faults": faults,
+                        "disconnected_bp_servers": disconnected,
+                    }))
+                    .to_value(),
+            )
+        }
+        _ => HttpResponse::ok().body("This request method is not supported."),
+    }
+}
+
+fn invalid_parameter(name: &str) -> Response {
+    JsonResponse::bad_request().body(
+        ApiEnvelope::failed(ErrorCode::InvalidParameter)
+            .message(format!("Invalid value of {}.", name))
+            .to_value(),
+    )
+}
+
+///
+/// Drops the connections of `target` BP servers. Returns their addresses, or `None` for an
+/// unknown target.
+///
+fn disconnect_bp(shared_context: &SharedContext, target: &str) -> Option<Vec<String>> {
+    if !matches!(target, "public" | "canary" | "dedicated" | "all") {
+        return None;
+    }
+
+    let mut clients = vec![];
+    if matches!(target, "public" | "all") {
+        clients.extend(shared_context.public_bp_servers.clients().cloned());
+    }
+    if matches!(target, "canary" | "all") {
+        if let Some(canary) = &shared_context.canary {
+            clients.push(canary.client.clone());
+        }
+    }
+    if matches!(target, "dedicated" | "all") {
+        clients.extend(shared_context.dedicated_bp_servers.clients().cloned());
+    }
+
+    Some(
+        clients
+            .iter()
+            .map(|client| {
+                client.reconnect();
+                client.address().to_string()
+            })
+            .collect(),
+    )
+}
@@ -0,0 +1,212 @@
+use std::collections::HashSet;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use uuid::Uuid;
+
+use crate::db::models::{BackgroundRemoverTask, ManifestFile};
+use crate::utils::{image_utils, path_utils};
+use crate::SharedContext;
+
+/// Maximum number of tasks of a group rendered into its contact sheet.
+pub const MAX_CONTACT_SHEET_TASKS: i64 = 100;
+
+/// Side in pixels of a single cell of the contact sheet.
+const CELL_SIDE: u32 = 160;
+
+/// Maximum number of cells per row.
+const MAX_COLUMNS: u32 = 10;
+
+///
+/// State of the contact sheet of a task group.
+///
+#[derive(Debug, Clone, PartialEq)]
+pub enum ContactSheetStatus {
+    /// Sheet of the current processed previews exists. Holds its full url.
+    Ready(String),
+    /// Sheet is being rendered in the background.
+    Generating,
+    /// No task of the group has a processed preview yet.
+    Empty,
+}
+
+impl ContactSheetStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ContactSheetStatus::Ready(_) => "ready",
+            ContactSheetStatus::Generating => "generating",
+            ContactSheetStatus::Empty => "empty",
+        }
+    }
+
+    pub fn url(&self) -> Option<&String> {
+        match self {
+            ContactSheetStatus::Ready(url) => Some(url),
+            _ => None,
+        }
+    }
+}
+
+///
+/// Grid images of the processed previews of a task group, for reviewing batches at a glance.
+///
+/// Sheets are named after the previews they contain, so a sheet is rendered once per set of
+/// previews and replaced when a task of the group finishes. Rendering runs on the image workers
+/// off the request path, at most once per task group at a time.
+///
+pub struct ContactSheets {
+    in_flight: Mutex<HashSet<Uuid>>,
+}
+
+impl Default for ContactSheets {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ContactSheets {
+    pub fn new() -> Self {
+        Self {
+            in_flight: Mutex::new(HashSet::new()),
+        }
+    }
+
+    ///
+    /// Returns the contact sheet of `tasks` of `task_group`. When it doesn't exist yet, rendering
+    /// is started in the background and `Generating` is returned.
+    ///
+    pub async fn get_or_generate(
+        &self,
+        shared_context: &SharedContext,
+        task_group: &Uuid,
+        tasks: &[BackgroundRemoverTask],
+    ) -> std::io::Result<ContactSheetStatus> {
+        let sources: Vec<String> = tasks
+            .iter()
+            .filter_map(|task| task.output_path(ManifestFile::PREVIEW_PROCESSED).cloned())
+            .collect();
+        if sources.is_empty() {
+            return Ok(ContactSheetStatus::Empty);
+        }
+
+        // A group uploaded by one tenant is stored with its media. Groups mixing tenants, or
+        // with unsigned uploads, are stored outside every tenant.
+        let tenant = tasks
+            .first()
+            .and_then(|task| task.tenant.as_deref())
+            .filter(|tenant| {
+                tasks
+                    .iter()
+                    .all(|task| task.tenant.as_deref() == Some(*tenant))
+            });
+        let filename = path_utils::content_filename(sources.join("\n").as_bytes(), "png");
+        let save_path = path_utils::generate_save_path(
+            tenant,
+            path_utils::ForImage::ContactSheet(task_group, &filename),
+        )
+        .await?;
+
+        if save_path.exists() {
+            let media_root = media_root()?;
+            let host = env::var("HOST").map_err(std::io::Error::other)?;
+            let relative_path =
+                path_utils::relative_media_url_from_full_path(&media_root, &save_path);
+            let url = path_utils::full_media_url_from_relative_path("https", &host, relative_path);
+            return Ok(ContactSheetStatus::Ready(url));
+        }
+
+        let started = match self.in_flight.lock() {
+            Ok(mut in_flight) => in_flight.insert(*task_group),
+            Err(_) => false,
+        };
+        if started {
+            tokio::spawn(generate(
+                shared_context.clone(),
+                *task_group,
+                sources,
+                save_path,
+            ));
+        }
+
+        Ok(ContactSheetStatus::Generating)
+    }
+
+    fn finish(&self, task_group: &Uuid) {
+        if let Ok(mut in_flight) = self.in_flight.lock() {
+            in_flight.remove(task_group);
+        }
+    }
+}
+
+async fn generate(
+    shared_context: SharedContext,
+    task_group: Uuid,
+    sources: Vec<String>,
+    save_path: PathBuf,
+) {
+    if let Err(error) = try_generate(&shared_context, sources, &save_path).await {
+        eprintln!(
+            "Failed to generate contact sheet of task group {}. Error: {}",
+            task_group, error
+        );
+    }
+
+    shared_context.contact_sheets.finish(&task_group);
+}
+
+async fn try_generate(
+    shared_context: &SharedContext,
+    sources: Vec<String>,
+    save_path: &Path,
+) -> std::io::Result<()> {
+    let media_root = media_root()?;
+
+    // Missing previews leave an empty cell instead of failing the whole sheet.
+    let mut images = Vec::with_capacity(sources.len());
+    for source in &sources {
+        let path =
+            path_utils::file_path_from_relative_url(media_root.clone(), PathBuf::from(source));
+        images.push(tokio::fs::read(path).await.unwrap_or_default());
+    }
+
+    let columns = ((images.len() as f64).sqrt().ceil() as u32).clamp(1, MAX_COLUMNS);
+    // Images are decoded one at a time while the sheet is kept.
+    let rows = (images.len() as u64).div_ceil(columns as u64).max(1);
+    let memory = images
+        .iter()
+        .map(|image| image_utils::estimate_memory(image))
+        .max()
+        .unwrap_or(0)
+        + columns as u64 * rows * (CELL_SIDE as u64).pow(2) * 4;
+    let sheet = shared_context
+        .image_workers
+        .run(memory, move || {
+            image_utils::contact_sheet(&images, CELL_SIDE, columns)
+        })
+        .await?
+        .map_err(std::io::Error::other)?;
+
+    // Written under a temporary name so a partially written sheet is never served as ready.
+    let temporary_path = save_path.with_extension("tmp");
+    tokio::fs::write(&temporary_path, &sheet).await?;
+    tokio::fs::rename(&temporary_path, save_path).await?;
+
+    // Sheets of earlier sets of previews are outdated.
+    if let Some(directory) = save_path.parent() {
+        let mut entries = tokio::fs::read_dir(directory).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if entry.path() != *save_path {
+                let _ = tokio::fs::remove_file(entry.path()).await;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn media_root() -> std::io::Result<PathBuf> {
+    Ok(PathBuf::from(
+        env::var("MEDIA_ROOT").map_err(std::io::Error::other)?,
+    ))
+}
@@ -0,0 +1,200 @@
+use std::env;
+use std::path::PathBuf;
+
+use racoon::core::request::Request;
+use racoon::core::response::{HttpResponse, JsonResponse, Response};
+
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::api::error_codes::ErrorCode;
+use crate::api::shortcuts;
+use crate::db::models::{BackgroundRemoverTask, ErasedRows, UserErasure};
+use crate::jobs::media_gc;
+use crate::utils::envelope_utils::ApiEnvelope;
+use crate::utils::path_utils;
+use crate::SharedContext;
+
+///
+/// Summary of erasing the tasks of a user identifier. Never contains the identifier itself.
+///
+#[derive(Debug, Default, Serialize)]
+pub struct ErasureReport {
+    /// Id of the report in table `user_erasure`. `None` in dry run.
+    pub erasure_id: Option<i64>,
+    /// Keys of the erased tasks.
+    pub tasks: Vec<Uuid>,
+    /// Task groups of the erased tasks. Their contact sheets, batch email registrations and
+    /// websocket histories are erased too.
+    pub task_groups: Vec<Uuid>,
+    /// Deleted rows per table. All zero in dry run.
+    pub rows: ErasedRows,
+    /// Removed media directories (or that would be removed in dry run), relative paths.
+    pub removed_directories: Vec<String>,
+    /// Media directories which failed to be removed, relative paths.
+    pub failed_directories: Vec<String>,
+    /// Total size of the removed directories in bytes.
+    pub reclaimed_bytes: u64,
+    pub dry_run: bool,
+}
+
+///
+/// Erases every task of a user for GDPR and CCPA requests: `POST
+/// /v1/admin/users/{user_identifier}/erase/`, with the identifier as sent by the client on upload.
+/// Pass `?dry_run=true` to only report what would be erased.
+///
+pub async fn erase_user_view(request: Request) -> Response {
+    if !shortcuts::is_admin(&request) {
+        return shortcuts::unauthorized();
+    }
+
+    if request.method != "POST" {
+        return HttpResponse::ok().body("This request method is not supported.");
+    }
+
+    let shared_context: &SharedContext = request.context().expect("SharedContext is missing.");
+    let user_identifier = request
+        .path_params
+        .value("user_identifier")
+        .expect("User identifier is missing.");
+    let dry_run = match request.query_params.value("dry_run") {
+        Some(value) => value == "true",
+        None => false,
+    };
+
+    match erase_user(shared_context, user_identifier, dry_run).await {
+        Ok(report) => {
+            JsonResponse::ok().body(ApiEnvelope::success("user_erased").data(report).to_value())
+        }
+        Err(error) => {
+            log::error!("User erasure failed. Error: {}", error);
+
+            JsonResponse::internal_server_error()
+                .body(ApiEnvelope::failed(ErrorCode::InternalServerError).to_value())
+        }
+    }
+}
+
+///
+/// Values `user_identifier` may be stored as: as sent before signing keys existed, and
+/// pseudonymized under every key of the keyring since.
+///
+pub fn stored_user_identifiers(
+    shared_context: &SharedContext,
+    user_identifier: &str,
+) -> Vec<String> {
+    let mut user_identifiers = vec![user_identifier.to_string()];
+    if let Some(keyring) = shared_context.secrets.keyring() {
+        user_identifiers.extend(keyring.user_identifier_hashes(user_identifier));
+    }
+    user_identifiers
+}
+
+///
+/// Deletes the rows of every task stored with `user_identifier`, archived or not, with their
+/// related rows, then removes their media directories and the contact sheets and websocket
+/// histories of their task groups. The report is stored in `user_erasure`.
+///
+/// Rows are deleted first, so a directory failing to be removed is no longer reachable through
+/// the API, and task directories left behind are collected by the media garbage collector.
+///
+pub async fn erase_user(
+    shared_context: &SharedContext,
+    user_identifier: &str,
+    dry_run: bool,
+) -> std::io::Result<ErasureReport> {
+    let media_root = match env::var("MEDIA_ROOT") {
+        Ok(path) => PathBuf::from(path),
+        Err(error) => {
+            eprintln!("MEDIA_ROOT environment variable is missing.");
+            return Err(std::io::Error::other(error));
+        }
+    };
+
+    let db_wrapper = shared_context.db_wrapper.clone();
+    let tasks = BackgroundRemoverTask::fetch_by_user_identifiers(
+        db_wrapper.clone(),
+        &stored_user_identifiers(shared_context, user_identifier),
+    )
+    .await
+    .map_err(std::io::Error::other)?;
+
+    let mut report = ErasureReport {
+        dry_run,
+        ..Default::default()
+    };
+
+    // Contact sheets of task groups mixing tenants are stored without tenant.
+    let mut directories: Vec<PathBuf> = vec![];
+    for (key, task_group, tenant) in &tasks {
+        let tenant = tenant.as_deref();
+        report.tasks.push(*key);
+        directories.push(
+            path_utils::task_directory(&media_root, tenant, key, path_utils::shard_levels())
+                .await?,
+        );
+
+        if !report.task_groups.contains(task_group) {
+            report.task_groups.push(*task_group);
+        }
+        for tenant in [None, tenant] {
+            let directory = path_utils::task_group_directory(&media_root, tenant, task_group)?;
+            if !directories.contains(&directory) {
+                directories.push(directory);
+            }
+        }
+    }
+
+    if !dry_run {
+        report.rows =
+            BackgroundRemoverTask::erase(db_wrapper.clone(), &report.tasks, &report.task_groups)
+                .await
+                .map_err(std::io::Error::other)?;
+    }
+
+    for directory in directories {
+        if !directory.exists() {
+            continue;
+        }
+
+        let size = media_gc::dir_size(&directory).await;
+        let relative_path = path_utils::relative_media_url_from_full_path(&media_root, &directory)
+            .to_string_lossy()
+            .to_string();
+
+        if !dry_run {
+            if let Err(error) = tokio::fs::remove_dir_all(&directory).await {
+                eprintln!("Failed to remove {:?}. Error: {}", directory, error);
+                report.failed_directories.push(relative_path);
+                continue;
+            }
+        }
+
+        report.reclaimed_bytes += size;
+        report.removed_directories.push(relative_path);
+    }
+
+    if dry_run {
+        return Ok(report);
+    }
+
+    for key in &report.tasks {
+        shared_context.progress.forget(key);
+    }
+    for task_group in &report.task_groups {
+        shared_context.ws_clients.forget(task_group).await;
+    }
+
+    let stored_report = serde_json::to_value(&report).map_err(std::io::Error::other)?;
+    let erasure_id = UserErasure::insert(db_wrapper, report.tasks.len() as i64, &stored_report)
+        .await
+        .map_err(std::io::Error::other)?;
+    report.erasure_id = Some(erasure_id);
+
+    log::info!(
+        "Erased {} tasks of a user. Erasure id: {}",
+        report.tasks.len(),
+        erasure_id
+    );
+    Ok(report)
+}
@@ -0,0 +1,332 @@
+use std::fmt::{Display, Formatter};
+
+use racoon::core::request::Request;
+use racoon::core::response::{HttpResponse, JsonResponse, Response};
+use serde::{Serialize, Serializer};
+use serde_json::{json, Value};
+
+use crate::utils::envelope_utils::ApiEnvelope;
+
+///
+/// `status_code` of every failed response and websocket message. Codes are stable: new codes
+/// may be added, but existing ones are never renamed or removed, so SDKs can generate typed
+/// errors from `/v1/errors/`. Status codes relayed from BP servers are not part of it.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    BadQuery,
+    BadRequest,
+    FormError,
+    InvalidBody,
+    InvalidImage,
+    ChecksumMismatch,
+    InvalidParameter,
+    InvalidFrames,
+    ReplayDirMissing,
+    NotProcessed,
+    PermissionError,
+    Unauthorized,
+    InvalidSignature,
+    PaymentRequired,
+    Forbidden,
+    NotFound,
+    ApiKeyTaken,
+    MemberExists,
+    LinkExpired,
+    LengthRequired,
+    PayloadTooLarge,
+    TooManyKeyFailures,
+    QuotaExceeded,
+    InternalServerError,
+    FileMoveFailed,
+    ComparisonFailed,
+    ComparisonTimeout,
+    SigningKeysMissing,
+    Maintenance,
+    BpSendFailed,
+    OriginalReadTimeout,
+    BpSendTimeout,
+    BpResponseTimeout,
+    ProcessingFailed,
+    InvalidMessageFormat,
+    UnknownMessageType,
+    InvalidPathFormat,
+    ConnectionLimit,
+    ServiceDegraded,
+    StoreUnavailable,
+}
+
+impl ErrorCode {
+    /// Every code, in the order of the registry.
+    pub const ALL: [ErrorCode; 40] = [
+        ErrorCode::BadQuery,
+        ErrorCode::BadRequest,
+        ErrorCode::FormError,
+        ErrorCode::InvalidBody,
+        ErrorCode::InvalidImage,
+        ErrorCode::ChecksumMismatch,
+        ErrorCode::InvalidParameter,
+        ErrorCode::InvalidFrames,
+        ErrorCode::ReplayDirMissing,
+        ErrorCode::NotProcessed,
+        ErrorCode::PermissionError,
+        ErrorCode::Unauthorized,
+        ErrorCode::InvalidSignature,
+        ErrorCode::PaymentRequired,
+        ErrorCode::Forbidden,
+        ErrorCode::NotFound,
+        ErrorCode::ApiKeyTaken,
+        ErrorCode::MemberExists,
+        ErrorCode::LinkExpired,
+        ErrorCode::LengthRequired,
+        ErrorCode::PayloadTooLarge,
+        ErrorCode::TooManyKeyFailures,
+        ErrorCode::QuotaExceeded,
+        ErrorCode::InternalServerError,
+        ErrorCode::FileMoveFailed,
+        ErrorCode::ComparisonFailed,
+        ErrorCode::ComparisonTimeout,
+        ErrorCode::SigningKeysMissing,
+        ErrorCode::Maintenance,
+        ErrorCode::BpSendFailed,
+        ErrorCode::OriginalReadTimeout,
+        ErrorCode::BpSendTimeout,
+        ErrorCode::BpResponseTimeout,
+        ErrorCode::ProcessingFailed,
+        ErrorCode::InvalidMessageFormat,
+        ErrorCode::UnknownMessageType,
+        ErrorCode::InvalidPathFormat,
+        ErrorCode::ConnectionLimit,
+        ErrorCode::ServiceDegraded,
+        ErrorCode::StoreUnavailable,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorCode::BadQuery => "bad_query",
+            ErrorCode::BadRequest => "bad_request",
+            ErrorCode::FormError => "form_error",
+            ErrorCode::InvalidBody => "invalid_body",
+            ErrorCode::InvalidImage => "invalid_image",
+            ErrorCode::ChecksumMismatch => "checksum_mismatch",
+            ErrorCode::InvalidParameter => "invalid_parameter",
+            ErrorCode::InvalidFrames => "invalid_frames",
+            ErrorCode::ReplayDirMissing => "replay_dir_missing",
+            ErrorCode::NotProcessed => "not_processed",
+            ErrorCode::PermissionError => "permission_error",
+            ErrorCode::Unauthorized => "unauthorized",
+            ErrorCode::InvalidSignature => "invalid_signature",
+            ErrorCode::PaymentRequired => "payment_required",
+            ErrorCode::Forbidden => "forbidden",
+            ErrorCode::NotFound => "not_found",
+            ErrorCode::ApiKeyTaken => "api_key_taken",
+            ErrorCode::MemberExists => "member_exists",
+            ErrorCode::LinkExpired => "link_expired",
+            ErrorCode::LengthRequired => "length_required",
+            ErrorCode::PayloadTooLarge => "payload_too_large",
+            ErrorCode::TooManyKeyFailures => "too_many_key_failures",
+            ErrorCode::QuotaExceeded => "quota_exceeded",
+            ErrorCode::InternalServerError => "internal_server_error",
+            ErrorCode::FileMoveFailed => "file_move_failed",
+            ErrorCode::ComparisonFailed => "comparison_failed",
+            ErrorCode::ComparisonTimeout => "comparison_timeout",
+            ErrorCode::SigningKeysMissing => "signing_keys_missing",
+            ErrorCode::Maintenance => "maintenance",
+            ErrorCode::BpSendFailed => "bp_send_failed",
+            ErrorCode::OriginalReadTimeout => "original_read_timeout",
+            ErrorCode::BpSendTimeout => "bp_send_timeout",
+            ErrorCode::BpResponseTimeout => "bp_response_timeout",
+            ErrorCode::ProcessingFailed => "processing_failed",
+            ErrorCode::InvalidMessageFormat => "invalid_message_format",
+            ErrorCode::UnknownMessageType => "unknown_message_type",
+            ErrorCode::InvalidPathFormat => "invalid_path_format",
+            ErrorCode::ConnectionLimit => "connection_limit",
+            ErrorCode::ServiceDegraded => "service_degraded",
+            ErrorCode::StoreUnavailable => "store_unavailable",
+        }
+    }
+
+    ///
+    /// HTTP statuses of responses with this code. Empty for codes only sent over websockets.
+    ///
+    pub fn http_statuses(&self) -> &'static [u16] {
+        match self {
+            ErrorCode::BadQuery
+            | ErrorCode::BadRequest
+            | ErrorCode::FormError
+            | ErrorCode::InvalidBody
+            | ErrorCode::InvalidImage
+            | ErrorCode::ChecksumMismatch
+            | ErrorCode::InvalidParameter
+            | ErrorCode::InvalidFrames
+            | ErrorCode::ReplayDirMissing
+            | ErrorCode::NotProcessed
+            | ErrorCode::PermissionError => &[400],
+            ErrorCode::Unauthorized => &[401],
+            ErrorCode::InvalidSignature => &[401, 403],
+            ErrorCode::PaymentRequired => &[402],
+            ErrorCode::Forbidden => &[403],
+            ErrorCode::NotFound => &[404],
+            ErrorCode::ApiKeyTaken | ErrorCode::MemberExists => &[409],
+            ErrorCode::LinkExpired => &[410],
+            ErrorCode::LengthRequired => &[411],
+            ErrorCode::PayloadTooLarge => &[413],
+            ErrorCode::TooManyKeyFailures | ErrorCode::QuotaExceeded => &[429],
+            ErrorCode::InternalServerError
+            | ErrorCode::FileMoveFailed
+            | ErrorCode::ComparisonFailed
+            | ErrorCode::ComparisonTimeout => &[500],
+            ErrorCode::BpSendFailed => &[500, 502],
+            ErrorCode::OriginalReadTimeout
+            | ErrorCode::BpSendTimeout
+            | ErrorCode::BpResponseTimeout
+            | ErrorCode::ProcessingFailed => &[502],
+            ErrorCode::SigningKeysMissing | ErrorCode::Maintenance => &[503],
+            ErrorCode::InvalidMessageFormat
+            | ErrorCode::UnknownMessageType
+            | ErrorCode::InvalidPathFormat
+            | ErrorCode::ConnectionLimit
+            | ErrorCode::ServiceDegraded
+            | ErrorCode::StoreUnavailable => &[],
+        }
+    }
+
+    ///
+    /// Whether websocket messages carry this code.
+    ///
+    pub fn is_websocket(&self) -> bool {
+        matches!(
+            self,
+            ErrorCode::NotFound
+                | ErrorCode::PermissionError
+                | ErrorCode::PayloadTooLarge
+                | ErrorCode::InternalServerError
+                | ErrorCode::TooManyKeyFailures
+                | ErrorCode::BpSendFailed
+                | ErrorCode::OriginalReadTimeout
+                | ErrorCode::BpSendTimeout
+                | ErrorCode::BpResponseTimeout
+                | ErrorCode::InvalidMessageFormat
+                | ErrorCode::UnknownMessageType
+                | ErrorCode::InvalidPathFormat
+                | ErrorCode::ConnectionLimit
+                | ErrorCode::ServiceDegraded
+                | ErrorCode::StoreUnavailable
+        )
+    }
+
+    ///
+    /// Whether the same request may succeed when sent again later.
+    ///
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            ErrorCode::ChecksumMismatch
+                | ErrorCode::TooManyKeyFailures
+                | ErrorCode::QuotaExceeded
+                | ErrorCode::InternalServerError
+                | ErrorCode::FileMoveFailed
+                | ErrorCode::ComparisonTimeout
+                | ErrorCode::BpSendFailed
+                | ErrorCode::OriginalReadTimeout
+                | ErrorCode::BpSendTimeout
+                | ErrorCode::BpResponseTimeout
+                | ErrorCode::Maintenance
+                | ErrorCode::ConnectionLimit
+                | ErrorCode::ServiceDegraded
+                | ErrorCode::StoreUnavailable
+        )
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            ErrorCode::BadQuery => "A query parameter is missing or invalid.",
+            ErrorCode::BadRequest => {
+                "The request is malformed, e.g. an invalid task id or JSON body."
+            }
+            ErrorCode::FormError => "Form fields are missing or invalid, see `field_errors`.",
+            ErrorCode::InvalidBody => "The request body is missing, malformed or too large.",
+            ErrorCode::InvalidImage => "The image is missing, unsupported or too large.",
+            ErrorCode::ChecksumMismatch => {
+                "The uploaded image does not match its `sha256`; upload it again."
+            }
+            ErrorCode::InvalidParameter => "A fault injection parameter is invalid.",
+            ErrorCode::InvalidFrames => "Recorded BP frames could not be read.",
+            ErrorCode::ReplayDirMissing => "No directory of recorded BP frames was given.",
+            ErrorCode::NotProcessed => "Only processed tasks can be refined.",
+            ErrorCode::PermissionError => "The task belongs to another task group.",
+            ErrorCode::Unauthorized => "Credentials are missing or invalid.",
+            ErrorCode::InvalidSignature => {
+                "The request signature or signed link is missing, invalid, expired or reused."
+            }
+            ErrorCode::PaymentRequired => "The subscription of the billing account is not active.",
+            ErrorCode::Forbidden => "The role of the member does not allow this request.",
+            ErrorCode::NotFound => "The requested object does not exist or is not accessible.",
+            ErrorCode::ApiKeyTaken => "The key id belongs to an organization already.",
+            ErrorCode::MemberExists => "The email is a member of the organization already.",
+            ErrorCode::LinkExpired => "The download link expired.",
+            ErrorCode::LengthRequired => "The request body was sent without `Content-Length`.",
+            ErrorCode::PayloadTooLarge => {
+                "The request body or websocket message is larger than allowed for its type."
+            }
+            ErrorCode::TooManyKeyFailures => {
+                "Too many unknown task keys were requested; retry after `Retry-After`."
+            }
+            ErrorCode::QuotaExceeded => "The monthly quota of the billing plan is used up.",
+            ErrorCode::InternalServerError => "The request failed on the server.",
+            ErrorCode::FileMoveFailed => "The uploaded image could not be stored.",
+            ErrorCode::ComparisonFailed => "The revisions could not be compared.",
+            ErrorCode::ComparisonTimeout => "Comparing the revisions timed out.",
+            ErrorCode::SigningKeysMissing => "Signing keys are not configured.",
+            ErrorCode::Maintenance => {
+                "The service is down for maintenance; retry after `Retry-After`."
+            }
+            ErrorCode::BpSendFailed => "The image could not be sent for processing.",
+            ErrorCode::OriginalReadTimeout => "Reading the original image timed out.",
+            ErrorCode::BpSendTimeout => "Sending the image for processing timed out.",
+            ErrorCode::BpResponseTimeout => "Saving the processed image timed out.",
+            ErrorCode::ProcessingFailed => "Processing failed without a reported reason.",
+            ErrorCode::InvalidMessageFormat => "The websocket message is not a valid JSON object.",
+            ErrorCode::UnknownMessageType => "The `action` of the websocket message is unknown.",
+            ErrorCode::InvalidPathFormat => "The task group of the websocket path is invalid.",
+            ErrorCode::ConnectionLimit => "Too many websocket connections of the task group or IP.",
+            ErrorCode::ServiceDegraded => "Image processing is temporarily unavailable.",
+            ErrorCode::StoreUnavailable => "The products of the synced store could not be listed.",
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "code": self.as_str(),
+            "http_statuses": self.http_statuses(),
+            "websocket": self.is_websocket(),
+            "retryable": self.is_retryable(),
+            "description": self.description(),
+        })
+    }
+}
+
+impl Display for ErrorCode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Serialize for ErrorCode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+///
+/// Lists every error code with its HTTP statuses, whether websocket messages carry it, whether
+/// it is retryable and its description (`GET`), for generating typed errors in SDKs.
+///
+pub async fn errors_view(request: Request) -> Response {
+    if request.method != "GET" {
+        return HttpResponse::ok().body("This request method is not supported.");
+    }
+
+    let errors: Vec<Value> = ErrorCode::ALL.iter().map(ErrorCode::to_json).collect();
+    JsonResponse::ok().body(ApiEnvelope::success("errors").data(errors).to_value())
+}
@@ -6,47 +6,116 @@ use racoon::forms::fields::uuid_field::UuidField;
 use racoon::forms::fields::AbstractFields;
 use racoon::forms::FormValidator;
 
+use image::ImageError;
 use uuid::Uuid;
 
+use crate::api::ingestion::MAX_IMAGE_SIZE;
+use crate::utils::{image_utils, path_utils, temp_utils};
+
+/// Largest brush-stroke correction image. Scribble masks are mostly empty and compress well.
+pub const MAX_CORRECTION_IMAGE_SIZE: usize = 10 * 1024 * 1024;
+
+///
+/// Rejects uploaded files larger than `max_size` bytes. Every file field has its own limit.
+///
+fn validate_file_size(
+    uploaded_file: UploadedFile,
+    max_size: usize,
+) -> Result<UploadedFile, Vec<String>> {
+    let temp_path = &uploaded_file.temp_path;
+
+    let file = match std::fs::File::open(temp_path) {
+        Ok(file) => file,
+        Err(error) => {
+            eprintln!("Failed to open file. Error: {}", error);
+            return Err(vec!["Unable to read file size.".to_string()]);
+        }
+    };
+
+    match file.metadata() {
+        Ok(metadata) => {
+            if metadata.size() > max_size as u64 {
+                return Err(vec![format!(
+                    "File size is too large. Maximum {} MB.",
+                    max_size / (1024 * 1024)
+                )]);
+            }
+        }
+        Err(error) => {
+            eprintln!("Failed to read file metadata. Error: {}", error);
+            return Err(vec!["Unable to read file size.".to_string()]);
+        }
+    }
+    Ok(uploaded_file)
+}
+
+///
+/// Rejects uploaded images without an allowed image extension, then checks the file size and the
+/// dimensions declared in the image header.
+///
+fn validate_original_image(uploaded_file: UploadedFile) -> Result<UploadedFile, Vec<String>> {
+    temp_utils::guard_upload(&uploaded_file.temp_path);
+    if path_utils::sanitize_filename(&uploaded_file.filename).is_none() {
+        return Err(vec![format!(
+            "Unsupported file type. Allowed extensions: {}.",
+            path_utils::ALLOWED_IMAGE_EXTENSIONS.join(", ")
+        )]);
+    }
+
+    let uploaded_file = validate_file_size(uploaded_file, MAX_IMAGE_SIZE)?;
+
+    match image_utils::read_file_dimensions(&uploaded_file.temp_path) {
+        Ok(_) => Ok(uploaded_file),
+        Err(ImageError::Limits(_)) => Err(vec![format!(
+            "Image is too large. Maximum {} pixels per side and {} megapixels.",
+            image_utils::MAX_IMAGE_SIDE,
+            image_utils::MAX_IMAGE_PIXELS / 1_000_000
+        )]),
+        Err(error) => {
+            eprintln!("Failed to read uploaded image header. Error: {}", error);
+            Err(vec!["Unable to read image.".to_string()])
+        }
+    }
+}
+
+fn validate_correction_image(uploaded_file: UploadedFile) -> Result<UploadedFile, Vec<String>> {
+    temp_utils::guard_upload(&uploaded_file.temp_path);
+    validate_file_size(uploaded_file, MAX_CORRECTION_IMAGE_SIZE)
+}
+
 pub struct PublicImageUploadForm {
     pub task_group: UuidField<Uuid>,
     pub original_image: FileField<UploadedFile>,
     pub country: InputField<Option<String>>,
     pub user_identifier: InputField<Option<String>>,
+    /// JSON object kept with the task. Validated by `metadata_utils::parse_metadata`.
+    pub metadata: InputField<Option<String>>,
+    /// Comma separated tags. Validated by `metadata_utils::parse_tags`.
+    pub tags: InputField<Option<String>>,
+    /// `all` (default) or `mask`. See `TaskOutputs`.
+    pub outputs: InputField<Option<String>>,
+    /// Background preset or `#rrggbb` color. Validated by `metadata_utils::parse_background_hint`.
+    pub background_hint: InputField<Option<String>>,
+    /// Notified once every task of the task group finished. See `batch_notifications`.
+    pub notify_email: InputField<Option<String>>,
+    /// Hex SHA-256 of `original_image`, checked against the stored file so clients detect
+    /// corrupted uploads. Validated by `signature_utils::parse_sha256`.
+    pub sha256: InputField<Option<String>>,
 }
 
 impl FormValidator for PublicImageUploadForm {
     fn new() -> Self {
         Self {
             task_group: UuidField::new("task_group"),
-            original_image: FileField::new("original_image").post_validate(
-                |uploaded_file: UploadedFile| {
-                    let temp_path = &uploaded_file.temp_path;
-
-                    let file = match std::fs::File::open(temp_path) {
-                        Ok(file) => file,
-                        Err(error) => {
-                            eprintln!("Failed to open file. Error: {}", error);
-                            return Err(vec!["Unable to read file size.".to_string()]);
-                        }
-                    };
-
-                    match file.metadata() {
-                        Ok(metadata) => {
-                            if metadata.size() > 60 * 1024 * 1024 {
-                                return Err(vec!["File size is too large.".to_string()]);
-                            }
-                        }
-                        Err(error) => {
-                            eprintln!("Failed to read file metadata. Error: {}", error);
-                            return Err(vec!["Unable to read file size.".to_string()]);
-                        }
-                    }
-                    Ok(uploaded_file)
-                },
-            ),
+            original_image: FileField::new("original_image").post_validate(validate_original_image),
             country: InputField::new("country"),
             user_identifier: InputField::new("user_identifier"),
+            metadata: InputField::new("metadata"),
+            tags: InputField::new("tags"),
+            outputs: InputField::new("outputs"),
+            background_hint: InputField::new("background_hint"),
+            notify_email: InputField::new("notify_email"),
+            sha256: InputField::new("sha256"),
         }
     }
 
@@ -56,6 +125,93 @@ impl FormValidator for PublicImageUploadForm {
             self.original_image.wrap(),
             self.country.wrap(),
             self.user_identifier.wrap(),
+            self.metadata.wrap(),
+            self.tags.wrap(),
+            self.outputs.wrap(),
+            self.background_hint.wrap(),
+            self.notify_email.wrap(),
+            self.sha256.wrap(),
         ]
     }
 }
+
+///
+/// Brush-stroke corrections for an existing task. `correction_image` is a scribble mask PNG.
+///
+pub struct RefineMaskForm {
+    pub task_group: UuidField<Uuid>,
+    pub correction_image: FileField<UploadedFile>,
+}
+
+impl FormValidator for RefineMaskForm {
+    fn new() -> Self {
+        Self {
+            task_group: UuidField::new("task_group"),
+            correction_image: FileField::new("correction_image")
+                .post_validate(validate_correction_image),
+        }
+    }
+
+    fn form_fields(&mut self) -> racoon::forms::FormFields {
+        vec![self.task_group.wrap(), self.correction_image.wrap()]
+    }
+}
+
+///
+/// Name of a new organization.
+///
+pub struct OrganizationForm {
+    pub name: InputField<String>,
+}
+
+impl FormValidator for OrganizationForm {
+    fn new() -> Self {
+        Self {
+            name: InputField::new("name"),
+        }
+    }
+
+    fn form_fields(&mut self) -> racoon::forms::FormFields {
+        vec![self.name.wrap()]
+    }
+}
+
+///
+/// Upload signing key id added to an organization.
+///
+pub struct OrganizationApiKeyForm {
+    pub key_id: InputField<String>,
+}
+
+impl FormValidator for OrganizationApiKeyForm {
+    fn new() -> Self {
+        Self {
+            key_id: InputField::new("key_id"),
+        }
+    }
+
+    fn form_fields(&mut self) -> racoon::forms::FormFields {
+        vec![self.key_id.wrap()]
+    }
+}
+
+///
+/// Member added to an organization. `role` is `admin` or `viewer`, see `Role`.
+///
+pub struct OrganizationMemberForm {
+    pub email: InputField<String>,
+    pub role: InputField<String>,
+}
+
+impl FormValidator for OrganizationMemberForm {
+    fn new() -> Self {
+        Self {
+            email: InputField::new("email"),
+            role: InputField::new("role"),
+        }
+    }
+
+    fn form_fields(&mut self) -> racoon::forms::FormFields {
+        vec![self.email.wrap(), self.role.wrap()]
+    }
+}
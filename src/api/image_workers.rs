@@ -0,0 +1,144 @@
+use std::any::Any;
+use std::env;
+use std::io::ErrorKind;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::Semaphore;
+
+const MIB: u64 = 1024 * 1024;
+
+///
+/// Bounded pool for CPU heavy image work such as decoding, resizing and comparing. Jobs run on
+/// the blocking thread pool so request handlers and websocket broadcasts are never stalled, and
+/// at most `size` jobs run at once.
+///
+/// A job panicking or running longer than `timeout` fails only its own caller. A timed out job can
+/// not be stopped; it keeps its worker until it returns, so stuck jobs never grow the pool.
+///
+/// With a memory budget, jobs also reserve their estimated memory and queue until it fits, so
+/// several huge images arriving at once run one after another instead of exhausting memory.
+///
+pub struct ImageWorkers {
+    permits: Arc<Semaphore>,
+    timeout: Duration,
+    /// Unreserved memory budget in MiB. `None` without a budget.
+    memory: Option<Arc<Semaphore>>,
+    memory_budget_mib: u32,
+}
+
+impl ImageWorkers {
+    pub fn new(size: usize, timeout: Duration, memory_budget_mib: u32) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(size.max(1))),
+            timeout,
+            memory: match memory_budget_mib {
+                0 => None,
+                budget => Some(Arc::new(Semaphore::new(budget as usize))),
+            },
+            memory_budget_mib,
+        }
+    }
+
+    ///
+    /// Reads `IMAGE_WORKERS`, defaulting to the number of available CPUs,
+    /// `IMAGE_JOB_TIMEOUT_SECS`, defaulting to 30 seconds, and `IMAGE_MEMORY_BUDGET_MB`,
+    /// defaulting to 0 for no budget.
+    ///
+    pub fn from_env() -> Self {
+        let default_size = match std::thread::available_parallelism() {
+            Ok(size) => size.get(),
+            Err(_) => 2,
+        };
+
+        let size = match env::var("IMAGE_WORKERS") {
+            Ok(value) => value.parse::<usize>().unwrap_or(default_size),
+            Err(_) => default_size,
+        };
+
+        let timeout = match env::var("IMAGE_JOB_TIMEOUT_SECS") {
+            Ok(value) => value.parse::<u64>().unwrap_or(30),
+            Err(_) => 30,
+        };
+
+        let memory_budget_mib = match env::var("IMAGE_MEMORY_BUDGET_MB") {
+            Ok(value) => value.parse::<u32>().unwrap_or(0),
+            Err(_) => 0,
+        };
+
+        Self::new(size, Duration::from_secs(timeout), memory_budget_mib)
+    }
+
+    ///
+    /// Memory of the budget in use, in bytes. The next queued job may already hold part of it
+    /// while waiting for the rest.
+    ///
+    pub fn reserved_memory(&self) -> u64 {
+        match &self.memory {
+            Some(memory) => {
+                (self.memory_budget_mib as u64 - memory.available_permits() as u64) * MIB
+            }
+            None => 0,
+        }
+    }
+
+    ///
+    /// Runs `work` once its estimated peak `memory` in bytes fits the budget and a worker is free,
+    /// and returns its result. Estimate with `image_utils::estimate_memory`. Fails with `TimedOut`
+    /// when it runs longer than the job timeout and with `Other` when it panics.
+    ///
+    pub async fn run<F, T>(&self, memory: u64, work: F) -> std::io::Result<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        // A job estimated above the whole budget waits for all of it and runs alone.
+        let memory_permit = match &self.memory {
+            Some(budget) => {
+                let mib = memory.div_ceil(MIB).clamp(1, self.memory_budget_mib as u64) as u32;
+                if (budget.available_permits() as u32) < mib {
+                    log::debug!("Image job of {} MiB waits for the memory budget.", mib);
+                }
+                let permit = budget.clone().acquire_many_owned(mib).await;
+                Some(permit.map_err(std::io::Error::other)?)
+            }
+            None => None,
+        };
+
+        let permit = self
+            .permits
+            .clone()
+            .acquire_owned()
+            .await
+            .map_err(std::io::Error::other)?;
+
+        let job = tokio::task::spawn_blocking(move || {
+            // Released when the job returns, even if its caller stopped waiting.
+            let _permits = (permit, memory_permit);
+            work()
+        });
+
+        match tokio::time::timeout(self.timeout, job).await {
+            Ok(Ok(output)) => Ok(output),
+            Ok(Err(error)) if error.is_panic() => Err(std::io::Error::other(format!(
+                "Image job panicked. Error: {}",
+                panic_message(error.into_panic())
+            ))),
+            Ok(Err(error)) => Err(std::io::Error::other(error)),
+            Err(_) => Err(std::io::Error::new(
+                ErrorKind::TimedOut,
+                format!("Image job timed out after {:?}.", self.timeout),
+            )),
+        }
+    }
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown".to_string()
+    }
+}
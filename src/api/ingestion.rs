@@ -0,0 +1,330 @@
+use std::env;
+use std::fmt::{Display, Formatter};
+use std::future::Future;
+use std::path::PathBuf;
+use std::time::Instant;
+
+use image::ImageError;
+use serde::Deserialize;
+use serde_json::Value;
+use uuid::Uuid;
+
+use crate::api::billing::{self, QuotaError};
+use crate::api::canary;
+use crate::api::lifecycle::{LifecycleEvent, LifecycleStage};
+use crate::api::previews::{self, PreviewOf};
+use crate::api::task::{self, Dispatch};
+use crate::db::models::{
+    BackgroundRemoverTask, NewBackgroundRemoverTask, TaskGroupNotification, TaskOutputs,
+};
+use crate::utils::billing_utils::BillingAccount;
+use crate::utils::{image_utils, metadata_utils, path_utils, signature_utils};
+use crate::SharedContext;
+
+/// Ingested images are limited like uploads.
+pub const MAX_IMAGE_SIZE: usize = 60 * 1024 * 1024;
+
+///
+/// Task submitted through a queue instead of an upload. Queue messages name the image with
+/// `image_url` or `object_key`, see `queue_ingest`; `s3_ingest` reads it from the notified
+/// object. The other fields are the options of the upload form.
+///
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Submission {
+    /// Key of the created task, so a redelivered submission creates its task once. A new key
+    /// when missing.
+    pub key: Option<Uuid>,
+    #[cfg_attr(not(feature = "nats-ingest"), allow(dead_code))]
+    pub image_url: Option<String>,
+    #[cfg_attr(not(feature = "nats-ingest"), allow(dead_code))]
+    pub object_key: Option<String>,
+    /// A new task group when missing.
+    pub task_group: Option<Uuid>,
+    /// Upload signing key id the task is billed to and stored under, like signed uploads.
+    pub key_id: Option<String>,
+    pub filename: Option<String>,
+    pub country: Option<String>,
+    pub user_identifier: Option<String>,
+    pub metadata: Option<Value>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    pub outputs: Option<String>,
+    pub background_hint: Option<String>,
+    pub notify_email: Option<String>,
+    /// Never served from the outputs of an earlier task, like uploads sent with `X-No-Dedup`.
+    #[serde(default)]
+    pub no_dedup: bool,
+}
+
+///
+/// Why a submission created no task.
+///
+#[derive(Debug)]
+pub enum IngestError {
+    /// The submission can never succeed, e.g. it is malformed or its image is missing.
+    Invalid(String),
+    /// Billing rejected the submission.
+    Rejected(QuotaError),
+    /// A dependency failed, so the submission may succeed later.
+    Unavailable(String),
+}
+
+impl IngestError {
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, IngestError::Unavailable(_))
+    }
+}
+
+impl Display for IngestError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IngestError::Invalid(reason) => write!(f, "Invalid submission. {}", reason),
+            IngestError::Rejected(error) => write!(f, "Rejected: {}.", error.status_code()),
+            IngestError::Unavailable(reason) => write!(f, "Unavailable. {}", reason),
+        }
+    }
+}
+
+///
+/// Creates the task of `submission` like an upload and sends it for processing. `image` reads the
+/// image once the submission was checked, so redelivered submissions are not read again. Returns
+/// the task key, also when the task already existed. A task which could not be sent is kept with
+/// its failure recorded, like uploads whose processing failed.
+///
+pub async fn create_task<F>(
+    shared_context: &SharedContext,
+    submission: Submission,
+    image: F,
+) -> Result<Uuid, IngestError>
+where
+    F: Future<Output = Result<Vec<u8>, IngestError>>,
+{
+    let options = TaskOptions::parse(shared_context, &submission)?;
+    let task_id = submission.key.unwrap_or_else(Uuid::new_v4);
+
+    // Redelivered submissions find their task.
+    match BackgroundRemoverTask::fetch(shared_context.db_wrapper.clone(), &task_id).await {
+        Ok(_) => return Ok(task_id),
+        Err(sqlx::Error::RowNotFound) => {}
+        Err(error) => return Err(IngestError::Unavailable(error.to_string())),
+    }
+
+    let account = match &submission.key_id {
+        Some(key_id) => Some(
+            billing::billing_account(shared_context.db_wrapper.clone(), key_id)
+                .await
+                .map_err(|error| IngestError::Unavailable(error.to_string()))?,
+        ),
+        None => None,
+    };
+    let priority = match &account {
+        Some(account) => billing::check_quota(shared_context, account)
+            .await
+            .map_err(IngestError::Rejected)?,
+        None => 0,
+    };
+    let tenant = account.as_ref().map(BillingAccount::media_prefix);
+
+    let data = image.await?;
+    let (data, filename, original_format) = tokio::task::spawn_blocking(move || {
+        inspect(&data).map(|(filename, format)| (data, filename, format))
+    })
+    .await
+    .map_err(|error| IngestError::Unavailable(error.to_string()))??;
+
+    let original_image_save_path = path_utils::generate_save_path(
+        tenant.as_deref(),
+        path_utils::ForImage::OriginalImage(&task_id, &filename),
+    )
+    .await
+    .map_err(|error| IngestError::Unavailable(error.to_string()))?;
+    tokio::fs::write(&original_image_save_path, &data)
+        .await
+        .map_err(|error| IngestError::Unavailable(error.to_string()))?;
+
+    let media_root = env::var("MEDIA_ROOT")
+        .map(PathBuf::from)
+        .map_err(|error| IngestError::Unavailable(error.to_string()))?;
+    let relative_original_image_media_url =
+        path_utils::relative_media_url_from_full_path(&media_root, &original_image_save_path);
+
+    // Stored pseudonymized when signing keys are configured. See `Keyring`.
+    let user_identifier = match (submission.user_identifier, shared_context.secrets.keyring()) {
+        (Some(user_identifier), Some(keyring)) => {
+            Some(keyring.hash_user_identifier(&user_identifier))
+        }
+        (user_identifier, _) => user_identifier,
+    };
+
+    // Display name only, like the client filename of uploads.
+    let original_filename = submission
+        .filename
+        .map(|filename| filename.chars().take(255).collect());
+
+    let config = shared_context.config.load_full();
+    let free_tier = submission
+        .key_id
+        .as_ref()
+        .is_some_and(|kid| config.free_tier_key_ids.contains(kid));
+
+    let new_task = NewBackgroundRemoverTask {
+        country: submission.country,
+        original_filename,
+        key: task_id,
+        original_image_path: relative_original_image_media_url
+            .to_string_lossy()
+            .to_string(),
+        preview_original_image_path: relative_original_image_media_url
+            .to_string_lossy()
+            .to_string(),
+        task_group: submission.task_group.unwrap_or_else(Uuid::new_v4),
+        user_identifier,
+        metadata: options.metadata,
+        tags: options.tags,
+        original_format: Some(original_format.to_string()),
+        outputs: options.outputs,
+        background_hint: options.background_hint,
+        preview_settings: config.preview_options().fingerprint(),
+        free_tier,
+        api_key_id: submission.key_id,
+        priority,
+        tenant,
+        content_sha256: (!submission.no_dedup).then(|| signature_utils::sha256_hex(&data)),
+        // Submissions arrive parsed, so there is no upload stage.
+        timings: None,
+    };
+
+    BackgroundRemoverTask::insert_new_task(shared_context.db_wrapper.clone(), &new_task)
+        .await
+        .map_err(|error| IngestError::Unavailable(error.to_string()))?;
+    // Ingested tasks are queued once stored; fetching the image came before.
+    let queued = Instant::now();
+    shared_context.lifecycle.emit(LifecycleEvent::new(
+        LifecycleStage::Created,
+        task_id,
+        new_task.task_group,
+    ));
+
+    if let Some(email) = &options.notify_email {
+        let db_wrapper = shared_context.db_wrapper.clone();
+        if let Err(error) =
+            TaskGroupNotification::register(db_wrapper, &new_task.task_group, email).await
+        {
+            eprintln!(
+                "Failed to register task group notification. Error: {}",
+                error
+            );
+        }
+    }
+
+    tokio::spawn(previews::generate(
+        shared_context.clone(),
+        task_id,
+        PreviewOf::Original,
+    ));
+
+    let instance =
+        match BackgroundRemoverTask::fetch(shared_context.db_wrapper.clone(), &task_id).await {
+            Ok(instance) => instance,
+            Err(error) => {
+                // Dispatched once a client of the task group asks for it.
+                eprintln!("Failed to fetch ingested task. Error: {}", error);
+                return Ok(task_id);
+            }
+        };
+    match task::dispatch(shared_context, &instance, queued).await {
+        Dispatch::Sent => canary::dispatch(shared_context, &instance),
+        Dispatch::Held | Dispatch::Deduplicated => {}
+        Dispatch::Failed { status_code, .. } => {
+            eprintln!(
+                "Failed to dispatch ingested task {}: {}",
+                task_id, status_code
+            );
+        }
+    }
+    Ok(task_id)
+}
+
+///
+/// Options of a submission, validated like the fields of the upload form.
+///
+struct TaskOptions {
+    metadata: Option<Value>,
+    tags: Vec<String>,
+    outputs: TaskOutputs,
+    background_hint: Option<String>,
+    notify_email: Option<String>,
+}
+
+impl TaskOptions {
+    fn parse(shared_context: &SharedContext, submission: &Submission) -> Result<Self, IngestError> {
+        let metadata = match &submission.metadata {
+            Some(metadata) => Some(
+                metadata_utils::parse_metadata(&metadata.to_string())
+                    .map_err(IngestError::Invalid)?,
+            ),
+            None => None,
+        };
+        let tags =
+            metadata_utils::parse_tags(&submission.tags.join(",")).map_err(IngestError::Invalid)?;
+
+        let outputs = match &submission.outputs {
+            Some(outputs) => TaskOutputs::parse(outputs.trim()).ok_or_else(|| {
+                IngestError::Invalid("Outputs must be either all or mask.".to_string())
+            })?,
+            None => TaskOutputs::All,
+        };
+
+        let background_hint = match &submission.background_hint {
+            Some(background_hint) => Some(
+                metadata_utils::parse_background_hint(background_hint)
+                    .map_err(IngestError::Invalid)?,
+            ),
+            None => None,
+        };
+
+        let notify_email = match (&submission.notify_email, &shared_context.mailer) {
+            (Some(email), Some(_)) => {
+                Some(metadata_utils::parse_notify_email(email).map_err(IngestError::Invalid)?)
+            }
+            (Some(_), None) => {
+                return Err(IngestError::Invalid(
+                    "Email notifications are not enabled.".to_string(),
+                ))
+            }
+            (None, _) => None,
+        };
+
+        Ok(Self {
+            metadata,
+            tags,
+            outputs,
+            background_hint,
+            notify_email,
+        })
+    }
+}
+
+///
+/// Checks the format and dimensions of fetched image `data` like uploads. Returns its content
+/// addressed filename and its detected format.
+///
+fn inspect(data: &[u8]) -> Result<(String, &'static str), IngestError> {
+    let format = image_utils::detect_format(data)
+        .filter(|format| path_utils::ALLOWED_IMAGE_EXTENSIONS.contains(format))
+        .ok_or_else(|| IngestError::Invalid("Unsupported image format.".to_string()))?;
+
+    match image_utils::read_dimensions(data) {
+        Ok(_) => Ok((path_utils::content_filename(data, format), format)),
+        Err(ImageError::Limits(_)) => Err(IngestError::Invalid(format!(
+            "Image is too large. Maximum {} pixels per side and {} megapixels.",
+            image_utils::MAX_IMAGE_SIDE,
+            image_utils::MAX_IMAGE_PIXELS / 1_000_000
+        ))),
+        Err(error) => Err(IngestError::Invalid(format!(
+            "Unable to read image. {}",
+            error
+        ))),
+    }
+}
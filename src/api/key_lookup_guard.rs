@@ -0,0 +1,57 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config::AppConfig;
+use crate::utils::lockout_utils::{FailureWindows, LockoutLimits};
+
+///
+/// Counts failed task key lookups per client IP and bans IPs failing too often for a while.
+/// Task keys are the only protection of user images, so guessing them must be slow.
+///
+/// Limits are read from `AppConfig` on every call, so reloaded settings apply immediately.
+///
+pub struct KeyLookupGuard {
+    windows: Mutex<FailureWindows>,
+}
+
+impl Default for KeyLookupGuard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl KeyLookupGuard {
+    pub fn new() -> Self {
+        Self {
+            windows: Mutex::new(FailureWindows::new(Instant::now())),
+        }
+    }
+
+    ///
+    /// Returns the remaining ban of `ip`, `None` when it may look up keys.
+    ///
+    pub fn banned_for(&self, ip: &str) -> Option<Duration> {
+        let windows = self.windows.lock().ok()?;
+        windows.banned_for(ip, Instant::now())
+    }
+
+    ///
+    /// Records a lookup of a missing key or of a task owned by someone else. Returns true when
+    /// this failure got `ip` banned.
+    ///
+    pub fn record_failure(&self, ip: &str, config: &AppConfig) -> bool {
+        if config.key_lookup_max_failures == 0 {
+            return false;
+        }
+
+        let limits = LockoutLimits {
+            max_failures: config.key_lookup_max_failures,
+            window: config.key_lookup_window,
+            ban: config.key_lookup_ban,
+        };
+        match self.windows.lock() {
+            Ok(mut windows) => windows.record(ip, Instant::now(), &limits),
+            Err(_) => false,
+        }
+    }
+}
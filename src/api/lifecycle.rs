@@ -0,0 +1,87 @@
+use chrono::Utc;
+use serde::Serialize;
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+/// Events kept for subscribers which fell behind. Older events are skipped.
+const EVENT_BUFFER_SIZE: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LifecycleStage {
+    /// Stored from an upload or a queue submission.
+    Created,
+    /// Sent to a BP server.
+    Sent,
+    /// Progress reported by the BP server.
+    Progress,
+    /// Outputs stored.
+    Completed,
+    /// Processing failed or timed out.
+    Failed,
+}
+
+///
+/// Step in the life of a task, published by `TaskLifecycle`.
+///
+#[derive(Debug, Clone, Serialize)]
+pub struct LifecycleEvent {
+    pub event: LifecycleStage,
+    pub task_key: Uuid,
+    pub task_group: Uuid,
+    /// Status code of progress and failures, e.g. `bp_send_timeout`.
+    pub status_code: Option<String>,
+    pub timestamp: String,
+}
+
+impl LifecycleEvent {
+    pub fn new(event: LifecycleStage, task_key: Uuid, task_group: Uuid) -> Self {
+        Self {
+            event,
+            task_key,
+            task_group,
+            status_code: None,
+            timestamp: Utc::now().to_rfc3339(),
+        }
+    }
+
+    pub fn status_code(mut self, status_code: &str) -> Self {
+        self.status_code = Some(status_code.to_string());
+        self
+    }
+}
+
+///
+/// In-process bus of task lifecycle events, the only one of the service. Subscribed by the admin
+/// feed and by publishers to downstream services such as `kafka_events`, `s3_ingest` and
+/// `drop_folder`. Events are dropped when nobody subscribed.
+///
+pub struct TaskLifecycle {
+    events: broadcast::Sender<LifecycleEvent>,
+}
+
+impl Default for TaskLifecycle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TaskLifecycle {
+    pub fn new() -> Self {
+        Self {
+            events: broadcast::channel(EVENT_BUFFER_SIZE).0,
+        }
+    }
+
+    pub fn emit(&self, event: LifecycleEvent) {
+        // Fails only when nobody subscribed.
+        let _ = self.events.send(event);
+    }
+
+    ///
+    /// Returns receiver of every event emitted from now on.
+    ///
+    pub fn subscribe(&self) -> broadcast::Receiver<LifecycleEvent> {
+        self.events.subscribe()
+    }
+}
@@ -0,0 +1,155 @@
+use std::sync::Arc;
+
+use chrono::Utc;
+use racoon::core::request::Request;
+use racoon::core::response::status::ResponseStatus;
+use racoon::core::response::{HttpResponse, JsonResponse, Response};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::api::error_codes::ErrorCode;
+use crate::api::shortcuts;
+use crate::config::AppConfig;
+use crate::db::models::AppSetting;
+use crate::utils::envelope_utils::ApiEnvelope;
+use crate::utils::maintenance_utils::{self, Maintenance};
+use crate::SharedContext;
+
+#[derive(Deserialize)]
+struct MaintenanceRequest {
+    /// RFC 3339, e.g. `2024-06-01T12:00:00Z`.
+    estimated_end: Option<String>,
+    message: Option<String>,
+}
+
+///
+/// Whether `path` is an admin endpoint, which stays reachable during maintenance.
+///
+pub fn is_admin_path(path: &str) -> bool {
+    path.starts_with("/v1/admin/") || path.starts_with("/ws/admin/")
+}
+
+///
+/// Answer of public endpoints during maintenance.
+///
+pub fn unavailable(maintenance: &Maintenance) -> Response {
+    let mut response = JsonResponse::with_status(503, "Service Unavailable").body(
+        ApiEnvelope::failed(ErrorCode::Maintenance)
+            .message(maintenance.message())
+            .field(
+                "estimated_end",
+                maintenance.estimated_end.map(|end| end.to_rfc3339()),
+            )
+            .to_value(),
+    );
+    response.get_headers().set(
+        "Retry-After",
+        maintenance.retry_after_secs(Utc::now()).to_string(),
+    );
+    response
+}
+
+///
+/// Returns the maintenance state (`GET`), starts maintenance with an optional JSON body of
+/// `estimated_end` and `message` (`POST`) or ends it (`DELETE`). Admin token only.
+///
+/// The state is stored in `app_config`, so it survives restarts and reaches other instances
+/// with the next settings reload. This instance applies it right away.
+///
+pub async fn maintenance_view(request: Request) -> Response {
+    if !shortcuts::is_admin(&request) {
+        return shortcuts::unauthorized();
+    }
+
+    let shared_context: &SharedContext = request.context().expect("SharedContext is missing.");
+    let settings = match request.method.as_str() {
+        "GET" => return state(shared_context.config.load().maintenance.as_ref()),
+        "POST" => {
+            let max_size = shared_context.config.load().max_json_body_size;
+            let body = match shortcuts::read_body(&request, max_size).await {
+                Some(body) if body.is_empty() => b"{}".to_vec(),
+                Some(body) => body,
+                None => return bad_request("The body must be JSON, sent with Content-Length."),
+            };
+            let maintenance: MaintenanceRequest = match serde_json::from_slice(&body) {
+                Ok(maintenance) => maintenance,
+                Err(error) => return bad_request(&error.to_string()),
+            };
+
+            let estimated_end = match maintenance.estimated_end {
+                Some(value) => match maintenance_utils::parse_estimated_end(&value) {
+                    Some(end) => Some(end.to_rfc3339()),
+                    None => return bad_request("estimated_end must be an RFC 3339 datetime."),
+                },
+                None => None,
+            };
+            let message = maintenance.message.filter(|message| !message.is_empty());
+            vec![
+                ("MAINTENANCE_MODE", Some("true".to_string())),
+                ("MAINTENANCE_ESTIMATED_END", estimated_end),
+                ("MAINTENANCE_MESSAGE", message),
+            ]
+        }
+        // Stored as false rather than deleted, so `MAINTENANCE_MODE` of the environment
+        // can't switch it back on.
+        "DELETE" => vec![
+            ("MAINTENANCE_MODE", Some("false".to_string())),
+            ("MAINTENANCE_ESTIMATED_END", None),
+            ("MAINTENANCE_MESSAGE", None),
+        ],
+        _ => return HttpResponse::ok().body("This request method is not supported."),
+    };
+
+    let settings: Vec<(&str, Option<&str>)> = settings
+        .iter()
+        .map(|(name, value)| (*name, value.as_deref()))
+        .collect();
+    let db_wrapper = shared_context.db_wrapper.clone();
+    if let Err(error) = AppSetting::set_all(db_wrapper.clone(), &settings).await {
+        log::error!("Failed to store maintenance settings. Error: {}", error);
+        return internal_server_error();
+    }
+
+    let overrides = match AppSetting::fetch_all(db_wrapper).await {
+        Ok(overrides) => overrides,
+        Err(error) => {
+            log::error!("Failed to read settings. Error: {}", error);
+            return internal_server_error();
+        }
+    };
+    let config = AppConfig::load(&overrides);
+    match &config.maintenance {
+        Some(maintenance) => log::info!("Maintenance started: {:?}", maintenance),
+        None => log::info!("Maintenance ended."),
+    }
+    let response = state(config.maintenance.as_ref());
+    shared_context.config.store(Arc::new(config));
+    response
+}
+
+fn state(maintenance: Option<&Maintenance>) -> Response {
+    JsonResponse::ok().body(
+        ApiEnvelope::success("maintenance")
+            .data(json!({
+                "enabled": maintenance.is_some(),
+                "estimated_end": maintenance
+                    .and_then(|maintenance| maintenance.estimated_end)
+                    .map(|end| end.to_rfc3339()),
+                "message": maintenance.map(Maintenance::message),
+            }))
+            .to_value(),
+    )
+}
+
+fn bad_request(message: &str) -> Response {
+    JsonResponse::bad_request().body(
+        ApiEnvelope::failed(ErrorCode::BadRequest)
+            .message(message)
+            .to_value(),
+    )
+}
+
+fn internal_server_error() -> Response {
+    JsonResponse::internal_server_error()
+        .body(ApiEnvelope::failed(ErrorCode::InternalServerError).to_value())
+}
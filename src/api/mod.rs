@@ -1,24 +1,71 @@
 use std::env;
+use std::time::{Duration, Instant};
 
-use racoon::core::headers::HeaderValue;
+use racoon::core::headers::{HeaderValue, Headers};
 use racoon::core::path::Path;
 use racoon::core::path::View;
 use racoon::core::request::Request;
-use racoon::core::response::Response;
+use racoon::core::response::{JsonResponse, Response};
 use racoon::core::server::Server;
 use racoon::wrap_view;
 
+use tokio::sync::watch;
+
+use crate::build_info;
+use crate::config::AppConfig;
+use crate::db;
+use crate::metrics::Metrics;
+use crate::utils::body_utils::{self, BodyRejection};
+use crate::utils::envelope_utils::ApiEnvelope;
+use crate::utils::ip_utils;
+use crate::utils::timing_utils;
 use crate::SharedContext;
 
+use error_codes::ErrorCode;
+use tls::TlsConfig;
+
+pub mod admin_feed;
+pub mod admin_views;
+pub mod batch_notifications;
+pub mod billing;
+pub mod bp_failover;
+#[cfg(feature = "bp-replay")]
+pub mod bp_replay;
+pub mod bp_routing;
+pub mod canary;
+#[cfg(feature = "chaos")]
+pub mod chaos;
+pub mod contact_sheets;
+pub mod erasure;
+pub mod error_codes;
 pub mod forms;
+pub mod image_workers;
+pub mod ingestion;
+pub mod key_lookup_guard;
+pub mod lifecycle;
+pub mod maintenance;
+pub mod organizations;
+pub mod plugin;
+pub mod previews;
+pub mod progress;
+pub mod processing_times;
+pub mod scaling;
 pub mod shortcuts;
+pub mod store_sync;
 pub mod task;
+pub mod task_events;
+pub mod task_json_cache;
+pub mod tls;
+pub mod upload_signatures;
 pub mod urls;
+pub mod user_exports;
 pub mod views;
 pub mod ws_clients;
+pub mod ws_messages;
 
 pub async fn middleware(request: Request, view: Option<View>) -> Response {
-    println!("Client IP: {:?}", request.remote_addr().await);
+    let started = Instant::now();
+    println!("Client IP: {:?}", shortcuts::client_ip(&request).await);
 
     let shared_context: &SharedContext = request.context().expect("SharedContext is missing.");
     let pid = std::process::id();
@@ -41,24 +88,232 @@ pub async fn middleware(request: Request, view: Option<View>) -> Response {
         _ => {}
     }
 
-    let mut response = Path::resolve(request, view).await;
+    // Instance id is for debugging only, so it is not revealed to the public.
+    let is_admin = shortcuts::is_admin(&request);
+    let config = shared_context.config.load_full();
+
+    // Bodies are checked by their declared size before any view reads them, whatever the view.
+    let header = |name: &str| {
+        request
+            .headers
+            .value(name)
+            .map(|value| value.as_str().to_string())
+    };
+    let body_check = body_utils::check_body(
+        &config.body_limits(),
+        header("Content-Type").as_deref(),
+        header("Content-Length").as_deref(),
+        header("Transfer-Encoding").as_deref(),
+    );
+
+    // Public endpoints are closed during maintenance. Admin endpoints, requests with the admin
+    // token and CORS preflights, which can't carry the error to the plugin, are let through.
+    let is_plugin = plugin::is_plugin_path(&request.path);
+    let is_preflight = is_plugin && request.method == "OPTIONS";
+    let ongoing_maintenance = config
+        .maintenance
+        .as_ref()
+        .filter(|_| !is_admin && !is_preflight && !maintenance::is_admin_path(&request.path));
+
+    // Signed uploads are checked before the body is read by the upload view.
+    let is_upload = request.method == "POST" && upload_signatures::is_upload_path(&request.path);
+    let request_check = match (ongoing_maintenance, body_check) {
+        (Some(ongoing_maintenance), _) => {
+            shared_context
+                .metrics
+                .increment("maintenance_rejections_total");
+            Err(maintenance::unavailable(ongoing_maintenance))
+        }
+        (None, Err(rejection)) => {
+            shared_context
+                .metrics
+                .increment("request_body_rejections_total");
+            Err(body_rejected(rejection))
+        }
+        (None, Ok(())) if is_upload => upload_signatures::verify(&request, shared_context).await,
+        (None, Ok(())) => Ok(()),
+    };
+
+    // Kept before the request moves into its view.
+    let metrics = shared_context.metrics.clone();
+    let method = request.method.clone();
+    let matched_route = urls::match_route(&request.path);
+
+    // Design tool plugins run in browsers, which preflight their cross-origin requests.
+    let (mut response, db_time) = match request_check {
+        Ok(()) if is_preflight => (plugin::preflight(), Duration::ZERO),
+        Ok(()) => db::measure_db_time(Path::resolve(request, view)).await,
+        Err(response) => (response, Duration::ZERO),
+    };
     let headers = response.get_headers();
-    let sid = env::var("SID").unwrap();
-    headers.set("SID", sid);
+    if is_admin {
+        if let Ok(sid) = env::var("SID") {
+            headers.set("SID", sid);
+        }
+        headers.set("Build-Version", build_info::version());
+    }
     headers.set("Access-Control-Allow-Origin", "*");
     headers.set("Access-Control-Allow-Methods", "GET, POST, PUT, DELETE");
+    if is_plugin {
+        plugin::set_cors_headers(headers);
+    }
+    set_security_headers(headers, &config);
+
+    let elapsed = started.elapsed();
+    record_timing(&metrics, &config, &method, matched_route, elapsed, db_time);
     response
 }
 
+///
+/// Observes the request latency by route and logs the request if slower than
+/// `SLOW_REQUEST_MILLIS`. Websockets are left out, as their view returns when they close.
+///
+fn record_timing(
+    metrics: &Metrics,
+    config: &AppConfig,
+    method: &str,
+    matched_route: Option<(&'static str, Vec<(String, String)>)>,
+    elapsed: Duration,
+    db_time: Duration,
+) {
+    let (route, params) = matched_route.unwrap_or((timing_utils::UNMATCHED_ROUTE, vec![]));
+    if route.starts_with("/ws/") {
+        return;
+    }
+
+    // Any token is accepted as method, so others share a label like unmatched paths do.
+    let method_label = match method {
+        "GET" | "POST" | "PUT" | "PATCH" | "DELETE" | "HEAD" | "OPTIONS" => method,
+        _ => "OTHER",
+    };
+    metrics.observe(
+        "http_request_duration_seconds",
+        &format!("route=\"{}\",method=\"{}\"", route, method_label),
+        timing_utils::LATENCY_BUCKETS,
+        elapsed.as_secs_f64(),
+    );
+
+    let is_slow = config
+        .slow_request_threshold
+        .is_some_and(|threshold| elapsed >= threshold);
+    if is_slow {
+        metrics.increment("slow_requests_total");
+        log::warn!(
+            "Slow request: {} {} took {} ms, {} ms in queries. Params: {}",
+            method,
+            route,
+            elapsed.as_millis(),
+            db_time.as_millis(),
+            timing_utils::sanitize_params(&params)
+        );
+    }
+}
+
+///
+/// Response to a request whose body is refused by `body_utils::check_body`.
+///
+fn body_rejected(rejection: BodyRejection) -> Response {
+    match rejection {
+        BodyRejection::TooLarge { kind, max_size } => {
+            JsonResponse::with_status(413, "Payload Too Large").body(
+                ApiEnvelope::failed(ErrorCode::PayloadTooLarge)
+                    .message(format!(
+                        "Request body exceeds the limit of {} bytes for {} bodies.",
+                        max_size,
+                        kind.name()
+                    ))
+                    .field("max_size", max_size)
+                    .to_value(),
+            )
+        }
+        BodyRejection::LengthRequired => JsonResponse::with_status(411, "Length Required").body(
+            ApiEnvelope::failed(ErrorCode::LengthRequired)
+                .message("Request bodies must be sent with Content-Length.")
+                .to_value(),
+        ),
+        BodyRejection::InvalidLength => JsonResponse::bad_request().body(
+            ApiEnvelope::failed(ErrorCode::BadRequest)
+                .message("Content-Length must be a number.")
+                .to_value(),
+        ),
+    }
+}
+
+///
+/// Headers hardening browsers against downgrade, MIME sniffing, referrer leaks and framing.
+///
+fn set_security_headers(headers: &mut Headers, config: &AppConfig) {
+    if config.hsts_max_age_secs > 0 {
+        headers.set(
+            "Strict-Transport-Security",
+            format!("max-age={}; includeSubDomains", config.hsts_max_age_secs),
+        );
+    }
+    headers.set("X-Content-Type-Options", "nosniff");
+    headers.set("Referrer-Policy", "no-referrer");
+    if let Some(policy) = &config.content_security_policy {
+        headers.set("Content-Security-Policy", policy.as_str());
+    }
+}
+
 pub async fn run_server(shared_context: SharedContext) -> std::io::Result<()> {
     let bind_address =
         env::var("BIND_ADDRESS").expect("BIND_ADDRESS value not present in not found in environment variable.");
 
-    // Available url routes served by the server.
-    let urls = urls::register_urls();
+    // Hostnames are resolved here, so names with only AAAA records work too. `[::]:<port>`
+    // accepts IPv4 clients as well unless the host disables dual-stack sockets.
+    let bind_address = match ip_utils::resolve(&bind_address).await?.first() {
+        Some(address) => {
+            println!("Binding to {} for BIND_ADDRESS {}.", address, bind_address);
+            address.to_string()
+        }
+        None => return Err(std::io::Error::other("BIND_ADDRESS did not resolve.")),
+    };
 
     Server::enable_logging();
 
+    let tls_config = match TlsConfig::from_env() {
+        Some(tls_config) => tls_config,
+        None => return run_plain_server(bind_address, shared_context).await,
+    };
+
+    // Refuse to start with broken certificates instead of failing on every handshake.
+    tls_config.validate()?;
+
+    let (changed_sender, mut changed) = watch::channel(0u64);
+    tokio::spawn(tls::watch_certificates(tls_config.clone(), changed_sender));
+
+    loop {
+        let mut server = tokio::spawn(run_tls_server(
+            bind_address.clone(),
+            tls_config.clone(),
+            shared_context.clone(),
+        ));
+
+        tokio::select! {
+            result = &mut server => {
+                return match result {
+                    Ok(result) => result,
+                    Err(error) => Err(std::io::Error::other(error)),
+                };
+            }
+            _ = changed.changed() => {
+                // Stops accepting with the old certificate. Open connections finish on their own.
+                server.abort();
+                let _ = server.await;
+                println!("Restarting HTTPS listener with renewed certificate.");
+            }
+        }
+    }
+}
+
+async fn run_plain_server(
+    bind_address: String,
+    shared_context: SharedContext,
+) -> std::io::Result<()> {
+    // Available url routes served by the server.
+    let urls = urls::register_urls();
+
     Server::bind(bind_address)
         .context(shared_context)
         .wrap(wrap_view!(middleware))
@@ -68,3 +323,25 @@ pub async fn run_server(shared_context: SharedContext) -> std::io::Result<()> {
 
     Ok(())
 }
+
+async fn run_tls_server(
+    bind_address: String,
+    tls_config: TlsConfig,
+    shared_context: SharedContext,
+) -> std::io::Result<()> {
+    // Available url routes served by the server.
+    let urls = urls::register_urls();
+
+    Server::bind_tls(
+        bind_address,
+        tls_config.cert_path.to_string_lossy().to_string(),
+        tls_config.key_path.to_string_lossy().to_string(),
+    )
+    .context(shared_context)
+    .wrap(wrap_view!(middleware))
+    .urls(urls)
+    .run()
+    .await?;
+
+    Ok(())
+}
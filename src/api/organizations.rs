@@ -0,0 +1,519 @@
+use racoon::core::headers::HeaderValue;
+use racoon::core::request::Request;
+use racoon::core::response::status::ResponseStatus;
+use racoon::core::response::{HttpResponse, JsonResponse, Response};
+use racoon::forms::FormValidator;
+use serde_json::{json, Value};
+
+use crate::api::error_codes::ErrorCode;
+use crate::api::forms::{OrganizationApiKeyForm, OrganizationForm, OrganizationMemberForm};
+use crate::api::{billing, shortcuts, upload_signatures};
+use crate::db::models::{ApiKeyUsage, Organization, OrganizationMember};
+use crate::utils::billing_utils::{BillingAccount, TenantAccess};
+use crate::utils::envelope_utils::ApiEnvelope;
+use crate::utils::metadata_utils;
+use crate::utils::organization_utils::{self, Role};
+use crate::SharedContext;
+
+///
+/// Member authenticated by `Authorization: Token <member token>`. Requests without a token are
+/// `Ok(None)`; a token matching no member is rejected. Check `shortcuts::is_admin` first, as the
+/// admin token uses the same header.
+///
+pub async fn authenticated_member(
+    request: &Request,
+) -> Result<Option<OrganizationMember>, Response> {
+    let token = match request.headers.value("Authorization") {
+        Some(value) => match value.as_str().strip_prefix("Token ") {
+            Some(token) => token.trim().to_string(),
+            None => return Ok(None),
+        },
+        None => return Ok(None),
+    };
+
+    let shared_context: &SharedContext = request.context().expect("SharedContext is missing.");
+    let token_hash = organization_utils::hash_member_token(&token);
+    match OrganizationMember::fetch_by_token_hash(shared_context.db_wrapper.clone(), &token_hash)
+        .await
+    {
+        Ok(Some(member)) => Ok(Some(member)),
+        Ok(None) => Err(JsonResponse::unauthorized().body(
+            ApiEnvelope::failed(ErrorCode::Unauthorized)
+                .message("Invalid token.")
+                .to_value(),
+        )),
+        Err(error) => {
+            eprintln!("Failed to fetch organization member. Error: {}", error);
+            Err(internal_server_error())
+        }
+    }
+}
+
+///
+/// Billing account the request is authenticated as: the organization of a member token, or the
+/// account of the key signing the request, signed like uploads. Requests without either are
+/// `Ok(None)`; invalid credentials are rejected.
+///
+pub async fn authenticated_account(request: &Request) -> Result<Option<BillingAccount>, Response> {
+    if let Some(member) = authenticated_member(request).await? {
+        return Ok(Some(BillingAccount::Organization(member.organization_id)));
+    }
+
+    let key_id = match upload_signatures::key_id(request) {
+        Some(key_id) => key_id,
+        None => return Ok(None),
+    };
+    let shared_context: &SharedContext = request.context().expect("SharedContext is missing.");
+    upload_signatures::verify(request, shared_context).await?;
+
+    match billing::billing_account(shared_context.db_wrapper.clone(), &key_id).await {
+        Ok(account) => Ok(Some(account)),
+        Err(error) => {
+            eprintln!("Failed to fetch billing account. Error: {}", error);
+            Err(internal_server_error())
+        }
+    }
+}
+
+///
+/// Tenants whose tasks the request may read: every tenant with the admin token, the tenant of
+/// `authenticated_account`, or none without credentials.
+///
+pub async fn tenant_access(request: &Request) -> Result<TenantAccess, Response> {
+    if shortcuts::is_admin(request) {
+        return Ok(TenantAccess::Admin);
+    }
+
+    Ok(match authenticated_account(request).await? {
+        Some(account) => TenantAccess::Tenant(account.media_prefix()),
+        None => TenantAccess::Anonymous,
+    })
+}
+
+///
+/// Checks that the request may act on organization `organization_id` with the `required` role.
+/// The admin token may act on every organization.
+///
+async fn check_access(
+    request: &Request,
+    organization_id: i64,
+    required: Role,
+) -> Result<(), Response> {
+    if shortcuts::is_admin(request) {
+        return Ok(());
+    }
+
+    let member = match authenticated_member(request).await? {
+        Some(member) => member,
+        None => return Err(shortcuts::unauthorized()),
+    };
+
+    // Members of other organizations don't learn whether this one exists.
+    if member.organization_id != organization_id {
+        return Err(not_found());
+    }
+
+    if !member.role().allows(required) {
+        return Err(JsonResponse::with_status(403, "Forbidden").body(
+            ApiEnvelope::failed(ErrorCode::Forbidden)
+                .message(format!("Requires the {} role.", required.as_str()))
+                .to_value(),
+        ));
+    }
+    Ok(())
+}
+
+fn organization_id(request: &Request) -> Option<i64> {
+    request
+        .path_params
+        .value("organization_id")?
+        .parse::<i64>()
+        .ok()
+}
+
+fn not_found() -> Response {
+    JsonResponse::not_found().body(
+        ApiEnvelope::failed(ErrorCode::NotFound)
+            .message("Organization not found.")
+            .to_value(),
+    )
+}
+
+fn internal_server_error() -> Response {
+    JsonResponse::internal_server_error()
+        .body(ApiEnvelope::failed(ErrorCode::InternalServerError).to_value())
+}
+
+fn form_error(field: &str, message: &str) -> Response {
+    JsonResponse::bad_request().body(
+        ApiEnvelope::failed(ErrorCode::FormError)
+            .field("field_errors", json!({ field: [message] }))
+            .to_value(),
+    )
+}
+
+///
+/// Lists organizations (`GET`) or creates one from form field `name` (`POST`). Admin token only.
+///
+pub async fn organizations_view(request: Request) -> Response {
+    if !shortcuts::is_admin(&request) {
+        return shortcuts::unauthorized();
+    }
+
+    let shared_context: &SharedContext = request.context().expect("SharedContext is missing.");
+    let db_wrapper = shared_context.db_wrapper.clone();
+
+    match request.method.as_str() {
+        "GET" => match Organization::fetch_all(db_wrapper).await {
+            Ok(organizations) => JsonResponse::ok().body(
+                ApiEnvelope::success("organizations")
+                    .data(organizations)
+                    .to_value(),
+            ),
+            Err(error) => {
+                eprintln!("Failed to fetch organizations. Error: {}", error);
+                internal_server_error()
+            }
+        },
+        "POST" => {
+            let validated_form = match OrganizationForm::new().validate(&request).await {
+                Ok(form) => form,
+                Err(error) => {
+                    return JsonResponse::bad_request().body(
+                        ApiEnvelope::failed(ErrorCode::FormError)
+                            .field("field_errors", error.field_errors)
+                            .field("other_errors", error.others)
+                            .to_value(),
+                    );
+                }
+            };
+
+            let name = validated_form.name.value().await;
+            let name = name.trim();
+            if name.is_empty() || name.chars().count() > 255 {
+                return form_error("name", "Name must have 1 to 255 characters.");
+            }
+
+            match Organization::create(db_wrapper, name).await {
+                Ok(organization) => JsonResponse::ok().body(
+                    ApiEnvelope::success("organization_created")
+                        .data(organization)
+                        .to_value(),
+                ),
+                Err(error) => {
+                    eprintln!("Failed to create organization. Error: {}", error);
+                    internal_server_error()
+                }
+            }
+        }
+        _ => HttpResponse::ok().body("This request method is not supported."),
+    }
+}
+
+///
+/// Returns an organization with its API keys, members and usage of the current month
+/// (`GET`, viewer role), or deletes it (`DELETE`, admin token only).
+///
+pub async fn organization_view(request: Request) -> Response {
+    let organization_id = match organization_id(&request) {
+        Some(organization_id) => organization_id,
+        None => return not_found(),
+    };
+
+    let shared_context: &SharedContext = request.context().expect("SharedContext is missing.");
+    let db_wrapper = shared_context.db_wrapper.clone();
+
+    match request.method.as_str() {
+        "GET" => {
+            if let Err(response) = check_access(&request, organization_id, Role::Viewer).await {
+                return response;
+            }
+        }
+        "DELETE" => {
+            if !shortcuts::is_admin(&request) {
+                return shortcuts::unauthorized();
+            }
+
+            return match Organization::delete(db_wrapper, organization_id).await {
+                Ok(true) => {
+                    JsonResponse::ok().body(ApiEnvelope::success("organization_deleted").to_value())
+                }
+                Ok(false) => not_found(),
+                Err(error) => {
+                    eprintln!("Failed to delete organization. Error: {}", error);
+                    internal_server_error()
+                }
+            };
+        }
+        _ => return HttpResponse::ok().body("This request method is not supported."),
+    }
+
+    let organization = match Organization::fetch(db_wrapper.clone(), organization_id).await {
+        Ok(Some(organization)) => organization,
+        Ok(None) => return not_found(),
+        Err(error) => {
+            eprintln!("Failed to fetch organization. Error: {}", error);
+            return internal_server_error();
+        }
+    };
+
+    let account = BillingAccount::Organization(organization_id);
+    let details = async {
+        let api_key_ids = Organization::api_key_ids(db_wrapper.clone(), organization_id).await?;
+        let members =
+            OrganizationMember::fetch_by_organization(db_wrapper.clone(), organization_id).await?;
+        let mut usage = billing::account_usage(db_wrapper.clone(), &account).await?;
+        let period_start = billing::period_start();
+        let by_key =
+            ApiKeyUsage::count_by_key_since(db_wrapper.clone(), organization_id, period_start)
+                .await?;
+
+        usage["keys"] = by_key
+            .into_iter()
+            .map(|(key_id, used)| json!({ "key_id": key_id, "used": used }))
+            .collect::<Vec<Value>>()
+            .into();
+        Ok::<_, sqlx::Error>((api_key_ids, members, usage))
+    };
+
+    let (api_key_ids, members, usage) = match details.await {
+        Ok(details) => details,
+        Err(error) => {
+            eprintln!("Failed to fetch organization details. Error: {}", error);
+            return internal_server_error();
+        }
+    };
+
+    JsonResponse::ok().body(
+        ApiEnvelope::success("organization")
+            .data(json!({
+                "id": organization.id,
+                "name": organization.name,
+                "date_created": organization.date_created,
+                "api_key_ids": api_key_ids,
+                "members": members,
+                "usage": usage,
+            }))
+            .to_value(),
+    )
+}
+
+///
+/// Adds the upload signing key id in form field `key_id` to an organization. Admin role.
+///
+pub async fn organization_api_keys_view(request: Request) -> Response {
+    if request.method != "POST" {
+        return HttpResponse::ok().body("This request method is not supported.");
+    }
+
+    let organization_id = match organization_id(&request) {
+        Some(organization_id) => organization_id,
+        None => return not_found(),
+    };
+    if let Err(response) = check_access(&request, organization_id, Role::Admin).await {
+        return response;
+    }
+
+    let validated_form = match OrganizationApiKeyForm::new().validate(&request).await {
+        Ok(form) => form,
+        Err(error) => {
+            return JsonResponse::bad_request().body(
+                ApiEnvelope::failed(ErrorCode::FormError)
+                    .field("field_errors", error.field_errors)
+                    .field("other_errors", error.others)
+                    .to_value(),
+            );
+        }
+    };
+
+    let shared_context: &SharedContext = request.context().expect("SharedContext is missing.");
+    let key_id = validated_form.key_id.value().await.trim().to_string();
+    let is_known = shared_context
+        .secrets
+        .upload_keyring()
+        .is_some_and(|keyring| keyring.contains(&key_id));
+    if !is_known {
+        return form_error("key_id", "Unknown key id.");
+    }
+
+    let db_wrapper = shared_context.db_wrapper.clone();
+    match Organization::fetch(db_wrapper.clone(), organization_id).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return not_found(),
+        Err(error) => {
+            eprintln!("Failed to fetch organization. Error: {}", error);
+            return internal_server_error();
+        }
+    }
+
+    match Organization::add_api_key(db_wrapper, organization_id, &key_id).await {
+        Ok(true) => JsonResponse::ok().body(
+            ApiEnvelope::success("api_key_added")
+                .data(json!({ "key_id": key_id }))
+                .to_value(),
+        ),
+        Ok(false) => JsonResponse::with_status(409, "Conflict").body(
+            ApiEnvelope::failed(ErrorCode::ApiKeyTaken)
+                .message("The key id already belongs to an organization.")
+                .to_value(),
+        ),
+        Err(error) => {
+            eprintln!("Failed to add api key. Error: {}", error);
+            internal_server_error()
+        }
+    }
+}
+
+///
+/// Removes an upload signing key id from an organization. Admin role.
+///
+pub async fn organization_api_key_view(request: Request) -> Response {
+    if request.method != "DELETE" {
+        return HttpResponse::ok().body("This request method is not supported.");
+    }
+
+    let organization_id = match organization_id(&request) {
+        Some(organization_id) => organization_id,
+        None => return not_found(),
+    };
+    if let Err(response) = check_access(&request, organization_id, Role::Admin).await {
+        return response;
+    }
+
+    let shared_context: &SharedContext = request.context().expect("SharedContext is missing.");
+    let key_id = request.path_params.value("key_id").unwrap();
+    match Organization::remove_api_key(shared_context.db_wrapper.clone(), organization_id, key_id)
+        .await
+    {
+        Ok(true) => JsonResponse::ok().body(ApiEnvelope::success("api_key_removed").to_value()),
+        Ok(false) => JsonResponse::not_found().body(
+            ApiEnvelope::failed(ErrorCode::NotFound)
+                .message("The organization doesn't own this key id.")
+                .to_value(),
+        ),
+        Err(error) => {
+            eprintln!("Failed to remove api key. Error: {}", error);
+            internal_server_error()
+        }
+    }
+}
+
+///
+/// Adds a member from form fields `email` and `role`. Admin role. The member token is only
+/// returned in this response.
+///
+pub async fn organization_members_view(request: Request) -> Response {
+    if request.method != "POST" {
+        return HttpResponse::ok().body("This request method is not supported.");
+    }
+
+    let organization_id = match organization_id(&request) {
+        Some(organization_id) => organization_id,
+        None => return not_found(),
+    };
+    if let Err(response) = check_access(&request, organization_id, Role::Admin).await {
+        return response;
+    }
+
+    let validated_form = match OrganizationMemberForm::new().validate(&request).await {
+        Ok(form) => form,
+        Err(error) => {
+            return JsonResponse::bad_request().body(
+                ApiEnvelope::failed(ErrorCode::FormError)
+                    .field("field_errors", error.field_errors)
+                    .field("other_errors", error.others)
+                    .to_value(),
+            );
+        }
+    };
+
+    let email = match metadata_utils::parse_notify_email(&validated_form.email.value().await) {
+        Ok(email) => email.to_lowercase(),
+        Err(error) => return form_error("email", &error),
+    };
+    let role = match Role::parse(&validated_form.role.value().await) {
+        Some(role) => role,
+        None => return form_error("role", "Role must be admin or viewer."),
+    };
+
+    let shared_context: &SharedContext = request.context().expect("SharedContext is missing.");
+    let db_wrapper = shared_context.db_wrapper.clone();
+    match Organization::fetch(db_wrapper.clone(), organization_id).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return not_found(),
+        Err(error) => {
+            eprintln!("Failed to fetch organization. Error: {}", error);
+            return internal_server_error();
+        }
+    }
+
+    let token = organization_utils::generate_member_token();
+    let token_hash = organization_utils::hash_member_token(&token);
+    let member =
+        match OrganizationMember::insert(db_wrapper, organization_id, &email, role, &token_hash)
+            .await
+        {
+            Ok(Some(member)) => member,
+            Ok(None) => {
+                return JsonResponse::with_status(409, "Conflict").body(
+                    ApiEnvelope::failed(ErrorCode::MemberExists)
+                        .message("The organization already has a member with this email.")
+                        .to_value(),
+                )
+            }
+            Err(error) => {
+                eprintln!("Failed to add organization member. Error: {}", error);
+                return internal_server_error();
+            }
+        };
+
+    let mut data = json!(member);
+    data["token"] = Value::from(token);
+    JsonResponse::ok().body(ApiEnvelope::success("member_added").data(data).to_value())
+}
+
+///
+/// Removes a member of an organization, revoking their token. Admin role.
+///
+pub async fn organization_member_view(request: Request) -> Response {
+    if request.method != "DELETE" {
+        return HttpResponse::ok().body("This request method is not supported.");
+    }
+
+    let organization_id = match organization_id(&request) {
+        Some(organization_id) => organization_id,
+        None => return not_found(),
+    };
+    if let Err(response) = check_access(&request, organization_id, Role::Admin).await {
+        return response;
+    }
+
+    let member_id = match request
+        .path_params
+        .value("member_id")
+        .and_then(|value| value.parse::<i64>().ok())
+    {
+        Some(member_id) => member_id,
+        None => return not_found(),
+    };
+
+    let shared_context: &SharedContext = request.context().expect("SharedContext is missing.");
+    match OrganizationMember::delete(
+        shared_context.db_wrapper.clone(),
+        organization_id,
+        member_id,
+    )
+    .await
+    {
+        Ok(true) => JsonResponse::ok().body(ApiEnvelope::success("member_removed").to_value()),
+        Ok(false) => JsonResponse::not_found().body(
+            ApiEnvelope::failed(ErrorCode::NotFound)
+                .message("Member not found.")
+                .to_value(),
+        ),
+        Err(error) => {
+            eprintln!("Failed to remove organization member. Error: {}", error);
+            internal_server_error()
+        }
+    }
+}
@@ -0,0 +1,294 @@
+use std::env;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use chrono::Utc;
+use racoon::core::headers::{HeaderValue, Headers};
+use racoon::core::request::Request;
+use racoon::core::response::status::ResponseStatus;
+use racoon::core::response::{HttpResponse, JsonResponse, Response};
+use serde_json::json;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::broadcast::Receiver;
+use uuid::Uuid;
+
+use crate::api::error_codes::ErrorCode;
+use crate::api::ingestion::{self, IngestError, Submission, MAX_IMAGE_SIZE};
+use crate::api::lifecycle::{LifecycleEvent, LifecycleStage};
+use crate::api::{shortcuts, upload_signatures};
+use crate::db::models::{BackgroundRemoverTask, ManifestFile};
+use crate::utils::envelope_utils::ApiEnvelope;
+use crate::utils::path_utils;
+use crate::SharedContext;
+
+/// Request headers of plugin endpoints, allowed in CORS preflights.
+const ALLOWED_HEADERS: &str = "Authorization, Content-Type, X-Filename, X-Signature, \
+    X-Signature-Timestamp, X-Signature-Nonce, X-Content-SHA256, X-No-Dedup";
+
+/// Browsers may reuse a preflight this long.
+const PREFLIGHT_MAX_AGE_SECS: &str = "600";
+
+///
+/// Whether `path` is a plugin endpoint, which answer CORS preflights.
+///
+pub fn is_plugin_path(path: &str) -> bool {
+    path.starts_with("/v1/plugin/")
+}
+
+///
+/// Answers the CORS preflight of a plugin endpoint. The middleware adds the CORS headers.
+///
+pub fn preflight() -> Response {
+    HttpResponse::ok().body("")
+}
+
+///
+/// CORS headers of plugin endpoints, on top of the allowed origin and methods every response
+/// has.
+///
+pub fn set_cors_headers(headers: &mut Headers) {
+    headers.set("Access-Control-Allow-Headers", ALLOWED_HEADERS);
+    headers.set("Access-Control-Max-Age", PREFLIGHT_MAX_AGE_SECS);
+}
+
+///
+/// Issues a plugin token to the upload signing key signing the request (`POST`), signed like an
+/// upload with the SHA-256 of the empty body. Meant for the backend of the customer, which
+/// passes the token on to its plugin, so plugins never hold the signing key.
+///
+pub async fn plugin_token_view(request: Request) -> Response {
+    if request.method != "POST" {
+        return HttpResponse::ok().body("This request method is not supported.");
+    }
+
+    let shared_context: &SharedContext = request.context().expect("SharedContext is missing.");
+    let keyring = match shared_context.secrets.keyring() {
+        Some(keyring) => keyring,
+        None => return not_enabled(),
+    };
+
+    let key_id = match upload_signatures::key_id(&request) {
+        Some(key_id) => key_id,
+        None => {
+            return JsonResponse::unauthorized().body(
+                ApiEnvelope::failed(ErrorCode::InvalidSignature)
+                    .message("Token requests must be signed.")
+                    .to_value(),
+            )
+        }
+    };
+    if let Err(response) = upload_signatures::verify(&request, shared_context).await {
+        return response;
+    }
+
+    let ttl = shared_context.config.load().plugin_token_ttl;
+    let expires = Utc::now().timestamp() + ttl.as_secs() as i64;
+    shared_context
+        .metrics
+        .increment("plugin_tokens_issued_total");
+
+    JsonResponse::ok().body(
+        ApiEnvelope::success("plugin_token")
+            .data(json!({
+                "token": keyring.sign_plugin_token(&key_id, expires),
+                "expires": expires,
+            }))
+            .to_value(),
+    )
+}
+
+///
+/// Removes the background of the image sent as request body (`POST`) in a single call, for
+/// plugins which can't keep a websocket open. Authenticated with `Authorization: Bearer <plugin
+/// token>`. Answers with the result URLs once stored, or with `processing` after
+/// `AppConfig::plugin_wait`, in which case the task details can be polled with the token.
+///
+pub async fn plugin_remove_background_view(request: Request) -> Response {
+    if request.method != "POST" {
+        return HttpResponse::ok().body("This request method is not supported.");
+    }
+
+    let shared_context: &SharedContext = request.context().expect("SharedContext is missing.");
+    let key_id = match authenticated_key_id(&request, shared_context) {
+        Ok(key_id) => key_id,
+        Err(response) => return response,
+    };
+
+    let data = match shortcuts::read_body(&request, MAX_IMAGE_SIZE).await {
+        Some(data) if !data.is_empty() => data,
+        _ => {
+            return JsonResponse::bad_request().body(
+                ApiEnvelope::failed(ErrorCode::InvalidBody)
+                    .message("The body must be the image, at most 60 MB, with Content-Length.")
+                    .to_value(),
+            )
+        }
+    };
+
+    let query = |name: &str| {
+        request
+            .query_params
+            .value(name)
+            .map(|value| value.to_string())
+    };
+    let submission = Submission {
+        key_id: Some(key_id),
+        filename: request
+            .headers
+            .value("X-Filename")
+            .map(|value| value.as_str().to_string()),
+        outputs: query("outputs"),
+        background_hint: query("background_hint"),
+        no_dedup: shortcuts::no_dedup(&request),
+        ..Default::default()
+    };
+
+    // Subscribed before the task exists, so its result can't be missed.
+    let mut events = shared_context.lifecycle.subscribe();
+    let key = match ingestion::create_task(shared_context, submission, async { Ok(data) }).await {
+        Ok(key) => key,
+        Err(IngestError::Invalid(message)) => {
+            return JsonResponse::bad_request().body(
+                ApiEnvelope::failed(ErrorCode::InvalidImage)
+                    .message(message)
+                    .to_value(),
+            )
+        }
+        Err(IngestError::Rejected(error)) => return error.response(),
+        Err(IngestError::Unavailable(error)) => {
+            eprintln!("Failed to create plugin task. Error: {}", error);
+            return JsonResponse::internal_server_error()
+                .body(ApiEnvelope::failed(ErrorCode::InternalServerError).to_value());
+        }
+    };
+    shared_context.metrics.increment("plugin_tasks_total");
+
+    let token = shared_context
+        .secrets
+        .keyring()
+        .map(|keyring| keyring.sign_task_key(&key));
+    let wait = shared_context.config.load().plugin_wait;
+    match wait_for_result(&mut events, &key, wait).await {
+        Some(event) if event.event == LifecycleStage::Completed => {
+            completed(shared_context, &key, token).await
+        }
+        Some(event) => JsonResponse::with_status(502, "Bad Gateway").body(
+            ApiEnvelope::failed(
+                event
+                    .status_code
+                    .unwrap_or(ErrorCode::ProcessingFailed.to_string()),
+            )
+            .data(json!({ "key": key, "token": token }))
+            .to_value(),
+        ),
+        None => JsonResponse::with_status(202, "Accepted").body(
+            ApiEnvelope::success("processing")
+                .data(json!({ "key": key, "token": token }))
+                .to_value(),
+        ),
+    }
+}
+
+///
+/// Upload signing key id of the plugin token in the `Authorization` header. Tokens of keys
+/// removed from `UPLOAD_SIGNING_KEYS` are rejected.
+///
+fn authenticated_key_id(
+    request: &Request,
+    shared_context: &SharedContext,
+) -> Result<String, Response> {
+    let (keyring, upload_keyring) = match (
+        shared_context.secrets.keyring(),
+        shared_context.secrets.upload_keyring(),
+    ) {
+        (Some(keyring), Some(upload_keyring)) => (keyring, upload_keyring),
+        _ => return Err(not_enabled()),
+    };
+
+    let token = request
+        .headers
+        .value("Authorization")
+        .and_then(|value| value.as_str().strip_prefix("Bearer ").map(str::to_string));
+    match token.and_then(|token| keyring.verify_plugin_token(token.trim(), Utc::now().timestamp()))
+    {
+        Some(key_id) if upload_keyring.contains(&key_id) => Ok(key_id),
+        _ => Err(JsonResponse::unauthorized().body(
+            ApiEnvelope::failed(ErrorCode::Unauthorized)
+                .message("Missing, invalid or expired plugin token.")
+                .to_value(),
+        )),
+    }
+}
+
+///
+/// Waits up to `wait` for task `key` to complete or fail.
+///
+async fn wait_for_result(
+    events: &mut Receiver<LifecycleEvent>,
+    key: &Uuid,
+    wait: Duration,
+) -> Option<LifecycleEvent> {
+    let deadline = tokio::time::Instant::now() + wait;
+    loop {
+        match tokio::time::timeout_at(deadline, events.recv()).await {
+            Ok(Ok(event))
+                if event.task_key == *key
+                    && matches!(
+                        event.event,
+                        LifecycleStage::Completed | LifecycleStage::Failed
+                    ) =>
+            {
+                return Some(event)
+            }
+            Ok(Ok(_)) | Ok(Err(RecvError::Lagged(_))) => continue,
+            Ok(Err(RecvError::Closed)) | Err(_) => return None,
+        }
+    }
+}
+
+///
+/// Result URLs of completed task `key`. Only the outputs are returned, to keep the response
+/// small; the task details have the rest.
+///
+async fn completed(shared_context: &SharedContext, key: &Uuid, token: Option<String>) -> Response {
+    let (task, host) = match (
+        BackgroundRemoverTask::fetch(shared_context.db_wrapper.clone(), key).await,
+        env::var("HOST"),
+    ) {
+        (Ok(task), Ok(host)) => (task, host),
+        (Err(error), _) => {
+            eprintln!("Failed to fetch plugin task. Error: {}", error);
+            return JsonResponse::internal_server_error()
+                .body(ApiEnvelope::failed(ErrorCode::InternalServerError).to_value());
+        }
+        (_, Err(error)) => {
+            eprintln!("The HOST environment variable is missing. Error: {}", error);
+            return JsonResponse::internal_server_error()
+                .body(ApiEnvelope::failed(ErrorCode::InternalServerError).to_value());
+        }
+    };
+
+    let url = |role: &str| {
+        task.output_path(role).map(|path| {
+            path_utils::full_media_url_from_relative_path("https", &host, PathBuf::from(path))
+        })
+    };
+    JsonResponse::ok().body(
+        ApiEnvelope::success("completed")
+            .data(json!({
+                "key": key,
+                "token": token,
+                "processed_image": url(ManifestFile::PROCESSED),
+                "mask_image": url(ManifestFile::MASK),
+            }))
+            .to_value(),
+    )
+}
+
+fn not_enabled() -> Response {
+    JsonResponse::not_found().body(
+        ApiEnvelope::failed(ErrorCode::NotFound)
+            .message("Plugin access is not enabled.")
+            .to_value(),
+    )
+}
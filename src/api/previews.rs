@@ -0,0 +1,194 @@
+use std::env;
+use std::path::PathBuf;
+use std::time::Instant;
+
+use image::ImageFormat;
+use uuid::Uuid;
+
+use crate::api::ws_messages::ServerMessage;
+use crate::db::models::{BackgroundRemoverTask, ManifestFile};
+use crate::utils::timing_utils::TaskStage;
+use crate::utils::{image_utils, path_utils, signature_utils};
+use crate::SharedContext;
+
+///
+/// Image a preview is generated from.
+///
+#[derive(Debug, Clone, Copy)]
+pub enum PreviewOf {
+    /// Uploaded image. Stored as JPEG in `preview-original`.
+    Original,
+    /// Transparent output of the BP server. Stored as PNG in `preview-transparent`.
+    Processed,
+}
+
+///
+/// Generates downscaled preview of task `key` on the image workers, stores it and sends
+/// `preview_ready` to the task group.
+///
+/// Until this finishes, the preview path of the task points to the full size image, so clients
+/// always have something to show. Meant to be spawned off the request path.
+///
+pub async fn generate(shared_context: SharedContext, key: Uuid, preview_of: PreviewOf) {
+    let started = Instant::now();
+    if let Err(error) = try_generate(&shared_context, &key, preview_of).await {
+        eprintln!(
+            "Failed to generate {:?} preview of task {}. Error: {}",
+            preview_of, key, error
+        );
+        return;
+    }
+
+    let stage = match preview_of {
+        PreviewOf::Original => TaskStage::PreviewOriginal,
+        PreviewOf::Processed => TaskStage::PreviewProcessed,
+    };
+    let db_wrapper = shared_context.db_wrapper.clone();
+    if let Err(error) =
+        BackgroundRemoverTask::record_timings(db_wrapper, &key, &[(stage, started.elapsed())]).await
+    {
+        eprintln!("Failed to record preview timing. Error: {}", error);
+    }
+}
+
+///
+/// Regenerates previews of `instance` in the background when they were made with other preview
+/// settings than the current ones. Called when a task is viewed, so settings changes apply
+/// lazily instead of regenerating every stored preview at once.
+///
+pub async fn refresh_if_stale(shared_context: &SharedContext, instance: &BackgroundRemoverTask) {
+    let fingerprint = shared_context.config.load().preview_options().fingerprint();
+    if instance.preview_settings.as_deref() == Some(fingerprint.as_str()) {
+        return;
+    }
+
+    let db_wrapper = shared_context.db_wrapper.clone();
+    match BackgroundRemoverTask::claim_preview_refresh(db_wrapper, &instance.key, &fingerprint)
+        .await
+    {
+        Ok(true) => {}
+        // Another request is already regenerating them.
+        Ok(false) => return,
+        Err(error) => {
+            eprintln!("Failed to claim preview refresh. Error: {}", error);
+            return;
+        }
+    }
+
+    tokio::spawn(generate(
+        shared_context.clone(),
+        instance.key,
+        PreviewOf::Original,
+    ));
+    if instance.output_path(ManifestFile::PROCESSED).is_some() {
+        tokio::spawn(generate(
+            shared_context.clone(),
+            instance.key,
+            PreviewOf::Processed,
+        ));
+    }
+}
+
+async fn try_generate(
+    shared_context: &SharedContext,
+    key: &Uuid,
+    preview_of: PreviewOf,
+) -> std::io::Result<()> {
+    let db_wrapper = shared_context.db_wrapper.clone();
+    let instance = BackgroundRemoverTask::fetch(db_wrapper.clone(), key)
+        .await
+        .map_err(std::io::Error::other)?;
+
+    let source = match preview_of {
+        PreviewOf::Original => Some(&instance.original_image_path),
+        PreviewOf::Processed => instance.output_path(ManifestFile::PROCESSED),
+    };
+    let source = match source {
+        Some(source) => source,
+        None => return Ok(()),
+    };
+
+    let media_root = PathBuf::from(env::var("MEDIA_ROOT").map_err(std::io::Error::other)?);
+    let data = tokio::fs::read(path_utils::file_path_from_relative_url(
+        media_root.clone(),
+        PathBuf::from(source),
+    ))
+    .await?;
+
+    let options = shared_context.config.load().preview_options();
+    let (format, extension) = match preview_of {
+        PreviewOf::Original => (ImageFormat::Jpeg, "jpg"),
+        PreviewOf::Processed => (ImageFormat::Png, "png"),
+    };
+
+    let memory = image_utils::estimate_memory(&data);
+    let preview = shared_context
+        .image_workers
+        .run(memory, move || {
+            image_utils::make_preview(&data, &options, format)
+        })
+        .await?
+        .map_err(std::io::Error::other)?;
+
+    let filename = path_utils::content_filename(&preview, extension);
+    let tenant = instance.tenant.as_deref();
+    let save_path = match preview_of {
+        PreviewOf::Original => {
+            path_utils::generate_save_path(
+                tenant,
+                path_utils::ForImage::PreviewOriginalImage(key, &filename),
+            )
+            .await?
+        }
+        PreviewOf::Processed => {
+            path_utils::generate_save_path(
+                tenant,
+                path_utils::ForImage::PreviewTransparentImage(key, &filename),
+            )
+            .await?
+        }
+    };
+    tokio::fs::write(&save_path, &preview).await?;
+
+    let relative_path = path_utils::relative_media_url_from_full_path(&media_root, &save_path)
+        .to_string_lossy()
+        .to_string();
+    let result = match preview_of {
+        PreviewOf::Original => {
+            BackgroundRemoverTask::update_preview_original_path(
+                db_wrapper.clone(),
+                key,
+                &relative_path,
+            )
+            .await
+        }
+        PreviewOf::Processed => {
+            let file = ManifestFile {
+                role: ManifestFile::PREVIEW_PROCESSED.to_string(),
+                path: relative_path,
+                size_bytes: preview.len() as u64,
+                sha256: signature_utils::sha256_hex(&preview),
+            };
+            BackgroundRemoverTask::update_preview_processed_path(db_wrapper.clone(), key, &file)
+                .await
+        }
+    };
+    result.map_err(std::io::Error::other)?;
+
+    let fresh_instance = BackgroundRemoverTask::fetch(db_wrapper, key)
+        .await
+        .map_err(std::io::Error::other)?;
+    let serialized = shared_context
+        .task_json_cache
+        .serialize(&fresh_instance, None)
+        .map_err(std::io::Error::other)?;
+
+    shared_context
+        .ws_clients
+        .broadcast(
+            &fresh_instance.task_group,
+            &ServerMessage::preview_ready(serialized),
+        )
+        .await;
+    Ok(())
+}
@@ -0,0 +1,94 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use uuid::Uuid;
+
+use crate::utils::timing_utils;
+
+/// Weight of the newest sample in the rolling average.
+const SMOOTHING: f64 = 0.2;
+
+/// Tasks without a response for longer than this are assumed lost and no longer count as queued.
+const MAX_TRACKED_AGE: Duration = Duration::from_secs(10 * 60);
+
+///
+/// Tracks tasks sent to the BP server and keeps a rolling average of how long the BP server takes
+/// to process them. Used to estimate the wait for queued users.
+///
+pub struct ProcessingTimes {
+    started: Mutex<HashMap<Uuid, Instant>>,
+    /// Exponential moving average in milliseconds. `0` until the first sample.
+    average_millis: AtomicU64,
+    /// Used as the average until the first task completes.
+    fallback: Duration,
+}
+
+impl ProcessingTimes {
+    pub fn new(fallback: Duration) -> Self {
+        Self {
+            started: Mutex::new(HashMap::new()),
+            average_millis: AtomicU64::new(0),
+            fallback,
+        }
+    }
+
+    ///
+    /// Marks task `key` as sent to the BP server.
+    ///
+    pub fn start(&self, key: Uuid) {
+        let mut started = self.started.lock().unwrap();
+        started.retain(|_, instant| instant.elapsed() < MAX_TRACKED_AGE);
+        started.insert(key, Instant::now());
+    }
+
+    ///
+    /// Marks task `key` as processed and adds its duration to the rolling average. Returns the
+    /// duration, or `None` if the task was not tracked.
+    ///
+    pub fn finish(&self, key: &Uuid) -> Option<Duration> {
+        let took = self.started.lock().unwrap().remove(key)?.elapsed();
+
+        let average = self.average_millis.load(Ordering::Relaxed);
+        self.average_millis.store(
+            timing_utils::rolling_average_millis(average, took, SMOOTHING),
+            Ordering::Relaxed,
+        );
+        Some(took)
+    }
+
+    ///
+    /// Stops tracking task `key` without recording a sample, e.g. when processing failed or its
+    /// response timed out.
+    ///
+    pub fn cancel(&self, key: &Uuid) {
+        self.started.lock().unwrap().remove(key);
+    }
+
+    pub fn average(&self) -> Duration {
+        match self.average_millis.load(Ordering::Relaxed) {
+            0 => self.fallback,
+            millis => Duration::from_millis(millis),
+        }
+    }
+
+    ///
+    /// Number of tasks sent to the BP server and still waiting for a result.
+    ///
+    pub fn queue_depth(&self) -> usize {
+        let started = self.started.lock().unwrap();
+        started
+            .values()
+            .filter(|instant| instant.elapsed() < MAX_TRACKED_AGE)
+            .count()
+    }
+
+    ///
+    /// Estimated seconds until a task processes when `queue_depth` tasks, including itself, are
+    /// ahead of it.
+    ///
+    pub fn estimate_seconds(&self, queue_depth: usize) -> u64 {
+        timing_utils::estimate_seconds(self.average(), queue_depth)
+    }
+}
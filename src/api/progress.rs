@@ -0,0 +1,79 @@
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+
+use lru::LruCache;
+use serde_json::Value;
+use uuid::Uuid;
+
+/// Maximum number of in flight tasks whose task group is remembered.
+const TASK_GROUP_CACHE_SIZE: usize = 10_000;
+
+///
+/// Progress of tasks being processed by the BP server.
+///
+/// Task groups of in flight tasks are remembered so progress updates are fanned out without a
+/// database lookup, and the latest progress per task is buffered and written in batches by the
+/// progress flush job.
+///
+pub struct ProgressTracker {
+    task_groups: Mutex<LruCache<Uuid, Uuid>>,
+    pending: Mutex<HashMap<Uuid, Value>>,
+}
+
+impl Default for ProgressTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ProgressTracker {
+    pub fn new() -> Self {
+        let capacity = NonZeroUsize::new(TASK_GROUP_CACHE_SIZE).unwrap_or(NonZeroUsize::MIN);
+        Self {
+            task_groups: Mutex::new(LruCache::new(capacity)),
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn remember_task_group(&self, key: Uuid, task_group: Uuid) {
+        if let Ok(mut task_groups) = self.task_groups.lock() {
+            task_groups.put(key, task_group);
+        }
+    }
+
+    pub fn task_group(&self, key: &Uuid) -> Option<Uuid> {
+        self.task_groups.lock().ok()?.get(key).copied()
+    }
+
+    ///
+    /// Called once the task finished or failed. Buffered progress of the task is dropped.
+    ///
+    pub fn forget(&self, key: &Uuid) {
+        if let Ok(mut task_groups) = self.task_groups.lock() {
+            task_groups.pop(key);
+        }
+        if let Ok(mut pending) = self.pending.lock() {
+            pending.remove(key);
+        }
+    }
+
+    ///
+    /// Buffers `progress` of the task, replacing progress not yet written.
+    ///
+    pub fn buffer(&self, key: Uuid, progress: Value) {
+        if let Ok(mut pending) = self.pending.lock() {
+            pending.insert(key, progress);
+        }
+    }
+
+    ///
+    /// Returns and clears buffered progress.
+    ///
+    pub fn take_pending(&self) -> HashMap<Uuid, Value> {
+        match self.pending.lock() {
+            Ok(mut pending) => std::mem::take(&mut *pending),
+            Err(_) => HashMap::new(),
+        }
+    }
+}
@@ -0,0 +1,72 @@
+use chrono::Utc;
+use racoon::core::request::Request;
+use racoon::core::response::{HttpResponse, JsonResponse, Response};
+use serde::Serialize;
+
+use crate::api::error_codes::ErrorCode;
+use crate::api::shortcuts;
+use crate::api::views::QUEUE_WINDOW_MINUTES;
+use crate::db::models::BackgroundRemoverTask;
+use crate::utils::envelope_utils::ApiEnvelope;
+use crate::SharedContext;
+
+///
+/// Load of the BP workers, for autoscalers to scale them by.
+///
+#[derive(Debug, Serialize)]
+pub struct ScalingSignal {
+    /// Tasks processing on any replica, created within `QUEUE_WINDOW_MINUTES`.
+    pub queue_depth: u64,
+    /// Tasks this instance sent to BP servers and is waiting on.
+    pub in_flight: usize,
+    /// Rolling average from sending a task to its result.
+    pub average_wait_secs: f64,
+    /// Estimated wait of a task queued now behind `queue_depth` tasks.
+    pub estimated_wait_secs: u64,
+    /// Public BP servers this instance is connected to.
+    pub bp_servers_connected: usize,
+}
+
+pub async fn signal(shared_context: &SharedContext) -> Result<ScalingSignal, sqlx::Error> {
+    let since = Utc::now() - chrono::Duration::minutes(QUEUE_WINDOW_MINUTES);
+    let queue_depth =
+        BackgroundRemoverTask::count_processing(shared_context.db_wrapper.clone(), &since).await?;
+
+    let processing_times = &shared_context.processing_times;
+    Ok(ScalingSignal {
+        queue_depth,
+        in_flight: processing_times.queue_depth(),
+        average_wait_secs: processing_times.average().as_secs_f64(),
+        estimated_wait_secs: processing_times.estimate_seconds(queue_depth as usize),
+        bp_servers_connected: shared_context
+            .public_bp_servers
+            .clients()
+            .filter(|client| client.is_connected())
+            .count(),
+    })
+}
+
+///
+/// Returns the scaling signal as flat JSON numbers under `data`, e.g. for the KEDA `metrics-api`
+/// scaler with `valueLocation: data.queue_depth`. Admin token only.
+///
+pub async fn scaling_view(request: Request) -> Response {
+    if !shortcuts::is_admin(&request) {
+        return shortcuts::unauthorized();
+    }
+    if request.method != "GET" {
+        return HttpResponse::ok().body("This request method is not supported.");
+    }
+
+    let shared_context: &SharedContext = request.context().expect("SharedContext is missing.");
+    match signal(shared_context).await {
+        Ok(signal) => {
+            JsonResponse::ok().body(ApiEnvelope::success("scaling").data(signal).to_value())
+        }
+        Err(error) => {
+            log::error!("Failed to count processing tasks. Error: {}", error);
+            JsonResponse::internal_server_error()
+                .body(ApiEnvelope::failed(ErrorCode::InternalServerError).to_value())
+        }
+    }
+}
@@ -1,12 +1,305 @@
-use racoon::core::websocket::WebSocket;
-use serde_json::json;
-
-pub async fn internal_server_error(websocket: &WebSocket) {
-    let _ = websocket
-        .send_json(&json!({
-            "status": "failed",
-            "status_code": "internal_server_error",
-            "message": "Internal Server Error",
-        }))
-        .await;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::time::Duration;
+
+use racoon::core::headers::{HeaderValue, Headers};
+use racoon::core::request::Request;
+use racoon::core::response::status::ResponseStatus;
+use racoon::core::response::AbstractResponse;
+use racoon::core::response::{HttpResponse, JsonResponse, Response};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use tokio::io::AsyncReadExt;
+
+use crate::api::error_codes::ErrorCode;
+use crate::api::ws_clients::WsConnection;
+use crate::api::ws_messages::ServerMessage;
+use crate::secrets;
+use crate::utils::encoding_utils::Encoding;
+use crate::utils::envelope_utils::ApiEnvelope;
+use crate::utils::ip_utils;
+use crate::utils::query_utils::{self, QueryError};
+use crate::SharedContext;
+
+pub fn internal_server_error(connection: &WsConnection) {
+    connection.send(&ServerMessage::internal_server_error());
+}
+
+///
+/// Returns true if the request carries `Authorization: Token <ADMIN_AUTH_TOKEN>`.
+///
+/// Admin endpoints are disabled when `ADMIN_AUTH_TOKEN` is not set.
+///
+pub fn is_admin(request: &Request) -> bool {
+    let shared_context: &SharedContext = request.context().expect("SharedContext is missing.");
+    let admin_auth_token = match shared_context.secrets.get(secrets::ADMIN_AUTH_TOKEN) {
+        Some(token) if !token.is_empty() => token,
+        _ => return false,
+    };
+
+    match request.headers.value("Authorization") {
+        Some(value) => value.as_str() == format!("Token {}", admin_auth_token),
+        None => false,
+    }
+}
+
+///
+/// Returns true if the request carries `X-No-Dedup: true`. Clients resubmitting an image on
+/// purpose, e.g. with options this service doesn't know of, send it so they are never served the
+/// outputs of an earlier upload. Counted in `upload_dedup_skipped_total`.
+///
+pub fn no_dedup(request: &Request) -> bool {
+    let no_dedup = request
+        .headers
+        .value("X-No-Dedup")
+        .is_some_and(|value| value.as_str().trim().eq_ignore_ascii_case("true"));
+    if no_dedup {
+        let shared_context: &SharedContext = request.context().expect("SharedContext is missing.");
+        shared_context
+            .metrics
+            .increment("upload_dedup_skipped_total");
+    }
+    no_dedup
+}
+
+///
+/// Returns the remaining ban of the client IP of `request` if it failed too many task key
+/// lookups. See `KeyLookupGuard`.
+///
+pub async fn key_lookup_ban(request: &Request) -> Option<Duration> {
+    let shared_context: &SharedContext = request.context().expect("SharedContext is missing.");
+    let ip = client_ip(request).await?;
+    shared_context.key_lookup_guard.banned_for(&ip)
+}
+
+///
+/// Counts a lookup of a missing or foreign task key against client IP `ip`.
+///
+pub fn record_key_lookup_failure(shared_context: &SharedContext, ip: Option<&str>) {
+    let ip = match ip {
+        Some(ip) => ip,
+        None => return,
+    };
+
+    let config = shared_context.config.load();
+    if shared_context.key_lookup_guard.record_failure(ip, &config) {
+        eprintln!(
+            "Banning {} from key lookups for {:?} after repeated invalid keys.",
+            ip, config.key_lookup_ban
+        );
+        shared_context.metrics.increment("key_lookup_bans_total");
+    }
+}
+
+pub fn too_many_key_failures(retry_after: Duration) -> Response {
+    let mut response = JsonResponse::with_status(429, "Too Many Requests").body(
+        ApiEnvelope::failed(ErrorCode::TooManyKeyFailures)
+            .message("Too many invalid task keys. Try again later.")
+            .to_value(),
+    );
+    response
+        .get_headers()
+        .set("Retry-After", (retry_after.as_secs() + 1).to_string());
+    response
+}
+
+pub fn unauthorized() -> Response {
+    JsonResponse::unauthorized().body(
+        ApiEnvelope::failed(ErrorCode::Unauthorized)
+            .message("Admin authentication required.")
+            .to_value(),
+    )
+}
+
+///
+/// Parses the query parameters of `request` into `T`, see `query_utils::parse_query`. Invalid
+/// parameters are answered with `bad_query`.
+///
+pub fn query<T: DeserializeOwned>(request: &Request) -> Result<T, Response> {
+    query_utils::parse_query(|name| {
+        request
+            .query_params
+            .value(name)
+            .map(|value| value.to_string())
+    })
+    .map_err(bad_query)
+}
+
+pub fn bad_query(error: QueryError) -> Response {
+    JsonResponse::bad_request().body(
+        ApiEnvelope::failed(ErrorCode::BadQuery)
+            .message("Invalid query parameters.")
+            .field("field_errors", error.field_errors)
+            .to_value(),
+    )
+}
+
+///
+/// Reads the body of `request`, which must declare its `Content-Length`. `None` when the length
+/// is missing or above `max_size`, or the connection closed early.
+///
+pub async fn read_body(request: &Request, max_size: usize) -> Option<Vec<u8>> {
+    let length = request
+        .headers
+        .value("Content-Length")?
+        .as_str()
+        .trim()
+        .parse::<usize>()
+        .ok()?;
+    if length > max_size {
+        return None;
+    }
+
+    let mut body = Vec::with_capacity(length);
+    while body.len() < length {
+        let chunk = request.stream.read_chunk().await.ok()?;
+        if chunk.is_empty() {
+            return None;
+        }
+        body.extend_from_slice(&chunk);
+    }
+    body.truncate(length);
+    Some(body)
+}
+
+/// Size of the chunks `stream_file` reads and writes.
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+///
+/// Response of a view which already wrote its answer to the connection itself. Nothing else is
+/// written and the connection is closed afterwards.
+///
+struct WrittenResponse {
+    headers: Headers,
+    body: Vec<u8>,
+}
+
+impl AbstractResponse for WrittenResponse {
+    fn status(&self) -> (u32, String) {
+        (200, "OK".to_string())
+    }
+
+    fn serve_default(&mut self) -> bool {
+        false
+    }
+
+    fn get_headers(&mut self) -> &mut Headers {
+        &mut self.headers
+    }
+
+    fn get_body(&mut self) -> &mut Vec<u8> {
+        &mut self.body
+    }
+
+    fn should_close(&mut self) -> bool {
+        true
+    }
+}
+
+///
+/// Streams the file at `path` as a `200 OK` response with `headers` in chunks of 64 KiB, so large
+/// files are never held in memory. Fails only when the file can't be opened, before anything was
+/// written. A client disconnecting midway is logged.
+///
+pub async fn stream_file(
+    request: &Request,
+    path: &Path,
+    headers: &[(&str, String)],
+) -> std::io::Result<Response> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let length = file.metadata().await?.len();
+
+    let mut head = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n", length);
+    for (name, value) in headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    // The middleware only sees the empty response below, so its hardening headers are sent here.
+    head.push_str("X-Content-Type-Options: nosniff\r\nReferrer-Policy: no-referrer\r\n");
+    head.push_str("Connection: close\r\n\r\n");
+
+    let response: Response = Box::new(WrittenResponse {
+        headers: Headers::new(),
+        body: vec![],
+    });
+    if let Err(error) = request.stream.write_chunk(head.as_bytes()).await {
+        log::debug!("Client left before the response. Error: {}", error);
+        return Ok(response);
+    }
+
+    let mut buffer = vec![0u8; STREAM_CHUNK_SIZE];
+    loop {
+        let read = match file.read(&mut buffer).await {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(error) => {
+                log::error!("Failed to read {:?}. Error: {}", path, error);
+                break;
+            }
+        };
+
+        if let Err(error) = request.stream.write_chunk(&buffer[..read]).await {
+            log::debug!("Client left during the response. Error: {}", error);
+            break;
+        }
+    }
+    Ok(response)
+}
+
+///
+/// Successful response encoded as requested by the `Accept` header, JSON by default.
+///
+pub fn negotiated_ok(request: &Request, body: Value) -> Response {
+    let accept = request.headers.value("Accept");
+    let encoding = Encoding::from_accept(accept.as_ref().map(|value| value.as_str()));
+
+    let mut response = match encoding {
+        Encoding::Json => JsonResponse::ok().body(body),
+        Encoding::MessagePack => match encoding.encode(&body) {
+            Ok(bytes) => {
+                let mut response = HttpResponse::ok().body(bytes);
+                response
+                    .get_headers()
+                    .set("Content-Type", encoding.content_type());
+                response
+            }
+            Err(error) => {
+                log::error!("Failed to encode response. Error: {}", error);
+                return JsonResponse::internal_server_error().empty();
+            }
+        },
+    };
+
+    // Caches must not serve one encoding to clients asking for the other.
+    response.get_headers().set("Vary", "Accept");
+    response
+}
+
+///
+/// Returns IP address of the client without port. Behind a trusted proxy, the address is taken
+/// from the forwarding headers.
+///
+pub async fn client_ip(request: &Request) -> Option<String> {
+    let remote_addr = request.remote_addr().await?;
+
+    let peer = match remote_addr.parse::<SocketAddr>() {
+        Ok(socket_addr) => socket_addr.ip(),
+        Err(_) => match remote_addr.parse::<IpAddr>() {
+            Ok(ip) => ip,
+            Err(_) => return Some(remote_addr),
+        },
+    };
+
+    let shared_context: &SharedContext = request.context().expect("SharedContext is missing.");
+    let config = shared_context.config.load();
+
+    let forwarded = request.headers.value("Forwarded");
+    let x_forwarded_for = request.headers.value("X-Forwarded-For");
+    let client = ip_utils::resolve_client_ip(
+        peer,
+        forwarded.as_ref().map(|value| value.as_str()),
+        x_forwarded_for.as_ref().map(|value| value.as_str()),
+        &config.trusted_proxies,
+    );
+
+    Some(client.to_string())
 }
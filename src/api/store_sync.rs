@@ -0,0 +1,339 @@
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use futures_util::stream::{self, StreamExt};
+use racoon::core::request::Request;
+use racoon::core::response::status::ResponseStatus;
+use racoon::core::response::{HttpResponse, JsonResponse, Response};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::time::Instant;
+use uuid::Uuid;
+
+use crate::api::error_codes::ErrorCode;
+use crate::api::ingestion::{self, IngestError, Submission};
+use crate::api::shortcuts;
+use crate::api::ws_messages::ServerMessage;
+use crate::clients::store_client::StoreClient;
+use crate::db::models::{BackgroundRemoverTask, ManifestFile, ResultStatus};
+use crate::utils::envelope_utils::ApiEnvelope;
+use crate::utils::store_utils::{self, ProductImage, Store};
+use crate::utils::{path_utils, retry_utils};
+use crate::SharedContext;
+
+/// Limit of the request body, which only holds credentials and options.
+const MAX_REQUEST_SIZE: usize = 64 * 1024;
+
+/// Product images processed per sync unless `max_images` is given.
+const DEFAULT_MAX_IMAGES: usize = 250;
+
+/// Upper bound of `max_images`.
+const MAX_IMAGES: usize = 5000;
+
+/// Product images downloaded and submitted at once.
+const SUBMIT_CONCURRENCY: usize = 4;
+
+/// Interval of checking the tasks of a sync for results.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Results arriving later are not pushed to the store.
+const SYNC_DEADLINE: Duration = Duration::from_secs(6 * 60 * 60);
+
+#[derive(Deserialize)]
+struct SyncRequest {
+    #[serde(flatten)]
+    store: Store,
+    /// Upload signing key id the tasks are billed to and stored under, like signed uploads.
+    key_id: Option<String>,
+    max_images: Option<usize>,
+    outputs: Option<String>,
+    background_hint: Option<String>,
+}
+
+///
+/// Progress of a sync, broadcast to its task group whenever it changes.
+///
+#[derive(Debug, Default, Serialize)]
+struct SyncProgress {
+    /// `listing`, `submitting`, `processing`, `completed`, `timed_out` or `failed`.
+    stage: &'static str,
+    platform: &'static str,
+    /// Product images to process, without cutouts and images synced before.
+    total: usize,
+    submitted: usize,
+    /// Images no task was created for, e.g. unsupported images or a used up quota.
+    rejected: usize,
+    processed: usize,
+    failed: usize,
+    pushed: usize,
+    push_failed: usize,
+}
+
+///
+/// Starts a sync of the product images of a Shopify or WooCommerce store: `POST
+/// /v1/admin/store-syncs/` with the store and its credentials as JSON. Admin token only.
+/// Returns the task group of the sync, whose websocket receives its progress.
+///
+pub async fn store_sync_view(request: Request) -> Response {
+    if !shortcuts::is_admin(&request) {
+        return shortcuts::unauthorized();
+    }
+
+    if request.method != "POST" {
+        return HttpResponse::ok().body("This request method is not supported.");
+    }
+
+    let shared_context: &SharedContext = request.context().expect("SharedContext is missing.");
+    let sync: SyncRequest = match shortcuts::read_body(&request, MAX_REQUEST_SIZE)
+        .await
+        .map(|body| serde_json::from_slice(&body))
+    {
+        Some(Ok(sync)) => sync,
+        Some(Err(error)) => return bad_request(&error.to_string()),
+        None => return bad_request("The body must be JSON of at most 64 KB, with Content-Length."),
+    };
+    if let Err(message) = sync.store.check() {
+        return bad_request(&message);
+    }
+
+    let task_group = Uuid::new_v4();
+    let platform = sync.store.platform();
+    println!(
+        "Starting {} store sync of task group {}.",
+        platform, task_group
+    );
+    shared_context.metrics.increment("store_syncs_total");
+    tokio::spawn(run(shared_context.clone(), sync, task_group));
+
+    JsonResponse::ok().body(
+        ApiEnvelope::success("store_sync_started")
+            .data(json!({
+                "task_group": task_group,
+                "platform": platform,
+            }))
+            .to_value(),
+    )
+}
+
+fn bad_request(message: &str) -> Response {
+    JsonResponse::bad_request().body(
+        ApiEnvelope::failed(ErrorCode::BadRequest)
+            .message(message)
+            .to_value(),
+    )
+}
+
+///
+/// Creates a task in `task_group` for every product image, then pushes the processed images to
+/// the store as new product images once they are stored. The sync lives on this replica only;
+/// after a restart it has to be started again, which skips images already pushed.
+///
+async fn run(shared_context: SharedContext, sync: SyncRequest, task_group: Uuid) {
+    let max_images = sync
+        .max_images
+        .unwrap_or(DEFAULT_MAX_IMAGES)
+        .min(MAX_IMAGES);
+    let client = StoreClient::new(sync.store);
+    let mut progress = SyncProgress {
+        stage: "listing",
+        platform: client.platform(),
+        ..Default::default()
+    };
+    report(&shared_context, &task_group, &progress).await;
+
+    let images: Vec<ProductImage> = match client.list_images(max_images).await {
+        Ok(images) => store_utils::pending_images(images),
+        Err(error) => {
+            eprintln!(
+                "Failed to list product images of task group {}. {}",
+                task_group, error
+            );
+            shared_context.metrics.increment("store_syncs_failed_total");
+            progress.stage = "failed";
+            report(&shared_context, &task_group, &progress).await;
+            let message = ServerMessage::failed(ErrorCode::StoreUnavailable, &error.to_string());
+            shared_context
+                .ws_clients
+                .broadcast(&task_group, &message)
+                .await;
+            return;
+        }
+    };
+    progress.stage = "submitting";
+    progress.total = images.len();
+    report(&shared_context, &task_group, &progress).await;
+
+    let (key_id, outputs, background_hint) = (&sync.key_id, &sync.outputs, &sync.background_hint);
+    let (shared_context_ref, client_ref) = (&shared_context, &client);
+    let submissions = stream::iter(images)
+        .map(|image| {
+            let (shared_context, client) = (shared_context_ref, client_ref);
+            async move {
+                let submission = Submission {
+                    task_group: Some(task_group),
+                    key_id: key_id.clone(),
+                    filename: image.filename(),
+                    metadata: Some(json!({
+                        "store_platform": client.platform(),
+                        "store_product_id": image.product_id,
+                        "store_image_id": image.image_id,
+                    })),
+                    outputs: outputs.clone(),
+                    background_hint: background_hint.clone(),
+                    ..Default::default()
+                };
+                let result =
+                    ingestion::create_task(shared_context, submission, client.download(&image))
+                        .await;
+                (image, result)
+            }
+        })
+        .buffer_unordered(SUBMIT_CONCURRENCY)
+        .collect::<Vec<(ProductImage, Result<Uuid, IngestError>)>>()
+        .await;
+
+    let mut pending: HashMap<Uuid, ProductImage> = HashMap::new();
+    for (image, result) in submissions {
+        match result {
+            Ok(key) => {
+                pending.insert(key, image);
+            }
+            Err(error) => {
+                eprintln!(
+                    "Skipping product image {} of task group {}. {}",
+                    image.src, task_group, error
+                );
+                progress.rejected += 1;
+            }
+        }
+    }
+    progress.submitted = pending.len();
+    progress.stage = "processing";
+    report(&shared_context, &task_group, &progress).await;
+
+    let deadline = Instant::now() + SYNC_DEADLINE;
+    let mut finished: HashSet<Uuid> = HashSet::new();
+    while finished.len() < pending.len() && Instant::now() < deadline {
+        tokio::time::sleep(POLL_INTERVAL).await;
+
+        let db_wrapper = shared_context.db_wrapper.clone();
+        let limit = pending.len() as i64;
+        let tasks = match BackgroundRemoverTask::fetch_by_task_group(db_wrapper, &task_group, limit)
+            .await
+        {
+            Ok(tasks) => tasks,
+            Err(error) => {
+                eprintln!("Failed to fetch tasks of store sync. Error: {}", error);
+                continue;
+            }
+        };
+
+        let done = finished.len();
+        for task in tasks {
+            let image = match pending.get(&task.key) {
+                Some(image) if !finished.contains(&task.key) => image,
+                _ => continue,
+            };
+            match task.result_status.as_deref() {
+                Some(status) if status == ResultStatus::Success.as_str() => {
+                    finished.insert(task.key);
+                    progress.processed += 1;
+                    match push(&shared_context, &client, image, &task).await {
+                        Ok(()) => {
+                            shared_context.metrics.increment("store_sync_pushed_total");
+                            progress.pushed += 1;
+                        }
+                        Err(error) => {
+                            eprintln!(
+                                "Failed to push cutout of task {} to the store. {}",
+                                task.key, error
+                            );
+                            shared_context
+                                .metrics
+                                .increment("store_sync_push_failed_total");
+                            progress.push_failed += 1;
+                        }
+                    }
+                }
+                Some(_) => {
+                    finished.insert(task.key);
+                    progress.failed += 1;
+                }
+                None => {}
+            }
+        }
+        if finished.len() > done {
+            report(&shared_context, &task_group, &progress).await;
+        }
+    }
+
+    progress.stage = if finished.len() < pending.len() {
+        "timed_out"
+    } else {
+        "completed"
+    };
+    println!(
+        "Store sync of task group {} {}: {} pushed, {} failed to push.",
+        task_group, progress.stage, progress.pushed, progress.push_failed
+    );
+    report(&shared_context, &task_group, &progress).await;
+}
+
+///
+/// Pushes the processed image of `task` to the product of `image`, retrying according to
+/// `AppConfig::webhook_retry` while the store is unavailable or throttles.
+///
+async fn push(
+    shared_context: &SharedContext,
+    client: &StoreClient,
+    image: &ProductImage,
+    task: &BackgroundRemoverTask,
+) -> Result<(), String> {
+    let path = task
+        .output_path(ManifestFile::PROCESSED)
+        .map(PathBuf::from)
+        .ok_or("The task has no processed image.")?;
+    let media_root = env::var("MEDIA_ROOT").map_err(|error| error.to_string())?;
+    let host = env::var("HOST").map_err(|error| error.to_string())?;
+
+    let full_path =
+        path_utils::file_path_from_relative_url(PathBuf::from(media_root), path.clone());
+    let data = tokio::fs::read(&full_path)
+        .await
+        .map_err(|error| error.to_string())?;
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or("png");
+    let filename =
+        store_utils::cutout_filename(&image.filename().unwrap_or("image".to_string()), extension);
+    let url = path_utils::full_media_url_from_relative_path("https", &host, path.clone());
+
+    let policy = shared_context.config.load().webhook_retry;
+    let mut failed = vec![];
+    let result = retry_utils::retry(
+        &policy,
+        |error| error.is_retryable(),
+        &mut failed,
+        |_| client.push_cutout(image, &filename, &data, &url),
+    )
+    .await;
+
+    for attempt in &failed {
+        eprintln!(
+            "Retrying push of task {} after attempt {} in {:?}. Error: {}",
+            task.key, attempt.attempt, attempt.delay, attempt.error
+        );
+    }
+    result.map_err(|error| error.to_string())
+}
+
+async fn report(shared_context: &SharedContext, task_group: &Uuid, progress: &SyncProgress) {
+    let message = ServerMessage::store_sync_progress(json!(progress));
+    shared_context
+        .ws_clients
+        .broadcast(task_group, &message)
+        .await;
+}
@@ -1,151 +1,560 @@
 use std::env;
+use std::fmt;
+use std::future::Future;
 use std::path::PathBuf;
-use std::str::FromStr;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use racoon::core::websocket::{Message, WebSocket};
+use racoon::core::websocket::Message;
 
+use image::ImageFormat;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use tej_protoc::protoc::File;
 
+use futures_util::future::select_all;
 use tokio::fs;
-use tokio::io::AsyncReadExt;
 use uuid::Uuid;
 
+use crate::api::batch_notifications;
+use crate::api::bp_routing;
+use crate::api::canary;
+use crate::api::error_codes::ErrorCode;
+use crate::api::image_workers::ImageWorkers;
+use crate::api::lifecycle::{LifecycleEvent, LifecycleStage};
+use crate::api::previews::{self, PreviewOf};
 use crate::api::shortcuts::{self, internal_server_error};
-use crate::clients::bp_request_client::BPRequestClient;
-use crate::db::models::{BackgroundRemoverTask, UpdateBackgroundRemoverTask};
-use crate::utils::{path_utils, save_utils};
+use crate::api::ws_clients::{self, WsConnection};
+use crate::api::ws_messages::{ClientMessage, ServerMessage};
+use crate::clients::bp_request_client::{BPRequestClient, ServerIdentity};
+use crate::clients::ops_notifier::OpsEvent;
+use crate::config::AppConfig;
+use crate::db::models::{
+    BackgroundRemoverTask, ManifestFile, OutboxEntry, ResultClaim, ResultStatus, TaskLogEntry,
+    TaskRevision, UpdateBackgroundRemoverTask,
+};
+use crate::utils::image_utils::{PreviewOptions, WatermarkOptions};
+use crate::utils::retry_utils::{self, FailedAttempt, RetryPolicy};
+use crate::utils::timing_utils::TaskStage;
+use crate::utils::{image_utils, path_utils, save_utils};
 use crate::SharedContext;
 
+/// How long a task is only sent by the replica which took its dispatch lease. Covers the time
+/// until the task is marked processing, after which it isn't sent again unless `PROCESS_HARD`.
+const DISPATCH_LEASE_TTL: Duration = Duration::from_secs(60);
+
+///
+/// Pipeline stages guarded by their own configurable timeout. Each stage has a distinct status
+/// code recorded in the task event log when it times out.
+///
+#[derive(Debug, Clone, Copy)]
+pub enum TimeoutStage {
+    OriginalRead,
+    BpSend,
+    BpResponse,
+}
+
+impl TimeoutStage {
+    pub fn error_code(&self) -> ErrorCode {
+        match self {
+            TimeoutStage::OriginalRead => ErrorCode::OriginalReadTimeout,
+            TimeoutStage::BpSend => ErrorCode::BpSendTimeout,
+            TimeoutStage::BpResponse => ErrorCode::BpResponseTimeout,
+        }
+    }
+
+    pub fn status_code(&self) -> &'static str {
+        self.error_code().as_str()
+    }
+
+    pub fn timeout(&self, config: &AppConfig) -> Duration {
+        match self {
+            TimeoutStage::OriginalRead => config.original_read_timeout,
+            TimeoutStage::BpSend => config.bp_send_timeout,
+            TimeoutStage::BpResponse => config.bp_response_timeout,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SendError {
+    Timeout(TimeoutStage),
+    Io(std::io::Error),
+}
+
+impl SendError {
+    ///
+    /// Whether sending again may succeed. Missing originals and slow disks are not retried.
+    ///
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            SendError::Timeout(TimeoutStage::BpSend) => true,
+            SendError::Timeout(_) => false,
+            SendError::Io(error) => error.kind() != std::io::ErrorKind::NotFound,
+        }
+    }
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::Timeout(stage) => write!(f, "Timed out: {}", stage.status_code()),
+            SendError::Io(error) => write!(f, "{}", error),
+        }
+    }
+}
+
 ///
 /// The abstraction for `BPRequestClient` to send task. Takes `BackgroundRemoverTask` instance, preprocesses and sends image to bp server for
 /// processing.
 ///
 pub async fn send(
     bp_request_client: Arc<BPRequestClient>,
+    config: &AppConfig,
+    image_workers: &ImageWorkers,
     task: &BackgroundRemoverTask,
-) -> std::io::Result<()> {
+    request_id: &Uuid,
+) -> Result<(), SendError> {
+    // BP servers may skip generating the transparent image of mask only tasks and pick a
+    // specialized model from the background hint, and order their work by the priority of the
+    // billing plan. `request_id` is echoed in the response.
     let message = json!({
         "task_id": task.key.to_string(),
+        "request_id": request_id.to_string(),
+        "outputs": task.outputs,
+        "background_hint": task.background_hint,
+        "priority": task.priority,
+    });
+
+    let files = [read_original(config, image_workers, task).await?];
+
+    write_to_bp_server(bp_request_client, config, &files, &message).await?;
+
+    println!("Sent task: {}", task.key);
+    Ok(())
+}
+
+///
+/// Sends `task` like `send` to the BP server chosen by `bp_routing::client_for`, retrying
+/// failures according to `AppConfig::bp_dispatch_retry`. The BP server is chosen again for every
+/// attempt, so retries fail over to a standby. Retried attempts are recorded in the task event
+/// log. All attempts share a new request id, recorded as the latest dispatch of the task.
+///
+pub async fn send_with_retry(
+    shared_context: &SharedContext,
+    task: &BackgroundRemoverTask,
+) -> Result<(), SendError> {
+    let config = shared_context.config.load_full();
+    let image_workers = &shared_context.image_workers;
+
+    let request_id = Uuid::new_v4();
+    BackgroundRemoverTask::set_bp_request_id(
+        shared_context.db_wrapper.clone(),
+        &task.key,
+        &request_id,
+    )
+    .await
+    .map_err(|error| SendError::Io(std::io::Error::other(error)))?;
+
+    let (config, request_id) = (&*config, &request_id);
+    retry_dispatch(shared_context, &task.key, config, |_| async move {
+        let client = bp_routing::client_for(shared_context, task);
+        let result = send(client.clone(), config, image_workers, task, request_id).await;
+        shared_context
+            .public_bp_servers
+            .record_send(config, &client, &task.key, result.is_ok());
+        result
+    })
+    .await
+}
+
+///
+/// Sends refinement `revision` like `send_refinement`, retrying failures according to
+/// `AppConfig::bp_dispatch_retry`. Like `send_with_retry`, every attempt may go to another BP
+/// server. Retried attempts are recorded in the task event log.
+///
+pub async fn send_refinement_with_retry(
+    shared_context: &SharedContext,
+    task: &BackgroundRemoverTask,
+    revision: &TaskRevision,
+) -> Result<(), SendError> {
+    let config = shared_context.config.load_full();
+    let image_workers = &shared_context.image_workers;
+
+    let config = &*config;
+    retry_dispatch(shared_context, &task.key, config, |_| async move {
+        let client = bp_routing::client_for(shared_context, task);
+        let result = send_refinement(client.clone(), config, image_workers, task, revision).await;
+        shared_context.public_bp_servers.record_send(
+            config,
+            &client,
+            &revision.key,
+            result.is_ok(),
+        );
+        result
+    })
+    .await
+}
+
+async fn retry_dispatch<F, Fut>(
+    shared_context: &SharedContext,
+    key: &Uuid,
+    config: &AppConfig,
+    dispatch: F,
+) -> Result<(), SendError>
+where
+    F: FnMut(u32) -> Fut,
+    Fut: Future<Output = Result<(), SendError>>,
+{
+    let policy = config.bp_dispatch_retry;
+    let mut failed = vec![];
+    let result = retry_utils::retry(&policy, SendError::is_retryable, &mut failed, dispatch).await;
+
+    record_retries(shared_context, key, "bp_dispatch_retry", &policy, &failed).await;
+    if let Err(error) = &result {
+        eprintln!(
+            "Failed to send {} to BP server after {} attempts. Error: {}",
+            key,
+            failed.len() + 1,
+            error
+        );
+    }
+    result
+}
+
+///
+/// Sends original image of `task` together with the user correction mask of `revision` to the
+/// BP server as a refinement request. The BP server replies with `revision.key` as `task_id`.
+///
+pub async fn send_refinement(
+    bp_request_client: Arc<BPRequestClient>,
+    config: &AppConfig,
+    image_workers: &ImageWorkers,
+    task: &BackgroundRemoverTask,
+    revision: &TaskRevision,
+) -> Result<(), SendError> {
+    let message = json!({
+        "task_id": revision.key.to_string(),
+        "action": "refine",
+        "parent_task_id": task.key.to_string(),
+        "background_hint": task.background_hint,
     });
 
+    let original = read_original(config, image_workers, task).await?;
+    let correction = read_media_file(config, &revision.correction_image_path).await?;
+    let files = [original, File::new(b"correction.png".to_vec(), correction)];
+
+    write_to_bp_server(bp_request_client, config, &files, &message).await?;
+
+    println!("Sent refinement: {}", revision.key);
+    Ok(())
+}
+
+///
+/// Reads the original image of `task`. JPEG XL, which the BP server can't decode, is transcoded
+/// to PNG.
+///
+async fn read_original(
+    config: &AppConfig,
+    image_workers: &ImageWorkers,
+    task: &BackgroundRemoverTask,
+) -> Result<File, SendError> {
+    let buffer = read_media_file(config, &task.original_image_path).await?;
+    if !image_utils::is_jxl(&buffer) {
+        return Ok(File::new(b"original.jpg".to_vec(), buffer));
+    }
+
+    let memory = image_utils::estimate_memory(&buffer);
+    let png = image_workers
+        .run(memory, move || {
+            image_utils::transcode(&buffer, ImageFormat::Png)
+        })
+        .await
+        .map_err(SendError::Io)?
+        .map_err(|error| SendError::Io(std::io::Error::other(error)))?;
+    Ok(File::new(b"original.png".to_vec(), png))
+}
+
+///
+/// Reads file of relative media path within `original_read_timeout`.
+///
+async fn read_media_file(config: &AppConfig, relative_path: &str) -> Result<Vec<u8>, SendError> {
     let media_root = match env::var("MEDIA_ROOT") {
         Ok(path) => PathBuf::from(path),
         Err(error) => {
             eprintln!("MEDIA_ROOT environment variable is missing.");
-            return Err(std::io::Error::other(error));
+            return Err(SendError::Io(std::io::Error::other(error)));
         }
     };
 
-    let original_image_file_path = path_utils::file_path_from_relative_url(
-        media_root.clone(),
-        PathBuf::from(&task.original_image_path),
-    );
-    println!("MEDIA_ROOT: {:?}", media_root);
-    println!("ORIGINAL IMAGE PATH: {:?}", task.original_image_path);
-    println!("Original path: {:?}", original_image_file_path);
-
-    let mut original_image_file = fs::File::open(&original_image_file_path).await?;
-    let mut buffer = vec![];
-    original_image_file.read_to_end(&mut buffer).await?;
-    let file = File::new(b"original.jpg".to_vec(), buffer);
-    let files = [file];
-
-    // Sends files to BP Server.
-    let result = tokio::time::timeout(
-        Duration::from_secs(12),
-        bp_request_client.send(&files, &message),
+    let file_path =
+        path_utils::file_path_from_relative_url(media_root, PathBuf::from(relative_path));
+    println!("Reading media file: {:?}", file_path);
+
+    match tokio::time::timeout(config.original_read_timeout, fs::read(&file_path)).await {
+        Ok(result) => result.map_err(SendError::Io),
+        Err(_) => Err(SendError::Timeout(TimeoutStage::OriginalRead)),
+    }
+}
+
+async fn write_to_bp_server(
+    bp_request_client: Arc<BPRequestClient>,
+    config: &AppConfig,
+    files: &[File],
+    message: &Value,
+) -> Result<(), SendError> {
+    match tokio::time::timeout(
+        config.bp_send_timeout,
+        bp_request_client.send(files, message),
     )
-    .await?;
+    .await
+    {
+        Ok(result) => result.map_err(SendError::Io),
+        Err(_) => Err(SendError::Timeout(TimeoutStage::BpSend)),
+    }
+}
 
-    println!("Send task result: {:?}", result);
-    Ok(())
+///
+/// Records a stage timeout in the task event log and metrics.
+///
+pub async fn record_timeout(shared_context: &SharedContext, key: &Uuid, stage: TimeoutStage) {
+    shared_context.metrics.increment(&format!(
+        "pipeline_timeouts_total{{stage=\"{}\"}}",
+        stage.status_code()
+    ));
+
+    let timeout = stage.timeout(&shared_context.config.load());
+    let entry = TaskLogEntry::new("timeout", stage.status_code()).details(json!({
+        "timeout_secs": timeout.as_secs(),
+    }));
+
+    if let Err(error) =
+        BackgroundRemoverTask::append_log(shared_context.db_wrapper.clone(), key, &entry).await
+    {
+        eprintln!("Failed to append task log. Error: {}", error);
+    }
+
+    set_result_status(shared_context, key, ResultStatus::Timeout).await;
+}
+
+///
+/// Records retried attempts of an operation on task `key` in its event log and metrics, together
+/// with the policy in effect.
+///
+pub async fn record_retries(
+    shared_context: &SharedContext,
+    key: &Uuid,
+    status_code: &str,
+    policy: &RetryPolicy,
+    failed: &[FailedAttempt],
+) {
+    for attempt in failed {
+        shared_context
+            .metrics
+            .increment(&format!("retries_total{{operation=\"{}\"}}", status_code));
+
+        let entry = TaskLogEntry::new("retry", status_code)
+            .message(&attempt.error)
+            .details(json!({
+                "attempt": attempt.attempt,
+                "delay_millis": attempt.delay.as_millis() as u64,
+                "policy": policy.to_json(),
+            }));
+
+        if let Err(error) =
+            BackgroundRemoverTask::append_log(shared_context.db_wrapper.clone(), key, &entry).await
+        {
+            eprintln!("Failed to append task log. Error: {}", error);
+        }
+    }
+}
+
+///
+/// Records outcome of a task. Failures are only logged since clients were already notified.
+///
+/// Once the last task of a task group has its outcome, the completion email of the group is sent.
+///
+async fn set_result_status(shared_context: &SharedContext, key: &Uuid, status: ResultStatus) {
+    if let Err(error) =
+        BackgroundRemoverTask::set_result_status(shared_context.db_wrapper.clone(), key, status)
+            .await
+    {
+        eprintln!("Failed to set result status. Error: {}", error);
+        return;
+    }
+
+    tokio::spawn(batch_notifications::task_finished(
+        shared_context.clone(),
+        *key,
+    ));
+}
+
+///
+/// Stores durations of `stages` of the task for the stats endpoint in the background, so
+/// dispatches and results don't wait for it. Failures are only logged.
+///
+fn record_timings(shared_context: &SharedContext, key: &Uuid, stages: Vec<(TaskStage, Duration)>) {
+    let db_wrapper = shared_context.db_wrapper.clone();
+    let key = *key;
+    tokio::spawn(async move {
+        if let Err(error) = BackgroundRemoverTask::record_timings(db_wrapper, &key, &stages).await {
+            eprintln!("Failed to record task timings. Error: {}", error);
+        }
+    });
+}
+
+///
+/// Called when handling a BP server response exceeded its timeout. Records the timeout and
+/// notifies clients of the task group.
+///
+pub async fn handle_response_timeout(shared_context: SharedContext, message: &Value) {
+    let key = match message
+        .get("task_id")
+        .and_then(|task_id| task_id.as_str())
+        .and_then(|task_id| Uuid::parse_str(task_id).ok())
+    {
+        Some(key) => key,
+        None => return,
+    };
+
+    record_timeout(&shared_context, &key, TimeoutStage::BpResponse).await;
+    // Stops counting the task as queued, in case the handler timed out before finishing it. Not
+    // sampled, as the handler's own delay says nothing about the BP server.
+    shared_context.processing_times.cancel(&key);
+
+    let instance = match BackgroundRemoverTask::fetch(shared_context.db_wrapper.clone(), &key).await
+    {
+        Ok(instance) => instance,
+        Err(error) => {
+            eprintln!("Failed to fetch background remover task. Error: {}", error);
+            return;
+        }
+    };
+
+    // The result may not have been stored, so the BP server may deliver it again.
+    let request_id = message
+        .get("request_id")
+        .and_then(|request_id| request_id.as_str())
+        .and_then(|request_id| Uuid::parse_str(request_id).ok())
+        .or(instance.bp_request_id);
+    release_result(&shared_context, &key, request_id).await;
+
+    shared_context.lifecycle.emit(
+        LifecycleEvent::new(LifecycleStage::Failed, key, instance.task_group)
+            .status_code(TimeoutStage::BpResponse.status_code()),
+    );
+    shared_context
+        .ws_clients
+        .broadcast(
+            &instance.task_group,
+            &ServerMessage::failed(
+                TimeoutStage::BpResponse.error_code(),
+                "Timed out while saving the processed image.",
+            ),
+        )
+        .await;
 }
 
 pub async fn handle_ws_received_message(
     task_group: &Uuid,
-    websocket: &WebSocket,
+    connection: &WsConnection,
     shared_context: &SharedContext,
     message: Message,
 ) {
     match message {
         Message::Text(text) => {
-            println!("Received: {}", text);
-
-            let json = match Value::from_str(&text) {
-                Ok(value) => value,
-                Err(error) => {
-                    eprintln!("Failed to parse text to JSON. Error: {}", error);
-
-                    // Invalid JSON message is received. Returns error response to the client.
-                    let _ = websocket
-                        .send_json(&json!({
-                            "status": "failed",
-                            "status_code": "invalid_message_format",
-                            "message": "Not a valid message format. Expected type JSON.",
-                        }))
-                        .await;
-                    return;
-                }
-            };
-
-            let key;
-            if let Some(value) = json.get("key") {
-                key = value;
-            } else {
+            // Refused unparsed, like oversized request bodies in the middleware.
+            let max_size = shared_context.config.load().max_ws_text_frame_size;
+            if text.len() > max_size {
+                shared_context
+                    .metrics
+                    .increment("ws_oversized_messages_total");
+                connection.send(&ServerMessage::failed(
+                    ErrorCode::PayloadTooLarge,
+                    &format!("Messages are limited to {} bytes.", max_size),
+                ));
                 return;
             }
+            println!("Received: {}", text);
 
-            if let Some(key) = key.as_str() {
-                let key = match Uuid::parse_str(key) {
-                    Ok(uuid) => uuid,
-                    Err(error) => {
-                        eprint!("Failed to parse key to UUID. Error: {}", error);
-
-                        let _ = websocket
-                            .send_json(&json!({
-                                "status": "failed",
-                                "status_code": "invalid_message_format",
-                                "message": "Invalid key format.",
-                            }))
-                            .await;
-                        return;
-                    }
-                };
-
-                handle_process_image_command(task_group, key, websocket, shared_context).await;
+            match ClientMessage::parse(&text) {
+                Ok(ClientMessage::ProcessImage { key }) => {
+                    handle_process_image_command(task_group, key, connection, shared_context).await;
+                }
+                Ok(ClientMessage::Hello { capabilities }) => {
+                    handle_hello_command(connection, capabilities).await;
+                }
+                Ok(ClientMessage::Ping) => {
+                    // Heartbeat of the client, so its connection isn't considered stale.
+                    connection.touch();
+                    connection.send(&ServerMessage::pong());
+                }
+                Err(error_message) => {
+                    connection.send(&error_message);
+                }
             }
         }
         _ => {}
     }
 }
 
+///
+/// Enables known capabilities requested by the client and replies with the enabled ones. Unknown
+/// capabilities are ignored.
+///
+pub async fn handle_hello_command(connection: &WsConnection, capabilities: Vec<String>) {
+    let accepted: Vec<String> = capabilities
+        .into_iter()
+        .filter(|capability| ClientMessage::CAPABILITIES.contains(&capability.as_str()))
+        .collect();
+
+    connection.set_binary_preview(
+        accepted
+            .iter()
+            .any(|capability| capability == "binary_preview"),
+    );
+
+    let msgpack = accepted.iter().any(|capability| capability == "msgpack");
+
+    // The reply is still JSON, so clients can tell whether MessagePack was accepted.
+    connection.send(&ServerMessage::capabilities(accepted));
+    connection.set_msgpack(msgpack);
+}
+
 pub async fn handle_process_image_command(
     task_group: &Uuid,
     key: Uuid,
-    websocket: &WebSocket,
+    connection: &WsConnection,
     shared_context: &SharedContext,
 ) {
+    let received = Instant::now();
+    let banned = match &connection.ip {
+        Some(ip) => shared_context.key_lookup_guard.banned_for(ip).is_some(),
+        None => false,
+    };
+    if banned {
+        connection.send(&ServerMessage::failed(
+            ErrorCode::TooManyKeyFailures,
+            "Too many invalid task keys. Try again later.",
+        ));
+        return;
+    }
+
     let db_wrapper = shared_context.db_wrapper.clone();
     let instance = match BackgroundRemoverTask::fetch(db_wrapper.clone(), &key).await {
         Ok(instance) => instance,
         Err(error) => {
             match error {
                 sqlx::Error::RowNotFound => {
-                    let _ = websocket
-                        .send_json(&json!({
-                            "status": "failed",
-                            "status_code": "not_found",
-                            "message": "Image with this key does not exist."
-                        }))
-                        .await;
+                    shortcuts::record_key_lookup_failure(shared_context, connection.ip.as_deref());
+                    connection.send(&ServerMessage::failed(
+                        ErrorCode::NotFound,
+                        "Image with this key does not exist.",
+                    ));
                 }
                 _ => {
                     eprintln!("Failed to fetch instance. Error: {}", error);
-                    shortcuts::internal_server_error(websocket).await;
+                    shortcuts::internal_server_error(connection);
                 }
             }
             return;
@@ -153,13 +562,21 @@ pub async fn handle_process_image_command(
     };
 
     if &instance.task_group != task_group {
-        let _ = websocket
-            .send_json(&json!({
-                "status": "failed",
-                "status_code": "permission_error",
-                "message": "This task_group does not have permission to process image with this key."
-            }))
-            .await;
+        shortcuts::record_key_lookup_failure(shared_context, connection.ip.as_deref());
+        connection.send(&ServerMessage::failed(
+            ErrorCode::PermissionError,
+            "This task_group does not have permission to process image with this key.",
+        ));
+        return;
+    }
+
+    // Tasks of a tenant require credentials of that tenant, like task details.
+    if !connection.tenant_access.allows(instance.tenant.as_slice()) {
+        shortcuts::record_key_lookup_failure(shared_context, connection.ip.as_deref());
+        connection.send(&ServerMessage::failed(
+            ErrorCode::Unauthorized,
+            "Credentials of the task's tenant are required.",
+        ));
         return;
     }
 
@@ -172,129 +589,650 @@ pub async fn handle_process_image_command(
     let need_processing = is_process_hard || !is_processing;
 
     if !need_processing {
-        // Image is already processed.
-        let serialized = match instance.serialize() {
+        previews::refresh_if_stale(shared_context, &instance).await;
+
+        // Image is already processed. Clients get outputs of the latest revision.
+        let latest_revision =
+            match TaskRevision::fetch_latest_completed(db_wrapper.clone(), &instance.key).await {
+                Ok(revision) => revision,
+                Err(error) => {
+                    eprintln!("Failed to fetch latest revision. Error: {}", error);
+                    internal_server_error(connection);
+                    return;
+                }
+            };
+
+        let serialized = match shared_context
+            .task_json_cache
+            .serialize(&instance, latest_revision.as_ref())
+        {
             Ok(serialized) => serialized,
             Err(error) => {
                 eprintln!("Failed to serialize data. Error: {}", error);
-                internal_server_error(websocket).await;
+                internal_server_error(connection);
                 return;
             }
         };
 
-        let _ = websocket
-            .send_json(&json!({
-                "status": "success",
-                "status_code": "result",
-                "data": serialized,
-            }))
-            .await;
+        connection.send(&ServerMessage::result(serialized));
     } else {
-        // Send this image for processing.
-        println!("Sending task: {} to Bp Server.", instance.task_id);
-        match send(shared_context.bp_request_client.clone(), &instance).await {
-            Ok(()) => {
-                println!("Sent task successfully for processing.");
-                let _ = BackgroundRemoverTask::update_processing_state(
-                    db_wrapper.clone(),
-                    &instance.key,
-                    true,
-                )
-                .await;
+        match dispatch(shared_context, &instance, received).await {
+            Dispatch::Sent => {
+                let processing_times = &shared_context.processing_times;
+                let estimated_seconds =
+                    processing_times.estimate_seconds(processing_times.queue_depth());
+                connection.send(&ServerMessage::queued(estimated_seconds));
+
+                canary::dispatch(shared_context, &instance);
             }
-            Err(error) => {
-                eprintln!("{}", instance.original_image_path);
-                eprintln!("Failed to send task to bp server. Error: {}", error);
+            Dispatch::Held => {
+                let processing_times = &shared_context.processing_times;
+                let estimated_seconds =
+                    processing_times.estimate_seconds(processing_times.queue_depth());
+                connection.send(&ServerMessage::queued(estimated_seconds));
             }
-        };
+            // The result was delivered to the task group, this connection included.
+            Dispatch::Deduplicated => {}
+            Dispatch::Failed {
+                status_code,
+                message,
+            } => connection.send(&ServerMessage::failed(status_code, message)),
+        }
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct BPResponse {
-    task_id: Uuid,
-    status: String,
-    status_code: String,
-    message: Option<String>,
-    timestamps: Option<Value>,
+///
+/// Outcome of `dispatch`.
+///
+pub enum Dispatch {
+    Sent,
+    /// Another replica, or another connection of the task group, is sending the task.
+    Held,
+    /// Served from the outputs of an earlier task instead, and the result delivered. See
+    /// `AppConfig::upload_dedup_window`.
+    Deduplicated,
+    /// Sending failed and the task was marked accordingly. The status code and message are meant
+    /// for clients.
+    Failed {
+        status_code: ErrorCode,
+        message: &'static str,
+    },
 }
 
-pub async fn handle_response_received_from_bp_server(
-    shared_context: SharedContext,
-    files: Vec<File>,
-    messsage: Value,
-) {
-    println!("Received from bp server: {}", messsage);
-    let bp_response: BPResponse = match serde_json::from_value(messsage) {
-        Ok(instance) => instance,
-        Err(error) => {
-            eprintln!(
-                "Invalid format message received from BP Server. Error: {}",
-                error
-            );
-            return;
-        }
-    };
-
-    let instance =
-        match BackgroundRemoverTask::fetch(shared_context.db_wrapper.clone(), &bp_response.task_id)
-            .await
-        {
-            Ok(instance) => instance,
-            Err(error) => {
-                eprintln!("Failed to fetch background remover task. Error: {}", error);
+///
+/// Sends `instance` for processing unless it is already being sent, and marks it processing.
+/// Tasks of an image processed shortly before are served from the earlier outputs instead.
+/// Failures are recorded on the task and reported to ops. Mirroring to the canary is left to the
+/// caller, once clients were answered. `requested` is when processing was asked for, from which
+/// the queue wait of the task is measured.
+///
+pub async fn dispatch(
+    shared_context: &SharedContext,
+    instance: &BackgroundRemoverTask,
+    requested: Instant,
+) -> Dispatch {
+    // Another replica, or another connection of the task group, may be sending the same task.
+    // The lease is left to expire once sent.
+    let lease = shared_context
+        .locks
+        .claim(&format!("dispatch:{}", instance.key), DISPATCH_LEASE_TTL)
+        .await;
+    if lease.is_held() {
+        return Dispatch::Held;
+    }
 
-                // Nothing can be done.
-                return;
-            }
-        };
+    if serve_from_dedup(shared_context, instance).await {
+        return Dispatch::Deduplicated;
+    }
 
-    if bp_response.status == "success" {
-        let is_fake_processed = bp_response.status_code == "fake_process_completed";
-        handle_files_received_from_bp_server(shared_context, instance, &files, is_fake_processed)
-            .await;
-    } else {
-        let websockets = shared_context
-            .ws_clients
-            .get_all(&instance.task_group)
+    // Send this image for processing.
+    println!("Sending task: {} to Bp Server.", instance.task_id);
+    match send_with_retry(shared_context, instance).await {
+        Ok(()) => {
+            println!("Sent task successfully for processing.");
+            let _ = BackgroundRemoverTask::update_processing_state(
+                shared_context.db_wrapper.clone(),
+                &instance.key,
+                true,
+            )
             .await;
 
-        for websocket in websockets {
-            let _ = websocket
-                .send_json(&json!({
-                    "status": bp_response.status,
-                    "status_code": bp_response.status_code,
-                    "message": bp_response.message,
-                }))
-                .await;
+            shared_context
+                .progress
+                .remember_task_group(instance.key, instance.task_group);
+            shared_context.processing_times.start(instance.key);
+            // Dispatches of a task which already has an outcome are reprocessing, not queueing.
+            if instance.result_status.is_none() {
+                let queue_wait = requested.elapsed();
+                record_timings(
+                    shared_context,
+                    &instance.key,
+                    vec![(TaskStage::QueueWait, queue_wait)],
+                );
+            }
+            shared_context.lifecycle.emit(LifecycleEvent::new(
+                LifecycleStage::Sent,
+                instance.key,
+                instance.task_group,
+            ));
+            Dispatch::Sent
+        }
+        Err(error) => {
+            lease.release().await;
+            eprintln!("{}", instance.original_image_path);
+            eprintln!("Failed to send task to bp server. Error: {}", error);
+
+            match error {
+                SendError::Timeout(stage) => {
+                    record_timeout(shared_context, &instance.key, stage).await;
+                    shared_context.lifecycle.emit(
+                        LifecycleEvent::new(
+                            LifecycleStage::Failed,
+                            instance.key,
+                            instance.task_group,
+                        )
+                        .status_code(stage.status_code()),
+                    );
+                    if let Some(notifier) = &shared_context.ops_notifier {
+                        notifier.notify(OpsEvent::TaskUndeliverable {
+                            key: instance.key,
+                            reason: stage.status_code().to_string(),
+                        });
+                    }
+                    Dispatch::Failed {
+                        status_code: stage.error_code(),
+                        message: "Timed out while sending image for processing.",
+                    }
+                }
+                SendError::Io(error) => {
+                    set_result_status(shared_context, &instance.key, ResultStatus::Failed).await;
+                    shared_context.lifecycle.emit(
+                        LifecycleEvent::new(
+                            LifecycleStage::Failed,
+                            instance.key,
+                            instance.task_group,
+                        )
+                        .status_code(ErrorCode::BpSendFailed.as_str()),
+                    );
+                    if let Some(notifier) = &shared_context.ops_notifier {
+                        notifier.notify(OpsEvent::TaskUndeliverable {
+                            key: instance.key,
+                            reason: error.to_string(),
+                        });
+                    }
+                    Dispatch::Failed {
+                        status_code: ErrorCode::BpSendFailed,
+                        message: "Failed to send image for processing.",
+                    }
+                }
+            }
         }
     }
 }
 
-async fn handle_files_received_from_bp_server(
-    shared_context: SharedContext,
-    instance: BackgroundRemoverTask,
-    files: &Vec<File>,
-    is_fake_processed: bool,
-) {
-    // Saves files received from BP Server. These paths are absolute and should not be used for
-    // saving in database.
-    let (transparent_image_path, mask_image_path, preview_transparent_image_path) =
-        match save_utils::save_files_received_from_bp_server(&instance, &files, is_fake_processed)
-            .await
-        {
-            Ok(paths) => paths,
-            Err(error) => {
+///
+/// Serves `instance` from the outputs of the latest task with the same original image and
+/// options within `AppConfig::upload_dedup_window`, like a result of the BP server. Returns false
+/// when the task has to be processed. Tasks whose upload opted out with `X-No-Dedup` are neither
+/// counted as hit nor miss.
+///
+async fn serve_from_dedup(
+    shared_context: &SharedContext,
+    instance: &BackgroundRemoverTask,
+) -> bool {
+    let window = match shared_context.config.load().upload_dedup_window {
+        Some(window) if instance.content_sha256.is_some() => window,
+        _ => return false,
+    };
+    let miss = || {
+        shared_context
+            .metrics
+            .increment("upload_dedup_misses_total");
+        false
+    };
+
+    let db_wrapper = shared_context.db_wrapper.clone();
+    let source = match BackgroundRemoverTask::fetch_dedup_source(db_wrapper, instance, window).await
+    {
+        Ok(Some(source)) => source,
+        Ok(None) => return miss(),
+        Err(error) => {
+            eprintln!("Failed to fetch deduplication source. Error: {}", error);
+            return miss();
+        }
+    };
+
+    // Media of the source may have been removed meanwhile, e.g. by erasure of its user.
+    let manifest =
+        match save_utils::copy_outputs(instance.tenant.as_deref(), &source, &instance.key).await {
+            Ok(manifest) => manifest,
+            Err(error) => {
                 eprintln!(
-                    "Failed to save files received from bp server. Error: {}",
-                    error
+                    "Failed to copy outputs of task {} for deduplication. Error: {}",
+                    source.key, error
                 );
+                return miss();
+            }
+        };
+    let path_of = |role: &str| {
+        manifest
+            .iter()
+            .find(|file| file.role == role)
+            .map(|file| file.path.clone())
+    };
+    let mask_image_path = match path_of(ManifestFile::MASK) {
+        Some(path) => path,
+        None => return miss(),
+    };
+    let preview_path = path_of(ManifestFile::PREVIEW_PROCESSED).unwrap_or(mask_image_path.clone());
 
-                broadcast_internal_server_error(shared_context.clone(), &instance.task_group).await;
+    let update_task = UpdateBackgroundRemoverTask {
+        key: instance.key,
+        mask_image_path,
+        processed_image_path: path_of(ManifestFile::PROCESSED),
+        preview_processed_image_path: path_of(ManifestFile::PREVIEW_PROCESSED),
+        manifest: manifest.clone(),
+        output_max_pixels: source.output_max_pixels,
+        request_id: None,
+    };
+    let outbox_id =
+        match BackgroundRemoverTask::update_task(shared_context.db_wrapper.clone(), &update_task)
+            .await
+        {
+            Ok(Some(outbox_id)) => outbox_id,
+            Ok(None) => return miss(),
+            Err(error) => {
+                eprintln!("Failed to update deduplicated task. Error: {}", error);
+                return miss();
+            }
+        };
+
+    println!(
+        "Serving task {} from outputs of task {}.",
+        instance.key, source.key
+    );
+    shared_context.metrics.increment("upload_dedup_hits_total");
+    let db_wrapper = shared_context.db_wrapper.clone();
+    if let Err(error) =
+        BackgroundRemoverTask::set_dedup_of(db_wrapper.clone(), &instance.key, &source.key).await
+    {
+        eprintln!("Failed to record deduplication source. Error: {}", error);
+    }
+    if let Err(error) = BackgroundRemoverTask::set_bp_identity(
+        db_wrapper.clone(),
+        &instance.key,
+        source.bp_worker_id.as_deref(),
+        source.bp_model_version.as_deref(),
+    )
+    .await
+    {
+        eprintln!("Failed to store BP server identity. Error: {}", error);
+    }
+
+    shared_context.lifecycle.emit(LifecycleEvent::new(
+        LifecycleStage::Completed,
+        instance.key,
+        instance.task_group,
+    ));
+
+    // On failures the outbox job delivers the result later.
+    let fresh_instance = match BackgroundRemoverTask::fetch(db_wrapper, &instance.key).await {
+        Ok(instance) => instance,
+        Err(error) => {
+            eprintln!("Failed to fetch deduplicated task. Error: {}", error);
+            return true;
+        }
+    };
+    let serialized = match shared_context
+        .task_json_cache
+        .serialize(&fresh_instance, None)
+    {
+        Ok(serialized) => serialized,
+        Err(error) => {
+            eprintln!("Failed to serialize deduplicated task. Error: {}", error);
+            return true;
+        }
+    };
+
+    let media_root = PathBuf::from(env::var("MEDIA_ROOT").unwrap_or_default());
+    deliver_result(
+        shared_context,
+        &instance.task_group,
+        instance.key,
+        ServerMessage::result(serialized),
+        &path_utils::file_path_from_relative_url(media_root, PathBuf::from(preview_path)),
+    )
+    .await;
+    finish_delivery(shared_context, outbox_id, &instance.key).await;
+
+    if !instance.is_mask_only() {
+        tokio::spawn(previews::generate(
+            shared_context.clone(),
+            instance.key,
+            PreviewOf::Processed,
+        ));
+    }
+    true
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BPResponse {
+    task_id: Uuid,
+    status: String,
+    status_code: String,
+    message: Option<String>,
+    timestamps: Option<Value>,
+    #[serde(default)]
+    worker_id: Option<String>,
+    #[serde(default)]
+    model_version: Option<String>,
+    /// Echo of the `request_id` the task was sent with. Missing from older BP servers.
+    #[serde(default)]
+    request_id: Option<Uuid>,
+}
+
+pub async fn handle_response_received_from_bp_server(
+    shared_context: SharedContext,
+    files: Vec<File>,
+    messsage: Value,
+) {
+    println!("Received from bp server: {}", messsage);
+    let bp_response: BPResponse = match serde_json::from_value(messsage) {
+        Ok(instance) => instance,
+        Err(error) => {
+            eprintln!(
+                "Invalid format message received from BP Server. Error: {}",
+                error
+            );
+            return;
+        }
+    };
+
+    // Progress of tasks sent by this instance is fanned out without touching the database.
+    if bp_response.status == "pending" {
+        if let Some(task_group) = shared_context.progress.task_group(&bp_response.task_id) {
+            handle_progress(&shared_context, task_group, bp_response).await;
+            return;
+        }
+    }
+
+    let instance =
+        match BackgroundRemoverTask::fetch(shared_context.db_wrapper.clone(), &bp_response.task_id)
+            .await
+        {
+            Ok(instance) => instance,
+            Err(sqlx::Error::RowNotFound) => {
+                // Refinement responses carry the revision key as `task_id`.
+                handle_revision_response(shared_context, bp_response, &files).await;
+                return;
+            }
+            Err(error) => {
+                eprintln!("Failed to fetch background remover task. Error: {}", error);
+
+                // Nothing can be done.
                 return;
             }
         };
 
+    // Results and failures of an earlier dispatch must not replace those of a later one.
+    let is_outcome = bp_response.status == "success" || bp_response.status == "failed";
+    if let (true, Some(request_id)) = (is_outcome, &bp_response.request_id) {
+        if instance.bp_request_id.as_ref() != Some(request_id) {
+            drop_stale_response(&shared_context, &instance.key, request_id).await;
+            return;
+        }
+    }
+
+    if bp_response.status == "success" {
+        // Older BP servers don't echo the request id. Their results belong to the latest dispatch.
+        let request_id = bp_response.request_id.or(instance.bp_request_id);
+        if let Some(request_id) = &request_id {
+            match BackgroundRemoverTask::claim_result(
+                shared_context.db_wrapper.clone(),
+                &instance.key,
+                request_id,
+            )
+            .await
+            {
+                Ok(ResultClaim::Claimed) => {}
+                Ok(ResultClaim::Duplicate) => {
+                    println!(
+                        "Dropping duplicate result of task {} for request {}.",
+                        instance.key, request_id
+                    );
+                    shared_context
+                        .metrics
+                        .increment("bp_duplicate_responses_total");
+                    return;
+                }
+                Ok(ResultClaim::Stale) => {
+                    drop_stale_response(&shared_context, &instance.key, request_id).await;
+                    return;
+                }
+                Err(error) => {
+                    eprintln!("Failed to claim task result. Error: {}", error);
+                    return;
+                }
+            }
+        }
+
+        shared_context.progress.forget(&instance.key);
+        let is_fake_processed = bp_response.status_code == "fake_process_completed";
+
+        // Older BP servers only announce themselves on the connection, if at all. Tasks of
+        // pinned keys were answered by their dedicated connection, others most likely by the
+        // public connection tasks are currently sent to.
+        let connection = match instance
+            .api_key_id
+            .as_deref()
+            .and_then(|key_id| shared_context.dedicated_bp_servers.client(key_id))
+        {
+            Some(dedicated) => dedicated.clone(),
+            None => shared_context
+                .public_bp_servers
+                .pick(&shared_context.config.load()),
+        };
+        let identity = ServerIdentity {
+            worker_id: bp_response.worker_id,
+            model_version: bp_response.model_version,
+        }
+        .or(connection.server_identity());
+
+        handle_files_received_from_bp_server(
+            shared_context,
+            instance,
+            &files,
+            is_fake_processed,
+            identity,
+            request_id,
+        )
+        .await;
+    } else if bp_response.status == "pending" {
+        shared_context
+            .progress
+            .remember_task_group(instance.key, instance.task_group);
+        handle_progress(&shared_context, instance.task_group, bp_response).await;
+    } else {
+        let processing_times = &shared_context.processing_times;
+        let mut message = ServerMessage::status(
+            &bp_response.status,
+            &bp_response.status_code,
+            bp_response.message,
+        );
+
+        if bp_response.status_code == "queued" {
+            message = message.with_estimated_seconds(
+                processing_times.estimate_seconds(processing_times.queue_depth()),
+            );
+        } else if bp_response.status == "failed" {
+            processing_times.cancel(&instance.key);
+            shared_context.progress.forget(&instance.key);
+            set_result_status(&shared_context, &instance.key, ResultStatus::Failed).await;
+            shared_context.lifecycle.emit(
+                LifecycleEvent::new(LifecycleStage::Failed, instance.key, instance.task_group)
+                    .status_code(&bp_response.status_code),
+            );
+        }
+
+        shared_context
+            .ws_clients
+            .broadcast(&instance.task_group, &message)
+            .await;
+    }
+}
+
+///
+/// Sends progress reported by the BP server to the task group and buffers it for the progress
+/// flush job.
+///
+async fn handle_progress(
+    shared_context: &SharedContext,
+    task_group: Uuid,
+    bp_response: BPResponse,
+) {
+    let processing_times = &shared_context.processing_times;
+    let estimated_seconds = processing_times.estimate_seconds(processing_times.queue_depth());
+
+    let message = ServerMessage::status(
+        &bp_response.status,
+        &bp_response.status_code,
+        bp_response.message.clone(),
+    )
+    .with_estimated_seconds(estimated_seconds);
+
+    shared_context.lifecycle.emit(
+        LifecycleEvent::new(LifecycleStage::Progress, bp_response.task_id, task_group)
+            .status_code(&bp_response.status_code),
+    );
+    shared_context.progress.buffer(
+        bp_response.task_id,
+        json!({
+            "status_code": bp_response.status_code,
+            "message": bp_response.message,
+            "timestamps": bp_response.timestamps,
+        }),
+    );
+
+    shared_context
+        .ws_clients
+        .broadcast(&task_group, &message)
+        .await;
+}
+
+///
+/// Drops a response to dispatch `request_id` of task `key` which was dispatched again since.
+/// Recorded in the task event log and metrics.
+///
+async fn drop_stale_response(shared_context: &SharedContext, key: &Uuid, request_id: &Uuid) {
+    println!(
+        "Dropping stale response of task {} for request {}.",
+        key, request_id
+    );
+    shared_context.metrics.increment("bp_stale_responses_total");
+
+    let entry = TaskLogEntry::new("stale_response", "bp_stale_response")
+        .message("Response of an earlier dispatch arrived after the task was dispatched again.")
+        .details(json!({ "request_id": request_id }));
+    if let Err(error) =
+        BackgroundRemoverTask::append_log(shared_context.db_wrapper.clone(), key, &entry).await
+    {
+        eprintln!("Failed to append task log. Error: {}", error);
+    }
+}
+
+///
+/// Releases the result claim of `request_id` after its result could not be stored.
+///
+async fn release_result(shared_context: &SharedContext, key: &Uuid, request_id: Option<Uuid>) {
+    let request_id = match request_id {
+        Some(request_id) => request_id,
+        None => return,
+    };
+
+    if let Err(error) =
+        BackgroundRemoverTask::release_result(shared_context.db_wrapper.clone(), key, &request_id)
+            .await
+    {
+        eprintln!("Failed to release task result claim. Error: {}", error);
+    }
+}
+
+///
+/// Invalid files received from the BP server fail the same way every time.
+///
+fn is_retryable_storage_error(error: &std::io::Error) -> bool {
+    error.kind() != std::io::ErrorKind::InvalidData
+}
+
+async fn handle_files_received_from_bp_server(
+    shared_context: SharedContext,
+    instance: BackgroundRemoverTask,
+    files: &Vec<File>,
+    is_fake_processed: bool,
+    identity: ServerIdentity,
+    request_id: Option<Uuid>,
+) {
+    // Processing an already processed task again keeps the previous outputs and stores the new
+    // ones as a revision. Mask only tasks only keep their latest mask.
+    let mask_only = instance.is_mask_only();
+    if instance.processed_image_path.is_some() && !mask_only {
+        handle_reprocessed_files(
+            shared_context,
+            instance,
+            files,
+            is_fake_processed,
+            request_id,
+        )
+        .await;
+        return;
+    }
+
+    // Saves files received from BP Server. These paths are absolute and should not be used for
+    // saving in database. Files are named after their content, so a retry rewrites the same files.
+    let save_started = Instant::now();
+    let policy = shared_context.config.load().storage_retry;
+    let mut failed = vec![];
+    let task = &instance;
+    let saved = retry_utils::retry(
+        &policy,
+        is_retryable_storage_error,
+        &mut failed,
+        |_| async move {
+            if mask_only {
+                save_utils::save_mask_received_from_bp_server(task, files)
+                    .await
+                    .map(|mask_image_path| (None, mask_image_path, None))
+            } else {
+                save_utils::save_files_received_from_bp_server(task, files, is_fake_processed)
+                    .await
+                    .map(|(transparent, mask, preview)| (Some(transparent), mask, Some(preview)))
+            }
+        },
+    )
+    .await;
+    record_retries(
+        &shared_context,
+        &instance.key,
+        "storage_retry",
+        &policy,
+        &failed,
+    )
+    .await;
+
+    let (transparent_image_path, mask_image_path, preview_transparent_image_path) = match saved {
+        Ok(paths) => paths,
+        Err(error) => {
+            eprintln!(
+                "Failed to save files received from bp server. Error: {}",
+                error
+            );
+
+            release_result(&shared_context, &instance.key, request_id).await;
+            broadcast_internal_server_error(shared_context.clone(), &instance.task_group).await;
+            return;
+        }
+    };
+
     let media_root = match env::var("MEDIA_ROOT") {
         Ok(path) => PathBuf::from(path),
         Err(error) => {
@@ -302,55 +1240,120 @@ async fn handle_files_received_from_bp_server(
                 "The MEDIA_ROOT path is not specified in environment variable. Error: {}",
                 error
             );
+            release_result(&shared_context, &instance.key, request_id).await;
             broadcast_internal_server_error(shared_context.clone(), &instance.task_group).await;
             return;
         }
     };
 
     // Converts to relative media url for saving in database.
-    let relative_mask_image_path =
-        path_utils::relative_media_url_from_full_path(&media_root, &mask_image_path);
-    let relative_transparent_image_path =
-        path_utils::relative_media_url_from_full_path(&media_root, &transparent_image_path);
-    let relative_preview_transparent_image_path =
-        path_utils::relative_media_url_from_full_path(&media_root, &preview_transparent_image_path);
-
-    let update_task = UpdateBackgroundRemoverTask {
-        key: instance.key,
-        logs: instance.logs,
-        mask_image_path: relative_mask_image_path.to_string_lossy().to_string(),
-        processed_image_path: relative_transparent_image_path
-            .to_string_lossy()
-            .to_string(),
-        preview_processed_image_path: relative_preview_transparent_image_path
+    let relative_media_url = |path: &PathBuf| {
+        path_utils::relative_media_url_from_full_path(&media_root, path)
             .to_string_lossy()
-            .to_string(),
+            .to_string()
     };
 
-    match BackgroundRemoverTask::update_task(shared_context.db_wrapper.clone(), &update_task).await
+    // Free tier tasks are served downscaled, watermarked outputs. The outputs as received are only
+    // kept in the manifest, for a later upgrade.
+    let limited = match apply_plan_limits(
+        &shared_context,
+        instance.free_tier,
+        mask_image_path,
+        transparent_image_path,
+        preview_transparent_image_path,
+    )
+    .await
     {
-        Ok(()) => {}
+        Ok(limited) => limited,
         Err(error) => {
-            eprintln!("Failed to update task record in database. Error: {}", error);
+            eprintln!("Failed to apply plan limits to outputs. Error: {}", error);
+            release_result(&shared_context, &instance.key, request_id).await;
             broadcast_internal_server_error(shared_context.clone(), &instance.task_group).await;
             return;
         }
     };
 
-    // Marks this task as completed.
-    match BackgroundRemoverTask::update_processing_state(
+    let mut outputs = vec![(ManifestFile::MASK, &limited.mask)];
+    if let Some(path) = &limited.processed {
+        outputs.push((ManifestFile::PROCESSED, path));
+    }
+    if let Some(path) = &limited.preview {
+        outputs.push((ManifestFile::PREVIEW_PROCESSED, path));
+    }
+    if let Some(path) = &limited.received_processed {
+        outputs.push((ManifestFile::UNWATERMARKED, path));
+    }
+    if let Some(path) = &limited.received_mask {
+        outputs.push((ManifestFile::UNCAPPED_MASK, path));
+    }
+
+    let mut manifest = vec![];
+    for (role, path) in outputs {
+        match save_utils::manifest_file(role, &media_root, path).await {
+            Ok(file) => manifest.push(file),
+            Err(error) => {
+                eprintln!("Failed to read saved file for manifest. Error: {}", error);
+                release_result(&shared_context, &instance.key, request_id).await;
+                broadcast_internal_server_error(shared_context.clone(), &instance.task_group).await;
+                return;
+            }
+        }
+    }
+
+    let update_task = UpdateBackgroundRemoverTask {
+        key: instance.key,
+        mask_image_path: relative_media_url(&limited.mask),
+        processed_image_path: limited.processed.as_ref().map(relative_media_url),
+        preview_processed_image_path: limited.preview.as_ref().map(relative_media_url),
+        manifest,
+        output_max_pixels: limited.max_pixels.map(|max_pixels| max_pixels as i64),
+        request_id,
+    };
+
+    // Result status, processing state and the outbox entry of the result notification are stored
+    // with the outputs, so a crash before delivery leaves the notification to the outbox job.
+    let outbox_id =
+        match BackgroundRemoverTask::update_task(shared_context.db_wrapper.clone(), &update_task)
+            .await
+        {
+            Ok(Some(outbox_id)) => outbox_id,
+            Ok(None) => {
+                // Dispatched again while the files were being saved.
+                if let Some(request_id) = &request_id {
+                    drop_stale_response(&shared_context, &instance.key, request_id).await;
+                }
+                return;
+            }
+            Err(error) => {
+                eprintln!("Failed to update task record in database. Error: {}", error);
+                release_result(&shared_context, &instance.key, request_id).await;
+                broadcast_internal_server_error(shared_context.clone(), &instance.task_group).await;
+                return;
+            }
+        };
+    let save = save_started.elapsed();
+
+    if let Err(error) = BackgroundRemoverTask::set_bp_identity(
         shared_context.db_wrapper.clone(),
         &instance.key,
-        false,
+        identity.worker_id.as_deref(),
+        identity.model_version.as_deref(),
     )
     .await
     {
-        Ok(()) => {}
-        Err(error) => {
-            eprintln!("Failed to update processing state. Error: {}", error);
-            broadcast_internal_server_error(shared_context.clone(), &instance.task_group).await;
-            return;
-        }
+        eprintln!("Failed to store BP server identity. Error: {}", error);
+    }
+
+    // Marks this task as completed.
+    shared_context.lifecycle.emit(LifecycleEvent::new(
+        LifecycleStage::Completed,
+        instance.key,
+        instance.task_group,
+    ));
+    // Only known to the replica which sent the task.
+    let bp_round_trip = shared_context.processing_times.finish(&instance.key);
+    if let Some(took) = bp_round_trip {
+        canary::record_primary_latency(&shared_context, &instance.key, took).await;
     }
 
     let fresh_instance = match BackgroundRemoverTask::fetch(
@@ -370,7 +1373,10 @@ async fn handle_files_received_from_bp_server(
         }
     };
 
-    let serialized = match fresh_instance.serialize() {
+    let serialized = match shared_context
+        .task_json_cache
+        .serialize(&fresh_instance, None)
+    {
         Ok(serialized) => serialized,
         Err(error) => {
             eprintln!(
@@ -382,27 +1388,620 @@ async fn handle_files_received_from_bp_server(
         }
     };
 
-    let websockets = shared_context
+    let broadcast_started = Instant::now();
+    deliver_result(
+        &shared_context,
+        &fresh_instance.task_group,
+        fresh_instance.key,
+        ServerMessage::result(serialized),
+        // Clients of mask only tasks get the mask as binary preview.
+        limited.preview.as_ref().unwrap_or(&limited.mask),
+    )
+    .await;
+    let broadcast = broadcast_started.elapsed();
+    finish_delivery(&shared_context, outbox_id, &fresh_instance.key).await;
+
+    let mut timings = vec![(TaskStage::Save, save), (TaskStage::Broadcast, broadcast)];
+    if let Some(took) = bp_round_trip {
+        timings.push((TaskStage::BpRoundTrip, took));
+    }
+    record_timings(&shared_context, &fresh_instance.key, timings);
+
+    if !mask_only {
+        tokio::spawn(previews::generate(
+            shared_context.clone(),
+            fresh_instance.key,
+            PreviewOf::Processed,
+        ));
+    }
+}
+
+///
+/// Marks the outbox entry of a delivered result as sent and sends the completion email of the
+/// task group once its last task has its outcome.
+///
+async fn finish_delivery(shared_context: &SharedContext, outbox_id: i64, key: &Uuid) {
+    if let Err(error) = OutboxEntry::mark_sent(shared_context.db_wrapper.clone(), outbox_id).await {
+        // The outbox job delivers the result again, which clients of the task group tolerate.
+        eprintln!("Failed to mark outbox entry as sent. Error: {}", error);
+    }
+
+    tokio::spawn(batch_notifications::task_finished(
+        shared_context.clone(),
+        *key,
+    ));
+}
+
+///
+/// Delivers the result notification of an outbox entry left unsent, e.g. by a crash between
+/// storing the result and broadcasting it. Returns false if it couldn't be delivered, so the
+/// entry is retried.
+///
+pub async fn deliver_outbox_entry(shared_context: &SharedContext, entry: &OutboxEntry) -> bool {
+    let instance = match BackgroundRemoverTask::fetch(
+        shared_context.db_wrapper.clone(),
+        &entry.task_key,
+    )
+    .await
+    {
+        Ok(instance) => instance,
+        Err(sqlx::Error::RowNotFound) => {
+            // Deleted since, nothing left to notify about.
+            finish_delivery(shared_context, entry.id, &entry.task_key).await;
+            return true;
+        }
+        Err(error) => {
+            eprintln!(
+                "Failed to fetch background remover task instance. Error: {}",
+                error
+            );
+            return false;
+        }
+    };
+
+    let serialized = match shared_context.task_json_cache.serialize(&instance, None) {
+        Ok(serialized) => serialized,
+        Err(error) => {
+            eprintln!(
+                "Failed to serialize background remover task instance. Error: {}",
+                error
+            );
+            return false;
+        }
+    };
+
+    let media_root = match env::var("MEDIA_ROOT") {
+        Ok(path) => PathBuf::from(path),
+        Err(error) => {
+            eprintln!(
+                "The MEDIA_ROOT path is not specified in environment variable. Error: {}",
+                error
+            );
+            return false;
+        }
+    };
+    let preview_path = instance
+        .output_path(ManifestFile::PREVIEW_PROCESSED)
+        .or(instance.output_path(ManifestFile::MASK))
+        .map(|path| path_utils::file_path_from_relative_url(media_root, PathBuf::from(path)))
+        .unwrap_or_default();
+
+    deliver_result(
+        shared_context,
+        &instance.task_group,
+        instance.key,
+        ServerMessage::result(serialized),
+        &preview_path,
+    )
+    .await;
+    finish_delivery(shared_context, entry.id, &instance.key).await;
+    true
+}
+
+///
+/// Sends result message to all websocket clients of the task group, followed by the preview
+/// bytes for clients with `binary_preview`. The result is kept in the task group history for
+/// clients connecting shortly after.
+///
+async fn deliver_result(
+    shared_context: &SharedContext,
+    task_group: &Uuid,
+    key: Uuid,
+    message: ServerMessage,
+    preview_path: &PathBuf,
+) {
+    let connections = shared_context.ws_clients.get_all(task_group).await;
+
+    // Preview bytes are read only if some client asked for binary delivery.
+    let mut preview_bytes = None;
+    if connections
+        .iter()
+        .any(|connection| connection.binary_preview())
+    {
+        match tokio::fs::read(preview_path).await {
+            Ok(bytes) => preview_bytes = Some(bytes),
+            Err(error) => {
+                eprintln!(
+                    "Failed to read preview for binary delivery. Error: {}",
+                    error
+                );
+            }
+        }
+    }
+
+    let result_payload = ws_clients::payload(&message.to_json());
+    shared_context
         .ws_clients
-        .get_all(&fresh_instance.task_group)
+        .record(task_group, &result_payload)
         .await;
+    for connection in connections.iter() {
+        connection.send_payload(result_payload.clone());
+
+        if let (true, Some(bytes)) = (connection.binary_preview(), &preview_bytes) {
+            connection.send(&ServerMessage::preview_binary(key, bytes.len()));
+            connection.send_bytes(bytes.clone());
+        }
+    }
+}
+
+///
+/// Stores outputs of a task processed again as a new `reprocess` revision.
+///
+async fn handle_reprocessed_files(
+    shared_context: SharedContext,
+    instance: BackgroundRemoverTask,
+    files: &Vec<File>,
+    is_fake_processed: bool,
+    request_id: Option<Uuid>,
+) {
+    let db_wrapper = shared_context.db_wrapper.clone();
+    let revision = match TaskRevision::insert_next(
+        db_wrapper.clone(),
+        &Uuid::new_v4(),
+        &instance.key,
+        "reprocess",
+        None,
+    )
+    .await
+    {
+        Ok(revision) => revision,
+        Err(error) => {
+            eprintln!("Failed to insert task revision. Error: {}", error);
+            release_result(&shared_context, &instance.key, request_id).await;
+            broadcast_internal_server_error(shared_context.clone(), &instance.task_group).await;
+            return;
+        }
+    };
+
+    let (revision, preview_path) = match store_revision_outputs(
+        &shared_context,
+        &revision,
+        &instance,
+        files,
+        is_fake_processed,
+    )
+    .await
+    {
+        Some(result) => result,
+        None => {
+            release_result(&shared_context, &instance.key, request_id).await;
+            return;
+        }
+    };
+
+    // Marks this task as completed.
+    match BackgroundRemoverTask::complete_reprocess(db_wrapper.clone(), &instance.key, request_id)
+        .await
+    {
+        Ok(true) => {}
+        Ok(false) => {
+            // Dispatched again while the files were being saved.
+            if let Some(request_id) = &request_id {
+                drop_stale_response(&shared_context, &instance.key, request_id).await;
+            }
+            return;
+        }
+        Err(error) => {
+            eprintln!("Failed to update processing state. Error: {}", error);
+            release_result(&shared_context, &instance.key, request_id).await;
+            broadcast_internal_server_error(shared_context.clone(), &instance.task_group).await;
+            return;
+        }
+    }
+    shared_context.processing_times.finish(&instance.key);
+    shared_context.lifecycle.emit(LifecycleEvent::new(
+        LifecycleStage::Completed,
+        instance.key,
+        instance.task_group,
+    ));
+
+    let serialized = match BackgroundRemoverTask::fetch(db_wrapper, &instance.key)
+        .await
+        .map_err(|error| error.to_string())
+        .and_then(|fresh_instance| {
+            shared_context
+                .task_json_cache
+                .serialize(&fresh_instance, Some(&revision))
+                .map_err(|error| error.to_string())
+        }) {
+        Ok(serialized) => serialized,
+        Err(error) => {
+            eprintln!("Failed to serialize reprocessed task. Error: {}", error);
+            broadcast_internal_server_error(shared_context.clone(), &instance.task_group).await;
+            return;
+        }
+    };
+
+    deliver_result(
+        &shared_context,
+        &instance.task_group,
+        instance.key,
+        ServerMessage::result(serialized),
+        &preview_path,
+    )
+    .await;
+}
+
+///
+/// Outputs of a task after the limits of its plan were applied.
+///
+struct PlanOutputs {
+    mask: PathBuf,
+    /// `None` for mask only tasks.
+    processed: Option<PathBuf>,
+    /// `None` for mask only tasks.
+    preview: Option<PathBuf>,
+    /// Processed image as received from the BP server, when the served one was scaled down or
+    /// watermarked.
+    received_processed: Option<PathBuf>,
+    /// Mask as received from the BP server, when the served one was scaled down.
+    received_mask: Option<PathBuf>,
+    /// Pixel cap the outputs were scaled down to.
+    max_pixels: Option<u64>,
+}
 
-    // Broadcasts response to all websocket clients.
-    for websocket in websockets {
-        let _ = websocket
-            .send_json(&json!({
-                "status": "success",
-                "status_code": "result",
-                "data": serialized
-            }))
+///
+/// Applies the plan of a free tier task to its outputs: they are scaled down to
+/// `FREE_TIER_MAX_OUTPUT_PIXELS` and the processed image is watermarked. Limited outputs are saved
+/// next to the received ones. The received preview is removed, while the received mask and
+/// processed image are kept. Outputs of other tasks are returned as they are.
+///
+async fn apply_plan_limits(
+    shared_context: &SharedContext,
+    free_tier: bool,
+    mask_path: PathBuf,
+    processed_path: Option<PathBuf>,
+    preview_path: Option<PathBuf>,
+) -> std::io::Result<PlanOutputs> {
+    let mut outputs = PlanOutputs {
+        mask: mask_path,
+        processed: processed_path,
+        preview: preview_path,
+        received_processed: None,
+        received_mask: None,
+        max_pixels: None,
+    };
+    if !free_tier {
+        return Ok(outputs);
+    }
+
+    let config = shared_context.config.load_full();
+    let max_pixels = config.free_tier_max_output_pixels;
+    let watermark = match config.watermark() {
+        Some((logo_path, options)) => Some((fs::read(logo_path).await?, options)),
+        None => None,
+    };
+
+    let data = fs::read(&outputs.mask).await?;
+    if exceeds_pixels(&data, max_pixels)? {
+        outputs.max_pixels = Some(max_pixels);
+        let memory = image_utils::estimate_memory(&data);
+        let capped = shared_context
+            .image_workers
+            .run(memory, move || {
+                image_utils::apply_output_limits(&data, max_pixels, None)
+            })
+            .await?
+            .map_err(std::io::Error::other)?;
+        if let Some(capped) = capped {
+            let capped_path = save_utils::save_next_to(&outputs.mask, &capped).await?;
+            outputs.received_mask = Some(std::mem::replace(&mut outputs.mask, capped_path));
+        }
+    }
+
+    let (processed_path, preview_path) = match (&outputs.processed, &outputs.preview) {
+        (Some(processed), Some(preview)) => (processed.clone(), preview.clone()),
+        _ => return Ok(outputs),
+    };
+
+    let data = fs::read(&processed_path).await?;
+    if exceeds_pixels(&data, max_pixels)? {
+        outputs.max_pixels = Some(max_pixels);
+    }
+    // The preview gets the same limits, so it never shows more than the plan allows.
+    let preview_options = config.preview_options();
+    let memory = image_utils::estimate_memory(&data);
+    let limited = shared_context
+        .image_workers
+        .run(memory, move || {
+            let watermark = watermark
+                .as_ref()
+                .map(|(logo, options)| (logo.as_slice(), options));
+            limit_processed(&data, max_pixels, watermark, &preview_options)
+        })
+        .await?
+        .map_err(std::io::Error::other)?;
+
+    if let Some((limited, preview)) = limited {
+        let limited_path = save_utils::save_next_to(&processed_path, &limited).await?;
+        let limited_preview_path = save_utils::save_next_to(&preview_path, &preview).await?;
+        if limited_preview_path != preview_path {
+            let _ = fs::remove_file(&preview_path).await;
+        }
+        outputs.received_processed = outputs.processed.replace(limited_path);
+        outputs.preview = Some(limited_preview_path);
+    }
+    Ok(outputs)
+}
+
+///
+/// Processed image `data` limited by `image_utils::apply_output_limits` with a preview of it, or
+/// `None` when no limit applies. The preview is downscaled before the limits are applied to it,
+/// so its watermark is drawn at preview resolution rather than shrunk with the image.
+///
+fn limit_processed(
+    data: &[u8],
+    max_pixels: u64,
+    watermark: Option<(&[u8], &WatermarkOptions)>,
+    preview_options: &PreviewOptions,
+) -> image::ImageResult<Option<(Vec<u8>, Vec<u8>)>> {
+    let limited = match image_utils::apply_output_limits(data, max_pixels, watermark)? {
+        Some(limited) => limited,
+        None => return Ok(None),
+    };
+    let preview = image_utils::make_preview(data, preview_options, ImageFormat::Png)?;
+    let preview =
+        image_utils::apply_output_limits(&preview, max_pixels, watermark)?.unwrap_or(preview);
+    Ok(Some((limited, preview)))
+}
+
+///
+/// Whether image `data` has more than `max_pixels` pixels. Only reads the image header.
+///
+fn exceeds_pixels(data: &[u8], max_pixels: u64) -> std::io::Result<bool> {
+    let (width, height) = image_utils::read_dimensions(data).map_err(std::io::Error::other)?;
+    Ok(image_utils::capped_dimensions(width, height, max_pixels) != (width, height))
+}
+
+///
+/// Saves files received from the BP server for `revision` of `task` and stores their paths.
+/// Returns the updated revision and the absolute preview path, or `None` after notifying clients
+/// of the failure.
+///
+async fn store_revision_outputs(
+    shared_context: &SharedContext,
+    revision: &TaskRevision,
+    task: &BackgroundRemoverTask,
+    files: &Vec<File>,
+    is_fake_processed: bool,
+) -> Option<(TaskRevision, PathBuf)> {
+    let db_wrapper = shared_context.db_wrapper.clone();
+    let policy = shared_context.config.load().storage_retry;
+    let mut failed = vec![];
+    let saved = retry_utils::retry(&policy, is_retryable_storage_error, &mut failed, |_| {
+        save_utils::save_revision_files_received_from_bp_server(
+            task.tenant.as_deref(),
+            revision,
+            files,
+            is_fake_processed,
+        )
+    })
+    .await;
+    record_retries(
+        shared_context,
+        &revision.task_key,
+        "storage_retry",
+        &policy,
+        &failed,
+    )
+    .await;
+
+    let (transparent_image_path, mask_image_path, preview_transparent_image_path) = match saved {
+        Ok(paths) => paths,
+        Err(error) => {
+            eprintln!("Failed to save revision files. Error: {}", error);
+            broadcast_internal_server_error(shared_context.clone(), &task.task_group).await;
+            return None;
+        }
+    };
+
+    let media_root = match env::var("MEDIA_ROOT") {
+        Ok(path) => PathBuf::from(path),
+        Err(_) => {
+            eprintln!("MEDIA_ROOT environment variable is missing.");
+            broadcast_internal_server_error(shared_context.clone(), &task.task_group).await;
+            return None;
+        }
+    };
+
+    let relative = |path: &PathBuf| {
+        path_utils::relative_media_url_from_full_path(&media_root, path)
+            .to_string_lossy()
+            .to_string()
+    };
+
+    let limited = match apply_plan_limits(
+        shared_context,
+        task.free_tier,
+        mask_image_path,
+        Some(transparent_image_path.clone()),
+        Some(preview_transparent_image_path.clone()),
+    )
+    .await
+    {
+        Ok(limited) => limited,
+        Err(error) => {
+            eprintln!(
+                "Failed to apply plan limits to revision outputs. Error: {}",
+                error
+            );
+            broadcast_internal_server_error(shared_context.clone(), &task.task_group).await;
+            return None;
+        }
+    };
+    let transparent_image_path = limited.processed.clone().unwrap_or(transparent_image_path);
+    let preview_transparent_image_path = limited
+        .preview
+        .clone()
+        .unwrap_or(preview_transparent_image_path);
+
+    if let Err(error) = TaskRevision::update_outputs(
+        db_wrapper.clone(),
+        &revision.key,
+        &relative(&limited.mask),
+        &relative(&transparent_image_path),
+        &relative(&preview_transparent_image_path),
+        limited.received_processed.as_ref().map(relative).as_deref(),
+        limited.received_mask.as_ref().map(relative).as_deref(),
+    )
+    .await
+    {
+        eprintln!("Failed to update revision. Error: {}", error);
+        broadcast_internal_server_error(shared_context.clone(), &task.task_group).await;
+        return None;
+    }
+
+    match TaskRevision::fetch(db_wrapper, &revision.key).await {
+        Ok(revision) => Some((revision, preview_transparent_image_path)),
+        Err(error) => {
+            eprintln!("Failed to fetch revision. Error: {}", error);
+            broadcast_internal_server_error(shared_context.clone(), &task.task_group).await;
+            None
+        }
+    }
+}
+
+///
+/// Handles BP server response of a refinement request sent by `send_refinement`.
+///
+async fn handle_revision_response(
+    shared_context: SharedContext,
+    bp_response: BPResponse,
+    files: &Vec<File>,
+) {
+    let db_wrapper = shared_context.db_wrapper.clone();
+    let revision = match TaskRevision::fetch(db_wrapper.clone(), &bp_response.task_id).await {
+        Ok(revision) => revision,
+        Err(error) => {
+            eprintln!("Failed to fetch task or revision. Error: {}", error);
+            return;
+        }
+    };
+
+    let task = match BackgroundRemoverTask::fetch(db_wrapper.clone(), &revision.task_key).await {
+        Ok(task) => task,
+        Err(error) => {
+            eprintln!("Failed to fetch task of revision. Error: {}", error);
+            return;
+        }
+    };
+
+    // Revision keys are unique per dispatch, so stored outputs mean the result was delivered
+    // twice.
+    if bp_response.status == "success" && revision.mask_image_path.is_some() {
+        println!("Dropping duplicate result of revision {}.", revision.key);
+        shared_context
+            .metrics
+            .increment("bp_duplicate_responses_total");
+        return;
+    }
+
+    if bp_response.status != "success" {
+        let message = ServerMessage::status(
+            &bp_response.status,
+            &bp_response.status_code,
+            bp_response.message,
+        );
+        shared_context
+            .ws_clients
+            .broadcast(&task.task_group, &message)
             .await;
+        return;
     }
+
+    let (revision, preview_path) =
+        match store_revision_outputs(&shared_context, &revision, &task, files, false).await {
+            Some(result) => result,
+            None => return,
+        };
+
+    let serialized = match serde_json::to_value(&revision) {
+        Ok(serialized) => serialized,
+        Err(error) => {
+            eprintln!("Failed to serialize revision. Error: {}", error);
+            broadcast_internal_server_error(shared_context.clone(), &task.task_group).await;
+            return;
+        }
+    };
+
+    deliver_result(
+        &shared_context,
+        &task.task_group,
+        revision.key,
+        ServerMessage::revision_result(serialized),
+        &preview_path,
+    )
+    .await;
 }
 
 async fn broadcast_internal_server_error(shared_context: SharedContext, task_group: &Uuid) {
     // Broadcast internal server error to all clients.
-    let websockets = shared_context.ws_clients.get_all(&task_group).await;
-    for websocket in websockets {
-        shortcuts::internal_server_error(&websocket).await;
+    shared_context
+        .ws_clients
+        .broadcast(task_group, &ServerMessage::internal_server_error())
+        .await;
+}
+
+///
+/// Notifies every websocket client when the links to all public BP servers dropped or one comes
+/// back, so frontends can warn users before they upload. While a standby is connected, tasks
+/// fail over to it and the service is not degraded.
+///
+pub async fn notify_bp_connection_changes(shared_context: SharedContext) {
+    let public_bp_servers = &shared_context.public_bp_servers;
+    let mut receivers: Vec<_> = public_bp_servers
+        .clients()
+        .map(|client| client.subscribe_connection_state())
+        .collect();
+    let mut connected = public_bp_servers.any_connected();
+
+    loop {
+        let changes = receivers
+            .iter_mut()
+            .map(|receiver| Box::pin(receiver.changed()));
+        if select_all(changes).await.0.is_err() {
+            return;
+        }
+
+        if public_bp_servers.any_connected() == connected {
+            continue;
+        }
+        connected = !connected;
+
+        let message = if connected {
+            ServerMessage::service_restored()
+        } else {
+            ServerMessage::service_degraded()
+        };
+
+        if let Some(notifier) = &shared_context.ops_notifier {
+            notifier.notify(if connected {
+                OpsEvent::BpConnectionRestored
+            } else {
+                OpsEvent::BpConnectionLost
+            });
+        }
+
+        shared_context.ws_clients.broadcast_all(&message).await;
     }
 }
@@ -0,0 +1,108 @@
+use std::env;
+use std::time::Duration;
+
+use redis::aio::ConnectionManager;
+use uuid::Uuid;
+
+use crate::api::ws_clients::Payload;
+
+/// Redis calls made while a websocket connects are cut short, falling back to local history.
+const REDIS_TIMEOUT: Duration = Duration::from_millis(500);
+
+///
+/// Recent task group events shared by all replicas through Redis, so a websocket can connect to
+/// any replica without sticky sessions. Enabled when `REDIS_URL` is set.
+///
+pub struct TaskEventStore {
+    connection: ConnectionManager,
+}
+
+impl TaskEventStore {
+    pub async fn from_env() -> Option<Self> {
+        let redis_url = env::var("REDIS_URL").ok()?;
+
+        let client = match redis::Client::open(redis_url) {
+            Ok(client) => client,
+            Err(error) => {
+                eprintln!("Invalid REDIS_URL. Error: {}", error);
+                return None;
+            }
+        };
+
+        match client.get_connection_manager().await {
+            Ok(connection) => Some(Self { connection }),
+            Err(error) => {
+                eprintln!("Failed to connect to redis. Error: {}", error);
+                None
+            }
+        }
+    }
+
+    fn key(task_group: &Uuid) -> String {
+        format!("bp:task_group_events:{}", task_group)
+    }
+
+    ///
+    /// Appends event to the task group, keeping the last `size` events for `ttl`.
+    ///
+    pub async fn push(&self, task_group: &Uuid, event: &Payload, size: usize, ttl: Duration) {
+        let key = Self::key(task_group);
+        let mut connection = self.connection.clone();
+
+        let result = redis::pipe()
+            .atomic()
+            .rpush(&key, &**event)
+            .ignore()
+            .ltrim(&key, -(size as isize), -1)
+            .ignore()
+            .expire(&key, ttl.as_secs().max(1) as i64)
+            .ignore()
+            .query_async::<_, ()>(&mut connection);
+
+        match tokio::time::timeout(REDIS_TIMEOUT, result).await {
+            Ok(Ok(())) => {}
+            Ok(Err(error)) => eprintln!("Failed to store task event in redis. Error: {}", error),
+            Err(_) => eprintln!("Storing task event in redis timed out."),
+        }
+    }
+
+    ///
+    /// Returns recent events of the task group, oldest first. `None` if Redis is unavailable.
+    ///
+    pub async fn fetch(&self, task_group: &Uuid) -> Option<Vec<Payload>> {
+        let mut connection = self.connection.clone();
+        let result = redis::cmd("LRANGE")
+            .arg(Self::key(task_group))
+            .arg(0)
+            .arg(-1)
+            .query_async::<_, Vec<String>>(&mut connection);
+
+        match tokio::time::timeout(REDIS_TIMEOUT, result).await {
+            Ok(Ok(events)) => Some(events.into_iter().map(Payload::from).collect()),
+            Ok(Err(error)) => {
+                eprintln!("Failed to read task events from redis. Error: {}", error);
+                None
+            }
+            Err(_) => {
+                eprintln!("Reading task events from redis timed out.");
+                None
+            }
+        }
+    }
+
+    ///
+    /// Removes the events of the task group.
+    ///
+    pub async fn delete(&self, task_group: &Uuid) {
+        let mut connection = self.connection.clone();
+        let result = redis::cmd("DEL")
+            .arg(Self::key(task_group))
+            .query_async::<_, ()>(&mut connection);
+
+        match tokio::time::timeout(REDIS_TIMEOUT, result).await {
+            Ok(Ok(())) => {}
+            Ok(Err(error)) => eprintln!("Failed to delete task events in redis. Error: {}", error),
+            Err(_) => eprintln!("Deleting task events in redis timed out."),
+        }
+    }
+}
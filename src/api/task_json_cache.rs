@@ -0,0 +1,78 @@
+use std::env;
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+use lru::LruCache;
+use serde_json::Value;
+use uuid::Uuid;
+
+use crate::db::models::{BackgroundRemoverTask, TaskRevision};
+
+/// (task key, task `date_updated`, revision key). Any update of the task bumps `date_updated`,
+/// so stale entries are never hit and age out of the cache.
+type CacheKey = (Uuid, DateTime<Utc>, Option<Uuid>);
+
+///
+/// Small LRU cache of serialized tasks. Serializing builds every media url from environment
+/// variables, which adds up when the same task is broadcast or polled repeatedly.
+///
+pub struct TaskJsonCache {
+    entries: Option<Mutex<LruCache<CacheKey, Value>>>,
+}
+
+impl TaskJsonCache {
+    ///
+    /// `capacity` of 0 disables caching.
+    ///
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: NonZeroUsize::new(capacity)
+                .map(|capacity| Mutex::new(LruCache::new(capacity))),
+        }
+    }
+
+    ///
+    /// Reads `TASK_JSON_CACHE_SIZE`, default 1000.
+    ///
+    pub fn from_env() -> Self {
+        let capacity = match env::var("TASK_JSON_CACHE_SIZE") {
+            Ok(value) => value.parse::<usize>().unwrap_or(1000),
+            Err(_) => 1000,
+        };
+
+        Self::new(capacity)
+    }
+
+    ///
+    /// Same as `BackgroundRemoverTask::serialize_with_revision`, served from the cache when the
+    /// task was not updated since it was last serialized.
+    ///
+    pub fn serialize(
+        &self,
+        instance: &BackgroundRemoverTask,
+        revision: Option<&TaskRevision>,
+    ) -> Result<Value, serde_json::Error> {
+        let (entries, date_updated) = match (&self.entries, instance.date_updated) {
+            (Some(entries), Some(date_updated)) => (entries, date_updated),
+            _ => return instance.serialize_with_revision(revision),
+        };
+
+        let cache_key = (
+            instance.key,
+            date_updated,
+            revision.map(|revision| revision.key),
+        );
+        if let Ok(mut entries) = entries.lock() {
+            if let Some(serialized) = entries.get(&cache_key) {
+                return Ok(serialized.clone());
+            }
+        }
+
+        let serialized = instance.serialize_with_revision(revision)?;
+        if let Ok(mut entries) = entries.lock() {
+            entries.put(cache_key, serialized.clone());
+        }
+        Ok(serialized)
+    }
+}
@@ -0,0 +1,102 @@
+use std::env;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+use tokio::sync::watch;
+use tokio::time::sleep;
+
+///
+/// Certificate and private key served by the API server. Enabled when both `TLS_CERT_PATH` and
+/// `TLS_KEY_PATH` are set.
+///
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    /// PEM encoded certificate chain.
+    pub cert_path: PathBuf,
+    /// PEM encoded private key.
+    pub key_path: PathBuf,
+    /// How often files are checked for changes. `TLS_RELOAD_INTERVAL_SECS`, default 60.
+    pub reload_interval: Duration,
+}
+
+impl TlsConfig {
+    pub fn from_env() -> Option<Self> {
+        let cert_path = env::var("TLS_CERT_PATH").ok()?;
+        let key_path = env::var("TLS_KEY_PATH").ok()?;
+
+        let reload_interval = match env::var("TLS_RELOAD_INTERVAL_SECS") {
+            Ok(value) => value.parse::<u64>().unwrap_or(60),
+            Err(_) => 60,
+        };
+
+        Some(Self {
+            cert_path: PathBuf::from(cert_path),
+            key_path: PathBuf::from(key_path),
+            reload_interval: Duration::from_secs(reload_interval.max(1)),
+        })
+    }
+
+    ///
+    /// Checks that the certificate chain and private key can be parsed, so a half written
+    /// renewal is never served.
+    ///
+    pub fn validate(&self) -> std::io::Result<()> {
+        let mut cert_reader = BufReader::new(File::open(&self.cert_path)?);
+        let certs = rustls_pemfile::certs(&mut cert_reader).collect::<Result<Vec<_>, _>>()?;
+        if certs.is_empty() {
+            return Err(std::io::Error::other(format!(
+                "No certificate found in {:?}.",
+                self.cert_path
+            )));
+        }
+
+        let mut key_reader = BufReader::new(File::open(&self.key_path)?);
+        if rustls_pemfile::private_key(&mut key_reader)?.is_none() {
+            return Err(std::io::Error::other(format!(
+                "No private key found in {:?}.",
+                self.key_path
+            )));
+        }
+
+        Ok(())
+    }
+
+    ///
+    /// Modification times of the certificate and key. Changes when either file is replaced.
+    ///
+    fn modified(&self) -> Option<(SystemTime, SystemTime)> {
+        let cert = std::fs::metadata(&self.cert_path).ok()?.modified().ok()?;
+        let key = std::fs::metadata(&self.key_path).ok()?.modified().ok()?;
+        Some((cert, key))
+    }
+}
+
+///
+/// Watches certificate files forever and bumps `changed` whenever a renewed, valid pair is found.
+///
+pub async fn watch_certificates(tls_config: TlsConfig, changed: watch::Sender<u64>) {
+    let mut last_modified = tls_config.modified();
+
+    loop {
+        sleep(tls_config.reload_interval).await;
+
+        let modified = tls_config.modified();
+        if modified.is_none() || modified == last_modified {
+            continue;
+        }
+
+        match tls_config.validate() {
+            Ok(()) => {
+                log::info!("TLS certificate changed. Reloading.");
+                last_modified = modified;
+                changed.send_modify(|generation| *generation += 1);
+            }
+            Err(error) => {
+                // Retried on next check in case the files are still being written.
+                eprintln!("Ignoring invalid TLS certificate. Error: {}", error);
+            }
+        }
+    }
+}
@@ -0,0 +1,186 @@
+use std::env;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use racoon::core::headers::HeaderValue;
+use racoon::core::request::Request;
+use racoon::core::response::status::ResponseStatus;
+use racoon::core::response::{JsonResponse, Response};
+use redis::aio::ConnectionManager;
+
+use crate::api::error_codes::ErrorCode;
+use crate::utils::envelope_utils::ApiEnvelope;
+use crate::utils::signature_utils::{NonceWindow, UploadSignature};
+use crate::SharedContext;
+
+/// Redis calls are cut short, falling back to the local nonce cache.
+const REDIS_TIMEOUT: Duration = Duration::from_millis(500);
+
+///
+/// Nonces of accepted signed uploads, so a captured upload can't be sent again. Shared by all
+/// replicas through Redis when `REDIS_URL` is set; kept in memory otherwise and while Redis is
+/// unavailable.
+///
+pub struct NonceCache {
+    redis: Option<ConnectionManager>,
+    seen: Mutex<NonceWindow>,
+}
+
+impl NonceCache {
+    pub async fn from_env() -> Self {
+        let redis = match env::var("REDIS_URL") {
+            Ok(redis_url) => match redis::Client::open(redis_url) {
+                Ok(client) => match client.get_connection_manager().await {
+                    Ok(connection) => Some(connection),
+                    Err(error) => {
+                        eprintln!("Failed to connect to redis for nonces. Error: {}", error);
+                        None
+                    }
+                },
+                Err(error) => {
+                    eprintln!("Invalid REDIS_URL. Error: {}", error);
+                    None
+                }
+            },
+            Err(_) => None,
+        };
+
+        Self {
+            redis,
+            seen: Mutex::new(NonceWindow::default()),
+        }
+    }
+
+    ///
+    /// Remembers `nonce` for `ttl`. Returns false if it was already seen within its ttl.
+    ///
+    pub async fn insert(&self, nonce: &str, ttl: Duration) -> bool {
+        if let Some(connection) = &self.redis {
+            let mut connection = connection.clone();
+            let result = redis::cmd("SET")
+                .arg(format!("bp:upload_nonce:{}", nonce))
+                .arg(1)
+                .arg("NX")
+                .arg("EX")
+                .arg(ttl.as_secs().max(1))
+                .query_async::<_, Option<String>>(&mut connection);
+
+            match tokio::time::timeout(REDIS_TIMEOUT, result).await {
+                Ok(Ok(reply)) => return reply.is_some(),
+                Ok(Err(error)) => eprintln!("Failed to store nonce in redis. Error: {}", error),
+                Err(_) => eprintln!("Storing nonce in redis timed out."),
+            }
+        }
+
+        match self.seen.lock() {
+            Ok(mut seen) => seen.insert(nonce, ttl, Instant::now()),
+            Err(_) => false,
+        }
+    }
+}
+
+///
+/// Whether `path` is a public endpoint receiving images of any API version: uploads and
+/// refinements.
+///
+pub fn is_upload_path(path: &str) -> bool {
+    if matches!(path, "/v1/bp/u/" | "/v2/bp/u/") {
+        return true;
+    }
+
+    [
+        "/v1/remove-background/refine/",
+        "/v2/remove-background/refine/",
+    ]
+    .iter()
+    .any(|prefix| path.starts_with(prefix))
+}
+
+///
+/// Key id of the upload signature, e.g. to look up the tier of the client. Only trustworthy once
+/// `verify` accepted the request.
+///
+pub fn key_id(request: &Request) -> Option<String> {
+    let signature = header(request, "X-Signature")?;
+    signature
+        .trim()
+        .split_once('.')
+        .map(|(kid, _)| kid.to_string())
+}
+
+fn header(request: &Request, name: &str) -> Option<String> {
+    request
+        .headers
+        .value(name)
+        .map(|value| value.as_str().to_string())
+}
+
+///
+/// Checks the signature of an upload request: a known key, a fresh timestamp and an unused
+/// nonce. Unsigned uploads pass unless `UPLOAD_SIGNATURES_REQUIRED` is set. The uploaded image
+/// is checked against `X-Content-SHA256` by the upload and refine views, as the body is only read
+/// there.
+/// Usage requests are signed the same way, see `billing::usage_view`.
+///
+pub async fn verify(request: &Request, shared_context: &SharedContext) -> Result<(), Response> {
+    let config = shared_context.config.load();
+    let signature = header(request, "X-Signature");
+    if signature.is_none() && !config.upload_signatures_required {
+        return Ok(());
+    }
+
+    let keyring = match shared_context.secrets.upload_keyring() {
+        Some(keyring) => keyring,
+        None => {
+            return Err(invalid_signature(
+                shared_context,
+                "Signed uploads are not enabled.",
+            ))
+        }
+    };
+
+    let timestamp = header(request, "X-Signature-Timestamp");
+    let nonce = header(request, "X-Signature-Nonce");
+    let content_sha256 = header(request, "X-Content-SHA256");
+    let upload_signature = match UploadSignature::parse(
+        timestamp.as_deref(),
+        nonce.as_deref(),
+        content_sha256.as_deref(),
+        signature.as_deref(),
+    ) {
+        Ok(upload_signature) => upload_signature,
+        Err(error) => return Err(invalid_signature(shared_context, &error)),
+    };
+
+    let max_age = config.upload_signature_max_age;
+    if let Err(error) = upload_signature.verify(
+        &keyring,
+        &request.method,
+        &request.path,
+        Utc::now().timestamp(),
+        max_age.as_secs(),
+    ) {
+        return Err(invalid_signature(shared_context, error));
+    }
+
+    // Nonces only need to outlive the window in which their timestamp is accepted.
+    let nonce = upload_signature.scoped_nonce();
+    if !shared_context.nonce_cache.insert(&nonce, max_age * 2).await {
+        return Err(invalid_signature(shared_context, "Nonce was already used."));
+    }
+
+    Ok(())
+}
+
+fn invalid_signature(shared_context: &SharedContext, message: &str) -> Response {
+    shared_context
+        .metrics
+        .increment("upload_signature_rejections_total");
+
+    JsonResponse::unauthorized().body(
+        ApiEnvelope::failed(ErrorCode::InvalidSignature)
+            .message(message)
+            .to_value(),
+    )
+}
@@ -1,19 +1,190 @@
-use racoon::core::path::Path;
+use std::sync::Mutex;
+
+use racoon::core::path::{Path, View};
 use racoon::view;
 
-use crate::api::views::{listen_processing_ws, public_upload, task_details_view, tasks_view};
+use crate::api::admin_feed::admin_feed_ws;
+use crate::api::admin_views::{
+    compare_revisions_view, debug_view, export_tasks_view, jobs_view, media_gc_view, metrics_view,
+    stats_view, task_inspection_view,
+};
+use crate::api::billing::{stripe_webhook_view, usage_view};
+#[cfg(feature = "bp-replay")]
+use crate::api::bp_replay::bp_replay_view;
+#[cfg(feature = "chaos")]
+use crate::api::chaos::chaos_view;
+use crate::api::erasure::erase_user_view;
+use crate::api::error_codes::errors_view;
+use crate::api::maintenance::maintenance_view;
+use crate::api::organizations::{
+    organization_api_key_view, organization_api_keys_view, organization_member_view,
+    organization_members_view, organization_view, organizations_view,
+};
+use crate::api::plugin::{plugin_remove_background_view, plugin_token_view};
+use crate::api::scaling::scaling_view;
+use crate::api::store_sync::store_sync_view;
+use crate::api::user_exports::{download_user_export_view, export_user_view};
+use crate::api::views::{
+    listen_processing_ws, public_upload, public_upload_v2, refine_task_view, service_status_view,
+    task_details_view, task_details_view_v2, task_group_summary_view, task_revision_details_view,
+    task_revisions_view, tasks_view, tasks_view_v2, version_view,
+};
+use crate::utils::timing_utils;
+use crate::utils::version_utils::ApiVersion;
+
+/// Patterns of the registered URLs, e.g. `/v1/remove-background/details/{task_id}/`.
+static ROUTE_PATTERNS: Mutex<Vec<&'static str>> = Mutex::new(vec![]);
+
+///
+/// Registers `view` at `pattern`, kept for labelling metrics and logs by route.
+///
+fn route(pattern: &'static str, view: View) -> Path {
+    let mut patterns = ROUTE_PATTERNS.lock().unwrap();
+    if !patterns.contains(&pattern) {
+        patterns.push(pattern);
+    }
+    Path::new(pattern, view)
+}
+
+///
+/// Registered pattern matching `path`, with the values of its params. Unlike the raw path, the
+/// pattern keeps the number of metric series bounded.
+///
+pub fn match_route(path: &str) -> Option<(&'static str, Vec<(String, String)>)> {
+    let patterns = ROUTE_PATTERNS.lock().unwrap();
+    timing_utils::match_route(&patterns, path)
+}
 
 pub fn register_urls() -> Vec<Path> {
-    vec![
-        Path::new("/v1/bp/u/", view!(public_upload)),
-        Path::new(
-            "/v1/remove-background/details/{task_id}/",
-            view!(task_details_view),
-        ),
-        Path::new(
+    let mut paths = vec![
+        route(
             "/ws/remove-background/{task_group}/",
             view!(listen_processing_ws),
         ),
-        Path::new("/v1/remove-tasks/", view!(tasks_view)),
-    ]
+        route("/ws/admin/feed/", view!(admin_feed_ws)),
+        route("/v1/admin/media-gc/", view!(media_gc_view)),
+        route("/v1/admin/export/", view!(export_tasks_view)),
+        route("/v1/admin/stats/", view!(stats_view)),
+        route("/v1/admin/debug/", view!(debug_view)),
+        route("/v1/admin/metrics/", view!(metrics_view)),
+        route("/v1/admin/jobs/", view!(jobs_view)),
+        route("/v1/admin/compare/", view!(compare_revisions_view)),
+        route("/v1/admin/tasks/{task_id}/", view!(task_inspection_view)),
+        route(
+            "/v1/admin/users/{user_identifier}/erase/",
+            view!(erase_user_view),
+        ),
+        route(
+            "/v1/admin/users/{user_identifier}/export/",
+            view!(export_user_view),
+        ),
+        route(
+            "/v1/user-exports/{export_id}/",
+            view!(download_user_export_view),
+        ),
+        route("/v1/admin/organizations/", view!(organizations_view)),
+        route(
+            "/v1/admin/organizations/{organization_id}/",
+            view!(organization_view),
+        ),
+        route(
+            "/v1/admin/organizations/{organization_id}/keys/",
+            view!(organization_api_keys_view),
+        ),
+        route(
+            "/v1/admin/organizations/{organization_id}/keys/{key_id}/",
+            view!(organization_api_key_view),
+        ),
+        route(
+            "/v1/admin/organizations/{organization_id}/members/",
+            view!(organization_members_view),
+        ),
+        route(
+            "/v1/admin/organizations/{organization_id}/members/{member_id}/",
+            view!(organization_member_view),
+        ),
+        route("/v1/admin/store-syncs/", view!(store_sync_view)),
+        route("/v1/admin/maintenance/", view!(maintenance_view)),
+        route("/v1/admin/scaling/", view!(scaling_view)),
+        route("/v1/billing/stripe-webhook/", view!(stripe_webhook_view)),
+        route("/v1/plugin/token/", view!(plugin_token_view)),
+        route(
+            "/v1/plugin/remove-background/",
+            view!(plugin_remove_background_view),
+        ),
+    ];
+
+    #[cfg(feature = "bp-replay")]
+    paths.push(route("/v1/admin/bp-replay/", view!(bp_replay_view)));
+    #[cfg(feature = "chaos")]
+    paths.push(route("/v1/admin/chaos/", view!(chaos_view)));
+
+    paths.extend(public_urls(ApiVersion::V1));
+    paths.extend(public_urls(ApiVersion::V2));
+    paths
+}
+
+///
+/// Public endpoints of an API version. Versions share handlers; views whose responses differ
+/// between versions have a wrapper per version passing the version to the shared handler.
+///
+fn public_urls(version: ApiVersion) -> Vec<Path> {
+    match version {
+        ApiVersion::V1 => vec![
+            route("/v1/bp/u/", view!(public_upload)),
+            route(
+                "/v1/remove-background/details/{task_id}/",
+                view!(task_details_view),
+            ),
+            route(
+                "/v1/remove-background/revisions/{task_id}/",
+                view!(task_revisions_view),
+            ),
+            route(
+                "/v1/remove-background/revisions/{task_id}/{revision}/",
+                view!(task_revision_details_view),
+            ),
+            route(
+                "/v1/remove-background/refine/{task_id}/",
+                view!(refine_task_view),
+            ),
+            route(
+                "/v1/task-groups/{task_group}/",
+                view!(task_group_summary_view),
+            ),
+            route("/v1/remove-tasks/", view!(tasks_view)),
+            route("/v1/status/", view!(service_status_view)),
+            route("/v1/usage/", view!(usage_view)),
+            route("/v1/errors/", view!(errors_view)),
+            route("/v1/version/", view!(version_view)),
+        ],
+        ApiVersion::V2 => vec![
+            route("/v2/bp/u/", view!(public_upload_v2)),
+            route(
+                "/v2/remove-background/details/{task_id}/",
+                view!(task_details_view_v2),
+            ),
+            route(
+                "/v2/remove-background/revisions/{task_id}/",
+                view!(task_revisions_view),
+            ),
+            route(
+                "/v2/remove-background/revisions/{task_id}/{revision}/",
+                view!(task_revision_details_view),
+            ),
+            route(
+                "/v2/remove-background/refine/{task_id}/",
+                view!(refine_task_view),
+            ),
+            route(
+                "/v2/task-groups/{task_group}/",
+                view!(task_group_summary_view),
+            ),
+            route("/v2/remove-tasks/", view!(tasks_view_v2)),
+            route("/v2/status/", view!(service_status_view)),
+            route("/v2/usage/", view!(usage_view)),
+            route("/v2/errors/", view!(errors_view)),
+            route("/v2/version/", view!(version_view)),
+        ],
+    }
 }
@@ -0,0 +1,337 @@
+use std::env;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use racoon::core::request::Request;
+use racoon::core::response::{HttpResponse, JsonResponse, Response};
+
+use chrono::Utc;
+use serde_json::{json, Value};
+use uuid::Uuid;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+use crate::api::error_codes::ErrorCode;
+use crate::api::{erasure, shortcuts};
+use crate::db::models::{BackgroundRemoverTask, TaskRevision};
+use crate::utils::envelope_utils::ApiEnvelope;
+use crate::utils::path_utils;
+use crate::SharedContext;
+
+///
+/// Directory holding user exports until their link expires: `USER_EXPORT_DIR`, by default
+/// `.user-exports` in `MEDIA_ROOT`, so every replica sharing the media serves the export. The
+/// static file server must not serve it, as exports are only downloaded through signed links.
+///
+fn exports_dir() -> PathBuf {
+    match env::var("USER_EXPORT_DIR") {
+        Ok(path) if !path.is_empty() => PathBuf::from(path),
+        _ => match env::var("MEDIA_ROOT") {
+            Ok(media_root) => PathBuf::from(media_root).join(".user-exports"),
+            Err(_) => env::temp_dir().join("bp-user-exports"),
+        },
+    }
+}
+
+fn export_path(export_id: &Uuid) -> PathBuf {
+    exports_dir().join(format!("{}.zip", export_id))
+}
+
+///
+/// Exports every task of a user for subject access requests: `POST
+/// /v1/admin/users/{user_identifier}/export/`, with the identifier as sent by the client on upload.
+/// Builds a ZIP of the media of the tasks and `tasks.json` with the tasks and their revisions,
+/// and returns a signed download link valid for `USER_EXPORT_TTL_SECS`, so it can be passed on to
+/// the user.
+///
+pub async fn export_user_view(request: Request) -> Response {
+    if !shortcuts::is_admin(&request) {
+        return shortcuts::unauthorized();
+    }
+
+    if request.method != "POST" {
+        return HttpResponse::ok().body("This request method is not supported.");
+    }
+
+    let shared_context: &SharedContext = request.context().expect("SharedContext is missing.");
+    let user_identifier = request
+        .path_params
+        .value("user_identifier")
+        .expect("User identifier is missing.");
+
+    // Download links are signed with the same keys as task tokens.
+    let keyring = match shared_context.secrets.keyring() {
+        Some(keyring) => keyring,
+        None => {
+            return JsonResponse::with_status(503, "Service Unavailable").body(
+                ApiEnvelope::failed(ErrorCode::SigningKeysMissing)
+                    .message(
+                        "SIGNING_KEYS or TASK_TOKEN_SECRET is required to sign download links.",
+                    )
+                    .to_value(),
+            );
+        }
+    };
+
+    let ttl = shared_context.config.load().user_export_ttl;
+    remove_expired_exports(ttl).await;
+
+    let export_id = Uuid::new_v4();
+    let (task_count, size) = match export_user(shared_context, user_identifier, &export_id).await {
+        Ok(summary) => summary,
+        Err(error) => {
+            log::error!("User export failed. Error: {}", error);
+
+            return JsonResponse::internal_server_error()
+                .body(ApiEnvelope::failed(ErrorCode::InternalServerError).to_value());
+        }
+    };
+
+    let expires = Utc::now().timestamp() + ttl.as_secs() as i64;
+    let signature = keyring.sign_user_export(&export_id, expires);
+    let host = env::var("HOST").unwrap_or_default();
+    let url = format!(
+        "https://{}/v1/user-exports/{}/?expires={}&signature={}",
+        host, export_id, expires, signature
+    );
+
+    log::info!(
+        "Exported {} tasks of a user. Export id: {}",
+        task_count,
+        export_id
+    );
+    JsonResponse::ok().body(
+        ApiEnvelope::success("user_export")
+            .data(json!({
+                "export_id": export_id,
+                "tasks": task_count,
+                "size": size,
+                "url": url,
+                "expires": expires,
+            }))
+            .to_value(),
+    )
+}
+
+///
+/// Serves a user export to holders of its signed link: `GET
+/// /v1/user-exports/{export_id}/?expires=&signature=`.
+///
+pub async fn download_user_export_view(request: Request) -> Response {
+    let shared_context: &SharedContext = request.context().expect("SharedContext is missing.");
+
+    let export_id = request
+        .path_params
+        .value("export_id")
+        .and_then(|value| Uuid::parse_str(value).ok());
+    let expires = request
+        .query_params
+        .value("expires")
+        .and_then(|value| value.parse::<i64>().ok());
+    let signature = request.query_params.value("signature");
+
+    let valid = match (
+        export_id,
+        expires,
+        signature,
+        shared_context.secrets.keyring(),
+    ) {
+        (Some(export_id), Some(expires), Some(signature), Some(keyring)) => {
+            keyring.verify_user_export(&export_id, expires, signature)
+        }
+        _ => false,
+    };
+    let (export_id, expires) = match (valid, export_id, expires) {
+        (true, Some(export_id), Some(expires)) => (export_id, expires),
+        _ => {
+            return JsonResponse::with_status(403, "Forbidden").body(
+                ApiEnvelope::failed(ErrorCode::InvalidSignature)
+                    .message("Invalid download link.")
+                    .to_value(),
+            );
+        }
+    };
+
+    if expires < Utc::now().timestamp() {
+        return JsonResponse::with_status(410, "Gone").body(
+            ApiEnvelope::failed(ErrorCode::LinkExpired)
+                .message("Download link expired.")
+                .to_value(),
+        );
+    }
+
+    // Exports of large users reach gigabytes, so they are streamed instead of read at once.
+    let headers = [
+        ("Content-Type", "application/zip".to_string()),
+        (
+            "Content-Disposition",
+            format!("attachment; filename=\"export-{}.zip\"", export_id),
+        ),
+        ("Cache-Control", "no-store".to_string()),
+    ];
+    match shortcuts::stream_file(&request, &export_path(&export_id), &headers).await {
+        Ok(response) => response,
+        Err(error) => {
+            log::error!("Failed to read user export. Error: {}", error);
+
+            JsonResponse::not_found().body(
+                ApiEnvelope::failed(ErrorCode::NotFound)
+                    .message("Export not found.")
+                    .to_value(),
+            )
+        }
+    }
+}
+
+///
+/// Writes the ZIP of every task of `user_identifier` to the exports directory. Returns the
+/// number of exported tasks and the size of the ZIP in bytes.
+///
+async fn export_user(
+    shared_context: &SharedContext,
+    user_identifier: &str,
+    export_id: &Uuid,
+) -> std::io::Result<(usize, u64)> {
+    let media_root = match env::var("MEDIA_ROOT") {
+        Ok(path) => PathBuf::from(path),
+        Err(error) => {
+            eprintln!("MEDIA_ROOT environment variable is missing.");
+            return Err(std::io::Error::other(error));
+        }
+    };
+
+    let db_wrapper = shared_context.db_wrapper.clone();
+    let keys = BackgroundRemoverTask::fetch_by_user_identifiers(
+        db_wrapper.clone(),
+        &erasure::stored_user_identifiers(shared_context, user_identifier),
+    )
+    .await
+    .map_err(std::io::Error::other)?;
+
+    let mut tasks: Vec<Value> = vec![];
+    let mut directories: Vec<(Uuid, PathBuf)> = vec![];
+    for (key, _, tenant) in &keys {
+        let instance = BackgroundRemoverTask::fetch(db_wrapper.clone(), key)
+            .await
+            .map_err(std::io::Error::other)?;
+        let revisions = TaskRevision::fetch_by_task(db_wrapper.clone(), key)
+            .await
+            .map_err(std::io::Error::other)?;
+
+        tasks.push(json!({
+            "task": instance.serialize_full().map_err(std::io::Error::other)?,
+            "revisions": revisions,
+        }));
+        directories.push((
+            *key,
+            path_utils::task_directory(
+                &media_root,
+                tenant.as_deref(),
+                key,
+                path_utils::shard_levels(),
+            )
+            .await?,
+        ));
+    }
+
+    let tasks_json = serde_json::to_vec_pretty(&tasks).map_err(std::io::Error::other)?;
+    let path = export_path(export_id);
+    let task_count = tasks.len();
+
+    // Files are read and written with blocking IO, so the ZIP is built off the runtime threads.
+    let size = tokio::task::spawn_blocking(move || write_archive(&path, &tasks_json, &directories))
+        .await
+        .map_err(std::io::Error::other)??;
+    Ok((task_count, size))
+}
+
+///
+/// Writes `tasks.json` and the files of every task directory as `tasks/<key>/...` to `path`.
+/// Media is already compressed, so entries are stored as they are. The ZIP is written next to
+/// `path` and renamed once complete, so a partial export is never served.
+///
+fn write_archive(
+    path: &Path,
+    tasks_json: &[u8],
+    directories: &[(Uuid, PathBuf)],
+) -> std::io::Result<u64> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let partial_path = path.with_extension("zip.partial");
+    let mut archive = ZipWriter::new(File::create(&partial_path)?);
+    let options = SimpleFileOptions::default()
+        .compression_method(CompressionMethod::Stored)
+        .large_file(true);
+
+    archive
+        .start_file("tasks.json", options)
+        .map_err(std::io::Error::other)?;
+    archive.write_all(tasks_json)?;
+
+    for (key, directory) in directories {
+        let mut pending = vec![directory.clone()];
+        while let Some(dir) = pending.pop() {
+            let entries = match std::fs::read_dir(&dir) {
+                Ok(entries) => entries,
+                Err(_) => continue,
+            };
+
+            for entry in entries.flatten() {
+                let entry_path = entry.path();
+                if entry_path.is_dir() {
+                    pending.push(entry_path);
+                    continue;
+                }
+
+                let relative_path = match entry_path.strip_prefix(directory) {
+                    Ok(relative_path) => relative_path.to_string_lossy().to_string(),
+                    Err(_) => continue,
+                };
+                archive
+                    .start_file(format!("tasks/{}/{}", key, relative_path), options)
+                    .map_err(std::io::Error::other)?;
+                std::io::copy(&mut File::open(&entry_path)?, &mut archive)?;
+            }
+        }
+    }
+
+    archive.finish().map_err(std::io::Error::other)?;
+    std::fs::rename(&partial_path, path)?;
+    Ok(std::fs::metadata(path)?.len())
+}
+
+///
+/// Deletes exports whose download links expired.
+///
+async fn remove_expired_exports(ttl: Duration) {
+    let mut entries = match tokio::fs::read_dir(exports_dir()).await {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let expired = match entry
+            .metadata()
+            .await
+            .and_then(|metadata| metadata.modified())
+        {
+            Ok(modified) => {
+                SystemTime::now()
+                    .duration_since(modified)
+                    .unwrap_or_default()
+                    > ttl
+            }
+            Err(_) => false,
+        };
+
+        if expired {
+            if let Err(error) = tokio::fs::remove_file(entry.path()).await {
+                eprintln!("Failed to remove {:?}. Error: {}", entry.path(), error);
+            }
+        }
+    }
+}
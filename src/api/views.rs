@@ -1,6 +1,8 @@
 use std::env;
 use std::path::PathBuf;
+use std::time::Instant;
 
+use racoon::core::headers::HeaderValue;
 use racoon::core::request::Request;
 use racoon::core::response::status::ResponseStatus;
 use racoon::core::response::{HttpResponse, JsonResponse, Response};
@@ -8,48 +10,269 @@ use racoon::core::shortcuts::SingleText;
 use racoon::core::websocket::WebSocket;
 use racoon::forms::FormValidator;
 
-use serde_json::json;
+use chrono::Utc;
+use serde::Deserialize;
+use serde_json::{json, Value};
 use uuid::Uuid;
 
-use crate::api::forms::PublicImageUploadForm;
-use crate::db::models::{BackgroundRemoverTask, NewBackgroundRemoverTask};
-use crate::utils::path_utils;
+use crate::api::billing;
+use crate::api::contact_sheets;
+use crate::api::error_codes::ErrorCode;
+use crate::api::forms::{PublicImageUploadForm, RefineMaskForm};
+use crate::api::lifecycle::{LifecycleEvent, LifecycleStage};
+use crate::api::organizations;
+use crate::api::previews::{self, PreviewOf};
+use crate::api::shortcuts;
+use crate::api::upload_signatures;
+use crate::api::ws_clients::{ConnectionLimitError, WsConnection};
+use crate::api::ws_messages::ServerMessage;
+use crate::build_info;
+use crate::db::models::{
+    BackgroundRemoverTask, NewBackgroundRemoverTask, TaskFilter, TaskGroupNotification, TaskOutputs,
+    TaskRevision, TASKS_PER_PAGE,
+};
+use crate::utils::billing_utils::{BillingAccount, TenantAccess};
+use crate::utils::envelope_utils::ApiEnvelope;
+use crate::utils::query_utils::QueryError;
+use crate::utils::temp_utils;
+use crate::utils::timing_utils::{self, TaskStage};
+use crate::utils::version_utils::ApiVersion;
+use crate::utils::{cursor_utils, image_utils, metadata_utils, path_utils, signature_utils};
 use crate::SharedContext;
 
 use super::task;
 
+// Hard coded base url. The version prefix is added per request.
+const API_BASE_URL: &str = "https://apistaging.erasebg.org";
+
+// Tasks processing for longer than this are assumed lost and not counted in the queue depth.
+pub const QUEUE_WINDOW_MINUTES: i64 = 10;
+
 pub async fn public_upload(request: Request) -> Response {
+    upload(request, ApiVersion::V1).await
+}
+
+pub async fn public_upload_v2(request: Request) -> Response {
+    upload(request, ApiVersion::V2).await
+}
+
+async fn upload(request: Request, version: ApiVersion) -> Response {
     if request.method != "POST" {
         return HttpResponse::ok().body("This request method is not supported.");
     }
 
     let form = PublicImageUploadForm::new();
 
-    // If form contains error, returns error response.
-    let validated_form = match form.validate(&request).await {
+    // If form contains error, returns error response. The uploaded file is moved to the task
+    // directory once everything else is validated. Its temp file is removed on every other return
+    // path, including failed validation.
+    let parse_started = Instant::now();
+    let (validated, _upload_temps) = temp_utils::guard_uploads(form.validate(&request)).await;
+    let validated_form = match validated {
         Ok(form) => form,
         Err(error) => {
             eprintln!("Errors: {:?}", error);
 
-            return JsonResponse::bad_request().body(json!({
-                "status": "failed",
-                "status_code": "form_error",
-                "field_errors": error.field_errors,
-                "other_errors": error.others,
-            }));
+            return JsonResponse::bad_request().body(
+                ApiEnvelope::failed(ErrorCode::FormError)
+                    .field("field_errors", error.field_errors)
+                    .field("other_errors", error.others)
+                    .to_value(),
+            );
         }
     };
+    let upload_parse = parse_started.elapsed();
 
-    // Handles validated form data
     let original_image = validated_form.original_image.value().await;
+
+    // Metadata and tags are optional. Validated before touching the uploaded file.
+    let metadata = match validated_form.metadata.value().await {
+        Some(raw) if !raw.trim().is_empty() => match metadata_utils::parse_metadata(&raw) {
+            Ok(metadata) => Some(metadata),
+            Err(error) => {
+                return JsonResponse::bad_request().body(
+                    ApiEnvelope::failed(ErrorCode::FormError)
+                        .field("field_errors", json!({ "metadata": [error] }))
+                        .to_value(),
+                );
+            }
+        },
+        _ => None,
+    };
+
+    let tags = match validated_form.tags.value().await {
+        Some(raw) => match metadata_utils::parse_tags(&raw) {
+            Ok(tags) => tags,
+            Err(error) => {
+                return JsonResponse::bad_request().body(
+                    ApiEnvelope::failed(ErrorCode::FormError)
+                        .field("field_errors", json!({ "tags": [error] }))
+                        .to_value(),
+                );
+            }
+        },
+        None => vec![],
+    };
+
+    let outputs = match validated_form.outputs.value().await {
+        Some(raw) if !raw.trim().is_empty() => match TaskOutputs::parse(raw.trim()) {
+            Some(outputs) => outputs,
+            None => {
+                return JsonResponse::bad_request().body(
+                    ApiEnvelope::failed(ErrorCode::FormError)
+                        .field(
+                            "field_errors",
+                            json!({ "outputs": ["Outputs must be either all or mask."] }),
+                        )
+                        .to_value(),
+                );
+            }
+        },
+        _ => TaskOutputs::All,
+    };
+
+    let background_hint = match validated_form.background_hint.value().await {
+        Some(raw) if !raw.trim().is_empty() => match metadata_utils::parse_background_hint(&raw) {
+            Ok(background_hint) => Some(background_hint),
+            Err(error) => {
+                return JsonResponse::bad_request().body(
+                    ApiEnvelope::failed(ErrorCode::FormError)
+                        .field("field_errors", json!({ "background_hint": [error] }))
+                        .to_value(),
+                );
+            }
+        },
+        _ => None,
+    };
+
     let shared_context: &SharedContext = request.context().expect("SharedContext is missing.");
 
+    let notify_email = match validated_form.notify_email.value().await {
+        Some(raw) if !raw.trim().is_empty() => {
+            // Unsigned uploads can't prove who they are, so they could make the service email
+            // anyone.
+            let parsed = match (&shared_context.mailer, upload_signatures::key_id(&request)) {
+                (None, _) => Err("Email notifications are not enabled.".to_string()),
+                (Some(_), None) => Err("Requires a signed upload.".to_string()),
+                (Some(_), Some(_)) => metadata_utils::parse_notify_email(&raw),
+            };
+
+            match parsed {
+                Ok(email) => Some(email),
+                Err(error) => {
+                    return JsonResponse::bad_request().body(
+                        ApiEnvelope::failed(ErrorCode::FormError)
+                            .field("field_errors", json!({ "notify_email": [error] }))
+                            .to_value(),
+                    );
+                }
+            }
+        }
+        _ => None,
+    };
+
+    let declared_sha256 = match validated_form.sha256.value().await {
+        Some(raw) if !raw.trim().is_empty() => match signature_utils::parse_sha256(&raw) {
+            Some(sha256) => Some(sha256),
+            None => {
+                return JsonResponse::bad_request().body(
+                    ApiEnvelope::failed(ErrorCode::FormError)
+                        .field(
+                            "field_errors",
+                            json!({ "sha256": ["Must be a hex SHA-256 of original_image."] }),
+                        )
+                        .to_value(),
+                );
+            }
+        },
+        _ => None,
+    };
+
     // Unique id for each task. Used for database lookup and saving files.
     let task_id = Uuid::new_v4();
 
+    // Never trust the client filename on disk. Files are stored under their content hash and the
+    // client filename is only kept in the database.
+    let extension = match path_utils::sanitize_filename(&original_image.filename) {
+        Some(sanitized) => match sanitized.rsplit_once('.') {
+            Some((_, extension)) => extension.to_string(),
+            None => "jpg".to_string(),
+        },
+        None => {
+            return JsonResponse::bad_request().body(
+                ApiEnvelope::failed(ErrorCode::FormError)
+                    .field("field_errors", json!({ "original_image": ["Unsupported file type."] }))
+                    .to_value(),
+            );
+        }
+    };
+
+    let (filename, original_format, content_sha256) =
+        match hash_uploaded_file(&original_image.temp_path, extension).await {
+            Ok(hashed) => hashed,
+            Err(error) => {
+                eprintln!("Failed to hash original image. Error: {}", error);
+                return JsonResponse::internal_server_error()
+                    .body(ApiEnvelope::failed(ErrorCode::InternalServerError).to_value());
+            }
+        };
+
+    // Clients declaring the hash of the image learn about uploads corrupted in transit, and can
+    // upload again.
+    if declared_sha256
+        .as_ref()
+        .is_some_and(|declared| *declared != content_sha256)
+    {
+        shared_context
+            .metrics
+            .increment("upload_checksum_mismatches_total{stage=\"upload\"}");
+        return checksum_mismatch();
+    }
+
+    // Signed uploads declare the hash of the image, which the middleware verified the signature
+    // of. See `upload_signatures`.
+    if let Some(declared) = request.headers.value("X-Content-SHA256") {
+        if declared.as_str().trim().to_ascii_lowercase() != content_sha256 {
+            return JsonResponse::unauthorized().body(
+                ApiEnvelope::failed(ErrorCode::InvalidSignature)
+                    .message("Uploaded image does not match X-Content-SHA256.")
+                    .to_value(),
+            );
+        }
+    }
+
+    // The signature was checked by the middleware, so its key id identifies the client. Its
+    // billing account is the tenant the media of the task is stored under, and accounts with a
+    // billing plan are checked against its quota. See `billing`.
+    let api_key_id = upload_signatures::key_id(&request);
+    let account = match &api_key_id {
+        Some(key_id) => {
+            match billing::billing_account(shared_context.db_wrapper.clone(), key_id).await {
+                Ok(account) => Some(account),
+                Err(error) => {
+                    eprintln!("Failed to fetch billing account. Error: {}", error);
+                    return JsonResponse::internal_server_error()
+                        .body(ApiEnvelope::failed(ErrorCode::InternalServerError).to_value());
+                }
+            }
+        }
+        None => None,
+    };
+    let priority = match &account {
+        Some(account) => match billing::check_quota(shared_context, account).await {
+            Ok(priority) => priority,
+            Err(error) => return error.response(),
+        },
+        None => 0,
+    };
+    let tenant = account.as_ref().map(BillingAccount::media_prefix);
+
     let original_image_save_path = match path_utils::generate_save_path(
-        path_utils::ForImage::OriginalImage(&task_id, &original_image.filename),
-    ) {
+        tenant.as_deref(),
+        path_utils::ForImage::OriginalImage(&task_id, &filename),
+    )
+    .await
+    {
         Ok(path) => path,
         Err(error) => {
             eprintln!(
@@ -57,10 +280,8 @@ pub async fn public_upload(request: Request) -> Response {
                 error
             );
 
-            return JsonResponse::internal_server_error().body(json!({
-                "status": "failed",
-                "status_code": "internal_server_error"
-            }));
+            return JsonResponse::internal_server_error()
+                .body(ApiEnvelope::failed(ErrorCode::InternalServerError).to_value());
         }
     };
 
@@ -69,23 +290,53 @@ pub async fn public_upload(request: Request) -> Response {
         "Moving file from: {:?} to {:?}",
         original_image.temp_path, original_image_save_path
     );
-    let result = tokio::fs::copy(original_image.temp_path, &original_image_save_path).await;
+    if let Err(error) =
+        temp_utils::move_file(&original_image.temp_path, &original_image_save_path).await
+    {
+        eprintln!("Failed to move original image. Error: {}", error);
 
-    let destination = std::path::PathBuf::from(&original_image_save_path);
-    if !destination.exists() {
-        eprintln!("File move called but not moved. More info:");
-        eprintln!("{:?}", result);
+        return JsonResponse::internal_server_error().body(
+            ApiEnvelope::failed(ErrorCode::FileMoveFailed)
+                .message("Failed to store the uploaded image.")
+                .field("reason", error.reason())
+                .to_value(),
+        );
+    }
 
-        return JsonResponse::internal_server_error().body(json!({
-            "status": "failed",
-            "message": "Internal server error.",
-        }))
+    // The stored file is read back, as the task is dispatched from it.
+    if let Some(declared) = &declared_sha256 {
+        match file_sha256(&original_image_save_path).await {
+            Ok(stored_sha256) if stored_sha256 == *declared => {}
+            Ok(_) => {
+                eprintln!("Stored original image does not match its sha256.");
+                shared_context
+                    .metrics
+                    .increment("upload_checksum_mismatches_total{stage=\"storage\"}");
+                let _ = tokio::fs::remove_file(&original_image_save_path).await;
+                return checksum_mismatch();
+            }
+            Err(error) => {
+                eprintln!("Failed to read back original image. Error: {}", error);
+                let _ = tokio::fs::remove_file(&original_image_save_path).await;
+                return JsonResponse::internal_server_error()
+                    .body(ApiEnvelope::failed(ErrorCode::InternalServerError).to_value());
+            }
+        }
     }
 
     // Saves to database
     let task_group = validated_form.task_group.value().await;
     let country = validated_form.country.value().await;
-    let user_identifier = validated_form.user_identifier.value().await;
+    // Stored pseudonymized when signing keys are configured. See `Keyring`.
+    let user_identifier = match (
+        validated_form.user_identifier.value().await,
+        shared_context.secrets.keyring(),
+    ) {
+        (Some(user_identifier), Some(keyring)) => {
+            Some(keyring.hash_user_identifier(&user_identifier))
+        }
+        (user_identifier, _) => user_identifier,
+    };
 
     let media_root = match env::var("MEDIA_ROOT") {
         Ok(path) => PathBuf::from(path),
@@ -94,11 +345,11 @@ pub async fn public_upload(request: Request) -> Response {
                 "The MEDIA_ROOT environment variable is missing. Error: {}",
                 error
             );
-            return JsonResponse::internal_server_error().body(json!({
-                "status": "failed",
-                "status_code": "internal_server_error",
-                "message": "Internal Server Error"
-            }));
+            return JsonResponse::internal_server_error().body(
+                ApiEnvelope::failed(ErrorCode::InternalServerError)
+                    .message("Internal Server Error")
+                    .to_value(),
+            );
         }
     };
 
@@ -108,8 +359,16 @@ pub async fn public_upload(request: Request) -> Response {
     let preview_original_image_media_url =
         path_utils::relative_media_url_from_full_path(&media_root, &original_image_save_path);
 
+    // Display name only. Capped since clients may send absurdly long names.
+    let original_filename: String = original_image.filename.chars().take(255).collect();
+
+    let free_tier = api_key_id
+        .as_ref()
+        .is_some_and(|kid| shared_context.config.load().free_tier_key_ids.contains(kid));
+
     let new_task = NewBackgroundRemoverTask {
         country,
+        original_filename: Some(original_filename),
         key: task_id,
         original_image_path: relative_original_image_media_url
             .to_string_lossy()
@@ -119,6 +378,28 @@ pub async fn public_upload(request: Request) -> Response {
             .to_string(),
         task_group,
         user_identifier,
+        metadata,
+        tags,
+        original_format: original_format.map(|format| format.to_string()),
+        outputs,
+        background_hint,
+        // The original preview generated below uses the current settings.
+        preview_settings: shared_context
+            .config
+            .load()
+            .preview_options()
+            .fingerprint(),
+        free_tier,
+        api_key_id,
+        priority,
+        tenant,
+        // Uploads of the same image may be served from the outputs of this task, or this task from
+        // theirs. See `AppConfig::upload_dedup_window`.
+        content_sha256: (!shortcuts::no_dedup(&request)).then_some(content_sha256),
+        timings: Some(timing_utils::timings_json(&[(
+            TaskStage::UploadParse,
+            upload_parse,
+        )])),
     };
 
     match BackgroundRemoverTask::insert_new_task(shared_context.db_wrapper.clone(), &new_task).await
@@ -126,57 +407,300 @@ pub async fn public_upload(request: Request) -> Response {
         Ok(()) => {}
         Err(error) => {
             eprint!("Failed to insert new task to database. Error: {}", error);
-            return JsonResponse::ok().body(json!({
-                "status": "success",
-                "filename": original_image.filename
-            }));
+            return JsonResponse::internal_server_error()
+                .body(ApiEnvelope::failed(ErrorCode::InternalServerError).to_value());
         }
     };
 
-    // Sends this image for processing.
-    JsonResponse::ok().body(json!({
-        "status": "success",
-        "status_code": "image_upload",
-        "data": {
-            "key": new_task.key,
-            "task_group": new_task.task_group,
+    shared_context.lifecycle.emit(LifecycleEvent::new(
+        LifecycleStage::Created,
+        task_id,
+        new_task.task_group,
+    ));
+
+    if let Some(email) = &notify_email {
+        let db_wrapper = shared_context.db_wrapper.clone();
+        if let Err(error) =
+            TaskGroupNotification::register(db_wrapper, &new_task.task_group, email).await
+        {
+            eprintln!("Failed to register task group notification. Error: {}", error);
         }
-    }))
+    }
+
+    // The preview is filled in later and announced with `preview_ready`.
+    tokio::spawn(previews::generate(
+        shared_context.clone(),
+        task_id,
+        PreviewOf::Original,
+    ));
+
+    // Sends this image for processing.
+    JsonResponse::ok().body(
+        ApiEnvelope::success("image_upload")
+            .data(json!({
+                version.task_id_field(): new_task.key,
+                "task_group": new_task.task_group,
+                "token": shared_context
+                    .secrets
+                    .keyring()
+                    .map(|keyring| keyring.sign_task_key(&new_task.key)),
+            }))
+            .to_value(),
+    )
 }
 
 pub async fn task_details_view(request: Request) -> Response {
+    task_details(request, ApiVersion::V1).await
+}
+
+pub async fn task_details_view_v2(request: Request) -> Response {
+    task_details(request, ApiVersion::V2).await
+}
+
+async fn task_details(request: Request, version: ApiVersion) -> Response {
     let context = request.context::<SharedContext>().unwrap();
+    let instance = match fetch_owned_task(&request, context).await {
+        Ok(instance) => instance,
+        Err(response) => return response,
+    };
+    previews::refresh_if_stale(context, &instance).await;
+
+    // Outputs of the latest revision are returned by default.
+    let latest_revision =
+        match TaskRevision::fetch_latest_completed(context.db_wrapper.clone(), &instance.key).await
+        {
+            Ok(revision) => revision,
+            Err(error) => {
+                log::error!("Failed to fetch latest revision. Error: {}", error);
+                return JsonResponse::internal_server_error().empty();
+            }
+        };
+
+    let mut serialized = match context
+        .task_json_cache
+        .serialize(&instance, latest_revision.as_ref())
+    {
+        Ok(serialized) => serialized,
+        Err(error) => {
+            log::error!("{}", error);
+            return JsonResponse::internal_server_error().empty();
+        }
+    };
+
+    if instance.processing.unwrap_or(false) {
+        let processing_times = &context.processing_times;
+        let estimated_seconds = processing_times.estimate_seconds(processing_times.queue_depth());
+        if let Some(map) = serialized.as_object_mut() {
+            map.insert("estimated_seconds".to_string(), Value::from(estimated_seconds));
+        }
+    }
+
+    shortcuts::negotiated_ok(&request, version.serialize_task(serialized))
+}
+
+///
+/// Fetches the task of path parameter `task_id` if the request proves ownership with the
+/// `task_group` of the task or its signed `token` as query parameter, the same check the
+/// websocket does. Tasks failing the check are reported as not found, so keys can't be probed,
+/// and count as failed key lookup of the client IP. Tasks stored under a tenant also require
+/// credentials of that tenant, see `check_tenants`.
+///
+async fn fetch_owned_task(
+    request: &Request,
+    context: &SharedContext,
+) -> Result<BackgroundRemoverTask, Response> {
+    fetch_task_owned_by(request, context, request.query_params.value("task_group")).await
+}
+
+///
+/// Same as `fetch_owned_task`, with `task_group` taken from elsewhere than the query, e.g. a form.
+///
+async fn fetch_task_owned_by(
+    request: &Request,
+    context: &SharedContext,
+    task_group: Option<&str>,
+) -> Result<BackgroundRemoverTask, Response> {
+    if let Some(retry_after) = shortcuts::key_lookup_ban(request).await {
+        return Err(shortcuts::too_many_key_failures(retry_after));
+    }
+    let client_ip = shortcuts::client_ip(request).await;
+
     let task_id = match Uuid::parse_str(request.path_params.value("task_id").unwrap()) {
         Ok(uuid) => uuid,
         Err(error) => {
             log::error!("{}", error);
+            shortcuts::record_key_lookup_failure(context, client_ip.as_deref());
 
-            return JsonResponse::bad_request().body(json!({
-                "error": "Not a valid task id format."
-            }));
+            return Err(JsonResponse::bad_request().body(legacy_error(
+                ErrorCode::BadRequest,
+                "Not a valid task id format.",
+            )));
         }
     };
 
+    let token = request.query_params.value("token");
+    if task_group.is_none() && token.is_none() {
+        return Err(JsonResponse::bad_request().body(
+            ApiEnvelope::failed(ErrorCode::BadQuery)
+                .message("task_group or token is required.")
+                .to_value(),
+        ));
+    }
+
+    let not_found =
+        || JsonResponse::not_found().body(legacy_error(ErrorCode::NotFound, "Invalid task id."));
+
     let instance = match BackgroundRemoverTask::fetch(context.db_wrapper.clone(), &task_id).await {
         Ok(instance) => instance,
+        Err(sqlx::Error::RowNotFound) => {
+            shortcuts::record_key_lookup_failure(context, client_ip.as_deref());
+            return Err(not_found());
+        }
         Err(error) => {
             log::error!("{}", error);
-
-            return JsonResponse::not_found().body(json!({
-                "error": "Invalid task id."
-            }));
+            return Err(not_found());
         }
     };
 
-    let serialized = match instance.serialize() {
-        Ok(serialized) => serialized,
+    let group_matches = task_group
+        .and_then(|task_group| Uuid::parse_str(task_group).ok())
+        .is_some_and(|task_group| task_group == instance.task_group);
+    let token_matches = match (token, context.secrets.keyring()) {
+        (Some(token), Some(keyring)) => keyring.verify_task_key(&instance.key, token),
+        _ => false,
+    };
+
+    if !group_matches && !token_matches {
+        shortcuts::record_key_lookup_failure(context, client_ip.as_deref());
+        return Err(not_found());
+    }
+
+    match check_tenants(request, instance.tenant.as_slice()).await? {
+        true => Ok(instance),
+        false => {
+            shortcuts::record_key_lookup_failure(context, client_ip.as_deref());
+            Err(not_found())
+        }
+    }
+}
+
+///
+/// Whether the request may read tasks stored under `tenants`, so a leaked task group or token of
+/// one tenant is useless without credentials of that tenant. Requests without credentials are
+/// rejected when any tenant is involved; the admin token reads every tenant.
+///
+async fn check_tenants<S: AsRef<str>>(
+    request: &Request,
+    tenants: &[S],
+) -> Result<bool, Response> {
+    if tenants.is_empty() {
+        return Ok(true);
+    }
+
+    match organizations::tenant_access(request).await? {
+        TenantAccess::Anonymous => Err(tenant_credentials_required()),
+        access => Ok(access.allows(tenants)),
+    }
+}
+
+fn tenant_credentials_required() -> Response {
+    JsonResponse::unauthorized().body(
+        ApiEnvelope::failed(ErrorCode::Unauthorized)
+            .message("Credentials of the task's tenant are required.")
+            .to_value(),
+    )
+}
+
+///
+/// Summary of the tasks of a task group with a contact sheet of their processed previews. The
+/// contact sheet is rendered in the background on first request, so clients poll until its status
+/// is `ready`.
+///
+pub async fn task_group_summary_view(request: Request) -> Response {
+    let context = request.context::<SharedContext>().unwrap();
+    let task_group = match Uuid::parse_str(request.path_params.value("task_group").unwrap()) {
+        Ok(uuid) => uuid,
         Err(error) => {
             log::error!("{}", error);
+
+            return JsonResponse::bad_request().body(legacy_error(
+                ErrorCode::BadRequest,
+                "Not a valid task group format.",
+            ));
+        }
+    };
+
+    let db_wrapper = context.db_wrapper.clone();
+    let (total, processed, processing, failed) =
+        match BackgroundRemoverTask::count_by_task_group(db_wrapper.clone(), &task_group).await {
+            Ok(counts) => counts,
+            Err(error) => {
+                log::error!("Failed to count tasks of task group. Error: {}", error);
+                return JsonResponse::internal_server_error().empty();
+            }
+        };
+
+    if total == 0 {
+        return JsonResponse::not_found()
+            .body(legacy_error(ErrorCode::NotFound, "Invalid task group."));
+    }
+
+    let tasks = match BackgroundRemoverTask::fetch_by_task_group(
+        db_wrapper,
+        &task_group,
+        contact_sheets::MAX_CONTACT_SHEET_TASKS,
+    )
+    .await
+    {
+        Ok(tasks) => tasks,
+        Err(error) => {
+            log::error!("Failed to fetch tasks of task group. Error: {}", error);
             return JsonResponse::internal_server_error().empty();
         }
     };
 
-    JsonResponse::ok().body(serialized)
+    let tenants: Vec<&str> = tasks
+        .iter()
+        .filter_map(|task| task.tenant.as_deref())
+        .collect();
+    match check_tenants(&request, &tenants).await {
+        Ok(true) => {}
+        Ok(false) => {
+            let client_ip = shortcuts::client_ip(&request).await;
+            shortcuts::record_key_lookup_failure(context, client_ip.as_deref());
+            return JsonResponse::not_found()
+                .body(legacy_error(ErrorCode::NotFound, "Invalid task group."));
+        }
+        Err(response) => return response,
+    }
+
+    let contact_sheet = match context
+        .contact_sheets
+        .get_or_generate(context, &task_group, &tasks)
+        .await
+    {
+        Ok(contact_sheet) => contact_sheet,
+        Err(error) => {
+            log::error!("Failed to look up contact sheet. Error: {}", error);
+            return JsonResponse::internal_server_error().empty();
+        }
+    };
+
+    let body = ApiEnvelope::success("task_group_summary")
+        .data(json!({
+            "task_group": task_group,
+            "total": total,
+            "processed": processed,
+            "processing": processing,
+            "failed": failed,
+            "contact_sheet": {
+                "status": contact_sheet.as_str(),
+                "url": contact_sheet.url(),
+                "max_tasks": contact_sheets::MAX_CONTACT_SHEET_TASKS,
+            },
+        }))
+        .to_value();
+    shortcuts::negotiated_ok(&request, body)
 }
 
 pub async fn listen_processing_ws(request: Request) -> Response {
@@ -196,12 +720,8 @@ pub async fn listen_processing_ws(request: Request) -> Response {
         Err(error) => {
             eprintln!("Failed to parse task_group to UUID. Error: {}", error);
 
-            let _ = websocket
-                .send_json(&json!({
-                    "status": "failed",
-                    "status_code": "invalid_path_format",
-                    "message": "Invalid task group."
-                }))
+            ServerMessage::failed(ErrorCode::InvalidPathFormat, "Invalid task group.")
+                .send(&websocket)
                 .await;
             return websocket.exit();
         }
@@ -211,66 +731,299 @@ pub async fn listen_processing_ws(request: Request) -> Response {
     let shared_context: &SharedContext = request.context().expect("SharedContext is missing.");
     let ws_clients = shared_context.ws_clients.clone();
 
+    // Tasks of a tenant are only followed with credentials of that tenant, like task details.
+    let tenant_access = match organizations::tenant_access(&request).await {
+        Ok(access) => access,
+        Err(_) => {
+            ServerMessage::failed(ErrorCode::Unauthorized, "Invalid credentials.")
+                .send(&websocket)
+                .await;
+            return websocket.exit();
+        }
+    };
+    let db_wrapper = shared_context.db_wrapper.clone();
+    let tenants = match BackgroundRemoverTask::fetch_task_group_tenants(db_wrapper, &task_group)
+        .await
+    {
+        Ok(tenants) => tenants,
+        Err(error) => {
+            eprintln!("Failed to fetch tenants of task group. Error: {}", error);
+            ServerMessage::internal_server_error()
+                .send(&websocket)
+                .await;
+            return websocket.exit();
+        }
+    };
+    if !tenant_access.allows(&tenants) {
+        ServerMessage::failed(
+            ErrorCode::Unauthorized,
+            "Credentials of the task's tenant are required.",
+        )
+        .send(&websocket)
+        .await;
+        return websocket.exit();
+    }
+
     // Adds this websocket connection to ws_clients. Until all references are dropped, it will stay
     // alive.
-    ws_clients.add(&task_group, websocket.clone()).await;
+    let mut connection =
+        WsConnection::new(websocket.clone(), shortcuts::client_ip(&request).await);
+    connection.tenant_access = tenant_access;
+    if let Err(error) = ws_clients.add(&task_group, connection.clone()).await {
+        let message = match error {
+            ConnectionLimitError::TaskGroup => "Too many connections for this task group.",
+            ConnectionLimitError::Ip => "Too many connections from this IP address.",
+        };
+
+        ServerMessage::failed(ErrorCode::ConnectionLimit, message)
+            .send(&websocket)
+            .await;
+        return websocket.exit();
+    }
 
     while let Some(message) = websocket.message().await {
-        task::handle_ws_received_message(&task_group, &websocket, shared_context, message).await;
+        connection.touch();
+        task::handle_ws_received_message(&task_group, &connection, shared_context, message).await;
     }
 
     // Removes websocket instance from ws_clients.
-    ws_clients.remove(&task_group, websocket.clone()).await;
+    ws_clients.remove(&task_group, &websocket).await;
     websocket.exit()
 }
 
 ///
 /// Endpoint for displaying all the background remover tasks.
 ///
+/// Supports two pagination modes:
+/// - `?page=` offset based pagination used by the dashboard.
+/// - `?cursor=` keyset pagination on `task_id`. Pass an empty cursor for the first page and the
+///   returned `next_cursor` for the following pages. Stable while new tasks are inserted.
+///
+/// Both modes can be filtered with `?tag=` and `?metadata=`, a JSON object the task metadata must
+/// contain. Only the admin token lists every task; tenants list their own tasks and requests
+/// without credentials the tasks outside every tenant.
+///
 pub async fn tasks_view(request: Request) -> Response {
+    tasks(request, ApiVersion::V1).await
+}
+
+pub async fn tasks_view_v2(request: Request) -> Response {
+    tasks(request, ApiVersion::V2).await
+}
+
+///
+/// Query parameters of `tasks_view`.
+///
+#[derive(Deserialize)]
+struct TasksQuery {
+    page: Option<u32>,
+    cursor: Option<String>,
+    tag: Option<String>,
+    metadata: Option<String>,
+}
+
+async fn tasks(request: Request, version: ApiVersion) -> Response {
     let shared_context = request.context::<SharedContext>().unwrap();
 
-    let page_num: u32;
-    if let Some(param_page) = request.query_params.value("page") {
-        // Type casts page string to u32. If fails returns JSON error
-        page_num = match param_page.parse::<u32>() {
-            Ok(value) => value,
-            Err(error) => {
-                log::error!(
-                    "Page number string to u32 conversion error. Error: {:?}",
-                    error
-                );
-                return JsonResponse::bad_request().body(json!({
-                    "status": "failed",
-                    "status_code": "bad_query",
-                    "message": "Invalid page format",
-                }));
-            }
-        };
+    let query: TasksQuery = match shortcuts::query(&request) {
+        Ok(query) => query,
+        Err(response) => return response,
+    };
+
+    let tag = match query.tag {
+        Some(tag) if !metadata_utils::is_valid_tag(&tag) => {
+            return shortcuts::bad_query(QueryError::field("tag", "Invalid tag"));
+        }
+        tag => tag,
+    };
+
+    let metadata = match query.metadata {
+        Some(raw) => match metadata_utils::parse_metadata(&raw) {
+            Ok(metadata) => Some(metadata),
+            Err(error) => return shortcuts::bad_query(QueryError::field("metadata", error)),
+        },
+        None => None,
+    };
+
+    // Like task details, only the admin token lists every tenant. Members of an organization list
+    // the tasks of its keys only.
+    let access = match organizations::tenant_access(&request).await {
+        Ok(access) => access,
+        Err(response) => return response,
+    };
+    let organization_id = match access {
+        TenantAccess::Admin => None,
+        _ => match organizations::authenticated_member(&request).await {
+            Ok(member) => member.map(|member| member.organization_id),
+            Err(response) => return response,
+        },
+    };
+
+    let filter = TaskFilter {
+        tag,
+        metadata,
+        organization_id,
+        access,
+    };
+
+    if let Some(cursor) = &query.cursor {
+        return tasks_view_by_cursor(shared_context, cursor, &filter, version).await;
+    }
+
+    let page_num = match query.page {
+        Some(0) => {
+            return shortcuts::bad_query(QueryError::field("page", "Page number starts from 1"));
+        }
+        page => page.unwrap_or(1),
+    };
+
+    let db_wrapper = shared_context.db_wrapper.clone();
+    let models = match BackgroundRemoverTask::fetch_by_page(db_wrapper, page_num, &filter).await {
+        Ok(models) => models,
+        Err(error) => {
+            println!("Failed to fetch models. Error: {}", error);
+
+            return JsonResponse::internal_server_error()
+                .body(ApiEnvelope::failed(ErrorCode::InternalServerError).to_value());
+        }
+    };
+
+    let values = serialize_full_all(&models, version);
+
+    let db_wrapper = shared_context.db_wrapper.clone();
+    let total = match BackgroundRemoverTask::length(db_wrapper, &filter).await {
+        Ok(value) => value,
+        Err(error) => {
+            log::error!("Failed to get length: Error: {}", error);
+            return JsonResponse::internal_server_error().empty();
+        }
+    };
+
+    let next_url;
+    if (page_num as u64) * (TASKS_PER_PAGE as u64) < total {
+        next_url = Some(format!(
+            "{}?page={}{}",
+            tasks_url(version),
+            page_num + 1,
+            filter_query(&filter)
+        ));
     } else {
-        page_num = 1;
+        next_url = None;
     }
 
-    let models =
-        match BackgroundRemoverTask::fetch_by_page(shared_context.db_wrapper.clone(), page_num)
-            .await
-        {
-            Ok(models) => models,
-            Err(error) => {
-                println!("Failed to fetch models. Error: {}", error);
+    let previous_url;
+    if page_num > 1 {
+        previous_url = Some(format!(
+            "{}?page={}{}",
+            tasks_url(version),
+            page_num - 1,
+            filter_query(&filter)
+        ));
+    } else {
+        previous_url = None;
+    }
 
-                return JsonResponse::internal_server_error().body(json!({
-                    "status": "failed",
-                    "status_code": "internal_server_error",
-                }));
+    JsonResponse::ok().body(json!({
+        "count": total,
+        "next": next_url,
+        "previous": previous_url,
+        "results": values
+    }))
+}
+
+async fn tasks_view_by_cursor(
+    shared_context: &SharedContext,
+    cursor: &str,
+    filter: &TaskFilter,
+    version: ApiVersion,
+) -> Response {
+    let before_task_id = if cursor.is_empty() {
+        None
+    } else {
+        match cursor_utils::decode_cursor(cursor) {
+            Some(task_id) => Some(task_id),
+            None => {
+                return JsonResponse::bad_request().body(
+                    ApiEnvelope::failed(ErrorCode::BadQuery)
+                        .message("Invalid cursor")
+                        .to_value(),
+                );
             }
-        };
+        }
+    };
 
+    let models = match BackgroundRemoverTask::fetch_by_cursor(
+        shared_context.db_wrapper.clone(),
+        before_task_id,
+        TASKS_PER_PAGE,
+        filter,
+    )
+    .await
+    {
+        Ok(models) => models,
+        Err(error) => {
+            log::error!("Failed to fetch models. Error: {}", error);
+
+            return JsonResponse::internal_server_error()
+                .body(ApiEnvelope::failed(ErrorCode::InternalServerError).to_value());
+        }
+    };
+
+    // A short page means there is nothing left to fetch.
+    let next_cursor = match models.last() {
+        Some(last) if models.len() as i64 == TASKS_PER_PAGE => {
+            Some(cursor_utils::encode_cursor(last.task_id))
+        }
+        _ => None,
+    };
+
+    let next_url = next_cursor.as_ref().map(|next_cursor| {
+        format!(
+            "{}?cursor={}{}",
+            tasks_url(version),
+            next_cursor,
+            filter_query(filter)
+        )
+    });
+
+    JsonResponse::ok().body(json!({
+        "next": next_url,
+        "next_cursor": next_cursor,
+        "results": serialize_full_all(&models, version)
+    }))
+}
+
+///
+/// Query string suffix keeping `filter` on pagination links.
+///
+fn filter_query(filter: &TaskFilter) -> String {
+    let mut query = String::new();
+    if let Some(tag) = &filter.tag {
+        query.push_str(&format!("&tag={}", metadata_utils::encode_query_value(tag)));
+    }
+    if let Some(metadata) = &filter.metadata {
+        let metadata = metadata.to_string();
+        query.push_str(&format!(
+            "&metadata={}",
+            metadata_utils::encode_query_value(&metadata)
+        ));
+    }
+    query
+}
+
+///
+/// Url of the tasks list of `version`, used for pagination links.
+///
+fn tasks_url(version: ApiVersion) -> String {
+    format!("{}{}/remove-tasks/", API_BASE_URL, version.prefix())
+}
+
+fn serialize_full_all(models: &[BackgroundRemoverTask], version: ApiVersion) -> Vec<Value> {
     let mut values = vec![];
     for instance in models {
         match instance.serialize_full() {
             Ok(serialized) => {
-                values.push(serialized);
+                values.push(version.serialize_task(serialized));
             }
 
             Err(error) => {
@@ -278,30 +1031,332 @@ pub async fn tasks_view(request: Request) -> Response {
             }
         }
     }
+    values
+}
 
-    let total = match BackgroundRemoverTask::length(shared_context.db_wrapper.clone()).await {
-        Ok(value) => value,
+///
+/// Public endpoint reporting whether image processing is currently available.
+///
+pub async fn service_status_view(request: Request) -> Response {
+    let shared_context: &SharedContext = request.context().expect("SharedContext is missing.");
+
+    let bp_connected = shared_context.public_bp_servers.any_connected();
+    let since = Utc::now() - chrono::Duration::minutes(QUEUE_WINDOW_MINUTES);
+    let queue_depth =
+        match BackgroundRemoverTask::count_processing(shared_context.db_wrapper.clone(), &since)
+            .await
+        {
+            Ok(queue_depth) => queue_depth,
+            Err(error) => {
+                log::error!("Failed to count processing tasks. Error: {}", error);
+                return JsonResponse::internal_server_error()
+                    .body(ApiEnvelope::failed(ErrorCode::InternalServerError).to_value());
+            }
+        };
+
+    let estimated_wait_secs = shared_context
+        .processing_times
+        .estimate_seconds(queue_depth as usize);
+
+    let body = ApiEnvelope::success("service_status")
+        .data(json!({
+            "processing_available": bp_connected,
+            "bp_connected": bp_connected,
+            "queue_depth": queue_depth,
+            "estimated_wait_secs": estimated_wait_secs,
+        }))
+        .to_value();
+    shortcuts::negotiated_ok(&request, body)
+}
+
+///
+/// Public endpoint returning the crate version, git commit and build time of this instance, for
+/// correlating incidents with deployed builds.
+///
+pub async fn version_view(request: Request) -> Response {
+    if request.method != "GET" {
+        return HttpResponse::ok().body("This request method is not supported.");
+    }
+
+    let body = ApiEnvelope::success("version")
+        .data(build_info::to_json())
+        .to_value();
+    shortcuts::negotiated_ok(&request, body)
+}
+
+///
+/// Accepts user brush-stroke corrections for a processed task and sends them to the BP server as
+/// a refinement request. The refined outputs are stored as a new revision of the task and
+/// delivered to the task group websocket as `revision_result`.
+///
+pub async fn refine_task_view(request: Request) -> Response {
+    if request.method != "POST" {
+        return HttpResponse::ok().body("This request method is not supported.");
+    }
+
+    let form = RefineMaskForm::new();
+    let (validated, _upload_temps) = temp_utils::guard_uploads(form.validate(&request)).await;
+    let validated_form = match validated {
+        Ok(form) => form,
         Err(error) => {
-            log::error!("Failed to get length: Error: {}", error);
+            return JsonResponse::bad_request().body(
+                ApiEnvelope::failed(ErrorCode::FormError)
+                    .field("field_errors", error.field_errors)
+                    .field("other_errors", error.others)
+                    .to_value(),
+            );
+        }
+    };
+
+    let correction_image = validated_form.correction_image.value().await;
+
+    let shared_context: &SharedContext = request.context().expect("SharedContext is missing.");
+    let db_wrapper = shared_context.db_wrapper.clone();
+
+    // Checked like task details, so refinements can't probe keys or reach tasks of other tenants.
+    let task_group = validated_form.task_group.value().await.to_string();
+    let instance = match fetch_task_owned_by(&request, shared_context, Some(&task_group)).await {
+        Ok(instance) => instance,
+        Err(response) => return response,
+    };
+
+    // Mask only tasks have no processed image, so the mask tells whether the task is processed.
+    if instance.mask_image_path.is_none() {
+        return JsonResponse::bad_request().body(
+            ApiEnvelope::failed(ErrorCode::NotProcessed)
+                .message("Only processed tasks can be refined.")
+                .to_value(),
+        );
+    }
+
+    // Saves correction mask inside the task directory.
+    let revision_key = Uuid::new_v4();
+    let (filename, content_sha256) =
+        match hash_uploaded_file(&correction_image.temp_path, "png".to_string()).await {
+            Ok((filename, _, content_sha256)) => (filename, content_sha256),
+            Err(error) => {
+                eprintln!("Failed to hash correction image. Error: {}", error);
+                return JsonResponse::internal_server_error()
+                    .body(ApiEnvelope::failed(ErrorCode::InternalServerError).to_value());
+            }
+        };
+
+    // Signed refinements declare the hash of the correction image, like signed uploads.
+    if let Some(declared) = request.headers.value("X-Content-SHA256") {
+        if declared.as_str().trim().to_ascii_lowercase() != content_sha256 {
+            return JsonResponse::unauthorized().body(
+                ApiEnvelope::failed(ErrorCode::InvalidSignature)
+                    .message("Uploaded image does not match X-Content-SHA256.")
+                    .to_value(),
+            );
+        }
+    }
+    let correction_save_path = match path_utils::generate_save_path(
+        instance.tenant.as_deref(),
+        path_utils::ForImage::RevisionImage(&instance.key, &revision_key, "correction", &filename),
+    )
+    .await
+    {
+        Ok(path) => path,
+        Err(error) => {
+            eprintln!("Failed to generate correction save path. Error: {}", error);
+            return JsonResponse::internal_server_error()
+                .body(ApiEnvelope::failed(ErrorCode::InternalServerError).to_value());
+        }
+    };
+
+    if let Err(error) =
+        temp_utils::move_file(&correction_image.temp_path, &correction_save_path).await
+    {
+        eprintln!("Failed to save correction image. Error: {}", error);
+        return JsonResponse::internal_server_error().body(
+            ApiEnvelope::failed(ErrorCode::FileMoveFailed)
+                .message("Failed to store the uploaded image.")
+                .field("reason", error.reason())
+                .to_value(),
+        );
+    }
+
+    let media_root = match env::var("MEDIA_ROOT") {
+        Ok(path) => PathBuf::from(path),
+        Err(error) => {
+            eprintln!(
+                "The MEDIA_ROOT environment variable is missing. Error: {}",
+                error
+            );
+            return JsonResponse::internal_server_error()
+                .body(ApiEnvelope::failed(ErrorCode::InternalServerError).to_value());
+        }
+    };
+
+    let relative_correction_path =
+        path_utils::relative_media_url_from_full_path(&media_root, &correction_save_path);
+
+    let revision = match TaskRevision::insert_next(
+        db_wrapper.clone(),
+        &revision_key,
+        &instance.key,
+        "refinement",
+        Some(&relative_correction_path.to_string_lossy()),
+    )
+    .await
+    {
+        Ok(revision) => revision,
+        Err(error) => {
+            eprintln!("Failed to insert task revision. Error: {}", error);
+            return JsonResponse::internal_server_error()
+                .body(ApiEnvelope::failed(ErrorCode::InternalServerError).to_value());
+        }
+    };
+
+    if let Err(error) = task::send_refinement_with_retry(shared_context, &instance, &revision).await
+    {
+        eprintln!("Failed to send refinement to bp server. Error: {}", error);
+        return JsonResponse::internal_server_error().body(
+            ApiEnvelope::failed(ErrorCode::BpSendFailed)
+                .message("Failed to send correction for processing.")
+                .to_value(),
+        );
+    }
+
+    let serialized = match serde_json::to_value(&revision) {
+        Ok(serialized) => serialized,
+        Err(error) => {
+            log::error!("{}", error);
             return JsonResponse::internal_server_error().empty();
         }
     };
 
-    // Hard coded base url
-    let base_url = "https://apistaging.erasebg.org/v1/remove-tasks/";
-    let next_url = format!("{}?page=", page_num + 1);
-    let previous_url;
+    JsonResponse::ok().body(
+        ApiEnvelope::success("refinement_queued")
+            .data(serialized)
+            .to_value(),
+    )
+}
 
-    if page_num == 0 {
-        previous_url = Some(format!("{}?page={}", base_url, page_num - 1));
-    } else {
-        previous_url = None;
+///
+/// Lists revisions of a task, oldest first. Revision 0, the task's own outputs, is available from
+/// the task details endpoint.
+///
+pub async fn task_revisions_view(request: Request) -> Response {
+    let context = request.context::<SharedContext>().unwrap();
+    let instance = match fetch_owned_task(&request, context).await {
+        Ok(instance) => instance,
+        Err(response) => return response,
+    };
+
+    let revisions =
+        match TaskRevision::fetch_by_task(context.db_wrapper.clone(), &instance.key).await {
+            Ok(revisions) => revisions,
+            Err(error) => {
+                log::error!("Failed to fetch task revisions. Error: {}", error);
+                return JsonResponse::internal_server_error().empty();
+            }
+        };
+
+    match serde_json::to_value(&revisions) {
+        Ok(serialized) => JsonResponse::ok().body(
+            ApiEnvelope::success("task_revisions")
+                .data(serialized)
+                .to_value(),
+        ),
+        Err(error) => {
+            log::error!("{}", error);
+            JsonResponse::internal_server_error().empty()
+        }
     }
+}
 
-    JsonResponse::ok().body(json!({
-        "count": total,
-        "next": next_url,
-        "previous": previous_url,
-        "results": values
-    }))
+///
+/// Returns a single revision of a task by its number, e.g. to undo to an older result.
+///
+pub async fn task_revision_details_view(request: Request) -> Response {
+    let context = request.context::<SharedContext>().unwrap();
+    let instance = match fetch_owned_task(&request, context).await {
+        Ok(instance) => instance,
+        Err(response) => return response,
+    };
+
+    let revision_number = match request.path_params.value("revision").unwrap().parse::<i32>() {
+        Ok(revision_number) => revision_number,
+        Err(_) => {
+            return JsonResponse::bad_request().body(legacy_error(
+                ErrorCode::BadRequest,
+                "Not a valid revision number.",
+            ));
+        }
+    };
+
+    let revision = match TaskRevision::fetch_by_number(
+        context.db_wrapper.clone(),
+        &instance.key,
+        revision_number,
+    )
+    .await
+    {
+        Ok(revision) => revision,
+        Err(error) => {
+            log::error!("{}", error);
+
+            return JsonResponse::not_found()
+                .body(legacy_error(ErrorCode::NotFound, "Invalid revision."));
+        }
+    };
+
+    match serde_json::to_value(&revision) {
+        Ok(serialized) => shortcuts::negotiated_ok(&request, serialized),
+        Err(error) => {
+            log::error!("{}", error);
+            JsonResponse::internal_server_error().empty()
+        }
+    }
+}
+
+///
+/// Returns content addressed filename of an uploaded temp file and its format detected from the
+/// content. The filename uses the detected format as extension, falling back to `extension`.
+/// Hashing runs off the async runtime since uploads may be up to 60 MB.
+///
+async fn hash_uploaded_file<P: AsRef<std::path::Path>>(
+    temp_path: P,
+    extension: String,
+) -> std::io::Result<(String, Option<&'static str>, String)> {
+    let data = tokio::fs::read(temp_path).await?;
+    tokio::task::spawn_blocking(move || {
+        let format = image_utils::detect_format(&data);
+        let extension = format.unwrap_or(&extension);
+        (
+            path_utils::content_filename(&data, extension),
+            format,
+            signature_utils::sha256_hex(&data),
+        )
+    })
+    .await
+    .map_err(std::io::Error::other)
+}
+
+async fn file_sha256<P: AsRef<std::path::Path>>(path: P) -> std::io::Result<String> {
+    let data = tokio::fs::read(path).await?;
+    tokio::task::spawn_blocking(move || signature_utils::sha256_hex(&data))
+        .await
+        .map_err(std::io::Error::other)
+}
+
+fn checksum_mismatch() -> Response {
+    JsonResponse::bad_request().body(
+        ApiEnvelope::failed(ErrorCode::ChecksumMismatch)
+            .message("The uploaded image does not match sha256. Upload it again.")
+            .to_value(),
+    )
+}
+
+///
+/// Failed envelope of the task lookups which used to answer `{"error": "<message>"}` only. The
+/// `error` field is kept for existing clients.
+///
+fn legacy_error(status_code: ErrorCode, message: &str) -> Value {
+    ApiEnvelope::failed(status_code)
+        .message(message)
+        .field("error", message)
+        .to_value()
 }
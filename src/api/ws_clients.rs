@@ -1,67 +1,668 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use arc_swap::ArcSwap;
+use chrono::Utc;
 
 use racoon::core::websocket::WebSocket;
 
+use serde::Serialize;
+use serde_json::Value;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{self, Sender};
 use tokio::sync::Mutex;
 use uuid::Uuid;
 
+use crate::api::task_events::TaskEventStore;
+use crate::api::ws_messages::ServerMessage;
+use crate::config::{self, Overrides};
+use crate::utils::billing_utils::TenantAccess;
+use crate::utils::encoding_utils::Encoding;
+
+/// Maximum number of messages waiting to be written to a single websocket. A client falling
+/// further behind is treated as a slow consumer and dropped.
+const OUTBOUND_QUEUE_SIZE: usize = 64;
+
+/// Maximum time a single frame may take to be written before the client is dropped.
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+
+///
+/// Optional features a websocket client opted into with the `hello` message.
+///
+#[derive(Default)]
+pub struct ClientCapabilities {
+    /// Client accepts the preview image as binary frame after the result message.
+    binary_preview: AtomicBool,
+    /// Messages are sent to the client as MessagePack binary frames instead of JSON text.
+    msgpack: AtomicBool,
+}
+
+///
+/// Serialized JSON text frame. Serialized once per event and shared by every receiving
+/// connection and the task group history.
+///
+pub type Payload = Arc<str>;
+
+pub fn payload(json: &Value) -> Payload {
+    Arc::from(json.to_string())
+}
+
+///
+/// `payload` re-encoded as a MessagePack binary frame, for connections with the `msgpack`
+/// capability.
+///
+fn msgpack_frame(payload: &Payload) -> Result<Arc<[u8]>, String> {
+    serde_json::from_str::<Value>(payload)
+        .map_err(|error| error.to_string())
+        .and_then(|json| Encoding::MessagePack.encode(&json))
+        .map(Arc::from)
+}
+
+///
+/// Frame waiting in the outbound queue of a connection.
+///
+enum Outbound {
+    Text(Payload),
+    Binary(Arc<[u8]>),
+}
+
+///
+/// Websocket connection registered in `WsClients` along with its per-connection state.
+///
+/// All frames are written by a dedicated writer task through a bounded queue, so a slow client
+/// never blocks broadcasts to other clients. Clones share the same state and queue.
+///
+#[derive(Clone)]
+pub struct WsConnection {
+    pub websocket: WebSocket,
+    /// Client IP used for per IP connection limits.
+    pub ip: Option<String>,
+    /// Tenants whose tasks the client may process, from the credentials of the upgrade request.
+    pub tenant_access: TenantAccess,
+    capabilities: Arc<ClientCapabilities>,
+    sender: Sender<Outbound>,
+    closed: Arc<AtomicBool>,
+    /// Unix timestamp in milliseconds of the last message received from the client.
+    last_seen: Arc<AtomicI64>,
+}
+
+impl WsConnection {
+    ///
+    /// Wraps websocket and spawns its writer task. The writer stops when the connection is
+    /// closed or every clone of the connection is dropped.
+    ///
+    pub fn new(websocket: WebSocket, ip: Option<String>) -> Self {
+        let (sender, mut receiver) = mpsc::channel::<Outbound>(OUTBOUND_QUEUE_SIZE);
+        let closed = Arc::new(AtomicBool::new(false));
+
+        let writer_websocket = websocket.clone();
+        let writer_closed = closed.clone();
+        tokio::spawn(async move {
+            while let Some(outbound) = receiver.recv().await {
+                if writer_closed.load(Ordering::Relaxed) {
+                    break;
+                }
+
+                let result = tokio::time::timeout(SEND_TIMEOUT, async {
+                    match outbound {
+                        Outbound::Text(text) => writer_websocket.send_text(&text).await,
+                        Outbound::Binary(bytes) => writer_websocket.send_bytes(&bytes).await,
+                    }
+                })
+                .await;
+
+                match result {
+                    Ok(Ok(_)) => {}
+                    Ok(Err(error)) => {
+                        eprintln!("Failed to write to websocket. Error: {}", error);
+                        writer_closed.store(true, Ordering::Relaxed);
+                        break;
+                    }
+                    Err(_) => {
+                        eprintln!("Websocket write timed out. Dropping slow consumer.");
+                        writer_closed.store(true, Ordering::Relaxed);
+                        break;
+                    }
+                }
+            }
+        });
+
+        Self {
+            websocket,
+            ip,
+            tenant_access: TenantAccess::Anonymous,
+            capabilities: Arc::new(ClientCapabilities::default()),
+            sender,
+            closed,
+            last_seen: Arc::new(AtomicI64::new(Utc::now().timestamp_millis())),
+        }
+    }
+
+    ///
+    /// Records activity from the client. Called for every received message.
+    ///
+    pub fn touch(&self) {
+        self.last_seen
+            .store(Utc::now().timestamp_millis(), Ordering::Relaxed);
+    }
+
+    ///
+    /// Returns true if nothing was received from the client within `stale_after`.
+    ///
+    pub fn is_stale(&self, stale_after: Duration) -> bool {
+        let idle_millis = Utc::now().timestamp_millis() - self.last_seen.load(Ordering::Relaxed);
+        idle_millis > stale_after.as_millis() as i64
+    }
+
+    pub fn binary_preview(&self) -> bool {
+        self.capabilities.binary_preview.load(Ordering::Relaxed)
+    }
+
+    pub fn set_binary_preview(&self, enabled: bool) {
+        self.capabilities
+            .binary_preview
+            .store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn msgpack(&self) -> bool {
+        self.capabilities.msgpack.load(Ordering::Relaxed)
+    }
+
+    pub fn set_msgpack(&self, enabled: bool) {
+        self.capabilities.msgpack.store(enabled, Ordering::Relaxed);
+    }
+
+    ///
+    /// Stops delivering messages to this client.
+    ///
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+    }
+
+    ///
+    /// Returns true if the connection was dropped for being slow, stale or broken.
+    ///
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
+    }
+
+    ///
+    /// Queues message for this client. Returns false if the message was not queued because the
+    /// connection is closed or its queue is full.
+    ///
+    pub fn send(&self, message: &ServerMessage) -> bool {
+        if self.msgpack() {
+            return self.send_encoded(Encoding::MessagePack.encode(message).map(Arc::from));
+        }
+        self.send_payload(payload(&message.to_json()))
+    }
+
+    ///
+    /// Queues JSON payload shared between connections. MessagePack clients get it re-encoded,
+    /// which is only paid for connections that opted in. Broadcasts encode it once for all of
+    /// them instead, see `WsClients::send_to_group`.
+    ///
+    pub fn send_payload(&self, payload: Payload) -> bool {
+        if self.msgpack() {
+            return self.send_encoded(msgpack_frame(&payload));
+        }
+        self.enqueue(Outbound::Text(payload))
+    }
+
+    fn send_encoded(&self, encoded: Result<Arc<[u8]>, String>) -> bool {
+        match encoded {
+            Ok(bytes) => self.enqueue(Outbound::Binary(bytes)),
+            Err(error) => {
+                eprintln!("Failed to encode websocket message. Error: {}", error);
+                false
+            }
+        }
+    }
+
+    pub fn send_bytes(&self, bytes: Vec<u8>) -> bool {
+        self.enqueue(Outbound::Binary(Arc::from(bytes)))
+    }
+
+    fn enqueue(&self, outbound: Outbound) -> bool {
+        if self.is_closed() {
+            return false;
+        }
+
+        // Dropped as if lost on the network, so the connection stays open.
+        #[cfg(feature = "chaos")]
+        if crate::utils::chaos_utils::drop_ws_message() {
+            return true;
+        }
+
+        match self.sender.try_send(outbound) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                eprintln!(
+                    "Outbound queue of websocket {:?} is full. Dropping slow consumer.",
+                    self.websocket.uid
+                );
+                self.closed.store(true, Ordering::Relaxed);
+                false
+            }
+            Err(TrySendError::Closed(_)) => {
+                self.closed.store(true, Ordering::Relaxed);
+                false
+            }
+        }
+    }
+}
+
+///
+/// Limits and heartbeat settings of `WsClients`.
+///
+#[derive(Debug, Clone, Copy)]
+pub struct WsClientsConfig {
+    /// Maximum number of connections accepted per task group.
+    pub max_per_task_group: usize,
+    /// Maximum number of connections accepted per client IP.
+    pub max_per_ip: usize,
+    /// Connections without any message (including `ping`) for this long are stale.
+    pub stale_after: Duration,
+    /// Whether stale connections are dropped from the registry.
+    pub drop_stale: bool,
+    /// Number of recent events kept per task group and replayed to new connections. `0` disables
+    /// the history.
+    pub history_size: usize,
+    /// History of a task group is dropped after this long without events or connections.
+    pub history_ttl: Duration,
+}
+
+impl WsClientsConfig {
+    ///
+    /// Reads `WS_MAX_CONNECTIONS_PER_TASK_GROUP`, `WS_MAX_CONNECTIONS_PER_IP`,
+    /// `WS_STALE_AFTER_SECS`, `WS_DROP_STALE_CONNECTIONS`, `WS_HISTORY_SIZE` and
+    /// `WS_HISTORY_TTL_SECS`, defaulting to 5, 50, 60, false, 10 and 300.
+    ///
+    pub fn from_env() -> Self {
+        Self::load(&Overrides::new())
+    }
+
+    ///
+    /// Same as `from_env` but values in `overrides` take precedence.
+    ///
+    pub fn load(overrides: &Overrides) -> Self {
+        let read = |name: &str, default: u64| match config::setting(overrides, name) {
+            Some(value) => value.parse::<u64>().unwrap_or(default),
+            None => default,
+        };
+
+        let drop_stale = match config::setting(overrides, "WS_DROP_STALE_CONNECTIONS") {
+            Some(value) => value.to_lowercase() == "true",
+            None => false,
+        };
+
+        Self {
+            max_per_task_group: read("WS_MAX_CONNECTIONS_PER_TASK_GROUP", 5) as usize,
+            max_per_ip: read("WS_MAX_CONNECTIONS_PER_IP", 50) as usize,
+            stale_after: Duration::from_secs(read("WS_STALE_AFTER_SECS", 60).max(1)),
+            drop_stale,
+            history_size: read("WS_HISTORY_SIZE", 10) as usize,
+            history_ttl: Duration::from_secs(read("WS_HISTORY_TTL_SECS", 300)),
+        }
+    }
+}
+
+///
+/// Connection counts for metrics and the admin debug endpoint.
+///
+#[derive(Debug, Serialize)]
+pub struct ConnectionStats {
+    pub task_groups: usize,
+    pub active: usize,
+    pub stale: usize,
+    pub unique_ips: usize,
+}
+
+///
+/// Reason a connection was not registered.
+///
+#[derive(Debug)]
+pub enum ConnectionLimitError {
+    TaskGroup,
+    Ip,
+}
+
+///
+/// Recent events of a task group, so a client connecting right after completion still receives
+/// the result.
+///
+struct GroupHistory {
+    events: VecDeque<Payload>,
+    /// Events recorded since the history was created, including those dropped from `events`.
+    recorded: u64,
+    last_activity: Instant,
+}
+
+#[derive(Default)]
+struct Registry {
+    groups: HashMap<String, Arc<Vec<WsConnection>>>,
+    connections_per_ip: HashMap<String, usize>,
+    history: HashMap<String, GroupHistory>,
+}
+
+///
+/// Registry of websocket connections grouped by task group.
+///
+/// Each group holds an immutable list which is replaced on every change, so broadcasters take a
+/// cheap snapshot and never hold the registry lock while sending.
+///
 pub struct WsClients {
-    inner: Arc<Mutex<HashMap<String, Vec<WebSocket>>>>,
+    inner: Arc<Mutex<Registry>>,
+    /// Swapped when settings are reloaded.
+    config: ArcSwap<WsClientsConfig>,
+    /// History shared with other replicas. Local history is only used when this is missing or
+    /// unavailable.
+    event_store: Option<TaskEventStore>,
 }
 
 impl WsClients {
-    pub fn new() -> Self {
+    pub fn new(config: WsClientsConfig, event_store: Option<TaskEventStore>) -> Self {
         Self {
-            inner: Arc::new(Mutex::new(HashMap::new())),
+            inner: Arc::new(Mutex::new(Registry::default())),
+            config: ArcSwap::from_pointee(config),
+            event_store,
         }
     }
 
-    pub async fn add(&self, task_group: &Uuid, websocket: WebSocket) {
-        let task_group = task_group.to_string();
+    pub fn config(&self) -> WsClientsConfig {
+        **self.config.load()
+    }
+
+    ///
+    /// Applies new limits. Existing connections above a lowered limit are kept.
+    ///
+    pub fn set_config(&self, config: WsClientsConfig) {
+        self.config.store(Arc::new(config));
+    }
+
+    ///
+    /// Registers connection unless the task group or the client IP already reached its
+    /// connection limit. Recent events of the task group are replayed to the new connection,
+    /// read through the shared event store when available. An event broadcast while the
+    /// connection is added may be received twice, but is never missed.
+    ///
+    pub async fn add(
+        &self,
+        task_group_key: &Uuid,
+        connection: WsConnection,
+    ) -> Result<(), ConnectionLimitError> {
+        let task_group = task_group_key.to_string();
+
+        let recorded_before = match self.inner.lock().await.history.get(&task_group) {
+            Some(history) => history.recorded,
+            None => 0,
+        };
+
+        // Read without holding the lock, so a slow Redis doesn't hold up other connections and
+        // broadcasts. Events recorded meanwhile are replayed from the local history below.
+        let shared_events = match &self.event_store {
+            Some(event_store) if self.config().history_size > 0 => {
+                event_store.fetch(task_group_key).await
+            }
+            _ => None,
+        };
+
+        let mut inner_lock = self.inner.lock().await;
+        let registry = &mut *inner_lock;
+
+        let mut connections = match registry.groups.get(&task_group) {
+            Some(connections) => connections.as_ref().clone(),
+            None => vec![],
+        };
+
+        if connections.len() >= self.config().max_per_task_group {
+            return Err(ConnectionLimitError::TaskGroup);
+        }
+
+        if let Some(ip) = &connection.ip {
+            let count = registry.connections_per_ip.entry(ip.clone()).or_insert(0);
+            if *count >= self.config().max_per_ip {
+                return Err(ConnectionLimitError::Ip);
+            }
+            *count += 1;
+        }
+
+        // Broadcasts record events before sending them to the registered connections, so every
+        // event not recorded yet is sent to this connection once it is registered below.
+        let mut replay = vec![];
+        if let Some(history) = registry.history.get_mut(&task_group) {
+            history.last_activity = Instant::now();
+            let missed = match &shared_events {
+                Some(_) => history.recorded.saturating_sub(recorded_before) as usize,
+                None => history.events.len(),
+            };
+            let skipped = history.events.len().saturating_sub(missed);
+            replay.extend(history.events.iter().skip(skipped).cloned());
+        }
+
+        for event in shared_events.into_iter().flatten().chain(replay) {
+            connection.send_payload(event);
+        }
+
+        connections.push(connection);
+        registry.groups.insert(task_group, Arc::new(connections));
+        Ok(())
+    }
+
+    ///
+    /// Stores event in the task group history and the shared event store without sending it.
+    /// Expired histories of other task groups are dropped.
+    ///
+    pub async fn record(&self, task_group: &Uuid, event: &Payload) {
+        let config = self.config();
+        if config.history_size == 0 {
+            return;
+        }
+
+        if let Some(event_store) = &self.event_store {
+            event_store
+                .push(task_group, event, config.history_size, config.history_ttl)
+                .await;
+        }
 
         let mut inner_lock = self.inner.lock().await;
-        if let Some(websockets) = inner_lock.get_mut(&task_group) {
-            websockets.push(websocket);
-        } else {
-            let websockets = vec![websocket];
-            inner_lock.insert(task_group, websockets);
+        let history_ttl = self.config().history_ttl;
+        inner_lock
+            .history
+            .retain(|_, history| history.last_activity.elapsed() < history_ttl);
+
+        let history = inner_lock
+            .history
+            .entry(task_group.to_string())
+            .or_insert_with(|| GroupHistory {
+                events: VecDeque::new(),
+                recorded: 0,
+                last_activity: Instant::now(),
+            });
+
+        if history.events.len() >= self.config().history_size {
+            history.events.pop_front();
         }
+        history.events.push_back(event.clone());
+        history.recorded += 1;
+        history.last_activity = Instant::now();
     }
 
-    pub async fn get_all(&self, task_group: &Uuid) -> Vec<WebSocket> {
+    ///
+    /// Drops the history of the task group here and in the shared event store, e.g. once its
+    /// tasks were erased.
+    ///
+    pub async fn forget(&self, task_group: &Uuid) {
+        if let Some(event_store) = &self.event_store {
+            event_store.delete(task_group).await;
+        }
+
+        let mut inner_lock = self.inner.lock().await;
+        inner_lock.history.remove(&task_group.to_string());
+    }
+
+    ///
+    /// Returns snapshot of connections of the task group.
+    ///
+    pub async fn get_all(&self, task_group: &Uuid) -> Arc<Vec<WsConnection>> {
         let task_group = task_group.to_string();
 
         let inner_lock = self.inner.lock().await;
-        if let Some(websocket) = inner_lock.get(&task_group) {
-            return websocket.to_owned();
+        match inner_lock.groups.get(&task_group) {
+            Some(connections) => connections.clone(),
+            None => Arc::new(vec![]),
+        }
+    }
+
+    ///
+    /// Queues message for every connection of the task group and stores it in the task group
+    /// history. Connections dropped as slow consumers are removed from the registry.
+    ///
+    pub async fn broadcast(&self, task_group: &Uuid, message: &ServerMessage) {
+        let payload = payload(&message.to_json());
+        self.record(task_group, &payload).await;
+        self.send_to_group(task_group, payload).await;
+    }
+
+    ///
+    /// Sends `message` to every connection of every task group. Service wide notices are not
+    /// stored in task group histories.
+    ///
+    pub async fn broadcast_all(&self, message: &ServerMessage) {
+        let payload = payload(&message.to_json());
+        for task_group in self.task_groups().await {
+            self.send_to_group(&task_group, payload.clone()).await;
         }
+    }
+
+    async fn send_to_group(&self, task_group: &Uuid, payload: Payload) {
+        let connections = self.get_all(task_group).await;
+
+        // Encoded on the first MessagePack connection and shared by the following ones.
+        let mut msgpack = None;
+        let mut has_closed = false;
+        for connection in connections.iter() {
+            let sent = if connection.msgpack() {
+                let frame = msgpack.get_or_insert_with(|| msgpack_frame(&payload));
+                connection.send_encoded(frame.clone())
+            } else {
+                connection.send_payload(payload.clone())
+            };
+            if !sent {
+                has_closed = true;
+            }
+        }
+
+        if has_closed {
+            self.remove_closed(task_group).await;
+        }
+    }
+
+    pub async fn remove(&self, task_group: &Uuid, websocket: &WebSocket) {
+        // Multiple unique websockets are allowed to connect to the same task group.
+        // Each websocket connection has unique uid string.
+        // If the websocket is cloned, the cloned websocket instance will also have the same
+        // unique uid.
+        self.retain(task_group, |connection| {
+            connection.websocket.uid != websocket.uid
+        })
+        .await;
+    }
+
+    ///
+    /// Returns counts of registered connections. A connection is stale if it has not sent any
+    /// message within the configured `stale_after`.
+    ///
+    pub async fn stats(&self) -> ConnectionStats {
+        let inner_lock = self.inner.lock().await;
+
+        let mut active = 0;
+        let mut stale = 0;
+        for connections in inner_lock.groups.values() {
+            for connection in connections.iter() {
+                if connection.is_stale(self.config().stale_after) {
+                    stale += 1;
+                } else {
+                    active += 1;
+                }
+            }
+        }
+
+        ConnectionStats {
+            task_groups: inner_lock.groups.len(),
+            active,
+            stale,
+            unique_ips: inner_lock.connections_per_ip.len(),
+        }
+    }
+
+    ///
+    /// Closes and removes every stale connection. Returns number of removed connections.
+    ///
+    pub async fn remove_stale(&self) -> usize {
+        let mut removed = 0;
+        for task_group in self.task_groups().await {
+            for connection in self.get_all(&task_group).await.iter() {
+                if connection.is_stale(self.config().stale_after) {
+                    connection.close();
+                    removed += 1;
+                }
+            }
+
+            self.remove_closed(&task_group).await;
+        }
+
+        removed
+    }
+
+    async fn task_groups(&self) -> Vec<Uuid> {
+        let inner_lock = self.inner.lock().await;
+        inner_lock
+            .groups
+            .keys()
+            .filter_map(|task_group| Uuid::parse_str(task_group).ok())
+            .collect()
+    }
 
-        vec![]
+    async fn remove_closed(&self, task_group: &Uuid) {
+        self.retain(task_group, |connection| !connection.is_closed())
+            .await;
     }
 
-    pub async fn remove(&self, task_group: &Uuid, websocket: WebSocket) {
+    async fn retain<F>(&self, task_group: &Uuid, keep: F)
+    where
+        F: Fn(&WsConnection) -> bool,
+    {
         let task_group = task_group.to_string();
 
         let mut inner_lock = self.inner.lock().await;
+        let registry = &mut *inner_lock;
 
-        if let Some(websockets) = inner_lock.get_mut(&task_group) {
-            // Multiple unique websockets are allowed to connect to the same task group.
-            // Each websocket connection has unique uid string.
-            // If the websocket is cloned, the cloned websocket instance will also have the same
-            // unique uid.
+        if let Some(connections) = registry.groups.get(&task_group) {
+            let (remaining, removed): (Vec<WsConnection>, Vec<WsConnection>) = connections
+                .iter()
+                .cloned()
+                .partition(|connection| keep(connection));
 
-            for i in (0..websockets.len()).rev() {
-                let current_websocket = &websockets[i];
-                if websocket.uid == current_websocket.uid {
-                    websockets.remove(i);
+            for connection in &removed {
+                if let Some(ip) = &connection.ip {
+                    if let Some(count) = registry.connections_per_ip.get_mut(ip) {
+                        *count = count.saturating_sub(1);
+                        if *count == 0 {
+                            registry.connections_per_ip.remove(ip);
+                        }
+                    }
                 }
             }
 
             // If there are no websocket connections stored in this task group,
             // removes the task group saved bucket from HashMap.
-            if websockets.len() == 0 {
-                inner_lock.remove(&task_group);
+            if remaining.is_empty() {
+                registry.groups.remove(&task_group);
+            } else {
+                registry.groups.insert(task_group, Arc::new(remaining));
             }
         }
     }
@@ -0,0 +1,345 @@
+use racoon::core::websocket::WebSocket;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use uuid::Uuid;
+
+use crate::api::error_codes::ErrorCode;
+use crate::utils::envelope_utils;
+
+///
+/// Version of the websocket message schema. Sent with every server message and bumped on
+/// breaking changes so clients can detect them.
+///
+pub const SCHEMA_VERSION: u32 = 1;
+
+///
+/// Messages accepted from websocket clients. The message type is selected by the `action` field.
+///
+/// For backward compatibility, a message without `action` but with `key` is treated as
+/// `process_image`.
+///
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum ClientMessage {
+    /// Requests processing of the uploaded image with `key`, or the existing result if it is
+    /// already processed.
+    ProcessImage { key: Uuid },
+    /// Opts into optional features, normally sent right after connecting. Known capabilities:
+    /// - `binary_preview`: after a result message, the preview image is sent as a
+    ///   `preview_binary` header message followed by a binary frame with the PNG bytes.
+    /// - `msgpack`: every following server message is sent as a MessagePack binary frame with
+    ///   the same fields instead of JSON text. The `capabilities` reply is still JSON.
+    Hello { capabilities: Vec<String> },
+    /// Heartbeat. Clients should send it at least every `WS_STALE_AFTER_SECS` seconds; the server
+    /// replies with `pong`.
+    Ping,
+}
+
+impl ClientMessage {
+    /// Known values of the `action` field.
+    pub const ACTIONS: &'static [&'static str] = &["process_image", "hello", "ping"];
+
+    /// Capabilities accepted in the `hello` message.
+    pub const CAPABILITIES: &'static [&'static str] = &["binary_preview", "msgpack"];
+
+    ///
+    /// Parses text frame received from a client. Returns the error message to send back on
+    /// failure.
+    ///
+    pub fn parse(text: &str) -> Result<ClientMessage, ServerMessage> {
+        let mut json: Value = match serde_json::from_str(text) {
+            Ok(value) => value,
+            Err(error) => {
+                eprintln!("Failed to parse text to JSON. Error: {}", error);
+                return Err(ServerMessage::failed(
+                    ErrorCode::InvalidMessageFormat,
+                    "Not a valid message format. Expected type JSON.",
+                ));
+            }
+        };
+
+        let object = match json.as_object_mut() {
+            Some(object) => object,
+            None => {
+                return Err(ServerMessage::failed(
+                    ErrorCode::InvalidMessageFormat,
+                    "Not a valid message format. Expected JSON object.",
+                ));
+            }
+        };
+
+        if !object.contains_key("action") && object.contains_key("key") {
+            object.insert("action".to_string(), Value::from("process_image"));
+        }
+
+        let action = match object.get("action").and_then(|action| action.as_str()) {
+            Some(action) => action.to_string(),
+            None => {
+                return Err(ServerMessage::failed(
+                    ErrorCode::InvalidMessageFormat,
+                    "Missing message action.",
+                ));
+            }
+        };
+
+        match serde_json::from_value::<ClientMessage>(json) {
+            Ok(message) => Ok(message),
+            Err(error) => {
+                eprintln!("Failed to parse client message. Error: {}", error);
+
+                if ClientMessage::ACTIONS.contains(&action.as_str()) {
+                    Err(ServerMessage::failed(
+                        ErrorCode::InvalidMessageFormat,
+                        "Invalid message fields.",
+                    ))
+                } else {
+                    Err(ServerMessage::failed(
+                        ErrorCode::UnknownMessageType,
+                        &format!("Unknown action: {}", action),
+                    ))
+                }
+            }
+        }
+    }
+}
+
+///
+/// Messages sent to websocket clients. Every variant carries `schema_version`, `status` and
+/// `status_code`; `status` and `status_code` mean the same as in `ApiEnvelope` of HTTP
+/// responses. The variants stay typed structs for the JSON Schema of the messages.
+///
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+#[serde(untagged)]
+pub enum ServerMessage {
+    Result(ResultMessage),
+    Failed(FailedMessage),
+    Status(StatusMessage),
+    Capabilities(CapabilitiesMessage),
+    PreviewBinary(PreviewBinaryMessage),
+}
+
+///
+/// Processing result. `data` is the serialized task, the serialized revision for
+/// `revision_result`, or the progress for `store_sync_progress`.
+///
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct ResultMessage {
+    pub schema_version: u32,
+    /// Always `success`.
+    pub status: String,
+    /// `result`, `revision_result`, `preview_ready` or `store_sync_progress`.
+    pub status_code: String,
+    pub data: Value,
+}
+
+///
+/// Error response.
+///
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct FailedMessage {
+    pub schema_version: u32,
+    /// Always `failed`.
+    pub status: String,
+    pub status_code: String,
+    pub message: String,
+}
+
+///
+/// Intermediate or final status reported by the BP server for a task, or a service notice such as
+/// `service_degraded` and `service_restored`.
+///
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct StatusMessage {
+    pub schema_version: u32,
+    pub status: String,
+    pub status_code: String,
+    pub message: Option<String>,
+    /// Estimated seconds until the result, sent with `pending` and `queued` statuses.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub estimated_seconds: Option<u64>,
+}
+
+///
+/// Reply to `hello` listing the capabilities enabled for this connection.
+///
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct CapabilitiesMessage {
+    pub schema_version: u32,
+    /// Always `success`.
+    pub status: String,
+    /// Always `capabilities`.
+    pub status_code: String,
+    pub capabilities: Vec<String>,
+}
+
+///
+/// Header of the binary frame sent right after it. Only sent to clients with the
+/// `binary_preview` capability.
+///
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct PreviewBinaryMessage {
+    pub schema_version: u32,
+    /// Always `success`.
+    pub status: String,
+    /// Always `preview_binary`.
+    pub status_code: String,
+    pub key: Uuid,
+    pub content_type: String,
+    /// Size of the following binary frame in bytes.
+    pub size: usize,
+}
+
+impl ServerMessage {
+    pub fn result(data: Value) -> Self {
+        ServerMessage::Result(ResultMessage {
+            schema_version: SCHEMA_VERSION,
+            status: envelope_utils::SUCCESS.to_string(),
+            status_code: "result".to_string(),
+            data,
+        })
+    }
+
+    ///
+    /// Refined outputs of a task revision requested with brush-stroke corrections.
+    ///
+    pub fn revision_result(data: Value) -> Self {
+        ServerMessage::Result(ResultMessage {
+            schema_version: SCHEMA_VERSION,
+            status: envelope_utils::SUCCESS.to_string(),
+            status_code: "revision_result".to_string(),
+            data,
+        })
+    }
+
+    ///
+    /// Sent once a downscaled preview was generated in the background. `data` is the task with
+    /// the new preview urls.
+    ///
+    pub fn preview_ready(data: Value) -> Self {
+        ServerMessage::Result(ResultMessage {
+            schema_version: SCHEMA_VERSION,
+            status: envelope_utils::SUCCESS.to_string(),
+            status_code: "preview_ready".to_string(),
+            data,
+        })
+    }
+
+    ///
+    /// Progress of a store sync, sent to the task group of the sync. See `store_sync`.
+    ///
+    pub fn store_sync_progress(data: Value) -> Self {
+        ServerMessage::Result(ResultMessage {
+            schema_version: SCHEMA_VERSION,
+            status: envelope_utils::SUCCESS.to_string(),
+            status_code: "store_sync_progress".to_string(),
+            data,
+        })
+    }
+
+    pub fn failed(status_code: ErrorCode, message: &str) -> Self {
+        ServerMessage::Failed(FailedMessage {
+            schema_version: SCHEMA_VERSION,
+            status: envelope_utils::FAILED.to_string(),
+            status_code: status_code.to_string(),
+            message: message.to_string(),
+        })
+    }
+
+    pub fn status(status: &str, status_code: &str, message: Option<String>) -> Self {
+        ServerMessage::Status(StatusMessage {
+            schema_version: SCHEMA_VERSION,
+            status: status.to_string(),
+            status_code: status_code.to_string(),
+            message,
+            estimated_seconds: None,
+        })
+    }
+
+    ///
+    /// Sent after the task was handed to the BP server.
+    ///
+    pub fn queued(estimated_seconds: u64) -> Self {
+        Self::status("pending", "queued", None).with_estimated_seconds(estimated_seconds)
+    }
+
+    ///
+    /// Attaches the estimated wait to status messages. Other messages are returned unchanged.
+    ///
+    pub fn with_estimated_seconds(mut self, seconds: u64) -> Self {
+        if let ServerMessage::Status(status) = &mut self {
+            status.estimated_seconds = Some(seconds);
+        }
+        self
+    }
+
+    pub fn capabilities(capabilities: Vec<String>) -> Self {
+        ServerMessage::Capabilities(CapabilitiesMessage {
+            schema_version: SCHEMA_VERSION,
+            status: envelope_utils::SUCCESS.to_string(),
+            status_code: "capabilities".to_string(),
+            capabilities,
+        })
+    }
+
+    pub fn preview_binary(key: Uuid, size: usize) -> Self {
+        ServerMessage::PreviewBinary(PreviewBinaryMessage {
+            schema_version: SCHEMA_VERSION,
+            status: envelope_utils::SUCCESS.to_string(),
+            status_code: "preview_binary".to_string(),
+            key,
+            content_type: "image/png".to_string(),
+            size,
+        })
+    }
+
+    pub fn pong() -> Self {
+        Self::status(envelope_utils::SUCCESS, "pong", None)
+    }
+
+    ///
+    /// Sent to every connected client when the BP server link drops.
+    ///
+    pub fn service_degraded() -> Self {
+        Self::status(
+            envelope_utils::FAILED,
+            ErrorCode::ServiceDegraded.as_str(),
+            Some("Image processing is temporarily unavailable.".to_string()),
+        )
+    }
+
+    ///
+    /// Sent to every connected client when the BP server link is back.
+    ///
+    pub fn service_restored() -> Self {
+        Self::status(envelope_utils::SUCCESS, "service_restored", None)
+    }
+
+    pub fn internal_server_error() -> Self {
+        Self::failed(ErrorCode::InternalServerError, "Internal Server Error")
+    }
+
+    pub fn to_json(&self) -> Value {
+        // Serializing plain structs with string keys can't fail.
+        serde_json::to_value(self).unwrap_or(Value::Null)
+    }
+
+    pub async fn send(&self, websocket: &WebSocket) {
+        let _ = websocket.send_json(&self.to_json()).await;
+    }
+}
+
+///
+/// Returns JSON Schema of client and server messages for client teams.
+///
+pub fn json_schema() -> Value {
+    let client = schemars::schema_for!(ClientMessage);
+    let server = schemars::schema_for!(ServerMessage);
+
+    serde_json::json!({
+        "schema_version": SCHEMA_VERSION,
+        "client_message": client,
+        "server_message": server,
+    })
+}
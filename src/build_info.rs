@@ -0,0 +1,34 @@
+use chrono::{DateTime, Utc};
+use serde_json::{json, Value};
+
+/// Version of the crate, e.g. `0.1.0`.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Commit the binary was built from, or `unknown` when built without git. See `build.rs`.
+pub const GIT_COMMIT: &str = env!("BUILD_GIT_COMMIT");
+
+/// Seconds since the epoch when `build.rs` last ran. See `build.rs`.
+const BUILD_TIMESTAMP: &str = env!("BUILD_TIMESTAMP");
+
+///
+/// Crate version with the short commit as build metadata, e.g. `0.1.0+1a2b3c4`. Sent in the
+/// `Build-Version` header and the BP handshake.
+///
+pub fn version() -> String {
+    let commit: String = GIT_COMMIT.chars().take(7).collect();
+    format!("{}+{}", VERSION, commit)
+}
+
+pub fn build_time() -> Option<DateTime<Utc>> {
+    let timestamp = BUILD_TIMESTAMP.parse::<i64>().ok()?;
+    DateTime::from_timestamp(timestamp, 0)
+}
+
+pub fn to_json() -> Value {
+    json!({
+        "version": VERSION,
+        "git_commit": GIT_COMMIT,
+        "build_time": build_time().map(|time| time.to_rfc3339()),
+        "full_version": version(),
+    })
+}
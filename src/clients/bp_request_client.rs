@@ -1,25 +1,157 @@
-use std::env;
 use std::str::FromStr;
-use std::sync::Arc;
-use std::time::Duration;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
 
 use futures_util::lock::Mutex;
 use futures_util::Future;
 
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use serde_json::{json, Value};
 use tej_protoc::protoc::encoder::build_bytes_for_message;
 use tej_protoc::{protoc::File, stream::Stream};
 
-use tokio::net::TcpStream;
+use tokio::sync::{broadcast, watch};
 use tokio::task::JoinHandle;
 use tokio::time::sleep;
 
+use crate::build_info;
+use crate::clients::happy_eyeballs;
+use crate::secrets::{self, SecretStore};
+use crate::utils::drain_utils::{self, DrainProgress, DrainState, MAX_IN_FLIGHT};
+use crate::utils::ip_utils;
+
+/// How often drain progress is checked and held back tasks retry.
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Task messages kept for subscribers of `subscribe_messages` which fall behind.
+const MESSAGE_BUFFER_SIZE: usize = 1024;
+
+///
+/// Application level keepalive settings for the BP server connection.
+///
+#[derive(Debug, Clone, Copy)]
+pub struct Keepalive {
+    /// Interval between keepalive frames.
+    pub interval: Duration,
+    /// The connection is considered dead and reconnected when nothing is received for this long.
+    pub liveness_timeout: Duration,
+}
+
+///
+/// BP worker and model which produced a result. Echoed by the BP server in its handshake reply
+/// and responses; either part may be missing with older BP servers.
+///
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ServerIdentity {
+    pub worker_id: Option<String>,
+    pub model_version: Option<String>,
+}
+
+impl ServerIdentity {
+    ///
+    /// Reads `worker_id` and `model_version` of a BP server message. `None` if it has neither.
+    ///
+    pub fn from_message(message: &Value) -> Option<Self> {
+        let read = |name: &str| {
+            message
+                .get(name)
+                .and_then(|value| value.as_str())
+                .map(|value| value.to_string())
+        };
+
+        let identity = Self {
+            worker_id: read("worker_id"),
+            model_version: read("model_version"),
+        };
+
+        if identity == Self::default() {
+            None
+        } else {
+            Some(identity)
+        }
+    }
+
+    ///
+    /// Fills parts missing in `self` from `fallback`.
+    ///
+    pub fn or(self, fallback: ServerIdentity) -> Self {
+        Self {
+            worker_id: self.worker_id.or(fallback.worker_id),
+            model_version: self.model_version.or(fallback.model_version),
+        }
+    }
+}
+
+///
+/// Whether a task message was sent to or received from the BP server.
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MessageDirection {
+    Sent,
+    Received,
+}
+
+impl MessageDirection {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MessageDirection::Sent => "sent",
+            MessageDirection::Received => "received",
+        }
+    }
+}
+
+///
+/// JSON message of a task exchanged with the BP server, without its files. Keepalive, drain and
+/// handshake frames are left out.
+///
+#[derive(Debug, Clone)]
+pub struct ExchangedMessage {
+    pub direction: MessageDirection,
+    pub message: Value,
+}
+
+///
+/// Armed while a frame is written. Dropped armed when the write was cut short, e.g. by the send
+/// timeout of the caller, and notifies the connection to reconnect: the partial frame left on
+/// the stream would corrupt every following frame.
+///
+struct WriteGuard<'a> {
+    reconnect: &'a watch::Sender<()>,
+    armed: bool,
+}
+
+impl Drop for WriteGuard<'_> {
+    fn drop(&mut self) {
+        if self.armed {
+            eprintln!("Write to BP server was interrupted. Reconnecting.");
+            self.reconnect.send_replace(());
+        }
+    }
+}
+
 pub struct BPRequestClient {
     address: String,
     buffer_size: usize,
     reconnect_duration: Duration,
+    keepalive: Keepalive,
+    /// Longest wait for in-flight tasks of a draining BP server before reconnecting anyway.
+    drain_timeout: Duration,
     stream_holder: Arc<Mutex<Option<Arc<Stream>>>>,
+    /// True while a handshaken connection to the BP server is open.
+    connected: Arc<watch::Sender<bool>>,
+    /// Last identity announced on the current connection.
+    identity: Arc<RwLock<ServerIdentity>>,
+    /// Source of `BP_SERVER_AUTH_TOKEN`, read on every handshake.
+    secrets: Arc<SecretStore>,
+    /// Sent to drop the current connection, e.g. to handshake with a rotated token. Requests
+    /// sent while connecting are kept for the connection being established.
+    reconnect: Arc<watch::Sender<()>>,
+    drain: Arc<RwLock<DrainState>>,
+    /// Task messages sent and received, for subscribers of `subscribe_messages`.
+    messages: broadcast::Sender<ExchangedMessage>,
 }
 
 impl BPRequestClient {
@@ -27,6 +159,9 @@ impl BPRequestClient {
         address: S,
         buffer_size: usize,
         reconnect_duration: Duration,
+        keepalive: Keepalive,
+        drain_timeout: Duration,
+        secrets: Arc<SecretStore>,
     ) -> Self {
         let address = address.as_ref().to_string();
 
@@ -34,7 +169,83 @@ impl BPRequestClient {
             address,
             buffer_size,
             reconnect_duration,
+            keepalive,
+            drain_timeout,
             stream_holder: Arc::new(Mutex::new(None)),
+            connected: Arc::new(watch::Sender::new(false)),
+            identity: Arc::new(RwLock::new(ServerIdentity::default())),
+            secrets,
+            reconnect: Arc::new(watch::Sender::new(())),
+            drain: Arc::new(RwLock::new(DrainState::default())),
+            messages: broadcast::channel(MESSAGE_BUFFER_SIZE).0,
+        }
+    }
+
+    ///
+    /// Address of the BP server, as configured.
+    ///
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    ///
+    /// Drops the current connection and connects again, handshaking with the current
+    /// `BP_SERVER_AUTH_TOKEN`.
+    ///
+    pub fn reconnect(&self) {
+        self.reconnect.send_replace(());
+    }
+
+    ///
+    /// Returns identity of the connected BP server, empty until it announced one.
+    ///
+    pub fn server_identity(&self) -> ServerIdentity {
+        match self.identity.read() {
+            Ok(identity) => identity.clone(),
+            Err(_) => ServerIdentity::default(),
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        *self.connected.borrow()
+    }
+
+    ///
+    /// True while the BP server finishes in-flight tasks before shutting down. New tasks are held
+    /// back until the client reconnected.
+    ///
+    pub fn is_draining(&self) -> bool {
+        match self.drain.read() {
+            Ok(drain) => drain.draining,
+            Err(_) => false,
+        }
+    }
+
+    ///
+    /// Returns receiver notified whenever the connection to the BP server is established or lost.
+    ///
+    pub fn subscribe_connection_state(&self) -> watch::Receiver<bool> {
+        self.connected.subscribe()
+    }
+
+    ///
+    /// Returns receiver of every task message sent to or received from the BP server. Messages
+    /// are only cloned while someone is subscribed.
+    ///
+    pub fn subscribe_messages(&self) -> broadcast::Receiver<ExchangedMessage> {
+        self.messages.subscribe()
+    }
+
+    fn publish_message(
+        messages: &broadcast::Sender<ExchangedMessage>,
+        direction: MessageDirection,
+        message: &Value,
+    ) {
+        if messages.receiver_count() > 0 {
+            let _ = messages.send(ExchangedMessage {
+                direction,
+                message: message.clone(),
+            });
         }
     }
 
@@ -47,14 +258,41 @@ impl BPRequestClient {
         let address = self.address.clone();
         let buffer_size = self.buffer_size.clone();
         let reconnect_duration = self.reconnect_duration.clone();
+        let keepalive = self.keepalive;
+        let drain_timeout = self.drain_timeout;
 
         let stream_holder = self.stream_holder.clone();
+        let connected = self.connected.clone();
+        let identity = self.identity.clone();
+        let secrets = self.secrets.clone();
+        let reconnect = self.reconnect.clone();
+        let drain = self.drain.clone();
+        let messages = self.messages.clone();
 
         tokio::spawn(async move {
+            let mut reconnect_requests = reconnect.subscribe();
             loop {
-                // Creates TcpStream
-                let tcp_stream = match TcpStream::connect(address.clone()).await {
-                    Ok(tcp_stream) => tcp_stream,
+                // Resolved on every attempt, so DNS changes are picked up on reconnect.
+                let addresses = match ip_utils::resolve(&address).await {
+                    Ok(addresses) => addresses,
+                    Err(error) => {
+                        eprintln!("Failed to resolve BP Server {}. Error: {}", address, error);
+                        Self::wait_reconnect(reconnect_duration.clone()).await;
+                        continue;
+                    }
+                };
+
+                // Creates TcpStream, racing IPv6 and IPv4 addresses of dual-stack hosts.
+                let tcp_stream = match happy_eyeballs::connect(
+                    &addresses,
+                    happy_eyeballs::ATTEMPT_DELAY,
+                )
+                .await
+                {
+                    Ok((tcp_stream, peer)) => {
+                        println!("Connected to BP Server {} at {}.", address, peer);
+                        tcp_stream
+                    }
                     Err(error) => {
                         eprintln!("Failed to connect to BP Server. Error: {}", error);
                         Self::wait_reconnect(reconnect_duration.clone()).await;
@@ -76,6 +314,9 @@ impl BPRequestClient {
                 // Abstracted Stream type
                 let stream: Arc<Stream> = Arc::new(Box::new(tcp_stream_wrapper));
 
+                // Requests until now are served by this connection, which handshakes with the
+                // current token. Later ones, even during the handshake, drop it.
+                reconnect_requests.mark_unchanged();
                 {
                     // Set same stream to allow sending data.
                     let mut stream_holder = stream_holder.lock().await;
@@ -83,9 +324,10 @@ impl BPRequestClient {
                 }
 
                 // Handshakes as request client.
-                match Self::handshake(stream.clone()).await {
+                match Self::handshake(stream.clone(), &secrets).await {
                     Ok(()) => {
                         println!("Handshake completed.");
+                        connected.send_replace(true);
                     }
                     Err(error) => {
                         eprintln!("Handshake failed with bp server. Error: {}", error);
@@ -94,14 +336,41 @@ impl BPRequestClient {
                     }
                 };
 
-                // Listens response in loop
-                Self::listen_stream_response(stream.clone(), &mut callback).await;
+                // Listens response in loop until the stream fails or the keepalive detects a
+                // half-open connection.
+                let last_received = Arc::new(AtomicI64::new(Utc::now().timestamp()));
+                tokio::select! {
+                    _ = Self::listen_stream_response(
+                        stream.clone(),
+                        &mut callback,
+                        last_received.clone(),
+                        identity.clone(),
+                        drain.clone(),
+                        &messages,
+                    ) => {}
+                    _ = Self::keepalive(stream_holder.clone(), keepalive, last_received.clone()) => {}
+                    _ = reconnect_requests.changed() => {
+                        println!("Reconnecting to BP server to handshake again.");
+                    }
+                    _ = Self::wait_drained(drain.clone(), drain_timeout) => {}
+                }
 
                 {
                     // Set same stream to allow sending data.
                     let mut stream_holder = stream_holder.lock().await;
                     stream_holder.take();
                 }
+                connected.send_replace(false);
+
+                // Outcomes of tasks sent on the dropped connection can't arrive anymore.
+                if let Ok(mut drain) = drain.write() {
+                    *drain = DrainState::default();
+                }
+
+                // The next connection may reach another worker.
+                if let Ok(mut identity) = identity.write() {
+                    *identity = ServerIdentity::default();
+                }
 
                 Self::wait_reconnect(reconnect_duration).await;
             }
@@ -119,20 +388,19 @@ impl BPRequestClient {
     /// }
     /// ```
     ///
-    async fn handshake(tcp_stream: Arc<Stream>) -> std::io::Result<()> {
+    async fn handshake(tcp_stream: Arc<Stream>, secrets: &SecretStore) -> std::io::Result<()> {
         #[derive(Serialize, Deserialize, Debug)]
         struct HandshakeRequest<'a> {
             client_type: &'a str,
             auth_token: String,
+            /// Lets BP servers log which build of this service is connected.
+            client_version: String,
         }
 
-        let bp_server_auth_token = match env::var("BP_SERVER_AUTH_TOKEN") {
-            Ok(token) => token,
-            Err(error) => {
-                eprintln!(
-                    "BP_SERVER_AUTH_TOKEN is missing from environment variable. Error: {}",
-                    error
-                );
+        let bp_server_auth_token = match secrets.get(secrets::BP_SERVER_AUTH_TOKEN) {
+            Some(token) => token,
+            None => {
+                eprintln!("BP_SERVER_AUTH_TOKEN is missing from environment variable and secrets.");
                 std::process::exit(-1);
             }
         };
@@ -140,6 +408,7 @@ impl BPRequestClient {
         let handshake_request = HandshakeRequest {
             client_type: "request",
             auth_token: bp_server_auth_token,
+            client_version: build_info::version(),
         };
 
         let handshake_request_json = serde_json::to_string(&handshake_request).unwrap();
@@ -150,13 +419,174 @@ impl BPRequestClient {
         Ok(())
     }
 
+    ///
+    /// Opens a separate connection, handshakes and waits up to `timeout` for the reply to a
+    /// keepalive frame. A BP server rejecting the auth token closes the connection instead. Used by
+    /// the startup self-check; returns the identity the BP server announced, if any.
+    ///
+    pub async fn check_handshake(&self, timeout: Duration) -> std::io::Result<ServerIdentity> {
+        // Handshaking without a token exits the process.
+        if self.secrets.get(secrets::BP_SERVER_AUTH_TOKEN).is_none() {
+            return Err(std::io::Error::other("BP_SERVER_AUTH_TOKEN is missing."));
+        }
+
+        let check = async {
+            let addresses = ip_utils::resolve(&self.address).await?;
+            let (tcp_stream, _) =
+                happy_eyeballs::connect(&addresses, happy_eyeballs::ATTEMPT_DELAY).await?;
+            let tcp_stream_wrapper =
+                tej_protoc::stream::TcpStreamWrapper::new(tcp_stream, self.buffer_size)
+                    .map_err(std::io::Error::other)?;
+            let stream: Arc<Stream> = Arc::new(Box::new(tcp_stream_wrapper));
+
+            Self::handshake(stream.clone(), &self.secrets).await?;
+            let ping = json!({ "action": "ping" }).to_string();
+            stream
+                .write_chunk(&build_bytes_for_message(&ping.as_bytes().to_vec()))
+                .await?;
+
+            let mut identity = ServerIdentity::default();
+            loop {
+                let decoded_response =
+                    tej_protoc::protoc::decoder::decode_tcp_stream(stream.clone())
+                        .await
+                        .map_err(std::io::Error::other)?;
+                let message: Value = serde_json::from_slice(&decoded_response.message)
+                    .map_err(std::io::Error::other)?;
+
+                if let Some(announced) = ServerIdentity::from_message(&message) {
+                    identity = announced.or(identity);
+                }
+                if Self::is_keepalive_message(&message) {
+                    return Ok(identity);
+                }
+            }
+        };
+
+        match tokio::time::timeout(timeout, check).await {
+            Ok(result) => result,
+            Err(_) => Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!("No keepalive reply within {:?}.", timeout),
+            )),
+        }
+    }
+
     async fn wait_reconnect(reconnect_duration: Duration) {
         println!("Reconnecting in {:?} ...", reconnect_duration);
         sleep(reconnect_duration).await;
     }
 
-    async fn listen_stream_response<F, Fut>(stream: Arc<Stream>, callback: &mut F)
-    where
+    ///
+    /// Sends keepalive frames every `keepalive.interval` and returns when nothing was received for
+    /// `keepalive.liveness_timeout` or a keepalive frame can't be written.
+    ///
+    /// Keepalive frame is following JSON message. The BP server replies with `{"action": "pong"}`.
+    /// ```
+    /// {
+    ///     "action": "ping"
+    /// }
+    /// ```
+    ///
+    async fn keepalive(
+        stream_holder: Arc<Mutex<Option<Arc<Stream>>>>,
+        keepalive: Keepalive,
+        last_received: Arc<AtomicI64>,
+    ) {
+        let ping = json!({ "action": "ping" }).to_string();
+        let bytes = build_bytes_for_message(&ping.as_bytes().to_vec());
+
+        loop {
+            sleep(keepalive.interval).await;
+
+            let silent_for = Utc::now().timestamp() - last_received.load(Ordering::Relaxed);
+            if silent_for >= keepalive.liveness_timeout.as_secs() as i64 {
+                eprintln!(
+                    "Nothing received from BP server for {} seconds. Reconnecting.",
+                    silent_for
+                );
+                return;
+            }
+
+            let stream_holder = stream_holder.lock().await;
+            if let Some(stream) = stream_holder.as_ref() {
+                if let Err(error) = stream.write_chunk(&bytes).await {
+                    eprintln!("Failed to send keepalive to BP server. Error: {}", error);
+                    return;
+                }
+            }
+        }
+    }
+
+    ///
+    /// Returns once a drain requested by the BP server is over: every task sent on the connection
+    /// got its outcome, or `drain_timeout` passed. Tasks still in flight then are recovered by the
+    /// response timeout.
+    ///
+    /// Nothing is sent again here, so no lease is taken. Tasks held back while draining are still
+    /// within `task::dispatch` under its lease, and tasks left in flight are only sent again when
+    /// a client asks for them, through `task::dispatch` as well.
+    ///
+    async fn wait_drained(drain: Arc<RwLock<DrainState>>, drain_timeout: Duration) {
+        let remaining = || match drain.read() {
+            Ok(drain) => drain.remaining(),
+            Err(_) => None,
+        };
+
+        while remaining().is_none() {
+            sleep(DRAIN_POLL_INTERVAL).await;
+        }
+
+        let started = Instant::now();
+        loop {
+            match drain_utils::drain_progress(remaining(), started.elapsed(), drain_timeout) {
+                DrainProgress::Drained => {
+                    println!("BP server drained. Reconnecting.");
+                    return;
+                }
+                DrainProgress::TimedOut(remaining) => {
+                    eprintln!(
+                        "BP server drain timed out after {:?} with {} tasks in flight.",
+                        drain_timeout, remaining
+                    );
+                    return;
+                }
+                DrainProgress::Waiting => sleep(DRAIN_POLL_INTERVAL).await,
+            }
+        }
+    }
+
+    ///
+    /// Returns true for keepalive frames which are not passed to the caller.
+    ///
+    fn is_keepalive_message(message: &Value) -> bool {
+        matches!(
+            message.get("action").and_then(|action| action.as_str()),
+            Some("ping") | Some("pong")
+        )
+    }
+
+    ///
+    /// Returns true for the control frame of a BP server about to shut down. It keeps sending
+    /// outcomes of tasks in flight but must not be sent new tasks.
+    /// ```
+    /// {
+    ///     "action": "drain"
+    /// }
+    /// ```
+    ///
+    fn is_drain_message(message: &Value) -> bool {
+        message.get("action").and_then(|action| action.as_str()) == Some("drain")
+    }
+
+    async fn listen_stream_response<F, Fut>(
+        stream: Arc<Stream>,
+        callback: &mut F,
+        last_received: Arc<AtomicI64>,
+        identity: Arc<RwLock<ServerIdentity>>,
+        drain: Arc<RwLock<DrainState>>,
+        messages: &broadcast::Sender<ExchangedMessage>,
+    ) where
         F: FnMut(Vec<File>, Value) -> Fut + Send + Sync + 'static,
         Fut: Future + Send + 'static,
         Fut::Output: Send + Sync + 'static,
@@ -171,6 +601,8 @@ impl BPRequestClient {
                     }
                 };
 
+            last_received.store(Utc::now().timestamp(), Ordering::Relaxed);
+
             let message = String::from_utf8_lossy(&decoded_response.message).to_string();
             let message_json = match Value::from_str(&message) {
                 Ok(json_value) => json_value,
@@ -180,32 +612,101 @@ impl BPRequestClient {
                 }
             };
 
+            if let Some(announced) = ServerIdentity::from_message(&message_json) {
+                if let Ok(mut identity) = identity.write() {
+                    let previous = identity.clone();
+                    *identity = announced.or(previous);
+                }
+            }
+
+            if Self::is_keepalive_message(&message_json) {
+                continue;
+            }
+
+            if Self::is_drain_message(&message_json) {
+                if let Ok(mut drain) = drain.write() {
+                    println!(
+                        "BP server is draining with {} tasks in flight.",
+                        drain.in_flight()
+                    );
+                    drain.draining = true;
+                }
+                continue;
+            }
+
+            let is_outcome = matches!(
+                message_json
+                    .get("status")
+                    .and_then(|status| status.as_str()),
+                Some("success") | Some("failed")
+            );
+            if let (true, Some(task_id)) = (is_outcome, message_json.get("task_id")) {
+                if let (Ok(mut drain), Some(task_id)) = (drain.write(), task_id.as_str()) {
+                    drain.untrack(task_id);
+                }
+            }
+
+            Self::publish_message(messages, MessageDirection::Received, &message_json);
+
             // Passes received data back to the caller.
             callback(decoded_response.files, message_json).await;
         }
     }
 
+    ///
+    /// Sends a task. While the BP server drains, waits for the reconnected connection, within the
+    /// send timeout of the caller.
+    ///
     pub async fn send(&self, files: &[File], message: &Value) -> std::io::Result<()> {
+        while self.is_draining() {
+            sleep(DRAIN_POLL_INTERVAL).await;
+        }
+
         let mut files_vec = vec![];
         for file in files {
             files_vec.push(file);
         }
 
-        let message = message.to_string().as_bytes().to_vec();
+        let task_id = message
+            .get("task_id")
+            .and_then(|task_id| task_id.as_str())
+            .map(|task_id| task_id.to_string());
+        let message_bytes = message.to_string().as_bytes().to_vec();
         let encoded_bytes =
-            tej_protoc::protoc::encoder::build_bytes(Some(&files_vec), Some(&message));
+            tej_protoc::protoc::encoder::build_bytes(Some(&files_vec), Some(&message_bytes));
 
-        {
+        // Tracked before writing so a drain announced meanwhile waits for the outcome, and
+        // untracked again when the task couldn't be sent.
+        if let (Some(task_id), Ok(mut drain)) = (&task_id, self.drain.write()) {
+            drain.track(task_id.clone(), Instant::now(), MAX_IN_FLIGHT);
+        }
+
+        let result = {
             let stream_holder = self.stream_holder.lock().await;
-            if let Some(stream) = stream_holder.as_ref() {
-                stream.write_chunk(&encoded_bytes).await?;
-            } else {
-                return Err(std::io::Error::other(
+            match stream_holder.as_ref() {
+                Some(stream) => {
+                    let mut guard = WriteGuard {
+                        reconnect: &self.reconnect,
+                        armed: true,
+                    };
+                    let result = stream.write_chunk(&encoded_bytes).await;
+                    guard.armed = false;
+                    result
+                }
+                None => Err(std::io::Error::other(
                     "BP Request client not connected to server.",
-                ));
+                )),
             }
+        };
+        if let Err(error) = result {
+            if let (Some(task_id), Ok(mut drain)) = (&task_id, self.drain.write()) {
+                drain.untrack(task_id);
+            }
+            return Err(error);
         }
 
+        Self::publish_message(&self.messages, MessageDirection::Sent, message);
+
         Ok(())
     }
 }
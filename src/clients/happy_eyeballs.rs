@@ -0,0 +1,58 @@
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use tokio::net::TcpStream;
+use tokio::time::sleep;
+
+/// Delay before the next address is tried while earlier attempts are still pending (RFC 8305).
+pub const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+async fn attempt(address: SocketAddr) -> (SocketAddr, std::io::Result<TcpStream>) {
+    (address, TcpStream::connect(address).await)
+}
+
+///
+/// Connects to the first of `addresses` that answers. Attempts start in order, `attempt_delay`
+/// apart or as soon as the previous attempt failed, and race each other; the first established
+/// connection wins and the others are dropped. Returns the stream with its peer address.
+///
+pub async fn connect(
+    addresses: &[SocketAddr],
+    attempt_delay: Duration,
+) -> std::io::Result<(TcpStream, SocketAddr)> {
+    let mut pending: VecDeque<SocketAddr> = addresses.iter().copied().collect();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_error = None;
+
+    loop {
+        if attempts.is_empty() {
+            match pending.pop_front() {
+                Some(address) => attempts.push(attempt(address)),
+                None => {
+                    return Err(last_error
+                        .unwrap_or_else(|| std::io::Error::other("No address to connect to.")))
+                }
+            }
+        }
+
+        tokio::select! {
+            Some((address, result)) = attempts.next() => match result {
+                Ok(stream) => return Ok((stream, address)),
+                Err(error) => {
+                    eprintln!("Failed to connect to {}. Error: {}", address, error);
+                    last_error = Some(error);
+                    if let Some(address) = pending.pop_front() {
+                        attempts.push(attempt(address));
+                    }
+                }
+            },
+            _ = sleep(attempt_delay), if !pending.is_empty() => {
+                if let Some(address) = pending.pop_front() {
+                    attempts.push(attempt(address));
+                }
+            }
+        }
+    }
+}
@@ -1,2 +1,7 @@
 pub mod bp_request_client;
+pub mod happy_eyeballs;
+pub mod mailer;
+pub mod ops_notifier;
+pub mod store_client;
+pub mod stripe_client;
 
@@ -0,0 +1,195 @@
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use arc_swap::ArcSwap;
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::config::AppConfig;
+use crate::utils::alert_utils::Cooldowns;
+use crate::utils::retry_utils;
+
+///
+/// Operational events worth waking someone up for.
+///
+#[derive(Debug, Clone)]
+pub enum OpsEvent {
+    BpConnectionLost,
+    BpConnectionRestored,
+    /// Share of failed and timed out results within the check window exceeded the threshold.
+    FailureRateSpike {
+        failed: u64,
+        total: u64,
+        window: Duration,
+    },
+    /// Filesystem of `MEDIA_ROOT` is fuller than the threshold.
+    DiskUsage {
+        path: String,
+        used_percent: u64,
+    },
+    /// Task could not be dispatched to the BP server and was given up.
+    TaskUndeliverable {
+        key: Uuid,
+        reason: String,
+    },
+}
+
+impl OpsEvent {
+    ///
+    /// Events of the same kind share a cooldown, so a burst of failures posts one message.
+    ///
+    fn kind(&self) -> &'static str {
+        match self {
+            OpsEvent::BpConnectionLost => "bp_connection_lost",
+            OpsEvent::BpConnectionRestored => "bp_connection_restored",
+            OpsEvent::FailureRateSpike { .. } => "failure_rate_spike",
+            OpsEvent::DiskUsage { .. } => "disk_usage",
+            OpsEvent::TaskUndeliverable { .. } => "task_undeliverable",
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            OpsEvent::BpConnectionLost => "BP server connection lost.".to_string(),
+            OpsEvent::BpConnectionRestored => "BP server connection restored.".to_string(),
+            OpsEvent::FailureRateSpike {
+                failed,
+                total,
+                window,
+            } => format!(
+                "{} of {} tasks failed in the last {} seconds.",
+                failed,
+                total,
+                window.as_secs()
+            ),
+            OpsEvent::DiskUsage { path, used_percent } => {
+                format!("Disk of {} is {}% full.", path, used_percent)
+            }
+            OpsEvent::TaskUndeliverable { key, reason } => {
+                format!(
+                    "Task {} could not be sent to the BP server: {}",
+                    key, reason
+                )
+            }
+        }
+    }
+}
+
+///
+/// Payload format of the webhook.
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WebhookKind {
+    Slack,
+    Discord,
+}
+
+///
+/// Posts operational events to a Slack or Discord incoming webhook.
+///
+pub struct OpsNotifier {
+    client: reqwest::Client,
+    url: String,
+    kind: WebhookKind,
+    /// Minimum time between two messages of the same event kind.
+    cooldown: Duration,
+    cooldowns: Mutex<Cooldowns>,
+    /// Read for `webhook_retry` on every alert.
+    config: Arc<ArcSwap<AppConfig>>,
+}
+
+impl OpsNotifier {
+    ///
+    /// Reads `OPS_WEBHOOK_URL`, `OPS_WEBHOOK_KIND` (`slack` or `discord`, detected from the url
+    /// when missing) and `OPS_ALERT_COOLDOWN_SECS`, default 300. `None` when no url is configured.
+    ///
+    pub fn from_env(config: Arc<ArcSwap<AppConfig>>) -> Option<Self> {
+        let url = env::var("OPS_WEBHOOK_URL").ok()?;
+
+        let kind = match env::var("OPS_WEBHOOK_KIND") {
+            Ok(value) if value.eq_ignore_ascii_case("discord") => WebhookKind::Discord,
+            Ok(_) => WebhookKind::Slack,
+            Err(_) if url.contains("discord.com") || url.contains("discordapp.com") => {
+                WebhookKind::Discord
+            }
+            Err(_) => WebhookKind::Slack,
+        };
+
+        let cooldown = match env::var("OPS_ALERT_COOLDOWN_SECS") {
+            Ok(value) => value.parse::<u64>().unwrap_or(300),
+            Err(_) => 300,
+        };
+
+        Some(Self {
+            client: reqwest::Client::new(),
+            url,
+            kind,
+            cooldown: Duration::from_secs(cooldown),
+            cooldowns: Mutex::new(Cooldowns::default()),
+            config,
+        })
+    }
+
+    ///
+    /// Posts `event` in the background unless an event of the same kind was posted within the
+    /// cooldown. Failed posts are retried according to `AppConfig::webhook_retry`.
+    ///
+    pub fn notify(self: &Arc<Self>, event: OpsEvent) {
+        if !self.should_send(event.kind()) {
+            return;
+        }
+
+        let notifier = self.clone();
+        tokio::spawn(async move {
+            let policy = notifier.config.load().webhook_retry;
+            let mut failed = vec![];
+            let result =
+                retry_utils::retry(&policy, |_| true, &mut failed, |_| notifier.post(&event)).await;
+
+            for attempt in &failed {
+                eprintln!(
+                    "Retrying {} alert after attempt {} in {:?}. Error: {}",
+                    event.kind(),
+                    attempt.attempt,
+                    attempt.delay,
+                    attempt.error
+                );
+            }
+            if let Err(error) = result {
+                eprintln!("Failed to post {} alert. Error: {}", event.kind(), error);
+            }
+        });
+    }
+
+    fn should_send(&self, kind: &'static str) -> bool {
+        match self.cooldowns.lock() {
+            Ok(mut cooldowns) => cooldowns.try_start(kind, Instant::now(), self.cooldown),
+            Err(_) => false,
+        }
+    }
+
+    async fn post(&self, event: &OpsEvent) -> std::io::Result<()> {
+        let text = format!("[bp-api-service] {}", event.message());
+        let payload = match self.kind {
+            WebhookKind::Slack => json!({ "text": text }),
+            WebhookKind::Discord => json!({ "content": text }),
+        };
+
+        let response = self
+            .client
+            .post(&self.url)
+            .json(&payload)
+            .send()
+            .await
+            .map_err(std::io::Error::other)?;
+
+        if !response.status().is_success() {
+            return Err(std::io::Error::other(format!(
+                "Webhook responded with {}",
+                response.status()
+            )));
+        }
+        Ok(())
+    }
+}
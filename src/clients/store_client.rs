@@ -0,0 +1,254 @@
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use reqwest::header::LINK;
+use reqwest::{Method, RequestBuilder, StatusCode};
+use serde_json::{json, Value};
+
+use crate::api::ingestion::{IngestError, MAX_IMAGE_SIZE};
+use crate::utils::store_utils::{self, ProductImage, Store};
+
+/// Timeout of every request to the store.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Products listed per page, the maximum of Shopify.
+const SHOPIFY_PAGE_SIZE: usize = 250;
+
+/// Products listed per page, the maximum of WooCommerce.
+const WOOCOMMERCE_PAGE_SIZE: usize = 100;
+
+///
+/// Why a store request failed.
+///
+#[derive(Debug)]
+pub enum StoreError {
+    /// The store refused the request, e.g. for invalid credentials or a deleted product.
+    Rejected(String),
+    /// The store was unreachable, failed or throttled, so the request may succeed later.
+    Unavailable(String),
+}
+
+impl StoreError {
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, StoreError::Unavailable(_))
+    }
+}
+
+impl Display for StoreError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StoreError::Rejected(reason) => write!(f, "Rejected by the store. {}", reason),
+            StoreError::Unavailable(reason) => write!(f, "Store unavailable. {}", reason),
+        }
+    }
+}
+
+///
+/// Lists product images of a Shopify or WooCommerce store and adds cutouts to its products.
+///
+pub struct StoreClient {
+    client: reqwest::Client,
+    store: Store,
+}
+
+impl StoreClient {
+    pub fn new(store: Store) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .unwrap_or_default();
+
+        Self { client, store }
+    }
+
+    pub fn platform(&self) -> &'static str {
+        self.store.platform()
+    }
+
+    ///
+    /// Request to the API of the store with its credentials.
+    ///
+    fn request(&self, method: Method, url: &str) -> RequestBuilder {
+        let request = self.client.request(method, url);
+        match &self.store {
+            Store::Shopify { access_token, .. } => {
+                request.header("X-Shopify-Access-Token", access_token)
+            }
+            Store::Woocommerce {
+                consumer_key,
+                consumer_secret,
+                ..
+            } => request.basic_auth(consumer_key, Some(consumer_secret)),
+        }
+    }
+
+    async fn send(&self, request: RequestBuilder) -> Result<reqwest::Response, StoreError> {
+        let response = request
+            .send()
+            .await
+            .map_err(|error| StoreError::Unavailable(error.to_string()))?;
+
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+        let reason = format!("The store answered {}.", status);
+        if status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error() {
+            Err(StoreError::Unavailable(reason))
+        } else {
+            Err(StoreError::Rejected(reason))
+        }
+    }
+
+    async fn json(response: reqwest::Response) -> Result<Value, StoreError> {
+        response
+            .json()
+            .await
+            .map_err(|error| StoreError::Unavailable(error.to_string()))
+    }
+
+    ///
+    /// Lists up to `max_images` product images in product order.
+    ///
+    pub async fn list_images(&self, max_images: usize) -> Result<Vec<ProductImage>, StoreError> {
+        let base = self.store.api_base();
+        let mut images = vec![];
+
+        match &self.store {
+            Store::Shopify { .. } => {
+                let mut url = Some(format!(
+                    "{}/products.json?limit={}&fields=id,images",
+                    base, SHOPIFY_PAGE_SIZE
+                ));
+                while let Some(page_url) = url.take() {
+                    let response = self.send(self.request(Method::GET, &page_url)).await?;
+                    url = response
+                        .headers()
+                        .get(LINK)
+                        .and_then(|link| link.to_str().ok())
+                        .and_then(store_utils::next_link);
+                    images.extend(store_utils::shopify_images(&Self::json(response).await?));
+                    if images.len() >= max_images {
+                        break;
+                    }
+                }
+            }
+            Store::Woocommerce { .. } => {
+                for page in 1.. {
+                    let url = format!(
+                        "{}/products?per_page={}&page={}",
+                        base, WOOCOMMERCE_PAGE_SIZE, page
+                    );
+                    let response = self.send(self.request(Method::GET, &url)).await?;
+                    let products = Self::json(response).await?;
+                    let count = products.as_array().map_or(0, Vec::len);
+                    images.extend(store_utils::woocommerce_images(&products));
+                    if count < WOOCOMMERCE_PAGE_SIZE || images.len() >= max_images {
+                        break;
+                    }
+                }
+            }
+        }
+
+        images.truncate(max_images);
+        Ok(images)
+    }
+
+    ///
+    /// Downloads a product image. Client errors are permanent, other failures are retryable.
+    ///
+    pub async fn download(&self, image: &ProductImage) -> Result<Vec<u8>, IngestError> {
+        // Images are served publicly, often by a CDN, so the credentials are not sent.
+        let mut response = self
+            .client
+            .get(&image.src)
+            .send()
+            .await
+            .map_err(|error| IngestError::Unavailable(error.to_string()))?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let reason = format!("Fetching the image answered {}.", status);
+            if status.is_client_error() {
+                return Err(IngestError::Invalid(reason));
+            }
+            return Err(IngestError::Unavailable(reason));
+        }
+
+        let too_large = || IngestError::Invalid("Image size is too large.".to_string());
+        if response
+            .content_length()
+            .is_some_and(|length| length > MAX_IMAGE_SIZE as u64)
+        {
+            return Err(too_large());
+        }
+
+        let mut data = vec![];
+        while let Some(chunk) = response
+            .chunk()
+            .await
+            .map_err(|error| IngestError::Unavailable(error.to_string()))?
+        {
+            if data.len() + chunk.len() > MAX_IMAGE_SIZE {
+                return Err(too_large());
+            }
+            data.extend_from_slice(&chunk);
+        }
+        Ok(data)
+    }
+
+    ///
+    /// Adds cutout `data` as new image of the product of `image`, keeping its other images.
+    /// Shopify gets the image uploaded; WooCommerce downloads it from `url`, which must be
+    /// publicly reachable.
+    ///
+    pub async fn push_cutout(
+        &self,
+        image: &ProductImage,
+        filename: &str,
+        data: &[u8],
+        url: &str,
+    ) -> Result<(), StoreError> {
+        let product_url = format!("{}/products/{}", self.store.api_base(), image.product_id);
+
+        match &self.store {
+            Store::Shopify { .. } => {
+                let body = json!({
+                    "image": {
+                        "attachment": STANDARD.encode(data),
+                        "filename": filename,
+                        "alt": store_utils::cutout_alt(image.image_id),
+                    }
+                });
+                let url = format!("{}/images.json", product_url);
+                self.send(self.request(Method::POST, &url).json(&body))
+                    .await?;
+            }
+            Store::Woocommerce { .. } => {
+                // The images of a product are replaced as a whole, so the current ones are
+                // fetched right before and sent along.
+                let response = self.send(self.request(Method::GET, &product_url)).await?;
+                let product = Self::json(response).await?;
+                let mut images: Vec<Value> = product
+                    .get("images")
+                    .and_then(Value::as_array)
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|image| Some(json!({ "id": image.get("id")?.as_u64()? })))
+                    .collect();
+                images.push(json!({
+                    "src": url,
+                    "name": filename,
+                    "alt": store_utils::cutout_alt(image.image_id),
+                }));
+
+                let body = json!({ "images": images });
+                self.send(self.request(Method::PUT, &product_url).json(&body))
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,82 @@
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::db::models::UsageReport;
+use crate::secrets::{self, SecretStore};
+
+const DEFAULT_API_BASE: &str = "https://api.stripe.com";
+
+/// Requests taking longer are failed, so a hanging Stripe API doesn't block usage reporting.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+///
+/// Sends usage records to the Stripe API. The secret key is read from `secrets` on every call,
+/// so a rotated key applies without restart.
+///
+pub struct StripeClient {
+    client: reqwest::Client,
+    /// `STRIPE_API_BASE`, default `https://api.stripe.com`.
+    api_base: String,
+    secrets: Arc<SecretStore>,
+}
+
+impl StripeClient {
+    pub fn new(secrets: Arc<SecretStore>) -> Self {
+        let api_base = env::var("STRIPE_API_BASE").unwrap_or_else(|_| DEFAULT_API_BASE.to_string());
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .unwrap_or_default();
+
+        Self {
+            client,
+            api_base: api_base.trim_end_matches('/').to_string(),
+            secrets,
+        }
+    }
+
+    ///
+    /// Whether `STRIPE_SECRET_KEY` is set.
+    ///
+    pub fn is_configured(&self) -> bool {
+        self.secrets.get(secrets::STRIPE_SECRET_KEY).is_some()
+    }
+
+    ///
+    /// Adds the quantity of `report` to its metered subscription item. The report id is the
+    /// idempotency key, so a report sent again after a lost response is counted once.
+    ///
+    pub async fn report_usage(&self, report: &UsageReport) -> std::io::Result<()> {
+        let secret_key = self
+            .secrets
+            .get(secrets::STRIPE_SECRET_KEY)
+            .ok_or_else(|| std::io::Error::other("STRIPE_SECRET_KEY is missing."))?;
+
+        let url = format!(
+            "{}/v1/subscription_items/{}/usage_records",
+            self.api_base, report.stripe_subscription_item_id
+        );
+        let response = self
+            .client
+            .post(url)
+            .bearer_auth(secret_key)
+            .header("Idempotency-Key", format!("bp-usage-report-{}", report.id))
+            .form(&[
+                ("quantity", report.quantity.to_string()),
+                ("timestamp", report.date_created.timestamp().to_string()),
+                ("action", "increment".to_string()),
+            ])
+            .send()
+            .await
+            .map_err(std::io::Error::other)?;
+
+        if !response.status().is_success() {
+            return Err(std::io::Error::other(format!(
+                "Stripe responded with {}",
+                response.status()
+            )));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,541 @@
+use std::collections::HashMap;
+use std::env;
+use std::time::Duration;
+
+use image::imageops::FilterType;
+
+use crate::api::ingestion::MAX_IMAGE_SIZE;
+use crate::utils::billing_utils::{self, PlanLimits};
+use crate::utils::body_utils::BodyLimits;
+use crate::utils::health_utils::HealthThresholds;
+use crate::utils::image_utils::{
+    self, PreviewFit, PreviewOptions, WatermarkOptions, WatermarkPosition,
+};
+use crate::utils::ip_utils::{self, Cidr};
+use crate::utils::maintenance_utils::{self, Maintenance};
+use crate::utils::retry_utils::RetryPolicy;
+use crate::utils::throttle_utils::DeleteThrottle;
+
+///
+/// Settings stored in the `app_config` table. They take precedence over environment variables
+/// of the same name and are reloaded without restart.
+///
+pub type Overrides = HashMap<String, String>;
+
+///
+/// Returns value of setting `name` from `overrides`, falling back to the environment variable.
+///
+pub fn setting(overrides: &Overrides, name: &str) -> Option<String> {
+    match overrides.get(name) {
+        Some(value) => Some(value.clone()),
+        None => env::var(name).ok(),
+    }
+}
+
+const DEFAULT_HSTS_MAX_AGE_SECS: u64 = 365 * 24 * 60 * 60;
+
+const DEFAULT_CONTENT_SECURITY_POLICY: &str = "default-src 'none'; frame-ancestors 'none'";
+
+///
+/// Application settings. Loaded from environment variables on startup and swapped whenever the
+/// overrides in `app_config` change.
+///
+#[derive(Debug, Clone)]
+pub struct AppConfig {
+    /// Reading the original image from disk before dispatching it to the BP server.
+    /// `ORIGINAL_READ_TIMEOUT_SECS`, default 10.
+    pub original_read_timeout: Duration,
+    /// Writing a task to the BP server connection. `BP_SEND_TIMEOUT_SECS`, default 12.
+    pub bp_send_timeout: Duration,
+    /// Handling a response received from the BP server, including saving files and updating the
+    /// database. `BP_RESPONSE_TIMEOUT_SECS`, default 6.
+    pub bp_response_timeout: Duration,
+    /// Interval between keepalive frames sent to the BP server. `BP_KEEPALIVE_INTERVAL_SECS`,
+    /// default 5. Only read on startup.
+    pub bp_keepalive_interval: Duration,
+    /// The BP connection is reconnected when nothing is received for this long.
+    /// `BP_LIVENESS_TIMEOUT_SECS`, default 15. Only read on startup.
+    pub bp_liveness_timeout: Duration,
+    /// Longest wait for outcomes of in-flight tasks after the BP server asked to drain its
+    /// connection, before reconnecting anyway. `BP_DRAIN_TIMEOUT_SECS`, default 60. Only read on
+    /// startup.
+    pub bp_drain_timeout: Duration,
+    /// Processing time of a single task assumed until the first BP result gives a measured
+    /// average. `ESTIMATED_SECS_PER_TASK`, default 2.
+    pub estimated_time_per_task: Duration,
+    /// Percentage of tasks mirrored to the canary BP server at `CANARY_BP_SERVER_HOST`.
+    /// `CANARY_PERCENT`, default 0 (disabled).
+    pub canary_percent: u32,
+    /// Tasks of a key pinned to a dedicated BP server in `DEDICATED_BP_SERVERS` go to the public
+    /// BP server while the dedicated one is disconnected or draining. `DEDICATED_BP_FALLBACK`,
+    /// default false, in which case they are only ever sent to the dedicated server.
+    pub dedicated_bp_fallback: bool,
+    /// Share of failed sends and responses, in percent, at which a public BP endpoint is failed
+    /// over from. `BP_FAILOVER_ERROR_PERCENT`, default 50; 0 disables the check.
+    pub bp_failover_error_percent: u32,
+    /// Average time from sending a task to its response above which a public BP endpoint is
+    /// failed over from. `BP_FAILOVER_LATENCY_SECS`, default none.
+    pub bp_failover_latency: Option<Duration>,
+    /// How long a public BP endpoint failed over from for its health gets no tasks before it is
+    /// tried again. `BP_FAILBACK_AFTER_SECS`, default 60.
+    pub bp_failback_after: Duration,
+    /// Files of tasks older than this are removed by the media garbage collector.
+    /// `MEDIA_RETENTION_DAYS`, default none (kept forever).
+    pub media_retention_days: Option<i64>,
+    /// Pace of deletions by scheduled media garbage collector runs. `MEDIA_GC_MAX_FILES_PER_SEC`,
+    /// `MEDIA_GC_MAX_BYTES_PER_SEC` and `MEDIA_GC_IDLE_IO`, default unthrottled.
+    pub media_gc_throttle: DeleteThrottle,
+    /// Tasks older than this are moved to the archive table. `TASK_ARCHIVE_AFTER_DAYS`, default
+    /// none (never archived).
+    pub task_archive_after_days: Option<i64>,
+    /// Country and user identifier of tasks older than this are cleared by the anonymization
+    /// job. `ANONYMIZE_AFTER_DAYS`, default none (kept forever).
+    pub anonymize_after_days: Option<i64>,
+    /// Longest side in pixels of generated previews. `PREVIEW_MAX_SIDE`, default 512.
+    pub preview_max_side: u32,
+    /// Resize filter of generated previews. `PREVIEW_FILTER`, one of `nearest`, `triangle`,
+    /// `catmullrom`, `gaussian` or `lanczos3`, default `triangle`.
+    pub preview_filter: FilterType,
+    /// How previews fit into the `preview_max_side` square. `PREVIEW_FIT`, one of `contain`,
+    /// `cover` or `pad`, default `contain`.
+    pub preview_fit: PreviewFit,
+    /// Quality of JPEG previews from 1 to 100. `PREVIEW_JPEG_QUALITY`, default 85.
+    pub preview_jpeg_quality: u8,
+    /// Key ids of `UPLOAD_SIGNING_KEYS` whose uploads are free tier. Comma separated in
+    /// `FREE_TIER_KEY_IDS`, default none.
+    pub free_tier_key_ids: Vec<String>,
+    /// Logo PNG composited onto processed outputs of free tier tasks. `WATERMARK_PATH`, default
+    /// none, in which case nothing is watermarked.
+    pub watermark_path: Option<String>,
+    /// `WATERMARK_POSITION`, one of `top-left`, `top-right`, `bottom-left`, `bottom-right` or
+    /// `center`, default `bottom-right`.
+    pub watermark_position: WatermarkPosition,
+    /// Opacity of the logo from 0 to 100. `WATERMARK_OPACITY_PERCENT`, default 50.
+    pub watermark_opacity_percent: u8,
+    /// Largest width of the logo in percent of the image width. `WATERMARK_SCALE_PERCENT`,
+    /// default 20.
+    pub watermark_scale_percent: u32,
+    /// Outputs of free tier tasks are scaled down to at most this many pixels.
+    /// `FREE_TIER_MAX_OUTPUT_PIXELS`, default 250000. 0 keeps full resolution.
+    pub free_tier_max_output_pixels: u64,
+    /// Limits of the Stripe plans by price lookup key. `BILLING_PLANS`, comma separated
+    /// `<lookup key>:<monthly quota>:<priority>`, default none. See `billing_utils::parse_plans`.
+    pub billing_plans: HashMap<String, PlanLimits>,
+    /// Results within this window are considered for the failure rate alert.
+    /// `OPS_FAILURE_WINDOW_SECS`, default 300.
+    pub ops_failure_window: Duration,
+    /// Failure rate alert fires when at least this percentage of the results within the window
+    /// failed or timed out. `OPS_FAILURE_RATE_PERCENT`, default 20.
+    pub ops_failure_rate_percent: u32,
+    /// Minimum number of results within the window before the failure rate is judged.
+    /// `OPS_FAILURE_MIN_TASKS`, default 20.
+    pub ops_failure_min_tasks: u64,
+    /// Disk alert fires when the filesystem of `MEDIA_ROOT` is fuller than this percentage.
+    /// `OPS_DISK_USAGE_PERCENT`, default 90.
+    pub ops_disk_usage_percent: u32,
+    /// Retries of sending a task to the BP server. `BP_DISPATCH_RETRY_*`, default 3 attempts,
+    /// 500 ms base, 5 s ceiling and 20% jitter.
+    pub bp_dispatch_retry: RetryPolicy,
+    /// Retries of delivering ops alerts and batch emails. `WEBHOOK_RETRY_*`, default 3 attempts,
+    /// 1 s base, 30 s ceiling and 20% jitter.
+    pub webhook_retry: RetryPolicy,
+    /// Retries of saving files received from the BP server. They run within
+    /// `bp_response_timeout`. `STORAGE_RETRY_*`, default 3 attempts, 100 ms base, 1 s ceiling
+    /// and 20% jitter.
+    pub storage_retry: RetryPolicy,
+    /// Failed task key lookups of a client IP within `key_lookup_window` before it is banned.
+    /// `KEY_LOOKUP_MAX_FAILURES`, default 20. `0` disables the ban.
+    pub key_lookup_max_failures: u32,
+    /// Window failed key lookups are counted in. `KEY_LOOKUP_WINDOW_SECS`, default 60.
+    pub key_lookup_window: Duration,
+    /// How long a banned client IP is refused key lookups. `KEY_LOOKUP_BAN_SECS`, default 900.
+    pub key_lookup_ban: Duration,
+    /// `max-age` of the `Strict-Transport-Security` header. `HSTS_MAX_AGE_SECS`, default one
+    /// year. `0` leaves the header out, e.g. while HTTPS is being rolled out.
+    pub hsts_max_age_secs: u64,
+    /// `Content-Security-Policy` header of every response. `CONTENT_SECURITY_POLICY`, default
+    /// `default-src 'none'; frame-ancestors 'none'`, which suits JSON responses. Empty leaves the
+    /// header out.
+    pub content_security_policy: Option<String>,
+    /// Uploads without a valid signature are rejected. `UPLOAD_SIGNATURES_REQUIRED`, default
+    /// false, in which case only uploads carrying `X-Signature` are checked.
+    pub upload_signatures_required: bool,
+    /// Signed uploads are accepted this long after, or before, their timestamp. Nonces are
+    /// remembered for twice as long. `UPLOAD_SIGNATURE_MAX_AGE_SECS`, default 300.
+    pub upload_signature_max_age: Duration,
+    /// Proxies whose `Forwarded` and `X-Forwarded-For` headers are believed. Comma separated
+    /// networks in `TRUSTED_PROXIES`, default none.
+    pub trusted_proxies: Vec<Cidr>,
+    /// How often the admin feed websocket sends a snapshot of queue depth, BP health and
+    /// websocket counts. `ADMIN_FEED_INTERVAL_SECS`, default 5. Read when a feed connects.
+    pub admin_feed_interval: Duration,
+    /// How long download links of user exports stay valid. Exports are deleted once expired.
+    /// `USER_EXPORT_TTL_SECS`, default 86400.
+    pub user_export_ttl: Duration,
+    /// Upload temp files older than this are removed by the temp file janitor.
+    /// `TEMP_FILE_MAX_AGE_SECS`, default 3600.
+    pub temp_file_max_age: Duration,
+    /// Pace of deletions by the temp file janitor. `TEMP_JANITOR_MAX_FILES_PER_SEC`,
+    /// `TEMP_JANITOR_MAX_BYTES_PER_SEC` and `TEMP_JANITOR_IDLE_IO`, default unthrottled.
+    pub temp_janitor_throttle: DeleteThrottle,
+    /// Whether task messages exchanged with BP servers are stored in table `bp_message`.
+    /// `BP_MESSAGE_ARCHIVE`, default false.
+    pub bp_message_archive: bool,
+    /// Newest archived BP messages kept. `BP_MESSAGE_ARCHIVE_MAX_ROWS`, default 100000.
+    pub bp_message_archive_max_rows: i64,
+    /// How long plugin tokens stay valid. `PLUGIN_TOKEN_TTL_SECS`, default 900.
+    pub plugin_token_ttl: Duration,
+    /// How long a plugin request waits for its result before answering that it is still
+    /// processing. `PLUGIN_WAIT_SECS`, default 60.
+    pub plugin_wait: Duration,
+    /// Uploads of an image already processed within this window, with the same options and
+    /// tenant, are served from the earlier outputs instead of being processed again.
+    /// `UPLOAD_DEDUP_WINDOW_SECS`, default none (disabled).
+    pub upload_dedup_window: Option<Duration>,
+    /// Largest JSON request body in bytes. `MAX_JSON_BODY_BYTES`, default 1 MB.
+    pub max_json_body_size: usize,
+    /// Largest multipart request body in bytes. `MAX_MULTIPART_BODY_BYTES`, default the largest
+    /// image plus 1 MB for the other form fields.
+    pub max_multipart_body_size: usize,
+    /// Largest text frame received on websockets in bytes. `MAX_WS_TEXT_FRAME_BYTES`, default
+    /// 64 KB.
+    pub max_ws_text_frame_size: usize,
+    /// Public endpoints answer `503` while set; admin endpoints stay reachable.
+    /// `MAINTENANCE_MODE`, default false, with the RFC 3339 `MAINTENANCE_ESTIMATED_END` and
+    /// `MAINTENANCE_MESSAGE`, default none. Set through `/v1/admin/maintenance/`.
+    pub maintenance: Option<Maintenance>,
+    /// Requests taking longer are logged with their route, params and time spent in queries.
+    /// `SLOW_REQUEST_MILLIS`, default 1000; 0 disables the log.
+    pub slow_request_threshold: Option<Duration>,
+}
+
+impl AppConfig {
+    pub fn from_env() -> Self {
+        Self::load(&Overrides::new())
+    }
+
+    pub fn load(overrides: &Overrides) -> Self {
+        let duration =
+            |name: &str, default_secs: u64| duration_setting(overrides, name, default_secs);
+
+        Self {
+            original_read_timeout: duration("ORIGINAL_READ_TIMEOUT_SECS", 10),
+            bp_send_timeout: duration("BP_SEND_TIMEOUT_SECS", 12),
+            bp_response_timeout: duration("BP_RESPONSE_TIMEOUT_SECS", 6),
+            bp_keepalive_interval: duration("BP_KEEPALIVE_INTERVAL_SECS", 5),
+            bp_liveness_timeout: duration("BP_LIVENESS_TIMEOUT_SECS", 15),
+            bp_drain_timeout: duration("BP_DRAIN_TIMEOUT_SECS", 60),
+            estimated_time_per_task: duration("ESTIMATED_SECS_PER_TASK", 2),
+            canary_percent: match setting(overrides, "CANARY_PERCENT") {
+                Some(value) => value.parse::<u32>().unwrap_or(0).min(100),
+                None => 0,
+            },
+            dedicated_bp_fallback: match setting(overrides, "DEDICATED_BP_FALLBACK") {
+                Some(value) => value.to_lowercase() == "true",
+                None => false,
+            },
+            bp_failover_error_percent: match setting(overrides, "BP_FAILOVER_ERROR_PERCENT") {
+                Some(value) => value.parse::<u32>().unwrap_or(50).min(100),
+                None => 50,
+            },
+            bp_failover_latency: match setting(overrides, "BP_FAILOVER_LATENCY_SECS") {
+                Some(value) => match value.parse::<u64>() {
+                    Ok(seconds) if seconds > 0 => Some(Duration::from_secs(seconds)),
+                    _ => None,
+                },
+                None => None,
+            },
+            bp_failback_after: duration("BP_FAILBACK_AFTER_SECS", 60),
+            media_retention_days: days_setting(overrides, "MEDIA_RETENTION_DAYS"),
+            media_gc_throttle: throttle_setting(overrides, "MEDIA_GC"),
+            task_archive_after_days: days_setting(overrides, "TASK_ARCHIVE_AFTER_DAYS"),
+            anonymize_after_days: days_setting(overrides, "ANONYMIZE_AFTER_DAYS"),
+            preview_max_side: match setting(overrides, "PREVIEW_MAX_SIDE") {
+                Some(value) => value.parse::<u32>().unwrap_or(512).max(1),
+                None => 512,
+            },
+            preview_filter: match setting(overrides, "PREVIEW_FILTER") {
+                Some(value) => image_utils::parse_filter(&value).unwrap_or(FilterType::Triangle),
+                None => FilterType::Triangle,
+            },
+            preview_fit: match setting(overrides, "PREVIEW_FIT") {
+                Some(value) => PreviewFit::parse(&value).unwrap_or(PreviewFit::Contain),
+                None => PreviewFit::Contain,
+            },
+            preview_jpeg_quality: match setting(overrides, "PREVIEW_JPEG_QUALITY") {
+                Some(value) => value.parse::<u8>().unwrap_or(85).clamp(1, 100),
+                None => 85,
+            },
+            free_tier_key_ids: match setting(overrides, "FREE_TIER_KEY_IDS") {
+                Some(value) => value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|kid| !kid.is_empty())
+                    .map(str::to_string)
+                    .collect(),
+                None => vec![],
+            },
+            watermark_path: setting(overrides, "WATERMARK_PATH").filter(|path| !path.is_empty()),
+            watermark_position: match setting(overrides, "WATERMARK_POSITION") {
+                Some(value) => {
+                    WatermarkPosition::parse(&value).unwrap_or(WatermarkPosition::BottomRight)
+                }
+                None => WatermarkPosition::BottomRight,
+            },
+            watermark_opacity_percent: match setting(overrides, "WATERMARK_OPACITY_PERCENT") {
+                Some(value) => value.parse::<u8>().unwrap_or(50).min(100),
+                None => 50,
+            },
+            watermark_scale_percent: match setting(overrides, "WATERMARK_SCALE_PERCENT") {
+                Some(value) => value.parse::<u32>().unwrap_or(20).clamp(1, 100),
+                None => 20,
+            },
+            free_tier_max_output_pixels: match setting(overrides, "FREE_TIER_MAX_OUTPUT_PIXELS") {
+                Some(value) => value.parse::<u64>().unwrap_or(250_000),
+                None => 250_000,
+            },
+            billing_plans: match setting(overrides, "BILLING_PLANS") {
+                Some(value) => billing_utils::parse_plans(&value).unwrap_or_else(|error| {
+                    eprintln!("Ignoring invalid BILLING_PLANS. Error: {}", error);
+                    HashMap::new()
+                }),
+                None => HashMap::new(),
+            },
+            ops_failure_window: duration("OPS_FAILURE_WINDOW_SECS", 300),
+            ops_failure_rate_percent: match setting(overrides, "OPS_FAILURE_RATE_PERCENT") {
+                Some(value) => value.parse::<u32>().unwrap_or(20).min(100),
+                None => 20,
+            },
+            ops_failure_min_tasks: match setting(overrides, "OPS_FAILURE_MIN_TASKS") {
+                Some(value) => value.parse::<u64>().unwrap_or(20).max(1),
+                None => 20,
+            },
+            ops_disk_usage_percent: match setting(overrides, "OPS_DISK_USAGE_PERCENT") {
+                Some(value) => value.parse::<u32>().unwrap_or(90).min(100),
+                None => 90,
+            },
+            bp_dispatch_retry: retry_setting(overrides, "BP_DISPATCH_RETRY", (3, 500, 5_000, 20)),
+            webhook_retry: retry_setting(overrides, "WEBHOOK_RETRY", (3, 1_000, 30_000, 20)),
+            storage_retry: retry_setting(overrides, "STORAGE_RETRY", (3, 100, 1_000, 20)),
+            key_lookup_max_failures: match setting(overrides, "KEY_LOOKUP_MAX_FAILURES") {
+                Some(value) => value.parse::<u32>().unwrap_or(20),
+                None => 20,
+            },
+            key_lookup_window: duration("KEY_LOOKUP_WINDOW_SECS", 60),
+            key_lookup_ban: duration("KEY_LOOKUP_BAN_SECS", 900),
+            hsts_max_age_secs: match setting(overrides, "HSTS_MAX_AGE_SECS") {
+                Some(value) => value.parse::<u64>().unwrap_or(DEFAULT_HSTS_MAX_AGE_SECS),
+                None => DEFAULT_HSTS_MAX_AGE_SECS,
+            },
+            content_security_policy: match setting(overrides, "CONTENT_SECURITY_POLICY") {
+                Some(value) if value.trim().is_empty() => None,
+                Some(value) => Some(value),
+                None => Some(DEFAULT_CONTENT_SECURITY_POLICY.to_string()),
+            },
+            upload_signatures_required: match setting(overrides, "UPLOAD_SIGNATURES_REQUIRED") {
+                Some(value) => value.to_lowercase() == "true",
+                None => false,
+            },
+            upload_signature_max_age: duration("UPLOAD_SIGNATURE_MAX_AGE_SECS", 300),
+            trusted_proxies: match setting(overrides, "TRUSTED_PROXIES") {
+                Some(value) => ip_utils::parse_cidrs(&value),
+                None => vec![],
+            },
+            admin_feed_interval: duration("ADMIN_FEED_INTERVAL_SECS", 5),
+            user_export_ttl: duration("USER_EXPORT_TTL_SECS", 86400),
+            temp_file_max_age: duration("TEMP_FILE_MAX_AGE_SECS", 3600),
+            temp_janitor_throttle: throttle_setting(overrides, "TEMP_JANITOR"),
+            bp_message_archive: match setting(overrides, "BP_MESSAGE_ARCHIVE") {
+                Some(value) => value.to_lowercase() == "true",
+                None => false,
+            },
+            bp_message_archive_max_rows: match setting(overrides, "BP_MESSAGE_ARCHIVE_MAX_ROWS") {
+                Some(value) => value.parse::<i64>().unwrap_or(100000).max(0),
+                None => 100000,
+            },
+            plugin_token_ttl: duration("PLUGIN_TOKEN_TTL_SECS", 900),
+            plugin_wait: duration("PLUGIN_WAIT_SECS", 60),
+            upload_dedup_window: match setting(overrides, "UPLOAD_DEDUP_WINDOW_SECS") {
+                Some(value) => match value.parse::<u64>() {
+                    Ok(seconds) if seconds > 0 => Some(Duration::from_secs(seconds)),
+                    _ => None,
+                },
+                None => None,
+            },
+            max_json_body_size: size_setting(overrides, "MAX_JSON_BODY_BYTES", 1024 * 1024),
+            max_multipart_body_size: size_setting(
+                overrides,
+                "MAX_MULTIPART_BODY_BYTES",
+                MAX_IMAGE_SIZE + 1024 * 1024,
+            ),
+            max_ws_text_frame_size: size_setting(overrides, "MAX_WS_TEXT_FRAME_BYTES", 64 * 1024),
+            maintenance: match setting(overrides, "MAINTENANCE_MODE") {
+                Some(value) if value.to_lowercase() == "true" => Some(Maintenance {
+                    estimated_end: setting(overrides, "MAINTENANCE_ESTIMATED_END")
+                        .and_then(|value| maintenance_utils::parse_estimated_end(&value)),
+                    message: setting(overrides, "MAINTENANCE_MESSAGE")
+                        .filter(|message| !message.is_empty()),
+                }),
+                _ => None,
+            },
+            slow_request_threshold: match setting(overrides, "SLOW_REQUEST_MILLIS")
+                .map(|value| value.parse::<u64>().unwrap_or(1000))
+                .unwrap_or(1000)
+            {
+                0 => None,
+                millis => Some(Duration::from_millis(millis)),
+            },
+        }
+    }
+
+    pub fn preview_options(&self) -> PreviewOptions {
+        PreviewOptions {
+            max_side: self.preview_max_side,
+            filter: self.preview_filter,
+            fit: self.preview_fit,
+            jpeg_quality: self.preview_jpeg_quality,
+        }
+    }
+
+    ///
+    /// Returns the logo path and watermark settings, or `None` when no logo is configured.
+    ///
+    pub fn watermark(&self) -> Option<(&str, WatermarkOptions)> {
+        let options = WatermarkOptions {
+            position: self.watermark_position,
+            opacity_percent: self.watermark_opacity_percent,
+            scale_percent: self.watermark_scale_percent,
+        };
+        self.watermark_path.as_deref().map(|path| (path, options))
+    }
+
+    ///
+    /// Request body limits enforced by the middleware. Bodies of other content types, e.g. raw
+    /// images, are limited to the largest image.
+    ///
+    pub fn body_limits(&self) -> BodyLimits {
+        BodyLimits {
+            json: self.max_json_body_size,
+            multipart: self.max_multipart_body_size,
+            other: MAX_IMAGE_SIZE,
+            ws_text_frame: self.max_ws_text_frame_size,
+        }
+    }
+
+    pub fn bp_failover_thresholds(&self) -> HealthThresholds {
+        HealthThresholds {
+            max_error_percent: self.bp_failover_error_percent,
+            max_latency: self.bp_failover_latency,
+        }
+    }
+}
+
+///
+/// Reads positive number of seconds of setting `name`, falling back to `default_secs` when
+/// missing or invalid.
+///
+fn duration_setting(overrides: &Overrides, name: &str, default_secs: u64) -> Duration {
+    let seconds = match setting(overrides, name) {
+        Some(value) => match value.parse::<u64>() {
+            Ok(seconds) if seconds > 0 => seconds,
+            _ => {
+                eprintln!("Ignoring invalid {} value: {}", name, value);
+                default_secs
+            }
+        },
+        None => default_secs,
+    };
+
+    Duration::from_secs(seconds)
+}
+
+///
+/// Reads positive number of bytes of setting `name`, falling back to `default` when missing or
+/// invalid.
+///
+fn size_setting(overrides: &Overrides, name: &str, default: usize) -> usize {
+    match setting(overrides, name) {
+        Some(value) => match value.parse::<usize>() {
+            Ok(size) if size > 0 => size,
+            _ => {
+                eprintln!("Ignoring invalid {} value: {}", name, value);
+                default
+            }
+        },
+        None => default,
+    }
+}
+
+///
+/// Reads retry policy from `<prefix>_ATTEMPTS`, `<prefix>_BASE_MILLIS`, `<prefix>_CEILING_MILLIS`
+/// and `<prefix>_JITTER_PERCENT`. `defaults` are given in the same order.
+///
+fn retry_setting(
+    overrides: &Overrides,
+    prefix: &str,
+    defaults: (u32, u64, u64, u32),
+) -> RetryPolicy {
+    let read = |suffix: &str, default: u64| {
+        let name = format!("{}_{}", prefix, suffix);
+        match setting(overrides, &name) {
+            Some(value) => match value.parse::<u64>() {
+                Ok(value) => value,
+                Err(_) => {
+                    eprintln!("Ignoring invalid {} value: {}", name, value);
+                    default
+                }
+            },
+            None => default,
+        }
+    };
+
+    let (attempts, base_millis, ceiling_millis, jitter_percent) = defaults;
+    RetryPolicy {
+        max_attempts: read("ATTEMPTS", attempts as u64).clamp(1, 10) as u32,
+        base: Duration::from_millis(read("BASE_MILLIS", base_millis)),
+        ceiling: Duration::from_millis(read("CEILING_MILLIS", ceiling_millis)),
+        jitter_percent: read("JITTER_PERCENT", jitter_percent as u64).min(100) as u32,
+    }
+}
+
+///
+/// Reads deletion limits from `<prefix>_MAX_FILES_PER_SEC`, `<prefix>_MAX_BYTES_PER_SEC` and
+/// `<prefix>_IDLE_IO`. Missing, invalid and zero limits don't limit.
+///
+fn throttle_setting(overrides: &Overrides, prefix: &str) -> DeleteThrottle {
+    let limit = |suffix: &str| {
+        let name = format!("{}_{}", prefix, suffix);
+        let value = setting(overrides, &name)?;
+        match value.parse::<u64>() {
+            Ok(0) => None,
+            Ok(limit) => Some(limit),
+            Err(_) => {
+                eprintln!("Ignoring invalid {} value: {}", name, value);
+                None
+            }
+        }
+    };
+
+    DeleteThrottle {
+        max_files_per_sec: limit("MAX_FILES_PER_SEC"),
+        max_bytes_per_sec: limit("MAX_BYTES_PER_SEC"),
+        idle_io: match setting(overrides, &format!("{}_IDLE_IO", prefix)) {
+            Some(value) => value.to_lowercase() == "true",
+            None => false,
+        },
+    }
+}
+
+///
+/// Reads positive number of days of setting `name`. `None` when missing or invalid.
+///
+fn days_setting(overrides: &Overrides, name: &str) -> Option<i64> {
+    let value = setting(overrides, name)?;
+    match value.parse::<i64>() {
+        Ok(days) if days > 0 => Some(days),
+        _ => {
+            eprintln!("Ignoring invalid {} value: {}", name, value);
+            None
+        }
+    }
+}
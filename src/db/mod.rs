@@ -1,6 +1,20 @@
+use std::cell::Cell;
 use std::env;
+use std::future::Future;
+use std::time::{Duration, Instant};
 
-use sqlx::{Executor, PgPool};
+use futures_util::future::BoxFuture;
+use futures_util::stream::BoxStream;
+use futures_util::StreamExt;
+use sqlx::postgres::{PgQueryResult, PgRow, PgStatement, PgTypeInfo};
+use sqlx::{Describe, Either, Execute, Executor, PgPool, Postgres};
+
+use crate::secrets::{self, SecretStore};
+
+tokio::task_local! {
+    /// Time spent in queries by the request being handled. See `measure_db_time`.
+    static DB_TIME: Cell<Duration>;
+}
 
 ///
 /// Connection pool for database connection to safely pass around threads.
@@ -9,6 +23,99 @@ pub struct DBWrapper {
     pub pool: PgPool,
 }
 
+impl DBWrapper {
+    ///
+    /// Executor on the pool counting the time of its queries into the DB time of the request.
+    ///
+    pub fn timed(&self) -> TimedPool<'_> {
+        TimedPool(&self.pool)
+    }
+}
+
+///
+/// Runs `future` and returns its output with the time spent in queries run through
+/// `DBWrapper::timed` meanwhile. Queries of transactions and of spawned tasks aren't counted.
+///
+pub async fn measure_db_time<F: Future>(future: F) -> (F::Output, Duration) {
+    DB_TIME
+        .scope(Cell::new(Duration::ZERO), async move {
+            let output = future.await;
+            (output, DB_TIME.with(Cell::get))
+        })
+        .await
+}
+
+/// Adds the time until it is dropped to the DB time of the request, if any.
+struct QueryTimer(Instant);
+
+impl Drop for QueryTimer {
+    fn drop(&mut self) {
+        let elapsed = self.0.elapsed();
+        let _ = DB_TIME.try_with(|time| time.set(time.get() + elapsed));
+    }
+}
+
+///
+/// Pool executor timing its queries, see `DBWrapper::timed`.
+///
+#[derive(Debug, Clone, Copy)]
+pub struct TimedPool<'p>(&'p PgPool);
+
+impl<'p> Executor<'p> for TimedPool<'p> {
+    type Database = Postgres;
+
+    fn fetch_many<'e, 'q: 'e, E: 'q>(
+        self,
+        query: E,
+    ) -> BoxStream<'e, Result<Either<PgQueryResult, PgRow>, sqlx::Error>>
+    where
+        'p: 'e,
+        E: Execute<'q, Postgres>,
+    {
+        let timer = QueryTimer(Instant::now());
+        Box::pin(self.0.fetch_many(query).map(move |item| {
+            let _timer = &timer;
+            item
+        }))
+    }
+
+    fn fetch_optional<'e, 'q: 'e, E: 'q>(
+        self,
+        query: E,
+    ) -> BoxFuture<'e, Result<Option<PgRow>, sqlx::Error>>
+    where
+        'p: 'e,
+        E: Execute<'q, Postgres>,
+    {
+        let future = self.0.fetch_optional(query);
+        Box::pin(async move {
+            let _timer = QueryTimer(Instant::now());
+            future.await
+        })
+    }
+
+    fn prepare_with<'e, 'q: 'e>(
+        self,
+        sql: &'q str,
+        parameters: &'e [PgTypeInfo],
+    ) -> BoxFuture<'e, Result<PgStatement<'q>, sqlx::Error>>
+    where
+        'p: 'e,
+    {
+        self.0.prepare_with(sql, parameters)
+    }
+
+    fn describe<'e, 'q: 'e>(
+        self,
+        sql: &'q str,
+    ) -> BoxFuture<'e, Result<Describe<Postgres>, sqlx::Error>>
+    where
+        'p: 'e,
+    {
+        self.0.describe(sql)
+    }
+}
+
 // Table creation query
 const CREATE_TABLE_BACKGROUND_REMOVER_TASK_SQL: &str = r#"
     CREATE TABLE IF NOT EXISTS background_remover_task(
@@ -29,396 +136,4472 @@ const CREATE_TABLE_BACKGROUND_REMOVER_TASK_SQL: &str = r#"
     )
 "#;
 
-///
-/// Configures initial database operations such as creating a table if not exist.
-///
-pub async fn setup() -> Result<DBWrapper, std::io::Error> {
-    // Extract postgres url
-    let postgres_url = match env::var("POSTGRES_URL") {
-        Ok(value) => value,
-        Err(error) => {
-            log::error!("Failed to read POSTGRES_URL from environment variable. Probably missing.");
-            return Err(std::io::Error::other(error));
-        }
-    };
+// Media audit findings. Each run of the audit shares the same `run_id`.
+const CREATE_TABLE_MEDIA_AUDIT_REPORT_SQL: &str = r#"
+    CREATE TABLE IF NOT EXISTS media_audit_report(
+        id BIGSERIAL PRIMARY KEY,
+        run_id UUID NOT NULL,
+        date_created TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP NOT NULL,
+        task_key UUID NOT NULL,
+        column_name VARCHAR(255) NOT NULL,
+        path TEXT NOT NULL,
+        nullified BOOLEAN DEFAULT FALSE NOT NULL
+    )
+"#;
 
-    return match PgPool::connect(&postgres_url).await {
-        Ok(pool) => match pool.execute(CREATE_TABLE_BACKGROUND_REMOVER_TASK_SQL).await {
-            Ok(_) => Ok(DBWrapper { pool }),
-            Err(error) => {
-                println!("Failed to create required tables.");
-                return Err(std::io::Error::other(error));
-            }
-        },
-        Err(error) => {
-            return Err(std::io::Error::other(error));
-        }
-    };
-}
+// Daily task counts per country and status, maintained by the stats rollup job.
+const CREATE_TABLE_DAILY_TASK_STATS_SQL: &str = r#"
+    CREATE TABLE IF NOT EXISTS daily_task_stats(
+        day DATE NOT NULL,
+        country VARCHAR(255) NOT NULL,
+        status VARCHAR(255) NOT NULL,
+        total BIGINT NOT NULL,
+        PRIMARY KEY (day, country, status)
+    )
+"#;
 
-pub mod models {
-    use std::env;
-    use std::fmt::Debug;
-    use std::path::PathBuf;
-    use std::sync::Arc;
+// Tasks moved out of the hot table by the archive job. Shares the exact column layout of
+// `background_remover_task`, so columns added to one table must be added to the other as well.
+const CREATE_TABLE_ARCHIVED_BACKGROUND_REMOVER_TASK_SQL: &str = r#"
+    CREATE TABLE IF NOT EXISTS archived_background_remover_task(
+        LIKE background_remover_task INCLUDING ALL
+    )
+"#;
 
-    use serde::ser::{Error, SerializeStruct};
-    use serde::{Serialize, Serializer};
-    use serde_json::Value;
+// Refinements of a task from user brush-stroke corrections. Not a foreign key because tasks may
+// be moved to the archive table.
+const CREATE_TABLE_TASK_REVISION_SQL: &str = r#"
+    CREATE TABLE IF NOT EXISTS task_revision(
+        revision_id BIGSERIAL PRIMARY KEY,
+        date_created TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP NOT NULL,
+        key UUID UNIQUE NOT NULL,
+        task_key UUID NOT NULL,
+        revision INTEGER NOT NULL,
+        correction_image_path TEXT NOT NULL,
+        mask_image_path TEXT,
+        processed_image_path TEXT,
+        preview_processed_image_path TEXT,
+        processing BOOLEAN DEFAULT FALSE,
+        UNIQUE (task_key, revision)
+    )
+"#;
 
-    use sqlx::types::chrono::Utc;
-    use sqlx::Executor;
+// Revisions are also created when an already processed task is processed again. Those have no
+// correction image.
+const ALTER_TABLE_TASK_REVISION_ADD_KIND_SQL: &str = r#"
+    ALTER TABLE task_revision
+        ADD COLUMN IF NOT EXISTS kind VARCHAR(32) NOT NULL DEFAULT 'refinement',
+        ALTER COLUMN correction_image_path DROP NOT NULL
+"#;
 
-    use chrono::DateTime;
-    use uuid::Uuid;
+// QA scores of comparing outputs of two revisions of a task. Revision 0 is the task itself.
+const CREATE_TABLE_REVISION_COMPARISON_SQL: &str = r#"
+    CREATE TABLE IF NOT EXISTS revision_comparison(
+        id BIGSERIAL PRIMARY KEY,
+        date_created TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP NOT NULL,
+        task_key UUID NOT NULL,
+        revision_a INTEGER NOT NULL,
+        revision_b INTEGER NOT NULL,
+        mask_iou DOUBLE PRECISION NOT NULL,
+        mean_pixel_diff DOUBLE PRECISION NOT NULL,
+        heatmap_path TEXT NOT NULL
+    )
+"#;
 
-    use crate::db::DBWrapper;
-    use crate::utils::path_utils;
+// Latency and quality of tasks mirrored to the canary BP server, compared to the primary one.
+const CREATE_TABLE_CANARY_RESULT_SQL: &str = r#"
+    CREATE TABLE IF NOT EXISTS canary_result(
+        task_key UUID PRIMARY KEY,
+        date_created TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP NOT NULL,
+        primary_millis BIGINT,
+        canary_millis BIGINT,
+        canary_mask_path TEXT,
+        mask_iou DOUBLE PRECISION
+    )
+"#;
 
-    ///
-    /// This struct is the mapped columns of table `background_remover_task`.
-    ///
-    #[derive(Debug, sqlx::FromRow)]
-    pub struct BackgroundRemoverTask {
-        /// Auto incremented unique integer for each background removal task.
-        pub task_id: i64,
-        /// Date when this removal task is created.
-        pub date_created: DateTime<Utc>,
-        /// Unique string for each task.
-        pub key: Uuid,
-        /// Unique string for websocket group used for listening websocket messags.
-        pub task_group: Uuid,
-        /// Relative path: media/image.jpg
-        pub original_image_path: String,
-        /// Relative path: media/image.png
-        pub preview_original_image_path: Option<String>,
-        /// Relative path: media/image.png
-        pub mask_image_path: Option<String>,
-        /// Relative path: media/image.png
-        pub processed_image_path: Option<String>,
-        /// Relative path: media/image.png
-        pub preview_processed_image_path: Option<String>,
-        /// Background removal status.
-        pub processing: Option<bool>,
-        /// Country from where photo is uploaded.
-        pub country: Option<String>,
-        /// Encoded string to identiy user.
-        pub user_identifier: Option<String>,
-        /// Task logs.
-        pub logs: Option<Value>,
-    }
+// Email notified once every task of a task group finished.
+const CREATE_TABLE_TASK_GROUP_NOTIFICATION_SQL: &str = r#"
+    CREATE TABLE IF NOT EXISTS task_group_notification(
+        task_group UUID PRIMARY KEY,
+        email VARCHAR(254) NOT NULL,
+        date_created TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP NOT NULL,
+        date_sent TIMESTAMPTZ
+    )
+"#;
 
-    ///
-    /// Serde JSON custom serialize implementation. It modifies field values for `path` fields.
-    ///
-    impl Serialize for BackgroundRemoverTask {
-        fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-        where
-            S: Serializer,
-        {
-            let mut state = serializer.serialize_struct("BackgroundRemoverTask", 11)?;
-            state.serialize_field("task_id", &self.task_id)?;
-            state.serialize_field("date_created", &self.date_created.to_string())?;
-            state.serialize_field("key", &self.key)?;
-            state.serialize_field("task_group", &self.task_group)?;
+// Completion emails whose sending failed are released with `failed_attempts` incremented and
+// retried by the outbox job.
+const ALTER_TABLE_TASK_GROUP_NOTIFICATION_ADD_FAILED_ATTEMPTS_SQL: &str = r#"
+    ALTER TABLE task_group_notification
+        ADD COLUMN IF NOT EXISTS failed_attempts INT DEFAULT 0 NOT NULL
+"#;
 
-            // Url configurations from environment variables.
-            let scheme = "https";
-            let host = match env::var("HOST") {
-                Ok(value) => value,
-                Err(error) => {
-                    return Err(Error::custom(error));
-                }
-            };
+// Notifications of finished tasks, written in the transaction storing the result and marked sent
+// once delivered. Entries left unsent by a crash are delivered by the outbox job.
+const CREATE_TABLE_NOTIFICATION_OUTBOX_SQL: &str = r#"
+    CREATE TABLE IF NOT EXISTS notification_outbox(
+        id BIGSERIAL PRIMARY KEY,
+        task_key UUID NOT NULL,
+        task_group UUID NOT NULL,
+        kind VARCHAR(32) NOT NULL,
+        attempts INTEGER DEFAULT 0 NOT NULL,
+        date_created TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP NOT NULL,
+        date_claimed TIMESTAMPTZ,
+        date_sent TIMESTAMPTZ
+    )
+"#;
 
-            // Adds full original image url to JSON object.
-            let full_original_image_url = path_utils::full_media_url_from_relative_path(
-                scheme,
-                &host,
-                PathBuf::from(&self.original_image_path),
-            );
-            state.serialize_field("original_image", &full_original_image_url)?;
+const CREATE_INDEX_NOTIFICATION_OUTBOX_PENDING_SQL: &str = r#"
+    CREATE INDEX IF NOT EXISTS notification_outbox_pending_idx
+        ON notification_outbox (date_created) WHERE date_sent IS NULL
+"#;
 
-            // Adds full media image url to JSON object.
-            let full_media_preview_image_url;
-            if let Some(preview_original_path) = &self.preview_original_image_path {
-                full_media_preview_image_url = Some(path_utils::full_media_url_from_relative_path(
-                    scheme,
-                    &host,
-                    PathBuf::from(preview_original_path),
-                ));
-            } else {
-                full_media_preview_image_url = None;
-            }
-            state.serialize_field("preview_original_image", &full_media_preview_image_url)?;
+// Output files of a task as saved, one row per task. Serializers and the media garbage collector
+// read paths from here instead of deriving them from the storage layout.
+const CREATE_TABLE_TASK_MANIFEST_SQL: &str = r#"
+    CREATE TABLE IF NOT EXISTS task_manifest(
+        task_key UUID PRIMARY KEY,
+        storage_backend VARCHAR(32) NOT NULL,
+        files JSONB NOT NULL,
+        date_updated TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP NOT NULL
+    )
+"#;
 
-            // Adds full processed image url to JSON object.
-            let full_processed_original_image_url;
-            if let Some(processed_original_path) = &self.processed_image_path {
-                full_processed_original_image_url =
-                    Some(path_utils::full_media_url_from_relative_path(
-                        scheme,
-                        &host,
-                        PathBuf::from(processed_original_path),
-                    ));
-            } else {
-                full_processed_original_image_url = None;
-            }
+// Settings overriding environment variables of the same name, reloaded without restart.
+const CREATE_TABLE_APP_CONFIG_SQL: &str = r#"
+    CREATE TABLE IF NOT EXISTS app_config(
+        name VARCHAR(255) PRIMARY KEY,
+        value TEXT NOT NULL,
+        date_updated TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP NOT NULL
+    )
+"#;
 
-            state.serialize_field("processed_image", &full_processed_original_image_url)?;
+// Every applied settings change. `NULL` values mean the setting was not overridden.
+const CREATE_TABLE_CONFIG_AUDIT_LOG_SQL: &str = r#"
+    CREATE TABLE IF NOT EXISTS config_audit_log(
+        id BIGSERIAL PRIMARY KEY,
+        date_created TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP NOT NULL,
+        name VARCHAR(255) NOT NULL,
+        old_value TEXT,
+        new_value TEXT
+    )
+"#;
 
-            let full_preview_processed_image_url;
-            if let Some(preview_processed_path) = &self.preview_processed_image_path {
-                full_preview_processed_image_url =
-                    Some(path_utils::full_media_url_from_relative_path(
-                        scheme,
-                        &host,
-                        PathBuf::from(preview_processed_path),
-                    ));
-            } else {
-                full_preview_processed_image_url = None;
-            }
+// Filename sent by the client, kept for display only. Files are stored under their content hash.
+// Added to both task tables so archiving keeps matching column layouts.
+const ALTER_TABLE_TASK_ADD_ORIGINAL_FILENAME_SQL: &str = r#"
+    ALTER TABLE background_remover_task ADD COLUMN IF NOT EXISTS original_filename TEXT
+"#;
 
-            state.serialize_field("preview_processed_image", &full_preview_processed_image_url)?;
+const ALTER_TABLE_ARCHIVED_TASK_ADD_ORIGINAL_FILENAME_SQL: &str = r#"
+    ALTER TABLE archived_background_remover_task ADD COLUMN IF NOT EXISTS original_filename TEXT
+"#;
 
-            let full_mask_image_url;
-            if let Some(preview_mask_path) = &self.mask_image_path {
-                full_mask_image_url = Some(path_utils::full_media_url_from_relative_path(
-                    scheme,
-                    &host,
-                    PathBuf::from(preview_mask_path),
-                ));
-            } else {
-                full_mask_image_url = None;
-            }
+// Bumped on every update of a task, e.g. to invalidate cached serializations.
+const ALTER_TABLE_TASK_ADD_DATE_UPDATED_SQL: &str = r#"
+    ALTER TABLE background_remover_task
+        ADD COLUMN IF NOT EXISTS date_updated TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+"#;
 
-            state.serialize_field("mask_image", &full_mask_image_url)?;
+const ALTER_TABLE_ARCHIVED_TASK_ADD_DATE_UPDATED_SQL: &str = r#"
+    ALTER TABLE archived_background_remover_task
+        ADD COLUMN IF NOT EXISTS date_updated TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+"#;
 
-            state.serialize_field("processing", &self.processing)?;
-            state.serialize_field("user_identifier", &self.user_identifier)?;
-            state.serialize_field("country", &self.country)?;
-            state.serialize_field("logs", &self.logs)?;
-            state.end()
-        }
-    }
+// `clock_timestamp` instead of `now`, so updates within one transaction still differ.
+const CREATE_FUNCTION_TOUCH_DATE_UPDATED_SQL: &str = r#"
+    CREATE OR REPLACE FUNCTION touch_date_updated() RETURNS TRIGGER AS $$
+    BEGIN
+        NEW.date_updated = clock_timestamp();
+        RETURN NEW;
+    END;
+    $$ LANGUAGE plpgsql
+"#;
 
-    ///
-    /// Partially mapped column for table `background_remover_task`.
-    /// Contains necessary fields required for new record insertion in the database.
-    ///
-    pub struct NewBackgroundRemoverTask {
-        pub key: Uuid,
-        pub task_group: Uuid,
-        pub original_image_path: String,
-        pub preview_original_image_path: String,
-        pub country: Option<String>,
-        pub user_identifier: Option<String>,
-    }
+const DROP_TRIGGER_TASK_DATE_UPDATED_SQL: &str = r#"
+    DROP TRIGGER IF EXISTS background_remover_task_date_updated ON background_remover_task
+"#;
 
-    ///
-    /// Partially mapped column for table `background_remover_task`.
-    /// Contains necessary fields required for updating existing record in the database.
-    ///
-    pub struct UpdateBackgroundRemoverTask {
-        pub key: Uuid,
-        pub mask_image_path: String,
-        pub processed_image_path: String,
-        pub preview_processed_image_path: String,
-        pub logs: Option<Value>,
-    }
+const CREATE_TRIGGER_TASK_DATE_UPDATED_SQL: &str = r#"
+    CREATE TRIGGER background_remover_task_date_updated
+        BEFORE UPDATE ON background_remover_task
+        FOR EACH ROW EXECUTE FUNCTION touch_date_updated()
+"#;
 
-    ///
-    /// Implementations for `BackgroundRemoverTask` model
-    ///
-    impl BackgroundRemoverTask {
-        ///
-        /// Also serialized auto increment column `task_id` and `logs` which may leak actual
-        /// available items count if accessible to users.
-        ///
-        pub fn serialize_full(&self) -> Result<Value, serde_json::Error> {
-            serde_json::to_value(&self)
-        }
+// Latest progress reported by the BP server while a task is processed.
+const ALTER_TABLE_TASK_ADD_PROGRESS_SQL: &str = r#"
+    ALTER TABLE background_remover_task ADD COLUMN IF NOT EXISTS progress JSONB
+"#;
 
-        ///
-        /// This does not include `task_id` and `logs` field and values.
-        ///
-        pub fn serialize(&self) -> Result<Value, serde_json::Error> {
-            let mut serialized_full = match self.serialize_full() {
-                Ok(value) => value,
-                Err(error) => {
-                    return Err(error);
-                }
-            };
+const ALTER_TABLE_ARCHIVED_TASK_ADD_PROGRESS_SQL: &str = r#"
+    ALTER TABLE archived_background_remover_task ADD COLUMN IF NOT EXISTS progress JSONB
+"#;
 
-            const REMOVE_FIELDS: [&str; 3] = ["task_id", "country", "logs"];
-            let map_object = serialized_full.as_object_mut();
+// BP worker and model which produced the result of a task.
+const ALTER_TABLE_TASK_ADD_BP_IDENTITY_SQL: &str = r#"
+    ALTER TABLE background_remover_task
+        ADD COLUMN IF NOT EXISTS bp_worker_id VARCHAR(255),
+        ADD COLUMN IF NOT EXISTS bp_model_version VARCHAR(255)
+"#;
 
-            if let Some(map) = map_object {
-                REMOVE_FIELDS.iter().for_each(|field| {
+const ALTER_TABLE_ARCHIVED_TASK_ADD_BP_IDENTITY_SQL: &str = r#"
+    ALTER TABLE archived_background_remover_task
+        ADD COLUMN IF NOT EXISTS bp_worker_id VARCHAR(255),
+        ADD COLUMN IF NOT EXISTS bp_model_version VARCHAR(255)
+"#;
+
+// Client supplied metadata object and tags, used by integrators to correlate tasks.
+const ALTER_TABLE_TASK_ADD_METADATA_SQL: &str = r#"
+    ALTER TABLE background_remover_task
+        ADD COLUMN IF NOT EXISTS metadata JSONB,
+        ADD COLUMN IF NOT EXISTS tags TEXT[] DEFAULT '{}' NOT NULL
+"#;
+
+const ALTER_TABLE_ARCHIVED_TASK_ADD_METADATA_SQL: &str = r#"
+    ALTER TABLE archived_background_remover_task
+        ADD COLUMN IF NOT EXISTS metadata JSONB,
+        ADD COLUMN IF NOT EXISTS tags TEXT[] DEFAULT '{}' NOT NULL
+"#;
+
+const CREATE_INDEX_TASK_TAGS_SQL: &str = r#"
+    CREATE INDEX IF NOT EXISTS background_remover_task_tags_idx
+        ON background_remover_task USING GIN (tags)
+"#;
+
+const CREATE_INDEX_TASK_METADATA_SQL: &str = r#"
+    CREATE INDEX IF NOT EXISTS background_remover_task_metadata_idx
+        ON background_remover_task USING GIN (metadata jsonb_path_ops)
+"#;
+
+// Format of the original image detected from its content, e.g. `jpg`, `webp` or `jxl`.
+const ALTER_TABLE_TASK_ADD_ORIGINAL_FORMAT_SQL: &str = r#"
+    ALTER TABLE background_remover_task ADD COLUMN IF NOT EXISTS original_format VARCHAR(16)
+"#;
+
+const ALTER_TABLE_ARCHIVED_TASK_ADD_ORIGINAL_FORMAT_SQL: &str = r#"
+    ALTER TABLE archived_background_remover_task ADD COLUMN IF NOT EXISTS original_format VARCHAR(16)
+"#;
+
+// Outputs requested on upload. See `TaskOutputs`.
+const ALTER_TABLE_TASK_ADD_OUTPUTS_SQL: &str = r#"
+    ALTER TABLE background_remover_task
+        ADD COLUMN IF NOT EXISTS outputs VARCHAR(16) DEFAULT 'all' NOT NULL
+"#;
+
+const ALTER_TABLE_ARCHIVED_TASK_ADD_OUTPUTS_SQL: &str = r#"
+    ALTER TABLE archived_background_remover_task
+        ADD COLUMN IF NOT EXISTS outputs VARCHAR(16) DEFAULT 'all' NOT NULL
+"#;
+
+// Background hint sent to the BP server to pick a specialized model.
+const ALTER_TABLE_TASK_ADD_BACKGROUND_HINT_SQL: &str = r#"
+    ALTER TABLE background_remover_task ADD COLUMN IF NOT EXISTS background_hint VARCHAR(32)
+"#;
+
+const ALTER_TABLE_ARCHIVED_TASK_ADD_BACKGROUND_HINT_SQL: &str = r#"
+    ALTER TABLE archived_background_remover_task
+        ADD COLUMN IF NOT EXISTS background_hint VARCHAR(32)
+"#;
+
+// Fingerprint of the preview settings the previews of a task were last generated with.
+const ALTER_TABLE_TASK_ADD_PREVIEW_SETTINGS_SQL: &str = r#"
+    ALTER TABLE background_remover_task ADD COLUMN IF NOT EXISTS preview_settings VARCHAR(64)
+"#;
+
+const ALTER_TABLE_ARCHIVED_TASK_ADD_PREVIEW_SETTINGS_SQL: &str = r#"
+    ALTER TABLE archived_background_remover_task
+        ADD COLUMN IF NOT EXISTS preview_settings VARCHAR(64)
+"#;
+
+// Id of the latest dispatch to the BP server and of the dispatch whose result was stored. BP
+// servers echo the id, so a result delivered twice is only stored once.
+const ALTER_TABLE_TASK_ADD_REQUEST_IDS_SQL: &str = r#"
+    ALTER TABLE background_remover_task
+        ADD COLUMN IF NOT EXISTS bp_request_id UUID,
+        ADD COLUMN IF NOT EXISTS completed_request_id UUID
+"#;
+
+const ALTER_TABLE_ARCHIVED_TASK_ADD_REQUEST_IDS_SQL: &str = r#"
+    ALTER TABLE archived_background_remover_task
+        ADD COLUMN IF NOT EXISTS bp_request_id UUID,
+        ADD COLUMN IF NOT EXISTS completed_request_id UUID
+"#;
+
+// Outputs of free tier tasks are watermarked. Their unwatermarked processed image is kept in the
+// task manifest, and in `unwatermarked_image_path` for revisions.
+const ALTER_TABLE_TASK_ADD_FREE_TIER_SQL: &str = r#"
+    ALTER TABLE background_remover_task
+        ADD COLUMN IF NOT EXISTS free_tier BOOLEAN NOT NULL DEFAULT FALSE
+"#;
+
+const ALTER_TABLE_ARCHIVED_TASK_ADD_FREE_TIER_SQL: &str = r#"
+    ALTER TABLE archived_background_remover_task
+        ADD COLUMN IF NOT EXISTS free_tier BOOLEAN NOT NULL DEFAULT FALSE
+"#;
+
+const ALTER_TABLE_TASK_REVISION_ADD_UNWATERMARKED_SQL: &str = r#"
+    ALTER TABLE task_revision ADD COLUMN IF NOT EXISTS unwatermarked_image_path TEXT
+"#;
+
+// Outputs of free tier tasks are scaled down to a pixel cap, recorded in `output_max_pixels`. The
+// received mask is kept in the task manifest, and in `uncapped_mask_image_path` for revisions.
+const ALTER_TABLE_TASK_ADD_OUTPUT_MAX_PIXELS_SQL: &str = r#"
+    ALTER TABLE background_remover_task ADD COLUMN IF NOT EXISTS output_max_pixels BIGINT
+"#;
+
+const ALTER_TABLE_ARCHIVED_TASK_ADD_OUTPUT_MAX_PIXELS_SQL: &str = r#"
+    ALTER TABLE archived_background_remover_task ADD COLUMN IF NOT EXISTS output_max_pixels BIGINT
+"#;
+
+const ALTER_TABLE_TASK_REVISION_ADD_UNCAPPED_MASK_SQL: &str = r#"
+    ALTER TABLE task_revision ADD COLUMN IF NOT EXISTS uncapped_mask_image_path TEXT
+"#;
+
+// Upload signing key id of the task and the priority of its billing plan, sent to the BP server.
+const ALTER_TABLE_TASK_ADD_BILLING_SQL: &str = r#"
+    ALTER TABLE background_remover_task
+        ADD COLUMN IF NOT EXISTS api_key_id VARCHAR(64),
+        ADD COLUMN IF NOT EXISTS priority INTEGER DEFAULT 0 NOT NULL
+"#;
+
+const ALTER_TABLE_ARCHIVED_TASK_ADD_BILLING_SQL: &str = r#"
+    ALTER TABLE archived_background_remover_task
+        ADD COLUMN IF NOT EXISTS api_key_id VARCHAR(64),
+        ADD COLUMN IF NOT EXISTS priority INTEGER DEFAULT 0 NOT NULL
+"#;
+
+// Stripe subscription of an upload signing key id, kept up to date by subscription webhooks.
+const CREATE_TABLE_BILLING_PLAN_SQL: &str = r#"
+    CREATE TABLE IF NOT EXISTS billing_plan(
+        key_id VARCHAR(64) PRIMARY KEY,
+        stripe_customer_id VARCHAR(255) NOT NULL,
+        stripe_subscription_id VARCHAR(255) NOT NULL,
+        stripe_subscription_item_id VARCHAR(255),
+        plan VARCHAR(255),
+        status VARCHAR(32) NOT NULL,
+        monthly_quota BIGINT,
+        priority INTEGER DEFAULT 0 NOT NULL,
+        date_updated TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP NOT NULL
+    )
+"#;
+
+// Creation time of the last subscription event applied to the plan, as Stripe delivers events
+// out of order.
+const ALTER_TABLE_BILLING_PLAN_ADD_EVENT_CREATED_SQL: &str = r#"
+    ALTER TABLE billing_plan ADD COLUMN IF NOT EXISTS event_created TIMESTAMPTZ
+"#;
+
+// Successful tasks of signed uploads, written with the result. Counted against quotas and
+// reported to Stripe, after which `report_id` is set.
+const CREATE_TABLE_API_KEY_USAGE_SQL: &str = r#"
+    CREATE TABLE IF NOT EXISTS api_key_usage(
+        task_key UUID PRIMARY KEY,
+        key_id VARCHAR(64) NOT NULL,
+        date_created TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP NOT NULL,
+        report_id BIGINT
+    )
+"#;
+
+const CREATE_INDEX_API_KEY_USAGE_KEY_ID_SQL: &str = r#"
+    CREATE INDEX IF NOT EXISTS api_key_usage_key_id_idx ON api_key_usage (key_id, date_created)
+"#;
+
+const CREATE_INDEX_API_KEY_USAGE_UNREPORTED_SQL: &str = r#"
+    CREATE INDEX IF NOT EXISTS api_key_usage_unreported_idx
+        ON api_key_usage (key_id) WHERE report_id IS NULL
+"#;
+
+// Usage records for Stripe. Written before they are sent and resent with their id as idempotency
+// key until Stripe accepts them.
+const CREATE_TABLE_BILLING_USAGE_REPORT_SQL: &str = r#"
+    CREATE TABLE IF NOT EXISTS billing_usage_report(
+        id BIGSERIAL PRIMARY KEY,
+        key_id VARCHAR(64) NOT NULL,
+        stripe_subscription_item_id VARCHAR(255) NOT NULL,
+        quantity BIGINT NOT NULL,
+        attempts INTEGER DEFAULT 0 NOT NULL,
+        date_created TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP NOT NULL,
+        date_claimed TIMESTAMPTZ,
+        date_sent TIMESTAMPTZ
+    )
+"#;
+
+// Organizations own upload signing key ids and have members. See `organization_utils::Role`.
+const CREATE_TABLE_ORGANIZATION_SQL: &str = r#"
+    CREATE TABLE IF NOT EXISTS organization(
+        id BIGSERIAL PRIMARY KEY,
+        name VARCHAR(255) NOT NULL,
+        date_created TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP NOT NULL
+    )
+"#;
+
+// A key id belongs to at most one organization.
+const CREATE_TABLE_ORGANIZATION_API_KEY_SQL: &str = r#"
+    CREATE TABLE IF NOT EXISTS organization_api_key(
+        key_id VARCHAR(64) PRIMARY KEY,
+        organization_id BIGINT NOT NULL REFERENCES organization(id) ON DELETE CASCADE,
+        date_created TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP NOT NULL
+    )
+"#;
+
+// Members authenticate with a token of which only the SHA-256 is stored.
+const CREATE_TABLE_ORGANIZATION_MEMBER_SQL: &str = r#"
+    CREATE TABLE IF NOT EXISTS organization_member(
+        id BIGSERIAL PRIMARY KEY,
+        organization_id BIGINT NOT NULL REFERENCES organization(id) ON DELETE CASCADE,
+        email VARCHAR(254) NOT NULL,
+        role VARCHAR(16) NOT NULL,
+        token_hash VARCHAR(64) NOT NULL UNIQUE,
+        date_created TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP NOT NULL,
+        UNIQUE (organization_id, email)
+    )
+"#;
+
+// Organization the key belonged to when the task succeeded, so moving a key doesn't move its
+// past usage.
+const ALTER_TABLE_API_KEY_USAGE_ADD_ORGANIZATION_SQL: &str = r#"
+    ALTER TABLE api_key_usage ADD COLUMN IF NOT EXISTS organization_id BIGINT
+"#;
+
+const CREATE_INDEX_API_KEY_USAGE_ORGANIZATION_SQL: &str = r#"
+    CREATE INDEX IF NOT EXISTS api_key_usage_organization_idx
+        ON api_key_usage (organization_id, date_created) WHERE organization_id IS NOT NULL
+"#;
+
+// Task listings of organizations filter by key id.
+const CREATE_INDEX_TASK_API_KEY_ID_SQL: &str = r#"
+    CREATE INDEX IF NOT EXISTS background_remover_task_api_key_id_idx
+        ON background_remover_task (api_key_id) WHERE api_key_id IS NOT NULL
+"#;
+
+// Media directory of the billing account of the task under `MEDIA_ROOT/tenants/`. `NULL` for
+// unsigned uploads, whose media stays directly under `MEDIA_ROOT`.
+const ALTER_TABLE_TASK_ADD_TENANT_SQL: &str = r#"
+    ALTER TABLE background_remover_task ADD COLUMN IF NOT EXISTS tenant VARCHAR(80)
+"#;
+
+const ALTER_TABLE_ARCHIVED_TASK_ADD_TENANT_SQL: &str = r#"
+    ALTER TABLE archived_background_remover_task ADD COLUMN IF NOT EXISTS tenant VARCHAR(80)
+"#;
+
+// Tasks with the same original image, options and tenant within `AppConfig::upload_dedup_window`
+// are served from the outputs of the earlier task, whose key is kept in `dedup_of`.
+// `content_sha256` is `NULL` for uploads which opted out with `X-No-Dedup`.
+const ALTER_TABLE_TASK_ADD_DEDUP_SQL: &str = r#"
+    ALTER TABLE background_remover_task
+        ADD COLUMN IF NOT EXISTS content_sha256 VARCHAR(64),
+        ADD COLUMN IF NOT EXISTS dedup_of UUID
+"#;
+
+const ALTER_TABLE_ARCHIVED_TASK_ADD_DEDUP_SQL: &str = r#"
+    ALTER TABLE archived_background_remover_task
+        ADD COLUMN IF NOT EXISTS content_sha256 VARCHAR(64),
+        ADD COLUMN IF NOT EXISTS dedup_of UUID
+"#;
+
+const CREATE_INDEX_TASK_CONTENT_SHA256_SQL: &str = r#"
+    CREATE INDEX IF NOT EXISTS background_remover_task_content_sha256_idx
+        ON background_remover_task (content_sha256) WHERE content_sha256 IS NOT NULL
+"#;
+
+// Milliseconds spent in each stage of the task by stage name, see `TaskStage`. Stages are merged
+// into the object as they finish, so tasks created before this column have only later stages.
+const ALTER_TABLE_TASK_ADD_TIMINGS_SQL: &str = r#"
+    ALTER TABLE background_remover_task ADD COLUMN IF NOT EXISTS timings JSONB
+"#;
+
+const ALTER_TABLE_ARCHIVED_TASK_ADD_TIMINGS_SQL: &str = r#"
+    ALTER TABLE archived_background_remover_task ADD COLUMN IF NOT EXISTS timings JSONB
+"#;
+
+// Stage timing stats aggregate the timings of tasks created within a range of days.
+const CREATE_INDEX_TASK_TIMINGS_DATE_CREATED_SQL: &str = r#"
+    CREATE INDEX IF NOT EXISTS background_remover_task_timings_date_created_idx
+        ON background_remover_task (date_created) WHERE timings IS NOT NULL
+"#;
+
+const CREATE_INDEX_ARCHIVED_TASK_TIMINGS_DATE_CREATED_SQL: &str = r#"
+    CREATE INDEX IF NOT EXISTS archived_background_remover_task_timings_date_created_idx
+        ON archived_background_remover_task (date_created) WHERE timings IS NOT NULL
+"#;
+
+// When `result_status` was last written, cleared with it on dispatch. Unlike `date_updated`, it
+// isn't bumped by later writes such as anonymization, so the ops monitor counts each result once.
+const ALTER_TABLE_TASK_ADD_DATE_COMPLETED_SQL: &str = r#"
+    ALTER TABLE background_remover_task ADD COLUMN IF NOT EXISTS date_completed TIMESTAMPTZ
+"#;
+
+const ALTER_TABLE_ARCHIVED_TASK_ADD_DATE_COMPLETED_SQL: &str = r#"
+    ALTER TABLE archived_background_remover_task ADD COLUMN IF NOT EXISTS date_completed TIMESTAMPTZ
+"#;
+
+const CREATE_INDEX_TASK_DATE_COMPLETED_SQL: &str = r#"
+    CREATE INDEX IF NOT EXISTS background_remover_task_date_completed_idx
+        ON background_remover_task (date_completed) WHERE date_completed IS NOT NULL
+"#;
+
+// Erasure requests look up every task of a user identifier.
+const CREATE_INDEX_TASK_USER_IDENTIFIER_SQL: &str = r#"
+    CREATE INDEX IF NOT EXISTS background_remover_task_user_identifier_idx
+        ON background_remover_task (user_identifier) WHERE user_identifier IS NOT NULL
+"#;
+
+const CREATE_INDEX_ARCHIVED_TASK_USER_IDENTIFIER_SQL: &str = r#"
+    CREATE INDEX IF NOT EXISTS archived_background_remover_task_user_identifier_idx
+        ON archived_background_remover_task (user_identifier) WHERE user_identifier IS NOT NULL
+"#;
+
+// Reports of erased users, kept as proof of erasure. They list task keys and counts only, never
+// the user identifier.
+const CREATE_TABLE_USER_ERASURE_SQL: &str = r#"
+    CREATE TABLE IF NOT EXISTS user_erasure(
+        id BIGSERIAL PRIMARY KEY,
+        date_created TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP NOT NULL,
+        task_count BIGINT NOT NULL,
+        report JSONB NOT NULL
+    )
+"#;
+
+// The anonymization job looks up old tasks which still have a country or user identifier.
+const CREATE_INDEX_TASK_NOT_ANONYMIZED_SQL: &str = r#"
+    CREATE INDEX IF NOT EXISTS background_remover_task_not_anonymized_idx
+        ON background_remover_task (date_created)
+        WHERE country IS NOT NULL OR user_identifier IS NOT NULL
+"#;
+
+const CREATE_INDEX_ARCHIVED_TASK_NOT_ANONYMIZED_SQL: &str = r#"
+    CREATE INDEX IF NOT EXISTS archived_background_remover_task_not_anonymized_idx
+        ON archived_background_remover_task (date_created)
+        WHERE country IS NOT NULL OR user_identifier IS NOT NULL
+"#;
+
+// The anonymization job also looks up old tasks whose metadata has user identifiers. Keys must
+// match `BackgroundRemoverTask::anonymize_older_than`.
+const CREATE_INDEX_TASK_METADATA_NOT_ANONYMIZED_SQL: &str = r#"
+    CREATE INDEX IF NOT EXISTS background_remover_task_metadata_not_anonymized_idx
+        ON background_remover_task (date_created)
+        WHERE metadata ?| ARRAY['user_id', 'user_identifier', 'email']
+"#;
+
+const CREATE_INDEX_ARCHIVED_TASK_METADATA_NOT_ANONYMIZED_SQL: &str = r#"
+    CREATE INDEX IF NOT EXISTS archived_background_remover_task_metadata_not_anonymized_idx
+        ON archived_background_remover_task (date_created)
+        WHERE metadata ?| ARRAY['user_id', 'user_identifier', 'email']
+"#;
+
+// Task messages exchanged with BP servers, without files, for debugging protocol disagreements.
+// Refinement messages carry the revision key as `task_key`. Capped by the archive job.
+const CREATE_TABLE_BP_MESSAGE_SQL: &str = r#"
+    CREATE TABLE IF NOT EXISTS bp_message(
+        id BIGSERIAL PRIMARY KEY,
+        date_created TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP NOT NULL,
+        task_key UUID NOT NULL,
+        bp_address TEXT NOT NULL,
+        direction VARCHAR(16) NOT NULL,
+        message JSONB NOT NULL
+    )
+"#;
+
+const CREATE_INDEX_BP_MESSAGE_TASK_KEY_SQL: &str = r#"
+    CREATE INDEX IF NOT EXISTS bp_message_task_key_idx ON bp_message (task_key)
+"#;
+
+// Last run of each cluster-wide scheduled job. `scheduled_for` is the run time claimed last, so
+// a run time is only claimed by one replica.
+const CREATE_TABLE_SCHEDULED_JOB_SQL: &str = r#"
+    CREATE TABLE IF NOT EXISTS scheduled_job(
+        name VARCHAR(255) PRIMARY KEY,
+        scheduled_for TIMESTAMPTZ NOT NULL,
+        last_started TIMESTAMPTZ NOT NULL,
+        last_finished TIMESTAMPTZ,
+        last_status VARCHAR(32) NOT NULL,
+        last_message TEXT,
+        last_instance TEXT NOT NULL,
+        runs BIGINT DEFAULT 0 NOT NULL,
+        failures BIGINT DEFAULT 0 NOT NULL
+    )
+"#;
+
+const CREATE_TABLE_LEASE_SQL: &str = r#"
+    CREATE TABLE IF NOT EXISTS lease(
+        name VARCHAR(255) PRIMARY KEY,
+        token UUID NOT NULL,
+        holder TEXT NOT NULL,
+        expires_at TIMESTAMPTZ NOT NULL
+    )
+"#;
+
+// Task group summaries and contact sheets look up all tasks of a group.
+const CREATE_INDEX_TASK_GROUP_SQL: &str = r#"
+    CREATE INDEX IF NOT EXISTS background_remover_task_task_group_idx
+        ON background_remover_task (task_group)
+"#;
+
+// Data migrations which already ran, so setup runs each of them once instead of on every start.
+const CREATE_TABLE_SCHEMA_MIGRATION_SQL: &str = r#"
+    CREATE TABLE IF NOT EXISTS schema_migration(
+        name VARCHAR(255) PRIMARY KEY,
+        date_applied TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP NOT NULL
+    )
+"#;
+
+// Fills `result_status` of tasks finished before it was written, once. Failures reported by the
+// BP server were never logged, so only successes and timeouts can be recovered.
+const BACKFILL_TASK_RESULT_STATUS_SQL: &str = r#"
+    WITH migration AS (
+        INSERT INTO schema_migration(name) VALUES ('backfill_task_result_status')
+            ON CONFLICT DO NOTHING
+            RETURNING name
+    )
+    UPDATE background_remover_task
+        SET result_status = CASE WHEN processed_image_path IS NOT NULL THEN 'success' ELSE 'timeout' END
+        WHERE EXISTS (SELECT 1 FROM migration)
+            AND result_status IS NULL
+            AND (processed_image_path IS NOT NULL OR logs @> '[{"event": "timeout"}]'::jsonb)
+"#;
+
+const BACKFILL_ARCHIVED_TASK_RESULT_STATUS_SQL: &str = r#"
+    WITH migration AS (
+        INSERT INTO schema_migration(name) VALUES ('backfill_archived_task_result_status')
+            ON CONFLICT DO NOTHING
+            RETURNING name
+    )
+    UPDATE archived_background_remover_task
+        SET result_status = CASE WHEN processed_image_path IS NOT NULL THEN 'success' ELSE 'timeout' END
+        WHERE EXISTS (SELECT 1 FROM migration)
+            AND result_status IS NULL
+            AND (processed_image_path IS NOT NULL OR logs @> '[{"event": "timeout"}]'::jsonb)
+"#;
+
+// Queries executed in order on startup.
+const SETUP_QUERIES: &[&str] = &[
+    CREATE_TABLE_BACKGROUND_REMOVER_TASK_SQL,
+    CREATE_TABLE_MEDIA_AUDIT_REPORT_SQL,
+    CREATE_TABLE_DAILY_TASK_STATS_SQL,
+    CREATE_TABLE_ARCHIVED_BACKGROUND_REMOVER_TASK_SQL,
+    CREATE_TABLE_TASK_REVISION_SQL,
+    ALTER_TABLE_TASK_REVISION_ADD_KIND_SQL,
+    CREATE_TABLE_REVISION_COMPARISON_SQL,
+    CREATE_TABLE_CANARY_RESULT_SQL,
+    CREATE_TABLE_TASK_GROUP_NOTIFICATION_SQL,
+    CREATE_TABLE_APP_CONFIG_SQL,
+    CREATE_TABLE_CONFIG_AUDIT_LOG_SQL,
+    ALTER_TABLE_TASK_ADD_ORIGINAL_FILENAME_SQL,
+    ALTER_TABLE_ARCHIVED_TASK_ADD_ORIGINAL_FILENAME_SQL,
+    ALTER_TABLE_TASK_ADD_DATE_UPDATED_SQL,
+    ALTER_TABLE_ARCHIVED_TASK_ADD_DATE_UPDATED_SQL,
+    CREATE_FUNCTION_TOUCH_DATE_UPDATED_SQL,
+    DROP_TRIGGER_TASK_DATE_UPDATED_SQL,
+    CREATE_TRIGGER_TASK_DATE_UPDATED_SQL,
+    ALTER_TABLE_TASK_ADD_PROGRESS_SQL,
+    ALTER_TABLE_ARCHIVED_TASK_ADD_PROGRESS_SQL,
+    CREATE_TABLE_SCHEMA_MIGRATION_SQL,
+    BACKFILL_TASK_RESULT_STATUS_SQL,
+    BACKFILL_ARCHIVED_TASK_RESULT_STATUS_SQL,
+    ALTER_TABLE_TASK_ADD_BP_IDENTITY_SQL,
+    ALTER_TABLE_ARCHIVED_TASK_ADD_BP_IDENTITY_SQL,
+    ALTER_TABLE_TASK_ADD_METADATA_SQL,
+    ALTER_TABLE_ARCHIVED_TASK_ADD_METADATA_SQL,
+    CREATE_INDEX_TASK_TAGS_SQL,
+    CREATE_INDEX_TASK_METADATA_SQL,
+    ALTER_TABLE_TASK_ADD_ORIGINAL_FORMAT_SQL,
+    ALTER_TABLE_ARCHIVED_TASK_ADD_ORIGINAL_FORMAT_SQL,
+    ALTER_TABLE_TASK_ADD_OUTPUTS_SQL,
+    ALTER_TABLE_ARCHIVED_TASK_ADD_OUTPUTS_SQL,
+    ALTER_TABLE_TASK_ADD_BACKGROUND_HINT_SQL,
+    ALTER_TABLE_ARCHIVED_TASK_ADD_BACKGROUND_HINT_SQL,
+    ALTER_TABLE_TASK_ADD_PREVIEW_SETTINGS_SQL,
+    ALTER_TABLE_ARCHIVED_TASK_ADD_PREVIEW_SETTINGS_SQL,
+    CREATE_INDEX_TASK_GROUP_SQL,
+    ALTER_TABLE_TASK_ADD_REQUEST_IDS_SQL,
+    ALTER_TABLE_ARCHIVED_TASK_ADD_REQUEST_IDS_SQL,
+    CREATE_TABLE_NOTIFICATION_OUTBOX_SQL,
+    CREATE_INDEX_NOTIFICATION_OUTBOX_PENDING_SQL,
+    CREATE_TABLE_TASK_MANIFEST_SQL,
+    ALTER_TABLE_TASK_ADD_FREE_TIER_SQL,
+    ALTER_TABLE_ARCHIVED_TASK_ADD_FREE_TIER_SQL,
+    ALTER_TABLE_TASK_REVISION_ADD_UNWATERMARKED_SQL,
+    ALTER_TABLE_TASK_ADD_OUTPUT_MAX_PIXELS_SQL,
+    ALTER_TABLE_ARCHIVED_TASK_ADD_OUTPUT_MAX_PIXELS_SQL,
+    ALTER_TABLE_TASK_REVISION_ADD_UNCAPPED_MASK_SQL,
+    ALTER_TABLE_TASK_ADD_BILLING_SQL,
+    ALTER_TABLE_ARCHIVED_TASK_ADD_BILLING_SQL,
+    CREATE_TABLE_BILLING_PLAN_SQL,
+    CREATE_TABLE_API_KEY_USAGE_SQL,
+    CREATE_INDEX_API_KEY_USAGE_KEY_ID_SQL,
+    CREATE_INDEX_API_KEY_USAGE_UNREPORTED_SQL,
+    CREATE_TABLE_BILLING_USAGE_REPORT_SQL,
+    CREATE_TABLE_ORGANIZATION_SQL,
+    CREATE_TABLE_ORGANIZATION_API_KEY_SQL,
+    CREATE_TABLE_ORGANIZATION_MEMBER_SQL,
+    ALTER_TABLE_API_KEY_USAGE_ADD_ORGANIZATION_SQL,
+    CREATE_INDEX_API_KEY_USAGE_ORGANIZATION_SQL,
+    CREATE_INDEX_TASK_API_KEY_ID_SQL,
+    ALTER_TABLE_TASK_ADD_TENANT_SQL,
+    ALTER_TABLE_ARCHIVED_TASK_ADD_TENANT_SQL,
+    CREATE_INDEX_TASK_USER_IDENTIFIER_SQL,
+    CREATE_INDEX_ARCHIVED_TASK_USER_IDENTIFIER_SQL,
+    CREATE_TABLE_USER_ERASURE_SQL,
+    CREATE_INDEX_TASK_NOT_ANONYMIZED_SQL,
+    CREATE_INDEX_ARCHIVED_TASK_NOT_ANONYMIZED_SQL,
+    CREATE_INDEX_TASK_METADATA_NOT_ANONYMIZED_SQL,
+    CREATE_INDEX_ARCHIVED_TASK_METADATA_NOT_ANONYMIZED_SQL,
+    CREATE_TABLE_BP_MESSAGE_SQL,
+    CREATE_INDEX_BP_MESSAGE_TASK_KEY_SQL,
+    CREATE_TABLE_SCHEDULED_JOB_SQL,
+    CREATE_TABLE_LEASE_SQL,
+    ALTER_TABLE_TASK_ADD_DEDUP_SQL,
+    ALTER_TABLE_ARCHIVED_TASK_ADD_DEDUP_SQL,
+    CREATE_INDEX_TASK_CONTENT_SHA256_SQL,
+    ALTER_TABLE_TASK_ADD_TIMINGS_SQL,
+    ALTER_TABLE_ARCHIVED_TASK_ADD_TIMINGS_SQL,
+    CREATE_INDEX_TASK_TIMINGS_DATE_CREATED_SQL,
+    CREATE_INDEX_ARCHIVED_TASK_TIMINGS_DATE_CREATED_SQL,
+    ALTER_TABLE_TASK_GROUP_NOTIFICATION_ADD_FAILED_ATTEMPTS_SQL,
+    ALTER_TABLE_BILLING_PLAN_ADD_EVENT_CREATED_SQL,
+    ALTER_TABLE_TASK_ADD_DATE_COMPLETED_SQL,
+    ALTER_TABLE_ARCHIVED_TASK_ADD_DATE_COMPLETED_SQL,
+    CREATE_INDEX_TASK_DATE_COMPLETED_SQL,
+];
+
+///
+/// Configures initial database operations such as creating a table if not exist.
+///
+pub async fn setup(secrets: &SecretStore) -> Result<DBWrapper, std::io::Error> {
+    // Extract postgres url
+    let postgres_url = match secrets.get(secrets::POSTGRES_URL) {
+        Some(value) => value,
+        None => {
+            log::error!("Failed to read POSTGRES_URL from environment variable. Probably missing.");
+            return Err(std::io::Error::other("POSTGRES_URL is missing."));
+        }
+    };
+
+    let pool = match PgPool::connect(&postgres_url).await {
+        Ok(pool) => pool,
+        Err(error) => {
+            return Err(std::io::Error::other(error));
+        }
+    };
+
+    for query in SETUP_QUERIES {
+        if let Err(error) = pool.execute(*query).await {
+            println!("Failed to create required tables.");
+            return Err(std::io::Error::other(error));
+        }
+    }
+
+    Ok(DBWrapper { pool })
+}
+
+///
+/// Names of the tables created by `SETUP_QUERIES`.
+///
+fn setup_tables() -> Vec<&'static str> {
+    SETUP_QUERIES
+        .iter()
+        .filter_map(|query| query.split("CREATE TABLE IF NOT EXISTS").nth(1))
+        .filter_map(|rest| {
+            rest.split(|c: char| c == '(' || c.is_whitespace())
+                .find(|name| !name.is_empty())
+        })
+        .collect()
+}
+
+///
+/// Problems of the database schema once setup ran: missing tables, and columns of
+/// `background_remover_task` missing from its archive table, which would fail archiving.
+///
+pub async fn schema_problems(db_wrapper: &DBWrapper) -> Result<Vec<String>, sqlx::Error> {
+    const MISSING_TABLES_QUERY: &str = r#"
+        SELECT name FROM UNNEST($1::TEXT[]) AS name WHERE to_regclass(name) IS NULL
+    "#;
+    const MISSING_ARCHIVE_COLUMNS_QUERY: &str = r#"
+        SELECT column_name::TEXT FROM information_schema.columns
+            WHERE table_schema=current_schema() AND table_name='background_remover_task'
+        EXCEPT
+        SELECT column_name::TEXT FROM information_schema.columns
+            WHERE table_schema=current_schema() AND table_name='archived_background_remover_task'
+    "#;
+
+    let missing_tables: Vec<(String,)> = sqlx::query_as(MISSING_TABLES_QUERY)
+        .bind(setup_tables())
+        .fetch_all(&db_wrapper.pool)
+        .await?;
+    let missing_columns: Vec<(String,)> = sqlx::query_as(MISSING_ARCHIVE_COLUMNS_QUERY)
+        .fetch_all(&db_wrapper.pool)
+        .await?;
+
+    let mut problems: Vec<String> = missing_tables
+        .into_iter()
+        .map(|(name,)| format!("Table {} is missing.", name))
+        .collect();
+    problems.extend(missing_columns.into_iter().map(|(name,)| {
+        format!(
+            "Column {} of background_remover_task is missing from archived_background_remover_task.",
+            name
+        )
+    }));
+    Ok(problems)
+}
+
+pub mod models {
+    use std::collections::HashMap;
+    use std::env;
+    use std::fmt::Debug;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use serde::ser::{Error, SerializeStruct};
+    use serde::{Deserialize, Serialize, Serializer};
+    use serde_json::{json, Value};
+
+    use sqlx::pool::PoolConnection;
+    use sqlx::types::chrono::Utc;
+    use sqlx::types::Json;
+    use sqlx::{Executor, Postgres, Transaction};
+
+    use chrono::{DateTime, NaiveDate};
+    use uuid::Uuid;
+
+    use crate::db::DBWrapper;
+    use crate::utils::billing_utils::{BillingAccount, TenantAccess};
+    use crate::utils::organization_utils::Role;
+    use crate::utils::path_utils;
+    use crate::utils::timing_utils::{self, TaskStage};
+
+    /// Page size of the tasks listing for both page and cursor based pagination.
+    pub const TASKS_PER_PAGE: i64 = 25;
+
+    ///
+    /// This struct is the mapped columns of table `background_remover_task`.
+    ///
+    #[derive(Debug, sqlx::FromRow)]
+    pub struct BackgroundRemoverTask {
+        /// Auto incremented unique integer for each background removal task.
+        pub task_id: i64,
+        /// Date when this removal task is created.
+        pub date_created: DateTime<Utc>,
+        /// Unique string for each task.
+        pub key: Uuid,
+        /// Unique string for websocket group used for listening websocket messags.
+        pub task_group: Uuid,
+        /// Relative path: media/image.jpg
+        pub original_image_path: String,
+        /// Relative path: media/image.png
+        pub preview_original_image_path: Option<String>,
+        /// Relative path: media/image.png
+        pub mask_image_path: Option<String>,
+        /// Relative path: media/image.png
+        pub processed_image_path: Option<String>,
+        /// Relative path: media/image.png
+        pub preview_processed_image_path: Option<String>,
+        /// Background removal status.
+        pub processing: Option<bool>,
+        /// Outcome of the last processing. See `ResultStatus`.
+        pub result_status: Option<String>,
+        /// Country from where photo is uploaded.
+        pub country: Option<String>,
+        /// Encoded string to identiy user.
+        pub user_identifier: Option<String>,
+        /// Task logs.
+        pub logs: Option<Value>,
+        /// Filename sent by the client. Display only, never used on disk.
+        pub original_filename: Option<String>,
+        /// Date of the last update of this row. Not serialized.
+        pub date_updated: Option<DateTime<Utc>>,
+        /// Latest progress reported by the BP server.
+        pub progress: Option<Value>,
+        /// BP worker which produced the result. Only in full serialization.
+        pub bp_worker_id: Option<String>,
+        /// Model version which produced the result. Only in full serialization.
+        pub bp_model_version: Option<String>,
+        /// Metadata object supplied by the client on upload.
+        pub metadata: Option<Value>,
+        /// Tags supplied by the client on upload.
+        pub tags: Vec<String>,
+        /// Format of the original image detected from its content.
+        pub original_format: Option<String>,
+        /// Outputs requested on upload. See `TaskOutputs`.
+        pub outputs: String,
+        /// Preset or `#rrggbb` color of the background supplied on upload.
+        pub background_hint: Option<String>,
+        /// `PreviewOptions::fingerprint` of the settings previews were generated with. Not
+        /// serialized.
+        pub preview_settings: Option<String>,
+        /// Id of the latest dispatch to the BP server. Not serialized.
+        pub bp_request_id: Option<Uuid>,
+        /// Id of the dispatch whose result was stored. Not serialized.
+        pub completed_request_id: Option<Uuid>,
+        /// Uploaded with a free tier key, so processed outputs are watermarked. Not serialized.
+        pub free_tier: bool,
+        /// Pixel cap the outputs were scaled down to, when the plan of the task has one and the
+        /// result was larger.
+        pub output_max_pixels: Option<i64>,
+        /// Key id of the upload signature, which identifies the client. `None` for unsigned
+        /// uploads. Not serialized.
+        pub api_key_id: Option<String>,
+        /// Priority of the billing plan of `api_key_id` at upload, sent to the BP server. Not
+        /// serialized.
+        pub priority: i32,
+        /// `BillingAccount::media_prefix` of `api_key_id` at upload. Media of the task is stored
+        /// under this tenant, and only the tenant may read the task. Not serialized.
+        pub tenant: Option<String>,
+        /// Lowercase hex SHA-256 of the original image, by which uploads are deduplicated. `None`
+        /// for uploads sent with `X-No-Dedup`. Not serialized.
+        pub content_sha256: Option<String>,
+        /// Key of the earlier task whose outputs this task was served from, instead of being
+        /// processed. Only in full serialization.
+        pub dedup_of: Option<Uuid>,
+        /// Files of `task_manifest`. Only selected by queries returning tasks to clients, and
+        /// `None` for tasks completed before manifests. Not serialized.
+        #[sqlx(default)]
+        pub manifest: Option<Json<Vec<ManifestFile>>>,
+    }
+
+    /// Sent with `output_cap` of tasks whose outputs were scaled down.
+    const OUTPUT_CAP_UPGRADE_HINT: &str =
+        "Free tier outputs are downscaled. Upgrade to a paid plan for full resolution results.";
+
+    ///
+    /// Serde JSON custom serialize implementation. It modifies field values for `path` fields.
+    ///
+    impl Serialize for BackgroundRemoverTask {
+        fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+        {
+            let mut state = serializer.serialize_struct("BackgroundRemoverTask", 26)?;
+            state.serialize_field("task_id", &self.task_id)?;
+            state.serialize_field("date_created", &self.date_created.to_string())?;
+            state.serialize_field("key", &self.key)?;
+            state.serialize_field("task_group", &self.task_group)?;
+
+            // Url configurations from environment variables.
+            let scheme = "https";
+            let host = match env::var("HOST") {
+                Ok(value) => value,
+                Err(error) => {
+                    return Err(Error::custom(error));
+                }
+            };
+
+            // Adds full original image url to JSON object.
+            let full_original_image_url = path_utils::full_media_url_from_relative_path(
+                scheme,
+                &host,
+                PathBuf::from(&self.original_image_path),
+            );
+            state.serialize_field("original_image", &full_original_image_url)?;
+            state.serialize_field("original_filename", &self.original_filename)?;
+            state.serialize_field("original_format", &self.original_format)?;
+            state.serialize_field("metadata", &self.metadata)?;
+            state.serialize_field("tags", &self.tags)?;
+            state.serialize_field("outputs", &self.outputs)?;
+            state.serialize_field("background_hint", &self.background_hint)?;
+
+            // Adds full media image url to JSON object.
+            let full_media_preview_image_url;
+            if let Some(preview_original_path) = &self.preview_original_image_path {
+                full_media_preview_image_url = Some(path_utils::full_media_url_from_relative_path(
+                    scheme,
+                    &host,
+                    PathBuf::from(preview_original_path),
+                ));
+            } else {
+                full_media_preview_image_url = None;
+            }
+            state.serialize_field("preview_original_image", &full_media_preview_image_url)?;
+
+            // Mask only tasks have no processed images, so only the mask url is returned.
+            let mask_only = self.is_mask_only();
+
+            // Adds full processed image url to JSON object.
+            let full_processed_original_image_url;
+            if let Some(processed_original_path) = self.output_path(ManifestFile::PROCESSED) {
+                full_processed_original_image_url =
+                    Some(path_utils::full_media_url_from_relative_path(
+                        scheme,
+                        &host,
+                        PathBuf::from(processed_original_path),
+                    ));
+            } else {
+                full_processed_original_image_url = None;
+            }
+
+            if !mask_only {
+                state.serialize_field("processed_image", &full_processed_original_image_url)?;
+            }
+
+            let full_preview_processed_image_url;
+            if let Some(preview_processed_path) = self.output_path(ManifestFile::PREVIEW_PROCESSED)
+            {
+                full_preview_processed_image_url =
+                    Some(path_utils::full_media_url_from_relative_path(
+                        scheme,
+                        &host,
+                        PathBuf::from(preview_processed_path),
+                    ));
+            } else {
+                full_preview_processed_image_url = None;
+            }
+
+            if !mask_only {
+                state.serialize_field(
+                    "preview_processed_image",
+                    &full_preview_processed_image_url,
+                )?;
+            }
+
+            let full_mask_image_url;
+            if let Some(preview_mask_path) = self.output_path(ManifestFile::MASK) {
+                full_mask_image_url = Some(path_utils::full_media_url_from_relative_path(
+                    scheme,
+                    &host,
+                    PathBuf::from(preview_mask_path),
+                ));
+            } else {
+                full_mask_image_url = None;
+            }
+
+            state.serialize_field("mask_image", &full_mask_image_url)?;
+
+            state.serialize_field("processing", &self.processing)?;
+            state.serialize_field("progress", &self.progress)?;
+            state.serialize_field("result_status", &self.result_status)?;
+            state.serialize_field("user_identifier", &self.user_identifier)?;
+            state.serialize_field("country", &self.country)?;
+            state.serialize_field("logs", &self.logs)?;
+            state.serialize_field("bp_worker_id", &self.bp_worker_id)?;
+            state.serialize_field("bp_model_version", &self.bp_model_version)?;
+            state.serialize_field("deduplicated", &self.dedup_of.is_some())?;
+            state.serialize_field("dedup_of", &self.dedup_of)?;
+
+            // Tells clients why the outputs are smaller than the original and how to get them at
+            // full resolution.
+            let output_cap = self.output_max_pixels.map(|max_pixels| {
+                json!({
+                    "max_pixels": max_pixels,
+                    "upgrade_hint": OUTPUT_CAP_UPGRADE_HINT,
+                    "upgrade_url": env::var("UPGRADE_URL").ok(),
+                })
+            });
+            state.serialize_field("output_cap", &output_cap)?;
+            state.end()
+        }
+    }
+
+    ///
+    /// Partially mapped column for table `background_remover_task`.
+    /// Contains necessary fields required for new record insertion in the database.
+    ///
+    pub struct NewBackgroundRemoverTask {
+        pub key: Uuid,
+        pub task_group: Uuid,
+        pub original_image_path: String,
+        pub preview_original_image_path: String,
+        pub original_filename: Option<String>,
+        pub country: Option<String>,
+        pub user_identifier: Option<String>,
+        pub metadata: Option<Value>,
+        pub tags: Vec<String>,
+        pub original_format: Option<String>,
+        pub outputs: TaskOutputs,
+        pub background_hint: Option<String>,
+        pub preview_settings: String,
+        pub free_tier: bool,
+        pub api_key_id: Option<String>,
+        pub priority: i32,
+        pub tenant: Option<String>,
+        /// `None` when the client opted out of deduplication.
+        pub content_sha256: Option<String>,
+        /// Stages finished before insertion, see `timing_utils::timings_json`.
+        pub timings: Option<Value>,
+    }
+
+    ///
+    /// Outputs produced for a task. Values of column `outputs`.
+    ///
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum TaskOutputs {
+        /// Transparent image, its preview and the mask.
+        All,
+        /// Only the mask, for clients doing their own compositing.
+        Mask,
+    }
+
+    impl TaskOutputs {
+        pub fn as_str(&self) -> &'static str {
+            match self {
+                TaskOutputs::All => "all",
+                TaskOutputs::Mask => "mask",
+            }
+        }
+
+        pub fn parse(value: &str) -> Option<Self> {
+            match value {
+                "all" => Some(TaskOutputs::All),
+                "mask" => Some(TaskOutputs::Mask),
+                _ => None,
+            }
+        }
+    }
+
+    ///
+    /// Filters of task listings. `None` fields match every task.
+    ///
+    #[derive(Debug, Default)]
+    pub struct TaskFilter {
+        /// Tasks having this tag.
+        pub tag: Option<String>,
+        /// Tasks whose metadata contains this JSON object.
+        pub metadata: Option<Value>,
+        /// Tasks uploaded with a key id currently owned by this organization.
+        pub organization_id: Option<i64>,
+        /// Tasks readable with this access: tasks outside every tenant when anonymous, tasks of
+        /// the tenant when authenticated, every task for the admin token.
+        pub access: TenantAccess,
+    }
+
+    impl TaskFilter {
+        ///
+        /// Binds of the tenant condition: whether every tenant matches, else the only tenant
+        /// matching, `None` for tasks outside every tenant.
+        ///
+        fn tenant(&self) -> (bool, Option<&str>) {
+            match &self.access {
+                TenantAccess::Admin => (true, None),
+                TenantAccess::Tenant(prefix) => (false, Some(prefix.as_str())),
+                TenantAccess::Anonymous => (false, None),
+            }
+        }
+    }
+
+    ///
+    /// Partially mapped column for table `background_remover_task`.
+    /// Contains necessary fields required for updating existing record in the database.
+    ///
+    pub struct UpdateBackgroundRemoverTask {
+        pub key: Uuid,
+        pub mask_image_path: String,
+        /// `None` for mask only tasks.
+        pub processed_image_path: Option<String>,
+        /// `None` for mask only tasks.
+        pub preview_processed_image_path: Option<String>,
+        /// Saved outputs, replacing the manifest of the task.
+        pub manifest: Vec<ManifestFile>,
+        /// Pixel cap the outputs were scaled down to. `None` for full resolution outputs.
+        pub output_max_pixels: Option<i64>,
+        /// Dispatch the outputs belong to. The update is skipped when the task was dispatched
+        /// again meanwhile. `None` for results of older BP servers, which always apply.
+        pub request_id: Option<Uuid>,
+    }
+
+    ///
+    /// Outcome of `BackgroundRemoverTask::claim_result`.
+    ///
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum ResultClaim {
+        /// The result is stored by the caller.
+        Claimed,
+        /// The result of this dispatch was already claimed.
+        Duplicate,
+        /// The task was dispatched again since, so the result is outdated.
+        Stale,
+    }
+
+    ///
+    /// Values of column `result_status`. Tasks still pending or processing have none.
+    ///
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum ResultStatus {
+        Success,
+        /// The BP server reported a failure or the task could not be sent.
+        Failed,
+        /// A pipeline stage exceeded its timeout.
+        Timeout,
+    }
+
+    impl ResultStatus {
+        pub fn as_str(&self) -> &'static str {
+            match self {
+                ResultStatus::Success => "success",
+                ResultStatus::Failed => "failed",
+                ResultStatus::Timeout => "timeout",
+            }
+        }
+    }
+
+    ///
+    /// Entry of the task event log stored as JSON array in column `logs`.
+    ///
+    #[derive(Debug, Serialize)]
+    pub struct TaskLogEntry {
+        pub timestamp: String,
+        /// Kind of event, e.g. `timeout`.
+        pub event: String,
+        /// Machine readable code, e.g. `bp_send_timeout`.
+        pub status_code: String,
+        pub message: Option<String>,
+        pub details: Option<Value>,
+    }
+
+    impl TaskLogEntry {
+        pub fn new(event: &str, status_code: &str) -> Self {
+            Self {
+                timestamp: Utc::now().to_rfc3339(),
+                event: event.to_string(),
+                status_code: status_code.to_string(),
+                message: None,
+                details: None,
+            }
+        }
+
+        pub fn message(mut self, message: &str) -> Self {
+            self.message = Some(message.to_string());
+            self
+        }
+
+        pub fn details(mut self, details: Value) -> Self {
+            self.details = Some(details);
+            self
+        }
+    }
+
+    ///
+    /// Lightweight columns of `background_remover_task` used for analytics exports. Paths and
+    /// logs are left out.
+    ///
+    #[derive(Debug, Serialize, sqlx::FromRow)]
+    pub struct TaskExportRow {
+        pub task_id: i64,
+        pub date_created: DateTime<Utc>,
+        pub key: Uuid,
+        pub task_group: Uuid,
+        pub processing: Option<bool>,
+        pub result_status: Option<String>,
+        pub country: Option<String>,
+        pub user_identifier: Option<String>,
+    }
+
+    impl TaskExportRow {
+        ///
+        /// Returns up to `limit` rows created between `from` and `to` with `task_id` greater
+        /// than `after_task_id`, in ascending order of `task_id`.
+        ///
+        pub async fn fetch_window_batch(
+            db_wrapper: Arc<DBWrapper>,
+            from: &DateTime<Utc>,
+            to: &DateTime<Utc>,
+            after_task_id: i64,
+            limit: i64,
+        ) -> Result<Vec<TaskExportRow>, sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const FETCH_QUERY: &str = r#"
+                SELECT
+                    task_id,
+                    date_created,
+                    key,
+                    task_group,
+                    processing,
+                    result_status,
+                    country,
+                    user_identifier
+                FROM background_remover_task
+                    WHERE date_created BETWEEN $1 AND $2 AND task_id > $3
+                    ORDER BY task_id ASC
+                    LIMIT $4
+            "#;
+
+            let rows: Vec<TaskExportRow> = sqlx::query_as(FETCH_QUERY)
+                .bind(from)
+                .bind(to)
+                .bind(after_task_id)
+                .bind(limit)
+                .fetch_all(connection)
+                .await?;
+
+            Ok(rows)
+        }
+    }
+
+    ///
+    /// Row of table `daily_task_stats`.
+    ///
+    #[derive(Debug, Serialize, sqlx::FromRow)]
+    pub struct DailyTaskStats {
+        pub day: NaiveDate,
+        pub country: String,
+        pub status: String,
+        pub total: i64,
+    }
+
+    impl DailyTaskStats {
+        ///
+        /// Recomputes rollup rows for every day starting from `since` (UTC). Rows of those days
+        /// are replaced, so running it repeatedly is safe.
+        ///
+        pub async fn refresh(
+            db_wrapper: Arc<DBWrapper>,
+            since: NaiveDate,
+        ) -> Result<(), sqlx::Error> {
+            let since_datetime = since.and_time(chrono::NaiveTime::MIN).and_utc();
+
+            const DELETE_QUERY: &str = r#"
+                DELETE FROM daily_task_stats WHERE day >= $1
+            "#;
+
+            // Tasks without a result status are counted by their processing state.
+            const ROLLUP_QUERY: &str = r#"
+                INSERT INTO daily_task_stats(day, country, status, total)
+                SELECT
+                    (date_created AT TIME ZONE 'UTC')::date,
+                    COALESCE(country, 'unknown'),
+                    COALESCE(
+                        result_status,
+                        CASE
+                            WHEN processed_image_path IS NOT NULL THEN 'success'
+                            WHEN processing THEN 'processing'
+                            ELSE 'pending'
+                        END
+                    ),
+                    COUNT(*)
+                FROM (
+                    SELECT date_created, country, result_status, processed_image_path, processing
+                        FROM background_remover_task
+                    UNION ALL
+                    SELECT date_created, country, result_status, processed_image_path, processing
+                        FROM archived_background_remover_task
+                ) AS tasks
+                    WHERE date_created >= $1
+                    GROUP BY 1, 2, 3
+            "#;
+
+            let mut transaction = db_wrapper.pool.begin().await?;
+            sqlx::query(DELETE_QUERY)
+                .bind(since)
+                .execute(&mut *transaction)
+                .await?;
+            sqlx::query(ROLLUP_QUERY)
+                .bind(since_datetime)
+                .execute(&mut *transaction)
+                .await?;
+            transaction.commit().await?;
+
+            Ok(())
+        }
+
+        ///
+        /// Returns rollup rows between `from` and `to` (inclusive).
+        ///
+        pub async fn fetch_range(
+            db_wrapper: Arc<DBWrapper>,
+            from: NaiveDate,
+            to: NaiveDate,
+        ) -> Result<Vec<DailyTaskStats>, sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const FETCH_QUERY: &str = r#"
+                SELECT day, country, status, total FROM daily_task_stats
+                    WHERE day BETWEEN $1 AND $2
+                    ORDER BY day ASC
+            "#;
+
+            let rows: Vec<DailyTaskStats> = sqlx::query_as(FETCH_QUERY)
+                .bind(from)
+                .bind(to)
+                .fetch_all(connection)
+                .await?;
+
+            Ok(rows)
+        }
+    }
+
+    ///
+    /// Aggregated durations of one `TaskStage` over tasks, archived or not, in milliseconds.
+    ///
+    #[derive(Debug, Serialize, sqlx::FromRow)]
+    pub struct StageTimingStats {
+        pub stage: String,
+        pub count: i64,
+        pub avg_ms: f64,
+        pub p50_ms: f64,
+        pub p95_ms: f64,
+    }
+
+    impl StageTimingStats {
+        ///
+        /// Returns stats of every stage recorded for tasks created between `from` and `to`
+        /// (inclusive, UTC). Stages without durations are absent.
+        ///
+        pub async fn fetch_range(
+            db_wrapper: Arc<DBWrapper>,
+            from: NaiveDate,
+            to: NaiveDate,
+        ) -> Result<Vec<StageTimingStats>, sqlx::Error> {
+            let connection = db_wrapper.timed();
+            let from = from.and_time(chrono::NaiveTime::MIN).and_utc();
+            let until = (to + chrono::Duration::days(1))
+                .and_time(chrono::NaiveTime::MIN)
+                .and_utc();
+
+            const FETCH_QUERY: &str = r#"
+                SELECT
+                    timing.stage,
+                    COUNT(*) AS count,
+                    AVG(timing.ms) AS avg_ms,
+                    percentile_cont(0.5) WITHIN GROUP (ORDER BY timing.ms) AS p50_ms,
+                    percentile_cont(0.95) WITHIN GROUP (ORDER BY timing.ms) AS p95_ms
+                FROM (
+                    SELECT date_created, timings FROM background_remover_task
+                    UNION ALL
+                    SELECT date_created, timings FROM archived_background_remover_task
+                ) AS tasks
+                    CROSS JOIN LATERAL (
+                        SELECT key AS stage, value::float8 AS ms FROM jsonb_each_text(tasks.timings)
+                    ) AS timing
+                    WHERE tasks.timings IS NOT NULL
+                        AND tasks.date_created >= $1 AND tasks.date_created < $2
+                    GROUP BY timing.stage
+                    ORDER BY timing.stage ASC
+            "#;
+
+            let rows: Vec<StageTimingStats> = sqlx::query_as(FETCH_QUERY)
+                .bind(from)
+                .bind(until)
+                .fetch_all(connection)
+                .await?;
+
+            Ok(rows)
+        }
+    }
+
+    ///
+    /// Path columns of `background_remover_task` which are allowed to be `NULL`.
+    ///
+    #[derive(Debug, Clone, Copy)]
+    pub enum NullablePathColumn {
+        MaskImage,
+        ProcessedImage,
+        PreviewProcessedImage,
+    }
+
+    impl NullablePathColumn {
+        pub fn name(&self) -> &'static str {
+            match self {
+                NullablePathColumn::MaskImage => "mask_image_path",
+                NullablePathColumn::ProcessedImage => "processed_image_path",
+                NullablePathColumn::PreviewProcessedImage => "preview_processed_image_path",
+            }
+        }
+
+        pub fn manifest_role(&self) -> &'static str {
+            match self {
+                NullablePathColumn::MaskImage => ManifestFile::MASK,
+                NullablePathColumn::ProcessedImage => ManifestFile::PROCESSED,
+                NullablePathColumn::PreviewProcessedImage => ManifestFile::PREVIEW_PROCESSED,
+            }
+        }
+    }
+
+    ///
+    /// Row of table `media_audit_report`. Records a path column pointing to a missing file.
+    ///
+    pub struct MediaAuditRecord {
+        pub run_id: Uuid,
+        pub task_key: Uuid,
+        pub column_name: String,
+        pub path: String,
+        pub nullified: bool,
+    }
+
+    impl MediaAuditRecord {
+        pub async fn insert(
+            db_wrapper: Arc<DBWrapper>,
+            record: &MediaAuditRecord,
+        ) -> Result<(), sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const INSERT_QUERY: &str = r#"
+                INSERT INTO media_audit_report(
+                    run_id,
+                    task_key,
+                    column_name,
+                    path,
+                    nullified
+                ) VALUES ($1, $2, $3, $4, $5)
+            "#;
+
+            connection
+                .execute(
+                    sqlx::query(INSERT_QUERY)
+                        .bind(record.run_id)
+                        .bind(record.task_key)
+                        .bind(&record.column_name)
+                        .bind(&record.path)
+                        .bind(record.nullified),
+                )
+                .await?;
+            Ok(())
+        }
+    }
+
+    ///
+    /// This struct is the mapped columns of table `task_revision`. A revision holds outputs of a
+    /// task produced after the first processing, so earlier outputs are never overwritten.
+    ///
+    #[derive(Debug, sqlx::FromRow)]
+    pub struct TaskRevision {
+        pub revision_id: i64,
+        pub date_created: DateTime<Utc>,
+        /// Unique string for each revision. Sent to the BP server as `task_id`.
+        pub key: Uuid,
+        /// Key of the refined `BackgroundRemoverTask`.
+        pub task_key: Uuid,
+        /// Revision number starting from 1 for each task. Revision 0 is the task itself.
+        pub revision: i32,
+        /// `refinement` for user corrections, `reprocess` for processing the task again.
+        pub kind: String,
+        /// Relative path: media/image.png. Only set for refinements.
+        pub correction_image_path: Option<String>,
+        /// Relative path: media/image.png
+        pub mask_image_path: Option<String>,
+        /// Relative path: media/image.png
+        pub processed_image_path: Option<String>,
+        /// Relative path: media/image.png
+        pub preview_processed_image_path: Option<String>,
+        pub processing: Option<bool>,
+        /// Relative path of the processed image before watermarking. Only set for free tier
+        /// tasks. Not serialized.
+        pub unwatermarked_image_path: Option<String>,
+        /// Relative path of the mask before it was scaled down to the pixel cap of a free tier
+        /// task. Not serialized.
+        pub uncapped_mask_image_path: Option<String>,
+    }
+
+    impl Serialize for TaskRevision {
+        fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+        {
+            let host = match env::var("HOST") {
+                Ok(value) => value,
+                Err(error) => {
+                    return Err(Error::custom(error));
+                }
+            };
+
+            let full_url = |path: &String| {
+                path_utils::full_media_url_from_relative_path("https", &host, PathBuf::from(path))
+            };
+
+            let mut state = serializer.serialize_struct("TaskRevision", 9)?;
+            state.serialize_field("date_created", &self.date_created.to_string())?;
+            state.serialize_field("key", &self.key)?;
+            state.serialize_field("task_key", &self.task_key)?;
+            state.serialize_field("revision", &self.revision)?;
+            state.serialize_field("correction_image", &full_url(&self.correction_image_path))?;
+            state.serialize_field("mask_image", &self.mask_image_path.as_ref().map(full_url))?;
+            state.serialize_field(
+                "processed_image",
+                &self.processed_image_path.as_ref().map(full_url),
+            )?;
+            state.serialize_field(
+                "preview_processed_image",
+                &self.preview_processed_image_path.as_ref().map(full_url),
+            )?;
+            state.serialize_field("processing", &self.processing)?;
+            state.end()
+        }
+    }
+
+    impl TaskRevision {
+        ///
+        /// Inserts the next revision of task `task_key` and returns it.
+        ///
+        pub async fn insert_next(
+            db_wrapper: Arc<DBWrapper>,
+            key: &Uuid,
+            task_key: &Uuid,
+            kind: &str,
+            correction_image_path: Option<&str>,
+        ) -> Result<TaskRevision, sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const INSERT_QUERY: &str = r#"
+                INSERT INTO task_revision(
+                    key,
+                    task_key,
+                    revision,
+                    kind,
+                    correction_image_path,
+                    processing
+                )
+                SELECT $1, $2, COALESCE(MAX(revision), 0) + 1, $3, $4, TRUE
+                    FROM task_revision WHERE task_key=$2
+                RETURNING *
+            "#;
+
+            sqlx::query_as(INSERT_QUERY)
+                .bind(key)
+                .bind(task_key)
+                .bind(kind)
+                .bind(correction_image_path)
+                .fetch_one(connection)
+                .await
+        }
+
+        pub async fn fetch(
+            db_wrapper: Arc<DBWrapper>,
+            key: &Uuid,
+        ) -> Result<TaskRevision, sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const FETCH_QUERY: &str = "SELECT * FROM task_revision WHERE key=$1";
+            sqlx::query_as(FETCH_QUERY)
+                .bind(key)
+                .fetch_one(connection)
+                .await
+        }
+
+        ///
+        /// Returns revisions of task `task_key` ordered by revision number.
+        ///
+        pub async fn fetch_by_task(
+            db_wrapper: Arc<DBWrapper>,
+            task_key: &Uuid,
+        ) -> Result<Vec<TaskRevision>, sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const FETCH_QUERY: &str =
+                "SELECT * FROM task_revision WHERE task_key=$1 ORDER BY revision";
+            sqlx::query_as(FETCH_QUERY)
+                .bind(task_key)
+                .fetch_all(connection)
+                .await
+        }
+
+        ///
+        /// Returns revision `revision` of task `task_key`.
+        ///
+        pub async fn fetch_by_number(
+            db_wrapper: Arc<DBWrapper>,
+            task_key: &Uuid,
+            revision: i32,
+        ) -> Result<TaskRevision, sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const FETCH_QUERY: &str =
+                "SELECT * FROM task_revision WHERE task_key=$1 AND revision=$2";
+            sqlx::query_as(FETCH_QUERY)
+                .bind(task_key)
+                .bind(revision)
+                .fetch_one(connection)
+                .await
+        }
+
+        ///
+        /// Returns the latest revision of task `task_key` which has outputs.
+        ///
+        pub async fn fetch_latest_completed(
+            db_wrapper: Arc<DBWrapper>,
+            task_key: &Uuid,
+        ) -> Result<Option<TaskRevision>, sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const FETCH_QUERY: &str = r#"
+                SELECT * FROM task_revision
+                    WHERE task_key=$1 AND processed_image_path IS NOT NULL
+                    ORDER BY revision DESC
+                    LIMIT 1
+            "#;
+            sqlx::query_as(FETCH_QUERY)
+                .bind(task_key)
+                .fetch_optional(connection)
+                .await
+        }
+
+        ///
+        /// Stores outputs received from the BP server and marks the revision completed.
+        /// `unwatermarked_image_path` and `uncapped_mask_image_path` are only given when outputs
+        /// were watermarked or scaled down.
+        ///
+        pub async fn update_outputs(
+            db_wrapper: Arc<DBWrapper>,
+            key: &Uuid,
+            mask_image_path: &str,
+            processed_image_path: &str,
+            preview_processed_image_path: &str,
+            unwatermarked_image_path: Option<&str>,
+            uncapped_mask_image_path: Option<&str>,
+        ) -> Result<(), sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const UPDATE_QUERY: &str = r#"
+                UPDATE task_revision
+                SET
+                    mask_image_path=$1,
+                    processed_image_path=$2,
+                    preview_processed_image_path=$3,
+                    unwatermarked_image_path=$5,
+                    uncapped_mask_image_path=$6,
+                    processing=FALSE
+                WHERE
+                    key=$4
+            "#;
+
+            connection
+                .execute(
+                    sqlx::query(UPDATE_QUERY)
+                        .bind(mask_image_path)
+                        .bind(processed_image_path)
+                        .bind(preview_processed_image_path)
+                        .bind(key)
+                        .bind(unwatermarked_image_path)
+                        .bind(uncapped_mask_image_path),
+                )
+                .await?;
+            Ok(())
+        }
+    }
+
+    ///
+    /// Scores of a revision comparison requested by the QA endpoint.
+    ///
+    pub struct RevisionComparison {
+        pub task_key: Uuid,
+        pub revision_a: i32,
+        pub revision_b: i32,
+        pub mask_iou: f64,
+        pub mean_pixel_diff: f64,
+        /// Relative path: media/image.png
+        pub heatmap_path: String,
+    }
+
+    impl RevisionComparison {
+        pub async fn insert(
+            db_wrapper: Arc<DBWrapper>,
+            comparison: &RevisionComparison,
+        ) -> Result<(), sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const INSERT_QUERY: &str = r#"
+                INSERT INTO revision_comparison(
+                    task_key,
+                    revision_a,
+                    revision_b,
+                    mask_iou,
+                    mean_pixel_diff,
+                    heatmap_path
+                ) VALUES ($1, $2, $3, $4, $5, $6)
+            "#;
+
+            connection
+                .execute(
+                    sqlx::query(INSERT_QUERY)
+                        .bind(comparison.task_key)
+                        .bind(comparison.revision_a)
+                        .bind(comparison.revision_b)
+                        .bind(comparison.mask_iou)
+                        .bind(comparison.mean_pixel_diff)
+                        .bind(&comparison.heatmap_path),
+                )
+                .await?;
+            Ok(())
+        }
+    }
+
+    ///
+    /// This struct is the mapped columns of table `canary_result`.
+    ///
+    #[derive(Debug, sqlx::FromRow)]
+    pub struct CanaryResult {
+        pub task_key: Uuid,
+        pub date_created: DateTime<Utc>,
+        pub primary_millis: Option<i64>,
+        pub canary_millis: Option<i64>,
+        /// Path of the canary mask relative to `MEDIA_ROOT`, or absolute for results stored before.
+        pub canary_mask_path: Option<String>,
+        pub mask_iou: Option<f64>,
+    }
+
+    impl CanaryResult {
+        pub async fn upsert_primary(
+            db_wrapper: Arc<DBWrapper>,
+            task_key: &Uuid,
+            primary_millis: i64,
+        ) -> Result<(), sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const UPSERT_QUERY: &str = r#"
+                INSERT INTO canary_result(task_key, primary_millis) VALUES ($1, $2)
+                ON CONFLICT (task_key) DO UPDATE SET primary_millis=EXCLUDED.primary_millis
+            "#;
+
+            connection
+                .execute(
+                    sqlx::query(UPSERT_QUERY)
+                        .bind(task_key)
+                        .bind(primary_millis),
+                )
+                .await?;
+            Ok(())
+        }
+
+        pub async fn upsert_canary(
+            db_wrapper: Arc<DBWrapper>,
+            task_key: &Uuid,
+            canary_millis: i64,
+            canary_mask_path: &str,
+        ) -> Result<(), sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const UPSERT_QUERY: &str = r#"
+                INSERT INTO canary_result(task_key, canary_millis, canary_mask_path)
+                    VALUES ($1, $2, $3)
+                ON CONFLICT (task_key) DO UPDATE SET
+                    canary_millis=EXCLUDED.canary_millis,
+                    canary_mask_path=EXCLUDED.canary_mask_path
+            "#;
+
+            connection
+                .execute(
+                    sqlx::query(UPSERT_QUERY)
+                        .bind(task_key)
+                        .bind(canary_millis)
+                        .bind(canary_mask_path),
+                )
+                .await?;
+            Ok(())
+        }
+
+        pub async fn fetch(
+            db_wrapper: Arc<DBWrapper>,
+            task_key: &Uuid,
+        ) -> Result<CanaryResult, sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const FETCH_QUERY: &str = "SELECT * FROM canary_result WHERE task_key=$1";
+            sqlx::query_as(FETCH_QUERY)
+                .bind(task_key)
+                .fetch_one(connection)
+                .await
+        }
+
+        pub async fn set_mask_iou(
+            db_wrapper: Arc<DBWrapper>,
+            task_key: &Uuid,
+            mask_iou: f64,
+        ) -> Result<(), sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const UPDATE_QUERY: &str = "UPDATE canary_result SET mask_iou=$1 WHERE task_key=$2";
+            connection
+                .execute(sqlx::query(UPDATE_QUERY).bind(mask_iou).bind(task_key))
+                .await?;
+            Ok(())
+        }
+    }
+
+    ///
+    /// This struct is the mapped columns of table `task_group_notification`.
+    ///
+    #[derive(Debug, sqlx::FromRow)]
+    pub struct TaskGroupNotification {
+        pub task_group: Uuid,
+        pub email: String,
+        pub date_created: DateTime<Utc>,
+        /// Set once the completion email was claimed for sending, and cleared again when sending
+        /// failed. Cleared when tasks are added to the group.
+        pub date_sent: Option<DateTime<Utc>>,
+        /// Sends of the completion email which failed since the group was last registered.
+        pub failed_attempts: i32,
+    }
+
+    impl TaskGroupNotification {
+        ///
+        /// Registers `email` to be notified when all tasks of `task_group` finished. Called after
+        /// inserting the uploaded task, so the group is never seen as finished in between. A group
+        /// which was already notified is notified again once its new tasks finish.
+        ///
+        pub async fn register(
+            db_wrapper: Arc<DBWrapper>,
+            task_group: &Uuid,
+            email: &str,
+        ) -> Result<(), sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const UPSERT_QUERY: &str = r#"
+                INSERT INTO task_group_notification(task_group, email) VALUES ($1, $2)
+                ON CONFLICT (task_group)
+                    DO UPDATE SET email=EXCLUDED.email, date_sent=NULL, failed_attempts=0
+            "#;
+
+            connection
+                .execute(sqlx::query(UPSERT_QUERY).bind(task_group).bind(email))
+                .await?;
+            Ok(())
+        }
+
+        ///
+        /// Marks the notification of the task group of task `key` as sent if every task of the
+        /// group has a result. Returns the notification only to the caller which claimed it, so
+        /// concurrently finishing tasks send a single email.
+        ///
+        pub async fn claim_if_finished(
+            db_wrapper: Arc<DBWrapper>,
+            key: &Uuid,
+        ) -> Result<Option<TaskGroupNotification>, sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const CLAIM_QUERY: &str = r#"
+                UPDATE task_group_notification AS notification
+                    SET date_sent=CURRENT_TIMESTAMP
+                    FROM background_remover_task AS task
+                    WHERE task.key=$1
+                        AND notification.task_group=task.task_group
+                        AND notification.date_sent IS NULL
+                        AND NOT EXISTS (
+                            SELECT 1 FROM background_remover_task
+                                WHERE task_group=notification.task_group
+                                    AND result_status IS NULL
+                        )
+                    RETURNING notification.*
+            "#;
+
+            sqlx::query_as(CLAIM_QUERY)
+                .bind(key)
+                .fetch_optional(connection)
+                .await
+        }
+
+        ///
+        /// Claims up to `limit` notifications of finished groups whose sending failed fewer than
+        /// `max_attempts` times, like `claim_if_finished`.
+        ///
+        pub async fn claim_failed(
+            db_wrapper: Arc<DBWrapper>,
+            max_attempts: i32,
+            limit: i64,
+        ) -> Result<Vec<TaskGroupNotification>, sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const CLAIM_QUERY: &str = r#"
+                UPDATE task_group_notification
+                    SET date_sent=CURRENT_TIMESTAMP
+                    WHERE task_group IN (
+                        SELECT task_group FROM task_group_notification AS notification
+                            WHERE date_sent IS NULL
+                                AND failed_attempts > 0
+                                AND failed_attempts < $1
+                                AND NOT EXISTS (
+                                    SELECT 1 FROM background_remover_task
+                                        WHERE task_group=notification.task_group
+                                            AND result_status IS NULL
+                                )
+                            LIMIT $2
+                            FOR UPDATE SKIP LOCKED
+                    )
+                    RETURNING *
+            "#;
+
+            sqlx::query_as(CLAIM_QUERY)
+                .bind(max_attempts)
+                .bind(limit)
+                .fetch_all(connection)
+                .await
+        }
+
+        ///
+        /// Deletes notifications of task groups whose tasks were all created before `before`,
+        /// sent or not, so their emails aren't kept past anonymization.
+        ///
+        /// Returns number of deleted notifications.
+        ///
+        pub async fn delete_older_than(
+            db_wrapper: Arc<DBWrapper>,
+            before: &DateTime<Utc>,
+        ) -> Result<u64, sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const DELETE_QUERY: &str = r#"
+                DELETE FROM task_group_notification AS notification
+                    WHERE date_created < $1
+                        AND NOT EXISTS (
+                            SELECT 1 FROM background_remover_task
+                                WHERE task_group=notification.task_group
+                                    AND date_created >= $1
+                        )
+            "#;
+
+            let result = connection
+                .execute(sqlx::query(DELETE_QUERY).bind(before))
+                .await?;
+            Ok(result.rows_affected())
+        }
+
+        ///
+        /// Releases the claim of a completion email which could not be sent, so it is retried.
+        ///
+        pub async fn release_failed(
+            db_wrapper: Arc<DBWrapper>,
+            task_group: &Uuid,
+        ) -> Result<(), sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const UPDATE_QUERY: &str = r#"
+                UPDATE task_group_notification
+                    SET date_sent=NULL, failed_attempts=failed_attempts + 1
+                    WHERE task_group=$1
+            "#;
+
+            connection
+                .execute(sqlx::query(UPDATE_QUERY).bind(task_group))
+                .await?;
+            Ok(())
+        }
+    }
+
+    ///
+    /// Output file of a task listed in its manifest.
+    ///
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct ManifestFile {
+        /// One of `MASK`, `PROCESSED`, `PREVIEW_PROCESSED`, `UNWATERMARKED` and `UNCAPPED_MASK`.
+        pub role: String,
+        /// Relative path: media/image.png
+        pub path: String,
+        pub size_bytes: u64,
+        /// Lowercase hex SHA-256 of the file content.
+        pub sha256: String,
+    }
+
+    impl ManifestFile {
+        pub const MASK: &'static str = "mask";
+        pub const PROCESSED: &'static str = "processed";
+        pub const PREVIEW_PROCESSED: &'static str = "preview_processed";
+        /// Processed image of a free tier task as received, before it was scaled down and
+        /// watermarked. Never served.
+        pub const UNWATERMARKED: &'static str = "unwatermarked";
+        /// Mask of a free tier task as received, before it was scaled down. Never served.
+        pub const UNCAPPED_MASK: &'static str = "uncapped_mask";
+    }
+
+    ///
+    /// This struct is the mapped columns of table `task_manifest`.
+    ///
+    #[derive(Debug, sqlx::FromRow)]
+    pub struct TaskManifest {
+        pub task_key: Uuid,
+        /// Where the files are stored. Only `local`, files under `MEDIA_ROOT`, for now.
+        pub storage_backend: String,
+        pub files: Json<Vec<ManifestFile>>,
+        pub date_updated: DateTime<Utc>,
+    }
+
+    impl TaskManifest {
+        pub const LOCAL_STORAGE: &'static str = "local";
+
+        ///
+        /// Replaces the manifest of the task `key` with `files` within `transaction`.
+        ///
+        pub async fn upsert(
+            transaction: &mut Transaction<'_, Postgres>,
+            key: &Uuid,
+            files: &[ManifestFile],
+        ) -> Result<(), sqlx::Error> {
+            const UPSERT_QUERY: &str = r#"
+                INSERT INTO task_manifest(task_key, storage_backend, files) VALUES ($1, $2, $3)
+                ON CONFLICT (task_key) DO UPDATE
+                    SET storage_backend=EXCLUDED.storage_backend,
+                        files=EXCLUDED.files,
+                        date_updated=CURRENT_TIMESTAMP
+            "#;
+
+            sqlx::query(UPSERT_QUERY)
+                .bind(key)
+                .bind(Self::LOCAL_STORAGE)
+                .bind(Json(files))
+                .execute(&mut **transaction)
+                .await?;
+            Ok(())
+        }
+
+        ///
+        /// Replaces the file with `role` in the manifest of the task `key` by `file`, or removes
+        /// it when `file` is `None`. Tasks without manifest are left as they are.
+        ///
+        pub async fn replace_file(
+            transaction: &mut Transaction<'_, Postgres>,
+            key: &Uuid,
+            role: &str,
+            file: Option<&ManifestFile>,
+        ) -> Result<(), sqlx::Error> {
+            const UPDATE_QUERY: &str = r#"
+                UPDATE task_manifest
+                SET
+                    files=(
+                        SELECT COALESCE(jsonb_agg(file), '[]'::jsonb)
+                            FROM jsonb_array_elements(files) AS file
+                            WHERE file->>'role' <> $2
+                    ) || COALESCE($3, '[]'::jsonb),
+                    date_updated=CURRENT_TIMESTAMP
+                WHERE task_key=$1
+            "#;
+
+            sqlx::query(UPDATE_QUERY)
+                .bind(key)
+                .bind(role)
+                .bind(file.map(|file| Json(vec![file])))
+                .execute(&mut **transaction)
+                .await?;
+            Ok(())
+        }
+
+        ///
+        /// Returns manifests of the tasks with `keys` which have one.
+        ///
+        pub async fn fetch_by_keys(
+            db_wrapper: Arc<DBWrapper>,
+            keys: &[Uuid],
+        ) -> Result<Vec<TaskManifest>, sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const FETCH_QUERY: &str = r#"
+                SELECT * FROM task_manifest WHERE task_key = ANY($1)
+            "#;
+
+            sqlx::query_as(FETCH_QUERY)
+                .bind(keys)
+                .fetch_all(connection)
+                .await
+        }
+    }
+
+    ///
+    /// This struct is the mapped columns of table `notification_outbox`.
+    ///
+    #[derive(Debug, sqlx::FromRow)]
+    pub struct OutboxEntry {
+        pub id: i64,
+        pub task_key: Uuid,
+        pub task_group: Uuid,
+        /// What to deliver. Only `task_result` for now: the result websocket message and the
+        /// batch completion email.
+        pub kind: String,
+        /// Deliveries started by the outbox job.
+        pub attempts: i32,
+        pub date_created: DateTime<Utc>,
+        /// Set when the outbox job started a delivery. Claims expire, so a replica crashing
+        /// while delivering doesn't hold the entry forever.
+        pub date_claimed: Option<DateTime<Utc>>,
+        pub date_sent: Option<DateTime<Utc>>,
+    }
+
+    impl OutboxEntry {
+        pub const TASK_RESULT: &'static str = "task_result";
+
+        ///
+        /// Queues a notification of the task `key` within `transaction`. Returns its id.
+        ///
+        pub async fn insert(
+            transaction: &mut Transaction<'_, Postgres>,
+            key: &Uuid,
+            kind: &str,
+        ) -> Result<i64, sqlx::Error> {
+            const INSERT_QUERY: &str = r#"
+                INSERT INTO notification_outbox(task_key, task_group, kind)
+                    SELECT key, task_group, $2 FROM background_remover_task WHERE key=$1
+                    RETURNING id
+            "#;
+
+            let (id,): (i64,) = sqlx::query_as(INSERT_QUERY)
+                .bind(key)
+                .bind(kind)
+                .fetch_one(&mut **transaction)
+                .await?;
+            Ok(id)
+        }
+
+        pub async fn mark_sent(db_wrapper: Arc<DBWrapper>, id: i64) -> Result<(), sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const UPDATE_QUERY: &str = r#"
+                UPDATE notification_outbox SET date_sent=CURRENT_TIMESTAMP WHERE id=$1
+            "#;
+
+            connection
+                .execute(sqlx::query(UPDATE_QUERY).bind(id))
+                .await?;
+            Ok(())
+        }
+
+        ///
+        /// Claims up to `limit` unsent entries older than `older_than`, leaving recent entries
+        /// to the replica which created them. Entries claimed more than `claim_timeout` ago are
+        /// claimed again. Replicas never claim the same entry at once.
+        ///
+        pub async fn claim_pending(
+            db_wrapper: Arc<DBWrapper>,
+            older_than: Duration,
+            claim_timeout: Duration,
+            limit: i64,
+        ) -> Result<Vec<OutboxEntry>, sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const CLAIM_QUERY: &str = r#"
+                UPDATE notification_outbox
+                    SET date_claimed=CURRENT_TIMESTAMP, attempts=attempts + 1
+                    WHERE id IN (
+                        SELECT id FROM notification_outbox
+                            WHERE date_sent IS NULL
+                                AND date_created < CURRENT_TIMESTAMP - make_interval(secs => $1)
+                                AND (
+                                    date_claimed IS NULL
+                                    OR date_claimed < CURRENT_TIMESTAMP - make_interval(secs => $2)
+                                )
+                            ORDER BY date_created
+                            LIMIT $3
+                            FOR UPDATE SKIP LOCKED
+                    )
+                    RETURNING *
+            "#;
+
+            sqlx::query_as(CLAIM_QUERY)
+                .bind(older_than.as_secs_f64())
+                .bind(claim_timeout.as_secs_f64())
+                .bind(limit)
+                .fetch_all(connection)
+                .await
+        }
+
+        ///
+        /// Removes entries sent before `before`.
+        ///
+        pub async fn delete_sent_before(
+            db_wrapper: Arc<DBWrapper>,
+            before: DateTime<Utc>,
+        ) -> Result<u64, sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const DELETE_QUERY: &str = r#"
+                DELETE FROM notification_outbox WHERE date_sent < $1
+            "#;
+
+            let result = connection
+                .execute(sqlx::query(DELETE_QUERY).bind(before))
+                .await?;
+            Ok(result.rows_affected())
+        }
+    }
+
+    ///
+    /// This struct is the mapped columns of table `bp_message`.
+    ///
+    #[derive(Debug, Serialize, sqlx::FromRow)]
+    pub struct BpMessage {
+        pub id: i64,
+        pub date_created: DateTime<Utc>,
+        /// Task key, or revision key of refinements.
+        pub task_key: Uuid,
+        pub bp_address: String,
+        /// `sent` or `received`.
+        pub direction: String,
+        pub message: Value,
+    }
+
+    impl BpMessage {
+        pub async fn insert(
+            db_wrapper: Arc<DBWrapper>,
+            task_key: &Uuid,
+            bp_address: &str,
+            direction: &str,
+            message: &Value,
+        ) -> Result<(), sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const INSERT_QUERY: &str = r#"
+                INSERT INTO bp_message(task_key, bp_address, direction, message)
+                    VALUES ($1, $2, $3, $4)
+            "#;
+
+            connection
+                .execute(
+                    sqlx::query(INSERT_QUERY)
+                        .bind(task_key)
+                        .bind(bp_address)
+                        .bind(direction)
+                        .bind(message),
+                )
+                .await?;
+            Ok(())
+        }
+
+        ///
+        /// Returns the messages of `task_keys` in the order they were archived.
+        ///
+        pub async fn fetch_by_task_keys(
+            db_wrapper: Arc<DBWrapper>,
+            task_keys: &[Uuid],
+        ) -> Result<Vec<BpMessage>, sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const FETCH_QUERY: &str = r#"
+                SELECT * FROM bp_message WHERE task_key = ANY($1) ORDER BY id ASC
+            "#;
+
+            sqlx::query_as(FETCH_QUERY)
+                .bind(task_keys)
+                .fetch_all(connection)
+                .await
+        }
+
+        ///
+        /// Deletes all but the newest `max_rows` messages.
+        ///
+        /// Returns number of deleted messages.
+        ///
+        pub async fn trim(db_wrapper: Arc<DBWrapper>, max_rows: i64) -> Result<u64, sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const DELETE_QUERY: &str = r#"
+                DELETE FROM bp_message
+                    WHERE id <= (SELECT id FROM bp_message ORDER BY id DESC OFFSET $1 LIMIT 1)
+            "#;
+
+            let result = connection
+                .execute(sqlx::query(DELETE_QUERY).bind(max_rows))
+                .await?;
+            Ok(result.rows_affected())
+        }
+    }
+
+    ///
+    /// This struct is the mapped columns of table `scheduled_job`.
+    ///
+    #[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+    pub struct ScheduledJobRun {
+        pub name: String,
+        pub scheduled_for: DateTime<Utc>,
+        pub last_started: DateTime<Utc>,
+        /// `None` while running, or if the replica running it died.
+        pub last_finished: Option<DateTime<Utc>>,
+        /// `running`, `succeeded` or `failed`.
+        pub last_status: String,
+        pub last_message: Option<String>,
+        /// `SID` or process id of the replica which ran it.
+        pub last_instance: String,
+        pub runs: i64,
+        pub failures: i64,
+    }
+
+    impl ScheduledJobRun {
+        ///
+        /// Claims run time `scheduled_for` of job `name` unless it or a later one was claimed
+        /// already. Returns whether it was claimed.
+        ///
+        pub async fn claim(
+            db_wrapper: Arc<DBWrapper>,
+            name: &str,
+            scheduled_for: &DateTime<Utc>,
+            instance: &str,
+        ) -> Result<bool, sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const CLAIM_QUERY: &str = r#"
+                INSERT INTO scheduled_job(name, scheduled_for, last_started, last_status, last_instance)
+                    VALUES ($1, $2, NOW(), 'running', $3)
+                ON CONFLICT (name) DO UPDATE SET
+                    scheduled_for=EXCLUDED.scheduled_for,
+                    last_started=EXCLUDED.last_started,
+                    last_finished=NULL,
+                    last_status=EXCLUDED.last_status,
+                    last_message=NULL,
+                    last_instance=EXCLUDED.last_instance
+                WHERE scheduled_job.scheduled_for < EXCLUDED.scheduled_for
+            "#;
+
+            let result = connection
+                .execute(
+                    sqlx::query(CLAIM_QUERY)
+                        .bind(name)
+                        .bind(scheduled_for)
+                        .bind(instance),
+                )
+                .await?;
+            Ok(result.rows_affected() > 0)
+        }
+
+        pub async fn finish(
+            db_wrapper: Arc<DBWrapper>,
+            name: &str,
+            succeeded: bool,
+            message: &str,
+        ) -> Result<(), sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const FINISH_QUERY: &str = r#"
+                UPDATE scheduled_job SET
+                    last_finished=NOW(),
+                    last_status=$2,
+                    last_message=$3,
+                    runs=runs + 1,
+                    failures=failures + (CASE WHEN $4 THEN 0 ELSE 1 END)
+                WHERE name=$1
+            "#;
+
+            connection
+                .execute(
+                    sqlx::query(FINISH_QUERY)
+                        .bind(name)
+                        .bind(if succeeded { "succeeded" } else { "failed" })
+                        .bind(message)
+                        .bind(succeeded),
+                )
+                .await?;
+            Ok(())
+        }
+
+        pub async fn fetch_all(
+            db_wrapper: Arc<DBWrapper>,
+        ) -> Result<Vec<ScheduledJobRun>, sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const FETCH_QUERY: &str = r#"
+                SELECT * FROM scheduled_job ORDER BY name ASC
+            "#;
+
+            sqlx::query_as(FETCH_QUERY).fetch_all(connection).await
+        }
+    }
+
+    ///
+    /// Postgres session level advisory lock, held on a connection taken out of the pool until
+    /// released. Postgres releases it if the connection is lost, so a dead replica never keeps it.
+    ///
+    pub struct AdvisoryLock {
+        connection: PoolConnection<Postgres>,
+        key: i64,
+    }
+
+    impl AdvisoryLock {
+        ///
+        /// Takes lock `key` without waiting. `None` if another session holds it.
+        ///
+        pub async fn try_acquire(
+            db_wrapper: Arc<DBWrapper>,
+            key: i64,
+        ) -> Result<Option<AdvisoryLock>, sqlx::Error> {
+            let mut connection = db_wrapper.pool.acquire().await?;
+
+            let (locked,): (bool,) = sqlx::query_as("SELECT pg_try_advisory_lock($1)")
+                .bind(key)
+                .fetch_one(&mut *connection)
+                .await?;
+            Ok(locked.then_some(AdvisoryLock { connection, key }))
+        }
+
+        pub async fn release(mut self) {
+            let unlocked = sqlx::query("SELECT pg_advisory_unlock($1)")
+                .bind(self.key)
+                .execute(&mut *self.connection)
+                .await;
+
+            if let Err(error) = unlocked {
+                eprintln!("Failed to release advisory lock. Error: {}", error);
+                // Ends the session, which releases the lock, instead of pooling it locked.
+                let _ = self.connection.close().await;
+            }
+        }
+    }
+
+    ///
+    /// Lease on `name` until `expires_at`, stored in table `lease`. Unlike an advisory lock it
+    /// holds no connection, so it suits work which outlives a request, and it is taken over by
+    /// others once expired, e.g. after its holder died.
+    ///
+    pub struct Lease {
+        db_wrapper: Arc<DBWrapper>,
+        pub name: String,
+        /// Tells this lease apart from later leases on the same name.
+        pub token: Uuid,
+        pub expires_at: DateTime<Utc>,
+    }
+
+    impl Lease {
+        ///
+        /// Takes lease `name` for `ttl` unless an unexpired lease on it exists. `holder` is kept
+        /// for inspection only.
+        ///
+        pub async fn try_acquire(
+            db_wrapper: Arc<DBWrapper>,
+            name: &str,
+            holder: &str,
+            ttl: Duration,
+        ) -> Result<Option<Lease>, sqlx::Error> {
+            let connection = db_wrapper.timed();
+            let token = Uuid::new_v4();
+
+            const ACQUIRE_QUERY: &str = r#"
+                INSERT INTO lease(name, token, holder, expires_at)
+                    VALUES ($1, $2, $3, CURRENT_TIMESTAMP + make_interval(secs => $4))
+                ON CONFLICT (name) DO UPDATE SET
+                    token=EXCLUDED.token,
+                    holder=EXCLUDED.holder,
+                    expires_at=EXCLUDED.expires_at
+                WHERE lease.expires_at <= CURRENT_TIMESTAMP
+                RETURNING expires_at
+            "#;
+
+            let expires_at: Option<(DateTime<Utc>,)> = sqlx::query_as(ACQUIRE_QUERY)
+                .bind(name)
+                .bind(token)
+                .bind(holder)
+                .bind(ttl.as_secs_f64())
+                .fetch_optional(connection)
+                .await?;
+
+            Ok(expires_at.map(|(expires_at,)| Lease {
+                db_wrapper,
+                name: name.to_string(),
+                token,
+                expires_at,
+            }))
+        }
+
+        ///
+        /// Extends the lease to `ttl` from now. Returns false if it expired and was taken over,
+        /// in which case the work it guards must stop.
+        ///
+        pub async fn renew(&mut self, ttl: Duration) -> Result<bool, sqlx::Error> {
+            let connection = self.db_wrapper.timed();
+
+            const RENEW_QUERY: &str = r#"
+                UPDATE lease SET expires_at=CURRENT_TIMESTAMP + make_interval(secs => $3)
+                    WHERE name=$1 AND token=$2
+                    RETURNING expires_at
+            "#;
+
+            let expires_at: Option<(DateTime<Utc>,)> = sqlx::query_as(RENEW_QUERY)
+                .bind(&self.name)
+                .bind(self.token)
+                .bind(ttl.as_secs_f64())
+                .fetch_optional(connection)
+                .await?;
+
+            match expires_at {
+                Some((expires_at,)) => {
+                    self.expires_at = expires_at;
+                    Ok(true)
+                }
+                None => Ok(false),
+            }
+        }
+
+        ///
+        /// Gives the lease up before it expires. Leases which were taken over are left alone.
+        ///
+        pub async fn release(self) {
+            let connection = self.db_wrapper.timed();
+
+            const RELEASE_QUERY: &str = r#"
+                DELETE FROM lease WHERE name=$1 AND token=$2
+            "#;
+
+            let result = connection
+                .execute(sqlx::query(RELEASE_QUERY).bind(&self.name).bind(self.token))
+                .await;
+            if let Err(error) = result {
+                eprintln!("Failed to release lease {}. Error: {}", self.name, error);
+            }
+        }
+
+        ///
+        /// Removes leases which expired before `before`.
+        ///
+        pub async fn delete_expired(
+            db_wrapper: Arc<DBWrapper>,
+            before: DateTime<Utc>,
+        ) -> Result<u64, sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const DELETE_QUERY: &str = r#"
+                DELETE FROM lease WHERE expires_at < $1
+            "#;
+
+            let result = connection
+                .execute(sqlx::query(DELETE_QUERY).bind(before))
+                .await?;
+            Ok(result.rows_affected())
+        }
+    }
+
+    ///
+    /// This struct is the mapped columns of table `billing_plan`.
+    ///
+    #[derive(Debug, Clone, sqlx::FromRow)]
+    pub struct BillingPlan {
+        /// `BillingAccount::plan_key` of the account the subscription pays for.
+        pub key_id: String,
+        pub stripe_customer_id: String,
+        pub stripe_subscription_id: String,
+        /// Metered item usage is reported to. `None` for subscriptions without items.
+        pub stripe_subscription_item_id: Option<String>,
+        /// Price lookup key of the subscription item.
+        pub plan: Option<String>,
+        /// Stripe subscription status, e.g. `active` or `past_due`.
+        pub status: String,
+        /// Successful tasks per calendar month (UTC). `None` is unlimited.
+        pub monthly_quota: Option<i64>,
+        pub priority: i32,
+        pub date_updated: DateTime<Utc>,
+        /// Creation time of the last Stripe event applied. `None` for plans stored before.
+        pub event_created: Option<DateTime<Utc>>,
+    }
+
+    impl BillingPlan {
+        pub async fn fetch(
+            db_wrapper: Arc<DBWrapper>,
+            key_id: &str,
+        ) -> Result<Option<BillingPlan>, sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const FETCH_QUERY: &str = "SELECT * FROM billing_plan WHERE key_id=$1";
+            sqlx::query_as(FETCH_QUERY)
+                .bind(key_id)
+                .fetch_optional(connection)
+                .await
+        }
+
+        ///
+        /// Inserts the plan, or replaces the plan of its key id. A plan of the same subscription
+        /// stored from a later event is kept. Returns false if so.
+        ///
+        pub async fn upsert(
+            db_wrapper: Arc<DBWrapper>,
+            plan: &BillingPlan,
+        ) -> Result<bool, sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const UPSERT_QUERY: &str = r#"
+                INSERT INTO billing_plan(
+                    key_id,
+                    stripe_customer_id,
+                    stripe_subscription_id,
+                    stripe_subscription_item_id,
+                    plan,
+                    status,
+                    monthly_quota,
+                    priority,
+                    event_created
+                ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+                ON CONFLICT (key_id) DO UPDATE SET
+                    stripe_customer_id=EXCLUDED.stripe_customer_id,
+                    stripe_subscription_id=EXCLUDED.stripe_subscription_id,
+                    stripe_subscription_item_id=EXCLUDED.stripe_subscription_item_id,
+                    plan=EXCLUDED.plan,
+                    status=EXCLUDED.status,
+                    monthly_quota=EXCLUDED.monthly_quota,
+                    priority=EXCLUDED.priority,
+                    date_updated=CURRENT_TIMESTAMP,
+                    event_created=EXCLUDED.event_created
+                WHERE billing_plan.stripe_subscription_id<>EXCLUDED.stripe_subscription_id
+                    OR billing_plan.event_created IS NULL
+                    OR billing_plan.event_created<=EXCLUDED.event_created
+            "#;
+
+            let result = connection
+                .execute(
+                    sqlx::query(UPSERT_QUERY)
+                        .bind(&plan.key_id)
+                        .bind(&plan.stripe_customer_id)
+                        .bind(&plan.stripe_subscription_id)
+                        .bind(&plan.stripe_subscription_item_id)
+                        .bind(&plan.plan)
+                        .bind(&plan.status)
+                        .bind(plan.monthly_quota)
+                        .bind(plan.priority)
+                        .bind(plan.event_created),
+                )
+                .await?;
+            Ok(result.rows_affected() > 0)
+        }
+
+        ///
+        /// Removes the plan paid by the subscription, unless it was stored from an event created
+        /// after `event_created`. Returns false if no plan was removed.
+        ///
+        pub async fn delete_by_subscription(
+            db_wrapper: Arc<DBWrapper>,
+            stripe_subscription_id: &str,
+            event_created: Option<DateTime<Utc>>,
+        ) -> Result<bool, sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const DELETE_QUERY: &str = r#"
+                DELETE FROM billing_plan
+                    WHERE stripe_subscription_id=$1
+                        AND (event_created IS NULL OR $2::TIMESTAMPTZ IS NULL OR event_created<=$2)
+            "#;
+            let result = connection
+                .execute(
+                    sqlx::query(DELETE_QUERY)
+                        .bind(stripe_subscription_id)
+                        .bind(event_created),
+                )
+                .await?;
+            Ok(result.rows_affected() > 0)
+        }
+    }
+
+    ///
+    /// Rows of table `api_key_usage`.
+    ///
+    pub struct ApiKeyUsage;
+
+    impl ApiKeyUsage {
+        ///
+        /// Records the success of the task `key` within `transaction`, if it was a signed upload,
+        /// together with the organization currently owning its key id.
+        ///
+        pub async fn record(
+            transaction: &mut Transaction<'_, Postgres>,
+            key: &Uuid,
+        ) -> Result<(), sqlx::Error> {
+            const INSERT_QUERY: &str = r#"
+                INSERT INTO api_key_usage(task_key, key_id, organization_id)
+                    SELECT key, api_key_id, organization_api_key.organization_id
+                        FROM background_remover_task
+                        LEFT JOIN organization_api_key
+                            ON organization_api_key.key_id=background_remover_task.api_key_id
+                        WHERE key=$1 AND api_key_id IS NOT NULL
+                    ON CONFLICT (task_key) DO NOTHING
+            "#;
+
+            sqlx::query(INSERT_QUERY)
+                .bind(key)
+                .execute(&mut **transaction)
+                .await?;
+            Ok(())
+        }
+
+        ///
+        /// Returns the number of successful tasks billed to `account` since `since`. Tasks of a
+        /// key while it belonged to an organization are billed to the organization.
+        ///
+        pub async fn count_since(
+            db_wrapper: Arc<DBWrapper>,
+            account: &BillingAccount,
+            since: DateTime<Utc>,
+        ) -> Result<i64, sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const COUNT_QUERY: &str = r#"
+                SELECT COUNT(*) FROM api_key_usage
+                    WHERE date_created >= $3
+                        AND CASE WHEN $2::BIGINT IS NULL
+                            THEN organization_id IS NULL AND key_id=$1
+                            ELSE organization_id=$2
+                        END
+            "#;
+
+            let (key_id, organization_id) = match account {
+                BillingAccount::Key(key_id) => (Some(key_id.as_str()), None),
+                BillingAccount::Organization(organization_id) => (None, Some(*organization_id)),
+            };
+            let (count,): (i64,) = sqlx::query_as(COUNT_QUERY)
+                .bind(key_id)
+                .bind(organization_id)
+                .bind(since)
+                .fetch_one(connection)
+                .await?;
+            Ok(count)
+        }
+
+        ///
+        /// Returns the successful tasks billed to organization `organization_id` since `since`
+        /// per key id, most used first.
+        ///
+        pub async fn count_by_key_since(
+            db_wrapper: Arc<DBWrapper>,
+            organization_id: i64,
+            since: DateTime<Utc>,
+        ) -> Result<Vec<(String, i64)>, sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const COUNT_QUERY: &str = r#"
+                SELECT key_id, COUNT(*) FROM api_key_usage
+                    WHERE organization_id=$1 AND date_created >= $2
+                    GROUP BY key_id
+                    ORDER BY COUNT(*) DESC, key_id ASC
+            "#;
+
+            sqlx::query_as(COUNT_QUERY)
+                .bind(organization_id)
+                .bind(since)
+                .fetch_all(connection)
+                .await
+        }
+    }
+
+    ///
+    /// This struct is the mapped columns of table `billing_usage_report`.
+    ///
+    #[derive(Debug, sqlx::FromRow)]
+    pub struct UsageReport {
+        pub id: i64,
+        /// `BillingAccount::plan_key` of the billed account.
+        pub key_id: String,
+        pub stripe_subscription_item_id: String,
+        /// Successful tasks covered by the report.
+        pub quantity: i64,
+        /// Sends started by the usage report job.
+        pub attempts: i32,
+        pub date_created: DateTime<Utc>,
+        /// Set when a send started. Claims expire, so a replica crashing while sending doesn't
+        /// hold the report forever.
+        pub date_claimed: Option<DateTime<Utc>>,
+        pub date_sent: Option<DateTime<Utc>>,
+    }
+
+    impl UsageReport {
+        ///
+        /// Moves up to `limit` unreported usage rows of accounts with a metered subscription into
+        /// new reports, one per account. Returns the number of reports created.
+        ///
+        pub async fn create_pending(
+            db_wrapper: Arc<DBWrapper>,
+            limit: i64,
+        ) -> Result<usize, sqlx::Error> {
+            const CLAIM_QUERY: &str = r#"
+                SELECT api_key_usage.task_key, billing_plan.key_id,
+                        billing_plan.stripe_subscription_item_id
+                    FROM api_key_usage
+                    -- Plan key of the account, see `BillingAccount::plan_key`.
+                    JOIN billing_plan ON billing_plan.key_id=COALESCE(
+                        'org:' || api_key_usage.organization_id,
+                        api_key_usage.key_id
+                    )
+                    WHERE api_key_usage.report_id IS NULL
+                        AND billing_plan.stripe_subscription_item_id IS NOT NULL
+                    LIMIT $1
+                    FOR UPDATE OF api_key_usage SKIP LOCKED
+            "#;
+
+            const INSERT_QUERY: &str = r#"
+                INSERT INTO billing_usage_report(key_id, stripe_subscription_item_id, quantity)
+                    VALUES ($1, $2, $3)
+                    RETURNING id
+            "#;
+
+            const UPDATE_QUERY: &str = r#"
+                UPDATE api_key_usage SET report_id=$1 WHERE task_key = ANY($2)
+            "#;
+
+            let mut transaction = db_wrapper.pool.begin().await?;
+            let rows: Vec<(Uuid, String, String)> = sqlx::query_as(CLAIM_QUERY)
+                .bind(limit)
+                .fetch_all(&mut *transaction)
+                .await?;
+
+            // (plan key, subscription item id) -> task keys
+            let mut by_key: HashMap<(String, String), Vec<Uuid>> = HashMap::new();
+            for (task_key, key_id, item_id) in rows {
+                by_key.entry((key_id, item_id)).or_default().push(task_key);
+            }
+
+            let created = by_key.len();
+            for ((key_id, item_id), task_keys) in by_key {
+                let (report_id,): (i64,) = sqlx::query_as(INSERT_QUERY)
+                    .bind(&key_id)
+                    .bind(&item_id)
+                    .bind(task_keys.len() as i64)
+                    .fetch_one(&mut *transaction)
+                    .await?;
+
+                sqlx::query(UPDATE_QUERY)
+                    .bind(report_id)
+                    .bind(&task_keys)
+                    .execute(&mut *transaction)
+                    .await?;
+            }
+
+            transaction.commit().await?;
+            Ok(created)
+        }
+
+        ///
+        /// Claims up to `limit` unsent reports. Reports claimed more than `claim_timeout` ago
+        /// are claimed again. Replicas never claim the same report at once.
+        ///
+        pub async fn claim_unsent(
+            db_wrapper: Arc<DBWrapper>,
+            claim_timeout: Duration,
+            limit: i64,
+        ) -> Result<Vec<UsageReport>, sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const CLAIM_QUERY: &str = r#"
+                UPDATE billing_usage_report
+                    SET date_claimed=CURRENT_TIMESTAMP, attempts=attempts + 1
+                    WHERE id IN (
+                        SELECT id FROM billing_usage_report
+                            WHERE date_sent IS NULL
+                                AND (
+                                    date_claimed IS NULL
+                                    OR date_claimed < CURRENT_TIMESTAMP - make_interval(secs => $1)
+                                )
+                            ORDER BY date_created
+                            LIMIT $2
+                            FOR UPDATE SKIP LOCKED
+                    )
+                    RETURNING *
+            "#;
+
+            sqlx::query_as(CLAIM_QUERY)
+                .bind(claim_timeout.as_secs_f64())
+                .bind(limit)
+                .fetch_all(connection)
+                .await
+        }
+
+        pub async fn mark_sent(db_wrapper: Arc<DBWrapper>, id: i64) -> Result<(), sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const UPDATE_QUERY: &str = r#"
+                UPDATE billing_usage_report SET date_sent=CURRENT_TIMESTAMP WHERE id=$1
+            "#;
+
+            connection
+                .execute(sqlx::query(UPDATE_QUERY).bind(id))
+                .await?;
+            Ok(())
+        }
+    }
+
+    ///
+    /// This struct is the mapped columns of table `organization`.
+    ///
+    #[derive(Debug, Serialize, sqlx::FromRow)]
+    pub struct Organization {
+        pub id: i64,
+        pub name: String,
+        pub date_created: DateTime<Utc>,
+    }
+
+    impl Organization {
+        pub async fn create(
+            db_wrapper: Arc<DBWrapper>,
+            name: &str,
+        ) -> Result<Organization, sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const INSERT_QUERY: &str = "INSERT INTO organization(name) VALUES ($1) RETURNING *";
+            sqlx::query_as(INSERT_QUERY)
+                .bind(name)
+                .fetch_one(connection)
+                .await
+        }
+
+        pub async fn fetch(
+            db_wrapper: Arc<DBWrapper>,
+            id: i64,
+        ) -> Result<Option<Organization>, sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const FETCH_QUERY: &str = "SELECT * FROM organization WHERE id=$1";
+            sqlx::query_as(FETCH_QUERY)
+                .bind(id)
+                .fetch_optional(connection)
+                .await
+        }
+
+        pub async fn fetch_all(
+            db_wrapper: Arc<DBWrapper>,
+        ) -> Result<Vec<Organization>, sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const FETCH_QUERY: &str = "SELECT * FROM organization ORDER BY id ASC";
+            sqlx::query_as(FETCH_QUERY).fetch_all(connection).await
+        }
+
+        ///
+        /// Removes the organization with its API keys and members. Usage already recorded stays
+        /// billed to it. Returns false if it didn't exist.
+        ///
+        pub async fn delete(db_wrapper: Arc<DBWrapper>, id: i64) -> Result<bool, sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const DELETE_QUERY: &str = "DELETE FROM organization WHERE id=$1";
+            let result = connection
+                .execute(sqlx::query(DELETE_QUERY).bind(id))
+                .await?;
+            Ok(result.rows_affected() > 0)
+        }
+
+        ///
+        /// Returns the upload signing key ids owned by the organization.
+        ///
+        pub async fn api_key_ids(
+            db_wrapper: Arc<DBWrapper>,
+            id: i64,
+        ) -> Result<Vec<String>, sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const FETCH_QUERY: &str = r#"
+                SELECT key_id FROM organization_api_key WHERE organization_id=$1 ORDER BY key_id
+            "#;
+
+            let rows: Vec<(String,)> = sqlx::query_as(FETCH_QUERY)
+                .bind(id)
+                .fetch_all(connection)
+                .await?;
+            Ok(rows.into_iter().map(|(key_id,)| key_id).collect())
+        }
+
+        ///
+        /// Adds upload signing key id `key_id` to the organization. Returns false if the key id
+        /// already belongs to an organization.
+        ///
+        pub async fn add_api_key(
+            db_wrapper: Arc<DBWrapper>,
+            id: i64,
+            key_id: &str,
+        ) -> Result<bool, sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const INSERT_QUERY: &str = r#"
+                INSERT INTO organization_api_key(key_id, organization_id) VALUES ($1, $2)
+                    ON CONFLICT (key_id) DO NOTHING
+            "#;
+
+            let result = connection
+                .execute(sqlx::query(INSERT_QUERY).bind(key_id).bind(id))
+                .await?;
+            Ok(result.rows_affected() > 0)
+        }
+
+        ///
+        /// Removes key id `key_id` from the organization. Returns false if it didn't own it.
+        ///
+        pub async fn remove_api_key(
+            db_wrapper: Arc<DBWrapper>,
+            id: i64,
+            key_id: &str,
+        ) -> Result<bool, sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const DELETE_QUERY: &str = r#"
+                DELETE FROM organization_api_key WHERE key_id=$1 AND organization_id=$2
+            "#;
+
+            let result = connection
+                .execute(sqlx::query(DELETE_QUERY).bind(key_id).bind(id))
+                .await?;
+            Ok(result.rows_affected() > 0)
+        }
+
+        ///
+        /// Returns the id of the organization owning key id `key_id`, if any.
+        ///
+        pub async fn id_of_api_key(
+            db_wrapper: Arc<DBWrapper>,
+            key_id: &str,
+        ) -> Result<Option<i64>, sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const FETCH_QUERY: &str = r#"
+                SELECT organization_id FROM organization_api_key WHERE key_id=$1
+            "#;
+
+            let row: Option<(i64,)> = sqlx::query_as(FETCH_QUERY)
+                .bind(key_id)
+                .fetch_optional(connection)
+                .await?;
+            Ok(row.map(|(organization_id,)| organization_id))
+        }
+    }
+
+    ///
+    /// This struct is the mapped columns of table `organization_member`.
+    ///
+    #[derive(Debug, Serialize, sqlx::FromRow)]
+    pub struct OrganizationMember {
+        pub id: i64,
+        pub organization_id: i64,
+        pub email: String,
+        /// `admin` or `viewer`. See `Role`.
+        pub role: String,
+        /// SHA-256 of the member token. See `organization_utils::hash_member_token`.
+        #[serde(skip)]
+        pub token_hash: String,
+        pub date_created: DateTime<Utc>,
+    }
+
+    impl OrganizationMember {
+        ///
+        /// Role of the member. Unknown roles, which are never written, only view.
+        ///
+        pub fn role(&self) -> Role {
+            Role::parse(&self.role).unwrap_or(Role::Viewer)
+        }
+
+        ///
+        /// Inserts a member. Returns `None` if the organization already has a member with the
+        /// same email.
+        ///
+        pub async fn insert(
+            db_wrapper: Arc<DBWrapper>,
+            organization_id: i64,
+            email: &str,
+            role: Role,
+            token_hash: &str,
+        ) -> Result<Option<OrganizationMember>, sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const INSERT_QUERY: &str = r#"
+                INSERT INTO organization_member(organization_id, email, role, token_hash)
+                    VALUES ($1, $2, $3, $4)
+                    ON CONFLICT (organization_id, email) DO NOTHING
+                    RETURNING *
+            "#;
+
+            sqlx::query_as(INSERT_QUERY)
+                .bind(organization_id)
+                .bind(email)
+                .bind(role.as_str())
+                .bind(token_hash)
+                .fetch_optional(connection)
+                .await
+        }
+
+        pub async fn fetch_by_token_hash(
+            db_wrapper: Arc<DBWrapper>,
+            token_hash: &str,
+        ) -> Result<Option<OrganizationMember>, sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const FETCH_QUERY: &str = "SELECT * FROM organization_member WHERE token_hash=$1";
+            sqlx::query_as(FETCH_QUERY)
+                .bind(token_hash)
+                .fetch_optional(connection)
+                .await
+        }
+
+        pub async fn fetch_by_organization(
+            db_wrapper: Arc<DBWrapper>,
+            organization_id: i64,
+        ) -> Result<Vec<OrganizationMember>, sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const FETCH_QUERY: &str = r#"
+                SELECT * FROM organization_member WHERE organization_id=$1 ORDER BY id ASC
+            "#;
+
+            sqlx::query_as(FETCH_QUERY)
+                .bind(organization_id)
+                .fetch_all(connection)
+                .await
+        }
+
+        ///
+        /// Removes member `id` of the organization. Returns false if it had no such member.
+        ///
+        pub async fn delete(
+            db_wrapper: Arc<DBWrapper>,
+            organization_id: i64,
+            id: i64,
+        ) -> Result<bool, sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const DELETE_QUERY: &str = r#"
+                DELETE FROM organization_member WHERE id=$1 AND organization_id=$2
+            "#;
+
+            let result = connection
+                .execute(sqlx::query(DELETE_QUERY).bind(id).bind(organization_id))
+                .await?;
+            Ok(result.rows_affected() > 0)
+        }
+    }
+
+    ///
+    /// Rows of table `app_config`.
+    ///
+    pub struct AppSetting;
+
+    impl AppSetting {
+        ///
+        /// Returns all settings as `name -> value`.
+        ///
+        pub async fn fetch_all(
+            db_wrapper: Arc<DBWrapper>,
+        ) -> Result<HashMap<String, String>, sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const FETCH_QUERY: &str = "SELECT name, value FROM app_config";
+            let rows: Vec<(String, String)> =
+                sqlx::query_as(FETCH_QUERY).fetch_all(connection).await?;
+            Ok(rows.into_iter().collect())
+        }
+
+        ///
+        /// Writes `settings` at once: `Some` values are stored, `None` deletes the row so the
+        /// environment variable applies again.
+        ///
+        pub async fn set_all(
+            db_wrapper: Arc<DBWrapper>,
+            settings: &[(&str, Option<&str>)],
+        ) -> Result<(), sqlx::Error> {
+            const UPSERT_QUERY: &str = r#"
+                INSERT INTO app_config(name, value) VALUES ($1, $2)
+                ON CONFLICT (name) DO UPDATE SET
+                    value=EXCLUDED.value, date_updated=CURRENT_TIMESTAMP
+            "#;
+
+            const DELETE_QUERY: &str = "DELETE FROM app_config WHERE name=$1";
+
+            let mut transaction = db_wrapper.pool.begin().await?;
+            for &(name, value) in settings {
+                let query = match value {
+                    Some(value) => sqlx::query(UPSERT_QUERY).bind(name).bind(value),
+                    None => sqlx::query(DELETE_QUERY).bind(name),
+                };
+                query.execute(&mut *transaction).await?;
+            }
+            transaction.commit().await?;
+            Ok(())
+        }
+    }
+
+    ///
+    /// Applied settings change for table `config_audit_log`.
+    ///
+    pub struct ConfigAuditEntry<'a> {
+        pub name: &'a str,
+        pub old_value: Option<&'a str>,
+        pub new_value: Option<&'a str>,
+    }
+
+    impl ConfigAuditEntry<'_> {
+        pub async fn insert(&self, db_wrapper: Arc<DBWrapper>) -> Result<(), sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const INSERT_QUERY: &str = r#"
+                INSERT INTO config_audit_log(name, old_value, new_value) VALUES ($1, $2, $3)
+            "#;
+
+            connection
+                .execute(
+                    sqlx::query(INSERT_QUERY)
+                        .bind(self.name)
+                        .bind(self.old_value)
+                        .bind(self.new_value),
+                )
+                .await?;
+            Ok(())
+        }
+    }
+
+    ///
+    /// Rows deleted by `BackgroundRemoverTask::erase`, per table.
+    ///
+    #[derive(Debug, Default, Serialize)]
+    pub struct ErasedRows {
+        pub tasks: u64,
+        pub revisions: u64,
+        pub revision_comparisons: u64,
+        pub canary_results: u64,
+        pub manifests: u64,
+        pub outbox_entries: u64,
+        pub media_audit_reports: u64,
+        pub task_group_notifications: u64,
+        pub bp_messages: u64,
+    }
+
+    ///
+    /// Report of an erased user for table `user_erasure`.
+    ///
+    pub struct UserErasure;
+
+    impl UserErasure {
+        pub async fn insert(
+            db_wrapper: Arc<DBWrapper>,
+            task_count: i64,
+            report: &Value,
+        ) -> Result<i64, sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const INSERT_QUERY: &str = r#"
+                INSERT INTO user_erasure(task_count, report) VALUES ($1, $2) RETURNING id
+            "#;
+
+            let (id,): (i64,) = sqlx::query_as(INSERT_QUERY)
+                .bind(task_count)
+                .bind(report)
+                .fetch_one(connection)
+                .await?;
+            Ok(id)
+        }
+    }
+
+    ///
+    /// Implementations for `BackgroundRemoverTask` model
+    ///
+    impl BackgroundRemoverTask {
+        ///
+        /// Whether only the mask is produced for this task.
+        ///
+        pub fn is_mask_only(&self) -> bool {
+            self.outputs == TaskOutputs::Mask.as_str()
+        }
+
+        ///
+        /// Relative path of the output with manifest `role`. Read from the manifest when the task
+        /// has one, from the path column otherwise.
+        ///
+        pub fn output_path(&self, role: &str) -> Option<&String> {
+            if let Some(Json(files)) = &self.manifest {
+                return files
+                    .iter()
+                    .find(|file| file.role == role)
+                    .map(|file| &file.path);
+            }
+
+            match role {
+                ManifestFile::MASK => self.mask_image_path.as_ref(),
+                ManifestFile::PROCESSED => self.processed_image_path.as_ref(),
+                ManifestFile::PREVIEW_PROCESSED => self.preview_processed_image_path.as_ref(),
+                _ => None,
+            }
+        }
+
+        ///
+        /// Also serialized auto increment column `task_id` and `logs` which may leak actual
+        /// available items count if accessible to users.
+        ///
+        pub fn serialize_full(&self) -> Result<Value, serde_json::Error> {
+            serde_json::to_value(&self)
+        }
+
+        ///
+        /// Same as `serialize` but output fields come from `revision` when given, so clients see
+        /// the latest outputs. Adds `revision` field, `0` for the task's own outputs.
+        ///
+        pub fn serialize_with_revision(
+            &self,
+            revision: Option<&TaskRevision>,
+        ) -> Result<Value, serde_json::Error> {
+            let mut serialized = self.serialize()?;
+            let map = match serialized.as_object_mut() {
+                Some(map) => map,
+                None => return Ok(serialized),
+            };
+
+            match revision {
+                Some(revision) => {
+                    let serialized_revision = serde_json::to_value(revision)?;
+                    const OUTPUT_FIELDS: [&str; 3] =
+                        ["mask_image", "processed_image", "preview_processed_image"];
+                    for field in OUTPUT_FIELDS {
+                        if let Some(value) = serialized_revision.get(field) {
+                            map.insert(field.to_string(), value.clone());
+                        }
+                    }
+                    map.insert("revision".to_string(), Value::from(revision.revision));
+                }
+                None => {
+                    map.insert("revision".to_string(), Value::from(0));
+                }
+            }
+
+            Ok(serialized)
+        }
+
+        ///
+        /// This does not include `task_id` and `logs` field and values.
+        ///
+        pub fn serialize(&self) -> Result<Value, serde_json::Error> {
+            let mut serialized_full = match self.serialize_full() {
+                Ok(value) => value,
+                Err(error) => {
+                    return Err(error);
+                }
+            };
+
+            const REMOVE_FIELDS: [&str; 6] = [
+                "task_id",
+                "country",
+                "logs",
+                "bp_worker_id",
+                "bp_model_version",
+                "dedup_of",
+            ];
+            let map_object = serialized_full.as_object_mut();
+
+            if let Some(map) = map_object {
+                REMOVE_FIELDS.iter().for_each(|field| {
                     map.remove(*field);
                 });
 
-                return Ok(Value::from(map.clone()));
-            }
+                return Ok(Value::from(map.clone()));
+            }
+
+            return Err(serde_json::Error::custom(
+                "Failed to parse while removing some fields.",
+            ));
+        }
+
+        ///
+        /// Inserts new record to the database.
+        ///
+        pub async fn insert_new_task(
+            db_wrapper: Arc<DBWrapper>,
+            new_task: &NewBackgroundRemoverTask,
+        ) -> Result<(), sqlx::Error> {
+            #[cfg(feature = "chaos")]
+            crate::utils::chaos_utils::db_error()?;
+
+            let connection = db_wrapper.timed();
+
+            const INSERT_QUERY: &str = r#"
+                INSERT INTO background_remover_task(
+                    key,
+                    task_group,
+                    original_image_path,
+                    preview_original_image_path,
+                    country,
+                    user_identifier,
+                    original_filename,
+                    metadata,
+                    tags,
+                    original_format,
+                    outputs,
+                    background_hint,
+                    preview_settings,
+                    free_tier,
+                    api_key_id,
+                    priority,
+                    tenant,
+                    content_sha256,
+                    timings
+                ) VALUES (
+                    $1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18,
+                    $19
+                )
+            "#;
+
+            connection
+                .execute(
+                    sqlx::query(&INSERT_QUERY)
+                        .bind(&new_task.key)
+                        .bind(&new_task.task_group)
+                        .bind(&new_task.original_image_path)
+                        .bind(&new_task.preview_original_image_path)
+                        .bind(&new_task.country.clone())
+                        .bind(&new_task.user_identifier.clone())
+                        .bind(&new_task.original_filename)
+                        .bind(&new_task.metadata)
+                        .bind(&new_task.tags)
+                        .bind(&new_task.original_format)
+                        .bind(new_task.outputs.as_str())
+                        .bind(&new_task.background_hint)
+                        .bind(&new_task.preview_settings)
+                        .bind(new_task.free_tier)
+                        .bind(&new_task.api_key_id)
+                        .bind(new_task.priority)
+                        .bind(&new_task.tenant)
+                        .bind(&new_task.content_sha256)
+                        .bind(&new_task.timings),
+                )
+                .await?;
+
+            Ok(())
+        }
+
+        ///
+        /// Stores outputs of the task, marks it succeeded and no longer processing, records the
+        /// usage of its api key and queues its result notification in `notification_outbox`, all
+        /// in one transaction. Returns the id of the outbox entry, or `None` without updating when
+        /// the task was dispatched again after `update_task.request_id`, so a late response never
+        /// replaces newer outputs.
+        ///
+        pub async fn update_task(
+            db_wrapper: Arc<DBWrapper>,
+            update_task: &UpdateBackgroundRemoverTask,
+        ) -> Result<Option<i64>, sqlx::Error> {
+            #[cfg(feature = "chaos")]
+            crate::utils::chaos_utils::db_error()?;
+
+            const UPDATE_QUERY: &str = r#"
+                UPDATE background_remover_task
+                SET
+                    mask_image_path=$1,
+                    processed_image_path=$2,
+                    preview_processed_image_path=$3,
+                    result_status=$6,
+                    date_completed=clock_timestamp(),
+                    output_max_pixels=$7,
+                    processing=FALSE
+                WHERE
+                    key=$4 AND ($5::UUID IS NULL OR bp_request_id=$5)
+            "#;
+
+            let mut transaction = db_wrapper.pool.begin().await?;
+            let result = sqlx::query(UPDATE_QUERY)
+                .bind(&update_task.mask_image_path)
+                .bind(&update_task.processed_image_path)
+                .bind(&update_task.preview_processed_image_path)
+                .bind(&update_task.key)
+                .bind(&update_task.request_id)
+                .bind(ResultStatus::Success.as_str())
+                .bind(update_task.output_max_pixels)
+                .execute(&mut *transaction)
+                .await?;
+
+            if result.rows_affected() == 0 {
+                return Ok(None);
+            }
+
+            TaskManifest::upsert(&mut transaction, &update_task.key, &update_task.manifest).await?;
+            ApiKeyUsage::record(&mut transaction, &update_task.key).await?;
+            let outbox_id =
+                OutboxEntry::insert(&mut transaction, &update_task.key, OutboxEntry::TASK_RESULT)
+                    .await?;
+            transaction.commit().await?;
+
+            Ok(Some(outbox_id))
+        }
+
+        ///
+        /// Appends entry to the task event log. Existing non-array values of `logs` are replaced
+        /// by a new array.
+        ///
+        pub async fn append_log(
+            db_wrapper: Arc<DBWrapper>,
+            key: &Uuid,
+            entry: &TaskLogEntry,
+        ) -> Result<(), sqlx::Error> {
+            let connection = db_wrapper.timed();
+            let entry =
+                serde_json::to_value(entry).map_err(|error| sqlx::Error::Encode(error.into()))?;
+
+            const UPDATE_QUERY: &str = r#"
+                UPDATE background_remover_task
+                SET
+                    logs=(
+                        CASE WHEN jsonb_typeof(logs) = 'array' THEN logs ELSE '[]'::jsonb END
+                    ) || jsonb_build_array($1::jsonb)
+                WHERE
+                    key=$2
+            "#;
+
+            connection
+                .execute(sqlx::query(UPDATE_QUERY).bind(entry).bind(key))
+                .await?;
+            Ok(())
+        }
+
+        ///
+        /// Updates processing state of the task.
+        ///
+        pub async fn update_processing_state(
+            db_wrapper: Arc<DBWrapper>,
+            key: &Uuid,
+            state: bool,
+        ) -> Result<(), sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const UPDATE_QUERY: &str = r#"
+                UPDATE background_remover_task
+                SET
+                    processing=$1
+                WHERE
+                    key=$2
+            "#;
+
+            connection
+                .execute(sqlx::query(UPDATE_QUERY).bind(state).bind(key))
+                .await?;
+            Ok(())
+        }
+
+        ///
+        /// Marks a reprocessed task succeeded and no longer processing, once the outputs of its
+        /// new revision are stored. Returns false without updating when the task was dispatched
+        /// again after `request_id`, like `update_task`.
+        ///
+        pub async fn complete_reprocess(
+            db_wrapper: Arc<DBWrapper>,
+            key: &Uuid,
+            request_id: Option<Uuid>,
+        ) -> Result<bool, sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const UPDATE_QUERY: &str = r#"
+                UPDATE background_remover_task
+                SET
+                    result_status=$1,
+                    date_completed=clock_timestamp(),
+                    processing=FALSE
+                WHERE
+                    key=$2 AND ($3::UUID IS NULL OR bp_request_id=$3)
+            "#;
+
+            let result = connection
+                .execute(
+                    sqlx::query(UPDATE_QUERY)
+                        .bind(ResultStatus::Success.as_str())
+                        .bind(key)
+                        .bind(request_id),
+                )
+                .await?;
+            Ok(result.rows_affected() > 0)
+        }
+
+        ///
+        /// Records `request_id` as the latest dispatch of the task to the BP server. Stored before
+        /// sending, so the response can never arrive first. The result status of an earlier
+        /// dispatch is cleared, as the task is processing again.
+        ///
+        pub async fn set_bp_request_id(
+            db_wrapper: Arc<DBWrapper>,
+            key: &Uuid,
+            request_id: &Uuid,
+        ) -> Result<(), sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const UPDATE_QUERY: &str = r#"
+                UPDATE background_remover_task
+                    SET bp_request_id=$1, result_status=NULL, date_completed=NULL
+                    WHERE key=$2
+            "#;
+
+            connection
+                .execute(sqlx::query(UPDATE_QUERY).bind(request_id).bind(key))
+                .await?;
+            Ok(())
+        }
+
+        ///
+        /// Claims storing the result of dispatch `request_id`. Only the result of the latest
+        /// dispatch can be claimed, and only once, even if the BP server delivers it again.
+        ///
+        pub async fn claim_result(
+            db_wrapper: Arc<DBWrapper>,
+            key: &Uuid,
+            request_id: &Uuid,
+        ) -> Result<ResultClaim, sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const UPDATE_QUERY: &str = r#"
+                UPDATE background_remover_task SET completed_request_id=$1
+                    WHERE key=$2
+                        AND bp_request_id=$1
+                        AND completed_request_id IS DISTINCT FROM $1
+            "#;
+
+            let result = connection
+                .execute(sqlx::query(UPDATE_QUERY).bind(request_id).bind(key))
+                .await?;
+            if result.rows_affected() > 0 {
+                return Ok(ResultClaim::Claimed);
+            }
+
+            const FETCH_QUERY: &str = r#"
+                SELECT bp_request_id FROM background_remover_task WHERE key=$1
+            "#;
+
+            let latest: (Option<Uuid>,) = sqlx::query_as(FETCH_QUERY)
+                .bind(key)
+                .fetch_one(connection)
+                .await?;
+            if latest.0.as_ref() == Some(request_id) {
+                Ok(ResultClaim::Duplicate)
+            } else {
+                Ok(ResultClaim::Stale)
+            }
+        }
+
+        ///
+        /// Gives up the claim of `claim_result` when the result could not be stored, so the BP
+        /// server may deliver it again.
+        ///
+        pub async fn release_result(
+            db_wrapper: Arc<DBWrapper>,
+            key: &Uuid,
+            request_id: &Uuid,
+        ) -> Result<(), sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const UPDATE_QUERY: &str = r#"
+                UPDATE background_remover_task SET completed_request_id=NULL
+                    WHERE key=$2 AND completed_request_id=$1
+            "#;
+
+            connection
+                .execute(sqlx::query(UPDATE_QUERY).bind(request_id).bind(key))
+                .await?;
+            Ok(())
+        }
+
+        ///
+        /// Merges durations of `stages` into column `timings` of the task, replacing earlier
+        /// durations of the same stages.
+        ///
+        pub async fn record_timings(
+            db_wrapper: Arc<DBWrapper>,
+            key: &Uuid,
+            stages: &[(TaskStage, Duration)],
+        ) -> Result<(), sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const UPDATE_QUERY: &str = r#"
+                UPDATE background_remover_task SET timings = COALESCE(timings, '{}'::jsonb) || $1
+                    WHERE key=$2
+            "#;
+
+            connection
+                .execute(
+                    sqlx::query(UPDATE_QUERY)
+                        .bind(timing_utils::timings_json(stages))
+                        .bind(key),
+                )
+                .await?;
+            Ok(())
+        }
+
+        ///
+        /// Records outcome of the last processing of the task.
+        ///
+        pub async fn set_result_status(
+            db_wrapper: Arc<DBWrapper>,
+            key: &Uuid,
+            status: ResultStatus,
+        ) -> Result<(), sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const UPDATE_QUERY: &str = r#"
+                UPDATE background_remover_task
+                    SET result_status=$1, date_completed=clock_timestamp()
+                    WHERE key=$2
+            "#;
+
+            connection
+                .execute(sqlx::query(UPDATE_QUERY).bind(status.as_str()).bind(key))
+                .await?;
+            Ok(())
+        }
+
+        ///
+        /// Records the BP worker and model version which produced the result of the task.
+        ///
+        pub async fn set_bp_identity(
+            db_wrapper: Arc<DBWrapper>,
+            key: &Uuid,
+            worker_id: Option<&str>,
+            model_version: Option<&str>,
+        ) -> Result<(), sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const UPDATE_QUERY: &str = r#"
+                UPDATE background_remover_task SET bp_worker_id=$1, bp_model_version=$2
+                    WHERE key=$3
+            "#;
+
+            connection
+                .execute(
+                    sqlx::query(UPDATE_QUERY)
+                        .bind(worker_id)
+                        .bind(model_version)
+                        .bind(key),
+                )
+                .await?;
+            Ok(())
+        }
+
+        ///
+        /// Latest successful task created within `window` before `instance` whose outputs
+        /// `instance` can be served from: same original image, tenant, outputs, background hint
+        /// and tier. Tasks without `content_sha256` are never matched.
+        ///
+        pub async fn fetch_dedup_source(
+            db_wrapper: Arc<DBWrapper>,
+            instance: &BackgroundRemoverTask,
+            window: Duration,
+        ) -> Result<Option<BackgroundRemoverTask>, sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const FETCH_QUERY: &str = r#"
+                SELECT *, (SELECT files FROM task_manifest WHERE task_key=key) AS manifest
+                    FROM background_remover_task
+                    WHERE
+                        content_sha256=$1
+                        AND key<>$2
+                        AND result_status=$3
+                        AND tenant IS NOT DISTINCT FROM $4
+                        AND outputs=$5
+                        AND background_hint IS NOT DISTINCT FROM $6
+                        AND free_tier=$7
+                        AND date_created >= $8::TIMESTAMPTZ - make_interval(secs => $9)
+                    ORDER BY date_created DESC
+                    LIMIT 1
+            "#;
+
+            let content_sha256 = match &instance.content_sha256 {
+                Some(content_sha256) => content_sha256,
+                None => return Ok(None),
+            };
 
-            return Err(serde_json::Error::custom(
-                "Failed to parse while removing some fields.",
-            ));
+            sqlx::query_as(FETCH_QUERY)
+                .bind(content_sha256)
+                .bind(&instance.key)
+                .bind(ResultStatus::Success.as_str())
+                .bind(&instance.tenant)
+                .bind(&instance.outputs)
+                .bind(&instance.background_hint)
+                .bind(instance.free_tier)
+                .bind(instance.date_created)
+                .bind(window.as_secs_f64())
+                .fetch_optional(connection)
+                .await
         }
 
         ///
-        /// Inserts new record to the database.
+        /// Records that the task was served from the outputs of task `source`.
         ///
-        pub async fn insert_new_task(
+        pub async fn set_dedup_of(
             db_wrapper: Arc<DBWrapper>,
-            new_task: &NewBackgroundRemoverTask,
+            key: &Uuid,
+            source: &Uuid,
         ) -> Result<(), sqlx::Error> {
-            let connection = db_wrapper.pool.clone();
+            let connection = db_wrapper.timed();
 
-            const INSERT_QUERY: &str = r#"
-                INSERT INTO background_remover_task(
-                    key,
-                    task_group,
-                    original_image_path,
-                    preview_original_image_path,
-                    country,
-                    user_identifier
-                ) VALUES ($1, $2, $3, $4, $5, $6)
+            const UPDATE_QUERY: &str = r#"
+                UPDATE background_remover_task SET dedup_of=$1 WHERE key=$2
             "#;
 
             connection
-                .execute(
-                    sqlx::query(&INSERT_QUERY)
-                        .bind(&new_task.key)
-                        .bind(&new_task.task_group)
-                        .bind(&new_task.original_image_path)
-                        .bind(&new_task.preview_original_image_path)
-                        .bind(&new_task.country.clone())
-                        .bind(&new_task.user_identifier.clone()),
-                )
+                .execute(sqlx::query(UPDATE_QUERY).bind(source).bind(key))
                 .await?;
-
             Ok(())
         }
 
         ///
-        /// Updates existing record in the database of matching `key`.
+        /// Sets `progress` of every task in `keys` to the value at the same index in `values`.
         ///
-        pub async fn update_task(
+        pub async fn update_progress_batch(
             db_wrapper: Arc<DBWrapper>,
-            update_task: &UpdateBackgroundRemoverTask,
+            keys: &[Uuid],
+            values: &[Value],
         ) -> Result<(), sqlx::Error> {
-            let connection = db_wrapper.pool.clone();
+            let connection = db_wrapper.timed();
 
             const UPDATE_QUERY: &str = r#"
-                UPDATE background_remover_task
-                SET
-                    mask_image_path=$1,
-                    processed_image_path=$2,
-                    preview_processed_image_path=$3,
-                    logs=$4
-                WHERE
-                    key=$5
+                UPDATE background_remover_task AS task SET progress = batch.progress
+                    FROM UNNEST($1::uuid[], $2::jsonb[]) AS batch(key, progress)
+                    WHERE task.key = batch.key
             "#;
 
             connection
-                .execute(
-                    sqlx::query(UPDATE_QUERY)
-                        .bind(&update_task.mask_image_path)
-                        .bind(&update_task.processed_image_path)
-                        .bind(&update_task.preview_processed_image_path)
-                        .bind(&update_task.logs)
-                        .bind(&update_task.key),
-                )
+                .execute(sqlx::query(UPDATE_QUERY).bind(keys).bind(values))
                 .await?;
             Ok(())
         }
 
         ///
-        /// Updates processing state of the task.
+        /// Records that previews of the task are regenerated with settings `fingerprint`. Returns
+        /// false if they already were, so only one caller regenerates them.
         ///
-        pub async fn update_processing_state(
+        pub async fn claim_preview_refresh(
             db_wrapper: Arc<DBWrapper>,
             key: &Uuid,
-            state: bool,
+            fingerprint: &str,
+        ) -> Result<bool, sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const UPDATE_QUERY: &str = r#"
+                UPDATE background_remover_task SET preview_settings=$1
+                    WHERE key=$2 AND preview_settings IS DISTINCT FROM $1
+            "#;
+
+            let result = connection
+                .execute(sqlx::query(UPDATE_QUERY).bind(fingerprint).bind(key))
+                .await?;
+            Ok(result.rows_affected() > 0)
+        }
+
+        ///
+        /// Points the original preview of the task to a generated preview.
+        ///
+        pub async fn update_preview_original_path(
+            db_wrapper: Arc<DBWrapper>,
+            key: &Uuid,
+            path: &str,
         ) -> Result<(), sqlx::Error> {
-            let connection = &db_wrapper.pool;
+            let connection = db_wrapper.timed();
 
             const UPDATE_QUERY: &str = r#"
-                UPDATE background_remover_task
-                SET
-                    processing=$1
-                WHERE
-                    key=$2
+                UPDATE background_remover_task SET preview_original_image_path=$1 WHERE key=$2
             "#;
 
             connection
-                .execute(sqlx::query(UPDATE_QUERY).bind(state).bind(key))
+                .execute(sqlx::query(UPDATE_QUERY).bind(path).bind(key))
+                .await?;
+            Ok(())
+        }
+
+        ///
+        /// Points the processed preview of the task to a generated preview, also in its manifest.
+        ///
+        pub async fn update_preview_processed_path(
+            db_wrapper: Arc<DBWrapper>,
+            key: &Uuid,
+            file: &ManifestFile,
+        ) -> Result<(), sqlx::Error> {
+            const UPDATE_QUERY: &str = r#"
+                UPDATE background_remover_task SET preview_processed_image_path=$1 WHERE key=$2
+            "#;
+
+            let mut transaction = db_wrapper.pool.begin().await?;
+            sqlx::query(UPDATE_QUERY)
+                .bind(&file.path)
+                .bind(key)
+                .execute(&mut *transaction)
                 .await?;
+            TaskManifest::replace_file(&mut transaction, key, &file.role, Some(file)).await?;
+            transaction.commit().await?;
             Ok(())
         }
 
         ///
-        /// Returns instance of `BackgroundRemoverTask` of matching `key`.
+        /// Returns instance of `BackgroundRemoverTask` of matching `key`. Falls back to the
+        /// archive table when the task is not in the hot table.
         ///
         pub async fn fetch(
             db_wrapper: Arc<DBWrapper>,
             key: &Uuid,
         ) -> Result<BackgroundRemoverTask, sqlx::Error> {
-            let connection = db_wrapper.pool.clone();
+            #[cfg(feature = "chaos")]
+            crate::utils::chaos_utils::db_error()?;
+
+            let connection = db_wrapper.timed();
 
             const FETCH_QUERY: &str = r#"
-                SELECT * FROM background_remover_task WHERE key=$1 LIMIT 1
+                SELECT *, (SELECT files FROM task_manifest WHERE task_key=key) AS manifest
+                    FROM background_remover_task WHERE key=$1 LIMIT 1
+            "#;
+
+            const FETCH_ARCHIVED_QUERY: &str = r#"
+                SELECT *, (SELECT files FROM task_manifest WHERE task_key=key) AS manifest
+                    FROM archived_background_remover_task WHERE key=$1 LIMIT 1
             "#;
 
-            let instance: BackgroundRemoverTask = sqlx::query_as(FETCH_QUERY)
+            let instance: BackgroundRemoverTask = match sqlx::query_as(FETCH_QUERY)
                 .bind(key)
-                .fetch_one(&connection)
-                .await?;
+                .fetch_one(connection)
+                .await
+            {
+                Ok(instance) => instance,
+                Err(sqlx::Error::RowNotFound) => {
+                    sqlx::query_as(FETCH_ARCHIVED_QUERY)
+                        .bind(key)
+                        .fetch_one(connection)
+                        .await?
+                }
+                Err(error) => return Err(error),
+            };
 
             Ok(instance)
         }
 
+        ///
+        /// Moves up to `limit` tasks created before `before` from the hot table to
+        /// `archived_background_remover_task`. Tasks still processing are skipped.
+        ///
+        /// Returns number of moved tasks.
+        ///
+        pub async fn archive_older_than(
+            db_wrapper: Arc<DBWrapper>,
+            before: &DateTime<Utc>,
+            limit: i64,
+        ) -> Result<u64, sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            // Delete and insert happen in one statement, so a task is never in both tables or in
+            // neither.
+            const ARCHIVE_QUERY: &str = r#"
+                WITH moved AS (
+                    DELETE FROM background_remover_task
+                        WHERE task_id IN (
+                            SELECT task_id FROM background_remover_task
+                                WHERE date_created < $1 AND processing IS NOT TRUE
+                                ORDER BY task_id ASC
+                                LIMIT $2
+                        )
+                        RETURNING *
+                )
+                INSERT INTO archived_background_remover_task SELECT * FROM moved
+            "#;
+
+            let result = connection
+                .execute(sqlx::query(ARCHIVE_QUERY).bind(before).bind(limit))
+                .await?;
+            Ok(result.rows_affected())
+        }
+
+        ///
+        /// Clears `country`, `user_identifier` and the `user_id`, `user_identifier` and `email`
+        /// metadata keys of up to `limit` tasks created before `before` in the hot table and as
+        /// many in the archive table. Tasks still processing are skipped.
+        ///
+        /// Returns number of anonymized tasks.
+        ///
+        pub async fn anonymize_older_than(
+            db_wrapper: Arc<DBWrapper>,
+            before: &DateTime<Utc>,
+            limit: i64,
+        ) -> Result<u64, sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const ANONYMIZE_QUERY: &str = r#"
+                WITH hot AS (
+                    UPDATE background_remover_task
+                        SET country=NULL,
+                            user_identifier=NULL,
+                            metadata=metadata - ARRAY['user_id', 'user_identifier', 'email']
+                        WHERE task_id IN (
+                            (SELECT task_id FROM background_remover_task
+                                WHERE date_created < $1
+                                    AND (country IS NOT NULL OR user_identifier IS NOT NULL)
+                                    AND processing IS NOT TRUE
+                                LIMIT $2)
+                            UNION
+                            (SELECT task_id FROM background_remover_task
+                                WHERE date_created < $1
+                                    AND metadata ?| ARRAY['user_id', 'user_identifier', 'email']
+                                    AND processing IS NOT TRUE
+                                LIMIT $2)
+                        )
+                        RETURNING 1
+                ), archived AS (
+                    UPDATE archived_background_remover_task
+                        SET country=NULL,
+                            user_identifier=NULL,
+                            metadata=metadata - ARRAY['user_id', 'user_identifier', 'email']
+                        WHERE task_id IN (
+                            (SELECT task_id FROM archived_background_remover_task
+                                WHERE date_created < $1
+                                    AND (country IS NOT NULL OR user_identifier IS NOT NULL)
+                                LIMIT $2)
+                            UNION
+                            (SELECT task_id FROM archived_background_remover_task
+                                WHERE date_created < $1
+                                    AND metadata ?| ARRAY['user_id', 'user_identifier', 'email']
+                                LIMIT $2)
+                        )
+                        RETURNING 1
+                )
+                SELECT (SELECT COUNT(*) FROM hot) + (SELECT COUNT(*) FROM archived) AS total
+            "#;
+
+            let anonymized: (i64,) = sqlx::query_as(ANONYMIZE_QUERY)
+                .bind(before)
+                .bind(limit)
+                .fetch_one(connection)
+                .await?;
+            Ok(anonymized.0 as u64)
+        }
+
         pub async fn fetch_by_page(
             db_wrapper: Arc<DBWrapper>,
             page: u32,
+            filter: &TaskFilter,
         ) -> Result<Vec<BackgroundRemoverTask>, sqlx::Error> {
-            let connection = db_wrapper.pool.clone();
-            let tasks_per_page = 25;
+            let connection = db_wrapper.timed();
+            let tasks_per_page = TASKS_PER_PAGE as u32;
             let offset = (page - 1) * tasks_per_page;
 
             const FETCH_QUERY: &str = r#"
-                SELECT * FROM background_remover_task
+                SELECT *, (SELECT files FROM task_manifest WHERE task_key=key) AS manifest
+                    FROM background_remover_task
+                    WHERE ($3::TEXT IS NULL OR $3 = ANY(tags))
+                        AND ($4::JSONB IS NULL OR metadata @> $4)
+                        AND ($5::BIGINT IS NULL OR api_key_id IN (
+                            SELECT key_id FROM organization_api_key WHERE organization_id=$5
+                        ))
+                        AND ($6::BOOLEAN OR tenant IS NOT DISTINCT FROM $7)
                     ORDER BY task_id DESC
                     OFFSET $1
                     LIMIT $2
             "#;
 
+            let (all_tenants, tenant) = filter.tenant();
             let models: Vec<BackgroundRemoverTask> = sqlx::query_as(FETCH_QUERY)
                 .bind(offset as i64)
                 .bind(tasks_per_page as i64)
-                .fetch_all(&connection)
+                .bind(&filter.tag)
+                .bind(&filter.metadata)
+                .bind(filter.organization_id)
+                .bind(all_tenants)
+                .bind(tenant)
+                .fetch_all(connection)
+                .await?;
+
+            Ok(models)
+        }
+
+        ///
+        /// Keyset pagination in descending order of `task_id`. Returns up to `limit` tasks with
+        /// `task_id` lower than `before_task_id`, or the newest tasks when it is `None`.
+        ///
+        pub async fn fetch_by_cursor(
+            db_wrapper: Arc<DBWrapper>,
+            before_task_id: Option<i64>,
+            limit: i64,
+            filter: &TaskFilter,
+        ) -> Result<Vec<BackgroundRemoverTask>, sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const FETCH_QUERY: &str = r#"
+                SELECT *, (SELECT files FROM task_manifest WHERE task_key=key) AS manifest
+                    FROM background_remover_task
+                    WHERE ($1::BIGINT IS NULL OR task_id < $1)
+                        AND ($3::TEXT IS NULL OR $3 = ANY(tags))
+                        AND ($4::JSONB IS NULL OR metadata @> $4)
+                        AND ($5::BIGINT IS NULL OR api_key_id IN (
+                            SELECT key_id FROM organization_api_key WHERE organization_id=$5
+                        ))
+                        AND ($6::BOOLEAN OR tenant IS NOT DISTINCT FROM $7)
+                    ORDER BY task_id DESC
+                    LIMIT $2
+            "#;
+
+            let (all_tenants, tenant) = filter.tenant();
+            let models: Vec<BackgroundRemoverTask> = sqlx::query_as(FETCH_QUERY)
+                .bind(before_task_id)
+                .bind(limit)
+                .bind(&filter.tag)
+                .bind(&filter.metadata)
+                .bind(filter.organization_id)
+                .bind(all_tenants)
+                .bind(tenant)
+                .fetch_all(connection)
+                .await?;
+
+            Ok(models)
+        }
+
+        pub async fn length(
+            db_wrapper: Arc<DBWrapper>,
+            filter: &TaskFilter,
+        ) -> Result<u64, sqlx::Error> {
+            let connection = db_wrapper.timed();
+            const COUNT_QUERY: &str = r#"
+                SELECT COUNT(task_id) AS total FROM background_remover_task
+                    WHERE ($1::TEXT IS NULL OR $1 = ANY(tags))
+                        AND ($2::JSONB IS NULL OR metadata @> $2)
+                        AND ($3::BIGINT IS NULL OR api_key_id IN (
+                            SELECT key_id FROM organization_api_key WHERE organization_id=$3
+                        ))
+                        AND ($4::BOOLEAN OR tenant IS NOT DISTINCT FROM $5)
+            "#;
+
+            let (all_tenants, tenant) = filter.tenant();
+            let size: (i64,) = sqlx::query_as(COUNT_QUERY)
+                .bind(&filter.tag)
+                .bind(&filter.metadata)
+                .bind(filter.organization_id)
+                .bind(all_tenants)
+                .bind(tenant)
+                .fetch_one(connection)
+                .await?;
+            Ok(size.0 as u64)
+        }
+
+        ///
+        /// Returns up to `limit` tasks of `task_group` in upload order.
+        ///
+        pub async fn fetch_by_task_group(
+            db_wrapper: Arc<DBWrapper>,
+            task_group: &Uuid,
+            limit: i64,
+        ) -> Result<Vec<BackgroundRemoverTask>, sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const FETCH_QUERY: &str = r#"
+                SELECT *, (SELECT files FROM task_manifest WHERE task_key=key) AS manifest
+                    FROM background_remover_task
+                    WHERE task_group = $1
+                    ORDER BY task_id ASC
+                    LIMIT $2
+            "#;
+
+            let models: Vec<BackgroundRemoverTask> = sqlx::query_as(FETCH_QUERY)
+                .bind(task_group)
+                .bind(limit)
+                .fetch_all(connection)
                 .await?;
 
             Ok(models)
         }
 
-        pub async fn length(db_wrapper: Arc<DBWrapper>) -> Result<u64, sqlx::Error> {
-            let connection = db_wrapper.pool.clone();
+        ///
+        /// Returns the distinct tenants of the tasks of `task_group`.
+        ///
+        pub async fn fetch_task_group_tenants(
+            db_wrapper: Arc<DBWrapper>,
+            task_group: &Uuid,
+        ) -> Result<Vec<String>, sqlx::Error> {
+            let connection = db_wrapper.timed();
+            const FETCH_QUERY: &str = r#"
+                SELECT DISTINCT tenant FROM background_remover_task
+                    WHERE task_group = $1 AND tenant IS NOT NULL
+            "#;
+
+            let rows: Vec<(String,)> = sqlx::query_as(FETCH_QUERY)
+                .bind(task_group)
+                .fetch_all(connection)
+                .await?;
+            Ok(rows.into_iter().map(|(tenant,)| tenant).collect())
+        }
+
+        ///
+        /// Returns `(total, processed, processing, failed)` task counts of `task_group`. Timed out
+        /// tasks are counted as failed.
+        ///
+        pub async fn count_by_task_group(
+            db_wrapper: Arc<DBWrapper>,
+            task_group: &Uuid,
+        ) -> Result<(u64, u64, u64, u64), sqlx::Error> {
+            let connection = db_wrapper.timed();
+            const COUNT_QUERY: &str = r#"
+                SELECT
+                    COUNT(task_id),
+                    COUNT(task_id) FILTER (WHERE result_status = 'success'),
+                    COUNT(task_id) FILTER (WHERE processing IS TRUE),
+                    COUNT(task_id) FILTER (WHERE result_status IN ('failed', 'timeout'))
+                FROM background_remover_task
+                WHERE task_group = $1
+            "#;
+
+            let counts: (i64, i64, i64, i64) = sqlx::query_as(COUNT_QUERY)
+                .bind(task_group)
+                .fetch_one(connection)
+                .await?;
+            Ok((
+                counts.0 as u64,
+                counts.1 as u64,
+                counts.2 as u64,
+                counts.3 as u64,
+            ))
+        }
+
+        ///
+        /// Returns `(total, failed)` number of tasks which got their result after `since`. Timed
+        /// out tasks are counted as failed.
+        ///
+        pub async fn count_results_since(
+            db_wrapper: Arc<DBWrapper>,
+            since: &DateTime<Utc>,
+        ) -> Result<(u64, u64), sqlx::Error> {
+            let connection = db_wrapper.timed();
+            const COUNT_QUERY: &str = r#"
+                SELECT
+                    COUNT(task_id),
+                    COUNT(task_id) FILTER (WHERE result_status IN ('failed', 'timeout'))
+                FROM background_remover_task
+                WHERE date_completed > $1 AND result_status IS NOT NULL
+            "#;
+
+            let counts: (i64, i64) = sqlx::query_as(COUNT_QUERY)
+                .bind(since)
+                .fetch_one(connection)
+                .await?;
+            Ok((counts.0 as u64, counts.1 as u64))
+        }
+
+        ///
+        /// Returns number of tasks created after `since` which are still processing.
+        ///
+        pub async fn count_processing(
+            db_wrapper: Arc<DBWrapper>,
+            since: &DateTime<Utc>,
+        ) -> Result<u64, sqlx::Error> {
+            let connection = db_wrapper.timed();
             const COUNT_QUERY: &str = r#"
                 SELECT COUNT(task_id) AS total FROM background_remover_task
+                WHERE processing IS TRUE AND date_created > $1
             "#;
 
-            let size: (i64,) = sqlx::query_as(COUNT_QUERY).fetch_one(&connection).await?;
+            let size: (i64,) = sqlx::query_as(COUNT_QUERY)
+                .bind(since)
+                .fetch_one(connection)
+                .await?;
             Ok(size.0 as u64)
         }
 
+        ///
+        /// Returns `(key, date_created)` pairs of the tasks matching any of `keys`. Keys without a
+        /// record are absent from the result.
+        ///
+        pub async fn fetch_creation_dates(
+            db_wrapper: Arc<DBWrapper>,
+            keys: &[Uuid],
+        ) -> Result<Vec<(Uuid, DateTime<Utc>)>, sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const FETCH_QUERY: &str = r#"
+                SELECT key, date_created FROM background_remover_task WHERE key = ANY($1)
+                UNION ALL
+                SELECT key, date_created FROM archived_background_remover_task WHERE key = ANY($1)
+            "#;
+
+            let rows: Vec<(Uuid, DateTime<Utc>)> = sqlx::query_as(FETCH_QUERY)
+                .bind(keys)
+                .fetch_all(connection)
+                .await?;
+
+            Ok(rows)
+        }
+
+        ///
+        /// Returns key, task group and tenant of every task, archived or not, stored with one of
+        /// `user_identifiers`.
+        ///
+        pub async fn fetch_by_user_identifiers(
+            db_wrapper: Arc<DBWrapper>,
+            user_identifiers: &[String],
+        ) -> Result<Vec<(Uuid, Uuid, Option<String>)>, sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const FETCH_QUERY: &str = r#"
+                SELECT key, task_group, tenant FROM background_remover_task
+                    WHERE user_identifier = ANY($1)
+                UNION ALL
+                SELECT key, task_group, tenant FROM archived_background_remover_task
+                    WHERE user_identifier = ANY($1)
+            "#;
+
+            sqlx::query_as(FETCH_QUERY)
+                .bind(user_identifiers)
+                .fetch_all(connection)
+                .await
+        }
+
+        ///
+        /// Deletes the tasks with `keys` from both task tables, with their revisions, revision
+        /// comparisons, canary results, manifests, outbox entries, media audit findings and
+        /// archived BP messages, and the batch email registrations of `task_groups`, in one
+        /// transaction. Usage rows are kept
+        /// for billing; they only hold the task key and the key id.
+        ///
+        pub async fn erase(
+            db_wrapper: Arc<DBWrapper>,
+            keys: &[Uuid],
+            task_groups: &[Uuid],
+        ) -> Result<ErasedRows, sqlx::Error> {
+            let mut transaction = db_wrapper.pool.begin().await?;
+            let tx = &mut transaction;
+
+            // Messages of refinements are stored under the revision key, so they go first.
+            const DELETE_BP_MESSAGES_QUERY: &str = r#"
+                DELETE FROM bp_message
+                    WHERE task_key = ANY($1)
+                        OR task_key IN (SELECT key FROM task_revision WHERE task_key = ANY($1))
+            "#;
+            let bp_messages = sqlx::query(DELETE_BP_MESSAGES_QUERY)
+                .bind(keys)
+                .execute(&mut **tx)
+                .await?
+                .rows_affected();
+
+            let tasks = delete_any(tx, "background_remover_task", "key", keys).await?
+                + delete_any(tx, "archived_background_remover_task", "key", keys).await?;
+            let erased = ErasedRows {
+                tasks,
+                revisions: delete_any(tx, "task_revision", "task_key", keys).await?,
+                revision_comparisons: delete_any(tx, "revision_comparison", "task_key", keys)
+                    .await?,
+                canary_results: delete_any(tx, "canary_result", "task_key", keys).await?,
+                manifests: delete_any(tx, "task_manifest", "task_key", keys).await?,
+                outbox_entries: delete_any(tx, "notification_outbox", "task_key", keys).await?,
+                media_audit_reports: delete_any(tx, "media_audit_report", "task_key", keys).await?,
+                task_group_notifications: delete_any(
+                    tx,
+                    "task_group_notification",
+                    "task_group",
+                    task_groups,
+                )
+                .await?,
+                bp_messages,
+            };
+
+            transaction.commit().await?;
+            Ok(erased)
+        }
+
+        ///
+        /// Returns up to `limit` tasks with `task_id` greater than `after_task_id` in ascending
+        /// order. Used for walking the whole table in batches.
+        ///
+        pub async fn fetch_batch_after(
+            db_wrapper: Arc<DBWrapper>,
+            after_task_id: i64,
+            limit: i64,
+        ) -> Result<Vec<BackgroundRemoverTask>, sqlx::Error> {
+            let connection = db_wrapper.timed();
+
+            const FETCH_QUERY: &str = r#"
+                SELECT * FROM background_remover_task
+                    WHERE task_id > $1
+                    ORDER BY task_id ASC
+                    LIMIT $2
+            "#;
+
+            let models: Vec<BackgroundRemoverTask> = sqlx::query_as(FETCH_QUERY)
+                .bind(after_task_id)
+                .bind(limit)
+                .fetch_all(connection)
+                .await?;
+
+            Ok(models)
+        }
+
+        ///
+        /// Sets one of the nullable output path columns to `NULL` and removes the file from the
+        /// manifest of the task.
+        ///
+        pub async fn nullify_path(
+            db_wrapper: Arc<DBWrapper>,
+            key: &Uuid,
+            column: NullablePathColumn,
+        ) -> Result<(), sqlx::Error> {
+            // Column name comes from a fixed set of identifiers, never from user input.
+            let update_query = format!(
+                "UPDATE background_remover_task SET {}=NULL WHERE key=$1",
+                column.name()
+            );
+
+            let mut transaction = db_wrapper.pool.begin().await?;
+            sqlx::query(&update_query)
+                .bind(key)
+                .execute(&mut *transaction)
+                .await?;
+            TaskManifest::replace_file(&mut transaction, key, column.manifest_role(), None).await?;
+            transaction.commit().await?;
+            Ok(())
+        }
+
         pub async fn fetch_by_date_from(
             db_wrapper: DBWrapper,
             from_past: &DateTime<Utc>,
             to_present: &DateTime<Utc>,
         ) -> Result<Vec<BackgroundRemoverTask>, sqlx::Error> {
-            let connection = db_wrapper.pool.clone();
+            let connection = db_wrapper.timed();
 
             let fetch_query = r#"
                 SELECT * FROM background_remover_task
@@ -428,10 +4611,135 @@ pub mod models {
             let models = sqlx::query_as(&fetch_query)
                 .bind(from_past)
                 .bind(to_present)
-                .fetch_all(&connection)
+                .fetch_all(connection)
                 .await?;
 
             Ok(models)
         }
     }
+
+    ///
+    /// Deletes rows of `table` whose `column` is one of `ids` within `transaction`. Returns the
+    /// number of deleted rows.
+    ///
+    async fn delete_any(
+        transaction: &mut Transaction<'_, Postgres>,
+        table: &str,
+        column: &str,
+        ids: &[Uuid],
+    ) -> Result<u64, sqlx::Error> {
+        // Table and column names are fixed identifiers, never user input.
+        let delete_query = format!("DELETE FROM {} WHERE {} = ANY($1)", table, column);
+        let result = sqlx::query(&delete_query)
+            .bind(ids)
+            .execute(&mut **transaction)
+            .await?;
+        Ok(result.rows_affected())
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::env;
+    use std::sync::Arc;
+
+    use chrono::{DateTime, Utc};
+    use sqlx::{Executor, PgPool};
+    use uuid::Uuid;
+
+    use super::models::{BackgroundRemoverTask, NewBackgroundRemoverTask, TaskOutputs};
+    use super::{DBWrapper, SETUP_QUERIES};
+
+    ///
+    /// Database set up from `TEST_POSTGRES_URL`. `None` when it isn't set, so tests needing
+    /// Postgres are skipped.
+    ///
+    async fn test_db() -> Option<Arc<DBWrapper>> {
+        let url = env::var("TEST_POSTGRES_URL").ok()?;
+        let pool = PgPool::connect(&url).await.unwrap();
+        for query in SETUP_QUERIES {
+            pool.execute(*query).await.unwrap();
+        }
+        Some(Arc::new(DBWrapper { pool }))
+    }
+
+    async fn result_of(db_wrapper: &Arc<DBWrapper>, key: &Uuid) -> (Option<String>, bool) {
+        let (result_status, date_completed): (Option<String>, Option<DateTime<Utc>>) =
+            sqlx::query_as(
+                "SELECT result_status, date_completed FROM background_remover_task WHERE key=$1",
+            )
+            .bind(key)
+            .fetch_one(&db_wrapper.pool)
+            .await
+            .unwrap();
+        (result_status, date_completed.is_some())
+    }
+
+    #[tokio::test]
+    pub async fn test_reprocess_result_status() {
+        let db_wrapper = match test_db().await {
+            Some(db_wrapper) => db_wrapper,
+            None => return,
+        };
+
+        let key = Uuid::new_v4();
+        let new_task = NewBackgroundRemoverTask {
+            key,
+            task_group: Uuid::new_v4(),
+            original_image_path: "test/original.png".to_string(),
+            preview_original_image_path: "test/preview.png".to_string(),
+            original_filename: None,
+            country: None,
+            user_identifier: None,
+            metadata: None,
+            tags: vec![],
+            original_format: None,
+            outputs: TaskOutputs::All,
+            background_hint: None,
+            preview_settings: String::new(),
+            free_tier: false,
+            api_key_id: None,
+            priority: 0,
+            tenant: None,
+            content_sha256: None,
+            timings: None,
+        };
+        BackgroundRemoverTask::insert_new_task(db_wrapper.clone(), &new_task)
+            .await
+            .unwrap();
+
+        // Dispatching clears the result, storing the reprocessed outputs sets it again.
+        let first = Uuid::new_v4();
+        BackgroundRemoverTask::set_bp_request_id(db_wrapper.clone(), &key, &first)
+            .await
+            .unwrap();
+        assert_eq!((None, false), result_of(&db_wrapper, &key).await);
+        assert!(
+            BackgroundRemoverTask::complete_reprocess(db_wrapper.clone(), &key, Some(first))
+                .await
+                .unwrap()
+        );
+        assert_eq!(
+            (Some("success".to_string()), true),
+            result_of(&db_wrapper, &key).await
+        );
+
+        // The response of an earlier dispatch doesn't complete a later one.
+        let second = Uuid::new_v4();
+        BackgroundRemoverTask::set_bp_request_id(db_wrapper.clone(), &key, &second)
+            .await
+            .unwrap();
+        assert!(
+            !BackgroundRemoverTask::complete_reprocess(db_wrapper.clone(), &key, Some(first))
+                .await
+                .unwrap()
+        );
+        assert_eq!((None, false), result_of(&db_wrapper, &key).await);
+
+        sqlx::query("DELETE FROM background_remover_task WHERE key=$1")
+            .bind(key)
+            .execute(&db_wrapper.pool)
+            .await
+            .unwrap();
+    }
 }
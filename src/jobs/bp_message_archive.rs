@@ -0,0 +1,86 @@
+use std::sync::Arc;
+
+use tokio::sync::broadcast::error::RecvError;
+use uuid::Uuid;
+
+use crate::clients::bp_request_client::BPRequestClient;
+use crate::db::models::BpMessage;
+use crate::SharedContext;
+
+///
+/// Archives task messages of the public, standby, canary and dedicated BP connections while
+/// `BP_MESSAGE_ARCHIVE` is enabled.
+///
+pub fn spawn(shared_context: SharedContext) {
+    let mut clients: Vec<_> = shared_context
+        .public_bp_servers
+        .clients()
+        .cloned()
+        .collect();
+    if let Some(canary) = &shared_context.canary {
+        clients.push(canary.client.clone());
+    }
+    clients.extend(shared_context.dedicated_bp_servers.clients().cloned());
+
+    for client in clients {
+        tokio::spawn(archive_messages(shared_context.clone(), client));
+    }
+}
+
+///
+/// Trims the archive to the newest `BP_MESSAGE_ARCHIVE_MAX_ROWS` messages.
+///
+pub async fn trim_once(shared_context: SharedContext) -> Result<String, String> {
+    let max_rows = shared_context.config.load().bp_message_archive_max_rows;
+    match BpMessage::trim(shared_context.db_wrapper.clone(), max_rows).await {
+        Ok(deleted) => Ok(format!("Trimmed {} archived BP messages.", deleted)),
+        Err(error) => Err(format!(
+            "Failed to trim archived BP messages. Error: {}",
+            error
+        )),
+    }
+}
+
+///
+/// Stores the task messages of `client`. Messages without a task key are skipped.
+///
+async fn archive_messages(shared_context: SharedContext, client: Arc<BPRequestClient>) {
+    let mut messages = client.subscribe_messages();
+
+    loop {
+        let exchanged = match messages.recv().await {
+            Ok(exchanged) => exchanged,
+            Err(RecvError::Lagged(skipped)) => {
+                eprintln!("Skipped archiving {} BP messages.", skipped);
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+
+        if !shared_context.config.load().bp_message_archive {
+            continue;
+        }
+
+        let task_key = match exchanged
+            .message
+            .get("task_id")
+            .and_then(|task_id| task_id.as_str())
+            .and_then(|task_id| Uuid::parse_str(task_id).ok())
+        {
+            Some(task_key) => task_key,
+            None => continue,
+        };
+
+        if let Err(error) = BpMessage::insert(
+            shared_context.db_wrapper.clone(),
+            &task_key,
+            client.address(),
+            exchanged.direction.as_str(),
+            &exchanged.message,
+        )
+        .await
+        {
+            eprintln!("Failed to archive BP message. Error: {}", error);
+        }
+    }
+}
@@ -0,0 +1,69 @@
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use std::time::Duration;
+
+use arc_swap::ArcSwap;
+use tokio::time::sleep;
+
+use crate::api::ws_clients::{WsClients, WsClientsConfig};
+use crate::config::{AppConfig, Overrides};
+use crate::db::models::{AppSetting, ConfigAuditEntry};
+use crate::db::DBWrapper;
+
+///
+/// Polls `app_config` forever, waiting `interval` between runs. When the overrides change, the
+/// application and websocket settings are rebuilt and swapped atomically, and every changed
+/// setting is written to `config_audit_log`.
+///
+pub async fn run_periodically(
+    db_wrapper: Arc<DBWrapper>,
+    config: Arc<ArcSwap<AppConfig>>,
+    ws_clients: Arc<WsClients>,
+    interval: Duration,
+) {
+    let mut applied = Overrides::new();
+
+    loop {
+        match AppSetting::fetch_all(db_wrapper.clone()).await {
+            Ok(overrides) if overrides != applied => {
+                config.store(Arc::new(AppConfig::load(&overrides)));
+                ws_clients.set_config(WsClientsConfig::load(&overrides));
+
+                audit_changes(db_wrapper.clone(), &applied, &overrides).await;
+                applied = overrides;
+            }
+            Ok(_) => {}
+            Err(error) => eprintln!("Failed to reload settings. Error: {}", error),
+        }
+
+        sleep(interval).await;
+    }
+}
+
+async fn audit_changes(db_wrapper: Arc<DBWrapper>, old: &Overrides, new: &Overrides) {
+    let names: BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+
+    for name in names {
+        let old_value = old.get(name).map(String::as_str);
+        let new_value = new.get(name).map(String::as_str);
+        if old_value == new_value {
+            continue;
+        }
+
+        log::info!(
+            "Applied setting {}: {:?} -> {:?}",
+            name,
+            old_value,
+            new_value
+        );
+
+        let entry = ConfigAuditEntry {
+            name,
+            old_value,
+            new_value,
+        };
+        if let Err(error) = entry.insert(db_wrapper.clone()).await {
+            eprintln!("Failed to write config audit log. Error: {}", error);
+        }
+    }
+}
@@ -0,0 +1,442 @@
+use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use chrono::Utc;
+use serde_json::{json, Value};
+use tokio::sync::broadcast::error::RecvError;
+use uuid::Uuid;
+
+use crate::api::ingestion::{self, IngestError, Submission, MAX_IMAGE_SIZE};
+use crate::api::lifecycle::{LifecycleEvent, LifecycleStage};
+use crate::db::models::{BackgroundRemoverTask, ManifestFile};
+use crate::utils::drop_folder_utils::{self, DropClient};
+use crate::utils::path_utils;
+use crate::SharedContext;
+
+///
+/// Client folders below `DROP_FOLDER_ROOT`, e.g. the home directories of an SFTP server. Each
+/// client drops images into `<folder>/in/` and gets results in `<folder>/out/`.
+///
+pub struct DropFolders {
+    root: PathBuf,
+    clients: Vec<DropClient>,
+    /// Files modified more recently are left for the next scan, as they may still be uploading.
+    settle: Duration,
+}
+
+impl DropFolders {
+    ///
+    /// Reads `DROP_FOLDER_ROOT`, `DROP_FOLDER_CLIENTS` and `DROP_FOLDER_SETTLE_SECS` (default
+    /// 30). `None` unless `DROP_FOLDER_ROOT` is set and clients are mapped.
+    ///
+    pub fn from_env() -> Option<Self> {
+        let root = env::var("DROP_FOLDER_ROOT")
+            .ok()
+            .filter(|root| !root.is_empty())?;
+        let clients = match drop_folder_utils::parse_clients(
+            &env::var("DROP_FOLDER_CLIENTS").unwrap_or_default(),
+        ) {
+            Ok(clients) if !clients.is_empty() => clients,
+            Ok(_) => {
+                eprintln!("DROP_FOLDER_ROOT is set but DROP_FOLDER_CLIENTS maps no folders.");
+                return None;
+            }
+            Err(error) => {
+                eprintln!("Ignoring invalid DROP_FOLDER_CLIENTS. {}", error);
+                return None;
+            }
+        };
+        let settle = match env::var("DROP_FOLDER_SETTLE_SECS") {
+            Ok(value) => value.parse::<u64>().unwrap_or(30),
+            Err(_) => 30,
+        };
+
+        Some(Self {
+            root: PathBuf::from(root),
+            clients,
+            settle: Duration::from_secs(settle),
+        })
+    }
+
+    fn in_dir(&self, client: &DropClient) -> PathBuf {
+        self.root.join(&client.folder).join("in")
+    }
+
+    fn out_dir(&self, client: &DropClient) -> PathBuf {
+        self.root.join(&client.folder).join("out")
+    }
+}
+
+///
+/// Outcome of a scan.
+///
+#[derive(Default)]
+struct ScanReport {
+    ingested: u64,
+    rejected: u64,
+    retrying: u64,
+    reconciled: u64,
+}
+
+///
+/// Ingests the settled files of every client folder once. Ingested and rejected files are
+/// removed from `in/`; files whose image or the database was unavailable are kept for the next
+/// scan. Results missed by the result writer, e.g. while no replica was running, are written
+/// too.
+///
+pub async fn scan_once(
+    shared_context: SharedContext,
+    drop_folders: Arc<DropFolders>,
+) -> Result<String, String> {
+    let mut report = ScanReport::default();
+    for client in &drop_folders.clients {
+        if let Err(error) = scan_client(&shared_context, &drop_folders, client, &mut report).await {
+            return Err(format!(
+                "Failed to scan drop folder {}. Error: {}",
+                client.folder, error
+            ));
+        }
+    }
+
+    Ok(format!(
+        "Ingested {} dropped files, rejected {}, retrying {}. Wrote {} missed results.",
+        report.ingested, report.rejected, report.retrying, report.reconciled
+    ))
+}
+
+async fn scan_client(
+    shared_context: &SharedContext,
+    drop_folders: &DropFolders,
+    client: &DropClient,
+    report: &mut ScanReport,
+) -> std::io::Result<()> {
+    let in_dir = drop_folders.in_dir(client);
+    let out_dir = drop_folders.out_dir(client);
+    tokio::fs::create_dir_all(&in_dir).await?;
+    tokio::fs::create_dir_all(&out_dir).await?;
+
+    let metrics = &shared_context.metrics;
+    let mut entries = tokio::fs::read_dir(&in_dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let filename = match entry.file_name().into_string() {
+            Ok(filename) if drop_folder_utils::is_candidate(&filename) => filename,
+            _ => continue,
+        };
+        let metadata = match entry.metadata().await {
+            Ok(metadata) if metadata.is_file() => metadata,
+            _ => continue,
+        };
+        let modified = metadata.modified().unwrap_or(UNIX_EPOCH);
+        let age = SystemTime::now()
+            .duration_since(modified)
+            .unwrap_or_default();
+        if age < drop_folders.settle {
+            continue;
+        }
+
+        let modified_nanos = modified
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let key =
+            drop_folder_utils::task_key(&client.folder, &filename, metadata.len(), modified_nanos);
+        write_status(
+            &out_dir,
+            &filename,
+            json!({ "task_key": key, "status": "processing" }),
+        )
+        .await;
+
+        let path = entry.path();
+        match ingest_file(
+            shared_context,
+            client,
+            key,
+            &filename,
+            &path,
+            metadata.len(),
+        )
+        .await
+        {
+            Ok(_) => {
+                println!("Ingested task {} from drop folder {}.", key, client.folder);
+                metrics.increment("drop_folder_tasks_total");
+                report.ingested += 1;
+            }
+            Err(error) if error.is_retryable() => {
+                eprintln!("Retrying dropped file {:?} later. {}", path, error);
+                metrics.increment("drop_folder_retries_total");
+                report.retrying += 1;
+                continue;
+            }
+            Err(error) => {
+                eprintln!("Rejecting dropped file {:?}. {}", path, error);
+                metrics.increment("drop_folder_rejections_total");
+                report.rejected += 1;
+                let status = json!({ "status": "rejected", "error": error.to_string() });
+                write_status(&out_dir, &filename, status).await;
+            }
+        }
+
+        if let Err(error) = tokio::fs::remove_file(&path).await {
+            eprintln!("Failed to remove dropped file {:?}. Error: {}", path, error);
+        }
+    }
+
+    reconcile_client(shared_context, drop_folders, &out_dir, report).await
+}
+
+///
+/// Writes the results of finished tasks whose status file still says `processing`, as their
+/// lifecycle event was skipped by a lagging result writer or arrived while none was running.
+///
+async fn reconcile_client(
+    shared_context: &SharedContext,
+    drop_folders: &DropFolders,
+    out_dir: &Path,
+    report: &mut ScanReport,
+) -> std::io::Result<()> {
+    let mut entries = tokio::fs::read_dir(out_dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let is_status = entry
+            .file_name()
+            .to_str()
+            .is_some_and(|name| !name.starts_with('.') && name.ends_with(".status.json"));
+        if !is_status {
+            continue;
+        }
+        let key = match tokio::fs::read(entry.path()).await {
+            Ok(status) => match drop_folder_utils::processing_task_key(&status) {
+                Some(key) => key,
+                None => continue,
+            },
+            Err(_) => continue,
+        };
+
+        let db_wrapper = shared_context.db_wrapper.clone();
+        let task = match BackgroundRemoverTask::fetch(db_wrapper, &key).await {
+            Ok(task) => task,
+            // The dropped file is kept for a retry and has no task yet.
+            Err(sqlx::Error::RowNotFound) => continue,
+            Err(error) => {
+                eprintln!(
+                    "Failed to fetch task for drop folder results. Error: {}",
+                    error
+                );
+                continue;
+            }
+        };
+        let outcome = match task.result_status.as_deref() {
+            Some("success") => Outcome::Completed,
+            Some(status) => Outcome::Failed(Some(status.to_string())),
+            None => continue,
+        };
+        write_result(shared_context, drop_folders, &task, outcome).await;
+        report.reconciled += 1;
+    }
+    Ok(())
+}
+
+async fn ingest_file(
+    shared_context: &SharedContext,
+    client: &DropClient,
+    key: Uuid,
+    filename: &str,
+    path: &Path,
+    size: u64,
+) -> Result<Uuid, IngestError> {
+    let submission = Submission {
+        key: Some(key),
+        key_id: Some(client.key_id.clone()),
+        filename: Some(filename.to_string()),
+        metadata: Some(json!({ "drop_folder": client.folder, "drop_filename": filename })),
+        ..Default::default()
+    };
+
+    let image = async {
+        if size > MAX_IMAGE_SIZE as u64 {
+            return Err(IngestError::Invalid("Image size is too large.".to_string()));
+        }
+        tokio::fs::read(path)
+            .await
+            .map_err(|error| IngestError::Unavailable(error.to_string()))
+    };
+    ingestion::create_task(shared_context, submission, image).await
+}
+
+///
+/// Writes results and status files of dropped files once their tasks completed or failed. Runs
+/// on every replica, as results are stored by the replica connected to the BP server.
+///
+pub fn spawn_result_writer(shared_context: SharedContext, drop_folders: Arc<DropFolders>) {
+    let mut events = shared_context.lifecycle.subscribe();
+    tokio::spawn(async move {
+        loop {
+            match events.recv().await {
+                Ok(event)
+                    if matches!(
+                        event.event,
+                        LifecycleStage::Completed | LifecycleStage::Failed
+                    ) =>
+                {
+                    let shared_context = shared_context.clone();
+                    let drop_folders = drop_folders.clone();
+                    tokio::spawn(async move {
+                        write_event_result(&shared_context, &drop_folders, &event).await
+                    });
+                }
+                Ok(_) => {}
+                // Results of skipped events are written by the next scan.
+                Err(RecvError::Lagged(skipped)) => {
+                    eprintln!("Drop folder result writer skipped {} events.", skipped);
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+}
+
+///
+/// Outcome of a task written back to its drop folder.
+///
+enum Outcome {
+    Completed,
+    /// Failed with the status code of the failure, if known.
+    Failed(Option<String>),
+}
+
+async fn write_event_result(
+    shared_context: &SharedContext,
+    drop_folders: &DropFolders,
+    event: &LifecycleEvent,
+) {
+    let db_wrapper = shared_context.db_wrapper.clone();
+    let task = match BackgroundRemoverTask::fetch(db_wrapper, &event.task_key).await {
+        Ok(task) => task,
+        Err(error) => {
+            eprintln!(
+                "Failed to fetch task for drop folder results. Error: {}",
+                error
+            );
+            return;
+        }
+    };
+    let outcome = match event.event {
+        LifecycleStage::Failed => Outcome::Failed(event.status_code.clone()),
+        _ => Outcome::Completed,
+    };
+    write_result(shared_context, drop_folders, &task, outcome).await;
+}
+
+async fn write_result(
+    shared_context: &SharedContext,
+    drop_folders: &DropFolders,
+    task: &BackgroundRemoverTask,
+    outcome: Outcome,
+) {
+    let source = task.metadata.as_ref().and_then(|metadata| {
+        Some((
+            metadata.get("drop_folder")?.as_str()?,
+            metadata.get("drop_filename")?.as_str()?,
+        ))
+    });
+    let (client, filename) = match source {
+        Some((folder, filename)) => match drop_folders
+            .clients
+            .iter()
+            .find(|client| client.folder == folder)
+        {
+            Some(client) => (client, filename),
+            None => return,
+        },
+        None => return,
+    };
+    let out_dir = drop_folders.out_dir(client);
+
+    if let Outcome::Failed(status_code) = outcome {
+        let status = json!({
+            "task_key": task.key,
+            "status": "failed",
+            "status_code": status_code,
+        });
+        write_status(&out_dir, filename, status).await;
+        return;
+    }
+
+    let media_root = match env::var("MEDIA_ROOT") {
+        Ok(media_root) => PathBuf::from(media_root),
+        Err(_) => return,
+    };
+    let mut results = vec![];
+    for (role, suffix) in [(ManifestFile::PROCESSED, ""), (ManifestFile::MASK, ".mask")] {
+        let path = match task.output_path(role) {
+            Some(path) => PathBuf::from(path),
+            None => continue,
+        };
+        let extension = path.extension().and_then(|extension| extension.to_str());
+        let name = drop_folder_utils::result_name(
+            filename,
+            &format!("{}.{}", suffix, extension.unwrap_or("png")),
+        );
+
+        let full_path = path_utils::file_path_from_relative_url(media_root.clone(), path);
+        let copied = match tokio::fs::read(&full_path).await {
+            Ok(data) => write_file(&out_dir, &name, &data).await,
+            Err(error) => Err(error),
+        };
+        if let Err(error) = copied {
+            eprintln!(
+                "Failed to write drop folder result {}. Error: {}",
+                name, error
+            );
+            shared_context
+                .metrics
+                .increment("drop_folder_results_failed_total");
+            let status = json!({
+                "task_key": task.key,
+                "status": "failed",
+                "error": "Failed to write results.",
+            });
+            write_status(&out_dir, filename, status).await;
+            return;
+        }
+        results.push(name);
+    }
+
+    shared_context
+        .metrics
+        .increment("drop_folder_results_written_total");
+    let status = json!({ "task_key": task.key, "status": "completed", "results": results });
+    write_status(&out_dir, filename, status).await;
+}
+
+///
+/// Writes the status file of dropped file `filename`, `<stem>.status.json`. `status` gets the
+/// filename and the time of the update added.
+///
+async fn write_status(out_dir: &Path, filename: &str, mut status: Value) {
+    if let Some(fields) = status.as_object_mut() {
+        fields.insert("filename".to_string(), json!(filename));
+        fields.insert("updated_at".to_string(), json!(Utc::now().to_rfc3339()));
+    }
+
+    let name = drop_folder_utils::result_name(filename, ".status.json");
+    if let Err(error) = write_file(out_dir, &name, status.to_string().as_bytes()).await {
+        eprintln!(
+            "Failed to write drop folder status {}. Error: {}",
+            name, error
+        );
+    }
+}
+
+///
+/// Writes `data` to `out_dir/name`. Written under a hidden temporary name first, so clients
+/// polling the folder never pick up a partially written file.
+///
+async fn write_file(out_dir: &Path, name: &str, data: &[u8]) -> std::io::Result<()> {
+    let temporary_path = out_dir.join(format!(".{}.tmp", name));
+    tokio::fs::write(&temporary_path, data).await?;
+    tokio::fs::rename(&temporary_path, out_dir.join(name)).await
+}
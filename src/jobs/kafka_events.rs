@@ -0,0 +1,146 @@
+use std::env;
+use std::time::Duration;
+
+use rdkafka::config::ClientConfig;
+use rdkafka::producer::{FutureProducer, FutureRecord};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::api::lifecycle::LifecycleEvent;
+use crate::secrets::{self, SecretStore};
+use crate::SharedContext;
+
+/// How long librdkafka keeps retrying an event before reporting it undelivered.
+const MESSAGE_TIMEOUT: Duration = Duration::from_secs(30);
+
+///
+/// Producer settings read from the environment. See `from_env`.
+///
+struct KafkaConfig {
+    brokers: String,
+    topic: String,
+    security_protocol: Option<String>,
+    sasl_mechanism: Option<String>,
+    sasl_username: Option<String>,
+}
+
+impl KafkaConfig {
+    ///
+    /// Reads `KAFKA_BROKERS`, `KAFKA_EVENTS_TOPIC`, `KAFKA_SECURITY_PROTOCOL`,
+    /// `KAFKA_SASL_MECHANISM` and `KAFKA_SASL_USERNAME`. `None` unless `KAFKA_BROKERS` is set.
+    ///
+    fn from_env() -> Option<Self> {
+        let non_empty = |name: &str| env::var(name).ok().filter(|value| !value.is_empty());
+
+        Some(Self {
+            brokers: non_empty("KAFKA_BROKERS")?,
+            topic: non_empty("KAFKA_EVENTS_TOPIC").unwrap_or("bp-task-events".to_string()),
+            security_protocol: non_empty("KAFKA_SECURITY_PROTOCOL"),
+            sasl_mechanism: non_empty("KAFKA_SASL_MECHANISM"),
+            sasl_username: non_empty("KAFKA_SASL_USERNAME"),
+        })
+    }
+
+    fn producer(&self, secrets: &SecretStore) -> Result<FutureProducer, String> {
+        let mut config = ClientConfig::new();
+        config.set("bootstrap.servers", &self.brokers).set(
+            "message.timeout.ms",
+            MESSAGE_TIMEOUT.as_millis().to_string(),
+        );
+
+        if let Some(security_protocol) = &self.security_protocol {
+            config.set("security.protocol", security_protocol);
+        }
+        if let Some(sasl_mechanism) = &self.sasl_mechanism {
+            config.set("sasl.mechanism", sasl_mechanism);
+        }
+        if let Some(sasl_username) = &self.sasl_username {
+            config.set("sasl.username", sasl_username);
+        }
+        if let Some(sasl_password) = secrets.get(secrets::KAFKA_SASL_PASSWORD) {
+            config.set("sasl.password", sasl_password);
+        }
+
+        config.create().map_err(|error| error.to_string())
+    }
+}
+
+///
+/// Publishes task lifecycle events to the Kafka (or Redpanda) topic `KAFKA_EVENTS_TOPIC` when
+/// `KAFKA_BROKERS` is set. Events are JSON keyed by task key, so the events of a task stay in
+/// order on one partition. Every replica publishes the events of the tasks it handles.
+///
+/// Delivery is at most once: events emitted while the producer can't keep up or Kafka is
+/// unreachable for longer than `MESSAGE_TIMEOUT` are dropped and counted.
+///
+pub fn spawn(shared_context: SharedContext) {
+    let config = match KafkaConfig::from_env() {
+        Some(config) => config,
+        None => return,
+    };
+
+    let producer = match config.producer(&shared_context.secrets) {
+        Ok(producer) => producer,
+        Err(error) => {
+            eprintln!("Failed to create Kafka producer. Error: {}", error);
+            return;
+        }
+    };
+
+    // Subscribed before returning, so no event emitted after startup is missed.
+    let mut events = shared_context.lifecycle.subscribe();
+    tokio::spawn(async move {
+        loop {
+            match events.recv().await {
+                Ok(event) => publish(&shared_context, &producer, &config.topic, &event),
+                Err(RecvError::Lagged(skipped)) => {
+                    eprintln!("Kafka event publisher skipped {} events.", skipped);
+                    shared_context
+                        .metrics
+                        .add("kafka_events_dropped_total", skipped);
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+}
+
+///
+/// Queues `event` in the producer. Delivery is awaited in the background.
+///
+fn publish(
+    shared_context: &SharedContext,
+    producer: &FutureProducer,
+    topic: &str,
+    event: &LifecycleEvent,
+) {
+    let metrics = shared_context.metrics.clone();
+    let payload = match serde_json::to_string(event) {
+        Ok(payload) => payload,
+        Err(error) => {
+            eprintln!("Failed to serialize lifecycle event. Error: {}", error);
+            return;
+        }
+    };
+    let key = event.task_key.to_string();
+
+    let delivery = match producer.send_result(FutureRecord::to(topic).key(&key).payload(&payload)) {
+        Ok(delivery) => delivery,
+        Err((error, _)) => {
+            eprintln!("Failed to queue Kafka event. Error: {}", error);
+            metrics.increment("kafka_events_dropped_total");
+            return;
+        }
+    };
+
+    tokio::spawn(async move {
+        match delivery.await {
+            Ok(Ok(_)) => metrics.increment("kafka_events_published_total"),
+            Ok(Err((error, _))) => {
+                eprintln!("Failed to publish Kafka event. Error: {}", error);
+                metrics.increment("kafka_events_dropped_total");
+            }
+            // The producer was dropped, e.g. on shutdown.
+            Err(_) => metrics.increment("kafka_events_dropped_total"),
+        }
+    });
+}
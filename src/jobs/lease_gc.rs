@@ -0,0 +1,21 @@
+use std::sync::Arc;
+
+use chrono::Utc;
+
+use crate::db::models::Lease;
+use crate::db::DBWrapper;
+
+/// Expired leases are kept this long for inspection.
+const EXPIRED_RETENTION_HOURS: i64 = 24;
+
+///
+/// Removes leases which expired more than `EXPIRED_RETENTION_HOURS` ago. Leases which are left
+/// to expire instead of being released, such as those of dispatched tasks, pile up otherwise.
+///
+pub async fn run_once(db_wrapper: Arc<DBWrapper>) -> Result<String, String> {
+    let before = Utc::now() - chrono::Duration::hours(EXPIRED_RETENTION_HOURS);
+    match Lease::delete_expired(db_wrapper, before).await {
+        Ok(deleted) => Ok(format!("Removed {} expired leases.", deleted)),
+        Err(error) => Err(format!("Failed to remove expired leases. Error: {}", error)),
+    }
+}
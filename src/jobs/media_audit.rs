@@ -0,0 +1,148 @@
+use std::env;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::db::models::{BackgroundRemoverTask, MediaAuditRecord, NullablePathColumn};
+use crate::db::DBWrapper;
+use crate::utils::path_utils;
+
+/// Number of tasks fetched from the database per batch.
+const BATCH_SIZE: i64 = 500;
+
+///
+/// Summary of a media audit run. Individual findings are stored in `media_audit_report`.
+///
+#[derive(Debug, Serialize)]
+pub struct AuditReport {
+    pub run_id: Uuid,
+    pub checked_tasks: u64,
+    pub checked_paths: u64,
+    pub missing_paths: u64,
+    pub nullified_paths: u64,
+}
+
+///
+/// Checks that every non-null path column of every task points to an existing file in
+/// `MEDIA_ROOT`. Mismatches are recorded in `media_audit_report`.
+///
+/// When `nullify` is true, broken output paths are set to `NULL` so serializers stop emitting
+/// dead urls. `original_image_path` and `preview_original_image_path` are not nullable and are
+/// only reported.
+///
+pub async fn verify_media(
+    db_wrapper: Arc<DBWrapper>,
+    nullify: bool,
+) -> std::io::Result<AuditReport> {
+    let media_root = match env::var("MEDIA_ROOT") {
+        Ok(path) => PathBuf::from(path),
+        Err(error) => {
+            eprintln!("MEDIA_ROOT environment variable is missing.");
+            return Err(std::io::Error::other(error));
+        }
+    };
+
+    let mut report = AuditReport {
+        run_id: Uuid::new_v4(),
+        checked_tasks: 0,
+        checked_paths: 0,
+        missing_paths: 0,
+        nullified_paths: 0,
+    };
+
+    let mut last_task_id = 0;
+    loop {
+        let tasks =
+            BackgroundRemoverTask::fetch_batch_after(db_wrapper.clone(), last_task_id, BATCH_SIZE)
+                .await
+                .map_err(std::io::Error::other)?;
+
+        let last = match tasks.last() {
+            Some(task) => task.task_id,
+            None => break,
+        };
+
+        for task in &tasks {
+            report.checked_tasks += 1;
+
+            let columns: [(&str, Option<&String>, Option<NullablePathColumn>); 5] = [
+                ("original_image_path", Some(&task.original_image_path), None),
+                (
+                    "preview_original_image_path",
+                    task.preview_original_image_path.as_ref(),
+                    None,
+                ),
+                (
+                    "mask_image_path",
+                    task.mask_image_path.as_ref(),
+                    Some(NullablePathColumn::MaskImage),
+                ),
+                (
+                    "processed_image_path",
+                    task.processed_image_path.as_ref(),
+                    Some(NullablePathColumn::ProcessedImage),
+                ),
+                (
+                    "preview_processed_image_path",
+                    task.preview_processed_image_path.as_ref(),
+                    Some(NullablePathColumn::PreviewProcessedImage),
+                ),
+            ];
+
+            for (column_name, path, nullable_column) in columns {
+                let path = match path {
+                    Some(path) => path,
+                    None => continue,
+                };
+
+                report.checked_paths += 1;
+
+                let file_path = path_utils::file_path_from_relative_url(
+                    media_root.clone(),
+                    PathBuf::from(path),
+                );
+                if tokio::fs::try_exists(&file_path).await.unwrap_or(false) {
+                    continue;
+                }
+
+                report.missing_paths += 1;
+
+                let mut nullified = false;
+                if let (true, Some(column)) = (nullify, nullable_column) {
+                    match BackgroundRemoverTask::nullify_path(db_wrapper.clone(), &task.key, column)
+                        .await
+                    {
+                        Ok(()) => {
+                            nullified = true;
+                            report.nullified_paths += 1;
+                        }
+                        Err(error) => {
+                            eprintln!(
+                                "Failed to nullify {} of {}. Error: {}",
+                                column_name, task.key, error
+                            );
+                        }
+                    }
+                }
+
+                let record = MediaAuditRecord {
+                    run_id: report.run_id,
+                    task_key: task.key,
+                    column_name: column_name.to_string(),
+                    path: path.to_string(),
+                    nullified,
+                };
+
+                if let Err(error) = MediaAuditRecord::insert(db_wrapper.clone(), &record).await {
+                    eprintln!("Failed to insert media audit record. Error: {}", error);
+                }
+            }
+        }
+
+        last_task_id = last;
+    }
+
+    Ok(report)
+}
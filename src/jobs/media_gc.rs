@@ -0,0 +1,282 @@
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use arc_swap::ArcSwap;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::config::AppConfig;
+use crate::db::models::{BackgroundRemoverTask, TaskManifest};
+use crate::db::DBWrapper;
+use crate::utils::path_utils;
+use crate::utils::throttle_utils::{DeleteThrottle, Pacer};
+
+/// Directories modified more recently than this are never collected. Uploads create the task
+/// directory before the database row is inserted, so a fresh directory may not have a row yet.
+const GRACE_PERIOD: Duration = Duration::from_secs(60 * 60);
+
+/// Number of task keys looked up in the database per query.
+const LOOKUP_CHUNK_SIZE: usize = 500;
+
+///
+/// Summary of a garbage collector run.
+///
+#[derive(Debug, Default, Serialize)]
+pub struct GcReport {
+    /// Number of task directories inspected.
+    pub scanned: u64,
+    /// Task keys whose directories were removed (or would be removed in dry run).
+    pub removed: Vec<String>,
+    /// Output files of kept tasks which are missing from their manifest, e.g. outputs of an
+    /// earlier dispatch or a replaced preview. Relative paths.
+    pub removed_outputs: Vec<String>,
+    /// Total size of the removed directories and outputs in bytes.
+    pub reclaimed_bytes: u64,
+    pub dry_run: bool,
+}
+
+///
+/// Walks `MEDIA_ROOT/background-remover` and the `background-remover` directory of every tenant,
+/// including their shard directories, and removes task directories which either have no matching
+/// row in the database or belong to tasks older than `retention_days`. `None` means files of
+/// existing tasks are kept forever. In directories holding files of a task manifest, files
+/// missing from the manifest are removed.
+///
+/// When `dry_run` is true, nothing is deleted but the report lists what would be removed.
+/// Otherwise files are removed at the pace of `throttle`.
+///
+pub async fn collect_orphaned_media(
+    db_wrapper: Arc<DBWrapper>,
+    retention_days: Option<i64>,
+    dry_run: bool,
+    throttle: DeleteThrottle,
+) -> std::io::Result<GcReport> {
+    let media_root = match env::var("MEDIA_ROOT") {
+        Ok(path) => PathBuf::from(path),
+        Err(error) => {
+            eprintln!("MEDIA_ROOT environment variable is missing.");
+            return Err(std::io::Error::other(error));
+        }
+    };
+
+    let mut report = GcReport {
+        dry_run,
+        ..Default::default()
+    };
+    let mut pacer = Pacer::new(throttle);
+
+    // Task directories are named after the task key, directly in a tasks directory or in its
+    // shard directories. Anything else is left untouched.
+    let mut candidates: Vec<(Uuid, PathBuf)> = vec![];
+    let mut pending = tasks_dirs(&media_root).await?;
+    while let Some(dir) = pending.pop() {
+        let mut entries = tokio::fs::read_dir(&dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let metadata = entry.metadata().await?;
+            if !metadata.is_dir() {
+                continue;
+            }
+
+            let name = entry.file_name().to_string_lossy().to_string();
+            if path_utils::is_shard_name(&name) {
+                pending.push(entry.path());
+                continue;
+            }
+
+            let key = match Uuid::parse_str(&name) {
+                Ok(key) => key,
+                Err(_) => continue,
+            };
+
+            report.scanned += 1;
+
+            let age = SystemTime::now()
+                .duration_since(metadata.modified()?)
+                .unwrap_or_default();
+            if age < GRACE_PERIOD {
+                continue;
+            }
+
+            candidates.push((key, entry.path()));
+        }
+    }
+
+    for chunk in candidates.chunks(LOOKUP_CHUNK_SIZE) {
+        let keys: Vec<Uuid> = chunk.iter().map(|(key, _)| *key).collect();
+        let rows = BackgroundRemoverTask::fetch_creation_dates(db_wrapper.clone(), &keys)
+            .await
+            .map_err(std::io::Error::other)?;
+        let date_created_by_key: HashMap<Uuid, DateTime<Utc>> = rows.into_iter().collect();
+        let mut kept_keys = vec![];
+
+        for (key, path) in chunk {
+            let expired = match (date_created_by_key.get(key), retention_days) {
+                (None, _) => true,
+                (Some(date_created), Some(days)) => {
+                    Utc::now() - *date_created > chrono::Duration::days(days)
+                }
+                (Some(_), None) => false,
+            };
+
+            if !expired {
+                kept_keys.push(*key);
+                continue;
+            }
+
+            let size = dir_size(path).await;
+            if !dry_run {
+                if let Err(error) = pacer.remove_dir_all(path).await {
+                    eprintln!("Failed to remove {:?}. Error: {}", path, error);
+                    continue;
+                }
+            }
+
+            report.reclaimed_bytes += size;
+            report.removed.push(key.to_string());
+        }
+
+        let manifests = TaskManifest::fetch_by_keys(db_wrapper.clone(), &kept_keys)
+            .await
+            .map_err(std::io::Error::other)?;
+        for manifest in manifests {
+            remove_unlisted_outputs(&media_root, &manifest, &mut report, &mut pacer).await;
+        }
+    }
+
+    Ok(report)
+}
+
+///
+/// Existing task directories: `MEDIA_ROOT/background-remover` and
+/// `MEDIA_ROOT/tenants/<tenant>/background-remover`.
+///
+async fn tasks_dirs(media_root: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut tasks_dirs = vec![media_root.join("background-remover")];
+
+    let tenants_dir = media_root.join(path_utils::TENANTS_DIRECTORY);
+    if tenants_dir.exists() {
+        let mut entries = tokio::fs::read_dir(&tenants_dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            tasks_dirs.push(entry.path().join("background-remover"));
+        }
+    }
+
+    tasks_dirs.retain(|tasks_dir| tasks_dir.is_dir());
+    Ok(tasks_dirs)
+}
+
+///
+/// Removes files next to the files of `manifest` which are not listed in it. Files modified
+/// within the grace period are kept, as a dispatch may have saved them before its manifest.
+///
+async fn remove_unlisted_outputs(
+    media_root: &PathBuf,
+    manifest: &TaskManifest,
+    report: &mut GcReport,
+    pacer: &mut Pacer,
+) {
+    if manifest.storage_backend != TaskManifest::LOCAL_STORAGE {
+        return;
+    }
+
+    let listed: HashSet<PathBuf> = manifest
+        .files
+        .iter()
+        .map(|file| {
+            path_utils::file_path_from_relative_url(media_root.clone(), PathBuf::from(&file.path))
+        })
+        .collect();
+    let dirs: HashSet<&Path> = listed.iter().filter_map(|path| path.parent()).collect();
+
+    for dir in dirs {
+        let mut entries = match tokio::fs::read_dir(dir).await {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let path = entry.path();
+            if listed.contains(&path) {
+                continue;
+            }
+
+            let metadata = match entry.metadata().await {
+                Ok(metadata) if metadata.is_file() => metadata,
+                _ => continue,
+            };
+            let age = metadata
+                .modified()
+                .ok()
+                .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+                .unwrap_or_default();
+            if age < GRACE_PERIOD {
+                continue;
+            }
+
+            if !report.dry_run {
+                if let Err(error) = pacer.remove_file(&path, metadata.len()).await {
+                    eprintln!("Failed to remove {:?}. Error: {}", path, error);
+                    continue;
+                }
+            }
+
+            report.reclaimed_bytes += metadata.len();
+            report.removed_outputs.push(
+                path_utils::relative_media_url_from_full_path(media_root, &path)
+                    .to_string_lossy()
+                    .to_string(),
+            );
+        }
+    }
+}
+
+///
+/// Returns total size in bytes of all files inside `path`. Unreadable entries are skipped.
+///
+pub async fn dir_size(path: &Path) -> u64 {
+    let mut total = 0;
+    let mut pending = vec![path.to_path_buf()];
+
+    while let Some(dir) = pending.pop() {
+        let mut entries = match tokio::fs::read_dir(&dir).await {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            match entry.metadata().await {
+                Ok(metadata) if metadata.is_dir() => pending.push(entry.path()),
+                Ok(metadata) => total += metadata.len(),
+                Err(_) => {}
+            }
+        }
+    }
+
+    total
+}
+
+///
+/// Runs the garbage collector once. The retention window and the throttle are read from `config`
+/// on every run.
+///
+pub async fn run_once(
+    db_wrapper: Arc<DBWrapper>,
+    config: Arc<ArcSwap<AppConfig>>,
+) -> Result<String, String> {
+    let config = config.load_full();
+    let throttle = config.media_gc_throttle;
+    match collect_orphaned_media(db_wrapper, config.media_retention_days, false, throttle).await {
+        Ok(report) => Ok(format!(
+            "Scanned {} directories, removed {} and {} unlisted outputs, reclaimed {} bytes.",
+            report.scanned,
+            report.removed.len(),
+            report.removed_outputs.len(),
+            report.reclaimed_bytes
+        )),
+        Err(error) => Err(format!("Media GC failed. Error: {}", error)),
+    }
+}
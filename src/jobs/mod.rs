@@ -0,0 +1,112 @@
+use std::sync::Arc;
+
+use crate::api::ws_messages;
+use crate::db;
+use crate::secrets::SecretStore;
+
+pub mod bp_message_archive;
+pub mod config_reload;
+pub mod drop_folder;
+#[cfg(feature = "kafka-events")]
+pub mod kafka_events;
+pub mod lease_gc;
+pub mod media_audit;
+pub mod media_gc;
+pub mod ops_monitor;
+pub mod outbox_delivery;
+pub mod progress_flush;
+#[cfg(feature = "nats-ingest")]
+pub mod queue_ingest;
+#[cfg(feature = "s3-ingest")]
+pub mod s3_ingest;
+pub mod scheduler;
+pub mod secrets_refresh;
+pub mod self_check;
+pub mod stats_rollup;
+pub mod task_anonymize;
+pub mod task_archive;
+pub mod temp_janitor;
+pub mod usage_report;
+pub mod ws_heartbeat;
+
+///
+/// Runs a maintenance command given as command line arguments and exits without starting the
+/// server.
+///
+/// Available commands:
+/// - `verify-media [--nullify]`
+/// - `refresh-stats <days>`
+/// - `ws-schema`
+/// - `check [--json]`
+///
+pub async fn run_command(args: &[String]) -> std::io::Result<()> {
+    let command = args[0].as_str();
+
+    match command {
+        "verify-media" => {
+            let nullify = args.iter().any(|arg| arg == "--nullify");
+            let db_wrapper = Arc::new(db::setup(&SecretStore::load().await).await?);
+
+            let report = media_audit::verify_media(db_wrapper, nullify).await?;
+            println!(
+                "Media audit {} checked {} paths of {} tasks. Missing: {}, nullified: {}.",
+                report.run_id,
+                report.checked_paths,
+                report.checked_tasks,
+                report.missing_paths,
+                report.nullified_paths
+            );
+            Ok(())
+        }
+        "refresh-stats" => {
+            let days = match args.get(1).map(|value| value.parse::<u32>()) {
+                Some(Ok(days)) if days > 0 => days,
+                _ => {
+                    return Err(std::io::Error::other(
+                        "Usage: refresh-stats <days>. Days must be a positive number.",
+                    ))
+                }
+            };
+            let db_wrapper = Arc::new(db::setup(&SecretStore::load().await).await?);
+
+            stats_rollup::refresh_recent(db_wrapper, days)
+                .await
+                .map_err(std::io::Error::other)?;
+            println!("Refreshed task stats rollups of the last {} days.", days);
+            Ok(())
+        }
+        "ws-schema" => {
+            let schema = ws_messages::json_schema();
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&schema).map_err(std::io::Error::other)?
+            );
+            Ok(())
+        }
+        "check" => {
+            let report = self_check::run().await;
+            if args.iter().any(|arg| arg == "--json") {
+                println!(
+                    "{}",
+                    serde_json::to_string_pretty(&report).map_err(std::io::Error::other)?
+                );
+            } else {
+                for check in &report.checks {
+                    println!("[{:?}] {}: {}", check.status, check.name, check.message);
+                }
+            }
+
+            match report.failed() {
+                0 => Ok(()),
+                failed => Err(std::io::Error::other(format!(
+                    "Self-check failed with {} problems.",
+                    failed
+                ))),
+            }
+        }
+        _ => Err(std::io::Error::other(format!(
+            "Unknown command: {}",
+            command
+        ))),
+    }
+}
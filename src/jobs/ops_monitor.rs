@@ -0,0 +1,97 @@
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+
+use arc_swap::ArcSwap;
+use chrono::Utc;
+use tokio::time::sleep;
+
+use crate::clients::ops_notifier::{OpsEvent, OpsNotifier};
+use crate::config::AppConfig;
+use crate::db::models::BackgroundRemoverTask;
+use crate::db::DBWrapper;
+use crate::utils::alert_utils;
+
+///
+/// Checks the failure rate of recent results and the disk usage of `MEDIA_ROOT` every
+/// `interval`, posting an alert when a threshold of `config` is exceeded. Thresholds are read on
+/// every check.
+///
+pub async fn run_periodically(
+    db_wrapper: Arc<DBWrapper>,
+    config: Arc<ArcSwap<AppConfig>>,
+    notifier: Arc<OpsNotifier>,
+    interval: Duration,
+) {
+    loop {
+        sleep(interval).await;
+
+        let config = config.load_full();
+        check_failure_rate(db_wrapper.clone(), &config, &notifier).await;
+        check_disk_usage(&config, &notifier);
+    }
+}
+
+async fn check_failure_rate(
+    db_wrapper: Arc<DBWrapper>,
+    config: &AppConfig,
+    notifier: &Arc<OpsNotifier>,
+) {
+    let window = config.ops_failure_window;
+    let since = Utc::now() - chrono::Duration::seconds(window.as_secs() as i64);
+
+    let (total, failed) = match BackgroundRemoverTask::count_results_since(db_wrapper, &since).await
+    {
+        Ok(counts) => counts,
+        Err(error) => {
+            eprintln!("Failed to count recent task results. Error: {}", error);
+            return;
+        }
+    };
+
+    if alert_utils::failure_rate_exceeded(
+        failed,
+        total,
+        config.ops_failure_min_tasks,
+        config.ops_failure_rate_percent,
+    ) {
+        notifier.notify(OpsEvent::FailureRateSpike {
+            failed,
+            total,
+            window,
+        });
+    }
+}
+
+fn check_disk_usage(config: &AppConfig, notifier: &Arc<OpsNotifier>) {
+    let media_root = match env::var("MEDIA_ROOT") {
+        Ok(path) => path,
+        Err(_) => return,
+    };
+
+    let stat = match nix::sys::statvfs::statvfs(media_root.as_str()) {
+        Ok(stat) => stat,
+        Err(error) => {
+            eprintln!(
+                "Failed to read disk usage of {}. Error: {}",
+                media_root, error
+            );
+            return;
+        }
+    };
+
+    // Same as `df`: blocks reserved for root count as neither used nor available.
+    let used = stat.blocks().saturating_sub(stat.blocks_free()) as u64;
+    let usable = used + stat.blocks_available() as u64;
+    if usable == 0 {
+        return;
+    }
+
+    let used_percent = (used * 100).div_ceil(usable);
+    if used_percent >= config.ops_disk_usage_percent as u64 {
+        notifier.notify(OpsEvent::DiskUsage {
+            path: media_root,
+            used_percent,
+        });
+    }
+}
@@ -0,0 +1,88 @@
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use tokio::time::sleep;
+
+use crate::api::{batch_notifications, task};
+use crate::db::models::OutboxEntry;
+use crate::SharedContext;
+
+/// Unsent entries younger than this are left to the replica which stored the result.
+const GRACE_PERIOD: Duration = Duration::from_secs(30);
+
+/// Claims older than this are assumed lost with their replica.
+const CLAIM_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// Entries claimed per run.
+const BATCH_SIZE: i64 = 100;
+
+/// Days sent entries are kept for inspection.
+const SENT_RETENTION_DAYS: i64 = 7;
+
+///
+/// Delivers result notifications which weren't marked sent after storing the result, e.g. after
+/// a crash before the broadcast, and completion emails whose sending failed, every `interval`.
+/// Sent entries past their retention are removed hourly. Only the replica holding the
+/// `outbox_delivery` lease redelivers at a time; claims of entries remain exclusive when the lease
+/// lapses during a slow run.
+///
+pub async fn run_periodically(shared_context: SharedContext, interval: Duration) {
+    let mut last_cleanup: Option<Instant> = None;
+
+    loop {
+        sleep(interval).await;
+
+        let lease = shared_context
+            .locks
+            .claim("outbox_delivery", CLAIM_TIMEOUT)
+            .await;
+        if lease.is_held() {
+            continue;
+        }
+
+        let db_wrapper = shared_context.db_wrapper.clone();
+        match OutboxEntry::claim_pending(
+            db_wrapper.clone(),
+            GRACE_PERIOD,
+            CLAIM_TIMEOUT,
+            BATCH_SIZE,
+        )
+        .await
+        {
+            Ok(entries) => {
+                for entry in entries {
+                    log::info!(
+                        "Delivering unsent {} notification of task {} (attempt {}).",
+                        entry.kind,
+                        entry.task_key,
+                        entry.attempts
+                    );
+                    if task::deliver_outbox_entry(&shared_context, &entry).await {
+                        shared_context
+                            .metrics
+                            .increment("outbox_redeliveries_total");
+                    }
+                }
+            }
+            Err(error) => eprintln!("Failed to claim outbox entries. Error: {}", error),
+        }
+        batch_notifications::retry_failed(&shared_context).await;
+
+        let cleanup_due = !last_cleanup
+            .is_some_and(|last_cleanup| last_cleanup.elapsed() < Duration::from_secs(3600));
+        if cleanup_due {
+            last_cleanup = Some(Instant::now());
+
+            if let Err(error) = OutboxEntry::delete_sent_before(
+                db_wrapper,
+                Utc::now() - chrono::Duration::days(SENT_RETENTION_DAYS),
+            )
+            .await
+            {
+                eprintln!("Failed to remove sent outbox entries. Error: {}", error);
+            }
+        }
+
+        lease.release().await;
+    }
+}
@@ -0,0 +1,35 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::time::sleep;
+
+use crate::api::progress::ProgressTracker;
+use crate::db::models::BackgroundRemoverTask;
+use crate::db::DBWrapper;
+
+///
+/// Writes buffered task progress forever, waiting `interval` between runs. Each run is a single
+/// statement however many progress updates were received.
+///
+pub async fn run_periodically(
+    db_wrapper: Arc<DBWrapper>,
+    progress: Arc<ProgressTracker>,
+    interval: Duration,
+) {
+    loop {
+        sleep(interval).await;
+
+        let pending = progress.take_pending();
+        if pending.is_empty() {
+            continue;
+        }
+
+        let (keys, values): (Vec<_>, Vec<_>) = pending.into_iter().unzip();
+        if let Err(error) =
+            BackgroundRemoverTask::update_progress_batch(db_wrapper.clone(), &keys, &values).await
+        {
+            // Progress is informational only, so a failed batch is dropped.
+            eprintln!("Failed to write task progress. Error: {}", error);
+        }
+    }
+}
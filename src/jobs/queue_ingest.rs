@@ -0,0 +1,262 @@
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_nats::jetstream::{self, consumer::pull, AckKind, Message};
+use futures_util::StreamExt;
+use tokio::sync::Semaphore;
+use uuid::Uuid;
+
+use crate::api::ingestion::{self, IngestError, Submission, MAX_IMAGE_SIZE};
+use crate::utils::ingest_utils;
+use crate::SharedContext;
+
+/// Wait before consuming again after the connection or the consumer failed.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Submissions whose image or the database was unavailable are redelivered after this.
+const RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Time to ingest a submission before NATS redelivers it, covering the fetch and the dispatch.
+const ACK_WAIT: Duration = Duration::from_secs(5 * 60);
+
+///
+/// Downloads the images of submissions.
+///
+struct ImageFetcher {
+    client: reqwest::Client,
+    object_base_url: Option<String>,
+}
+
+impl ImageFetcher {
+    ///
+    /// Images are fetched with a timeout of `INGEST_FETCH_TIMEOUT_SECS`, default 60. Object keys
+    /// are fetched below `INGEST_OBJECT_BASE_URL`.
+    ///
+    fn from_env() -> Self {
+        let fetch_timeout = match env::var("INGEST_FETCH_TIMEOUT_SECS") {
+            Ok(value) => value.parse::<u64>().unwrap_or(60).max(1),
+            Err(_) => 60,
+        };
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(fetch_timeout))
+            .build()
+            .unwrap_or_default();
+
+        Self {
+            client,
+            object_base_url: env::var("INGEST_OBJECT_BASE_URL")
+                .ok()
+                .filter(|value| !value.is_empty()),
+        }
+    }
+
+    ///
+    /// URL of the image of `submission`, from its `image_url` or `object_key`.
+    ///
+    fn url(&self, submission: &Submission) -> Result<String, IngestError> {
+        match (&submission.image_url, &submission.object_key) {
+            (Some(url), None) => {
+                ingest_utils::check_image_url(url).map_err(IngestError::Invalid)?;
+                Ok(url.clone())
+            }
+            (None, Some(object_key)) => match &self.object_base_url {
+                Some(base_url) => {
+                    ingest_utils::object_url(base_url, object_key).map_err(IngestError::Invalid)
+                }
+                None => Err(IngestError::Invalid(
+                    "Object keys need INGEST_OBJECT_BASE_URL.".to_string(),
+                )),
+            },
+            _ => Err(IngestError::Invalid(
+                "Exactly one of image_url and object_key is required.".to_string(),
+            )),
+        }
+    }
+
+    ///
+    /// Downloads the image at `url`. Client errors are permanent, other failures are retried.
+    ///
+    async fn fetch(&self, url: &str) -> Result<Vec<u8>, IngestError> {
+        let mut response = self
+            .client
+            .get(url)
+            .send()
+            .await
+            .map_err(|error| IngestError::Unavailable(error.to_string()))?;
+
+        let status = response.status();
+        if status.is_client_error() {
+            return Err(IngestError::Invalid(format!(
+                "Fetching the image answered {}.",
+                status
+            )));
+        }
+        if !status.is_success() {
+            return Err(IngestError::Unavailable(format!(
+                "Fetching the image answered {}.",
+                status
+            )));
+        }
+
+        let too_large = || IngestError::Invalid("Image size is too large.".to_string());
+        if response
+            .content_length()
+            .is_some_and(|length| length > MAX_IMAGE_SIZE as u64)
+        {
+            return Err(too_large());
+        }
+
+        let mut data = vec![];
+        while let Some(chunk) = response
+            .chunk()
+            .await
+            .map_err(|error| IngestError::Unavailable(error.to_string()))?
+        {
+            if data.len() + chunk.len() > MAX_IMAGE_SIZE {
+                return Err(too_large());
+            }
+            data.extend_from_slice(&chunk);
+        }
+        Ok(data)
+    }
+}
+
+struct QueueConfig {
+    url: String,
+    stream: String,
+    consumer: String,
+    concurrency: usize,
+}
+
+impl QueueConfig {
+    ///
+    /// `None` unless `INGEST_NATS_URL` is set.
+    ///
+    fn from_env() -> Option<Self> {
+        let url = env::var("INGEST_NATS_URL")
+            .ok()
+            .filter(|url| !url.is_empty())?;
+        let concurrency = match env::var("INGEST_CONCURRENCY") {
+            Ok(value) => value.parse::<usize>().unwrap_or(4).max(1),
+            Err(_) => 4,
+        };
+
+        Some(Self {
+            url,
+            stream: env::var("INGEST_NATS_STREAM").unwrap_or("SUBMISSIONS".to_string()),
+            consumer: env::var("INGEST_NATS_CONSUMER").unwrap_or("bp-api-service".to_string()),
+            concurrency,
+        })
+    }
+}
+
+///
+/// Consumes task submissions from the JetStream stream `INGEST_NATS_STREAM` when
+/// `INGEST_NATS_URL` is set. Replicas share the durable consumer `INGEST_NATS_CONSUMER`, so every
+/// submission is ingested by one of them. Submissions are acknowledged once their task exists,
+/// terminated when they can never succeed and redelivered after `RETRY_DELAY` otherwise.
+///
+pub fn spawn(shared_context: SharedContext) {
+    let config = match QueueConfig::from_env() {
+        Some(config) => config,
+        None => return,
+    };
+
+    tokio::spawn(async move {
+        let fetcher = Arc::new(ImageFetcher::from_env());
+        let permits = Arc::new(Semaphore::new(config.concurrency));
+
+        loop {
+            if let Err(error) = consume(&shared_context, &config, &fetcher, &permits).await {
+                eprintln!("Failed to consume task submissions. Error: {}", error);
+            }
+            tokio::time::sleep(RECONNECT_DELAY).await;
+        }
+    });
+}
+
+async fn consume(
+    shared_context: &SharedContext,
+    config: &QueueConfig,
+    fetcher: &Arc<ImageFetcher>,
+    permits: &Arc<Semaphore>,
+) -> Result<(), async_nats::Error> {
+    let client = async_nats::connect(&config.url).await?;
+    let stream = jetstream::new(client).get_stream(&config.stream).await?;
+    let consumer = stream
+        .get_or_create_consumer(
+            &config.consumer,
+            pull::Config {
+                durable_name: Some(config.consumer.clone()),
+                ack_wait: ACK_WAIT,
+                ..Default::default()
+            },
+        )
+        .await?;
+
+    println!(
+        "Consuming task submissions from NATS stream {}.",
+        config.stream
+    );
+    let mut messages = consumer.messages().await?;
+    while let Some(message) = messages.next().await {
+        let message = message?;
+        let permit = permits.clone().acquire_owned().await?;
+
+        let shared_context = shared_context.clone();
+        let fetcher = fetcher.clone();
+        tokio::spawn(async move {
+            handle_message(&shared_context, &fetcher, message).await;
+            drop(permit);
+        });
+    }
+    Ok(())
+}
+
+async fn handle_message(shared_context: &SharedContext, fetcher: &ImageFetcher, message: Message) {
+    let metrics = &shared_context.metrics;
+    let result = match serde_json::from_slice::<Submission>(&message.payload) {
+        Ok(submission) => ingest(shared_context, fetcher, submission).await,
+        Err(error) => Err(IngestError::Invalid(error.to_string())),
+    };
+
+    let ack = match result {
+        Ok(key) => {
+            println!("Ingested task {} from NATS.", key);
+            metrics.increment("ingest_tasks_total");
+            AckKind::Ack
+        }
+        Err(error) if error.is_retryable() => {
+            eprintln!("Retrying task submission later. {}", error);
+            metrics.increment("ingest_retries_total");
+            AckKind::Nak(Some(RETRY_DELAY))
+        }
+        Err(error) => {
+            eprintln!("Dropping task submission. {}", error);
+            metrics.increment("ingest_rejections_total");
+            AckKind::Term
+        }
+    };
+
+    // Unacknowledged submissions are redelivered after `ACK_WAIT`.
+    if let Err(error) = message.ack_with(ack).await {
+        eprintln!("Failed to acknowledge task submission. Error: {}", error);
+    }
+}
+
+///
+/// Creates the task of `submission` with the image at its URL. The filename defaults to the last
+/// segment of the URL.
+///
+async fn ingest(
+    shared_context: &SharedContext,
+    fetcher: &ImageFetcher,
+    mut submission: Submission,
+) -> Result<Uuid, IngestError> {
+    let url = fetcher.url(&submission)?;
+    if submission.filename.is_none() {
+        submission.filename = ingest_utils::filename_from_url(&url);
+    }
+    ingestion::create_task(shared_context, submission, fetcher.fetch(&url)).await
+}
@@ -0,0 +1,457 @@
+use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use aws_config::BehaviorVersion;
+use aws_sdk_s3::error::DisplayErrorContext;
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_sqs::types::Message;
+use futures_util::future::join_all;
+use serde_json::{json, Value};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::broadcast::Receiver;
+use uuid::Uuid;
+
+use crate::api::ingestion::{self, IngestError, Submission, MAX_IMAGE_SIZE};
+use crate::api::lifecycle::{LifecycleEvent, LifecycleStage};
+use crate::db::models::{BackgroundRemoverTask, ManifestFile};
+use crate::utils::s3_utils::{self, ObjectCreated};
+use crate::utils::{path_utils, retry_utils};
+use crate::SharedContext;
+
+/// Wait before polling again after receiving from the queue failed.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Long polling wait of a receive, the maximum SQS allows.
+const RECEIVE_WAIT_SECS: i32 = 20;
+
+/// Messages received at once, the maximum SQS allows. They are ingested concurrently.
+const RECEIVE_BATCH_SIZE: i32 = 10;
+
+struct S3IngestConfig {
+    queue_url: String,
+    prefix: String,
+    result_prefix: String,
+    key_id: Option<String>,
+}
+
+impl S3IngestConfig {
+    ///
+    /// Reads `S3_INGEST_QUEUE_URL`, `S3_INGEST_PREFIX`, `S3_INGEST_RESULT_PREFIX` and
+    /// `S3_INGEST_KEY_ID`. `None` unless `S3_INGEST_QUEUE_URL` is set.
+    ///
+    fn from_env() -> Option<Self> {
+        let queue_url = env::var("S3_INGEST_QUEUE_URL")
+            .ok()
+            .filter(|url| !url.is_empty())?;
+
+        Some(Self {
+            queue_url,
+            prefix: env::var("S3_INGEST_PREFIX").unwrap_or("incoming/".to_string()),
+            result_prefix: env::var("S3_INGEST_RESULT_PREFIX").unwrap_or("processed/".to_string()),
+            key_id: env::var("S3_INGEST_KEY_ID")
+                .ok()
+                .filter(|key_id| !key_id.is_empty()),
+        })
+    }
+}
+
+struct S3Ingest {
+    config: S3IngestConfig,
+    sqs: aws_sdk_sqs::Client,
+    s3: aws_sdk_s3::Client,
+}
+
+///
+/// Creates tasks from objects created below `S3_INGEST_PREFIX` when `S3_INGEST_QUEUE_URL` is
+/// set, the SQS queue receiving the event notifications of the bucket. Replicas share the queue,
+/// so every object is ingested by one of them. Results are written to the same path below
+/// `S3_INGEST_RESULT_PREFIX` by the replica storing them. AWS credentials and region are read
+/// the usual way, e.g. from `AWS_REGION` and the instance role.
+///
+pub fn spawn(shared_context: SharedContext) {
+    let config = match S3IngestConfig::from_env() {
+        Some(config) => config,
+        None => return,
+    };
+    if config.result_prefix.starts_with(&config.prefix) {
+        // Results would be ingested again.
+        eprintln!("S3_INGEST_RESULT_PREFIX must not be below S3_INGEST_PREFIX.");
+        return;
+    }
+
+    // Subscribed before returning, so no result stored after startup is missed.
+    let events = shared_context.lifecycle.subscribe();
+    tokio::spawn(async move {
+        let sdk_config = aws_config::load_defaults(BehaviorVersion::latest()).await;
+        let ingest = Arc::new(S3Ingest {
+            config,
+            sqs: aws_sdk_sqs::Client::new(&sdk_config),
+            s3: aws_sdk_s3::Client::new(&sdk_config),
+        });
+        tokio::spawn(write_results(
+            shared_context.clone(),
+            ingest.clone(),
+            events,
+        ));
+
+        println!(
+            "Ingesting S3 objects below {} from {}.",
+            ingest.config.prefix, ingest.config.queue_url
+        );
+        loop {
+            if let Err(error) = receive(&shared_context, &ingest).await {
+                eprintln!("Failed to receive S3 notifications. Error: {}", error);
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            }
+        }
+    });
+}
+
+async fn receive(shared_context: &SharedContext, ingest: &S3Ingest) -> Result<(), String> {
+    let output = ingest
+        .sqs
+        .receive_message()
+        .queue_url(&ingest.config.queue_url)
+        .max_number_of_messages(RECEIVE_BATCH_SIZE)
+        .wait_time_seconds(RECEIVE_WAIT_SECS)
+        .send()
+        .await
+        .map_err(|error| DisplayErrorContext(error).to_string())?;
+
+    let messages = output.messages.unwrap_or_default();
+    join_all(
+        messages
+            .into_iter()
+            .map(|message| handle_message(shared_context, ingest, message)),
+    )
+    .await;
+    Ok(())
+}
+
+///
+/// Ingests the objects of a notification. The message is deleted unless ingesting an object
+/// should be retried; then it is received again once its visibility timeout expired, and its
+/// ingested objects find their tasks.
+///
+async fn handle_message(shared_context: &SharedContext, ingest: &S3Ingest, message: Message) {
+    let metrics = &shared_context.metrics;
+    let objects = match message.body().map(s3_utils::parse_notification) {
+        Some(Ok(objects)) => objects,
+        Some(Err(error)) => {
+            eprintln!("Dropping S3 notification. {}", error);
+            metrics.increment("s3_ingest_rejections_total");
+            vec![]
+        }
+        None => vec![],
+    };
+
+    let mut retry = false;
+    for object in &objects {
+        let relative = match s3_utils::relative_key(&object.key, &ingest.config.prefix) {
+            Some(relative) => relative,
+            None => continue,
+        };
+
+        match ingest_object(shared_context, ingest, object, relative).await {
+            Ok(key) => {
+                println!(
+                    "Ingested task {} from s3://{}/{}.",
+                    key, object.bucket, object.key
+                );
+                metrics.increment("s3_ingest_tasks_total");
+            }
+            Err(error) if error.is_retryable() => {
+                eprintln!("Retrying S3 object {} later. {}", object.key, error);
+                metrics.increment("s3_ingest_retries_total");
+                retry = true;
+            }
+            Err(error) => {
+                eprintln!("Dropping S3 object {}. {}", object.key, error);
+                metrics.increment("s3_ingest_rejections_total");
+                let failure = json!({ "error": error.to_string() });
+                ingest
+                    .write_failure(shared_context, &object.bucket, relative, &failure)
+                    .await;
+            }
+        }
+    }
+
+    if retry {
+        return;
+    }
+    if let Some(receipt_handle) = message.receipt_handle() {
+        let deleted = ingest
+            .sqs
+            .delete_message()
+            .queue_url(&ingest.config.queue_url)
+            .receipt_handle(receipt_handle)
+            .send()
+            .await;
+        if let Err(error) = deleted {
+            eprintln!(
+                "Failed to delete S3 notification. Error: {}",
+                DisplayErrorContext(error)
+            );
+        }
+    }
+}
+
+///
+/// Creates the task of `object`, stored at `relative` below the ingested prefix. Its key is
+/// derived from the event, so redelivered notifications create the task once.
+///
+async fn ingest_object(
+    shared_context: &SharedContext,
+    ingest: &S3Ingest,
+    object: &ObjectCreated,
+    relative: &str,
+) -> Result<Uuid, IngestError> {
+    let filename = relative.rsplit('/').next().map(str::to_string);
+    let submission = Submission {
+        key: Some(s3_utils::task_key(object)),
+        key_id: ingest.config.key_id.clone(),
+        filename,
+        metadata: Some(json!({ "s3_bucket": object.bucket, "s3_key": object.key })),
+        ..Default::default()
+    };
+
+    let image = ingest.get_object(&object.bucket, &object.key);
+    ingestion::create_task(shared_context, submission, image).await
+}
+
+///
+/// Writes the results of tasks created from objects once they are stored, and a failure note when
+/// they failed.
+///
+async fn write_results(
+    shared_context: SharedContext,
+    ingest: Arc<S3Ingest>,
+    mut events: Receiver<LifecycleEvent>,
+) {
+    loop {
+        match events.recv().await {
+            Ok(event)
+                if matches!(
+                    event.event,
+                    LifecycleStage::Completed | LifecycleStage::Failed
+                ) =>
+            {
+                let shared_context = shared_context.clone();
+                let ingest = ingest.clone();
+                tokio::spawn(async move { ingest.write_result(&shared_context, &event).await });
+            }
+            Ok(_) => {}
+            Err(RecvError::Lagged(skipped)) => {
+                eprintln!("S3 result writer skipped {} events.", skipped);
+            }
+            Err(RecvError::Closed) => break,
+        }
+    }
+}
+
+impl S3Ingest {
+    ///
+    /// Downloads an object. Missing and too large objects are permanent errors, other failures
+    /// are retried.
+    ///
+    async fn get_object(&self, bucket: &str, key: &str) -> Result<Vec<u8>, IngestError> {
+        let mut output = self
+            .s3
+            .get_object()
+            .bucket(bucket)
+            .key(key)
+            .send()
+            .await
+            .map_err(|error| match error.as_service_error() {
+                Some(service_error) if service_error.is_no_such_key() => {
+                    IngestError::Invalid("Object no longer exists.".to_string())
+                }
+                _ => IngestError::Unavailable(DisplayErrorContext(&error).to_string()),
+            })?;
+
+        let too_large = || IngestError::Invalid("Image size is too large.".to_string());
+        if output
+            .content_length()
+            .is_some_and(|length| length > MAX_IMAGE_SIZE as i64)
+        {
+            return Err(too_large());
+        }
+
+        let mut data = vec![];
+        while let Some(chunk) = output
+            .body
+            .try_next()
+            .await
+            .map_err(|error| IngestError::Unavailable(error.to_string()))?
+        {
+            if data.len() + chunk.len() > MAX_IMAGE_SIZE {
+                return Err(too_large());
+            }
+            data.extend_from_slice(&chunk);
+        }
+        Ok(data)
+    }
+
+    ///
+    /// Writes the result of the task of `event` if it was created from an object. Completed tasks
+    /// get their processed image and mask written, replacing an earlier failure note.
+    ///
+    async fn write_result(&self, shared_context: &SharedContext, event: &LifecycleEvent) {
+        let db_wrapper = shared_context.db_wrapper.clone();
+        let task = match BackgroundRemoverTask::fetch(db_wrapper, &event.task_key).await {
+            Ok(task) => task,
+            Err(error) => {
+                eprintln!("Failed to fetch task for S3 results. Error: {}", error);
+                return;
+            }
+        };
+
+        let source = task.metadata.as_ref().and_then(|metadata| {
+            Some((
+                metadata.get("s3_bucket")?.as_str()?,
+                metadata.get("s3_key")?.as_str()?,
+            ))
+        });
+        let (bucket, relative) = match source {
+            Some((bucket, key)) => match s3_utils::relative_key(key, &self.config.prefix) {
+                Some(relative) => (bucket, relative),
+                None => return,
+            },
+            None => return,
+        };
+
+        if event.event == LifecycleStage::Failed {
+            let failure = json!({
+                "task_key": task.key,
+                "status_code": event.status_code,
+                "timestamp": event.timestamp,
+            });
+            self.write_failure(shared_context, bucket, relative, &failure)
+                .await;
+            return;
+        }
+
+        let media_root = match env::var("MEDIA_ROOT") {
+            Ok(media_root) => PathBuf::from(media_root),
+            Err(_) => return,
+        };
+        for (role, suffix) in [(ManifestFile::PROCESSED, ""), (ManifestFile::MASK, ".mask")] {
+            let path = match task.output_path(role) {
+                Some(path) => PathBuf::from(path),
+                None => continue,
+            };
+            let extension = path.extension().and_then(|extension| extension.to_str());
+            let suffix = format!("{}.{}", suffix, extension.unwrap_or("png"));
+            let key = s3_utils::result_key(&self.config.result_prefix, relative, &suffix);
+
+            let full_path = path_utils::file_path_from_relative_url(media_root.clone(), path);
+            match self
+                .put_file(shared_context, bucket, &key, &full_path)
+                .await
+            {
+                Ok(()) => shared_context.metrics.increment("s3_results_written_total"),
+                Err(error) => {
+                    eprintln!("Failed to write S3 result {}. Error: {}", key, error);
+                    shared_context.metrics.increment("s3_results_failed_total");
+                    return;
+                }
+            }
+        }
+
+        let failure_key =
+            s3_utils::result_key(&self.config.result_prefix, relative, ".failed.json");
+        if let Err(error) = self
+            .s3
+            .delete_object()
+            .bucket(bucket)
+            .key(&failure_key)
+            .send()
+            .await
+        {
+            eprintln!(
+                "Failed to delete S3 failure note {}. Error: {}",
+                failure_key,
+                DisplayErrorContext(error)
+            );
+        }
+    }
+
+    ///
+    /// Writes `failure` as failure note of the object at `relative`.
+    ///
+    async fn write_failure(
+        &self,
+        shared_context: &SharedContext,
+        bucket: &str,
+        relative: &str,
+        failure: &Value,
+    ) {
+        let key = s3_utils::result_key(&self.config.result_prefix, relative, ".failed.json");
+        let body = failure.to_string().into_bytes();
+        if let Err(error) = self
+            .put(shared_context, bucket, &key, body, "application/json")
+            .await
+        {
+            eprintln!("Failed to write S3 failure note {}. Error: {}", key, error);
+            shared_context.metrics.increment("s3_results_failed_total");
+        }
+    }
+
+    async fn put_file(
+        &self,
+        shared_context: &SharedContext,
+        bucket: &str,
+        key: &str,
+        path: &Path,
+    ) -> Result<(), String> {
+        let data = tokio::fs::read(path)
+            .await
+            .map_err(|error| error.to_string())?;
+        let content_type = s3_utils::content_type(key);
+        self.put(shared_context, bucket, key, data, content_type)
+            .await
+    }
+
+    ///
+    /// Uploads `data`, retrying according to `AppConfig::webhook_retry`.
+    ///
+    async fn put(
+        &self,
+        shared_context: &SharedContext,
+        bucket: &str,
+        key: &str,
+        data: Vec<u8>,
+        content_type: &str,
+    ) -> Result<(), String> {
+        let policy = shared_context.config.load().webhook_retry;
+        let mut failed = vec![];
+        let result = retry_utils::retry(
+            &policy,
+            |_| true,
+            &mut failed,
+            |_| async {
+                self.s3
+                    .put_object()
+                    .bucket(bucket)
+                    .key(key)
+                    .content_type(content_type)
+                    .body(ByteStream::from(data.clone()))
+                    .send()
+                    .await
+                    .map(|_| ())
+                    .map_err(|error| DisplayErrorContext(error).to_string())
+            },
+        )
+        .await;
+
+        for attempt in &failed {
+            eprintln!(
+                "Retrying S3 upload of {} after attempt {} in {:?}. Error: {}",
+                key, attempt.attempt, attempt.delay, attempt.error
+            );
+        }
+        result
+    }
+}
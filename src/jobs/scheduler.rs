@@ -0,0 +1,269 @@
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::time::sleep;
+
+use crate::db::models::ScheduledJobRun;
+use crate::db::DBWrapper;
+use crate::locks::Locks;
+use crate::utils::schedule_utils::{self, Schedule};
+
+///
+/// Where a scheduled job runs.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobScope {
+    /// Once per run time across all replicas, under a Postgres advisory lock.
+    Cluster,
+    /// On every replica, e.g. for cleaning up local files.
+    Instance,
+}
+
+///
+/// State of a scheduled job on this replica.
+///
+#[derive(Debug, Clone, Serialize)]
+pub struct JobStatus {
+    pub name: &'static str,
+    /// `None` when disabled.
+    pub schedule: Option<String>,
+    pub scope: JobScope,
+    pub running: bool,
+    pub next_run: Option<DateTime<Utc>>,
+    pub last_started: Option<DateTime<Utc>>,
+    pub last_finished: Option<DateTime<Utc>>,
+    /// `succeeded`, `failed`, or `skipped` when another replica took the run.
+    pub last_status: Option<&'static str>,
+    pub last_message: Option<String>,
+    pub runs: u64,
+    pub failures: u64,
+}
+
+///
+/// Runs periodic jobs on their schedules and keeps their status for the admin API. Schedules
+/// default to the ones given on registration and are overridden by `JOB_SCHEDULES`.
+///
+pub struct Scheduler {
+    db_wrapper: Arc<DBWrapper>,
+    locks: Arc<Locks>,
+    overrides: HashMap<String, Option<Schedule>>,
+    jobs: Mutex<BTreeMap<&'static str, JobStatus>>,
+}
+
+impl Scheduler {
+    pub fn from_env(db_wrapper: Arc<DBWrapper>, locks: Arc<Locks>) -> std::io::Result<Self> {
+        let overrides = match env::var("JOB_SCHEDULES") {
+            Ok(value) => schedule_utils::parse_job_schedules(&value).map_err(|error| {
+                std::io::Error::other(format!("Invalid JOB_SCHEDULES. {}", error))
+            })?,
+            Err(_) => HashMap::new(),
+        };
+
+        Ok(Self {
+            db_wrapper,
+            locks,
+            overrides,
+            jobs: Mutex::new(BTreeMap::new()),
+        })
+    }
+
+    ///
+    /// Status of every registered job, by name.
+    ///
+    pub fn statuses(&self) -> Vec<JobStatus> {
+        match self.jobs.lock() {
+            Ok(jobs) => jobs.values().cloned().collect(),
+            Err(_) => vec![],
+        }
+    }
+
+    ///
+    /// Registers job `name` and runs it on its schedule until the process exits. `job` returns a
+    /// summary of the run, or why it failed. `default` of `None` leaves the job disabled unless
+    /// `JOB_SCHEDULES` schedules it.
+    ///
+    pub fn spawn<F, Fut>(
+        self: &Arc<Self>,
+        name: &'static str,
+        default: Option<Schedule>,
+        scope: JobScope,
+        job: F,
+    ) where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<String, String>> + Send + 'static,
+    {
+        let schedule = match self.overrides.get(name) {
+            Some(schedule) => schedule.clone(),
+            None => default,
+        };
+
+        let status = JobStatus {
+            name,
+            schedule: schedule.as_ref().map(|schedule| schedule.to_string()),
+            scope,
+            running: false,
+            next_run: None,
+            last_started: None,
+            last_finished: None,
+            last_status: None,
+            last_message: None,
+            runs: 0,
+            failures: 0,
+        };
+        if let Ok(mut jobs) = self.jobs.lock() {
+            jobs.insert(name, status);
+        }
+
+        if let Some(schedule) = schedule {
+            tokio::spawn(self.clone().run(name, schedule, scope, job));
+        }
+    }
+
+    fn update(&self, name: &'static str, change: impl FnOnce(&mut JobStatus)) {
+        if let Some(status) = self
+            .jobs
+            .lock()
+            .ok()
+            .as_mut()
+            .and_then(|jobs| jobs.get_mut(name))
+        {
+            change(status);
+        }
+    }
+
+    async fn run<F, Fut>(
+        self: Arc<Self>,
+        name: &'static str,
+        schedule: Schedule,
+        scope: JobScope,
+        job: F,
+    ) where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<String, String>> + Send + 'static,
+    {
+        loop {
+            let now = Utc::now();
+            let next_run = match schedule
+                .next_after(now.timestamp())
+                .and_then(|next| DateTime::from_timestamp(next, 0))
+            {
+                Some(next_run) => next_run,
+                None => {
+                    eprintln!("Schedule {} of job {} never matches.", schedule, name);
+                    return;
+                }
+            };
+            self.update(name, |status| {
+                status.next_run = Some(next_run);
+            });
+
+            let wait = (next_run - now).to_std().unwrap_or_default();
+            sleep(wait).await;
+
+            match scope {
+                JobScope::Instance => {
+                    self.execute(name, &job).await;
+                }
+                JobScope::Cluster => self.execute_once(name, &next_run, &job).await,
+            }
+        }
+    }
+
+    ///
+    /// Runs a cluster job for `scheduled_for` unless another replica runs it or already ran it.
+    /// The advisory lock keeps runs from overlapping when a run overruns the next run time; the
+    /// claim in `scheduled_job` keeps replicas from running the same run time one after another.
+    ///
+    async fn execute_once<F, Fut>(&self, name: &'static str, scheduled_for: &DateTime<Utc>, job: &F)
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<String, String>> + Send + 'static,
+    {
+        let lock = match self.locks.try_lock(&format!("job:{}", name)).await {
+            Ok(Some(lock)) => lock,
+            Ok(None) => {
+                self.skip(name, "Running on another replica.");
+                return;
+            }
+            Err(error) => {
+                self.skip(
+                    name,
+                    &format!("Failed to take the job lock. Error: {}", error),
+                );
+                return;
+            }
+        };
+
+        match ScheduledJobRun::claim(
+            self.db_wrapper.clone(),
+            name,
+            scheduled_for,
+            self.locks.holder(),
+        )
+        .await
+        {
+            Ok(true) => {
+                let (succeeded, message) = self.execute(name, job).await;
+                if let Err(error) =
+                    ScheduledJobRun::finish(self.db_wrapper.clone(), name, succeeded, &message)
+                        .await
+                {
+                    eprintln!("Failed to record run of job {}. Error: {}", name, error);
+                }
+            }
+            Ok(false) => self.skip(name, "Already run by another replica."),
+            Err(error) => self.skip(name, &format!("Failed to claim the run. Error: {}", error)),
+        }
+
+        lock.release().await;
+    }
+
+    ///
+    /// Runs `job` once and records the outcome. A panicking job counts as failed.
+    ///
+    async fn execute<F, Fut>(&self, name: &'static str, job: &F) -> (bool, String)
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<String, String>> + Send + 'static,
+    {
+        self.update(name, |status| {
+            status.running = true;
+            status.last_started = Some(Utc::now());
+        });
+
+        let (succeeded, message) = match tokio::spawn(job()).await {
+            Ok(Ok(summary)) => (true, summary),
+            Ok(Err(error)) => (false, error),
+            Err(error) => (false, format!("Job panicked. Error: {}", error)),
+        };
+        if succeeded {
+            println!("Job {} succeeded. {}", name, message);
+        } else {
+            eprintln!("Job {} failed. {}", name, message);
+        }
+
+        self.update(name, |status| {
+            status.running = false;
+            status.last_finished = Some(Utc::now());
+            status.last_status = Some(if succeeded { "succeeded" } else { "failed" });
+            status.last_message = Some(message.clone());
+            status.runs += 1;
+            if !succeeded {
+                status.failures += 1;
+            }
+        });
+        (succeeded, message)
+    }
+
+    fn skip(&self, name: &'static str, reason: &str) {
+        self.update(name, |status| {
+            status.last_status = Some("skipped");
+            status.last_message = Some(reason.to_string());
+        });
+    }
+}
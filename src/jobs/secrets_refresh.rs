@@ -0,0 +1,33 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::time::sleep;
+
+use crate::clients::bp_request_client::BPRequestClient;
+use crate::secrets::{self, SecretStore};
+
+///
+/// Re-reads secrets every `interval`. When the BP server auth token rotated, `clients` reconnect
+/// so the new token is used for their handshake.
+///
+pub async fn run_periodically(
+    secrets: Arc<SecretStore>,
+    clients: Vec<Arc<BPRequestClient>>,
+    interval: Duration,
+) {
+    loop {
+        sleep(interval).await;
+
+        let changed = secrets.refresh().await;
+        if changed.is_empty() {
+            continue;
+        }
+
+        log::info!("Rotated secrets: {}", changed.join(", "));
+        if changed.contains(&secrets::BP_SERVER_AUTH_TOKEN) {
+            for client in &clients {
+                client.reconnect();
+            }
+        }
+    }
+}
@@ -0,0 +1,350 @@
+use std::env;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::api::tls::TlsConfig;
+use crate::clients::bp_request_client::{BPRequestClient, Keepalive};
+use crate::config::{self, AppConfig, Overrides};
+use crate::db::models::AppSetting;
+use crate::db::{self, DBWrapper};
+use crate::secrets::{self, SecretStore};
+use crate::utils::token_utils::Keyring;
+use crate::utils::{billing_utils, routing_utils};
+
+/// Longest wait for a BP server to answer the keepalive sent after the handshake.
+const BP_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckStatus {
+    Ok,
+    /// Works, but probably not as intended.
+    Warning,
+    /// The service would fail to start or to serve requests.
+    Failed,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CheckResult {
+    pub name: String,
+    pub status: CheckStatus,
+    pub message: String,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct SelfCheckReport {
+    pub checks: Vec<CheckResult>,
+}
+
+impl SelfCheckReport {
+    fn push<N: Into<String>, M: Into<String>>(&mut self, name: N, status: CheckStatus, message: M) {
+        self.checks.push(CheckResult {
+            name: name.into(),
+            status,
+            message: message.into(),
+        });
+    }
+
+    pub fn failed(&self) -> usize {
+        self.checks
+            .iter()
+            .filter(|check| check.status == CheckStatus::Failed)
+            .count()
+    }
+}
+
+///
+/// Verifies the deployment before it takes traffic: the database schema after migrations,
+/// `MEDIA_ROOT` writability, the handshake with every configured BP server, signing keys and
+/// the consistency of settings, including overrides in `app_config`. Nothing is started and
+/// nothing but a probe file in `MEDIA_ROOT` is written.
+///
+pub async fn run() -> SelfCheckReport {
+    let mut report = SelfCheckReport::default();
+    let secrets = SecretStore::load().await;
+
+    for name in ["BIND_ADDRESS", "BP_SERVER_HOST", "MEDIA_ROOT"] {
+        if !env::var(name).is_ok_and(|value| !value.is_empty()) {
+            report.push(
+                "environment",
+                CheckStatus::Failed,
+                format!("{} is missing.", name),
+            );
+        }
+    }
+    for name in [secrets::POSTGRES_URL, secrets::BP_SERVER_AUTH_TOKEN] {
+        if secrets.get(name).is_none() {
+            report.push(
+                "secrets",
+                CheckStatus::Failed,
+                format!("{} is missing.", name),
+            );
+        }
+    }
+    if secrets.get(secrets::ADMIN_AUTH_TOKEN).is_none() {
+        report.push(
+            "secrets",
+            CheckStatus::Warning,
+            "ADMIN_AUTH_TOKEN is missing, so admin endpoints are disabled.",
+        );
+    }
+
+    let overrides = check_database(&mut report, &secrets).await;
+    check_media_root(&mut report).await;
+    check_signing_keys(&mut report, &secrets, &overrides);
+    check_settings(&mut report, &overrides);
+    check_bp_servers(&mut report, &secrets, &overrides).await;
+    report
+}
+
+///
+/// Runs the migrations and checks the resulting schema. Returns the overrides stored in
+/// `app_config`, which the remaining checks apply.
+///
+async fn check_database(report: &mut SelfCheckReport, secrets: &SecretStore) -> Overrides {
+    let db_wrapper: Arc<DBWrapper> = match db::setup(secrets).await {
+        Ok(db_wrapper) => Arc::new(db_wrapper),
+        Err(error) => {
+            report.push(
+                "database",
+                CheckStatus::Failed,
+                format!("Connecting or migrating failed. Error: {}", error),
+            );
+            return Overrides::new();
+        }
+    };
+
+    match db::schema_problems(&db_wrapper).await {
+        Ok(problems) if problems.is_empty() => {
+            report.push("database", CheckStatus::Ok, "Schema is up to date.")
+        }
+        Ok(problems) => {
+            for problem in problems {
+                report.push("database", CheckStatus::Failed, problem);
+            }
+        }
+        Err(error) => report.push(
+            "database",
+            CheckStatus::Failed,
+            format!("Failed to inspect the schema. Error: {}", error),
+        ),
+    }
+
+    match AppSetting::fetch_all(db_wrapper).await {
+        Ok(overrides) => overrides,
+        Err(error) => {
+            report.push(
+                "settings",
+                CheckStatus::Failed,
+                format!("Failed to read app_config. Error: {}", error),
+            );
+            Overrides::new()
+        }
+    }
+}
+
+async fn check_media_root(report: &mut SelfCheckReport) {
+    let media_root = match env::var("MEDIA_ROOT") {
+        Ok(path) if !path.is_empty() => PathBuf::from(path),
+        _ => return,
+    };
+
+    if !media_root.is_dir() {
+        report.push(
+            "media_root",
+            CheckStatus::Failed,
+            format!("{:?} is not a directory.", media_root),
+        );
+        return;
+    }
+
+    let probe = media_root.join(format!(".self-check-{}", Uuid::new_v4()));
+    match tokio::fs::write(&probe, b"self-check").await {
+        Ok(()) => {
+            let _ = tokio::fs::remove_file(&probe).await;
+            report.push(
+                "media_root",
+                CheckStatus::Ok,
+                format!("{:?} is writable.", media_root),
+            );
+        }
+        Err(error) => report.push(
+            "media_root",
+            CheckStatus::Failed,
+            format!("{:?} is not writable. Error: {}", media_root, error),
+        ),
+    }
+}
+
+fn check_signing_keys(report: &mut SelfCheckReport, secrets: &SecretStore, overrides: &Overrides) {
+    // Invalid keyrings are ignored at runtime, so they are parsed here to report why.
+    match secrets.get(secrets::SIGNING_KEYS) {
+        Some(value) => match Keyring::parse(&value) {
+            Ok(_) => report.push("signing_keys", CheckStatus::Ok, "SIGNING_KEYS is valid."),
+            Err(error) => report.push(
+                "signing_keys",
+                CheckStatus::Failed,
+                format!("SIGNING_KEYS is invalid. {}", error),
+            ),
+        },
+        None if secrets.keyring().is_some() => report.push(
+            "signing_keys",
+            CheckStatus::Ok,
+            "Signing with TASK_TOKEN_SECRET.",
+        ),
+        None => report.push(
+            "signing_keys",
+            CheckStatus::Warning,
+            "SIGNING_KEYS and TASK_TOKEN_SECRET are missing, so task tokens, download links and \
+             user identifiers are not signed.",
+        ),
+    }
+
+    let required = AppConfig::load(overrides).upload_signatures_required;
+    match secrets.get(secrets::UPLOAD_SIGNING_KEYS) {
+        Some(value) => match Keyring::parse(&value) {
+            Ok(_) => report.push(
+                "upload_signing_keys",
+                CheckStatus::Ok,
+                "UPLOAD_SIGNING_KEYS is valid.",
+            ),
+            Err(error) => report.push(
+                "upload_signing_keys",
+                CheckStatus::Failed,
+                format!("UPLOAD_SIGNING_KEYS is invalid. {}", error),
+            ),
+        },
+        None if required => report.push(
+            "upload_signing_keys",
+            CheckStatus::Failed,
+            "UPLOAD_SIGNATURES_REQUIRED is true, but UPLOAD_SIGNING_KEYS is missing, so every \
+             upload is rejected.",
+        ),
+        None => {}
+    }
+}
+
+///
+/// Settings which are silently ignored or fall back to defaults at runtime when invalid.
+///
+fn check_settings(report: &mut SelfCheckReport, overrides: &Overrides) {
+    let before = report.checks.len();
+    let config = AppConfig::load(overrides);
+
+    if let Some(value) = config::setting(overrides, "BILLING_PLANS") {
+        if let Err(error) = billing_utils::parse_plans(&value) {
+            report.push(
+                "settings",
+                CheckStatus::Failed,
+                format!("BILLING_PLANS is invalid. {}", error),
+            );
+        }
+    }
+    if let Ok(value) = env::var("DEDICATED_BP_SERVERS") {
+        if let Err(error) = routing_utils::parse_dedicated_routes(&value) {
+            report.push(
+                "settings",
+                CheckStatus::Failed,
+                format!("DEDICATED_BP_SERVERS is invalid. {}", error),
+            );
+        }
+    }
+    if let Some((path, _)) = config.watermark() {
+        if !PathBuf::from(path).is_file() {
+            report.push(
+                "settings",
+                CheckStatus::Failed,
+                format!("WATERMARK_PATH {:?} does not exist.", path),
+            );
+        }
+    }
+    if config.bp_liveness_timeout <= config.bp_keepalive_interval {
+        report.push(
+            "settings",
+            CheckStatus::Warning,
+            "BP_LIVENESS_TIMEOUT_SECS is not above BP_KEEPALIVE_INTERVAL_SECS, so idle BP \
+             connections are dropped.",
+        );
+    }
+    if let Some(tls_config) = TlsConfig::from_env() {
+        if let Err(error) = tls_config.validate() {
+            report.push(
+                "settings",
+                CheckStatus::Failed,
+                format!("TLS certificate or key is invalid. {}", error),
+            );
+        }
+    }
+
+    if report.checks.len() == before {
+        report.push("settings", CheckStatus::Ok, "Settings are consistent.");
+    }
+}
+
+///
+/// Handshakes with the public, standby, canary and dedicated BP servers on separate connections.
+///
+async fn check_bp_servers(
+    report: &mut SelfCheckReport,
+    secrets: &Arc<SecretStore>,
+    overrides: &Overrides,
+) {
+    let mut hosts: Vec<(String, String)> = vec![];
+    if let Ok(host) = env::var("BP_SERVER_HOST") {
+        hosts.push(("bp_server".to_string(), host));
+    }
+    if let Ok(value) = env::var("BP_SERVER_BACKUP_HOSTS") {
+        for host in value
+            .split(',')
+            .map(str::trim)
+            .filter(|host| !host.is_empty())
+        {
+            hosts.push(("backup_bp_server".to_string(), host.to_string()));
+        }
+    }
+    if let Ok(host) = env::var("CANARY_BP_SERVER_HOST") {
+        hosts.push(("canary_bp_server".to_string(), host));
+    }
+    if let Ok(Ok(routes)) =
+        env::var("DEDICATED_BP_SERVERS").map(|value| routing_utils::parse_dedicated_routes(&value))
+    {
+        for route in routes {
+            hosts.push(("dedicated_bp_server".to_string(), route.host));
+        }
+    }
+
+    let config = AppConfig::load(overrides);
+    for (name, host) in hosts {
+        let client = BPRequestClient::new(
+            &host,
+            8096,
+            Duration::from_secs(3),
+            Keepalive {
+                interval: config.bp_keepalive_interval,
+                liveness_timeout: config.bp_liveness_timeout,
+            },
+            config.bp_drain_timeout,
+            secrets.clone(),
+        );
+
+        match client.check_handshake(BP_HANDSHAKE_TIMEOUT).await {
+            Ok(identity) => report.push(
+                name,
+                CheckStatus::Ok,
+                format!(
+                    "Handshake with {} succeeded. Worker: {:?}, model: {:?}.",
+                    host, identity.worker_id, identity.model_version
+                ),
+            ),
+            Err(error) => report.push(
+                name,
+                CheckStatus::Failed,
+                format!("Handshake with {} failed. Error: {}", host, error),
+            ),
+        }
+    }
+}
@@ -0,0 +1,34 @@
+use std::sync::Arc;
+
+use chrono::Utc;
+
+use crate::db::models::DailyTaskStats;
+use crate::db::DBWrapper;
+
+/// Days refreshed by every run, including today. Rollups of earlier days are final.
+pub const REFRESHED_DAYS: u32 = 2;
+
+///
+/// Recomputes `daily_task_stats` for the last `days` days, including today.
+///
+pub async fn refresh_recent(db_wrapper: Arc<DBWrapper>, days: u32) -> Result<(), sqlx::Error> {
+    let since = Utc::now().date_naive() - chrono::Duration::days(days.saturating_sub(1) as i64);
+    DailyTaskStats::refresh(db_wrapper, since).await
+}
+
+///
+/// Refreshes rollups of the last `REFRESHED_DAYS` days. Two days are refreshed so tasks
+/// completing shortly after midnight still update yesterday's counts.
+///
+pub async fn run_once(db_wrapper: Arc<DBWrapper>) -> Result<String, String> {
+    match refresh_recent(db_wrapper, REFRESHED_DAYS).await {
+        Ok(()) => Ok(format!(
+            "Refreshed rollups of the last {} days.",
+            REFRESHED_DAYS
+        )),
+        Err(error) => Err(format!(
+            "Failed to refresh task stats rollups. Error: {}",
+            error
+        )),
+    }
+}
@@ -0,0 +1,69 @@
+use std::sync::Arc;
+
+use arc_swap::ArcSwap;
+use chrono::Utc;
+
+use crate::config::AppConfig;
+use crate::db::models::{BackgroundRemoverTask, TaskGroupNotification};
+use crate::db::DBWrapper;
+use crate::jobs::stats_rollup;
+use crate::utils::anonymize_utils;
+
+/// Number of tasks anonymized per table and statement. Keeps each transaction short.
+const ANONYMIZE_BATCH_SIZE: i64 = 1000;
+
+///
+/// Clears country and user identifiers of all tasks older than `anonymize_after_days`, archived
+/// or not, and deletes the notify emails of their task groups.
+///
+/// Returns number of anonymized tasks and of deleted notify emails.
+///
+pub async fn anonymize_old_tasks(
+    db_wrapper: Arc<DBWrapper>,
+    anonymize_after_days: i64,
+) -> Result<(u64, u64), sqlx::Error> {
+    let before = anonymize_utils::anonymize_before(
+        Utc::now(),
+        anonymize_after_days,
+        stats_rollup::REFRESHED_DAYS,
+    );
+    let mut total = 0;
+
+    loop {
+        let anonymized = BackgroundRemoverTask::anonymize_older_than(
+            db_wrapper.clone(),
+            &before,
+            ANONYMIZE_BATCH_SIZE,
+        )
+        .await?;
+
+        total += anonymized;
+        if anonymized == 0 {
+            break;
+        }
+    }
+
+    let notifications = TaskGroupNotification::delete_older_than(db_wrapper, &before).await?;
+    Ok((total, notifications))
+}
+
+///
+/// Anonymizes old tasks once. The retention window is read from `config` on every run; nothing
+/// is anonymized while it is not set.
+///
+pub async fn run_once(
+    db_wrapper: Arc<DBWrapper>,
+    config: Arc<ArcSwap<AppConfig>>,
+) -> Result<String, String> {
+    let Some(anonymize_after_days) = config.load().anonymize_after_days else {
+        return Ok("ANONYMIZE_AFTER_DAYS is not set.".to_string());
+    };
+
+    match anonymize_old_tasks(db_wrapper, anonymize_after_days).await {
+        Ok((anonymized, notifications)) => Ok(format!(
+            "Anonymized {} tasks and deleted {} notify emails.",
+            anonymized, notifications
+        )),
+        Err(error) => Err(format!("Failed to anonymize tasks. Error: {}", error)),
+    }
+}
@@ -0,0 +1,58 @@
+use std::sync::Arc;
+
+use arc_swap::ArcSwap;
+use chrono::Utc;
+
+use crate::config::AppConfig;
+use crate::db::models::BackgroundRemoverTask;
+use crate::db::DBWrapper;
+
+/// Number of tasks moved per statement. Keeps each transaction short.
+const ARCHIVE_BATCH_SIZE: i64 = 1000;
+
+///
+/// Moves all tasks older than `archive_after_days` to the archive table.
+///
+/// Returns number of moved tasks.
+///
+pub async fn archive_old_tasks(
+    db_wrapper: Arc<DBWrapper>,
+    archive_after_days: i64,
+) -> Result<u64, sqlx::Error> {
+    let before = Utc::now() - chrono::Duration::days(archive_after_days);
+    let mut total = 0;
+
+    loop {
+        let moved = BackgroundRemoverTask::archive_older_than(
+            db_wrapper.clone(),
+            &before,
+            ARCHIVE_BATCH_SIZE,
+        )
+        .await?;
+
+        total += moved;
+        if moved < ARCHIVE_BATCH_SIZE as u64 {
+            break;
+        }
+    }
+
+    Ok(total)
+}
+
+///
+/// Archives old tasks once. The archive age is read from `config` on every run; nothing is
+/// archived while it is not set.
+///
+pub async fn run_once(
+    db_wrapper: Arc<DBWrapper>,
+    config: Arc<ArcSwap<AppConfig>>,
+) -> Result<String, String> {
+    let Some(archive_after_days) = config.load().task_archive_after_days else {
+        return Ok("TASK_ARCHIVE_AFTER_DAYS is not set.".to_string());
+    };
+
+    match archive_old_tasks(db_wrapper, archive_after_days).await {
+        Ok(moved) => Ok(format!("Archived {} tasks.", moved)),
+        Err(error) => Err(format!("Failed to archive tasks. Error: {}", error)),
+    }
+}
@@ -0,0 +1,67 @@
+use std::env;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use arc_swap::ArcSwap;
+
+use crate::config::AppConfig;
+use crate::utils::temp_utils;
+use crate::utils::throttle_utils::{DeleteThrottle, Pacer};
+
+///
+/// Removes upload temp files in the temporary directory last modified more than `max_age` ago,
+/// e.g. left behind by aborted multipart uploads or a crash. Files being written are modified
+/// continuously, so uploads in progress are kept. Other files are never touched. Removals are
+/// paced by `throttle`.
+///
+/// Returns number of removed files.
+///
+pub async fn remove_stale_temp_files(
+    max_age: Duration,
+    throttle: DeleteThrottle,
+) -> std::io::Result<u64> {
+    let mut removed = 0;
+    let mut pacer = Pacer::new(throttle);
+    let mut entries = tokio::fs::read_dir(env::temp_dir()).await?;
+
+    while let Some(entry) = entries.next_entry().await? {
+        if !temp_utils::is_upload_temp_file(&entry.file_name().to_string_lossy()) {
+            continue;
+        }
+
+        let metadata = match entry.metadata().await {
+            Ok(metadata) if metadata.is_file() => metadata,
+            _ => continue,
+        };
+        let age = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+            .unwrap_or_default();
+        if age < max_age {
+            continue;
+        }
+
+        match pacer.remove_file(&entry.path(), metadata.len()).await {
+            Ok(()) => removed += 1,
+            Err(error) => eprintln!("Failed to remove {:?}. Error: {}", entry.path(), error),
+        }
+    }
+
+    Ok(removed)
+}
+
+///
+/// Removes stale upload temp files once. The maximum age and the throttle are read from `config`
+/// on every run.
+///
+pub async fn run_once(config: Arc<ArcSwap<AppConfig>>) -> Result<String, String> {
+    let config = config.load_full();
+    match remove_stale_temp_files(config.temp_file_max_age, config.temp_janitor_throttle).await {
+        Ok(removed) => Ok(format!("Removed {} stale upload temp files.", removed)),
+        Err(error) => Err(format!(
+            "Failed to remove stale temp files. Error: {}",
+            error
+        )),
+    }
+}
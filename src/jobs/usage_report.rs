@@ -0,0 +1,54 @@
+use std::time::Duration;
+
+use crate::clients::stripe_client::StripeClient;
+use crate::db::models::UsageReport;
+use crate::SharedContext;
+
+/// Claims older than this are assumed lost with their replica.
+const CLAIM_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// Usage rows moved into reports, and reports sent, per run.
+const BATCH_SIZE: i64 = 1000;
+
+///
+/// Reports successful tasks of keys with a metered Stripe subscription. Usage is first moved into
+/// reports in the database, then each report is sent until Stripe accepts it. Nothing is
+/// reported while `STRIPE_SECRET_KEY` is missing; usage is kept for later runs.
+///
+pub async fn run_once(shared_context: SharedContext) -> Result<String, String> {
+    let stripe = StripeClient::new(shared_context.secrets.clone());
+    if !stripe.is_configured() {
+        return Ok("STRIPE_SECRET_KEY is missing.".to_string());
+    }
+
+    let db_wrapper = shared_context.db_wrapper.clone();
+    if let Err(error) = UsageReport::create_pending(db_wrapper.clone(), BATCH_SIZE).await {
+        eprintln!("Failed to create usage reports. Error: {}", error);
+    }
+
+    let reports = UsageReport::claim_unsent(db_wrapper.clone(), CLAIM_TIMEOUT, BATCH_SIZE)
+        .await
+        .map_err(|error| format!("Failed to claim usage reports. Error: {}", error))?;
+
+    let (mut sent, mut failed) = (0, 0);
+    for report in reports {
+        if let Err(error) = stripe.report_usage(&report).await {
+            eprintln!(
+                "Failed to send usage report {} of {} (attempt {}). Error: {}",
+                report.id, report.key_id, report.attempts, error
+            );
+            failed += 1;
+            continue;
+        }
+
+        if let Err(error) = UsageReport::mark_sent(db_wrapper.clone(), report.id).await {
+            eprintln!("Failed to mark usage report sent. Error: {}", error);
+        }
+        shared_context
+            .metrics
+            .add("billing_usage_reported_total", report.quantity as u64);
+        sent += 1;
+    }
+
+    Ok(format!("Sent {} usage reports, {} failed.", sent, failed))
+}
@@ -0,0 +1,23 @@
+use std::sync::Arc;
+
+use tokio::time::sleep;
+
+use crate::api::ws_clients::WsClients;
+use crate::metrics::Metrics;
+
+///
+/// Removes stale websocket connections forever, checking twice per stale window.
+///
+pub async fn run_periodically(ws_clients: Arc<WsClients>, metrics: Arc<Metrics>) {
+    let interval = ws_clients.config().stale_after / 2;
+
+    loop {
+        sleep(interval).await;
+
+        let removed = ws_clients.remove_stale().await;
+        if removed > 0 {
+            println!("Dropped {} stale websocket connections.", removed);
+            metrics.add("ws_stale_connections_dropped_total", removed as u64);
+        }
+    }
+}
@@ -0,0 +1,95 @@
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::db::models::{AdvisoryLock, Lease};
+use crate::db::DBWrapper;
+use crate::utils::lock_utils::lock_key;
+
+///
+/// Coordinates work between replicas through Postgres so background work runs once, however
+/// many replicas are running.
+///
+/// - Locks are session level advisory locks. They hold a pooled connection until released and
+///   vanish with the connection, so they suit short exclusive sections such as a job run.
+/// - Leases are rows in `lease` which expire after their ttl unless renewed. They hold no
+///   connection, so they suit work which outlives a request, such as a task waiting for its BP
+///   response.
+///
+pub struct Locks {
+    db_wrapper: Arc<DBWrapper>,
+    /// Identifies this replica in `lease` and `scheduled_job`: `SID`, or the process id.
+    holder: String,
+}
+
+impl Locks {
+    pub fn new(db_wrapper: Arc<DBWrapper>) -> Self {
+        let holder = match env::var("SID") {
+            Ok(sid) if !sid.is_empty() => sid,
+            _ => format!("pid-{}", std::process::id()),
+        };
+
+        Self { db_wrapper, holder }
+    }
+
+    pub fn holder(&self) -> &str {
+        &self.holder
+    }
+
+    ///
+    /// Takes advisory lock `name` without waiting. `None` if another replica holds it.
+    ///
+    pub async fn try_lock(&self, name: &str) -> Result<Option<AdvisoryLock>, sqlx::Error> {
+        AdvisoryLock::try_acquire(self.db_wrapper.clone(), lock_key(name)).await
+    }
+
+    ///
+    /// Takes lease `name` for `ttl` without waiting. `None` if another holder's lease on it has
+    /// not expired yet.
+    ///
+    pub async fn try_lease(&self, name: &str, ttl: Duration) -> Result<Option<Lease>, sqlx::Error> {
+        Lease::try_acquire(self.db_wrapper.clone(), name, &self.holder, ttl).await
+    }
+
+    ///
+    /// Like `try_lease`, but a failing database yields `LeaseClaim::Unguarded` so work is
+    /// duplicated rather than lost.
+    ///
+    pub async fn claim(&self, name: &str, ttl: Duration) -> LeaseClaim {
+        match self.try_lease(name, ttl).await {
+            Ok(Some(lease)) => LeaseClaim::Acquired(lease),
+            Ok(None) => LeaseClaim::Held,
+            Err(error) => {
+                eprintln!(
+                    "Failed to take lease {}. Proceeding. Error: {}",
+                    name, error
+                );
+                LeaseClaim::Unguarded
+            }
+        }
+    }
+}
+
+///
+/// Outcome of `Locks::claim`.
+///
+pub enum LeaseClaim {
+    /// Taken. Released once the work is done, or left to expire.
+    Acquired(Lease),
+    /// The database failed, so the work proceeds without a lease.
+    Unguarded,
+    /// Another holder's lease has not expired yet.
+    Held,
+}
+
+impl LeaseClaim {
+    pub fn is_held(&self) -> bool {
+        matches!(self, LeaseClaim::Held)
+    }
+
+    pub async fn release(self) {
+        if let LeaseClaim::Acquired(lease) = self {
+            lease.release().await;
+        }
+    }
+}
@@ -2,29 +2,89 @@ use std::env;
 use std::sync::Arc;
 use std::time::Duration;
 
+use api::bp_failover::PublicBpServers;
+use api::bp_routing::DedicatedBpServers;
+use api::canary::{self, Canary};
+use api::contact_sheets::ContactSheets;
+use api::image_workers::ImageWorkers;
+use api::key_lookup_guard::KeyLookupGuard;
+use api::lifecycle::TaskLifecycle;
+use api::processing_times::ProcessingTimes;
+use api::progress::ProgressTracker;
 use api::task;
-use api::ws_clients::WsClients;
+use api::task_events::TaskEventStore;
+use api::task_json_cache::TaskJsonCache;
+use api::upload_signatures::NonceCache;
+use api::ws_clients::{WsClients, WsClientsConfig};
+use arc_swap::ArcSwap;
 
-use clients::bp_request_client::BPRequestClient;
+use clients::bp_request_client::{BPRequestClient, Keepalive};
+use clients::mailer::Mailer;
+use clients::ops_notifier::OpsNotifier;
+use config::AppConfig;
 use db::DBWrapper;
 use env_logger::Env;
+use jobs::scheduler::{JobScope, Scheduler};
+use locks::Locks;
+use metrics::Metrics;
+use secrets::SecretStore;
+use utils::routing_utils;
+use utils::schedule_utils::Schedule;
 
 mod api;
+mod build_info;
 mod clients;
+mod config;
 mod db;
+mod jobs;
+mod locks;
+mod metrics;
+mod secrets;
 mod utils;
 
 #[derive(Clone)]
 pub struct SharedContext {
-    bp_request_client: Arc<BPRequestClient>,
+    config: Arc<ArcSwap<AppConfig>>,
+    public_bp_servers: Arc<PublicBpServers>,
+    dedicated_bp_servers: Arc<DedicatedBpServers>,
     db_wrapper: Arc<DBWrapper>,
     ws_clients: Arc<WsClients>,
+    metrics: Arc<Metrics>,
+    processing_times: Arc<ProcessingTimes>,
+    canary: Option<Arc<Canary>>,
+    image_workers: Arc<ImageWorkers>,
+    task_json_cache: Arc<TaskJsonCache>,
+    progress: Arc<ProgressTracker>,
+    contact_sheets: Arc<ContactSheets>,
+    mailer: Option<Arc<Mailer>>,
+    ops_notifier: Option<Arc<OpsNotifier>>,
+    key_lookup_guard: Arc<KeyLookupGuard>,
+    secrets: Arc<SecretStore>,
+    nonce_cache: Arc<NonceCache>,
+    locks: Arc<Locks>,
+    scheduler: Arc<Scheduler>,
+    lifecycle: Arc<TaskLifecycle>,
 }
 
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
     env_logger::Builder::from_env(Env::default().default_filter_or("debug")).init();
     dotenv::dotenv().ok();
+    log::info!("bp-api-service {}", build_info::version());
+
+    // Selects the GPU for image processing when `IMAGE_BACKEND` is `gpu`.
+    #[cfg(feature = "gpu")]
+    utils::gpu_utils::init();
+    #[cfg(not(feature = "gpu"))]
+    if env::var("IMAGE_BACKEND").is_ok_and(|value| value.trim() == "gpu") {
+        log::warn!("IMAGE_BACKEND is gpu, but the gpu feature is disabled. Using the CPU.");
+    }
+
+    // Maintenance commands run and exit without starting the server.
+    let args: Vec<String> = env::args().skip(1).collect();
+    if !args.is_empty() {
+        return jobs::run_command(&args).await;
+    }
 
     let bp_server_host = match env::var("BP_SERVER_HOST") {
         Ok(value) => value,
@@ -36,47 +96,423 @@ async fn main() -> std::io::Result<()> {
         }
     };
 
-    let db_wrapper = Arc::new(db::setup().await?);
-    let ws_clients = Arc::new(WsClients::new());
-    let bp_request_client = Arc::new(BPRequestClient::new(
-        bp_server_host,
-        8096,
-        Duration::from_secs(3),
+    let config = AppConfig::from_env();
+    let secrets = SecretStore::load().await;
+    let db_wrapper = Arc::new(db::setup(&secrets).await?);
+    let locks = Arc::new(Locks::new(db_wrapper.clone()));
+    let scheduler = Arc::new(Scheduler::from_env(db_wrapper.clone(), locks.clone())?);
+    let ws_clients = Arc::new(WsClients::new(
+        WsClientsConfig::from_env(),
+        TaskEventStore::from_env().await,
+    ));
+    let metrics = Arc::new(Metrics::new());
+    let processing_times = Arc::new(ProcessingTimes::new(config.estimated_time_per_task));
+
+    // Warm standbys of the public BP server, connected from the start and used in order when
+    // earlier ones are down, draining or unhealthy. See `bp_failover`.
+    let mut public_hosts = vec![bp_server_host];
+    if let Ok(value) = env::var("BP_SERVER_BACKUP_HOSTS") {
+        public_hosts.extend(
+            value
+                .split(',')
+                .map(str::trim)
+                .filter(|host| !host.is_empty())
+                .map(str::to_string),
+        );
+    }
+    let public_bp_servers = Arc::new(PublicBpServers::new(
+        public_hosts
+            .into_iter()
+            .map(|host| {
+                let client = Arc::new(BPRequestClient::new(
+                    &host,
+                    8096,
+                    Duration::from_secs(3),
+                    Keepalive {
+                        interval: config.bp_keepalive_interval,
+                        liveness_timeout: config.bp_liveness_timeout,
+                    },
+                    config.bp_drain_timeout,
+                    secrets.clone(),
+                ));
+                (host, client)
+            })
+            .collect(),
     ));
 
+    // Optional second BP connection running a candidate model. Connected whenever a host is
+    // configured so `CANARY_PERCENT` can be raised without restart.
+    let canary = match env::var("CANARY_BP_SERVER_HOST") {
+        Ok(host) => {
+            let client = Arc::new(BPRequestClient::new(
+                host,
+                8096,
+                Duration::from_secs(3),
+                Keepalive {
+                    interval: config.bp_keepalive_interval,
+                    liveness_timeout: config.bp_liveness_timeout,
+                },
+                config.bp_drain_timeout,
+                secrets.clone(),
+            ));
+            Some(Arc::new(Canary::new(client, config.estimated_time_per_task)))
+        }
+        Err(_) => None,
+    };
+
+    // BP connections dedicated to the keys of enterprise tenants. See `bp_routing`.
+    let dedicated_routes = match env::var("DEDICATED_BP_SERVERS") {
+        Ok(value) => routing_utils::parse_dedicated_routes(&value).map_err(|error| {
+            std::io::Error::other(format!("Invalid DEDICATED_BP_SERVERS. {}", error))
+        })?,
+        Err(_) => vec![],
+    };
+    let dedicated_bp_servers = DedicatedBpServers::new(
+        dedicated_routes
+            .into_iter()
+            .map(|route| {
+                let client = Arc::new(BPRequestClient::new(
+                    &route.host,
+                    8096,
+                    Duration::from_secs(3),
+                    Keepalive {
+                        interval: config.bp_keepalive_interval,
+                        liveness_timeout: config.bp_liveness_timeout,
+                    },
+                    config.bp_drain_timeout,
+                    secrets.clone(),
+                ));
+                (route, client)
+            })
+            .collect(),
+    );
+
     // Resources shared across API views and task handlers.
+    let config = Arc::new(ArcSwap::from_pointee(config));
     let shared_context = SharedContext {
-        bp_request_client: bp_request_client.clone(),
+        config: config.clone(),
+        public_bp_servers: public_bp_servers.clone(),
+        dedicated_bp_servers: Arc::new(dedicated_bp_servers),
         ws_clients,
         db_wrapper,
+        metrics,
+        processing_times,
+        canary,
+        image_workers: Arc::new(ImageWorkers::from_env()),
+        task_json_cache: Arc::new(TaskJsonCache::from_env()),
+        progress: Arc::new(ProgressTracker::new()),
+        contact_sheets: Arc::new(ContactSheets::new()),
+        mailer: Mailer::from_env().map(Arc::new),
+        ops_notifier: OpsNotifier::from_env(config.clone()).map(Arc::new),
+        key_lookup_guard: Arc::new(KeyLookupGuard::new()),
+        secrets: secrets.clone(),
+        nonce_cache: Arc::new(NonceCache::from_env().await),
+        locks,
+        scheduler: scheduler.clone(),
+        lifecycle: Arc::new(TaskLifecycle::new()),
+    };
+
+    // Periodic orphaned media cleanup. Disabled unless an interval or a schedule is configured.
+    let media_gc_schedule = match env::var("MEDIA_GC_INTERVAL_SECS") {
+        Ok(value) => match value.parse::<u64>() {
+            Ok(seconds) if seconds > 0 => Some(Schedule::Every(Duration::from_secs(seconds))),
+            _ => {
+                eprintln!("Ignoring invalid MEDIA_GC_INTERVAL_SECS value: {}", value);
+                None
+            }
+        },
+        Err(_) => None,
+    };
+    let context = shared_context.clone();
+    scheduler.spawn(
+        "media_gc",
+        media_gc_schedule,
+        JobScope::Cluster,
+        move || jobs::media_gc::run_once(context.db_wrapper.clone(), context.config.clone()),
+    );
+
+    // Moves old tasks out of the hot table. Runs do nothing unless an age is configured.
+    let context = shared_context.clone();
+    scheduler.spawn(
+        "task_archive",
+        Some(Schedule::Every(Duration::from_secs(60 * 60))),
+        JobScope::Cluster,
+        move || jobs::task_archive::run_once(context.db_wrapper.clone(), context.config.clone()),
+    );
+
+    // Clears countries and user identifiers of old tasks. Runs do nothing unless an age is
+    // configured.
+    let context = shared_context.clone();
+    scheduler.spawn(
+        "task_anonymize",
+        Some(Schedule::Every(Duration::from_secs(60 * 60))),
+        JobScope::Cluster,
+        move || jobs::task_anonymize::run_once(context.db_wrapper.clone(), context.config.clone()),
+    );
+
+    // Applies settings changed in the `app_config` table without restart.
+    let config_reload_interval = match env::var("CONFIG_RELOAD_INTERVAL_SECS") {
+        Ok(value) => value.parse::<u64>().unwrap_or(30).max(1),
+        Err(_) => 30,
+    };
+    tokio::spawn(jobs::config_reload::run_periodically(
+        shared_context.db_wrapper.clone(),
+        shared_context.config.clone(),
+        shared_context.ws_clients.clone(),
+        Duration::from_secs(config_reload_interval),
+    ));
+
+    // Re-reads secrets so rotated values apply without restart. The BP connections re-handshake
+    // when their auth token changes.
+    let secrets_refresh_interval = match env::var("SECRETS_REFRESH_INTERVAL_SECS") {
+        Ok(value) => value.parse::<u64>().unwrap_or(60).max(1),
+        Err(_) => 60,
+    };
+    let mut bp_clients: Vec<_> = public_bp_servers.clients().cloned().collect();
+    if let Some(canary_instance) = &shared_context.canary {
+        bp_clients.push(canary_instance.client.clone());
+    }
+    bp_clients.extend(shared_context.dedicated_bp_servers.clients().cloned());
+    tokio::spawn(jobs::secrets_refresh::run_periodically(
+        secrets,
+        bp_clients,
+        Duration::from_secs(secrets_refresh_interval),
+    ));
+
+    // Drops websocket clients which stopped sending heartbeats.
+    if shared_context.ws_clients.config().drop_stale {
+        tokio::spawn(jobs::ws_heartbeat::run_periodically(
+            shared_context.ws_clients.clone(),
+            shared_context.metrics.clone(),
+        ));
+    }
+
+    // Keeps daily stats rollups fresh for the admin stats endpoint.
+    let stats_rollup_interval = match env::var("STATS_ROLLUP_INTERVAL_SECS") {
+        Ok(value) => value.parse::<u64>().unwrap_or(600).max(1),
+        Err(_) => 600,
+    };
+    let context = shared_context.clone();
+    scheduler.spawn(
+        "stats_rollup",
+        Some(Schedule::Every(Duration::from_secs(stats_rollup_interval))),
+        JobScope::Cluster,
+        move || jobs::stats_rollup::run_once(context.db_wrapper.clone()),
+    );
+
+    // Removes upload temp files left behind by aborted uploads. Temp files are local, so every
+    // replica cleans up its own.
+    let temp_janitor_interval = match env::var("TEMP_JANITOR_INTERVAL_SECS") {
+        Ok(value) => value.parse::<u64>().unwrap_or(600).max(1),
+        Err(_) => 600,
+    };
+    let context = shared_context.clone();
+    scheduler.spawn(
+        "temp_janitor",
+        Some(Schedule::Every(Duration::from_secs(temp_janitor_interval))),
+        JobScope::Instance,
+        move || jobs::temp_janitor::run_once(context.config.clone()),
+    );
+
+    // Failure rate and disk usage alerts. Only run when an ops webhook is configured.
+    if let Some(notifier) = &shared_context.ops_notifier {
+        let ops_check_interval = match env::var("OPS_CHECK_INTERVAL_SECS") {
+            Ok(value) => value.parse::<u64>().unwrap_or(60).max(1),
+            Err(_) => 60,
+        };
+        tokio::spawn(jobs::ops_monitor::run_periodically(
+            shared_context.db_wrapper.clone(),
+            shared_context.config.clone(),
+            notifier.clone(),
+            Duration::from_secs(ops_check_interval),
+        ));
+    }
+
+    // Writes progress reported by the BP server in batches.
+    let progress_flush_interval = match env::var("PROGRESS_FLUSH_INTERVAL_MILLIS") {
+        Ok(value) => value.parse::<u64>().unwrap_or(500),
+        Err(_) => 500,
     };
+    tokio::spawn(jobs::progress_flush::run_periodically(
+        shared_context.db_wrapper.clone(),
+        shared_context.progress.clone(),
+        Duration::from_millis(progress_flush_interval.max(1)),
+    ));
+
+    // Delivers result notifications left unsent, e.g. by a crash before their broadcast.
+    let outbox_interval = match env::var("OUTBOX_INTERVAL_SECS") {
+        Ok(value) => value.parse::<u64>().unwrap_or(10).max(1),
+        Err(_) => 10,
+    };
+    tokio::spawn(jobs::outbox_delivery::run_periodically(
+        shared_context.clone(),
+        Duration::from_secs(outbox_interval),
+    ));
+
+    // Publishes task lifecycle events to Kafka while `KAFKA_BROKERS` is set.
+    #[cfg(feature = "kafka-events")]
+    jobs::kafka_events::spawn(shared_context.clone());
+
+    // Creates tasks from submissions of internal batch systems while `INGEST_NATS_URL` is set.
+    #[cfg(feature = "nats-ingest")]
+    jobs::queue_ingest::spawn(shared_context.clone());
+
+    // Creates tasks from objects dropped into S3 while `S3_INGEST_QUEUE_URL` is set.
+    #[cfg(feature = "s3-ingest")]
+    jobs::s3_ingest::spawn(shared_context.clone());
+
+    // Creates tasks from images dropped into client folders below `DROP_FOLDER_ROOT`, e.g. over
+    // SFTP, and writes their results back.
+    if let Some(drop_folders) = jobs::drop_folder::DropFolders::from_env().map(Arc::new) {
+        let drop_folder_interval = match env::var("DROP_FOLDER_SCAN_INTERVAL_SECS") {
+            Ok(value) => value.parse::<u64>().unwrap_or(30).max(1),
+            Err(_) => 30,
+        };
+        jobs::drop_folder::spawn_result_writer(shared_context.clone(), drop_folders.clone());
+        let context = shared_context.clone();
+        scheduler.spawn(
+            "drop_folder_scan",
+            Some(Schedule::Every(Duration::from_secs(drop_folder_interval))),
+            JobScope::Cluster,
+            move || jobs::drop_folder::scan_once(context.clone(), drop_folders.clone()),
+        );
+    }
+
+    // Reports successful tasks of metered subscriptions to Stripe.
+    let usage_report_interval = match env::var("USAGE_REPORT_INTERVAL_SECS") {
+        Ok(value) => value.parse::<u64>().unwrap_or(3600).max(1),
+        Err(_) => 3600,
+    };
+    let context = shared_context.clone();
+    scheduler.spawn(
+        "usage_report",
+        Some(Schedule::Every(Duration::from_secs(usage_report_interval))),
+        JobScope::Cluster,
+        move || jobs::usage_report::run_once(context.clone()),
+    );
+
+    // Removes leases left to expire, e.g. of dispatched tasks.
+    let context = shared_context.clone();
+    scheduler.spawn(
+        "lease_gc",
+        Some(Schedule::Every(Duration::from_secs(60 * 60))),
+        JobScope::Cluster,
+        move || jobs::lease_gc::run_once(context.db_wrapper.clone()),
+    );
+
+    tokio::spawn(task::notify_bp_connection_changes(shared_context.clone()));
+
+    // Stores task messages exchanged with BP servers while `BP_MESSAGE_ARCHIVE` is enabled, and
+    // trims the archive to `BP_MESSAGE_ARCHIVE_MAX_ROWS`.
+    jobs::bp_message_archive::spawn(shared_context.clone());
+    let context = shared_context.clone();
+    scheduler.spawn(
+        "bp_message_trim",
+        Some(Schedule::Every(Duration::from_secs(10 * 60))),
+        JobScope::Cluster,
+        move || jobs::bp_message_archive::trim_once(context.clone()),
+    );
+
+    if let Some(canary_instance) = &shared_context.canary {
+        let shared_context_cloned = shared_context.clone();
+        canary_instance
+            .client
+            .listen(move |files, message| {
+                let shared_context_cloned = shared_context_cloned.clone();
 
+                async move {
+                    tokio::spawn(canary::handle_canary_response(
+                        shared_context_cloned,
+                        files,
+                        message,
+                    ));
+                }
+            })
+            .await;
+    }
+
+    // Standby and dedicated BP servers answer like the public one.
+    for client in shared_context.dedicated_bp_servers.clients() {
+        listen_for_results(&shared_context, client).await;
+    }
+    for client in public_bp_servers.clients() {
+        listen_for_results(&shared_context, client).await;
+    }
+
+    api::run_server(shared_context).await?;
+    Ok(())
+}
+
+///
+/// Handles task results received on `client`, a public, standby or dedicated BP connection.
+///
+async fn listen_for_results(shared_context: &SharedContext, client: &Arc<BPRequestClient>) {
     let shared_context_cloned = shared_context.clone();
+    let client_cloned = client.clone();
+    #[cfg(feature = "bp-replay")]
+    let address = client.address().to_string();
 
-    bp_request_client
+    client
         .listen(move |files, message| {
             let shared_context_cloned = shared_context_cloned.clone();
+            #[cfg(feature = "bp-replay")]
+            let address = address.clone();
+
+            // Outcomes count towards the health of public endpoints, see `bp_failover`.
+            shared_context_cloned.public_bp_servers.record_response(
+                &shared_context_cloned.config.load(),
+                &client_cloned,
+                &message,
+            );
 
             async move {
+                #[cfg(feature = "bp-replay")]
+                record_frame(&address, &files, &message).await;
+
                 // Spawns new tokio task. Pros: functions even if crashed, runs tasks in concurrently in background.
                 tokio::spawn(async move {
                     // These tasks may run for long time. So set timeout to prevent unintended bug
                     // which hangs runtime.
                     let result = tokio::time::timeout(
-                        Duration::from_secs(6),
+                        shared_context_cloned.config.load().bp_response_timeout,
                         task::handle_response_received_from_bp_server(
-                            shared_context_cloned,
+                            shared_context_cloned.clone(),
                             files,
-                            message,
+                            message.clone(),
                         ),
                     )
                     .await;
                     println!("Handle bp server response result: {:?}", result);
+
+                    if result.is_err() {
+                        task::handle_response_timeout(shared_context_cloned, &message).await;
+                    }
                 });
             }
         })
         .await;
+}
 
-    api::run_server(shared_context).await?;
-    Ok(())
+///
+/// Records a frame received from a BP server to `BP_RECORD_DIR`, if set, to be replayed later.
+///
+#[cfg(feature = "bp-replay")]
+async fn record_frame(
+    address: &str,
+    files: &[tej_protoc::protoc::File],
+    message: &serde_json::Value,
+) {
+    let dir = match utils::replay_utils::record_dir() {
+        Some(dir) => dir,
+        None => return,
+    };
+
+    let files: Vec<(&[u8], &[u8])> = files
+        .iter()
+        .map(|file| (file.name.as_slice(), file.data.as_slice()))
+        .collect();
+    let frame = utils::replay_utils::RecordedFrame::new(address, message, &files);
+    if let Err(error) = utils::replay_utils::record(&dir, &frame).await {
+        eprintln!("Failed to record BP frame. Error: {}", error);
+    }
 }
@@ -0,0 +1,81 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+
+use crate::utils::timing_utils::Histogram;
+
+///
+/// In-process counters and histograms exposed in Prometheus text format at
+/// `/v1/admin/metrics/`.
+///
+/// Counter names may carry labels, e.g. `bp_timeouts_total{stage="send"}`.
+///
+pub struct Metrics {
+    counters: Mutex<BTreeMap<String, u64>>,
+    /// Histograms by name, then by labels.
+    histograms: Mutex<BTreeMap<String, BTreeMap<String, Histogram>>>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self {
+            counters: Mutex::new(BTreeMap::new()),
+            histograms: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn increment(&self, name: &str) {
+        self.add(name, 1);
+    }
+
+    pub fn add(&self, name: &str, value: u64) {
+        let mut counters = self.counters.lock().unwrap();
+        *counters.entry(name.to_string()).or_insert(0) += value;
+    }
+
+    ///
+    /// Records `value` in the histogram `name` with `labels`, e.g. `route="/v1/status/"`,
+    /// created with `bounds` on first use.
+    ///
+    pub fn observe(&self, name: &str, labels: &str, bounds: &'static [f64], value: f64) {
+        let mut histograms = self.histograms.lock().unwrap();
+        histograms
+            .entry(name.to_string())
+            .or_default()
+            .entry(labels.to_string())
+            .or_insert_with(|| Histogram::new(bounds))
+            .observe(value);
+    }
+
+    ///
+    /// Appends all counters to `output` in Prometheus text format.
+    ///
+    pub fn render_counters(&self, output: &mut String) {
+        let counters = self.counters.lock().unwrap();
+        for (name, value) in counters.iter() {
+            let _ = writeln!(output, "{} {}", name, value);
+        }
+    }
+
+    ///
+    /// Appends all histograms to `output` in Prometheus text format.
+    ///
+    pub fn render_histograms(&self, output: &mut String) {
+        let histograms = self.histograms.lock().unwrap();
+        for (name, series) in histograms.iter() {
+            let _ = writeln!(output, "# TYPE {} histogram", name);
+            for (labels, histogram) in series.iter() {
+                histogram.render(output, name, labels);
+            }
+        }
+    }
+}
+
+///
+/// Appends a single gauge sample in Prometheus text format.
+///
+pub fn render_gauge(output: &mut String, name: &str, help: &str, value: f64) {
+    let _ = writeln!(output, "# HELP {} {}", name, help);
+    let _ = writeln!(output, "# TYPE {} gauge", name);
+    let _ = writeln!(output, "{} {}", name, value);
+}
@@ -0,0 +1,216 @@
+use std::collections::HashMap;
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+
+use arc_swap::ArcSwap;
+use serde_json::Value;
+
+use crate::utils::secret_utils;
+use crate::utils::token_utils::Keyring;
+
+pub const BP_SERVER_AUTH_TOKEN: &str = "BP_SERVER_AUTH_TOKEN";
+pub const POSTGRES_URL: &str = "POSTGRES_URL";
+pub const ADMIN_AUTH_TOKEN: &str = "ADMIN_AUTH_TOKEN";
+pub const TASK_TOKEN_SECRET: &str = "TASK_TOKEN_SECRET";
+pub const SIGNING_KEYS: &str = "SIGNING_KEYS";
+pub const UPLOAD_SIGNING_KEYS: &str = "UPLOAD_SIGNING_KEYS";
+pub const STRIPE_SECRET_KEY: &str = "STRIPE_SECRET_KEY";
+pub const STRIPE_WEBHOOK_SECRET: &str = "STRIPE_WEBHOOK_SECRET";
+pub const KAFKA_SASL_PASSWORD: &str = "KAFKA_SASL_PASSWORD";
+
+/// Secrets managed by `SecretStore`.
+pub const SECRET_NAMES: [&str; 9] = [
+    BP_SERVER_AUTH_TOKEN,
+    POSTGRES_URL,
+    ADMIN_AUTH_TOKEN,
+    TASK_TOKEN_SECRET,
+    SIGNING_KEYS,
+    UPLOAD_SIGNING_KEYS,
+    STRIPE_SECRET_KEY,
+    STRIPE_WEBHOOK_SECRET,
+    KAFKA_SASL_PASSWORD,
+];
+
+/// Longest wait for Vault, so an unresponsive server doesn't stall startup and refreshes.
+const VAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+///
+/// KV secret of a HashiCorp Vault server holding secrets under their names.
+///
+struct Vault {
+    client: reqwest::Client,
+    /// Full url of the secret, e.g. `https://vault:8200/v1/secret/data/bp-api-service`.
+    url: String,
+}
+
+impl Vault {
+    ///
+    /// Reads `VAULT_ADDR`, `VAULT_SECRET_PATH` and the token from `VAULT_TOKEN` or
+    /// `VAULT_TOKEN_FILE`. `None` when Vault is not configured.
+    ///
+    fn from_env() -> Option<Self> {
+        let address = env::var("VAULT_ADDR").ok()?;
+        let path = env::var("VAULT_SECRET_PATH").ok()?;
+
+        Some(Self {
+            client: reqwest::Client::builder()
+                .timeout(VAULT_TIMEOUT)
+                .build()
+                .unwrap_or_default(),
+            url: format!(
+                "{}/v1/{}",
+                address.trim_end_matches('/'),
+                path.trim_start_matches('/')
+            ),
+        })
+    }
+
+    ///
+    /// Returns string values of the secret, see `secret_utils::vault_kv_values`.
+    ///
+    async fn fetch(&self) -> std::io::Result<HashMap<String, String>> {
+        let token = read_local("VAULT_TOKEN")
+            .ok_or_else(|| std::io::Error::other("VAULT_TOKEN is missing."))?;
+
+        let response = self
+            .client
+            .get(&self.url)
+            .header("X-Vault-Token", token)
+            .send()
+            .await
+            .map_err(std::io::Error::other)?;
+
+        if !response.status().is_success() {
+            return Err(std::io::Error::other(format!(
+                "Vault responded with {}",
+                response.status()
+            )));
+        }
+
+        let body: Value = response.json().await.map_err(std::io::Error::other)?;
+        secret_utils::vault_kv_values(&body)
+            .ok_or_else(|| std::io::Error::other("Vault secret has no data."))
+    }
+}
+
+///
+/// Reads secret `name` from the file at `<name>_FILE`, as mounted by docker and kubernetes
+/// secrets, falling back to environment variable `name`. Trailing newlines of files are removed.
+///
+fn read_local(name: &str) -> Option<String> {
+    if let Ok(path) = env::var(format!("{}_FILE", name)) {
+        match std::fs::read_to_string(&path) {
+            Ok(value) => return Some(value.trim_end_matches(['\r', '\n']).to_string()),
+            Err(error) => {
+                eprintln!("Failed to read {} from {}. Error: {}", name, path, error);
+            }
+        }
+    }
+
+    env::var(name).ok()
+}
+
+///
+/// Secrets read from Vault, files or environment variables, in this order of precedence.
+/// Values are cached and re-read by `refresh`, so rotated secrets apply without restart.
+///
+pub struct SecretStore {
+    vault: Option<Vault>,
+    values: ArcSwap<HashMap<String, String>>,
+}
+
+impl SecretStore {
+    ///
+    /// Reads every secret once. When Vault is configured but unavailable, secrets of files and
+    /// environment variables are used until the next successful refresh.
+    ///
+    pub async fn load() -> Arc<Self> {
+        let store = Self {
+            vault: Vault::from_env(),
+            values: ArcSwap::from_pointee(HashMap::new()),
+        };
+
+        store.refresh().await;
+        Arc::new(store)
+    }
+
+    pub fn get(&self, name: &str) -> Option<String> {
+        self.values.load().get(name).cloned()
+    }
+
+    ///
+    /// Keyring signing task tokens and user identifiers, parsed from `SIGNING_KEYS` or made of
+    /// `TASK_TOKEN_SECRET` when that is missing. `None` disables signing, as does an invalid
+    /// `SIGNING_KEYS`.
+    ///
+    pub fn keyring(&self) -> Option<Keyring> {
+        if let Some(value) = self.get(SIGNING_KEYS) {
+            return match Keyring::parse(&value) {
+                Ok(keyring) => Some(keyring),
+                Err(error) => {
+                    eprintln!("Ignoring invalid SIGNING_KEYS. Error: {}", error);
+                    None
+                }
+            };
+        }
+
+        match self.get(TASK_TOKEN_SECRET) {
+            Some(secret) if !secret.is_empty() => Some(Keyring::single(&secret)),
+            _ => None,
+        }
+    }
+
+    ///
+    /// Keyring of clients signing their uploads, parsed from `UPLOAD_SIGNING_KEYS`. Key ids
+    /// identify the client. `None` when missing or invalid.
+    ///
+    pub fn upload_keyring(&self) -> Option<Keyring> {
+        match Keyring::parse(&self.get(UPLOAD_SIGNING_KEYS)?) {
+            Ok(keyring) => Some(keyring),
+            Err(error) => {
+                eprintln!("Ignoring invalid UPLOAD_SIGNING_KEYS. Error: {}", error);
+                None
+            }
+        }
+    }
+
+    ///
+    /// Re-reads every secret and returns names of the secrets whose value changed.
+    ///
+    pub async fn refresh(&self) -> Vec<&'static str> {
+        let from_vault = match &self.vault {
+            Some(vault) => match vault.fetch().await {
+                Ok(values) => values,
+                Err(error) => {
+                    eprintln!("Failed to read secrets from Vault. Error: {}", error);
+                    // Keeps the last secrets read from Vault rather than falling back.
+                    if !self.values.load().is_empty() {
+                        return vec![];
+                    }
+                    HashMap::new()
+                }
+            },
+            None => HashMap::new(),
+        };
+
+        let mut values = HashMap::new();
+        for name in SECRET_NAMES {
+            let value = match from_vault.get(name) {
+                Some(value) => Some(value.clone()),
+                None => read_local(name),
+            };
+
+            if let Some(value) = value {
+                values.insert(name.to_string(), value);
+            }
+        }
+
+        let previous = self.values.swap(Arc::new(values));
+        let current = self.values.load();
+        SECRET_NAMES
+            .into_iter()
+            .filter(|name| previous.get(*name) != current.get(*name))
+            .collect()
+    }
+}
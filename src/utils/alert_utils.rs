@@ -0,0 +1,70 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+///
+/// Whether `failed` of `total` results reach `rate_percent`. Never with fewer than `min_tasks`
+/// results, so a single failure on an idle service doesn't alert.
+///
+pub fn failure_rate_exceeded(failed: u64, total: u64, min_tasks: u64, rate_percent: u32) -> bool {
+    if total == 0 || total < min_tasks {
+        return false;
+    }
+    failed * 100 >= total * rate_percent as u64
+}
+
+///
+/// Time each kind of alert was last sent, so a kind is sent at most once per cooldown.
+///
+#[derive(Debug, Default)]
+pub struct Cooldowns {
+    last_sent: HashMap<&'static str, Instant>,
+}
+
+impl Cooldowns {
+    ///
+    /// Returns true and starts the cooldown of `kind` when none is running at `now`.
+    ///
+    pub fn try_start(&mut self, kind: &'static str, now: Instant, cooldown: Duration) -> bool {
+        match self.last_sent.get(kind) {
+            Some(sent) if now.duration_since(*sent) < cooldown => false,
+            _ => {
+                self.last_sent.insert(kind, now);
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::time::{Duration, Instant};
+
+    use super::{failure_rate_exceeded, Cooldowns};
+
+    #[test]
+    pub fn test_failure_rate_exceeded() {
+        assert!(failure_rate_exceeded(4, 20, 20, 20));
+        assert!(failure_rate_exceeded(20, 20, 20, 20));
+        assert!(!failure_rate_exceeded(3, 20, 20, 20));
+        // Too few results to judge.
+        assert!(!failure_rate_exceeded(19, 19, 20, 20));
+        assert!(!failure_rate_exceeded(0, 0, 0, 0));
+        assert!(failure_rate_exceeded(0, 1, 0, 0));
+    }
+
+    #[test]
+    pub fn test_cooldowns() {
+        let cooldown = Duration::from_secs(300);
+        let now = Instant::now();
+        let mut cooldowns = Cooldowns::default();
+
+        assert!(cooldowns.try_start("disk_usage", now, cooldown));
+        assert!(!cooldowns.try_start("disk_usage", now + Duration::from_secs(299), cooldown));
+        // Kinds don't share a cooldown.
+        assert!(cooldowns.try_start("failure_rate_spike", now, cooldown));
+
+        // Rejected alerts don't extend the cooldown.
+        assert!(cooldowns.try_start("disk_usage", now + cooldown, cooldown));
+        assert!(!cooldowns.try_start("disk_usage", now + cooldown, cooldown));
+    }
+}
@@ -0,0 +1,41 @@
+use chrono::{DateTime, Utc};
+
+///
+/// Creation date before which tasks are anonymized. Never later than the first of the last
+/// `refreshed_days` days, which the stats rollup job still refreshes, so countries are only
+/// cleared once their daily counts are final.
+///
+pub fn anonymize_before(
+    now: DateTime<Utc>,
+    anonymize_after_days: i64,
+    refreshed_days: u32,
+) -> DateTime<Utc> {
+    let first_refreshed_day =
+        now.date_naive() - chrono::Duration::days(refreshed_days.saturating_sub(1) as i64);
+    let first_refreshed_day = first_refreshed_day
+        .and_time(chrono::NaiveTime::MIN)
+        .and_utc();
+
+    (now - chrono::Duration::days(anonymize_after_days)).min(first_refreshed_day)
+}
+
+#[cfg(test)]
+pub mod test {
+    use chrono::{DateTime, Utc};
+
+    use super::anonymize_before;
+
+    #[test]
+    pub fn test_anonymize_before() {
+        let now: DateTime<Utc> = "2024-07-10T15:30:00Z".parse().unwrap();
+        let at = |value: &str| value.parse::<DateTime<Utc>>().unwrap();
+
+        assert_eq!(at("2024-06-10T15:30:00Z"), anonymize_before(now, 30, 2));
+        // Days still refreshed by the stats rollup keep their countries.
+        assert_eq!(at("2024-07-09T00:00:00Z"), anonymize_before(now, 0, 2));
+        assert_eq!(at("2024-07-09T00:00:00Z"), anonymize_before(now, 1, 2));
+        assert_eq!(at("2024-07-08T15:30:00Z"), anonymize_before(now, 2, 2));
+        assert_eq!(at("2024-07-10T00:00:00Z"), anonymize_before(now, 0, 1));
+        assert_eq!(at("2024-07-10T00:00:00Z"), anonymize_before(now, 0, 0));
+    }
+}
@@ -0,0 +1,381 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use serde_json::Value;
+use sha2::Sha256;
+
+use crate::db::models::BillingPlan;
+use crate::utils::signature_utils;
+
+type HmacSha256 = Hmac<Sha256>;
+
+///
+/// Limits of a billing plan, looked up by the Stripe price lookup key of a subscription.
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PlanLimits {
+    /// Successful tasks per calendar month (UTC). `None` is unlimited.
+    pub monthly_quota: Option<i64>,
+    /// Sent to the BP server with every task of the plan. Higher is more urgent.
+    pub priority: i32,
+}
+
+///
+/// Who pays for the tasks of an upload signing key: the organization owning the key, or else the
+/// key itself.
+///
+#[derive(Debug, Clone, PartialEq)]
+pub enum BillingAccount {
+    Key(String),
+    Organization(i64),
+}
+
+impl BillingAccount {
+    ///
+    /// Key of the plan of the account in table `billing_plan`: the key id, or `org:<id>` for
+    /// organizations. Key ids can't contain `:`, so the two never collide.
+    ///
+    pub fn plan_key(&self) -> String {
+        match self {
+            BillingAccount::Key(key_id) => key_id.clone(),
+            BillingAccount::Organization(organization_id) => format!("org:{}", organization_id),
+        }
+    }
+
+    ///
+    /// Directory of the account's media under `MEDIA_ROOT/tenants/`: `org-<id>` for organizations,
+    /// or `key-` and a hash prefix of the key id, so key ids never show up in media URLs.
+    ///
+    pub fn media_prefix(&self) -> String {
+        match self {
+            BillingAccount::Key(key_id) => {
+                let hash = signature_utils::sha256_hex(key_id.as_bytes());
+                format!("key-{}", &hash[..16])
+            }
+            BillingAccount::Organization(organization_id) => format!("org-{}", organization_id),
+        }
+    }
+}
+
+///
+/// Tenants whose tasks a client may read, from the credentials it sent.
+///
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum TenantAccess {
+    /// No credentials. Only tasks stored outside every tenant.
+    #[default]
+    Anonymous,
+    /// Authenticated as the account with this `BillingAccount::media_prefix`.
+    Tenant(String),
+    /// The admin token, which reads every tenant.
+    Admin,
+}
+
+impl TenantAccess {
+    ///
+    /// Whether tasks stored under every one of `tenants` may be read.
+    ///
+    pub fn allows<S: AsRef<str>>(&self, tenants: &[S]) -> bool {
+        match self {
+            TenantAccess::Admin => true,
+            TenantAccess::Tenant(prefix) => tenants.iter().all(|tenant| tenant.as_ref() == prefix),
+            TenantAccess::Anonymous => tenants.is_empty(),
+        }
+    }
+}
+
+///
+/// Parses comma separated `<lookup key>:<monthly quota>:<priority>` entries, e.g.
+/// `starter:5000:1,pro:100000:5,enterprise::10`. An empty quota is unlimited.
+///
+pub fn parse_plans(value: &str) -> Result<HashMap<String, PlanLimits>, String> {
+    let mut plans = HashMap::new();
+    for entry in value
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+    {
+        let parts: Vec<&str> = entry.split(':').collect();
+        let (name, quota, priority) = match parts[..] {
+            [name, quota, priority] if !name.is_empty() => (name, quota, priority),
+            _ => {
+                return Err(format!(
+                    "Expected <lookup key>:<monthly quota>:<priority>, found {:?}.",
+                    entry
+                ))
+            }
+        };
+
+        let monthly_quota = match quota {
+            "" => None,
+            quota => match quota.parse::<i64>() {
+                Ok(quota) if quota >= 0 => Some(quota),
+                _ => return Err(format!("Invalid monthly quota of plan {:?}.", name)),
+            },
+        };
+        let priority = match priority.parse::<i32>() {
+            Ok(priority) => priority,
+            Err(_) => return Err(format!("Invalid priority of plan {:?}.", name)),
+        };
+
+        if plans
+            .insert(
+                name.to_string(),
+                PlanLimits {
+                    monthly_quota,
+                    priority,
+                },
+            )
+            .is_some()
+        {
+            return Err(format!("Plan {:?} is listed twice.", name));
+        }
+    }
+    Ok(plans)
+}
+
+///
+/// Plan of a Stripe subscription object, or `None` without `metadata.organization_id` or
+/// `metadata.key_id`. The first subscription item is the metered one. Price lookup keys missing
+/// from `plans` are unlimited. `event_created` is the creation time of the event carrying it.
+///
+pub fn subscription_plan(
+    subscription: &Value,
+    plans: &HashMap<String, PlanLimits>,
+    event_created: Option<DateTime<Utc>>,
+) -> Option<BillingPlan> {
+    let metadata = &subscription["metadata"];
+    let account = match metadata["organization_id"].as_str() {
+        Some(organization_id) => BillingAccount::Organization(organization_id.parse().ok()?),
+        None => BillingAccount::Key(metadata["key_id"].as_str()?.to_string()),
+    };
+    let key_id = account.plan_key();
+    let item = &subscription["items"]["data"][0];
+    let plan = item["price"]["lookup_key"].as_str().map(str::to_string);
+
+    let limits = match plan.as_ref().and_then(|plan| plans.get(plan)) {
+        Some(limits) => *limits,
+        None => {
+            eprintln!(
+                "Plan {:?} of {} is missing from BILLING_PLANS. Treating as unlimited.",
+                plan, key_id
+            );
+            PlanLimits {
+                monthly_quota: None,
+                priority: 0,
+            }
+        }
+    };
+
+    Some(BillingPlan {
+        key_id,
+        stripe_customer_id: subscription["customer"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string(),
+        stripe_subscription_id: subscription["id"].as_str()?.to_string(),
+        stripe_subscription_item_id: item["id"].as_str().map(str::to_string),
+        plan,
+        status: subscription["status"]
+            .as_str()
+            .unwrap_or("active")
+            .to_string(),
+        monthly_quota: limits.monthly_quota,
+        priority: limits.priority,
+        date_updated: Utc::now(),
+        event_created,
+    })
+}
+
+///
+/// Checks the `Stripe-Signature` header of a webhook, `t=<unix seconds>,v1=<hex signature>`,
+/// against the HMAC-SHA256 of `<t>.<body>`. Any `v1` entry may match, as Stripe signs with every
+/// active secret while one is rolled. Webhooks signed more than `tolerance_secs` away from `now`
+/// are rejected, so a captured webhook can't be replayed later.
+///
+pub fn verify_stripe_signature(
+    secret: &str,
+    header: &str,
+    body: &[u8],
+    now: i64,
+    tolerance_secs: u64,
+) -> Result<(), String> {
+    let mut timestamp = None;
+    let mut signatures = vec![];
+    for (name, value) in header
+        .split(',')
+        .filter_map(|entry| entry.trim().split_once('='))
+    {
+        match name {
+            "t" => timestamp = value.parse::<i64>().ok(),
+            "v1" => signatures.extend(decode_hex(value)),
+            _ => {}
+        }
+    }
+
+    let timestamp = timestamp.ok_or("Stripe-Signature has no timestamp.")?;
+    if now.abs_diff(timestamp) > tolerance_secs {
+        return Err("Stripe-Signature expired.".to_string());
+    }
+
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    if signatures
+        .iter()
+        .any(|signature| mac.clone().verify_slice(signature).is_ok())
+    {
+        Ok(())
+    } else {
+        Err("Stripe-Signature mismatch.".to_string())
+    }
+}
+
+fn decode_hex(value: &str) -> Option<Vec<u8>> {
+    value
+        .as_bytes()
+        .chunks(2)
+        .map(|pair| match pair {
+            [_, _] => u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok(),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+pub mod test {
+    use chrono::DateTime;
+    use hmac::Mac;
+    use serde_json::json;
+
+    use super::{
+        parse_plans, subscription_plan, verify_stripe_signature, BillingAccount, HmacSha256,
+        PlanLimits, TenantAccess,
+    };
+
+    #[test]
+    pub fn test_parse_plans() {
+        let plans = parse_plans("starter:5000:1, enterprise::10").unwrap();
+        assert_eq!(
+            Some(&PlanLimits {
+                monthly_quota: Some(5000),
+                priority: 1,
+            }),
+            plans.get("starter")
+        );
+        assert_eq!(None, plans["enterprise"].monthly_quota);
+        assert!(parse_plans("").unwrap().is_empty());
+
+        assert!(parse_plans("starter:5000").is_err());
+        assert!(parse_plans(":5000:1").is_err());
+        assert!(parse_plans("starter:-1:1").is_err());
+        assert!(parse_plans("starter:5000:high").is_err());
+        assert!(parse_plans("a:1:1,a:2:2").is_err());
+    }
+
+    #[test]
+    pub fn test_billing_account() {
+        assert_eq!(
+            "customer",
+            BillingAccount::Key("customer".to_string()).plan_key()
+        );
+        assert_eq!("org:42", BillingAccount::Organization(42).plan_key());
+
+        let key_prefix = BillingAccount::Key("customer".to_string()).media_prefix();
+        assert_eq!(20, key_prefix.len());
+        assert!(key_prefix.starts_with("key-"));
+        assert!(!key_prefix.contains("customer"));
+        assert_ne!(
+            key_prefix,
+            BillingAccount::Key("other".to_string()).media_prefix()
+        );
+        assert_eq!("org-42", BillingAccount::Organization(42).media_prefix());
+    }
+
+    #[test]
+    pub fn test_tenant_access() {
+        let no_tenants: [&str; 0] = [];
+        assert!(TenantAccess::Anonymous.allows(&no_tenants));
+        assert!(!TenantAccess::Anonymous.allows(&["org-1"]));
+
+        let tenant = TenantAccess::Tenant("org-1".to_string());
+        assert!(tenant.allows(&no_tenants));
+        assert!(tenant.allows(&["org-1", "org-1"]));
+        assert!(!tenant.allows(&["org-1", "org-2"]));
+
+        assert!(TenantAccess::Admin.allows(&["org-1", "key-0123456789abcdef"]));
+    }
+
+    #[test]
+    pub fn test_subscription_plan() {
+        let plans = parse_plans("starter:5000:1").unwrap();
+        let created = DateTime::from_timestamp(1700000000, 0);
+        let subscription = json!({
+            "id": "sub_1",
+            "customer": "cus_1",
+            "status": "past_due",
+            "metadata": {"organization_id": "42", "key_id": "customer"},
+            "items": {"data": [{"id": "si_1", "price": {"lookup_key": "starter"}}]},
+        });
+
+        let plan = subscription_plan(&subscription, &plans, created).unwrap();
+        assert_eq!("org:42", plan.key_id);
+        assert_eq!("cus_1", plan.stripe_customer_id);
+        assert_eq!("sub_1", plan.stripe_subscription_id);
+        assert_eq!(Some("si_1"), plan.stripe_subscription_item_id.as_deref());
+        assert_eq!(Some("starter"), plan.plan.as_deref());
+        assert_eq!("past_due", plan.status);
+        assert_eq!(Some(5000), plan.monthly_quota);
+        assert_eq!(1, plan.priority);
+        assert_eq!(created, plan.event_created);
+
+        // Keys are billed without organization, and unknown prices are unlimited.
+        let subscription = json!({
+            "id": "sub_2",
+            "metadata": {"key_id": "customer"},
+            "items": {"data": [{"id": "si_2", "price": {"lookup_key": "unknown"}}]},
+        });
+        let plan = subscription_plan(&subscription, &plans, None).unwrap();
+        assert_eq!("customer", plan.key_id);
+        assert_eq!("active", plan.status);
+        assert_eq!(None, plan.monthly_quota);
+        assert_eq!(0, plan.priority);
+
+        assert!(subscription_plan(&json!({"id": "sub_3", "metadata": {}}), &plans, None).is_none());
+        let invalid_organization = json!({"id": "sub_4", "metadata": {"organization_id": "x"}});
+        assert!(subscription_plan(&invalid_organization, &plans, None).is_none());
+        let without_id = json!({"metadata": {"key_id": "customer"}});
+        assert!(subscription_plan(&without_id, &plans, None).is_none());
+    }
+
+    #[test]
+    pub fn test_verify_stripe_signature() {
+        let body = br#"{"type":"customer.subscription.updated"}"#;
+        let mut mac = HmacSha256::new_from_slice(b"whsec_test").unwrap();
+        mac.update(b"1700000000.");
+        mac.update(body);
+        let signature: String = mac
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+
+        let header = format!("t=1700000000,v1=00ff,v1={}", signature);
+        assert!(verify_stripe_signature("whsec_test", &header, body, 1700000100, 300).is_ok());
+        assert!(verify_stripe_signature("whsec_test", &header, body, 1700000301, 300).is_err());
+        assert!(verify_stripe_signature("whsec_other", &header, body, 1700000000, 300).is_err());
+        assert!(verify_stripe_signature("whsec_test", &header, b"{}", 1700000000, 300).is_err());
+        assert!(verify_stripe_signature(
+            "whsec_test",
+            &format!("v1={}", signature),
+            body,
+            1700000000,
+            300
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,175 @@
+///
+/// Kind of a request body by its `Content-Type`, which decides its size limit.
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BodyKind {
+    /// `application/json` and `application/*+json`.
+    Json,
+    /// `multipart/form-data`, i.e. uploads.
+    Multipart,
+    /// Anything else, e.g. the raw image of plugin requests.
+    Other,
+}
+
+impl BodyKind {
+    pub fn from_content_type(content_type: Option<&str>) -> Self {
+        let media_type = content_type
+            .and_then(|value| value.split(';').next())
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+
+        if media_type == "application/json"
+            || (media_type.starts_with("application/") && media_type.ends_with("+json"))
+        {
+            BodyKind::Json
+        } else if media_type == "multipart/form-data" {
+            BodyKind::Multipart
+        } else {
+            BodyKind::Other
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            BodyKind::Json => "JSON",
+            BodyKind::Multipart => "multipart",
+            BodyKind::Other => "other",
+        }
+    }
+}
+
+///
+/// Largest request bodies by `BodyKind` and websocket text frames, in bytes. Views may accept
+/// less.
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BodyLimits {
+    pub json: usize,
+    pub multipart: usize,
+    pub other: usize,
+    pub ws_text_frame: usize,
+}
+
+impl BodyLimits {
+    pub fn max_size(&self, kind: BodyKind) -> usize {
+        match kind {
+            BodyKind::Json => self.json,
+            BodyKind::Multipart => self.multipart,
+            BodyKind::Other => self.other,
+        }
+    }
+}
+
+///
+/// Why a request body is refused before it is read.
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BodyRejection {
+    /// The declared `Content-Length` is above the limit of the body kind.
+    TooLarge { kind: BodyKind, max_size: usize },
+    /// The body is sent without `Content-Length`, e.g. chunked, so its size is unknown.
+    LengthRequired,
+    /// `Content-Length` is not a number.
+    InvalidLength,
+}
+
+///
+/// Checks the declared size of a request body against `limits` from its headers, so oversized
+/// bodies are refused before a view reads them. Requests without `Content-Length` and
+/// `Transfer-Encoding` carry no body.
+///
+pub fn check_body(
+    limits: &BodyLimits,
+    content_type: Option<&str>,
+    content_length: Option<&str>,
+    transfer_encoding: Option<&str>,
+) -> Result<(), BodyRejection> {
+    let length = match (content_length, transfer_encoding) {
+        (Some(length), _) => match length.trim().parse::<usize>() {
+            Ok(length) => length,
+            Err(_) => return Err(BodyRejection::InvalidLength),
+        },
+        (None, Some(_)) => return Err(BodyRejection::LengthRequired),
+        (None, None) => return Ok(()),
+    };
+
+    let kind = BodyKind::from_content_type(content_type);
+    let max_size = limits.max_size(kind);
+    if length > max_size {
+        return Err(BodyRejection::TooLarge { kind, max_size });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::{check_body, BodyKind, BodyLimits, BodyRejection};
+
+    const LIMITS: BodyLimits = BodyLimits {
+        json: 100,
+        multipart: 1000,
+        other: 500,
+        ws_text_frame: 10,
+    };
+
+    #[test]
+    pub fn test_body_kind() {
+        let kind = |content_type| BodyKind::from_content_type(content_type);
+        assert_eq!(BodyKind::Json, kind(Some("application/json")));
+        assert_eq!(
+            BodyKind::Json,
+            kind(Some("Application/JSON; charset=utf-8"))
+        );
+        assert_eq!(BodyKind::Json, kind(Some("application/merge-patch+json")));
+        assert_eq!(
+            BodyKind::Multipart,
+            kind(Some("multipart/form-data; boundary=----abc"))
+        );
+        assert_eq!(BodyKind::Other, kind(Some("image/png")));
+        assert_eq!(BodyKind::Other, kind(Some("text/json")));
+        assert_eq!(BodyKind::Other, kind(None));
+    }
+
+    #[test]
+    pub fn test_check_body() {
+        assert_eq!(Ok(()), check_body(&LIMITS, None, None, None));
+        assert_eq!(
+            Ok(()),
+            check_body(&LIMITS, Some("application/json"), Some("100"), None)
+        );
+        assert_eq!(
+            Err(BodyRejection::TooLarge {
+                kind: BodyKind::Json,
+                max_size: 100
+            }),
+            check_body(&LIMITS, Some("application/json"), Some(" 101 "), None)
+        );
+        assert_eq!(
+            Ok(()),
+            check_body(
+                &LIMITS,
+                Some("multipart/form-data; boundary=x"),
+                Some("1000"),
+                None
+            )
+        );
+        assert_eq!(
+            Err(BodyRejection::TooLarge {
+                kind: BodyKind::Other,
+                max_size: 500
+            }),
+            check_body(&LIMITS, None, Some("501"), None)
+        );
+
+        // Chunked bodies can't be checked before they are read.
+        assert_eq!(
+            Err(BodyRejection::LengthRequired),
+            check_body(&LIMITS, Some("application/json"), None, Some("chunked"))
+        );
+        assert_eq!(
+            Err(BodyRejection::InvalidLength),
+            check_body(&LIMITS, None, Some("-1"), None)
+        );
+    }
+}
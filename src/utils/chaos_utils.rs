@@ -0,0 +1,111 @@
+use std::sync::RwLock;
+use std::time::Duration;
+
+use serde::Serialize;
+
+///
+/// Faults injected into the running service, set through `/v1/admin/chaos/`. All off by default.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Faults {
+    /// Percentage of task queries failing with an injected database error.
+    pub db_error_percent: u32,
+    /// Delay before every write of files received from BP servers.
+    #[serde(serialize_with = "serialize_millis", rename = "disk_write_delay_ms")]
+    pub disk_write_delay: Duration,
+    /// Percentage of outbound websocket messages silently dropped.
+    pub ws_drop_percent: u32,
+}
+
+impl Faults {
+    pub const NONE: Faults = Faults {
+        db_error_percent: 0,
+        disk_write_delay: Duration::ZERO,
+        ws_drop_percent: 0,
+    };
+}
+
+fn serialize_millis<S: serde::Serializer>(
+    duration: &Duration,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(duration.as_millis() as u64)
+}
+
+static FAULTS: RwLock<Faults> = RwLock::new(Faults::NONE);
+
+pub fn faults() -> Faults {
+    *FAULTS.read().unwrap_or_else(|error| error.into_inner())
+}
+
+pub fn set_faults(faults: Faults) {
+    *FAULTS.write().unwrap_or_else(|error| error.into_inner()) = faults;
+}
+
+///
+/// Parses a percentage from 0 to 100.
+///
+pub fn parse_percent(value: &str) -> Option<u32> {
+    match value.trim().parse::<u32>() {
+        Ok(percent) if percent <= 100 => Some(percent),
+        _ => None,
+    }
+}
+
+///
+/// Whether an event with probability `percent` happens. `random` in `[0, 100)` picks the outcome.
+///
+fn happens(percent: u32, random: u32) -> bool {
+    random < percent
+}
+
+///
+/// Fails with an injected error as often as `db_error_percent` says.
+///
+pub fn db_error() -> Result<(), sqlx::Error> {
+    if happens(faults().db_error_percent, rand::random::<u32>() % 100) {
+        return Err(sqlx::Error::Io(std::io::Error::other(
+            "Injected database error.",
+        )));
+    }
+    Ok(())
+}
+
+///
+/// Waits `disk_write_delay`.
+///
+pub async fn slow_disk_write() {
+    let delay = faults().disk_write_delay;
+    if !delay.is_zero() {
+        tokio::time::sleep(delay).await;
+    }
+}
+
+///
+/// Whether to drop an outbound websocket message, as often as `ws_drop_percent` says.
+///
+pub fn drop_ws_message() -> bool {
+    happens(faults().ws_drop_percent, rand::random::<u32>() % 100)
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::{happens, parse_percent};
+
+    #[test]
+    pub fn test_parse_percent() {
+        assert_eq!(Some(0), parse_percent("0"));
+        assert_eq!(Some(100), parse_percent(" 100"));
+        assert_eq!(None, parse_percent("101"));
+        assert_eq!(None, parse_percent("-1"));
+        assert_eq!(None, parse_percent("half"));
+    }
+
+    #[test]
+    pub fn test_happens() {
+        assert!(!happens(0, 0));
+        assert!(happens(1, 0));
+        assert!(!happens(1, 1));
+        assert!(happens(100, 99));
+    }
+}
@@ -0,0 +1,33 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+
+/// Version prefix of the cursor payload. Allows changing the format without breaking clients
+/// holding older cursors silently.
+const CURSOR_PREFIX: &str = "v1:";
+
+///
+/// Returns opaque cursor token pointing after the task with `task_id`.
+///
+pub fn encode_cursor(task_id: i64) -> String {
+    URL_SAFE_NO_PAD.encode(format!("{}{}", CURSOR_PREFIX, task_id))
+}
+
+///
+/// Returns `task_id` stored in the cursor token or `None` if the token is malformed.
+///
+pub fn decode_cursor(cursor: &str) -> Option<i64> {
+    let bytes = URL_SAFE_NO_PAD.decode(cursor).ok()?;
+    let payload = String::from_utf8(bytes).ok()?;
+    payload.strip_prefix(CURSOR_PREFIX)?.parse::<i64>().ok()
+}
+
+#[cfg(test)]
+pub mod test {
+    #[test]
+    pub fn test_cursor_round_trip() {
+        let cursor = super::encode_cursor(1024);
+        assert_eq!(Some(1024), super::decode_cursor(&cursor));
+        assert_eq!(None, super::decode_cursor("1024"));
+        assert_eq!(None, super::decode_cursor("not a cursor"));
+    }
+}
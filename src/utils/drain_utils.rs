@@ -0,0 +1,139 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Tasks tracked per connection at most. Beyond, the oldest are dropped; their outcome then isn't
+/// awaited by a drain and they are recovered by the response timeout if lost.
+pub const MAX_IN_FLIGHT: usize = 10_000;
+
+///
+/// Tasks sent on the current BP server connection without an outcome yet, and whether the BP
+/// server asked to drain the connection. Reset whenever the connection drops.
+///
+#[derive(Debug, Default)]
+pub struct DrainState {
+    pub draining: bool,
+    /// Task ids with the time they were sent.
+    in_flight: HashMap<String, Instant>,
+}
+
+impl DrainState {
+    ///
+    /// Tracks `task_id` as sent at `now`, dropping the oldest task once `max_in_flight` are
+    /// tracked.
+    ///
+    pub fn track(&mut self, task_id: String, now: Instant, max_in_flight: usize) {
+        if self.in_flight.len() >= max_in_flight && !self.in_flight.contains_key(&task_id) {
+            let oldest = self
+                .in_flight
+                .iter()
+                .min_by_key(|(_, sent)| **sent)
+                .map(|(task_id, _)| task_id.clone());
+            if let Some(oldest) = oldest {
+                self.in_flight.remove(&oldest);
+            }
+        }
+        self.in_flight.insert(task_id, now);
+    }
+
+    ///
+    /// Stops tracking `task_id`, once its outcome arrived or it couldn't be sent.
+    ///
+    pub fn untrack(&mut self, task_id: &str) {
+        self.in_flight.remove(task_id);
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    ///
+    /// Tasks still awaited while draining. `None` while the BP server isn't draining.
+    ///
+    pub fn remaining(&self) -> Option<usize> {
+        match self.draining {
+            true => Some(self.in_flight.len()),
+            false => None,
+        }
+    }
+}
+
+///
+/// State of a drain as checked by the BP request client.
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DrainProgress {
+    /// The BP server isn't draining, or tasks are still awaited within the timeout.
+    Waiting,
+    /// Every task got its outcome, or the drain ended with the connection.
+    Drained,
+    /// The timeout passed with this many tasks in flight.
+    TimedOut(usize),
+}
+
+///
+/// Progress of a drain which started `elapsed` ago with `remaining` tasks in flight, as returned
+/// by `DrainState::remaining`.
+///
+pub fn drain_progress(
+    remaining: Option<usize>,
+    elapsed: Duration,
+    drain_timeout: Duration,
+) -> DrainProgress {
+    match remaining {
+        Some(0) | None => DrainProgress::Drained,
+        Some(remaining) if elapsed >= drain_timeout => DrainProgress::TimedOut(remaining),
+        Some(_) => DrainProgress::Waiting,
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::time::{Duration, Instant};
+
+    use super::{drain_progress, DrainProgress, DrainState};
+
+    #[test]
+    pub fn test_drain_state() {
+        let mut drain = DrainState::default();
+        let now = Instant::now();
+        assert_eq!(None, drain.remaining());
+
+        drain.track("a".to_string(), now, 2);
+        drain.track("b".to_string(), now + Duration::from_secs(1), 2);
+        drain.track("b".to_string(), now + Duration::from_secs(2), 2);
+        assert_eq!(2, drain.in_flight());
+
+        // The oldest task is dropped once the limit is reached.
+        drain.track("c".to_string(), now + Duration::from_secs(3), 2);
+        assert_eq!(2, drain.in_flight());
+        drain.untrack("a");
+        assert_eq!(2, drain.in_flight());
+
+        drain.draining = true;
+        drain.untrack("b");
+        assert_eq!(Some(1), drain.remaining());
+        drain.untrack("c");
+        assert_eq!(Some(0), drain.remaining());
+    }
+
+    #[test]
+    pub fn test_drain_progress() {
+        let timeout = Duration::from_secs(30);
+        assert_eq!(
+            DrainProgress::Drained,
+            drain_progress(Some(0), Duration::ZERO, timeout)
+        );
+        assert_eq!(
+            DrainProgress::Drained,
+            drain_progress(None, Duration::ZERO, timeout)
+        );
+        assert_eq!(
+            DrainProgress::Waiting,
+            drain_progress(Some(3), Duration::from_secs(29), timeout)
+        );
+        assert_eq!(
+            DrainProgress::TimedOut(3),
+            drain_progress(Some(3), timeout, timeout)
+        );
+    }
+}
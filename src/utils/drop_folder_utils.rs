@@ -0,0 +1,176 @@
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+/// Suffixes of files still being uploaded by common SFTP and FTP clients.
+const PARTIAL_SUFFIXES: [&str; 4] = [".part", ".filepart", ".tmp", ".crdownload"];
+
+///
+/// Drop folder of a client and the upload signing key id its tasks are billed to.
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct DropClient {
+    pub folder: String,
+    pub key_id: String,
+}
+
+///
+/// Parses `DROP_FOLDER_CLIENTS`, comma separated `<folder>:<key id>` pairs, e.g.
+/// `printshop:acme,dam:legacy-dam`. Folders are plain directory names below the drop root.
+///
+pub fn parse_clients(value: &str) -> Result<Vec<DropClient>, String> {
+    let mut clients: Vec<DropClient> = vec![];
+
+    for pair in value
+        .split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+    {
+        let (folder, key_id) = pair
+            .split_once(':')
+            .map(|(folder, key_id)| (folder.trim(), key_id.trim()))
+            .ok_or_else(|| format!("Expected <folder>:<key id>, got \"{}\".", pair))?;
+
+        let is_plain = !folder.is_empty()
+            && !folder.starts_with('.')
+            && folder
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c));
+        if !is_plain {
+            return Err(format!("Invalid folder name \"{}\".", folder));
+        }
+        if key_id.is_empty() {
+            return Err(format!("Folder \"{}\" has no key id.", folder));
+        }
+        if clients.iter().any(|client| client.folder == folder) {
+            return Err(format!("Folder \"{}\" is mapped twice.", folder));
+        }
+
+        clients.push(DropClient {
+            folder: folder.to_string(),
+            key_id: key_id.to_string(),
+        });
+    }
+    Ok(clients)
+}
+
+///
+/// Whether the dropped file `name` may be ingested. Hidden files and files named like uploads
+/// in progress are skipped.
+///
+pub fn is_candidate(name: &str) -> bool {
+    let lowercase = name.to_ascii_lowercase();
+    !name.starts_with('.')
+        && !PARTIAL_SUFFIXES
+            .iter()
+            .any(|suffix| lowercase.ends_with(suffix))
+}
+
+///
+/// Name of a file written back for dropped file `filename`: its stem followed by `suffix`, e.g.
+/// `a.status.json` for `a.jpg` and `.status.json`.
+///
+pub fn result_name(filename: &str, suffix: &str) -> String {
+    let stem = match filename.rsplit_once('.') {
+        Some((stem, _)) if !stem.is_empty() => stem,
+        _ => filename,
+    };
+    format!("{}{}", stem, suffix)
+}
+
+///
+/// Task key of a dropped file, the same on every scan until the file is replaced, so a file
+/// whose removal failed creates its task once.
+///
+pub fn task_key(folder: &str, filename: &str, size: u64, modified_nanos: u128) -> Uuid {
+    let name = format!(
+        "drop-folder:{}/{}:{}:{}",
+        folder, filename, size, modified_nanos
+    );
+    let digest = Sha256::digest(name.as_bytes());
+    let mut bytes = [0u8; 16];
+    bytes.copy_from_slice(&digest[..16]);
+    uuid::Builder::from_random_bytes(bytes).into_uuid()
+}
+
+///
+/// Task key of a status file, as written by the drop folder job, while it says `processing`.
+///
+pub fn processing_task_key(status: &[u8]) -> Option<Uuid> {
+    let status: Value = serde_json::from_slice(status).ok()?;
+    if status.get("status")?.as_str()? != "processing" {
+        return None;
+    }
+    status.get("task_key")?.as_str()?.parse().ok()
+}
+
+#[cfg(test)]
+pub mod test {
+    use uuid::Uuid;
+
+    use super::{
+        is_candidate, parse_clients, processing_task_key, result_name, task_key, DropClient,
+    };
+
+    #[test]
+    pub fn test_parse_clients() {
+        assert_eq!(
+            Ok(vec![
+                DropClient {
+                    folder: "printshop".to_string(),
+                    key_id: "acme".to_string(),
+                },
+                DropClient {
+                    folder: "dam".to_string(),
+                    key_id: "legacy-dam".to_string(),
+                },
+            ]),
+            parse_clients(" printshop:acme, dam : legacy-dam ,")
+        );
+        assert_eq!(Ok(vec![]), parse_clients(""));
+
+        assert!(parse_clients("printshop").is_err());
+        assert!(parse_clients("printshop:").is_err());
+        assert!(parse_clients("../etc:acme").is_err());
+        assert!(parse_clients(".hidden:acme").is_err());
+        assert!(parse_clients("a/b:acme").is_err());
+        assert!(parse_clients("a:acme,a:other").is_err());
+    }
+
+    #[test]
+    pub fn test_is_candidate() {
+        assert!(is_candidate("a.jpg"));
+        assert!(is_candidate("Scan 001.TIF"));
+        assert!(!is_candidate(".a.jpg"));
+        assert!(!is_candidate("a.jpg.part"));
+        assert!(!is_candidate("a.jpg.FILEPART"));
+        assert!(!is_candidate("a.tmp"));
+    }
+
+    #[test]
+    pub fn test_result_name() {
+        assert_eq!("a.png", result_name("a.jpg", ".png"));
+        assert_eq!("a.b.mask.png", result_name("a.b.jpg", ".mask.png"));
+        assert_eq!("a.status.json", result_name("a", ".status.json"));
+    }
+
+    #[test]
+    pub fn test_task_key() {
+        let key = task_key("printshop", "a.jpg", 100, 1);
+        assert_eq!(key, task_key("printshop", "a.jpg", 100, 1));
+        assert_ne!(key, task_key("printshop", "a.jpg", 100, 2));
+        assert_ne!(key, task_key("dam", "a.jpg", 100, 1));
+    }
+
+    #[test]
+    pub fn test_processing_task_key() {
+        let key = Uuid::new_v4();
+        let processing = format!(r#"{{"task_key":"{}","status":"processing"}}"#, key);
+        assert_eq!(Some(key), processing_task_key(processing.as_bytes()));
+
+        let completed = format!(r#"{{"task_key":"{}","status":"completed"}}"#, key);
+        assert_eq!(None, processing_task_key(completed.as_bytes()));
+        assert_eq!(None, processing_task_key(br#"{"status":"rejected"}"#));
+        assert_eq!(None, processing_task_key(b"{"));
+    }
+}
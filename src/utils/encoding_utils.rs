@@ -0,0 +1,204 @@
+use serde::Serialize;
+
+pub const JSON_CONTENT_TYPE: &str = "application/json";
+pub const MSGPACK_CONTENT_TYPE: &str = "application/msgpack";
+
+///
+/// Wire encoding of response bodies and websocket messages. Both encode the same serde data
+/// model, so every serializable response is available in either encoding.
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Json,
+    MessagePack,
+}
+
+impl Encoding {
+    ///
+    /// Picks the encoding from an `Accept` header. Each encoding gets the quality of the most
+    /// specific media range matching it. MessagePack is used when its quality is higher than the
+    /// one of JSON, or equal but named explicitly while JSON only matches a wildcard, e.g. for
+    /// `application/msgpack, */*`. Anything else falls back to JSON.
+    ///
+    pub fn from_accept(accept: Option<&str>) -> Self {
+        let accept = match accept {
+            Some(accept) => accept,
+            None => return Encoding::Json,
+        };
+
+        let mut json = None;
+        let mut msgpack = None;
+        for media_range in accept.split(',') {
+            let mut parts = media_range.split(';');
+            let media_type = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+
+            let quality = parts
+                .filter_map(|parameter| parameter.trim().strip_prefix("q="))
+                .find_map(|quality| quality.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+
+            let range = match media_type.as_str() {
+                MSGPACK_CONTENT_TYPE | "application/x-msgpack" => {
+                    msgpack = most_specific(msgpack, Specificity::Exact, quality);
+                    continue;
+                }
+                JSON_CONTENT_TYPE => {
+                    json = most_specific(json, Specificity::Exact, quality);
+                    continue;
+                }
+                "application/*" => Specificity::Subtype,
+                "*/*" => Specificity::Any,
+                _ => continue,
+            };
+            json = most_specific(json, range, quality);
+            msgpack = most_specific(msgpack, range, quality);
+        }
+
+        let (msgpack_range, msgpack_quality) = match msgpack {
+            Some((range, quality)) if quality > 0.0 => (range, quality),
+            _ => return Encoding::Json,
+        };
+        let (json_range, json_quality) = json.unwrap_or((Specificity::Any, 0.0));
+
+        if msgpack_quality > json_quality
+            || (msgpack_quality == json_quality && msgpack_range > json_range)
+        {
+            Encoding::MessagePack
+        } else {
+            Encoding::Json
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Encoding::Json => JSON_CONTENT_TYPE,
+            Encoding::MessagePack => MSGPACK_CONTENT_TYPE,
+        }
+    }
+
+    ///
+    /// Structs are encoded as MessagePack maps with field names, so both encodings have the same
+    /// shape.
+    ///
+    pub fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, String> {
+        match self {
+            Encoding::Json => serde_json::to_vec(value).map_err(|error| error.to_string()),
+            Encoding::MessagePack => {
+                rmp_serde::to_vec_named(value).map_err(|error| error.to_string())
+            }
+        }
+    }
+}
+
+///
+/// How specifically a media range of an `Accept` header names a type, least specific first.
+///
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+enum Specificity {
+    Any,
+    Subtype,
+    Exact,
+}
+
+///
+/// Quality of a type after another range matching it. Ranges less specific than the current one
+/// are ignored, equally specific ones keep the higher quality.
+///
+fn most_specific(
+    current: Option<(Specificity, f32)>,
+    range: Specificity,
+    quality: f32,
+) -> Option<(Specificity, f32)> {
+    match current {
+        Some((current_range, _)) if current_range > range => current,
+        Some((current_range, current_quality)) if current_range == range => {
+            Some((range, f32::max(current_quality, quality)))
+        }
+        _ => Some((range, quality)),
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use serde_json::{json, Value};
+
+    use super::Encoding;
+
+    #[test]
+    pub fn test_from_accept() {
+        assert_eq!(Encoding::Json, Encoding::from_accept(None));
+        assert_eq!(Encoding::Json, Encoding::from_accept(Some("*/*")));
+        assert_eq!(
+            Encoding::MessagePack,
+            Encoding::from_accept(Some("application/msgpack"))
+        );
+        assert_eq!(
+            Encoding::MessagePack,
+            Encoding::from_accept(Some("application/x-msgpack, application/json;q=0.5"))
+        );
+        assert_eq!(
+            Encoding::Json,
+            Encoding::from_accept(Some("application/msgpack;q=0.5, application/json"))
+        );
+        assert_eq!(
+            Encoding::Json,
+            Encoding::from_accept(Some("application/msgpack;q=0"))
+        );
+    }
+
+    #[test]
+    pub fn test_from_accept_wildcards() {
+        // Named types win ties against wildcards.
+        assert_eq!(
+            Encoding::MessagePack,
+            Encoding::from_accept(Some("application/msgpack, */*"))
+        );
+        assert_eq!(
+            Encoding::MessagePack,
+            Encoding::from_accept(Some("*/*;q=0.8, application/msgpack;q=0.8"))
+        );
+        assert_eq!(
+            Encoding::Json,
+            Encoding::from_accept(Some("application/*, */*"))
+        );
+        assert_eq!(
+            Encoding::Json,
+            Encoding::from_accept(Some("application/json, application/msgpack"))
+        );
+
+        // Higher quality wins regardless of specificity.
+        assert_eq!(
+            Encoding::Json,
+            Encoding::from_accept(Some("application/msgpack;q=0.5, */*"))
+        );
+        assert_eq!(
+            Encoding::MessagePack,
+            Encoding::from_accept(Some("application/msgpack, application/json;q=0.9"))
+        );
+
+        // The most specific range decides, so a named type can be refused despite a wildcard.
+        assert_eq!(
+            Encoding::MessagePack,
+            Encoding::from_accept(Some("application/json;q=0, */*"))
+        );
+        assert_eq!(
+            Encoding::Json,
+            Encoding::from_accept(Some("*/*, application/msgpack;q=0"))
+        );
+        assert_eq!(
+            Encoding::MessagePack,
+            Encoding::from_accept(Some("application/json;q=0.5, application/*"))
+        );
+    }
+
+    #[test]
+    pub fn test_encode() {
+        let value = json!({"status": "success", "data": {"revision": 2, "tags": ["a"]}});
+
+        let encoded = Encoding::MessagePack.encode(&value).unwrap();
+        assert_eq!(value, rmp_serde::from_slice::<Value>(&encoded).unwrap());
+
+        let encoded = Encoding::Json.encode(&value).unwrap();
+        assert_eq!(value, serde_json::from_slice::<Value>(&encoded).unwrap());
+    }
+}
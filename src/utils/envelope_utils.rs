@@ -0,0 +1,163 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+/// `status` of successful responses.
+pub const SUCCESS: &str = "success";
+
+/// `status` of failed responses.
+pub const FAILED: &str = "failed";
+
+///
+/// Envelope of every JSON response: `status`, `status_code`, then the optional `message` and
+/// `data`, then fields of the endpoint, e.g. `field_errors` or `next`. Absent fields are left
+/// out rather than sent as `null`. Websocket messages carry the same `status` and
+/// `status_code`, see `ServerMessage`.
+///
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ApiEnvelope<T = Value> {
+    pub status: String,
+    pub status_code: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<T>,
+    #[serde(flatten)]
+    pub fields: Map<String, Value>,
+}
+
+impl ApiEnvelope {
+    pub fn new(status: &str, status_code: impl ToString) -> Self {
+        Self {
+            status: status.to_string(),
+            status_code: status_code.to_string(),
+            message: None,
+            data: None,
+            fields: Map::new(),
+        }
+    }
+
+    pub fn success(status_code: impl ToString) -> Self {
+        Self::new(SUCCESS, status_code)
+    }
+
+    ///
+    /// Failed envelope, usually with an `ErrorCode` as `status_code`.
+    ///
+    pub fn failed(status_code: impl ToString) -> Self {
+        Self::new(FAILED, status_code)
+    }
+}
+
+impl<T> ApiEnvelope<T> {
+    pub fn message(mut self, message: impl ToString) -> Self {
+        self.message = Some(message.to_string());
+        self
+    }
+
+    pub fn data<D>(self, data: D) -> ApiEnvelope<D> {
+        ApiEnvelope {
+            status: self.status,
+            status_code: self.status_code,
+            message: self.message,
+            data: Some(data),
+            fields: self.fields,
+        }
+    }
+
+    ///
+    /// Adds a top level field next to `data`. Fields named like the envelope fields are
+    /// ignored, so they can't be sent twice.
+    ///
+    pub fn field(mut self, name: &str, value: impl Serialize) -> Self {
+        if !matches!(name, "status" | "status_code" | "message" | "data") {
+            let value = serde_json::to_value(value).unwrap_or(Value::Null);
+            self.fields.insert(name.to_string(), value);
+        }
+        self
+    }
+}
+
+impl<T: Serialize> ApiEnvelope<T> {
+    pub fn to_value(&self) -> Value {
+        serde_json::to_value(self).unwrap_or(Value::Null)
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use serde::{Deserialize, Serialize};
+    use serde_json::json;
+
+    use super::ApiEnvelope;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Task {
+        key: String,
+    }
+
+    #[test]
+    pub fn test_wire_format() {
+        assert_eq!(
+            r#"{"status":"success","status_code":"tasks","data":[1,2],"next":null}"#,
+            ApiEnvelope::success("tasks")
+                .data(json!([1, 2]))
+                .field("next", None::<String>)
+                .to_value()
+                .to_string()
+        );
+        assert_eq!(
+            r#"{"status":"failed","status_code":"form_error","field_errors":{"tags":["Too many."]}}"#,
+            ApiEnvelope::failed("form_error")
+                .field("field_errors", json!({ "tags": ["Too many."] }))
+                .to_value()
+                .to_string()
+        );
+        assert_eq!(
+            r#"{"status":"failed","status_code":"not_found","message":"Task not found."}"#,
+            ApiEnvelope::failed("not_found")
+                .message("Task not found.")
+                .to_value()
+                .to_string()
+        );
+        assert_eq!(
+            json!({ "status": "failed", "status_code": "internal_server_error" }),
+            ApiEnvelope::failed("internal_server_error").to_value()
+        );
+    }
+
+    #[test]
+    pub fn test_envelope_fields_are_not_overridden() {
+        let envelope = ApiEnvelope::success("stats")
+            .field("status", "failed")
+            .field("data", 1);
+        assert_eq!(
+            json!({ "status": "success", "status_code": "stats" }),
+            envelope.to_value()
+        );
+    }
+
+    #[test]
+    pub fn test_typed_data() {
+        let envelope = ApiEnvelope::success("result").data(Task {
+            key: "a".to_string(),
+        });
+        let value = envelope.to_value();
+        assert_eq!(
+            json!({ "status": "success", "status_code": "result", "data": { "key": "a" } }),
+            value
+        );
+
+        let parsed: ApiEnvelope<Task> = serde_json::from_value(value).unwrap();
+        assert_eq!(envelope, parsed);
+
+        let parsed: ApiEnvelope = serde_json::from_value(json!({
+            "status": "failed",
+            "status_code": "quota_exceeded",
+            "message": "Used up.",
+            "monthly_quota": 100,
+        }))
+        .unwrap();
+        assert_eq!(None, parsed.data);
+        assert_eq!(Some(&json!(100)), parsed.fields.get("monthly_quota"));
+    }
+}
@@ -0,0 +1,99 @@
+use chrono::{DateTime, NaiveDate, NaiveTime, Utc};
+
+use crate::db::models::TaskExportRow;
+
+pub const CSV_HEADER: &str =
+    "task_id,date_created,key,task_group,processing,result_status,country,user_identifier";
+
+///
+/// Parses `YYYY-MM-DD` or RFC 3339 datetime string.
+///
+/// A plain date resolves to the start of that day in UTC, or to its last second when
+/// `end_of_day` is true, so `?from=2024-01-01&to=2024-01-01` covers the whole day.
+///
+pub fn parse_datetime_param(value: &str, end_of_day: bool) -> Option<DateTime<Utc>> {
+    if let Ok(datetime) = DateTime::parse_from_rfc3339(value) {
+        return Some(datetime.with_timezone(&Utc));
+    }
+
+    let date = NaiveDate::parse_from_str(value, "%Y-%m-%d").ok()?;
+    let time = if end_of_day {
+        NaiveTime::from_hms_opt(23, 59, 59)?
+    } else {
+        NaiveTime::MIN
+    };
+
+    Some(date.and_time(time).and_utc())
+}
+
+///
+/// Quotes a CSV field if it contains a separator, quote or line break.
+///
+pub fn escape_csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+///
+/// Returns the row as a CSV line including the trailing line break. Columns follow
+/// `CSV_HEADER`.
+///
+pub fn to_csv_line(row: &TaskExportRow) -> String {
+    let optional = |value: &Option<String>| match value {
+        Some(value) => escape_csv_field(value),
+        None => String::new(),
+    };
+
+    let processing = match row.processing {
+        Some(value) => value.to_string(),
+        None => String::new(),
+    };
+
+    format!(
+        "{},{},{},{},{},{},{},{}\n",
+        row.task_id,
+        row.date_created.to_rfc3339(),
+        row.key,
+        row.task_group,
+        processing,
+        optional(&row.result_status),
+        optional(&row.country),
+        optional(&row.user_identifier),
+    )
+}
+
+///
+/// Returns the row as a single JSON line including the trailing line break.
+///
+pub fn to_ndjson_line(row: &TaskExportRow) -> Result<String, serde_json::Error> {
+    let mut line = serde_json::to_string(row)?;
+    line.push('\n');
+    Ok(line)
+}
+
+#[cfg(test)]
+pub mod test {
+    #[test]
+    pub fn test_escape_csv_field() {
+        assert_eq!("NP", super::escape_csv_field("NP"));
+        assert_eq!("\"a,b\"", super::escape_csv_field("a,b"));
+        assert_eq!("\"say \"\"hi\"\"\"", super::escape_csv_field("say \"hi\""));
+    }
+
+    #[test]
+    pub fn test_parse_datetime_param() {
+        let start = super::parse_datetime_param("2024-01-01", false).unwrap();
+        assert_eq!("2024-01-01T00:00:00+00:00", start.to_rfc3339());
+
+        let end = super::parse_datetime_param("2024-01-01", true).unwrap();
+        assert_eq!("2024-01-01T23:59:59+00:00", end.to_rfc3339());
+
+        let exact = super::parse_datetime_param("2024-01-01T10:00:00+01:00", false).unwrap();
+        assert_eq!("2024-01-01T09:00:00+00:00", exact.to_rfc3339());
+
+        assert!(super::parse_datetime_param("yesterday", false).is_none());
+    }
+}
@@ -0,0 +1,513 @@
+use std::borrow::Cow;
+use std::env;
+use std::fmt::{Display, Formatter};
+use std::sync::{mpsc, Mutex, OnceLock};
+
+use image::imageops::FilterType;
+use image::{DynamicImage, RgbaImage};
+use wgpu::util::DeviceExt;
+
+/// Images with fewer pixels stay on the CPU, where they take less time than the upload.
+pub const MIN_GPU_PIXELS: u64 = 1_000_000;
+
+/// Side of the compute workgroups, as declared in the shaders.
+const WORKGROUP_SIZE: u32 = 8;
+
+const SHADER: &str = include_str!("gpu_utils.wgsl");
+
+static GPU: OnceLock<Option<Gpu>> = OnceLock::new();
+
+#[derive(Debug)]
+pub enum GpuError {
+    /// A buffer would exceed the limits of the device.
+    TooLarge,
+    Device(String),
+}
+
+impl Display for GpuError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GpuError::TooLarge => write!(f, "Image exceeds the buffer limits of the GPU."),
+            GpuError::Device(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+///
+/// Device running the `image_utils` operations, with their compiled pipelines.
+///
+pub struct Gpu {
+    device: wgpu::Device,
+    queue: wgpu::Queue,
+    resize_vertical: wgpu::ComputePipeline,
+    resize_horizontal: wgpu::ComputePipeline,
+    overlay: wgpu::ComputePipeline,
+    /// Largest buffer bound to a shader, in bytes.
+    max_buffer_size: u64,
+    /// Held during an operation, as error scopes are shared by every thread using the device.
+    lock: Mutex<()>,
+}
+
+///
+/// Selects the GPU when `IMAGE_BACKEND` is `gpu`. Without a usable hardware adapter the CPU is
+/// kept. Called once on startup, before any image is processed.
+///
+pub fn init() {
+    let is_selected = env::var("IMAGE_BACKEND").is_ok_and(|value| value.trim() == "gpu");
+    let gpu = match is_selected {
+        true => match pollster::block_on(Gpu::new()) {
+            Ok(gpu) => Some(gpu),
+            Err(error) => {
+                log::warn!("GPU unavailable, processing images on the CPU. {}", error);
+                None
+            }
+        },
+        false => None,
+    };
+    let _ = GPU.set(gpu);
+}
+
+pub fn gpu() -> Option<&'static Gpu> {
+    GPU.get().and_then(Option::as_ref)
+}
+
+///
+/// `DynamicImage::resize_exact` on the GPU, or `None` to resize on the CPU: without a GPU, for
+/// small images, for empty or unchanged dimensions, for color types with more than 8 bits per
+/// channel and when the GPU fails.
+///
+pub fn resize_exact(
+    image: &DynamicImage,
+    width: u32,
+    height: u32,
+    filter: FilterType,
+) -> Option<DynamicImage> {
+    let gpu = gpu()?;
+    if (image.width() as u64 * image.height() as u64) < MIN_GPU_PIXELS
+        || width == 0
+        || height == 0
+        || (width, height) == (image.width(), image.height())
+    {
+        return None;
+    }
+
+    let rgba = match image {
+        DynamicImage::ImageRgba8(rgba) => Cow::Borrowed(rgba),
+        DynamicImage::ImageRgb8(_) | DynamicImage::ImageLuma8(_) | DynamicImage::ImageLumaA8(_) => {
+            Cow::Owned(image.to_rgba8())
+        }
+        _ => return None,
+    };
+    let resized = match gpu.resize(&rgba, width, height, filter) {
+        Ok(resized) => DynamicImage::ImageRgba8(resized),
+        Err(error) => {
+            log::warn!("GPU resize failed, resizing on the CPU. Error: {}", error);
+            return None;
+        }
+    };
+
+    // The color type is kept, as the CPU resize does.
+    Some(match image {
+        DynamicImage::ImageRgb8(_) => DynamicImage::ImageRgb8(resized.to_rgb8()),
+        DynamicImage::ImageLuma8(_) => DynamicImage::ImageLuma8(resized.to_luma8()),
+        DynamicImage::ImageLumaA8(_) => DynamicImage::ImageLumaA8(resized.to_luma_alpha8()),
+        _ => resized,
+    })
+}
+
+///
+/// `imageops::overlay` on the GPU. Returns false to composite on the CPU instead, see
+/// `resize_exact`.
+///
+pub fn overlay(canvas: &mut RgbaImage, top: &RgbaImage, x: i64, y: i64) -> bool {
+    let gpu = match gpu() {
+        Some(gpu) if top.width() as u64 * top.height() as u64 >= MIN_GPU_PIXELS => gpu,
+        _ => return false,
+    };
+
+    match gpu.overlay(canvas, top, x, y) {
+        Ok(()) => true,
+        Err(error) => {
+            log::warn!(
+                "GPU overlay failed, compositing on the CPU. Error: {}",
+                error
+            );
+            false
+        }
+    }
+}
+
+///
+/// Shader code and support radius of a resize filter, as defined by `image`.
+///
+fn filter_kernel(filter: FilterType) -> (u32, f32) {
+    match filter {
+        FilterType::Nearest => (0, 0.0),
+        FilterType::Triangle => (1, 1.0),
+        FilterType::CatmullRom => (2, 2.0),
+        FilterType::Gaussian => (3, 3.0),
+        FilterType::Lanczos3 => (4, 3.0),
+    }
+}
+
+impl Gpu {
+    async fn new() -> Result<Self, String> {
+        let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
+            backends: wgpu::Backends::PRIMARY,
+            ..Default::default()
+        });
+        let adapter = instance
+            .request_adapter(&wgpu::RequestAdapterOptions {
+                power_preference: wgpu::PowerPreference::HighPerformance,
+                force_fallback_adapter: false,
+                compatible_surface: None,
+            })
+            .await
+            .ok_or("No GPU adapter found.")?;
+
+        // Software rasterizers would only move the work to slower CPU code.
+        let info = adapter.get_info();
+        if info.device_type == wgpu::DeviceType::Cpu {
+            return Err(format!("{} is a software adapter.", info.name));
+        }
+
+        let limits = adapter.limits();
+        let (device, queue) = adapter
+            .request_device(
+                &wgpu::DeviceDescriptor {
+                    label: Some("image_utils"),
+                    required_features: wgpu::Features::empty(),
+                    required_limits: limits.clone(),
+                },
+                None,
+            )
+            .await
+            .map_err(|error| error.to_string())?;
+        device.on_uncaptured_error(Box::new(|error| {
+            log::error!("Uncaptured GPU error: {}", error);
+        }));
+
+        device.push_error_scope(wgpu::ErrorFilter::Validation);
+        let module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("image_utils"),
+            source: wgpu::ShaderSource::Wgsl(Cow::Borrowed(SHADER)),
+        });
+        let pipeline = |entry_point| {
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some(entry_point),
+                layout: None,
+                module: &module,
+                entry_point,
+                compilation_options: Default::default(),
+            })
+        };
+        let resize_vertical = pipeline("resize_vertical");
+        let resize_horizontal = pipeline("resize_horizontal");
+        let overlay = pipeline("overlay");
+        if let Some(error) = device.pop_error_scope().await {
+            return Err(format!("Failed to compile shaders. Error: {}", error));
+        }
+
+        log::info!(
+            "Processing images on the GPU: {} ({:?})",
+            info.name,
+            info.backend
+        );
+        Ok(Self {
+            device,
+            queue,
+            resize_vertical,
+            resize_horizontal,
+            overlay,
+            max_buffer_size: limits
+                .max_buffer_size
+                .min(limits.max_storage_buffer_binding_size as u64),
+            lock: Mutex::new(()),
+        })
+    }
+
+    ///
+    /// Resizes `image` to exactly `width` x `height` with the separable filters of
+    /// `imageops::resize`. Results may differ from the CPU by rounding.
+    ///
+    pub fn resize(
+        &self,
+        image: &RgbaImage,
+        width: u32,
+        height: u32,
+        filter: FilterType,
+    ) -> Result<RgbaImage, GpuError> {
+        let (src_width, src_height) = image.dimensions();
+        let columns_size = src_width as u64 * height as u64 * 16;
+        let output_size = width as u64 * height as u64 * 4;
+        self.check_sizes(&[image.as_raw().len() as u64, columns_size, output_size])?;
+
+        let (filter, support) = filter_kernel(filter);
+        let bytes = self.run(|| {
+            let params = self.uniform(&[
+                src_width,
+                src_height,
+                width,
+                height,
+                filter,
+                support.to_bits(),
+                0,
+                0,
+            ]);
+            let input = self.input_buffer(image.as_raw(), wgpu::BufferUsages::STORAGE);
+            let columns = self.output_buffer(columns_size, wgpu::BufferUsages::STORAGE);
+            let output = self.output_buffer(
+                output_size,
+                wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+            );
+
+            let vertical = self.bind_group(
+                &self.resize_vertical,
+                &[(0, &params), (1, &input), (2, &columns)],
+            );
+            let horizontal = self.bind_group(
+                &self.resize_horizontal,
+                &[(0, &params), (3, &columns), (4, &output)],
+            );
+            let mut encoder = self.device.create_command_encoder(&Default::default());
+            dispatch(
+                &mut encoder,
+                &self.resize_vertical,
+                &vertical,
+                (src_width, height),
+            );
+            dispatch(
+                &mut encoder,
+                &self.resize_horizontal,
+                &horizontal,
+                (width, height),
+            );
+            self.read_back(encoder, &output, output_size)
+        })?;
+
+        RgbaImage::from_raw(width, height, bytes)
+            .ok_or_else(|| GpuError::Device("Resized image has the wrong size.".to_string()))
+    }
+
+    ///
+    /// Composites `top` onto `canvas` at `x`, `y` like `imageops::overlay`.
+    ///
+    pub fn overlay(
+        &self,
+        canvas: &mut RgbaImage,
+        top: &RgbaImage,
+        x: i64,
+        y: i64,
+    ) -> Result<(), GpuError> {
+        let (x, y) = match (i32::try_from(x), i32::try_from(y)) {
+            (Ok(x), Ok(y)) => (x, y),
+            _ => return Err(GpuError::TooLarge),
+        };
+        let canvas_size = canvas.as_raw().len() as u64;
+        self.check_sizes(&[canvas_size, top.as_raw().len() as u64])?;
+
+        let bytes = self.run(|| {
+            let params = self.uniform(&[
+                canvas.width(),
+                canvas.height(),
+                top.width(),
+                top.height(),
+                x as u32,
+                y as u32,
+                0,
+                0,
+            ]);
+            let canvas_buffer = self.input_buffer(
+                canvas.as_raw(),
+                wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+            );
+            let top_buffer = self.input_buffer(top.as_raw(), wgpu::BufferUsages::STORAGE);
+
+            let bind_group = self.bind_group(
+                &self.overlay,
+                &[(5, &params), (6, &canvas_buffer), (7, &top_buffer)],
+            );
+            let mut encoder = self.device.create_command_encoder(&Default::default());
+            dispatch(
+                &mut encoder,
+                &self.overlay,
+                &bind_group,
+                (top.width(), top.height()),
+            );
+            self.read_back(encoder, &canvas_buffer, canvas_size)
+        })?;
+
+        canvas.copy_from_slice(&bytes);
+        Ok(())
+    }
+
+    fn check_sizes(&self, sizes: &[u64]) -> Result<(), GpuError> {
+        match sizes.iter().all(|size| *size <= self.max_buffer_size) {
+            true => Ok(()),
+            false => Err(GpuError::TooLarge),
+        }
+    }
+
+    ///
+    /// Runs `operation` one at a time, failing on validation errors and exhausted GPU memory.
+    ///
+    fn run<T>(&self, operation: impl FnOnce() -> Result<T, GpuError>) -> Result<T, GpuError> {
+        let _lock = self.lock.lock().unwrap_or_else(|error| error.into_inner());
+        self.device.push_error_scope(wgpu::ErrorFilter::OutOfMemory);
+        self.device.push_error_scope(wgpu::ErrorFilter::Validation);
+        let result = operation();
+        let validation_error = pollster::block_on(self.device.pop_error_scope());
+        let memory_error = pollster::block_on(self.device.pop_error_scope());
+
+        match validation_error.or(memory_error) {
+            Some(error) => Err(GpuError::Device(error.to_string())),
+            None => result,
+        }
+    }
+
+    fn uniform(&self, values: &[u32; 8]) -> wgpu::Buffer {
+        let contents: Vec<u8> = values
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect();
+        self.input_buffer(&contents, wgpu::BufferUsages::UNIFORM)
+    }
+
+    fn input_buffer(&self, contents: &[u8], usage: wgpu::BufferUsages) -> wgpu::Buffer {
+        self.device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: None,
+                contents,
+                usage,
+            })
+    }
+
+    fn output_buffer(&self, size: u64, usage: wgpu::BufferUsages) -> wgpu::Buffer {
+        self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size,
+            usage,
+            mapped_at_creation: false,
+        })
+    }
+
+    fn bind_group(
+        &self,
+        pipeline: &wgpu::ComputePipeline,
+        buffers: &[(u32, &wgpu::Buffer)],
+    ) -> wgpu::BindGroup {
+        let entries: Vec<wgpu::BindGroupEntry> = buffers
+            .iter()
+            .map(|(binding, buffer)| wgpu::BindGroupEntry {
+                binding: *binding,
+                resource: buffer.as_entire_binding(),
+            })
+            .collect();
+        self.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.get_bind_group_layout(0),
+            entries: &entries,
+        })
+    }
+
+    ///
+    /// Submits `encoder` and copies the first `size` bytes of `buffer` back from the GPU.
+    ///
+    fn read_back(
+        &self,
+        mut encoder: wgpu::CommandEncoder,
+        buffer: &wgpu::Buffer,
+        size: u64,
+    ) -> Result<Vec<u8>, GpuError> {
+        let staging = self.output_buffer(
+            size,
+            wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+        );
+        encoder.copy_buffer_to_buffer(buffer, 0, &staging, 0, size);
+        self.queue.submit(Some(encoder.finish()));
+
+        let slice = staging.slice(..);
+        let (sender, receiver) = mpsc::channel();
+        slice.map_async(wgpu::MapMode::Read, move |result| {
+            let _ = sender.send(result);
+        });
+        self.device.poll(wgpu::Maintain::Wait);
+        match receiver.recv() {
+            Ok(Ok(())) => {}
+            Ok(Err(error)) => return Err(GpuError::Device(error.to_string())),
+            Err(error) => return Err(GpuError::Device(error.to_string())),
+        }
+
+        let bytes = slice.get_mapped_range().to_vec();
+        staging.unmap();
+        Ok(bytes)
+    }
+}
+
+fn dispatch(
+    encoder: &mut wgpu::CommandEncoder,
+    pipeline: &wgpu::ComputePipeline,
+    bind_group: &wgpu::BindGroup,
+    (width, height): (u32, u32),
+) {
+    let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+        label: None,
+        timestamp_writes: None,
+    });
+    pass.set_pipeline(pipeline);
+    pass.set_bind_group(0, bind_group, &[]);
+    pass.dispatch_workgroups(
+        width.div_ceil(WORKGROUP_SIZE),
+        height.div_ceil(WORKGROUP_SIZE),
+        1,
+    );
+}
+
+#[cfg(test)]
+pub mod test {
+    use image::imageops::FilterType;
+    use image::{Rgba, RgbaImage};
+
+    use super::Gpu;
+
+    /// Compares with the CPU where a GPU is available. Passes without one.
+    #[test]
+    pub fn test_gpu_matches_cpu() {
+        let gpu = match pollster::block_on(Gpu::new()) {
+            Ok(gpu) => gpu,
+            Err(_) => return,
+        };
+
+        let image = RgbaImage::from_fn(64, 48, |x, y| {
+            Rgba([
+                (x * 4) as u8,
+                (y * 5) as u8,
+                ((x + y) * 2) as u8,
+                255 - x as u8,
+            ])
+        });
+        for filter in [FilterType::Triangle, FilterType::Lanczos3] {
+            let expected = image::imageops::resize(&image, 20, 15, filter);
+            let resized = gpu.resize(&image, 20, 15, filter).unwrap();
+            let max_difference = expected
+                .as_raw()
+                .iter()
+                .zip(resized.as_raw())
+                .map(|(a, b)| a.abs_diff(*b))
+                .max();
+            assert!(
+                max_difference <= Some(1),
+                "{:?}: {:?}",
+                filter,
+                max_difference
+            );
+        }
+
+        let top = RgbaImage::from_pixel(8, 8, Rgba([255, 0, 0, 128]));
+        let mut expected = RgbaImage::from_pixel(16, 16, Rgba([0, 0, 255, 255]));
+        let mut canvas = expected.clone();
+        image::imageops::overlay(&mut expected, &top, 12, -2);
+        gpu.overlay(&mut canvas, &top, 12, -2).unwrap();
+        assert_eq!(expected, canvas);
+    }
+}
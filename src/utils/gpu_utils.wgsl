@@ -0,0 +1,195 @@
+// Compute shaders of `gpu_utils`. Images are arrays of RGBA8 pixels packed into `u32`, red in
+// the lowest byte, as laid out by `RgbaImage`.
+//
+// Resizing is separable like `image::imageops::resize`: `resize_vertical` resamples columns into
+// an unclamped float image, `resize_horizontal` resamples its rows and rounds back to RGBA8.
+
+struct ResizeParams {
+    src_width: u32,
+    src_height: u32,
+    dst_width: u32,
+    dst_height: u32,
+    // 0 nearest, 1 triangle, 2 catmull-rom, 3 gaussian, 4 lanczos3.
+    filter_type: u32,
+    support: f32,
+    padding_0: u32,
+    padding_1: u32,
+}
+
+struct OverlayParams {
+    canvas_width: u32,
+    canvas_height: u32,
+    top_width: u32,
+    top_height: u32,
+    x: i32,
+    y: i32,
+    padding_0: u32,
+    padding_1: u32,
+}
+
+@group(0) @binding(0) var<uniform> resize_params: ResizeParams;
+@group(0) @binding(1) var<storage, read> resize_input: array<u32>;
+@group(0) @binding(2) var<storage, read_write> resize_columns: array<vec4<f32>>;
+@group(0) @binding(3) var<storage, read> resize_rows: array<vec4<f32>>;
+@group(0) @binding(4) var<storage, read_write> resize_output: array<u32>;
+
+@group(0) @binding(5) var<uniform> overlay_params: OverlayParams;
+@group(0) @binding(6) var<storage, read_write> overlay_canvas: array<u32>;
+@group(0) @binding(7) var<storage, read> overlay_top: array<u32>;
+
+const PI: f32 = 3.14159265358979;
+
+fn unpack(pixel: u32) -> vec4<f32> {
+    return vec4<f32>(
+        f32(pixel & 0xffu),
+        f32((pixel >> 8u) & 0xffu),
+        f32((pixel >> 16u) & 0xffu),
+        f32(pixel >> 24u),
+    );
+}
+
+fn pack(channels: vec4<u32>) -> u32 {
+    return channels.x | (channels.y << 8u) | (channels.z << 16u) | (channels.w << 24u);
+}
+
+fn sinc(t: f32) -> f32 {
+    if (t == 0.0) {
+        return 1.0;
+    }
+    let a = t * PI;
+    return sin(a) / a;
+}
+
+// Mitchell-Netravali cubic with b = 0 and c = 0.5.
+fn catmull_rom(x: f32) -> f32 {
+    let a = abs(x);
+    if (a < 1.0) {
+        return (9.0 * a * a * a - 15.0 * a * a + 6.0) / 6.0;
+    }
+    if (a < 2.0) {
+        return (-3.0 * a * a * a + 15.0 * a * a - 24.0 * a + 12.0) / 6.0;
+    }
+    return 0.0;
+}
+
+fn kernel(x: f32) -> f32 {
+    switch resize_params.filter_type {
+        case 0u: {
+            return select(0.0, 1.0, abs(x) <= 0.5);
+        }
+        case 1u: {
+            return max(1.0 - abs(x), 0.0);
+        }
+        case 2u: {
+            return catmull_rom(x);
+        }
+        case 3u: {
+            // Gaussian with a radius of 0.5. Weights are normalized, so the scale is left out.
+            return exp(-2.0 * x * x);
+        }
+        default: {
+            return select(0.0, sinc(x) * sinc(x / 3.0), abs(x) < 3.0);
+        }
+    }
+}
+
+struct Window {
+    left: u32,
+    right: u32,
+    center: f32,
+    scale: f32,
+}
+
+// Input pixels `left..right` contributing to output pixel `index` of a side resized from
+// `src_length` to `dst_length`.
+fn window(index: u32, src_length: u32, dst_length: u32) -> Window {
+    let ratio = f32(src_length) / f32(dst_length);
+    let scale = max(ratio, 1.0);
+    let support = resize_params.support * scale;
+    let center = (f32(index) + 0.5) * ratio;
+    let left = u32(clamp(floor(center - support), 0.0, f32(src_length - 1u)));
+    let right = u32(clamp(ceil(center + support), f32(left + 1u), f32(src_length)));
+    return Window(left, right, center - 0.5, scale);
+}
+
+@compute @workgroup_size(8, 8)
+fn resize_vertical(@builtin(global_invocation_id) id: vec3<u32>) {
+    let width = resize_params.src_width;
+    if (id.x >= width || id.y >= resize_params.dst_height) {
+        return;
+    }
+
+    let w = window(id.y, resize_params.src_height, resize_params.dst_height);
+    var sum = 0.0;
+    var color = vec4<f32>(0.0);
+    for (var i = w.left; i < w.right; i = i + 1u) {
+        let weight = kernel((f32(i) - w.center) / w.scale);
+        sum = sum + weight;
+        color = color + weight * unpack(resize_input[i * width + id.x]);
+    }
+    if (sum == 0.0) {
+        color = unpack(resize_input[w.left * width + id.x]);
+    } else {
+        color = color / sum;
+    }
+    resize_columns[id.y * width + id.x] = color;
+}
+
+@compute @workgroup_size(8, 8)
+fn resize_horizontal(@builtin(global_invocation_id) id: vec3<u32>) {
+    let width = resize_params.src_width;
+    if (id.x >= resize_params.dst_width || id.y >= resize_params.dst_height) {
+        return;
+    }
+
+    let w = window(id.x, width, resize_params.dst_width);
+    var sum = 0.0;
+    var color = vec4<f32>(0.0);
+    for (var i = w.left; i < w.right; i = i + 1u) {
+        let weight = kernel((f32(i) - w.center) / w.scale);
+        sum = sum + weight;
+        color = color + weight * resize_rows[id.y * width + i];
+    }
+    if (sum == 0.0) {
+        color = resize_rows[id.y * width + w.left];
+    } else {
+        color = color / sum;
+    }
+    let channels = vec4<u32>(round(clamp(color, vec4<f32>(0.0), vec4<f32>(255.0))));
+    resize_output[id.y * resize_params.dst_width + id.x] = pack(channels);
+}
+
+// Source-over compositing of non-premultiplied colors, like `Rgba::blend`.
+@compute @workgroup_size(8, 8)
+fn overlay(@builtin(global_invocation_id) id: vec3<u32>) {
+    if (id.x >= overlay_params.top_width || id.y >= overlay_params.top_height) {
+        return;
+    }
+    let x = i32(id.x) + overlay_params.x;
+    let y = i32(id.y) + overlay_params.y;
+    if (x < 0 || y < 0 || x >= i32(overlay_params.canvas_width)
+        || y >= i32(overlay_params.canvas_height)) {
+        return;
+    }
+
+    let index = u32(y) * overlay_params.canvas_width + u32(x);
+    let top_pixel = overlay_top[id.y * overlay_params.top_width + id.x];
+    let top_alpha = top_pixel >> 24u;
+    if (top_alpha == 0u) {
+        return;
+    }
+    if (top_alpha == 255u) {
+        overlay_canvas[index] = top_pixel;
+        return;
+    }
+
+    let fg = unpack(top_pixel) / 255.0;
+    let bg = unpack(overlay_canvas[index]) / 255.0;
+    let alpha = bg.a + fg.a - bg.a * fg.a;
+    if (alpha == 0.0) {
+        return;
+    }
+    let rgb = (fg.rgb * fg.a + bg.rgb * bg.a * (1.0 - fg.a)) / alpha;
+    // Truncated like the casts of `Rgba::blend`.
+    overlay_canvas[index] = pack(vec4<u32>(vec4<f32>(rgb, alpha) * 255.0));
+}
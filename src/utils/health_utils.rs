@@ -0,0 +1,172 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use serde::Serialize;
+
+/// Outcomes kept per endpoint. Older outcomes no longer count towards the error rate.
+const OUTCOME_WINDOW: usize = 20;
+
+/// Outcomes needed before the error rate counts, so a single early failure doesn't trip an
+/// endpoint.
+const MIN_OUTCOMES: usize = 5;
+
+/// Weight of the newest latency in the moving average.
+const LATENCY_WEIGHT: f64 = 0.2;
+
+///
+/// Limits beyond which an endpoint is considered unhealthy.
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HealthThresholds {
+    /// Share of failed outcomes, in percent, at or above which the endpoint is unhealthy. 0
+    /// disables the check.
+    pub max_error_percent: u32,
+    /// Average latency above which the endpoint is unhealthy. `None` disables the check.
+    pub max_latency: Option<Duration>,
+}
+
+///
+/// Recent outcomes of an endpoint: sends and responses, with the latency of responses.
+///
+#[derive(Debug, Clone, Default)]
+pub struct HealthScore {
+    outcomes: VecDeque<bool>,
+    latency_millis: Option<f64>,
+}
+
+///
+/// Snapshot of a `HealthScore` for the admin API.
+///
+#[derive(Debug, Clone, Serialize)]
+pub struct HealthSummary {
+    pub outcomes: usize,
+    pub error_percent: u32,
+    pub latency_millis: Option<u64>,
+}
+
+impl HealthScore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    ///
+    /// Records an outcome. `latency` is the time from sending a task to its response and only
+    /// given for responses.
+    ///
+    pub fn record(&mut self, succeeded: bool, latency: Option<Duration>) {
+        if self.outcomes.len() == OUTCOME_WINDOW {
+            self.outcomes.pop_front();
+        }
+        self.outcomes.push_back(succeeded);
+
+        if let Some(latency) = latency {
+            let millis = latency.as_secs_f64() * 1000.0;
+            self.latency_millis = Some(match self.latency_millis {
+                Some(average) => average + LATENCY_WEIGHT * (millis - average),
+                None => millis,
+            });
+        }
+    }
+
+    ///
+    /// Percentage of failed outcomes in the window, or 0 while there are fewer than
+    /// `MIN_OUTCOMES`.
+    ///
+    pub fn error_percent(&self) -> u32 {
+        if self.outcomes.len() < MIN_OUTCOMES {
+            return 0;
+        }
+
+        let failed = self
+            .outcomes
+            .iter()
+            .filter(|succeeded| !**succeeded)
+            .count();
+        (failed * 100 / self.outcomes.len()) as u32
+    }
+
+    pub fn latency(&self) -> Option<Duration> {
+        self.latency_millis
+            .map(|millis| Duration::from_secs_f64(millis / 1000.0))
+    }
+
+    pub fn is_healthy(&self, thresholds: &HealthThresholds) -> bool {
+        let error_rate_ok = thresholds.max_error_percent == 0
+            || self.error_percent() < thresholds.max_error_percent;
+        let latency_ok = match (thresholds.max_latency, self.latency()) {
+            (Some(max_latency), Some(latency)) => latency <= max_latency,
+            _ => true,
+        };
+        error_rate_ok && latency_ok
+    }
+
+    pub fn summary(&self) -> HealthSummary {
+        HealthSummary {
+            outcomes: self.outcomes.len(),
+            error_percent: self.error_percent(),
+            latency_millis: self.latency_millis.map(|millis| millis as u64),
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::time::Duration;
+
+    use super::{HealthScore, HealthThresholds};
+
+    const THRESHOLDS: HealthThresholds = HealthThresholds {
+        max_error_percent: 50,
+        max_latency: Some(Duration::from_secs(10)),
+    };
+
+    #[test]
+    pub fn test_error_rate() {
+        let mut score = HealthScore::new();
+        for _ in 0..4 {
+            score.record(false, None);
+        }
+        // Too few outcomes to judge.
+        assert_eq!(0, score.error_percent());
+        assert!(score.is_healthy(&THRESHOLDS));
+
+        score.record(true, None);
+        assert_eq!(80, score.error_percent());
+        assert!(!score.is_healthy(&THRESHOLDS));
+
+        // Failures leave the window.
+        for _ in 0..20 {
+            score.record(true, None);
+        }
+        assert_eq!(0, score.error_percent());
+        assert!(score.is_healthy(&THRESHOLDS));
+
+        let disabled = HealthThresholds {
+            max_error_percent: 0,
+            max_latency: None,
+        };
+        for _ in 0..20 {
+            score.record(false, None);
+        }
+        assert!(score.is_healthy(&disabled));
+    }
+
+    #[test]
+    pub fn test_latency() {
+        let mut score = HealthScore::new();
+        assert_eq!(None, score.latency());
+
+        score.record(true, Some(Duration::from_secs(5)));
+        assert_eq!(Some(Duration::from_secs(5)), score.latency());
+        assert!(score.is_healthy(&THRESHOLDS));
+
+        // A single slow response moves the average by a fifth.
+        score.record(true, Some(Duration::from_secs(30)));
+        assert_eq!(Some(Duration::from_secs(10)), score.latency());
+        assert!(score.is_healthy(&THRESHOLDS));
+
+        score.record(true, Some(Duration::from_secs(30)));
+        assert!(!score.is_healthy(&THRESHOLDS));
+        assert_eq!(14_000, score.summary().latency_millis.unwrap());
+    }
+}
@@ -1 +1,942 @@
-// pub fn
+use std::io::{BufRead, BufReader, Cursor, Read, Seek};
+use std::path::Path;
+
+use image::codecs::jpeg::JpegEncoder;
+use image::error::{DecodingError, ImageFormatHint, LimitError, LimitErrorKind};
+use image::imageops::{self, FilterType};
+use image::io::{Limits, Reader};
+use image::{
+    DynamicImage, GrayImage, ImageDecoder, ImageError, ImageFormat, ImageResult, Rgba, RgbaImage,
+};
+use jxl_oxide::integration::JxlDecoder;
+use serde::Serialize;
+
+/// Mask pixels brighter than this are treated as foreground.
+const MASK_THRESHOLD: u8 = 127;
+
+/// Largest accepted width or height of an image.
+pub const MAX_IMAGE_SIDE: u32 = 16384;
+
+/// Largest accepted number of pixels of an image.
+pub const MAX_IMAGE_PIXELS: u64 = 64_000_000;
+
+/// Largest allocation a decoder may make, enough for `MAX_IMAGE_PIXELS` of 16 bit RGBA.
+const MAX_DECODE_ALLOC: u64 = MAX_IMAGE_PIXELS * 8;
+
+/// Memory per pixel held by an image job at its peak: the decoded image, up to 8 bytes for 16 bit
+/// RGBA, and an 8 bit RGBA copy for conversion or resizing.
+const JOB_BYTES_PER_PIXEL: u64 = 12;
+
+///
+/// Returns an error when `width` x `height` exceeds `MAX_IMAGE_SIDE` or `MAX_IMAGE_PIXELS`.
+///
+pub fn check_dimensions(width: u32, height: u32) -> ImageResult<()> {
+    if width == 0
+        || height == 0
+        || width > MAX_IMAGE_SIDE
+        || height > MAX_IMAGE_SIDE
+        || width as u64 * height as u64 > MAX_IMAGE_PIXELS
+    {
+        return Err(ImageError::Limits(LimitError::from_kind(
+            LimitErrorKind::DimensionError,
+        )));
+    }
+    Ok(())
+}
+
+/// Signature of a bare JPEG XL codestream.
+const JXL_CODESTREAM_SIGNATURE: &[u8] = &[0xFF, 0x0A];
+
+/// Signature of a JPEG XL container.
+const JXL_CONTAINER_SIGNATURE: &[u8] = &[
+    0x00, 0x00, 0x00, 0x0C, 0x4A, 0x58, 0x4C, 0x20, 0x0D, 0x0A, 0x87, 0x0A,
+];
+
+///
+/// Whether `data` starts with a JPEG XL signature. JPEG XL is not decoded by the `image` crate.
+///
+pub fn is_jxl(data: &[u8]) -> bool {
+    data.starts_with(JXL_CODESTREAM_SIGNATURE) || data.starts_with(JXL_CONTAINER_SIGNATURE)
+}
+
+///
+/// Detects the format of encoded image `data` from its signature. Returns the usual file
+/// extension of the format, e.g. `jpg`, `png`, `webp` or `jxl`.
+///
+pub fn detect_format(data: &[u8]) -> Option<&'static str> {
+    if is_jxl(data) {
+        return Some("jxl");
+    }
+
+    let format = image::guess_format(data).ok()?;
+    format.extensions_str().first().copied()
+}
+
+///
+/// Reads dimensions of encoded image `data` from its header, without decoding pixels, and checks
+/// them with `check_dimensions`.
+///
+pub fn read_dimensions(data: &[u8]) -> ImageResult<(u32, u32)> {
+    let (width, height) = if is_jxl(data) {
+        jxl_decoder(Cursor::new(data))?.dimensions()
+    } else {
+        Reader::new(Cursor::new(data))
+            .with_guessed_format()?
+            .into_dimensions()?
+    };
+    check_dimensions(width, height)?;
+    Ok((width, height))
+}
+
+///
+/// Same as `read_dimensions` for the image at `path`.
+///
+pub fn read_file_dimensions<P: AsRef<Path>>(path: P) -> ImageResult<(u32, u32)> {
+    let (width, height) = if is_jxl_file(&path)? {
+        jxl_decoder(BufReader::new(std::fs::File::open(path)?))?.dimensions()
+    } else {
+        Reader::open(path)?
+            .with_guessed_format()?
+            .into_dimensions()?
+    };
+    check_dimensions(width, height)?;
+    Ok((width, height))
+}
+
+///
+/// Estimates the peak memory of an image job decoding and processing encoded image `data`: the
+/// data, the decoded pixels and a converted or resized copy. Data whose dimensions can't be read
+/// counts with its size only, as decoding it fails before allocating pixels.
+///
+pub fn estimate_memory(data: &[u8]) -> u64 {
+    let pixels = read_dimensions(data).map_or(0, |(width, height)| width as u64 * height as u64);
+    data.len() as u64 + pixels * JOB_BYTES_PER_PIXEL
+}
+
+///
+/// Same as `estimate_memory` for the image at `path`.
+///
+pub fn estimate_file_memory<P: AsRef<Path>>(path: P) -> u64 {
+    let size = std::fs::metadata(&path).map_or(0, |metadata| metadata.len());
+    let pixels =
+        read_file_dimensions(path).map_or(0, |(width, height)| width as u64 * height as u64);
+    size + pixels * JOB_BYTES_PER_PIXEL
+}
+
+///
+/// Decodes image `data` with dimensions checked before any pixel is allocated. Use it instead of
+/// `image::load_from_memory` for data which is not trusted; small files can declare huge images.
+///
+pub fn load_from_memory(data: &[u8]) -> ImageResult<DynamicImage> {
+    read_dimensions(data)?;
+    if is_jxl(data) {
+        return decode_jxl(jxl_decoder(Cursor::new(data))?);
+    }
+    decode_limited(Reader::new(Cursor::new(data)).with_guessed_format()?)
+}
+
+///
+/// Same as `load_from_memory` for the image at `path`. Use it instead of `image::open`.
+///
+pub fn open<P: AsRef<Path>>(path: P) -> ImageResult<DynamicImage> {
+    read_file_dimensions(&path)?;
+    if is_jxl_file(&path)? {
+        return decode_jxl(jxl_decoder(BufReader::new(std::fs::File::open(path)?))?);
+    }
+    decode_limited(Reader::open(path)?.with_guessed_format()?)
+}
+
+///
+/// Decodes image `data` and encodes it again in `format`, for consumers which can't decode the
+/// original format.
+///
+pub fn transcode(data: &[u8], format: ImageFormat) -> ImageResult<Vec<u8>> {
+    let image = load_from_memory(data)?;
+    let mut bytes = Cursor::new(Vec::new());
+    image.write_to(&mut bytes, format)?;
+    Ok(bytes.into_inner())
+}
+
+fn decode_limits() -> Limits {
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_IMAGE_SIDE);
+    limits.max_image_height = Some(MAX_IMAGE_SIDE);
+    limits.max_alloc = Some(MAX_DECODE_ALLOC);
+    limits
+}
+
+fn decode_limited<R: BufRead + Seek>(mut reader: Reader<R>) -> ImageResult<DynamicImage> {
+    reader.limits(decode_limits());
+    reader.decode()
+}
+
+fn is_jxl_file<P: AsRef<Path>>(path: P) -> ImageResult<bool> {
+    let mut header = Vec::with_capacity(JXL_CONTAINER_SIGNATURE.len());
+    std::fs::File::open(path)?
+        .take(JXL_CONTAINER_SIGNATURE.len() as u64)
+        .read_to_end(&mut header)?;
+    Ok(is_jxl(&header))
+}
+
+fn jxl_decoder<R: Read>(reader: R) -> ImageResult<JxlDecoder<R>> {
+    JxlDecoder::new(reader).map_err(|error| {
+        ImageError::Decoding(DecodingError::new(
+            ImageFormatHint::Name("JPEG XL".to_string()),
+            error,
+        ))
+    })
+}
+
+fn decode_jxl<R: Read>(mut decoder: JxlDecoder<R>) -> ImageResult<DynamicImage> {
+    decoder.set_limits(decode_limits())?;
+    DynamicImage::from_decoder(decoder)
+}
+
+///
+/// Scores of comparing outputs of two processing results.
+///
+#[derive(Debug, Serialize)]
+pub struct ComparisonScores {
+    /// Intersection over union of the foreground of both masks. `1.0` when both masks are empty.
+    pub mask_iou: f64,
+    /// Mean absolute per channel difference of the processed images, from `0.0` to `255.0`.
+    pub mean_pixel_diff: f64,
+}
+
+///
+/// Returns intersection over union of foreground pixels of masks `a` and `b`. `b` is resized to
+/// the dimensions of `a` when they differ.
+///
+pub fn mask_iou(a: &GrayImage, b: &GrayImage) -> f64 {
+    let b = if a.dimensions() != b.dimensions() {
+        imageops::resize(b, a.width(), a.height(), FilterType::Nearest)
+    } else {
+        b.clone()
+    };
+
+    let mut intersection: u64 = 0;
+    let mut union: u64 = 0;
+    for (pixel_a, pixel_b) in a.pixels().zip(b.pixels()) {
+        let in_a = pixel_a.0[0] > MASK_THRESHOLD;
+        let in_b = pixel_b.0[0] > MASK_THRESHOLD;
+
+        if in_a && in_b {
+            intersection += 1;
+        }
+        if in_a || in_b {
+            union += 1;
+        }
+    }
+
+    if union == 0 {
+        return 1.0;
+    }
+    intersection as f64 / union as f64
+}
+
+///
+/// Returns heatmap of per pixel differences between `a` and `b`, from blue for equal pixels to
+/// red for the largest difference, together with the mean absolute per channel difference. `b`
+/// is resized to the dimensions of `a` when they differ.
+///
+pub fn diff_heatmap(a: &RgbaImage, b: &RgbaImage) -> (RgbaImage, f64) {
+    let b = if a.dimensions() != b.dimensions() {
+        imageops::resize(b, a.width(), a.height(), FilterType::Triangle)
+    } else {
+        b.clone()
+    };
+
+    let mut heatmap = RgbaImage::new(a.width(), a.height());
+    let mut total: u64 = 0;
+
+    for ((pixel_a, pixel_b), heat) in a.pixels().zip(b.pixels()).zip(heatmap.pixels_mut()) {
+        let diff: u32 = pixel_a
+            .0
+            .iter()
+            .zip(pixel_b.0.iter())
+            .map(|(value_a, value_b)| value_a.abs_diff(*value_b) as u32)
+            .sum();
+        total += diff as u64;
+
+        let intensity = (diff / 4) as u8;
+        *heat = Rgba([intensity, 0, 255 - intensity, 255]);
+    }
+
+    let channels = a.width() as u64 * a.height() as u64 * 4;
+    let mean = if channels == 0 {
+        0.0
+    } else {
+        total as f64 / channels as f64
+    };
+
+    (heatmap, mean)
+}
+
+///
+/// Compares masks and processed images of two results and writes the diff heatmap of the
+/// processed images to `heatmap_path`. Blocking; run it on `ImageWorkers`.
+///
+pub fn compare_outputs(
+    mask_a: &Path,
+    mask_b: &Path,
+    processed_a: &Path,
+    processed_b: &Path,
+    heatmap_path: &Path,
+) -> image::ImageResult<ComparisonScores> {
+    let mask_iou = mask_iou(&open(mask_a)?.to_luma8(), &open(mask_b)?.to_luma8());
+
+    let (heatmap, mean_pixel_diff) = diff_heatmap(
+        &open(processed_a)?.to_rgba8(),
+        &open(processed_b)?.to_rgba8(),
+    );
+    heatmap.save(heatmap_path)?;
+
+    Ok(ComparisonScores {
+        mask_iou,
+        mean_pixel_diff,
+    })
+}
+
+///
+/// Returns dimensions of `width` x `height` scaled down to fit within `max_side`, keeping the
+/// aspect ratio. Smaller images keep their dimensions.
+///
+pub fn preview_dimensions(width: u32, height: u32, max_side: u32) -> (u32, u32) {
+    let longest = width.max(height);
+    if longest <= max_side {
+        return (width, height);
+    }
+
+    let scale = |side: u32| ((side as u64 * max_side as u64) / longest as u64).max(1) as u32;
+    (scale(width), scale(height))
+}
+
+///
+/// Returns dimensions of `width` x `height` scaled down so the shorter side is `side`, keeping
+/// the aspect ratio. Images with a shorter side below `side` keep their dimensions.
+///
+pub fn cover_dimensions(width: u32, height: u32, side: u32) -> (u32, u32) {
+    let shortest = width.min(height);
+    if shortest <= side {
+        return (width, height);
+    }
+
+    let scale = |length: u32| ((length as u64 * side as u64) / shortest as u64).max(1) as u32;
+    (scale(width), scale(height))
+}
+
+///
+/// How a preview fits into the `max_side` square.
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PreviewFit {
+    /// Scaled down to fit within the square, keeping the aspect ratio.
+    Contain,
+    /// Scaled down to cover the square and center cropped to it.
+    Cover,
+    /// Scaled down like `Contain` and centered on a square canvas. The canvas is transparent, or
+    /// white for JPEG.
+    Pad,
+}
+
+impl PreviewFit {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PreviewFit::Contain => "contain",
+            PreviewFit::Cover => "cover",
+            PreviewFit::Pad => "pad",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "contain" => Some(PreviewFit::Contain),
+            "cover" => Some(PreviewFit::Cover),
+            "pad" => Some(PreviewFit::Pad),
+            _ => None,
+        }
+    }
+}
+
+///
+/// Parses name of a resize filter: `nearest`, `triangle`, `catmullrom`, `gaussian` or
+/// `lanczos3`.
+///
+pub fn parse_filter(value: &str) -> Option<FilterType> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "nearest" => Some(FilterType::Nearest),
+        "triangle" => Some(FilterType::Triangle),
+        "catmullrom" => Some(FilterType::CatmullRom),
+        "gaussian" => Some(FilterType::Gaussian),
+        "lanczos3" => Some(FilterType::Lanczos3),
+        _ => None,
+    }
+}
+
+///
+/// Settings of generated previews.
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PreviewOptions {
+    /// Side of the square previews fit into.
+    pub max_side: u32,
+    pub filter: FilterType,
+    pub fit: PreviewFit,
+    /// Quality of JPEG previews from 1 to 100.
+    pub jpeg_quality: u8,
+}
+
+impl PreviewOptions {
+    ///
+    /// Short text identifying these settings. Previews made with other settings are stale.
+    ///
+    pub fn fingerprint(&self) -> String {
+        format!(
+            "{}:{:?}:{}:{}",
+            self.max_side,
+            self.filter,
+            self.fit.as_str(),
+            self.jpeg_quality
+        )
+        .to_ascii_lowercase()
+    }
+}
+
+///
+/// `DynamicImage::resize_exact`, on the GPU when one is selected, see `gpu_utils`.
+///
+fn resize_exact(image: &DynamicImage, width: u32, height: u32, filter: FilterType) -> DynamicImage {
+    #[cfg(feature = "gpu")]
+    {
+        if let Some(resized) = super::gpu_utils::resize_exact(image, width, height, filter) {
+            return resized;
+        }
+    }
+    image.resize_exact(width, height, filter)
+}
+
+///
+/// `imageops::overlay`, on the GPU when one is selected, see `gpu_utils`.
+///
+fn overlay(canvas: &mut RgbaImage, top: &RgbaImage, x: i64, y: i64) {
+    #[cfg(feature = "gpu")]
+    {
+        if super::gpu_utils::overlay(canvas, top, x, y) {
+            return;
+        }
+    }
+    imageops::overlay(canvas, top, x, y);
+}
+
+///
+/// Decodes image `data` and encodes it downscaled according to `options` in `format`. JPEG
+/// previews drop the alpha channel.
+///
+pub fn make_preview(
+    data: &[u8],
+    options: &PreviewOptions,
+    format: ImageFormat,
+) -> ImageResult<Vec<u8>> {
+    let image = load_from_memory(data)?;
+    let (image_width, image_height) = (image.width(), image.height());
+
+    let resize = |image: DynamicImage, (width, height): (u32, u32)| {
+        if (width, height) == (image_width, image_height) {
+            image
+        } else {
+            resize_exact(&image, width, height, options.filter)
+        }
+    };
+
+    let preview = match options.fit {
+        PreviewFit::Contain => resize(
+            image,
+            preview_dimensions(image_width, image_height, options.max_side),
+        ),
+        PreviewFit::Cover => {
+            let side = options.max_side.min(image_width).min(image_height);
+            let (width, height) = cover_dimensions(image_width, image_height, side);
+            resize(image, (width, height)).crop_imm(
+                (width - side) / 2,
+                (height - side) / 2,
+                side,
+                side,
+            )
+        }
+        PreviewFit::Pad => {
+            let (width, height) = preview_dimensions(image_width, image_height, options.max_side);
+            let resized = resize(image, (width, height)).to_rgba8();
+
+            let side = width.max(height);
+            let background = match format {
+                ImageFormat::Jpeg => Rgba([255, 255, 255, 255]),
+                _ => Rgba([0, 0, 0, 0]),
+            };
+            let mut canvas = RgbaImage::from_pixel(side, side, background);
+            overlay(
+                &mut canvas,
+                &resized,
+                ((side - width) / 2) as i64,
+                ((side - height) / 2) as i64,
+            );
+            DynamicImage::ImageRgba8(canvas)
+        }
+    };
+
+    let mut bytes = Cursor::new(Vec::new());
+    match format {
+        ImageFormat::Jpeg => {
+            let encoder = JpegEncoder::new_with_quality(&mut bytes, options.jpeg_quality);
+            DynamicImage::ImageRgb8(preview.to_rgb8()).write_with_encoder(encoder)?;
+        }
+        _ => preview.write_to(&mut bytes, format)?,
+    }
+    Ok(bytes.into_inner())
+}
+
+///
+/// Where on an image the watermark is placed.
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WatermarkPosition {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+    Center,
+}
+
+impl WatermarkPosition {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WatermarkPosition::TopLeft => "top-left",
+            WatermarkPosition::TopRight => "top-right",
+            WatermarkPosition::BottomLeft => "bottom-left",
+            WatermarkPosition::BottomRight => "bottom-right",
+            WatermarkPosition::Center => "center",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "top-left" => Some(WatermarkPosition::TopLeft),
+            "top-right" => Some(WatermarkPosition::TopRight),
+            "bottom-left" => Some(WatermarkPosition::BottomLeft),
+            "bottom-right" => Some(WatermarkPosition::BottomRight),
+            "center" => Some(WatermarkPosition::Center),
+            _ => None,
+        }
+    }
+}
+
+///
+/// Settings of the watermark composited onto outputs of free tier tasks.
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WatermarkOptions {
+    pub position: WatermarkPosition,
+    /// Opacity of the logo from 0 to 100.
+    pub opacity_percent: u8,
+    /// Largest width of the logo in percent of the image width, from 1 to 100.
+    pub scale_percent: u32,
+}
+
+///
+/// Composites `logo` onto `image`. The logo is scaled to fit `scale_percent` of the image width,
+/// keeping its aspect ratio, and kept off the edges by a 2% margin of the shorter image side.
+///
+fn overlay_watermark(
+    image: &mut RgbaImage,
+    logo: &[u8],
+    options: &WatermarkOptions,
+) -> ImageResult<()> {
+    let (width, height) = image.dimensions();
+
+    let max_logo_width = (width as u64 * options.scale_percent.clamp(1, 100) as u64 / 100) as u32;
+    let mut logo = load_from_memory(logo)?
+        .resize(max_logo_width.max(1), height, FilterType::Triangle)
+        .to_rgba8();
+    let opacity = options.opacity_percent.min(100) as u32;
+    for pixel in logo.pixels_mut() {
+        pixel[3] = (pixel[3] as u32 * opacity / 100) as u8;
+    }
+
+    let (logo_width, logo_height) = logo.dimensions();
+    let margin = width.min(height) / 50;
+    let left = margin;
+    let right = width.saturating_sub(logo_width + margin);
+    let top = margin;
+    let bottom = height.saturating_sub(logo_height + margin);
+    let (x, y) = match options.position {
+        WatermarkPosition::TopLeft => (left, top),
+        WatermarkPosition::TopRight => (right, top),
+        WatermarkPosition::BottomLeft => (left, bottom),
+        WatermarkPosition::BottomRight => (right, bottom),
+        WatermarkPosition::Center => (
+            width.saturating_sub(logo_width) / 2,
+            height.saturating_sub(logo_height) / 2,
+        ),
+    };
+    overlay(image, &logo, x as i64, y as i64);
+    Ok(())
+}
+
+///
+/// Returns dimensions of `width` x `height` scaled down to at most `max_pixels` pixels, keeping
+/// the aspect ratio. Smaller images, and any image when `max_pixels` is 0, keep their dimensions.
+///
+pub fn capped_dimensions(width: u32, height: u32, max_pixels: u64) -> (u32, u32) {
+    let pixels = width as u64 * height as u64;
+    if max_pixels == 0 || pixels <= max_pixels {
+        return (width, height);
+    }
+
+    // Rounding down keeps the product within the cap.
+    let scale = (max_pixels as f64 / pixels as f64).sqrt();
+    let side = |length: u32| ((length as f64 * scale).floor() as u32).max(1);
+    (side(width), side(height))
+}
+
+///
+/// Scales image `data` down to at most `max_pixels` pixels, see `capped_dimensions`, then
+/// composites `watermark` onto it and encodes the result as PNG. Returns `None` when neither
+/// applies, so the image can be kept as it is.
+///
+pub fn apply_output_limits(
+    data: &[u8],
+    max_pixels: u64,
+    watermark: Option<(&[u8], &WatermarkOptions)>,
+) -> ImageResult<Option<Vec<u8>>> {
+    let mut image = load_from_memory(data)?;
+    let (width, height) = (image.width(), image.height());
+    let (capped_width, capped_height) = capped_dimensions(width, height, max_pixels);
+    let capped = (capped_width, capped_height) != (width, height);
+    if !capped && watermark.is_none() {
+        return Ok(None);
+    }
+
+    if capped {
+        image = resize_exact(&image, capped_width, capped_height, FilterType::Lanczos3);
+    }
+    if let Some((logo, options)) = watermark {
+        let mut rgba = image.to_rgba8();
+        overlay_watermark(&mut rgba, logo, options)?;
+        image = DynamicImage::ImageRgba8(rgba);
+    }
+
+    let mut bytes = Cursor::new(Vec::new());
+    image.write_to(&mut bytes, ImageFormat::Png)?;
+    Ok(Some(bytes.into_inner()))
+}
+
+///
+/// Renders `images` into a grid of `cell_side` pixel cells, `columns` per row, and encodes it as
+/// PNG. Images are scaled down to fit their cell and centered on a light grey background so
+/// transparent outputs stay visible. Images which can't be decoded leave an empty cell.
+///
+pub fn contact_sheet(images: &[Vec<u8>], cell_side: u32, columns: u32) -> ImageResult<Vec<u8>> {
+    let columns = columns.max(1);
+    let rows = (images.len() as u32).div_ceil(columns).max(1);
+    let mut sheet = RgbaImage::from_pixel(
+        columns * cell_side,
+        rows * cell_side,
+        Rgba([235, 235, 235, 255]),
+    );
+
+    for (index, data) in images.iter().enumerate() {
+        let image = match load_from_memory(data) {
+            Ok(image) => image,
+            Err(_) => continue,
+        };
+
+        let (width, height) = preview_dimensions(image.width(), image.height(), cell_side);
+        let thumbnail = resize_exact(&image, width, height, FilterType::Triangle).to_rgba8();
+
+        let column = index as u32 % columns;
+        let row = index as u32 / columns;
+        overlay(
+            &mut sheet,
+            &thumbnail,
+            (column * cell_side + (cell_side - width) / 2) as i64,
+            (row * cell_side + (cell_side - height) / 2) as i64,
+        );
+    }
+
+    let mut bytes = Cursor::new(Vec::new());
+    sheet.write_to(&mut bytes, ImageFormat::Png)?;
+    Ok(bytes.into_inner())
+}
+
+#[cfg(test)]
+pub mod test {
+    use image::{GrayImage, Luma, Rgba, RgbaImage};
+
+    #[test]
+    pub fn test_mask_iou() {
+        let mut a = GrayImage::new(2, 2);
+        let mut b = GrayImage::new(2, 2);
+        a.put_pixel(0, 0, Luma([255]));
+        a.put_pixel(1, 0, Luma([255]));
+        b.put_pixel(0, 0, Luma([255]));
+
+        assert_eq!(0.5, super::mask_iou(&a, &b));
+        assert_eq!(
+            1.0,
+            super::mask_iou(&GrayImage::new(2, 2), &GrayImage::new(2, 2))
+        );
+    }
+
+    #[test]
+    pub fn test_diff_heatmap() {
+        let a = RgbaImage::from_pixel(2, 1, Rgba([0, 0, 0, 255]));
+        let mut b = a.clone();
+        b.put_pixel(1, 0, Rgba([255, 255, 255, 255]));
+
+        let (heatmap, mean) = super::diff_heatmap(&a, &b);
+        assert_eq!(&Rgba([0, 0, 255, 255]), heatmap.get_pixel(0, 0));
+        assert_eq!(&Rgba([191, 0, 64, 255]), heatmap.get_pixel(1, 0));
+        assert_eq!(765.0 / 8.0, mean);
+    }
+
+    ///
+    /// Encodes a 1x1 PNG and patches its header to declare `width` x `height`.
+    ///
+    fn png_declaring(width: u32, height: u32) -> Vec<u8> {
+        let mut bytes = std::io::Cursor::new(Vec::new());
+        GrayImage::new(1, 1)
+            .write_to(&mut bytes, image::ImageFormat::Png)
+            .unwrap();
+        let mut png = bytes.into_inner();
+
+        // Signature (8) + chunk length (4), then `IHDR` followed by width and height.
+        png[16..20].copy_from_slice(&width.to_be_bytes());
+        png[20..24].copy_from_slice(&height.to_be_bytes());
+        let crc = crc32(&png[12..29]);
+        png[29..33].copy_from_slice(&crc.to_be_bytes());
+        png
+    }
+
+    fn crc32(data: &[u8]) -> u32 {
+        let mut crc = 0xFFFF_FFFFu32;
+        for byte in data {
+            crc ^= *byte as u32;
+            for _ in 0..8 {
+                crc = if crc & 1 == 1 {
+                    (crc >> 1) ^ 0xEDB8_8320
+                } else {
+                    crc >> 1
+                };
+            }
+        }
+        !crc
+    }
+
+    #[test]
+    pub fn test_decode_bomb_rejected() {
+        let bomb = png_declaring(100_000, 100_000);
+        assert!(matches!(
+            super::load_from_memory(&bomb),
+            Err(image::ImageError::Limits(_))
+        ));
+        let options = super::PreviewOptions {
+            max_side: 512,
+            filter: image::imageops::FilterType::Triangle,
+            fit: super::PreviewFit::Contain,
+            jpeg_quality: 85,
+        };
+        assert!(super::make_preview(&bomb, &options, image::ImageFormat::Png).is_err());
+
+        assert!(super::read_dimensions(&png_declaring(16384, 3906)).is_ok());
+        assert!(super::read_dimensions(&png_declaring(16384, 3907)).is_err());
+        assert!(super::read_dimensions(&png_declaring(20_000, 1)).is_err());
+        assert!(super::load_from_memory(&png_declaring(1, 1)).is_ok());
+    }
+
+    ///
+    /// Decodes truncated and corrupted variants of valid images. Every one must fail or succeed
+    /// without panicking.
+    ///
+    #[test]
+    pub fn test_fuzz_decode() {
+        let mut seeds = vec![];
+        for format in [image::ImageFormat::Png, image::ImageFormat::Jpeg] {
+            let mut bytes = std::io::Cursor::new(Vec::new());
+            image::DynamicImage::ImageRgb8(image::RgbImage::new(8, 8))
+                .write_to(&mut bytes, format)
+                .unwrap();
+            seeds.push(bytes.into_inner());
+        }
+
+        // Xorshift keeps the cases reproducible.
+        let mut state = 0x2545_F491_4F6C_DD1Du64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+
+        for seed in &seeds {
+            for length in 0..seed.len() {
+                let _ = super::load_from_memory(&seed[..length]);
+            }
+
+            for _ in 0..500 {
+                let mut data = seed.clone();
+                for _ in 0..(next() % 8 + 1) {
+                    let index = (next() % data.len() as u64) as usize;
+                    data[index] = next() as u8;
+                }
+                let _ = super::load_from_memory(&data);
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_detect_format() {
+        let mut png = std::io::Cursor::new(Vec::new());
+        GrayImage::new(1, 1)
+            .write_to(&mut png, image::ImageFormat::Png)
+            .unwrap();
+
+        assert_eq!(Some("png"), super::detect_format(&png.into_inner()));
+        assert_eq!(Some("jxl"), super::detect_format(&[0xFF, 0x0A, 0x00]));
+        assert_eq!(
+            Some("jxl"),
+            super::detect_format(&[
+                0x00, 0x00, 0x00, 0x0C, 0x4A, 0x58, 0x4C, 0x20, 0x0D, 0x0A, 0x87, 0x0A, 0x00,
+            ])
+        );
+        assert_eq!(Some("webp"), super::detect_format(b"RIFF\0\0\0\0WEBPVP8 "));
+        assert_eq!(None, super::detect_format(b"not an image"));
+        assert!(super::load_from_memory(&[0xFF, 0x0A, 0x00]).is_err());
+    }
+
+    #[test]
+    pub fn test_make_preview_fit() {
+        let mut png = std::io::Cursor::new(Vec::new());
+        RgbaImage::from_pixel(400, 200, Rgba([255, 0, 0, 255]))
+            .write_to(&mut png, image::ImageFormat::Png)
+            .unwrap();
+        let png = png.into_inner();
+
+        let dimensions = |fit: super::PreviewFit| {
+            let options = super::PreviewOptions {
+                max_side: 100,
+                filter: image::imageops::FilterType::Triangle,
+                fit,
+                jpeg_quality: 80,
+            };
+            let preview = super::make_preview(&png, &options, image::ImageFormat::Png).unwrap();
+            let preview = image::load_from_memory(&preview).unwrap();
+            (preview.width(), preview.height(), preview.to_rgba8())
+        };
+
+        let (width, height, _) = dimensions(super::PreviewFit::Contain);
+        assert_eq!((100, 50), (width, height));
+
+        let (width, height, _) = dimensions(super::PreviewFit::Cover);
+        assert_eq!((100, 100), (width, height));
+
+        let (width, height, pixels) = dimensions(super::PreviewFit::Pad);
+        assert_eq!((100, 100), (width, height));
+        assert_eq!(&Rgba([0, 0, 0, 0]), pixels.get_pixel(50, 10));
+        assert_eq!(&Rgba([255, 0, 0, 255]), pixels.get_pixel(50, 50));
+    }
+
+    #[test]
+    pub fn test_contact_sheet() {
+        let mut png = std::io::Cursor::new(Vec::new());
+        RgbaImage::from_pixel(40, 20, Rgba([255, 0, 0, 255]))
+            .write_to(&mut png, image::ImageFormat::Png)
+            .unwrap();
+        let png = png.into_inner();
+
+        let images = vec![png.clone(), b"broken".to_vec(), png];
+        let sheet = super::contact_sheet(&images, 10, 2).unwrap();
+        let sheet = image::load_from_memory(&sheet).unwrap().to_rgba8();
+
+        assert_eq!((20, 20), sheet.dimensions());
+        assert_eq!(&Rgba([255, 0, 0, 255]), sheet.get_pixel(5, 5));
+        assert_eq!(&Rgba([235, 235, 235, 255]), sheet.get_pixel(5, 1));
+        assert_eq!(&Rgba([235, 235, 235, 255]), sheet.get_pixel(15, 5));
+        assert_eq!(&Rgba([255, 0, 0, 255]), sheet.get_pixel(5, 15));
+    }
+
+    #[test]
+    pub fn test_apply_watermark() {
+        let png = |image: RgbaImage| {
+            let mut bytes = std::io::Cursor::new(Vec::new());
+            image.write_to(&mut bytes, image::ImageFormat::Png).unwrap();
+            bytes.into_inner()
+        };
+        let data = png(RgbaImage::from_pixel(100, 50, Rgba([255, 0, 0, 255])));
+        let logo = png(RgbaImage::from_pixel(10, 10, Rgba([255, 255, 255, 255])));
+
+        let mut options = super::WatermarkOptions {
+            position: super::WatermarkPosition::BottomRight,
+            opacity_percent: 100,
+            scale_percent: 20,
+        };
+        let watermarked = super::apply_output_limits(&data, 0, Some((&logo, &options)))
+            .unwrap()
+            .unwrap();
+        let watermarked = image::load_from_memory(&watermarked).unwrap().to_rgba8();
+
+        // 20x20 logo with a 1 pixel margin.
+        assert_eq!((100, 50), watermarked.dimensions());
+        assert_eq!(&Rgba([255, 255, 255, 255]), watermarked.get_pixel(79, 29));
+        assert_eq!(&Rgba([255, 255, 255, 255]), watermarked.get_pixel(98, 48));
+        assert_eq!(&Rgba([255, 0, 0, 255]), watermarked.get_pixel(78, 29));
+        assert_eq!(&Rgba([255, 0, 0, 255]), watermarked.get_pixel(99, 49));
+
+        options.position = super::WatermarkPosition::TopLeft;
+        options.opacity_percent = 50;
+        let watermarked = super::apply_output_limits(&data, 0, Some((&logo, &options)))
+            .unwrap()
+            .unwrap();
+        let watermarked = image::load_from_memory(&watermarked).unwrap().to_rgba8();
+
+        let blended = watermarked.get_pixel(1, 1);
+        assert!((100..160).contains(&blended[1]));
+        assert_eq!(&Rgba([255, 0, 0, 255]), watermarked.get_pixel(0, 0));
+    }
+
+    #[test]
+    pub fn test_capped_dimensions() {
+        assert_eq!((707, 353), super::capped_dimensions(2000, 1000, 250_000));
+        assert_eq!((500, 500), super::capped_dimensions(1000, 1000, 250_000));
+        assert_eq!((300, 200), super::capped_dimensions(300, 200, 250_000));
+        assert_eq!((2000, 1000), super::capped_dimensions(2000, 1000, 0));
+    }
+
+    #[test]
+    pub fn test_apply_output_limits() {
+        let mut png = std::io::Cursor::new(Vec::new());
+        GrayImage::from_pixel(40, 10, Luma([255]))
+            .write_to(&mut png, image::ImageFormat::Png)
+            .unwrap();
+        let png = png.into_inner();
+
+        assert_eq!(None, super::apply_output_limits(&png, 400, None).unwrap());
+        let capped = super::apply_output_limits(&png, 100, None)
+            .unwrap()
+            .unwrap();
+        let capped = image::load_from_memory(&capped).unwrap();
+        assert_eq!((20, 5), (capped.width(), capped.height()));
+        assert_eq!(image::ColorType::L8, capped.color());
+    }
+
+    #[test]
+    pub fn test_cover_dimensions() {
+        assert_eq!((1024, 512), super::cover_dimensions(2048, 1024, 512));
+        assert_eq!((300, 200), super::cover_dimensions(300, 200, 512));
+    }
+
+    #[test]
+    pub fn test_preview_dimensions() {
+        assert_eq!((512, 256), super::preview_dimensions(2048, 1024, 512));
+        assert_eq!((1, 512), super::preview_dimensions(10, 6000, 512));
+        assert_eq!((300, 200), super::preview_dimensions(300, 200, 512));
+    }
+}
@@ -0,0 +1,95 @@
+///
+/// Checks that `url` is an absolute `http` or `https` URL.
+///
+pub fn check_image_url(url: &str) -> Result<(), String> {
+    let rest = match url
+        .strip_prefix("https://")
+        .or_else(|| url.strip_prefix("http://"))
+    {
+        Some(rest) => rest,
+        None => return Err("Image URL must start with http:// or https://.".to_string()),
+    };
+
+    if rest.is_empty() || rest.starts_with('/') || url.chars().any(char::is_whitespace) {
+        return Err("Image URL is not valid.".to_string());
+    }
+    Ok(())
+}
+
+///
+/// URL of object store key `key` below `base_url`, e.g. `https://bucket.s3.amazonaws.com/` and
+/// `batches/7/a.jpg`. Keys are relative paths of URL safe characters without `.` or `..`
+/// segments, so they can't leave `base_url`.
+///
+#[cfg_attr(not(feature = "nats-ingest"), allow(dead_code))]
+pub fn object_url(base_url: &str, key: &str) -> Result<String, String> {
+    let is_safe = |character: char| {
+        character.is_ascii_alphanumeric() || "/-_.~!$&'()*+,;=:@".contains(character)
+    };
+    if key.is_empty()
+        || !key.chars().all(is_safe)
+        || key
+            .split('/')
+            .any(|segment| segment.is_empty() || segment == "." || segment == "..")
+    {
+        return Err(format!("Object key {:?} is not valid.", key));
+    }
+
+    Ok(format!("{}/{}", base_url.trim_end_matches('/'), key))
+}
+
+///
+/// Filename shown for an ingested image: the last path segment of its URL or object key,
+/// without query string.
+///
+pub fn filename_from_url(url: &str) -> Option<String> {
+    let path = url.split(['?', '#']).next()?;
+    let path = path.split_once("://").map_or(path, |(_, rest)| rest);
+    let (_, filename) = path.rsplit_once('/')?;
+    if filename.is_empty() {
+        return None;
+    }
+    Some(filename.to_string())
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::{check_image_url, filename_from_url, object_url};
+
+    #[test]
+    pub fn test_check_image_url() {
+        assert!(check_image_url("https://cdn.example.com/a.jpg").is_ok());
+        assert!(check_image_url("http://10.0.3.7:8080/a.png?sig=1").is_ok());
+        assert!(check_image_url("file:///etc/passwd").is_err());
+        assert!(check_image_url("https://").is_err());
+        assert!(check_image_url("https:///a.jpg").is_err());
+        assert!(check_image_url("https://cdn.example.com/a b.jpg").is_err());
+    }
+
+    #[test]
+    pub fn test_object_url() {
+        assert_eq!(
+            Ok("https://bucket.s3.amazonaws.com/batches/7/a.jpg".to_string()),
+            object_url("https://bucket.s3.amazonaws.com/", "batches/7/a.jpg")
+        );
+        assert_eq!(
+            Ok("http://minio:9000/images/a.jpg".to_string()),
+            object_url("http://minio:9000/images", "a.jpg")
+        );
+        assert!(object_url("https://bucket", "").is_err());
+        assert!(object_url("https://bucket", "/a.jpg").is_err());
+        assert!(object_url("https://bucket", "batches/../a.jpg").is_err());
+        assert!(object_url("https://bucket", "a.jpg?x=1").is_err());
+        assert!(object_url("https://bucket", "a b.jpg").is_err());
+    }
+
+    #[test]
+    pub fn test_filename_from_url() {
+        assert_eq!(
+            Some("a.jpg".to_string()),
+            filename_from_url("https://cdn.example.com/shop/a.jpg?sig=1#x")
+        );
+        assert_eq!(None, filename_from_url("https://cdn.example.com/"));
+        assert_eq!(None, filename_from_url("https://cdn.example.com"));
+    }
+}
@@ -0,0 +1,322 @@
+use std::net::{IpAddr, SocketAddr};
+
+///
+/// IP network such as `10.0.0.0/8` or `fd00::/8`. A plain address matches only itself.
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn parse(value: &str) -> Option<Self> {
+        let (address, prefix) = match value.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (value, None),
+        };
+
+        let network = address.trim().parse::<IpAddr>().ok()?;
+        let max_prefix = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.trim().parse::<u8>().ok()?,
+            None => max_prefix,
+        };
+
+        if prefix > max_prefix {
+            return None;
+        }
+
+        Some(Self { network, prefix })
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(*ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(*ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+///
+/// Parses comma separated networks. Invalid entries are skipped with a warning.
+///
+pub fn parse_cidrs(value: &str) -> Vec<Cidr> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .filter_map(|entry| match Cidr::parse(entry) {
+            Some(cidr) => Some(cidr),
+            None => {
+                eprintln!("Ignoring invalid network: {}", entry);
+                None
+            }
+        })
+        .collect()
+}
+
+///
+/// Parses an address which may carry a port or IPv6 brackets, e.g. `"[2001:db8::1]:4711"`.
+///
+fn parse_address(value: &str) -> Option<IpAddr> {
+    let value = value.trim().trim_matches('"');
+
+    if let Ok(ip) = value.parse::<IpAddr>() {
+        return Some(ip);
+    }
+
+    if let Ok(socket_addr) = value.parse::<SocketAddr>() {
+        return Some(socket_addr.ip());
+    }
+
+    value
+        .trim_start_matches('[')
+        .split(']')
+        .next()
+        .and_then(|ip| ip.parse::<IpAddr>().ok())
+}
+
+///
+/// Returns `for=` addresses of a `Forwarded` header, closest to the client first. Obfuscated
+/// identifiers such as `unknown` are kept as `None`.
+///
+fn forwarded_addresses(value: &str) -> Vec<Option<IpAddr>> {
+    value
+        .split(',')
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (name, value) = pair.split_once('=')?;
+                if name.trim().eq_ignore_ascii_case("for") {
+                    Some(parse_address(value))
+                } else {
+                    None
+                }
+            })
+        })
+        .collect()
+}
+
+///
+/// Returns the real client address of a request received from `peer`.
+///
+/// Forwarding headers are only believed when `peer` is a trusted proxy. The hops are then walked
+/// from the closest one and the first address outside `trusted` is the client. `Forwarded` takes
+/// precedence over `X-Forwarded-For`.
+///
+pub fn resolve_client_ip(
+    peer: IpAddr,
+    forwarded: Option<&str>,
+    x_forwarded_for: Option<&str>,
+    trusted: &[Cidr],
+) -> IpAddr {
+    let is_trusted = |ip: &IpAddr| trusted.iter().any(|cidr| cidr.contains(ip));
+
+    if !is_trusted(&peer) {
+        return peer;
+    }
+
+    let hops = match (forwarded, x_forwarded_for) {
+        (Some(value), _) => forwarded_addresses(value),
+        (None, Some(value)) => value.split(',').map(parse_address).collect(),
+        (None, None) => return peer,
+    };
+
+    let mut client = peer;
+    for hop in hops.into_iter().rev() {
+        match hop {
+            Some(ip) if is_trusted(&ip) => client = ip,
+            Some(ip) => return ip,
+            // Anything beyond an unparsable hop can not be verified.
+            None => return client,
+        }
+    }
+
+    client
+}
+
+///
+/// Splits `host:port` into host and port. IPv6 addresses must be bracketed, e.g. `[::1]:8000`;
+/// brackets are removed from the returned host.
+///
+pub fn split_host_port(value: &str) -> Result<(String, u16), String> {
+    let value = value.trim();
+    let (host, port) = match value.strip_prefix('[') {
+        Some(rest) => match rest.split_once("]:") {
+            Some((host, port)) => (host, port),
+            None => return Err(format!("Expected [<ipv6>]:<port>, found {:?}.", value)),
+        },
+        None => match value.rsplit_once(':') {
+            Some((host, _)) if host.contains(':') => {
+                return Err(format!(
+                    "IPv6 addresses must be bracketed, e.g. [::1]:8000. Found {:?}.",
+                    value
+                ))
+            }
+            Some((host, port)) => (host, port),
+            None => return Err(format!("Expected <host>:<port>, found {:?}.", value)),
+        },
+    };
+
+    if host.is_empty() {
+        return Err(format!("Host is missing in {:?}.", value));
+    }
+
+    match port.parse::<u16>() {
+        Ok(port) => Ok((host.to_string(), port)),
+        Err(_) => Err(format!("Invalid port in {:?}.", value)),
+    }
+}
+
+///
+/// Orders resolved addresses for connection attempts by alternating address families, starting
+/// with the family of the first address (RFC 8305). Order within a family is kept.
+///
+pub fn interleave_families(addresses: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_is_ipv6 = match addresses.first() {
+        Some(address) => address.is_ipv6(),
+        None => return addresses,
+    };
+
+    let (mut first, mut second): (Vec<_>, Vec<_>) = addresses
+        .into_iter()
+        .partition(|address| address.is_ipv6() == first_is_ipv6);
+    first.reverse();
+    second.reverse();
+
+    let mut interleaved = Vec::with_capacity(first.len() + second.len());
+    while !first.is_empty() || !second.is_empty() {
+        interleaved.extend(first.pop());
+        interleaved.extend(second.pop());
+    }
+    interleaved
+}
+
+///
+/// Resolves `host:port`, where host is a name or an IPv4 or bracketed IPv6 address, to the
+/// addresses of every A and AAAA record in the order of `interleave_families`.
+///
+pub async fn resolve(value: &str) -> std::io::Result<Vec<SocketAddr>> {
+    let (host, port) = split_host_port(value).map_err(std::io::Error::other)?;
+
+    let addresses: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), port))
+        .await?
+        .collect();
+    if addresses.is_empty() {
+        return Err(std::io::Error::other(format!(
+            "{} did not resolve to any address.",
+            host
+        )));
+    }
+
+    Ok(interleave_families(addresses))
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::net::{IpAddr, SocketAddr};
+
+    use super::{interleave_families, parse_cidrs, resolve_client_ip, split_host_port, Cidr};
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    pub fn test_cidr_contains() {
+        let cidr = Cidr::parse("10.0.0.0/8").unwrap();
+        assert!(cidr.contains(&ip("10.1.2.3")));
+        assert!(!cidr.contains(&ip("11.0.0.1")));
+        assert!(!cidr.contains(&ip("::1")));
+
+        assert!(Cidr::parse("0.0.0.0/0").unwrap().contains(&ip("8.8.8.8")));
+        assert!(Cidr::parse("fd00::/8").unwrap().contains(&ip("fd12::1")));
+        assert!(Cidr::parse("127.0.0.1").unwrap().contains(&ip("127.0.0.1")));
+        assert_eq!(None, Cidr::parse("10.0.0.0/33"));
+        assert_eq!(2, parse_cidrs("10.0.0.0/8, invalid, ::1").len());
+    }
+
+    #[test]
+    pub fn test_resolve_client_ip() {
+        let trusted = parse_cidrs("10.0.0.0/8");
+
+        // Untrusted peers can not spoof their address.
+        let client = resolve_client_ip(ip("1.2.3.4"), None, Some("5.6.7.8"), &trusted);
+        assert_eq!(ip("1.2.3.4"), client);
+
+        let client = resolve_client_ip(
+            ip("10.0.0.1"),
+            None,
+            Some("9.9.9.9, 5.6.7.8, 10.0.0.2"),
+            &trusted,
+        );
+        assert_eq!(ip("5.6.7.8"), client);
+
+        let client = resolve_client_ip(
+            ip("10.0.0.1"),
+            Some(r#"for="[2001:db8::17]:4711";proto=https, for=10.0.0.2"#),
+            Some("5.6.7.8"),
+            &trusted,
+        );
+        assert_eq!(ip("2001:db8::17"), client);
+
+        let client = resolve_client_ip(ip("10.0.0.1"), None, None, &trusted);
+        assert_eq!(ip("10.0.0.1"), client);
+    }
+
+    #[test]
+    pub fn test_split_host_port() {
+        assert_eq!(Ok(("bp".to_string(), 8000)), split_host_port("bp:8000"));
+        assert_eq!(
+            Ok(("10.0.0.1".to_string(), 80)),
+            split_host_port("10.0.0.1:80")
+        );
+        assert_eq!(Ok(("::".to_string(), 8000)), split_host_port("[::]:8000"));
+        assert_eq!(
+            Ok(("2001:db8::1".to_string(), 443)),
+            split_host_port(" [2001:db8::1]:443 ")
+        );
+
+        assert!(split_host_port("2001:db8::1:443").is_err());
+        assert!(split_host_port("[::1]").is_err());
+        assert!(split_host_port("bp").is_err());
+        assert!(split_host_port(":8000").is_err());
+        assert!(split_host_port("bp:port").is_err());
+    }
+
+    #[test]
+    pub fn test_interleave_families() {
+        let addresses = |values: &[&str]| -> Vec<SocketAddr> {
+            values.iter().map(|value| value.parse().unwrap()).collect()
+        };
+
+        assert_eq!(
+            addresses(&[
+                "[::1]:80",
+                "10.0.0.1:80",
+                "[::2]:80",
+                "10.0.0.2:80",
+                "[::3]:80"
+            ]),
+            interleave_families(addresses(&[
+                "[::1]:80",
+                "[::2]:80",
+                "[::3]:80",
+                "10.0.0.1:80",
+                "10.0.0.2:80"
+            ]))
+        );
+        assert_eq!(
+            addresses(&["10.0.0.1:80", "[::1]:80", "10.0.0.2:80"]),
+            interleave_families(addresses(&["10.0.0.1:80", "10.0.0.2:80", "[::1]:80"]))
+        );
+        assert!(interleave_families(vec![]).is_empty());
+    }
+}
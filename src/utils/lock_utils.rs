@@ -0,0 +1,24 @@
+use crate::utils::signature_utils;
+
+///
+/// Advisory lock key of `name`: the first 8 bytes of the SHA-256 of `lock:<name>`. Replicas of
+/// different releases run side by side during a deploy, so the key must never change or both
+/// could take the same lock.
+///
+pub fn lock_key(name: &str) -> i64 {
+    let hash = signature_utils::sha256_hex(format!("lock:{}", name).as_bytes());
+    u64::from_str_radix(&hash[..16], 16).unwrap_or_default() as i64
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::lock_key;
+
+    #[test]
+    pub fn test_lock_key() {
+        // Pinned, see `lock_key`.
+        assert_eq!(6652438697592420322, lock_key("job:task_archive"));
+        assert_eq!(-2739971410385955106, lock_key("job:lease_gc"));
+        assert_ne!(lock_key("job:task_archive"), lock_key("job:task_anonymize"));
+    }
+}
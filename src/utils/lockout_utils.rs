@@ -0,0 +1,174 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Forgotten clients are pruned at most this often, so failures stay cheap under a flood of
+/// distinct IPs.
+pub const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+///
+/// When failing clients get banned: after `max_failures` within `window`, for `ban`.
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LockoutLimits {
+    pub max_failures: u32,
+    pub window: Duration,
+    pub ban: Duration,
+}
+
+///
+/// Failures of a single client.
+///
+struct FailureWindow {
+    failures: u32,
+    window_start: Instant,
+    banned_until: Option<Instant>,
+}
+
+impl FailureWindow {
+    fn is_over(&self, now: Instant, window: Duration) -> bool {
+        match self.banned_until {
+            Some(banned_until) => banned_until <= now,
+            None => now.duration_since(self.window_start) >= window,
+        }
+    }
+}
+
+///
+/// Failures per client within their window and the bans they caused.
+///
+pub struct FailureWindows {
+    entries: HashMap<String, FailureWindow>,
+    last_pruned: Instant,
+}
+
+impl FailureWindows {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            entries: HashMap::new(),
+            last_pruned: now,
+        }
+    }
+
+    ///
+    /// Returns the remaining ban of `client` at `now`, `None` when it isn't banned.
+    ///
+    pub fn banned_for(&self, client: &str, now: Instant) -> Option<Duration> {
+        let banned_until = self.entries.get(client)?.banned_until?;
+        banned_until
+            .checked_duration_since(now)
+            .filter(|remaining| !remaining.is_zero())
+    }
+
+    ///
+    /// Records a failure of `client` at `now`. Returns true when this failure got it banned.
+    ///
+    pub fn record(&mut self, client: &str, now: Instant, limits: &LockoutLimits) -> bool {
+        if now.duration_since(self.last_pruned) >= PRUNE_INTERVAL {
+            self.prune(now, limits.window);
+        }
+
+        let entry = self
+            .entries
+            .entry(client.to_string())
+            .or_insert(FailureWindow {
+                failures: 0,
+                window_start: now,
+                banned_until: None,
+            });
+        if entry.is_over(now, limits.window) {
+            entry.failures = 0;
+            entry.window_start = now;
+            entry.banned_until = None;
+        }
+
+        entry.failures += 1;
+        if entry.failures >= limits.max_failures && entry.banned_until.is_none() {
+            entry.banned_until = Some(now + limits.ban);
+            return true;
+        }
+        false
+    }
+
+    ///
+    /// Forgets clients whose window and ban are over, so the map doesn't grow with every client.
+    ///
+    pub fn prune(&mut self, now: Instant, window: Duration) {
+        self.entries.retain(|_, entry| !entry.is_over(now, window));
+        self.last_pruned = now;
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::time::{Duration, Instant};
+
+    use super::{FailureWindows, LockoutLimits, PRUNE_INTERVAL};
+
+    const LIMITS: LockoutLimits = LockoutLimits {
+        max_failures: 3,
+        window: Duration::from_secs(60),
+        ban: Duration::from_secs(900),
+    };
+
+    #[test]
+    pub fn test_limit() {
+        let now = Instant::now();
+        let mut windows = FailureWindows::new(now);
+
+        assert!(!windows.record("a", now, &LIMITS));
+        assert!(!windows.record("a", now, &LIMITS));
+        assert!(!windows.record("b", now, &LIMITS));
+        assert_eq!(None, windows.banned_for("a", now));
+
+        // The failure reaching the limit bans, later ones don't ban again.
+        assert!(windows.record("a", now, &LIMITS));
+        assert!(!windows.record("a", now, &LIMITS));
+        assert_eq!(Some(LIMITS.ban), windows.banned_for("a", now));
+        assert_eq!(None, windows.banned_for("b", now));
+
+        // Failures after the ban start a new window.
+        let unbanned = now + LIMITS.ban;
+        assert_eq!(None, windows.banned_for("a", unbanned));
+        assert!(!windows.record("a", unbanned, &LIMITS));
+    }
+
+    #[test]
+    pub fn test_window() {
+        let now = Instant::now();
+        let mut windows = FailureWindows::new(now);
+
+        windows.record("a", now, &LIMITS);
+        windows.record("a", now + Duration::from_secs(30), &LIMITS);
+        // The window started with the first failure, so these are the first of a new one.
+        let later = now + LIMITS.window;
+        assert!(!windows.record("a", later, &LIMITS));
+        assert!(!windows.record("a", later, &LIMITS));
+        assert!(windows.record("a", later, &LIMITS));
+    }
+
+    #[test]
+    pub fn test_prune() {
+        let now = Instant::now();
+        let mut windows = FailureWindows::new(now);
+        for _ in 0..LIMITS.max_failures {
+            windows.record("banned", now, &LIMITS);
+        }
+        windows.record("a", now, &LIMITS);
+
+        // Pruned on the first failure once the interval passed, keeping bans.
+        let later = now + PRUNE_INTERVAL.max(LIMITS.window);
+        windows.record("b", later - Duration::from_secs(1), &LIMITS);
+        assert_eq!(3, windows.len());
+        windows.record("b", later, &LIMITS);
+        assert_eq!(2, windows.len());
+        assert!(windows.banned_for("banned", later).is_some());
+    }
+}
@@ -0,0 +1,91 @@
+use chrono::{DateTime, Utc};
+
+/// `Retry-After` of maintenance responses while the end is unknown or overdue.
+pub const DEFAULT_RETRY_AFTER_SECS: u64 = 60;
+
+/// Message of maintenance responses unless `MAINTENANCE_MESSAGE` is set.
+const DEFAULT_MESSAGE: &str = "The service is down for maintenance.";
+
+///
+/// Maintenance in progress, during which public endpoints answer `503`.
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct Maintenance {
+    pub estimated_end: Option<DateTime<Utc>>,
+    pub message: Option<String>,
+}
+
+impl Maintenance {
+    pub fn message(&self) -> &str {
+        self.message.as_deref().unwrap_or(DEFAULT_MESSAGE)
+    }
+
+    ///
+    /// Seconds until the estimated end, at least 1. `DEFAULT_RETRY_AFTER_SECS` when the end is
+    /// unknown or has passed, as maintenance is only over once switched off.
+    ///
+    pub fn retry_after_secs(&self, now: DateTime<Utc>) -> u64 {
+        match self.estimated_end {
+            Some(end) if end > now => (end - now).num_seconds().max(1) as u64,
+            _ => DEFAULT_RETRY_AFTER_SECS,
+        }
+    }
+}
+
+///
+/// Parses the RFC 3339 estimated end of a maintenance, e.g. `2024-06-01T12:00:00Z`.
+///
+pub fn parse_estimated_end(value: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(value.trim())
+        .ok()
+        .map(|end| end.with_timezone(&Utc))
+}
+
+#[cfg(test)]
+pub mod test {
+    use chrono::{Duration, Utc};
+
+    use super::{parse_estimated_end, Maintenance, DEFAULT_RETRY_AFTER_SECS};
+
+    #[test]
+    pub fn test_retry_after() {
+        let now = Utc::now();
+        let maintenance = |estimated_end| Maintenance {
+            estimated_end,
+            message: None,
+        };
+
+        assert_eq!(
+            600,
+            maintenance(Some(now + Duration::seconds(600))).retry_after_secs(now)
+        );
+        assert_eq!(
+            1,
+            maintenance(Some(now + Duration::milliseconds(200))).retry_after_secs(now)
+        );
+        assert_eq!(
+            DEFAULT_RETRY_AFTER_SECS,
+            maintenance(Some(now - Duration::seconds(5))).retry_after_secs(now)
+        );
+        assert_eq!(
+            DEFAULT_RETRY_AFTER_SECS,
+            maintenance(None).retry_after_secs(now)
+        );
+        assert_eq!(
+            "The service is down for maintenance.",
+            maintenance(None).message()
+        );
+    }
+
+    #[test]
+    pub fn test_parse_estimated_end() {
+        assert_eq!(
+            "2024-06-01T10:00:00+00:00",
+            parse_estimated_end("2024-06-01T12:00:00+02:00")
+                .unwrap()
+                .to_rfc3339()
+        );
+        assert_eq!(None, parse_estimated_end("2024-06-01"));
+        assert_eq!(None, parse_estimated_end("soon"));
+    }
+}
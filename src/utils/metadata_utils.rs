@@ -0,0 +1,221 @@
+use serde_json::Value;
+
+/// Maximum size of the serialized metadata object in bytes.
+pub const MAX_METADATA_BYTES: usize = 4096;
+
+/// Maximum number of tags of a task.
+pub const MAX_TAGS: usize = 20;
+
+/// Maximum length of a single tag.
+pub const MAX_TAG_LENGTH: usize = 64;
+
+///
+/// Parses client supplied metadata. It must be a JSON object of at most `MAX_METADATA_BYTES`.
+///
+pub fn parse_metadata(raw: &str) -> Result<Value, String> {
+    if raw.len() > MAX_METADATA_BYTES {
+        return Err(format!(
+            "Metadata must not exceed {} bytes.",
+            MAX_METADATA_BYTES
+        ));
+    }
+
+    match serde_json::from_str::<Value>(raw) {
+        Ok(value) if value.is_object() => Ok(value),
+        Ok(_) => Err("Metadata must be a JSON object.".to_string()),
+        Err(_) => Err("Metadata is not valid JSON.".to_string()),
+    }
+}
+
+///
+/// Parses comma separated tags. Tags are trimmed, empty ones skipped and duplicates removed while
+/// keeping the order. Allowed characters are ASCII letters, digits and `-_.:`.
+///
+pub fn parse_tags(raw: &str) -> Result<Vec<String>, String> {
+    let mut tags: Vec<String> = vec![];
+
+    for tag in raw.split(',').map(|tag| tag.trim()) {
+        if tag.is_empty() || tags.iter().any(|existing| existing == tag) {
+            continue;
+        }
+
+        if !is_valid_tag(tag) {
+            return Err(format!(
+                "Invalid tag \"{}\". Tags are up to {} characters of letters, digits and -_.:",
+                tag, MAX_TAG_LENGTH
+            ));
+        }
+
+        tags.push(tag.to_string());
+    }
+
+    if tags.len() > MAX_TAGS {
+        return Err(format!("At most {} tags are allowed.", MAX_TAGS));
+    }
+
+    Ok(tags)
+}
+
+///
+/// Whether `tag` is a single valid tag.
+///
+pub fn is_valid_tag(tag: &str) -> bool {
+    !tag.is_empty()
+        && tag.len() <= MAX_TAG_LENGTH
+        && tag
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':'))
+}
+
+/// Named background hints understood by the BP server.
+pub const BACKGROUND_HINT_PRESETS: [&str; 4] = [
+    "white_studio",
+    "black_studio",
+    "grey_studio",
+    "green_screen",
+];
+
+///
+/// Parses a background hint: a preset of `BACKGROUND_HINT_PRESETS` or a dominant background
+/// color as `#rrggbb`. Returns the normalized lowercase hint.
+///
+pub fn parse_background_hint(raw: &str) -> Result<String, String> {
+    let hint = raw.trim().to_ascii_lowercase();
+
+    let is_color = hint.len() == 7
+        && hint.starts_with('#')
+        && hint[1..].chars().all(|c| c.is_ascii_hexdigit());
+    if is_color || BACKGROUND_HINT_PRESETS.contains(&hint.as_str()) {
+        return Ok(hint);
+    }
+
+    Err(format!(
+        "Background hint must be a color as #rrggbb or one of {}.",
+        BACKGROUND_HINT_PRESETS.join(", ")
+    ))
+}
+
+/// Maximum length of a notification email address.
+pub const MAX_EMAIL_LENGTH: usize = 254;
+
+///
+/// Parses the email notified when a task group finished. Only the shape is checked: a single `@`
+/// with a non empty local part and a dotted domain, no whitespace and at most `MAX_EMAIL_LENGTH`
+/// characters.
+///
+pub fn parse_notify_email(raw: &str) -> Result<String, String> {
+    let email = raw.trim();
+    let invalid = || Err("Enter a valid email address.".to_string());
+
+    if email.len() > MAX_EMAIL_LENGTH
+        || email
+            .chars()
+            .any(|c| c.is_whitespace() || c.is_control() || matches!(c, '<' | '>' | ',' | ';'))
+    {
+        return invalid();
+    }
+
+    let (local, domain) = match email.split_once('@') {
+        Some(parts) => parts,
+        None => return invalid(),
+    };
+
+    let valid_domain = !domain.contains('@')
+        && domain.contains('.')
+        && domain.split('.').all(|label| !label.is_empty());
+    if local.is_empty() || !valid_domain {
+        return invalid();
+    }
+
+    Ok(email.to_string())
+}
+
+///
+/// Percent-encodes `value` for use in a URL query string.
+///
+pub fn encode_query_value(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_' | b'.' | b'~') {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+pub mod test {
+    use serde_json::json;
+
+    #[test]
+    pub fn test_parse_notify_email() {
+        let parse = |raw: &str| super::parse_notify_email(raw);
+
+        assert_eq!(
+            Ok("a.b+c@example.com".to_string()),
+            parse(" a.b+c@example.com ")
+        );
+        assert!(parse("example.com").is_err());
+        assert!(parse("@example.com").is_err());
+        assert!(parse("a@localhost").is_err());
+        assert!(parse("a@@example.com").is_err());
+        assert!(parse("a@example..com").is_err());
+        assert!(parse("a b@example.com").is_err());
+        assert!(parse("a@example.com\r\nBcc: b@example.com").is_err());
+        assert!(parse("a@example.com, b@example.com").is_err());
+        assert!(parse(&format!("{}@example.com", "a".repeat(250))).is_err());
+    }
+
+    #[test]
+    pub fn test_parse_metadata() {
+        assert_eq!(
+            Ok(json!({ "order_id": "A-1" })),
+            super::parse_metadata(r#"{"order_id": "A-1"}"#)
+        );
+        assert!(super::parse_metadata("[1, 2]").is_err());
+        assert!(super::parse_metadata("not json").is_err());
+
+        let too_large = format!(r#"{{"a": "{}"}}"#, "x".repeat(super::MAX_METADATA_BYTES));
+        assert!(super::parse_metadata(&too_large).is_err());
+    }
+
+    #[test]
+    pub fn test_parse_tags() {
+        assert_eq!(
+            Ok(vec!["shop:1".to_string(), "batch-2".to_string()]),
+            super::parse_tags(" shop:1, batch-2,,shop:1 ")
+        );
+        assert_eq!(Ok(vec![]), super::parse_tags(""));
+        assert!(super::parse_tags("has space").is_err());
+        assert!(super::parse_tags(&"x".repeat(super::MAX_TAG_LENGTH + 1)).is_err());
+
+        let too_many: Vec<String> = (0..=super::MAX_TAGS).map(|i| i.to_string()).collect();
+        assert!(super::parse_tags(&too_many.join(",")).is_err());
+    }
+
+    #[test]
+    pub fn test_parse_background_hint() {
+        assert_eq!(
+            Ok("white_studio".to_string()),
+            super::parse_background_hint(" White_Studio ")
+        );
+        assert_eq!(
+            Ok("#ffaa00".to_string()),
+            super::parse_background_hint("#FFAA00")
+        );
+        assert!(super::parse_background_hint("#ffaa0").is_err());
+        assert!(super::parse_background_hint("#gggggg").is_err());
+        assert!(super::parse_background_hint("beach").is_err());
+    }
+
+    #[test]
+    pub fn test_encode_query_value() {
+        assert_eq!("shop%3A1", super::encode_query_value("shop:1"));
+        assert_eq!(
+            "%7B%22id%22%3A%22%C3%A9%22%7D",
+            super::encode_query_value(r#"{"id":"é"}"#)
+        );
+    }
+}
@@ -1,3 +1,42 @@
+pub mod alert_utils;
+pub mod anonymize_utils;
+pub mod billing_utils;
+pub mod body_utils;
+#[cfg(feature = "chaos")]
+pub mod chaos_utils;
+pub mod cursor_utils;
+pub mod drain_utils;
+pub mod drop_folder_utils;
+pub mod encoding_utils;
+pub mod envelope_utils;
+pub mod export_utils;
+#[cfg(feature = "gpu")]
+pub mod gpu_utils;
+pub mod health_utils;
 pub mod image_utils;
+pub mod ingest_utils;
+pub mod ip_utils;
+pub mod lock_utils;
+pub mod lockout_utils;
+pub mod maintenance_utils;
+pub mod metadata_utils;
+pub mod organization_utils;
 pub mod path_utils;
+pub mod query_utils;
+#[cfg(feature = "bp-replay")]
+pub mod replay_utils;
+pub mod retry_utils;
+pub mod routing_utils;
+#[cfg(feature = "s3-ingest")]
+pub mod s3_utils;
 pub mod save_utils;
+pub mod schedule_utils;
+pub mod secret_utils;
+pub mod signature_utils;
+pub mod store_utils;
+pub mod temp_utils;
+pub mod template_utils;
+pub mod throttle_utils;
+pub mod timing_utils;
+pub mod token_utils;
+pub mod version_utils;
@@ -0,0 +1,82 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+
+use crate::utils::signature_utils;
+
+/// Prefix of member tokens, so leaked tokens are easy to recognize.
+const MEMBER_TOKEN_PREFIX: &str = "bpm_";
+
+///
+/// Role of an organization member. Viewers read the organization, its usage and its tasks;
+/// admins also manage its API keys and members.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    Viewer,
+    Admin,
+}
+
+impl Role {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim() {
+            "viewer" => Some(Role::Viewer),
+            "admin" => Some(Role::Admin),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Viewer => "viewer",
+            Role::Admin => "admin",
+        }
+    }
+
+    ///
+    /// Whether the role may do what `required` may do.
+    ///
+    pub fn allows(&self, required: Role) -> bool {
+        *self >= required
+    }
+}
+
+///
+/// New random member token. Only its hash is stored, see `hash_member_token`.
+///
+pub fn generate_member_token() -> String {
+    let bytes: [u8; 32] = rand::random();
+    format!("{}{}", MEMBER_TOKEN_PREFIX, URL_SAFE_NO_PAD.encode(bytes))
+}
+
+///
+/// Stored form of a member token. Tokens are random, so an unsalted hash is enough.
+///
+pub fn hash_member_token(token: &str) -> String {
+    signature_utils::sha256_hex(token.as_bytes())
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::{generate_member_token, hash_member_token, Role};
+
+    #[test]
+    pub fn test_role() {
+        assert_eq!(Some(Role::Admin), Role::parse("admin"));
+        assert_eq!(Some(Role::Viewer), Role::parse(" viewer"));
+        assert_eq!(None, Role::parse("owner"));
+        assert_eq!("viewer", Role::Viewer.as_str());
+
+        assert!(Role::Admin.allows(Role::Viewer));
+        assert!(Role::Admin.allows(Role::Admin));
+        assert!(!Role::Viewer.allows(Role::Admin));
+    }
+
+    #[test]
+    pub fn test_member_token() {
+        let token = generate_member_token();
+        assert!(token.starts_with("bpm_"));
+        assert_ne!(token, generate_member_token());
+        assert_eq!(64, hash_member_token(&token).len());
+        assert_eq!(hash_member_token(&token), hash_member_token(&token));
+    }
+}
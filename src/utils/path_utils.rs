@@ -1,7 +1,8 @@
 use std::env;
 use std::ffi::OsStr;
-use std::path::PathBuf;
+use std::path::{Component, Path, PathBuf};
 
+use sha2::{Digest, Sha256};
 use uuid::Uuid;
 
 ///
@@ -95,19 +96,215 @@ pub fn relative_media_url_from_full_path(media_root: &PathBuf, full_path: &PathB
     relative_media_url
 }
 
+/// Extensions accepted for uploaded images, lowercase.
+pub const ALLOWED_IMAGE_EXTENSIONS: [&str; 9] = [
+    "jpg", "jpeg", "png", "webp", "jxl", "bmp", "gif", "tif", "tiff",
+];
+
+/// Bytes of the SHA-256 digest used in content addressed filenames (16 hex characters).
+const CONTENT_HASH_PREFIX_BYTES: usize = 8;
+
+/// Maximum length of the name part of a sanitized filename.
+const MAX_FILENAME_STEM_LENGTH: usize = 64;
+
+///
+/// Normalizes client supplied filename to `<slug>.<extension>`.
+///
+/// Directories are stripped, the name is reduced to lowercase ASCII letters, digits and dashes
+/// and capped at 64 characters. Returns `None` if the extension is not an allowed image
+/// extension.
+///
+pub fn sanitize_filename(filename: &str) -> Option<String> {
+    let name = filename.rsplit(['/', '\\']).next().unwrap_or_default();
+    let (stem, extension) = name.rsplit_once('.')?;
+
+    let extension = extension.to_ascii_lowercase();
+    if !ALLOWED_IMAGE_EXTENSIONS.contains(&extension.as_str()) {
+        return None;
+    }
+
+    let mut slug = String::new();
+    for char in stem.chars() {
+        if char.is_ascii_alphanumeric() {
+            slug.push(char.to_ascii_lowercase());
+        } else if !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+
+    let mut slug: String = slug
+        .trim_matches('-')
+        .chars()
+        .take(MAX_FILENAME_STEM_LENGTH)
+        .collect();
+    slug = slug.trim_end_matches('-').to_string();
+    if slug.is_empty() {
+        slug = "image".to_string();
+    }
+
+    Some(format!("{}.{}", slug, extension))
+}
+
+///
+/// Returns `<sha256 prefix>.<extension>` for file content `data`. Files are stored under their
+/// content hash so two different files of a task never overwrite each other.
+///
+pub fn content_filename(data: &[u8], extension: &str) -> String {
+    let digest = Sha256::digest(data);
+    let prefix: String = digest[..CONTENT_HASH_PREFIX_BYTES]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+
+    format!("{}.{}", prefix, extension)
+}
+
+///
+/// Rejects filenames which are not a single plain path component, e.g. `../x` or `a/b`.
+///
+fn ensure_plain_filename(filename: &str) -> std::io::Result<()> {
+    let mut components = Path::new(filename).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) => Ok(()),
+        _ => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Invalid filename: {:?}", filename),
+        )),
+    }
+}
+
+/// Directory of `MEDIA_ROOT` holding one directory of media per tenant.
+pub const TENANTS_DIRECTORY: &str = "tenants";
+
+/// Shard levels used when `MEDIA_SHARD_LEVELS` is not set.
+const DEFAULT_SHARD_LEVELS: usize = 2;
+
+/// Most shard levels, one per leading byte of the task key.
+const MAX_SHARD_LEVELS: usize = 4;
+
+///
+/// Number of directories between `background-remover` and task directories, named after the
+/// leading bytes of the task key: `MEDIA_SHARD_LEVELS`, default 2 (`ab/cd/<key>`), at most 4. 0
+/// keeps every task directory directly in `background-remover`.
+///
+pub fn shard_levels() -> usize {
+    match env::var("MEDIA_SHARD_LEVELS") {
+        Ok(value) => value
+            .parse::<usize>()
+            .unwrap_or(DEFAULT_SHARD_LEVELS)
+            .min(MAX_SHARD_LEVELS),
+        Err(_) => DEFAULT_SHARD_LEVELS,
+    }
+}
+
+///
+/// Path of the directory of task `key` relative to `background-remover`, with `levels` shard
+/// directories, e.g. `ab/cd/abcd0123-...` for 2 levels.
+///
+pub fn shard_path(key: &Uuid, levels: usize) -> PathBuf {
+    let hex = key.simple().to_string();
+    let mut path = PathBuf::new();
+    for level in 0..levels.min(MAX_SHARD_LEVELS) {
+        path.push(&hex[level * 2..level * 2 + 2]);
+    }
+    path.push(key.to_string());
+    path
+}
+
+///
+/// Whether `name` is a shard directory name, two lowercase hex digits.
+///
+pub fn is_shard_name(name: &str) -> bool {
+    name.len() == 2
+        && name
+            .chars()
+            .all(|char| char.is_ascii_digit() || ('a'..='f').contains(&char))
+}
+
+///
+/// Directory of task `key` in `tasks_dir` with `levels` shard levels. Tasks stored before
+/// sharding, or with other shard levels, keep their existing directory so all of their files stay
+/// together. Up to five directories are checked, so this runs on the blocking thread pool.
+///
+async fn resolve_task_directory(
+    tasks_dir: &Path,
+    key: &Uuid,
+    levels: usize,
+) -> std::io::Result<PathBuf> {
+    let (tasks_dir, key) = (tasks_dir.to_path_buf(), *key);
+    tokio::task::spawn_blocking(move || find_task_directory(&tasks_dir, &key, levels))
+        .await
+        .map_err(std::io::Error::other)
+}
+
+fn find_task_directory(tasks_dir: &Path, key: &Uuid, levels: usize) -> PathBuf {
+    let directory = tasks_dir.join(shard_path(key, levels));
+    if directory.is_dir() {
+        return directory;
+    }
+
+    (0..=MAX_SHARD_LEVELS)
+        .filter(|other_levels| *other_levels != levels)
+        .map(|other_levels| tasks_dir.join(shard_path(key, other_levels)))
+        .find(|other_directory| other_directory.is_dir())
+        .unwrap_or(directory)
+}
+
 pub enum ForImage<'a> {
     OriginalImage(&'a Uuid, &'a String),
     PreviewOriginalImage(&'a Uuid, &'a String),
     MaskImage(&'a Uuid, &'a String),
     TransparentImage(&'a Uuid, &'a String),
     PreviewTransparentImage(&'a Uuid, &'a String),
+    /// Files of a task revision: (task key, revision key, kind, filename). Kept inside the task
+    /// directory so they share the task lifecycle.
+    RevisionImage(&'a Uuid, &'a Uuid, &'a str, &'a String),
+    /// Diff heatmaps of revision comparisons: (task key, filename).
+    ComparisonImage(&'a Uuid, &'a String),
+    /// Outputs of the canary BP server, never served to users: (task key, filename).
+    CanaryImage(&'a Uuid, &'a String),
+    /// Contact sheet of a task group: (task group, filename). Stored in
+    /// `background-remover-groups` because the media garbage collector removes directories in
+    /// `background-remover` which don't belong to a task.
+    ContactSheet(&'a Uuid, &'a String),
 }
 
 ///
 /// Returns path.
 /// Depends on environment variables.
 ///
-pub fn generate_save_path(for_image: ForImage) -> std::io::Result<PathBuf> {
+/// Fails for filenames which could escape the task directory. Stored files are named with
+/// `content_filename`; client supplied names are only kept in the database.
+///
+/// Media of a `tenant` (see `BillingAccount::media_prefix`) is stored under
+/// `MEDIA_ROOT/tenants/<tenant>/`, so it can be served or expired per tenant. Tasks without a
+/// tenant keep the layout directly under `MEDIA_ROOT`.
+///
+/// Task directories are sharded by the leading bytes of the task key, see `shard_levels`.
+///
+pub async fn generate_save_path(
+    tenant: Option<&str>,
+    for_image: ForImage<'_>,
+) -> std::io::Result<PathBuf> {
+    if let Some(tenant) = tenant {
+        ensure_plain_filename(tenant)?;
+    }
+
+    match &for_image {
+        ForImage::OriginalImage(_, filename)
+        | ForImage::PreviewOriginalImage(_, filename)
+        | ForImage::MaskImage(_, filename)
+        | ForImage::TransparentImage(_, filename)
+        | ForImage::PreviewTransparentImage(_, filename)
+        | ForImage::ComparisonImage(_, filename)
+        | ForImage::CanaryImage(_, filename)
+        | ForImage::ContactSheet(_, filename) => ensure_plain_filename(filename)?,
+        ForImage::RevisionImage(_, _, kind, filename) => {
+            ensure_plain_filename(kind)?;
+            ensure_plain_filename(filename)?;
+        }
+    }
+
     let media_root = match env::var("MEDIA_ROOT") {
         Ok(dir) => dir,
         Err(error) => {
@@ -117,17 +314,19 @@ pub fn generate_save_path(for_image: ForImage) -> std::io::Result<PathBuf> {
 
     let mut relative_url = PathBuf::new();
     relative_url.push(&media_root);
+    if let Some(tenant) = tenant {
+        relative_url.push(TENANTS_DIRECTORY);
+        relative_url.push(tenant);
+    }
     relative_url.push("background-remover");
 
     match for_image {
         ForImage::OriginalImage(uuid, filename) => {
-            relative_url.push(uuid.to_string());
+            relative_url = resolve_task_directory(&relative_url, uuid, shard_levels()).await?;
             relative_url.push("original");
 
             // Creates directories if not exists.
-            if !relative_url.exists() {
-                std::fs::create_dir_all(&relative_url)?;
-            }
+            tokio::fs::create_dir_all(&relative_url).await?;
 
             relative_url.push(filename);
 
@@ -138,13 +337,11 @@ pub fn generate_save_path(for_image: ForImage) -> std::io::Result<PathBuf> {
         }
 
         ForImage::PreviewOriginalImage(uuid, filename) => {
-            relative_url.push(uuid.to_string());
+            relative_url = resolve_task_directory(&relative_url, uuid, shard_levels()).await?;
             relative_url.push("preview-original");
 
             // Creates directories if not exists.
-            if !relative_url.exists() {
-                std::fs::create_dir_all(&relative_url)?;
-            }
+            tokio::fs::create_dir_all(&relative_url).await?;
 
             relative_url.push(filename);
 
@@ -155,13 +352,11 @@ pub fn generate_save_path(for_image: ForImage) -> std::io::Result<PathBuf> {
         }
 
         ForImage::MaskImage(uuid, filename) => {
-            relative_url.push(uuid.to_string());
+            relative_url = resolve_task_directory(&relative_url, uuid, shard_levels()).await?;
             relative_url.push("mask");
 
             // Creates directories if not exists.
-            if !relative_url.exists() {
-                std::fs::create_dir_all(&relative_url)?;
-            }
+            tokio::fs::create_dir_all(&relative_url).await?;
 
             relative_url.push(filename);
 
@@ -172,13 +367,11 @@ pub fn generate_save_path(for_image: ForImage) -> std::io::Result<PathBuf> {
         }
 
         ForImage::TransparentImage(uuid, filename) => {
-            relative_url.push(uuid.to_string());
+            relative_url = resolve_task_directory(&relative_url, uuid, shard_levels()).await?;
             relative_url.push("transparent");
 
             // Creates directories if not exists.
-            if !relative_url.exists() {
-                std::fs::create_dir_all(&relative_url)?;
-            }
+            tokio::fs::create_dir_all(&relative_url).await?;
 
             relative_url.push(filename);
 
@@ -189,13 +382,75 @@ pub fn generate_save_path(for_image: ForImage) -> std::io::Result<PathBuf> {
         }
 
         ForImage::PreviewTransparentImage(uuid, filename) => {
-            relative_url.push(uuid.to_string());
+            relative_url = resolve_task_directory(&relative_url, uuid, shard_levels()).await?;
             relative_url.push("preview-transparent");
 
             // Creates directories if not exists.
-            if !relative_url.exists() {
-                std::fs::create_dir_all(&relative_url)?;
-            }
+            tokio::fs::create_dir_all(&relative_url).await?;
+
+            relative_url.push(filename);
+
+            Ok(file_path_from_relative_url(
+                PathBuf::from(media_root),
+                relative_url,
+            ))
+        }
+
+        ForImage::CanaryImage(uuid, filename) => {
+            relative_url = resolve_task_directory(&relative_url, uuid, shard_levels()).await?;
+            relative_url.push("canary");
+
+            // Creates directories if not exists.
+            tokio::fs::create_dir_all(&relative_url).await?;
+
+            relative_url.push(filename);
+
+            Ok(file_path_from_relative_url(
+                PathBuf::from(media_root),
+                relative_url,
+            ))
+        }
+
+        ForImage::ComparisonImage(uuid, filename) => {
+            relative_url = resolve_task_directory(&relative_url, uuid, shard_levels()).await?;
+            relative_url.push("comparisons");
+
+            // Creates directories if not exists.
+            tokio::fs::create_dir_all(&relative_url).await?;
+
+            relative_url.push(filename);
+
+            Ok(file_path_from_relative_url(
+                PathBuf::from(media_root),
+                relative_url,
+            ))
+        }
+
+        ForImage::ContactSheet(task_group, filename) => {
+            relative_url.pop();
+            relative_url.push("background-remover-groups");
+            relative_url.push(task_group.to_string());
+            relative_url.push("contact-sheet");
+
+            // Creates directories if not exists.
+            tokio::fs::create_dir_all(&relative_url).await?;
+
+            relative_url.push(filename);
+
+            Ok(file_path_from_relative_url(
+                PathBuf::from(media_root),
+                relative_url,
+            ))
+        }
+
+        ForImage::RevisionImage(task_uuid, revision_uuid, kind, filename) => {
+            relative_url = resolve_task_directory(&relative_url, task_uuid, shard_levels()).await?;
+            relative_url.push("revisions");
+            relative_url.push(revision_uuid.to_string());
+            relative_url.push(kind);
+
+            // Creates directories if not exists.
+            tokio::fs::create_dir_all(&relative_url).await?;
 
             relative_url.push(filename);
 
@@ -207,6 +462,44 @@ pub fn generate_save_path(for_image: ForImage) -> std::io::Result<PathBuf> {
     }
 }
 
+///
+/// Directory of `MEDIA_ROOT` holding every file of the task with `key`, including revisions,
+/// comparisons and canary outputs: the existing one, or else where `generate_save_path` would
+/// create it with `levels` shard levels, see `shard_levels`.
+///
+pub async fn task_directory(
+    media_root: &Path,
+    tenant: Option<&str>,
+    key: &Uuid,
+    levels: usize,
+) -> std::io::Result<PathBuf> {
+    let tasks_dir = tenant_directory(media_root, tenant)?.join("background-remover");
+    resolve_task_directory(&tasks_dir, key, levels).await
+}
+
+///
+/// Directory of `MEDIA_ROOT` holding the contact sheets of a task group.
+///
+pub fn task_group_directory(
+    media_root: &Path,
+    tenant: Option<&str>,
+    task_group: &Uuid,
+) -> std::io::Result<PathBuf> {
+    Ok(tenant_directory(media_root, tenant)?
+        .join("background-remover-groups")
+        .join(task_group.to_string()))
+}
+
+fn tenant_directory(media_root: &Path, tenant: Option<&str>) -> std::io::Result<PathBuf> {
+    match tenant {
+        Some(tenant) => {
+            ensure_plain_filename(tenant)?;
+            Ok(media_root.join(TENANTS_DIRECTORY).join(tenant))
+        }
+        None => Ok(media_root.to_path_buf()),
+    }
+}
+
 #[cfg(test)]
 pub mod test {
     use std::path::PathBuf;
@@ -247,4 +540,119 @@ pub mod test {
         let relative_url = super::relative_media_url_from_full_path(&media_root, &full_path);
         assert_eq!(PathBuf::from("media/example.txt"), relative_url);
     }
+
+    #[test]
+    pub fn test_sanitize_filename() {
+        let sanitize = |filename: &str| super::sanitize_filename(filename);
+
+        assert_eq!(
+            Some("my-photo-1.jpg".to_string()),
+            sanitize("My Photo (1).JPG")
+        );
+        assert_eq!(
+            Some("x.png".to_string()),
+            sanitize("../../etc/cron.d/x.png")
+        );
+        assert_eq!(Some("x.png".to_string()), sanitize("..\\..\\x.png"));
+        assert_eq!(Some("image.png".to_string()), sanitize("ファイル.png"));
+        assert_eq!(Some("image.webp".to_string()), sanitize(".webp"));
+        assert_eq!(None, sanitize("../../etc/cron.d/x"));
+        assert_eq!(None, sanitize("shell.php"));
+
+        let long = format!("{}.png", "a".repeat(500));
+        assert_eq!(Some(format!("{}.png", "a".repeat(64))), sanitize(&long));
+    }
+
+    #[test]
+    pub fn test_content_filename() {
+        // SHA-256 of "hello" starts with 2cf24dba5fb0a30e.
+        assert_eq!(
+            "2cf24dba5fb0a30e.png",
+            super::content_filename(b"hello", "png")
+        );
+        assert_ne!(
+            super::content_filename(b"a", "jpg"),
+            super::content_filename(b"b", "jpg")
+        );
+    }
+
+    #[test]
+    pub fn test_ensure_plain_filename() {
+        assert!(super::ensure_plain_filename("image.png").is_ok());
+        assert!(super::ensure_plain_filename("../image.png").is_err());
+        assert!(super::ensure_plain_filename("a/image.png").is_err());
+        assert!(super::ensure_plain_filename("/image.png").is_err());
+        assert!(super::ensure_plain_filename("..").is_err());
+        assert!(super::ensure_plain_filename("").is_err());
+    }
+
+    #[tokio::test]
+    pub async fn test_task_directory() {
+        let media_root = PathBuf::from("/media");
+        let key = uuid::Uuid::nil();
+
+        assert_eq!(
+            PathBuf::from(format!("/media/background-remover/00/00/{}", key)),
+            super::task_directory(&media_root, None, &key, 2)
+                .await
+                .unwrap()
+        );
+        assert_eq!(
+            PathBuf::from(format!("/media/background-remover/{}", key)),
+            super::task_directory(&media_root, None, &key, 0)
+                .await
+                .unwrap()
+        );
+        assert_eq!(
+            PathBuf::from(format!(
+                "/media/tenants/org-1/background-remover-groups/{}",
+                key
+            )),
+            super::task_group_directory(&media_root, Some("org-1"), &key).unwrap()
+        );
+        assert!(
+            super::task_directory(&media_root, Some("../org-1"), &key, 2)
+                .await
+                .is_err()
+        );
+    }
+
+    #[test]
+    pub fn test_shard_path() {
+        let key = uuid::Uuid::parse_str("abcd0123-4567-89ab-cdef-0123456789ab").unwrap();
+
+        assert_eq!(PathBuf::from(key.to_string()), super::shard_path(&key, 0));
+        assert_eq!(
+            PathBuf::from(format!("ab/cd/{}", key)),
+            super::shard_path(&key, 2)
+        );
+        assert_eq!(
+            PathBuf::from(format!("ab/cd/01/23/{}", key)),
+            super::shard_path(&key, 9)
+        );
+
+        assert!(super::is_shard_name("0f"));
+        assert!(!super::is_shard_name("0F"));
+        assert!(!super::is_shard_name("abc"));
+        assert!(!super::is_shard_name(&key.to_string()));
+    }
+
+    #[test]
+    pub fn test_find_task_directory() {
+        let tasks_dir =
+            std::env::temp_dir().join(format!("bp-path-utils-{}", uuid::Uuid::new_v4()));
+        let key = uuid::Uuid::new_v4();
+
+        // New tasks use the given levels.
+        assert_eq!(
+            tasks_dir.join(super::shard_path(&key, 2)),
+            super::find_task_directory(&tasks_dir, &key, 2)
+        );
+
+        // Tasks stored with other levels keep their directory.
+        let unsharded = tasks_dir.join(key.to_string());
+        std::fs::create_dir_all(&unsharded).unwrap();
+        assert_eq!(unsharded, super::find_task_directory(&tasks_dir, &key, 2));
+        std::fs::remove_dir_all(&tasks_dir).unwrap();
+    }
 }
@@ -0,0 +1,334 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::{Display, Formatter};
+
+use serde::de::{self, DeserializeOwned, DeserializeSeed, IntoDeserializer, MapAccess, Visitor};
+use serde::forward_to_deserialize_any;
+
+///
+/// Invalid query parameters, as `field_errors` of the response: messages by parameter name.
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryError {
+    pub field_errors: BTreeMap<String, Vec<String>>,
+}
+
+impl QueryError {
+    ///
+    /// Error of a single parameter, for checks done after parsing, e.g. ranges.
+    ///
+    pub fn field(name: &str, message: impl ToString) -> Self {
+        let mut field_errors = BTreeMap::new();
+        field_errors.insert(name.to_string(), vec![message.to_string()]);
+        Self { field_errors }
+    }
+}
+
+///
+/// Deserializes the query parameters read by `value`, e.g. `request.query_params.value(..)`,
+/// into struct `T`. Each field is looked up by its serde name and parsed from its string, so
+/// `page: Option<u32>` accepts `?page=2`, unit enums match their variant names and unknown
+/// parameters are ignored. Missing `Option` fields are `None`.
+///
+/// Every invalid parameter is reported, not only the first one.
+///
+pub fn parse_query<T: DeserializeOwned>(
+    value: impl Fn(&str) -> Option<String>,
+) -> Result<T, QueryError> {
+    let mut field_errors = BTreeMap::new();
+    let mut skipped = HashSet::new();
+
+    // Invalid parameters are left out and the struct parsed again, so the errors of the
+    // following ones are collected as well. A required field left out ends the loop.
+    loop {
+        let deserializer = QueryDeserializer {
+            value: &value,
+            skipped: &skipped,
+        };
+        let error = match T::deserialize(deserializer) {
+            Ok(query) if field_errors.is_empty() => return Ok(query),
+            Ok(_) => return Err(QueryError { field_errors }),
+            Err(error) => error,
+        };
+
+        let field = error.field.unwrap_or("query");
+        field_errors
+            .entry(field.to_string())
+            .or_insert_with(|| vec![error.message]);
+        if error.field.is_none() || !skipped.insert(field) {
+            return Err(QueryError { field_errors });
+        }
+    }
+}
+
+///
+/// Error of a single parameter, `field` is set once known.
+///
+#[derive(Debug)]
+struct FieldError {
+    field: Option<&'static str>,
+    message: String,
+}
+
+impl Display for FieldError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for FieldError {}
+
+impl de::Error for FieldError {
+    fn custom<T: Display>(message: T) -> Self {
+        Self {
+            field: None,
+            message: message.to_string(),
+        }
+    }
+
+    fn missing_field(field: &'static str) -> Self {
+        Self {
+            field: Some(field),
+            message: "This parameter is required.".to_string(),
+        }
+    }
+
+    fn unknown_variant(_: &str, expected: &'static [&'static str]) -> Self {
+        Self::custom(format!("Must be one of {}.", expected.join(", ")))
+    }
+}
+
+struct QueryDeserializer<'a, F> {
+    value: &'a F,
+    skipped: &'a HashSet<&'static str>,
+}
+
+impl<'de, F: Fn(&str) -> Option<String>> de::Deserializer<'de> for QueryDeserializer<'_, F> {
+    type Error = FieldError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value, Self::Error> {
+        Err(de::Error::custom(
+            "Query parameters are parsed into structs only.",
+        ))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        visitor.visit_map(QueryFields {
+            fields: fields.iter(),
+            value: self.value,
+            skipped: self.skipped,
+            pending: None,
+        })
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 char str string bytes byte_buf option unit
+        unit_struct newtype_struct seq tuple tuple_struct map enum identifier ignored_any
+    }
+}
+
+///
+/// Fields of the struct which have a parameter, in declaration order.
+///
+struct QueryFields<'a, F> {
+    fields: std::slice::Iter<'static, &'static str>,
+    value: &'a F,
+    skipped: &'a HashSet<&'static str>,
+    pending: Option<(&'static str, String)>,
+}
+
+impl<'de, F: Fn(&str) -> Option<String>> MapAccess<'de> for QueryFields<'_, F> {
+    type Error = FieldError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Self::Error> {
+        for &field in self.fields.by_ref() {
+            if self.skipped.contains(field) {
+                continue;
+            }
+            if let Some(value) = (self.value)(field) {
+                self.pending = Some((field, value));
+                return seed.deserialize(field.into_deserializer()).map(Some);
+            }
+        }
+        Ok(None)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> Result<V::Value, Self::Error> {
+        let (field, value) = self
+            .pending
+            .take()
+            .ok_or_else(|| de::Error::custom("Value read before its parameter."))?;
+        seed.deserialize(ParamDeserializer(value))
+            .map_err(|error: FieldError| FieldError {
+                field: Some(field),
+                message: error.message,
+            })
+    }
+}
+
+///
+/// Value of a single parameter, parsed into the type of its field.
+///
+struct ParamDeserializer(String);
+
+macro_rules! deserialize_number {
+    ($($method:ident $visit:ident $type:ty, $message:literal;)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+                match self.0.trim().parse::<$type>() {
+                    Ok(value) => visitor.$visit(value),
+                    Err(_) => Err(de::Error::custom($message)),
+                }
+            }
+        )*
+    };
+}
+
+impl<'de> de::Deserializer<'de> for ParamDeserializer {
+    type Error = FieldError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_string(self.0)
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self.0.trim() {
+            "true" => visitor.visit_bool(true),
+            "false" => visitor.visit_bool(false),
+            _ => Err(de::Error::custom("Must be true or false.")),
+        }
+    }
+
+    deserialize_number! {
+        deserialize_i8 visit_i8 i8, "Must be a whole number.";
+        deserialize_i16 visit_i16 i16, "Must be a whole number.";
+        deserialize_i32 visit_i32 i32, "Must be a whole number.";
+        deserialize_i64 visit_i64 i64, "Must be a whole number.";
+        deserialize_u8 visit_u8 u8, "Must be a positive whole number.";
+        deserialize_u16 visit_u16 u16, "Must be a positive whole number.";
+        deserialize_u32 visit_u32 u32, "Must be a positive whole number.";
+        deserialize_u64 visit_u64 u64, "Must be a positive whole number.";
+        deserialize_f32 visit_f32 f32, "Must be a number.";
+        deserialize_f64 visit_f64 f64, "Must be a number.";
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        _: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        visitor.visit_enum(self.0.into_deserializer())
+    }
+
+    forward_to_deserialize_any! {
+        char str string bytes byte_buf unit unit_struct seq tuple tuple_struct map struct
+        identifier ignored_any
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::collections::HashMap;
+
+    use serde::Deserialize;
+
+    use super::{parse_query, QueryError};
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    #[serde(rename_all = "lowercase")]
+    enum Format {
+        Csv,
+        Ndjson,
+    }
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct Query {
+        page: Option<u32>,
+        tag: Option<String>,
+        #[serde(default)]
+        dry_run: bool,
+        format: Option<Format>,
+        limit: i64,
+    }
+
+    fn parse(params: &[(&str, &str)]) -> Result<Query, QueryError> {
+        let params: HashMap<&str, &str> = params.iter().copied().collect();
+        parse_query(|name| params.get(name).map(|value| value.to_string()))
+    }
+
+    #[test]
+    pub fn test_parse_query() {
+        assert_eq!(
+            Ok(Query {
+                page: Some(2),
+                tag: Some("a b".to_string()),
+                dry_run: true,
+                format: Some(Format::Ndjson),
+                limit: -1,
+            }),
+            parse(&[
+                ("page", "2"),
+                ("tag", "a b"),
+                ("dry_run", "true"),
+                ("format", "ndjson"),
+                ("limit", "-1"),
+                ("unknown", "x"),
+            ])
+        );
+        assert_eq!(
+            Ok(Query {
+                page: None,
+                tag: None,
+                dry_run: false,
+                format: None,
+                limit: 5,
+            }),
+            parse(&[("limit", "5")])
+        );
+        assert_eq!(
+            Err(QueryError::field("limit", "This parameter is required.")),
+            parse(&[])
+        );
+    }
+
+    #[test]
+    pub fn test_every_invalid_parameter_is_reported() {
+        let error = parse(&[
+            ("page", "-1"),
+            ("dry_run", "yes"),
+            ("format", "xml"),
+            ("limit", "1.5"),
+        ])
+        .unwrap_err();
+
+        let errors = |field: &str| error.field_errors[field].join(" ");
+        assert_eq!(4, error.field_errors.len());
+        assert_eq!("Must be a positive whole number.", errors("page"));
+        assert_eq!("Must be true or false.", errors("dry_run"));
+        assert_eq!("Must be one of csv, ndjson.", errors("format"));
+        assert_eq!("Must be a whole number.", errors("limit"));
+    }
+}
@@ -0,0 +1,181 @@
+use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Orders frames recorded within the same millisecond.
+static FRAME_SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+///
+/// File of a recorded frame. `data` is base64 encoded, so frames stay plain JSON which can be
+/// edited by hand, e.g. to drop a file.
+///
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordedFile {
+    pub name: String,
+    pub data: String,
+}
+
+///
+/// A `tej_protoc` frame received from a BP server, as stored in `BP_RECORD_DIR`.
+///
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordedFrame {
+    pub bp_address: String,
+    pub recorded_at: String,
+    pub message: Value,
+    #[serde(default)]
+    pub files: Vec<RecordedFile>,
+}
+
+impl RecordedFrame {
+    ///
+    /// Frame of `message` with `files` given as `(name, data)`.
+    ///
+    pub fn new(bp_address: &str, message: &Value, files: &[(&[u8], &[u8])]) -> Self {
+        Self {
+            bp_address: bp_address.to_string(),
+            recorded_at: Utc::now().to_rfc3339(),
+            message: message.clone(),
+            files: files
+                .iter()
+                .map(|(name, data)| RecordedFile {
+                    name: String::from_utf8_lossy(name).to_string(),
+                    data: STANDARD.encode(data),
+                })
+                .collect(),
+        }
+    }
+
+    ///
+    /// Decoded `(name, data)` of the files of the frame.
+    ///
+    pub fn decode_files(&self) -> Result<Vec<(Vec<u8>, Vec<u8>)>, String> {
+        self.files
+            .iter()
+            .map(|file| match STANDARD.decode(&file.data) {
+                Ok(data) => Ok((file.name.as_bytes().to_vec(), data)),
+                Err(error) => Err(format!("Invalid data of file {:?}. {}", file.name, error)),
+            })
+            .collect()
+    }
+}
+
+///
+/// Directory frames received from BP servers are recorded to: `BP_RECORD_DIR`. `None` disables
+/// recording.
+///
+pub fn record_dir() -> Option<PathBuf> {
+    match env::var("BP_RECORD_DIR") {
+        Ok(path) if !path.is_empty() => Some(PathBuf::from(path)),
+        _ => None,
+    }
+}
+
+///
+/// Writes `frame` to `dir` as `<unix millis>-<sequence>.json`, so file names sort in the order
+/// frames were received.
+///
+pub async fn record(dir: &Path, frame: &RecordedFrame) -> std::io::Result<PathBuf> {
+    tokio::fs::create_dir_all(dir).await?;
+
+    let sequence = FRAME_SEQUENCE.fetch_add(1, Ordering::Relaxed);
+    let path = dir.join(format!(
+        "{:013}-{:06}.json",
+        Utc::now().timestamp_millis(),
+        sequence % 1_000_000
+    ));
+    let json = serde_json::to_vec_pretty(frame).map_err(std::io::Error::other)?;
+    tokio::fs::write(&path, json).await?;
+    Ok(path)
+}
+
+///
+/// Reads every `.json` frame of `dir` in file name order. Fails on the first invalid frame, naming
+/// its file.
+///
+pub fn read_frames(dir: &Path) -> std::io::Result<Vec<(PathBuf, RecordedFrame)>> {
+    let mut paths: Vec<PathBuf> = std::fs::read_dir(dir)?
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|extension| extension == "json")
+        })
+        .collect();
+    paths.sort();
+
+    let mut frames = vec![];
+    for path in paths {
+        let frame: RecordedFrame = serde_json::from_slice(&std::fs::read(&path)?)
+            .map_err(|error| std::io::Error::other(format!("{:?}: {}", path, error)))?;
+        frames.push((path, frame));
+    }
+    Ok(frames)
+}
+
+#[cfg(test)]
+pub mod test {
+    use serde_json::json;
+
+    use super::{read_frames, RecordedFrame};
+
+    #[test]
+    pub fn test_recorded_frame() {
+        let message = json!({ "task_id": "7b1e", "status": "success" });
+        let frame = RecordedFrame::new(
+            "bp:9000",
+            &message,
+            &[(b"transparent.png", &[0, 1, 2]), (b"mask.png", &[])],
+        );
+
+        assert_eq!(message, frame.message);
+        assert_eq!(
+            vec![
+                (b"transparent.png".to_vec(), vec![0, 1, 2]),
+                (b"mask.png".to_vec(), vec![])
+            ],
+            frame.decode_files().unwrap()
+        );
+
+        // Frames written by hand may leave out files.
+        let frame: RecordedFrame = serde_json::from_value(json!({
+            "bp_address": "bp:9000",
+            "recorded_at": "2024-01-01T00:00:00Z",
+            "message": message,
+        }))
+        .unwrap();
+        assert!(frame.decode_files().unwrap().is_empty());
+
+        let mut frame = RecordedFrame::new("bp:9000", &message, &[(b"mask.png", &[1])]);
+        frame.files[0].data = "not base64!".to_string();
+        assert!(frame.decode_files().is_err());
+    }
+
+    #[test]
+    pub fn test_read_frames() {
+        let dir = std::env::temp_dir().join(format!("bp-replay-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let frame = RecordedFrame::new("bp:9000", &json!({ "status": "pending" }), &[]);
+        for name in ["0002.json", "0001.json"] {
+            std::fs::write(dir.join(name), serde_json::to_vec(&frame).unwrap()).unwrap();
+        }
+        std::fs::write(dir.join("notes.txt"), b"skipped").unwrap();
+
+        let frames = read_frames(&dir).unwrap();
+        assert_eq!(2, frames.len());
+        assert!(frames[0].0.ends_with("0001.json"));
+        assert_eq!(frame, frames[0].1);
+
+        std::fs::write(dir.join("0003.json"), b"{").unwrap();
+        assert!(read_frames(&dir).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,182 @@
+use std::fmt::Display;
+use std::future::Future;
+use std::time::Duration;
+
+use serde_json::{json, Value};
+
+///
+/// How often and how long apart a failed operation is attempted again.
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    /// Total number of attempts including the first one. `1` disables retries.
+    pub max_attempts: u32,
+    /// Delay before the first retry. Doubled for every following retry.
+    pub base: Duration,
+    /// Upper bound of the delay.
+    pub ceiling: Duration,
+    /// Up to this percentage of the delay is randomly taken off, so clients failing together
+    /// don't retry together.
+    pub jitter_percent: u32,
+}
+
+impl RetryPolicy {
+    ///
+    /// Delay after failed attempt `attempt` (starting at 1). `random` in `[0, 1)` picks the
+    /// jitter.
+    ///
+    pub fn delay(&self, attempt: u32, random: f64) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(31);
+        let delay = self.base.saturating_mul(1 << exponent).min(self.ceiling);
+
+        let jitter = self.jitter_percent.min(100) as f64 / 100.0 * random.clamp(0.0, 1.0);
+        delay.mul_f64(1.0 - jitter)
+    }
+
+    ///
+    /// Policy as recorded in the task event log.
+    ///
+    pub fn to_json(&self) -> Value {
+        json!({
+            "max_attempts": self.max_attempts,
+            "base_millis": self.base.as_millis() as u64,
+            "ceiling_millis": self.ceiling.as_millis() as u64,
+            "jitter_percent": self.jitter_percent,
+        })
+    }
+}
+
+///
+/// Failed attempt which was retried.
+///
+#[derive(Debug, Clone)]
+pub struct FailedAttempt {
+    /// Number of the failed attempt, starting at 1.
+    pub attempt: u32,
+    /// Delay waited before the next attempt.
+    pub delay: Duration,
+    pub error: String,
+}
+
+///
+/// Runs `operation` until it succeeds, fails with an error `is_retryable` rejects, or
+/// `policy.max_attempts` attempts were made. Every retried failure is appended to `failed`, so
+/// callers can record them. `operation` receives the attempt number starting at 1.
+///
+pub async fn retry<T, E, F, Fut>(
+    policy: &RetryPolicy,
+    is_retryable: impl Fn(&E) -> bool,
+    failed: &mut Vec<FailedAttempt>,
+    mut operation: F,
+) -> Result<T, E>
+where
+    E: Display,
+    F: FnMut(u32) -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let mut attempt = 1;
+    loop {
+        match operation(attempt).await {
+            Ok(output) => return Ok(output),
+            Err(error) if attempt < policy.max_attempts && is_retryable(&error) => {
+                let delay = policy.delay(attempt, rand::random::<f64>());
+                failed.push(FailedAttempt {
+                    attempt,
+                    delay,
+                    error: error.to_string(),
+                });
+
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::time::Duration;
+
+    use super::{retry, RetryPolicy};
+
+    #[test]
+    pub fn test_delay() {
+        let policy = RetryPolicy {
+            max_attempts: 5,
+            base: Duration::from_millis(100),
+            ceiling: Duration::from_millis(350),
+            jitter_percent: 50,
+        };
+
+        assert_eq!(Duration::from_millis(100), policy.delay(1, 0.0));
+        assert_eq!(Duration::from_millis(200), policy.delay(2, 0.0));
+        assert_eq!(Duration::from_millis(350), policy.delay(3, 0.0));
+        assert_eq!(Duration::from_millis(350), policy.delay(40, 0.0));
+        assert_eq!(Duration::from_millis(150), policy.delay(2, 0.5));
+
+        let no_jitter = RetryPolicy {
+            jitter_percent: 0,
+            ..policy
+        };
+        assert_eq!(Duration::from_millis(200), no_jitter.delay(2, 0.9));
+    }
+
+    #[tokio::test]
+    pub async fn test_retry() {
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            base: Duration::ZERO,
+            ceiling: Duration::ZERO,
+            jitter_percent: 0,
+        };
+
+        // Retryable failures are retried until an attempt succeeds.
+        let mut failed = vec![];
+        let result = retry(
+            &policy,
+            |_: &String| true,
+            &mut failed,
+            |attempt| async move {
+                match attempt {
+                    3 => Ok(attempt),
+                    _ => Err(format!("attempt {} failed", attempt)),
+                }
+            },
+        )
+        .await;
+        assert_eq!(Ok(3), result);
+        assert_eq!(
+            vec![1, 2],
+            failed
+                .iter()
+                .map(|failure| failure.attempt)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!("attempt 1 failed", failed[0].error);
+
+        // The last failure is returned once the attempts are used up.
+        let mut failed = vec![];
+        let result: Result<u32, String> = retry(
+            &policy,
+            |_: &String| true,
+            &mut failed,
+            |attempt| async move { Err(format!("attempt {} failed", attempt)) },
+        )
+        .await;
+        assert_eq!(Err("attempt 3 failed".to_string()), result);
+        assert_eq!(2, failed.len());
+
+        // Failures which can't succeed later are returned right away.
+        let mut failed = vec![];
+        let result: Result<u32, String> = retry(
+            &policy,
+            |error: &String| error != "fatal",
+            &mut failed,
+            |_| async move { Err("fatal".to_string()) },
+        )
+        .await;
+        assert_eq!(Err("fatal".to_string()), result);
+        assert!(failed.is_empty());
+    }
+}
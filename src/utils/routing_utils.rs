@@ -0,0 +1,73 @@
+///
+/// BP server dedicated to some upload signing key ids.
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct DedicatedRoute {
+    pub host: String,
+    pub key_ids: Vec<String>,
+}
+
+///
+/// Parses comma separated `<key id>:<host>` pairs, e.g. `acme:bp-acme.internal,globex:10.0.3.7`.
+/// Key ids pinned to the same host share one route. A key id may only be pinned once.
+///
+pub fn parse_dedicated_routes(value: &str) -> Result<Vec<DedicatedRoute>, String> {
+    let mut routes: Vec<DedicatedRoute> = vec![];
+    for pair in value
+        .split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+    {
+        // Key ids can't contain `:`, so hosts may, e.g. IPv6 addresses.
+        let (key_id, host) = match pair.split_once(':') {
+            Some((key_id, host)) if !key_id.is_empty() && !host.is_empty() => (key_id, host),
+            _ => return Err(format!("Expected <key id>:<host>, found {:?}.", pair)),
+        };
+
+        if routes
+            .iter()
+            .any(|route| route.key_ids.iter().any(|existing| existing == key_id))
+        {
+            return Err(format!("Key id {:?} is pinned twice.", key_id));
+        }
+
+        match routes.iter_mut().find(|route| route.host == host) {
+            Some(route) => route.key_ids.push(key_id.to_string()),
+            None => routes.push(DedicatedRoute {
+                host: host.to_string(),
+                key_ids: vec![key_id.to_string()],
+            }),
+        }
+    }
+    Ok(routes)
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::{parse_dedicated_routes, DedicatedRoute};
+
+    #[test]
+    pub fn test_parse_dedicated_routes() {
+        let routes =
+            parse_dedicated_routes("acme:bp-acme, acme-eu:bp-acme,globex:fd00::7").unwrap();
+        assert_eq!(
+            vec![
+                DedicatedRoute {
+                    host: "bp-acme".to_string(),
+                    key_ids: vec!["acme".to_string(), "acme-eu".to_string()],
+                },
+                DedicatedRoute {
+                    host: "fd00::7".to_string(),
+                    key_ids: vec!["globex".to_string()],
+                },
+            ],
+            routes
+        );
+        assert!(parse_dedicated_routes("").unwrap().is_empty());
+
+        assert!(parse_dedicated_routes("acme").is_err());
+        assert!(parse_dedicated_routes(":bp-acme").is_err());
+        assert!(parse_dedicated_routes("acme:").is_err());
+        assert!(parse_dedicated_routes("acme:bp-1,acme:bp-2").is_err());
+    }
+}
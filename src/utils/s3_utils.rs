@@ -0,0 +1,243 @@
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+///
+/// Object created in a bucket, from an S3 event notification.
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObjectCreated {
+    pub bucket: String,
+    pub key: String,
+    /// Orders events of the same key. The same for redeliveries of an event.
+    pub sequencer: Option<String>,
+}
+
+///
+/// Parses the body of an SQS message holding an S3 event notification, either sent by S3
+/// directly or through SNS. Returns the created objects; other events, e.g. deletions and the
+/// test event S3 sends when notifications are configured, are left out.
+///
+pub fn parse_notification(body: &str) -> Result<Vec<ObjectCreated>, String> {
+    let mut notification: Value =
+        serde_json::from_str(body).map_err(|error| format!("Invalid JSON. {}", error))?;
+
+    // SNS wraps the notification as string in `Message`.
+    if let Some(message) = notification.get("Message").and_then(Value::as_str) {
+        notification = serde_json::from_str(message)
+            .map_err(|error| format!("Invalid JSON in SNS message. {}", error))?;
+    }
+
+    if notification.get("Event").and_then(Value::as_str) == Some("s3:TestEvent") {
+        return Ok(vec![]);
+    }
+
+    let records = notification
+        .get("Records")
+        .and_then(Value::as_array)
+        .ok_or("Notification has no Records.")?;
+
+    let mut objects = vec![];
+    for record in records {
+        let is_created = record
+            .get("eventName")
+            .and_then(Value::as_str)
+            .is_some_and(|name| name.starts_with("ObjectCreated:"));
+        if !is_created {
+            continue;
+        }
+
+        let bucket = record.pointer("/s3/bucket/name").and_then(Value::as_str);
+        let key = record.pointer("/s3/object/key").and_then(Value::as_str);
+        let (bucket, key) = match (bucket, key) {
+            (Some(bucket), Some(key)) => (bucket, decode_key(key)?),
+            _ => return Err("Record has no bucket name or object key.".to_string()),
+        };
+
+        objects.push(ObjectCreated {
+            bucket: bucket.to_string(),
+            key,
+            sequencer: record
+                .pointer("/s3/object/sequencer")
+                .and_then(Value::as_str)
+                .map(str::to_string),
+        });
+    }
+    Ok(objects)
+}
+
+///
+/// Decodes an object key of an event notification, which are URL encoded with `+` for spaces.
+///
+pub fn decode_key(raw: &str) -> Result<String, String> {
+    let invalid = || format!("Object key {:?} is not URL encoded.", raw);
+
+    let mut bytes = Vec::with_capacity(raw.len());
+    let mut chars = raw.bytes();
+    while let Some(byte) = chars.next() {
+        match byte {
+            b'+' => bytes.push(b' '),
+            b'%' => {
+                let high = chars.next().and_then(|byte| (byte as char).to_digit(16));
+                let low = chars.next().and_then(|byte| (byte as char).to_digit(16));
+                match (high, low) {
+                    (Some(high), Some(low)) => bytes.push((high * 16 + low) as u8),
+                    _ => return Err(invalid()),
+                }
+            }
+            byte => bytes.push(byte),
+        }
+    }
+    String::from_utf8(bytes).map_err(|_| invalid())
+}
+
+///
+/// Path of `key` below `prefix`, or `None` when the key is outside of it or a folder marker.
+///
+pub fn relative_key<'a>(key: &'a str, prefix: &str) -> Option<&'a str> {
+    match key.strip_prefix(prefix) {
+        Some(relative) if !relative.is_empty() && !relative.ends_with('/') => Some(relative),
+        _ => None,
+    }
+}
+
+///
+/// Key of a result of the object at `relative` below `result_prefix`: the same path with the
+/// extension replaced by `suffix`, e.g. `processed/batch/a.mask.png` for `batch/a.jpg` and
+/// `.mask.png`.
+///
+pub fn result_key(result_prefix: &str, relative: &str, suffix: &str) -> String {
+    let (directory, filename) = match relative.rsplit_once('/') {
+        Some((directory, filename)) => (Some(directory), filename),
+        None => (None, relative),
+    };
+    let stem = match filename.rsplit_once('.') {
+        Some((stem, _)) if !stem.is_empty() => stem,
+        _ => filename,
+    };
+
+    match directory {
+        Some(directory) => format!("{}{}/{}{}", result_prefix, directory, stem, suffix),
+        None => format!("{}{}{}", result_prefix, stem, suffix),
+    }
+}
+
+///
+/// Content type of a result written to `key`, from its extension.
+///
+pub fn content_type(key: &str) -> &'static str {
+    match key.rsplit_once('.').map(|(_, extension)| extension) {
+        Some("png") => "image/png",
+        Some("webp") => "image/webp",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("json") => "application/json",
+        _ => "application/octet-stream",
+    }
+}
+
+///
+/// Task key of `object`, the same for every delivery of its event, so redelivered events create
+/// their task once. A later upload to the same key is a new task.
+///
+pub fn task_key(object: &ObjectCreated) -> Uuid {
+    let name = format!(
+        "s3-object:{}/{}:{}",
+        object.bucket,
+        object.key,
+        object.sequencer.as_deref().unwrap_or_default()
+    );
+    let digest = Sha256::digest(name.as_bytes());
+    let mut bytes = [0u8; 16];
+    bytes.copy_from_slice(&digest[..16]);
+    uuid::Builder::from_random_bytes(bytes).into_uuid()
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::{
+        content_type, decode_key, parse_notification, relative_key, result_key, task_key,
+        ObjectCreated,
+    };
+
+    #[test]
+    pub fn test_parse_notification() {
+        let body = r#"{"Records":[
+            {"eventName":"ObjectCreated:Put","s3":{"bucket":{"name":"acme"},
+             "object":{"key":"incoming/batch+1/a%C3%A9.jpg","sequencer":"0055AED6DCD90281E5"}}},
+            {"eventName":"ObjectRemoved:Delete","s3":{"bucket":{"name":"acme"},
+             "object":{"key":"incoming/b.jpg"}}}
+        ]}"#;
+        let created = ObjectCreated {
+            bucket: "acme".to_string(),
+            key: "incoming/batch 1/aé.jpg".to_string(),
+            sequencer: Some("0055AED6DCD90281E5".to_string()),
+        };
+        assert_eq!(Ok(vec![created.clone()]), parse_notification(body));
+
+        // Through SNS.
+        let wrapped = serde_json::json!({ "Type": "Notification", "Message": body }).to_string();
+        assert_eq!(Ok(vec![created]), parse_notification(&wrapped));
+
+        assert_eq!(
+            Ok(vec![]),
+            parse_notification(r#"{"Service":"Amazon S3","Event":"s3:TestEvent"}"#)
+        );
+        assert!(parse_notification("{}").is_err());
+        assert!(parse_notification("not json").is_err());
+    }
+
+    #[test]
+    pub fn test_decode_key() {
+        assert_eq!(Ok("a b/c+d.jpg".to_string()), decode_key("a+b/c%2Bd.jpg"));
+        assert!(decode_key("a%2").is_err());
+        assert!(decode_key("a%zz").is_err());
+        assert!(decode_key("%FF").is_err());
+    }
+
+    #[test]
+    pub fn test_relative_key() {
+        assert_eq!(
+            Some("batch/a.jpg"),
+            relative_key("incoming/batch/a.jpg", "incoming/")
+        );
+        assert_eq!(None, relative_key("incoming/batch/", "incoming/"));
+        assert_eq!(None, relative_key("incoming/", "incoming/"));
+        assert_eq!(None, relative_key("processed/a.png", "incoming/"));
+        assert_eq!(Some("a.jpg"), relative_key("a.jpg", ""));
+    }
+
+    #[test]
+    pub fn test_result_key() {
+        assert_eq!(
+            "processed/batch/a.png",
+            result_key("processed/", "batch/a.jpg", ".png")
+        );
+        assert_eq!(
+            "processed/a.mask.png",
+            result_key("processed/", "a.jpg", ".mask.png")
+        );
+        assert_eq!(
+            "processed/batch.v2/.hidden.failed.json",
+            result_key("processed/", "batch.v2/.hidden", ".failed.json")
+        );
+
+        assert_eq!("image/png", content_type("processed/a.mask.png"));
+        assert_eq!("application/json", content_type("processed/a.failed.json"));
+        assert_eq!("application/octet-stream", content_type("processed/a"));
+    }
+
+    #[test]
+    pub fn test_task_key() {
+        let object = ObjectCreated {
+            bucket: "acme".to_string(),
+            key: "incoming/batch/a.jpg".to_string(),
+            sequencer: Some("1".to_string()),
+        };
+        let again = ObjectCreated {
+            sequencer: Some("2".to_string()),
+            ..object.clone()
+        };
+        assert_eq!(task_key(&object), task_key(&object.clone()));
+        assert_ne!(task_key(&object), task_key(&again));
+    }
+}
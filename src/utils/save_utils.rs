@@ -1,12 +1,116 @@
-use std::ffi::OsStr;
+use std::env;
 use std::path::PathBuf;
 
 use tej_protoc::protoc::File;
 use tokio::io::AsyncWriteExt;
+use uuid::Uuid;
 
-use crate::db::models::BackgroundRemoverTask;
+use crate::db::models::{BackgroundRemoverTask, ManifestFile, TaskRevision};
 
 use super::path_utils::{self, ForImage};
+use super::{image_utils, signature_utils};
+
+///
+/// Files received from the BP server which can never be saved. Fails with `InvalidData`, so
+/// saving is not retried.
+///
+fn invalid_files<E>(error: E) -> std::io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    std::io::Error::new(std::io::ErrorKind::InvalidData, error)
+}
+
+///
+/// Checks dimensions declared by every image received from the BP server before it is saved, so
+/// a broken or hostile BP server can not plant images which exhaust memory when decoded later.
+///
+pub fn validate_bp_images(files: &[File]) -> std::io::Result<()> {
+    for (index, file) in files.iter().enumerate() {
+        if let Err(error) = image_utils::read_dimensions(&file.data) {
+            return Err(invalid_files(format!(
+                "Invalid image at index {} received from BP server. Error: {}",
+                index, error
+            )));
+        }
+    }
+    Ok(())
+}
+
+///
+/// Saves `data` in the directory of `path` under its content filename, e.g. a derived version of
+/// the file at `path`. Returns the path written.
+///
+pub async fn save_next_to(path: &PathBuf, data: &[u8]) -> std::io::Result<PathBuf> {
+    let dir = path
+        .parent()
+        .ok_or_else(|| std::io::Error::other(format!("No directory of {:?}.", path)))?;
+    let save_path = dir.join(path_utils::content_filename(data, "png"));
+    tokio::fs::write(&save_path, data).await?;
+    Ok(save_path)
+}
+
+///
+/// Describes a saved file for the task manifest from what was actually written to `path`.
+///
+pub async fn manifest_file(
+    role: &str,
+    media_root: &PathBuf,
+    path: &PathBuf,
+) -> std::io::Result<ManifestFile> {
+    let data = tokio::fs::read(path).await?;
+    Ok(ManifestFile {
+        role: role.to_string(),
+        path: path_utils::relative_media_url_from_full_path(media_root, path)
+            .to_string_lossy()
+            .to_string(),
+        size_bytes: data.len() as u64,
+        sha256: signature_utils::sha256_hex(&data),
+    })
+}
+
+///
+/// Copies the outputs of task `source` into the directories of task `key`, stored under `tenant`,
+/// and returns the manifest of the copies. Files are copied rather than shared, since they are
+/// removed together with the directory of their task.
+///
+pub async fn copy_outputs(
+    tenant: Option<&str>,
+    source: &BackgroundRemoverTask,
+    key: &Uuid,
+) -> std::io::Result<Vec<ManifestFile>> {
+    let media_root = PathBuf::from(env::var("MEDIA_ROOT").map_err(std::io::Error::other)?);
+    let roles = [
+        ManifestFile::MASK,
+        ManifestFile::PROCESSED,
+        ManifestFile::PREVIEW_PROCESSED,
+        ManifestFile::UNWATERMARKED,
+        ManifestFile::UNCAPPED_MASK,
+    ];
+
+    let mut manifest = vec![];
+    for role in roles {
+        let path = match source.output_path(role) {
+            Some(path) => PathBuf::from(path),
+            None => continue,
+        };
+        let filename = match path.file_name().and_then(|name| name.to_str()) {
+            Some(filename) => filename.to_string(),
+            None => return Err(invalid_files(format!("No filename in {:?}.", path))),
+        };
+        let for_image = match role {
+            ManifestFile::MASK | ManifestFile::UNCAPPED_MASK => ForImage::MaskImage(key, &filename),
+            ManifestFile::PREVIEW_PROCESSED => ForImage::PreviewTransparentImage(key, &filename),
+            _ => ForImage::TransparentImage(key, &filename),
+        };
+
+        let from = path_utils::file_path_from_relative_url(media_root.clone(), path);
+        let save_path = path_utils::generate_save_path(tenant, for_image).await?;
+        tokio::fs::copy(&from, &save_path).await?;
+        manifest.push(manifest_file(role, &media_root, &save_path).await?);
+    }
+    Ok(manifest)
+}
 
 ///
 /// Returns (transparent_image_path, mask_image_path, preview_transparent_image_path)
@@ -18,41 +122,44 @@ pub async fn save_files_received_from_bp_server(
 ) -> std::io::Result<(PathBuf, PathBuf, PathBuf)> {
     println!("Is fake processed: {}", is_fake_processed);
 
+    #[cfg(feature = "chaos")]
+    super::chaos_utils::slow_disk_write().await;
+
     if is_fake_processed {
         if files.len() < 2 {
-            return Err(std::io::Error::other(format!(
+            return Err(invalid_files(format!(
                 "Minimum 2 files required for fake processed. But received {}.",
                 files.len()
             )));
         }
     } else {
         if files.len() < 3 {
-            return Err(std::io::Error::other(format!(
+            return Err(invalid_files(format!(
                 "Minimum 3 files required. But received {}.",
                 files.len()
             )));
         }
     }
 
-    let original_image_path = PathBuf::from(&instance.original_image_path);
-    let filename_without_extension;
-    if let Some(filename_str) = original_image_path.file_stem() {
-        filename_without_extension = filename_str;
-    } else {
-        filename_without_extension = &OsStr::new("image.jpg");
-    };
+    validate_bp_images(files)?;
 
     let transparent_image = &files[0];
     let mask_image = &files[1];
     let preview_transparent_image = &files[0];
 
-    let png_filename = format!("{}.png", filename_without_extension.to_string_lossy());
+    // Outputs are stored under their content hash, so a reprocess never overwrites other files.
+    let transparent_filename = path_utils::content_filename(&transparent_image.data, "png");
+    let mask_filename = path_utils::content_filename(&mask_image.data, "png");
+    let preview_transparent_filename =
+        path_utils::content_filename(&preview_transparent_image.data, "png");
 
     // ======== Transparent image save begins ==========
-    let transparent_image_save_path = path_utils::generate_save_path(ForImage::TransparentImage(
-        &instance.key,
-        &png_filename.to_string(),
-    ))?;
+    let tenant = instance.tenant.as_deref();
+    let transparent_image_save_path = path_utils::generate_save_path(
+        tenant,
+        ForImage::TransparentImage(&instance.key, &transparent_filename),
+    )
+    .await?;
 
     if transparent_image_save_path.exists() {
         println!("Transparent image file already exists. Removing file.");
@@ -72,10 +179,9 @@ pub async fn save_files_received_from_bp_server(
     // Transparent image save ends.
 
     // ============= Mask image save begins ==============
-    let mask_image_save_path = path_utils::generate_save_path(ForImage::MaskImage(
-        &instance.key,
-        &png_filename.to_string(),
-    ))?;
+    let mask_image_save_path =
+        path_utils::generate_save_path(tenant, ForImage::MaskImage(&instance.key, &mask_filename))
+            .await?;
 
     if mask_image_save_path.exists() {
         println!("Mask image file already exists. Removing file.");
@@ -91,8 +197,10 @@ pub async fn save_files_received_from_bp_server(
 
     // Preview transparent image save ends
     let preview_transparent_image_save_path = path_utils::generate_save_path(
-        ForImage::PreviewTransparentImage(&instance.key, &png_filename.to_string()),
-    )?;
+        tenant,
+        ForImage::PreviewTransparentImage(&instance.key, &preview_transparent_filename),
+    )
+    .await?;
 
     if preview_transparent_image_save_path.exists() {
         println!("Preview transparent image file already exists. Removing file.");
@@ -117,3 +225,104 @@ pub async fn save_files_received_from_bp_server(
         preview_transparent_image_save_path,
     ))
 }
+
+///
+/// Saves the mask of a mask only task. BP servers which honor `outputs` send the mask alone;
+/// others send the usual files with the mask second.
+///
+/// Returns mask_image_path
+///
+pub async fn save_mask_received_from_bp_server(
+    instance: &BackgroundRemoverTask,
+    files: &Vec<File>,
+) -> std::io::Result<PathBuf> {
+    #[cfg(feature = "chaos")]
+    super::chaos_utils::slow_disk_write().await;
+
+    let mask_image = match files.len() {
+        0 => {
+            return Err(invalid_files(
+                "Mask required for mask only task. But received no files.",
+            ));
+        }
+        1 => &files[0],
+        _ => &files[1],
+    };
+    validate_bp_images(std::slice::from_ref(mask_image))?;
+
+    let mask_filename = path_utils::content_filename(&mask_image.data, "png");
+    let mask_image_save_path = path_utils::generate_save_path(
+        instance.tenant.as_deref(),
+        ForImage::MaskImage(&instance.key, &mask_filename),
+    )
+    .await?;
+
+    println!("Writing mask image to {:?}.", mask_image_save_path);
+    tokio::fs::write(&mask_image_save_path, &mask_image.data).await?;
+    Ok(mask_image_save_path)
+}
+
+///
+/// Saves outputs of a task revision with the media of its task, stored under `tenant`. BP server
+/// sends the same files as for a normal task.
+///
+/// Returns (transparent_image_path, mask_image_path, preview_transparent_image_path)
+///
+pub async fn save_revision_files_received_from_bp_server(
+    tenant: Option<&str>,
+    revision: &TaskRevision,
+    files: &Vec<File>,
+    is_fake_processed: bool,
+) -> std::io::Result<(PathBuf, PathBuf, PathBuf)> {
+    #[cfg(feature = "chaos")]
+    super::chaos_utils::slow_disk_write().await;
+
+    let required = if is_fake_processed { 2 } else { 3 };
+    if files.len() < required {
+        return Err(invalid_files(format!(
+            "Minimum {} files required. But received {}.",
+            required,
+            files.len()
+        )));
+    }
+
+    validate_bp_images(files)?;
+
+    let transparent_filename = path_utils::content_filename(&files[0].data, "png");
+    let mask_filename = path_utils::content_filename(&files[1].data, "png");
+    let transparent_image_save_path = path_utils::generate_save_path(
+        tenant,
+        ForImage::RevisionImage(
+            &revision.task_key,
+            &revision.key,
+            "transparent",
+            &transparent_filename,
+        ),
+    )
+    .await?;
+    let mask_image_save_path = path_utils::generate_save_path(
+        tenant,
+        ForImage::RevisionImage(&revision.task_key, &revision.key, "mask", &mask_filename),
+    )
+    .await?;
+    let preview_transparent_image_save_path = path_utils::generate_save_path(
+        tenant,
+        ForImage::RevisionImage(
+            &revision.task_key,
+            &revision.key,
+            "preview-transparent",
+            &transparent_filename,
+        ),
+    )
+    .await?;
+
+    tokio::fs::write(&transparent_image_save_path, &files[0].data).await?;
+    tokio::fs::write(&mask_image_save_path, &files[1].data).await?;
+    tokio::fs::write(&preview_transparent_image_save_path, &files[0].data).await?;
+
+    Ok((
+        transparent_image_save_path,
+        mask_image_save_path,
+        preview_transparent_image_save_path,
+    ))
+}
@@ -0,0 +1,343 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+/// Days searched for the next match of a cron expression. Covers leap days.
+const CRON_SEARCH_DAYS: i64 = 366 * 4;
+
+///
+/// Cron expression of five fields, `<minute> <hour> <day of month> <month> <day of week>`, in
+/// UTC. Fields take `*`, numbers, ranges `a-b`, steps `*/n` or `a-b/n` and comma separated lists
+/// of those. Days of week run from 0 (Sunday) to 6; 7 is Sunday too. As in cron, a time matches
+/// either day field when both are restricted.
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct CronSchedule {
+    expression: String,
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    days_restricted: bool,
+    weekdays_restricted: bool,
+}
+
+impl CronSchedule {
+    pub fn parse(expression: &str) -> Result<Self, String> {
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(format!(
+                "Expected 5 fields in cron expression {:?}.",
+                expression
+            ));
+        };
+
+        let mut weekdays_bits = parse_field(weekdays, 0, 7)?;
+        // Both 0 and 7 are Sunday.
+        if weekdays_bits & (1 << 7) != 0 {
+            weekdays_bits = (weekdays_bits | 1) & !(1 << 7);
+        }
+
+        Ok(Self {
+            expression: fields.join(" "),
+            minutes: parse_field(minutes, 0, 59)?,
+            hours: parse_field(hours, 0, 23)?,
+            days: parse_field(days, 1, 31)?,
+            months: parse_field(months, 1, 12)?,
+            weekdays: weekdays_bits,
+            days_restricted: days != "*",
+            weekdays_restricted: weekdays != "*",
+        })
+    }
+
+    fn matches_day(&self, day: u32, month: u32, weekday: u32) -> bool {
+        if self.months & (1 << month) == 0 {
+            return false;
+        }
+
+        let day_matches = self.days & (1 << day) != 0;
+        let weekday_matches = self.weekdays & (1 << weekday) != 0;
+        match (self.days_restricted, self.weekdays_restricted) {
+            (true, true) => day_matches || weekday_matches,
+            _ => day_matches && weekday_matches,
+        }
+    }
+
+    ///
+    /// First matching minute strictly after `after`, in Unix seconds. `None` if nothing matches
+    /// within four years, e.g. for February 30.
+    ///
+    pub fn next_after(&self, after: i64) -> Option<i64> {
+        let first_minute = after.div_euclid(60) + 1;
+        let first_day = first_minute.div_euclid(1440);
+
+        for day in first_day..first_day + CRON_SEARCH_DAYS {
+            let (_, month, day_of_month) = civil_from_days(day);
+            let weekday = (day + 4).rem_euclid(7) as u32;
+            if !self.matches_day(day_of_month, month, weekday) {
+                continue;
+            }
+
+            let start = if day == first_day {
+                first_minute.rem_euclid(1440)
+            } else {
+                0
+            };
+            for minute_of_day in start..1440 {
+                let (hour, minute) = (minute_of_day / 60, minute_of_day % 60);
+                if self.hours & (1 << hour) != 0 && self.minutes & (1 << minute) != 0 {
+                    return Some((day * 1440 + minute_of_day) * 60);
+                }
+            }
+        }
+        None
+    }
+}
+
+///
+/// Bits of the values a cron field allows, bit `n` for value `n`.
+///
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64, String> {
+    let mut bits = 0u64;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => match step.parse::<u32>() {
+                Ok(step) if step > 0 => (range, step),
+                _ => return Err(format!("Invalid step in cron field {:?}.", field)),
+            },
+            None => (part, 1),
+        };
+
+        let parse = |value: &str| match value.parse::<u32>() {
+            Ok(value) if (min..=max).contains(&value) => Ok(value),
+            _ => Err(format!(
+                "Cron field {:?} only takes values from {} to {}.",
+                field, min, max
+            )),
+        };
+        let (start, end) = match range {
+            "*" => (min, max),
+            range => match range.split_once('-') {
+                Some((start, end)) => (parse(start)?, parse(end)?),
+                // `5/15` runs from 5 to the end of the field.
+                None if step > 1 => (parse(range)?, max),
+                None => {
+                    let value = parse(range)?;
+                    (value, value)
+                }
+            },
+        };
+        if start > end {
+            return Err(format!("Invalid range in cron field {:?}.", field));
+        }
+
+        for value in (start..=end).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+///
+/// Year, month and day of the days since 1970-01-01 in the proleptic Gregorian calendar.
+///
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u32;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+///
+/// When a scheduled job runs: every fixed interval, aligned to the Unix epoch so every replica
+/// computes the same run times, or on a cron expression.
+///
+#[derive(Debug, Clone, PartialEq)]
+pub enum Schedule {
+    Every(Duration),
+    Cron(CronSchedule),
+}
+
+impl Schedule {
+    ///
+    /// Parses `@every <n>s|m|h`, `@hourly`, `@daily` or a cron expression.
+    ///
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let value = value.trim();
+        match value {
+            "@hourly" => return Schedule::parse("0 * * * *"),
+            "@daily" => return Schedule::parse("0 0 * * *"),
+            _ => {}
+        }
+
+        let Some(interval) = value.strip_prefix("@every ") else {
+            return CronSchedule::parse(value).map(Schedule::Cron);
+        };
+        let interval = interval.trim();
+        let (number, unit) = interval.split_at(interval.len().saturating_sub(1));
+        let secs = match (number.parse::<u64>(), unit) {
+            (Ok(number), "s") => number,
+            (Ok(number), "m") => number * 60,
+            (Ok(number), "h") => number * 60 * 60,
+            _ => return Err(format!("Invalid interval {:?}.", interval)),
+        };
+        if secs == 0 {
+            return Err("Interval must be positive.".to_string());
+        }
+        Ok(Schedule::Every(Duration::from_secs(secs)))
+    }
+
+    ///
+    /// First run time strictly after `after`, in Unix seconds.
+    ///
+    pub fn next_after(&self, after: i64) -> Option<i64> {
+        match self {
+            Schedule::Every(interval) => {
+                let secs = interval.as_secs().max(1) as i64;
+                Some((after.div_euclid(secs) + 1) * secs)
+            }
+            Schedule::Cron(cron) => cron.next_after(after),
+        }
+    }
+}
+
+impl Display for Schedule {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Schedule::Every(interval) => write!(f, "@every {}s", interval.as_secs()),
+            Schedule::Cron(cron) => write!(f, "{}", cron.expression),
+        }
+    }
+}
+
+///
+/// Parses `;` separated `<job>=<schedule>` entries, e.g. `media_gc=0 3 * * *;stats_rollup=@every
+/// 10m`. A schedule of `off` disables the job, given as `None`.
+///
+pub fn parse_job_schedules(value: &str) -> Result<HashMap<String, Option<Schedule>>, String> {
+    let mut schedules = HashMap::new();
+    for entry in value
+        .split(';')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+    {
+        let (name, schedule) = match entry.split_once('=') {
+            Some((name, schedule)) if !name.trim().is_empty() => (name.trim(), schedule.trim()),
+            _ => return Err(format!("Expected <job>=<schedule>, found {:?}.", entry)),
+        };
+
+        let schedule = match schedule {
+            "off" => None,
+            schedule => Some(
+                Schedule::parse(schedule)
+                    .map_err(|error| format!("Invalid schedule of {}. {}", name, error))?,
+            ),
+        };
+        if schedules.insert(name.to_string(), schedule).is_some() {
+            return Err(format!("Job {:?} is listed twice.", name));
+        }
+    }
+    Ok(schedules)
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::time::Duration;
+
+    use super::{civil_from_days, parse_job_schedules, CronSchedule, Schedule};
+
+    /// 2024-03-15T10:20:30Z, a Friday.
+    const NOW: i64 = 1_710_498_030;
+
+    #[test]
+    pub fn test_civil_from_days() {
+        assert_eq!((1970, 1, 1), civil_from_days(0));
+        assert_eq!((2024, 2, 29), civil_from_days(19_782));
+        assert_eq!((2024, 3, 15), civil_from_days(NOW / 86_400));
+        assert_eq!((1969, 12, 31), civil_from_days(-1));
+    }
+
+    #[test]
+    pub fn test_cron_schedule() {
+        let every_minute = CronSchedule::parse("* * * * *").unwrap();
+        assert_eq!(Some(1_710_498_060), every_minute.next_after(NOW));
+        assert_eq!(Some(1_710_498_060), every_minute.next_after(1_710_498_000));
+
+        // 03:00 the next day.
+        let nightly = Schedule::parse("0 3 * * *").unwrap();
+        assert_eq!(Some(1_710_558_000), nightly.next_after(NOW));
+
+        // Every 15 minutes from 10:00 to 11:59 on weekdays.
+        let business = CronSchedule::parse("*/15 10-11 * * 1-5").unwrap();
+        assert_eq!(Some(1_710_498_600), business.next_after(NOW));
+
+        // Sundays, given as 7, at 00:00.
+        let sunday = CronSchedule::parse("0 0 * * 7").unwrap();
+        assert_eq!(Some(1_710_633_600), sunday.next_after(NOW));
+
+        // Either the 1st or a Monday once both day fields are restricted.
+        let either = CronSchedule::parse("0 0 1 * 1").unwrap();
+        assert_eq!(Some(1_710_720_000), either.next_after(NOW));
+
+        assert_eq!(
+            None,
+            CronSchedule::parse("0 0 30 2 *").unwrap().next_after(NOW)
+        );
+
+        assert!(CronSchedule::parse("* * * *").is_err());
+        assert!(CronSchedule::parse("60 * * * *").is_err());
+        assert!(CronSchedule::parse("*/0 * * * *").is_err());
+        assert!(CronSchedule::parse("5-1 * * * *").is_err());
+    }
+
+    #[test]
+    pub fn test_schedule() {
+        assert_eq!(
+            Schedule::Every(Duration::from_secs(600)),
+            Schedule::parse("@every 10m").unwrap()
+        );
+        assert_eq!(
+            Schedule::parse("0 * * * *").unwrap(),
+            Schedule::parse("@hourly").unwrap()
+        );
+        assert_eq!(
+            "@every 600s",
+            Schedule::parse("@every 10m").unwrap().to_string()
+        );
+        assert_eq!(
+            "0 3 * * *",
+            Schedule::parse(" 0  3 * * *").unwrap().to_string()
+        );
+        assert!(Schedule::parse("@every 0s").is_err());
+        assert!(Schedule::parse("@every 10d").is_err());
+
+        // Aligned to the epoch.
+        let every = Schedule::parse("@every 1h").unwrap();
+        assert_eq!(Some(1_710_500_400), every.next_after(NOW));
+        assert_eq!(Some(1_710_504_000), every.next_after(1_710_500_400));
+    }
+
+    #[test]
+    pub fn test_parse_job_schedules() {
+        let schedules = parse_job_schedules("media_gc=0 3 * * *; stats_rollup=off;").unwrap();
+        assert!(matches!(schedules["media_gc"], Some(Schedule::Cron(_))));
+        assert_eq!(None, schedules["stats_rollup"]);
+        assert!(parse_job_schedules("").unwrap().is_empty());
+
+        assert!(parse_job_schedules("media_gc").is_err());
+        assert!(parse_job_schedules("media_gc=sometimes").is_err());
+        assert!(parse_job_schedules("media_gc=off;media_gc=@daily").is_err());
+    }
+}
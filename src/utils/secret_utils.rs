@@ -0,0 +1,52 @@
+use std::collections::HashMap;
+
+use serde_json::Value;
+
+///
+/// Returns string values of a Vault KV secret response. Works with KV version 2, which nests
+/// values under `data.data`, and version 1, which has them under `data`. Values of other types
+/// are skipped. `None` when the response has no data.
+///
+pub fn vault_kv_values(body: &Value) -> Option<HashMap<String, String>> {
+    let data = match body.pointer("/data/data") {
+        Some(Value::Object(data)) => data,
+        _ => match body.get("data") {
+            Some(Value::Object(data)) => data,
+            _ => return None,
+        },
+    };
+
+    Some(
+        data.iter()
+            .filter_map(|(name, value)| Some((name.clone(), value.as_str()?.to_string())))
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::collections::HashMap;
+
+    use serde_json::json;
+
+    use super::vault_kv_values;
+
+    #[test]
+    pub fn test_vault_kv_values() {
+        let expected = HashMap::from([("ADMIN_AUTH_TOKEN".to_string(), "a".to_string())]);
+
+        let version_2 = json!({
+            "data": {
+                "data": { "ADMIN_AUTH_TOKEN": "a", "PORT": 8000 },
+                "metadata": { "version": 3 }
+            }
+        });
+        assert_eq!(Some(expected.clone()), vault_kv_values(&version_2));
+
+        let version_1 = json!({ "data": { "ADMIN_AUTH_TOKEN": "a" }, "lease_duration": 0 });
+        assert_eq!(Some(expected), vault_kv_values(&version_1));
+
+        assert_eq!(None, vault_kv_values(&json!({ "errors": [] })));
+        assert_eq!(None, vault_kv_values(&json!({ "data": null })));
+    }
+}
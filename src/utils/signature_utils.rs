@@ -0,0 +1,275 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use sha2::{Digest, Sha256};
+
+use crate::utils::token_utils::Keyring;
+
+/// First line of the signed string, so upload signatures can't be valid for anything else.
+const UPLOAD_SIGNATURE_VERSION: &str = "bp-upload-v1";
+
+///
+/// Signature headers of a signed upload request.
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct UploadSignature {
+    /// Unix seconds the request was signed at. `X-Signature-Timestamp`.
+    pub timestamp: i64,
+    /// Unique per request, at most 128 characters. `X-Signature-Nonce`.
+    pub nonce: String,
+    /// Hex SHA-256 of the uploaded original image. `X-Content-SHA256`.
+    pub content_sha256: String,
+    /// `<kid>.<URL safe base64 HMAC-SHA256>` of the signed string. `X-Signature`.
+    pub signature: String,
+}
+
+impl UploadSignature {
+    ///
+    /// Parses the signature headers. `Err` names the first missing or malformed header.
+    ///
+    pub fn parse(
+        timestamp: Option<&str>,
+        nonce: Option<&str>,
+        content_sha256: Option<&str>,
+        signature: Option<&str>,
+    ) -> Result<Self, String> {
+        let timestamp = match timestamp.map(|value| value.trim().parse::<i64>()) {
+            Some(Ok(timestamp)) => timestamp,
+            _ => return Err("X-Signature-Timestamp must be unix seconds.".to_string()),
+        };
+
+        let nonce = match nonce.map(str::trim) {
+            Some(nonce) if !nonce.is_empty() && nonce.len() <= 128 => nonce.to_string(),
+            _ => return Err("X-Signature-Nonce must have 1 to 128 characters.".to_string()),
+        };
+
+        let content_sha256 = match content_sha256.and_then(parse_sha256) {
+            Some(hash) => hash,
+            None => return Err("X-Content-SHA256 must be a hex SHA-256.".to_string()),
+        };
+
+        let signature = match signature.map(str::trim) {
+            Some(signature) if !signature.is_empty() => signature.to_string(),
+            _ => return Err("X-Signature is missing.".to_string()),
+        };
+
+        Ok(Self {
+            timestamp,
+            nonce,
+            content_sha256,
+            signature,
+        })
+    }
+
+    ///
+    /// String covered by the signature, lines joined with `\n`:
+    ///
+    /// ```text
+    /// bp-upload-v1
+    /// POST
+    /// /v1/bp/u/
+    /// <timestamp>
+    /// <nonce>
+    /// <content sha256>
+    /// ```
+    ///
+    pub fn signed_string(&self, method: &str, path: &str) -> String {
+        [
+            UPLOAD_SIGNATURE_VERSION,
+            &method.to_ascii_uppercase(),
+            path,
+            &self.timestamp.to_string(),
+            &self.nonce,
+            &self.content_sha256,
+        ]
+        .join("\n")
+    }
+
+    ///
+    /// Whether the request was signed within `max_age_secs` of `now`, in either direction to
+    /// allow for clock skew.
+    ///
+    pub fn is_fresh(&self, now: i64, max_age_secs: u64) -> bool {
+        now.abs_diff(self.timestamp) <= max_age_secs
+    }
+
+    ///
+    /// Checks that the request `method` `path` was signed within `max_age_secs` of `now` by a key
+    /// of `keyring`. `Err` tells the client why not.
+    ///
+    pub fn verify(
+        &self,
+        keyring: &Keyring,
+        method: &str,
+        path: &str,
+        now: i64,
+        max_age_secs: u64,
+    ) -> Result<(), &'static str> {
+        if !self.is_fresh(now, max_age_secs) {
+            return Err("Signature expired.");
+        }
+
+        let signed_string = self.signed_string(method, path);
+        if !keyring.verify_upload_request(&signed_string, &self.signature) {
+            return Err("Signature mismatch.");
+        }
+        Ok(())
+    }
+
+    ///
+    /// Nonce prefixed with the key id of the signature, so clients can't burn each other's
+    /// nonces.
+    ///
+    pub fn scoped_nonce(&self) -> String {
+        let (kid, _) = self.signature.split_once('.').unwrap_or_default();
+        format!("{}:{}", kid, self.nonce)
+    }
+}
+
+///
+/// Nonces seen within their ttl, kept in memory by a single replica.
+///
+#[derive(Debug, Default)]
+pub struct NonceWindow {
+    expiries: HashMap<String, Instant>,
+}
+
+impl NonceWindow {
+    ///
+    /// Remembers `nonce` from `now` for `ttl`. Returns false if it was already seen within its
+    /// ttl.
+    ///
+    pub fn insert(&mut self, nonce: &str, ttl: Duration, now: Instant) -> bool {
+        self.expiries.retain(|_, expires_at| *expires_at > now);
+        if self.expiries.contains_key(nonce) {
+            return false;
+        }
+        self.expiries.insert(nonce.to_string(), now + ttl);
+        true
+    }
+}
+
+///
+/// Lowercase hex SHA-256 of `data`.
+///
+pub fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+///
+/// Lowercase hex SHA-256 declared by a client, or `None` if `value` isn't one.
+///
+pub fn parse_sha256(value: &str) -> Option<String> {
+    let value = value.trim();
+    let is_sha256 = value.len() == 64 && value.chars().all(|c| c.is_ascii_hexdigit());
+    is_sha256.then(|| value.to_ascii_lowercase())
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::time::{Duration, Instant};
+
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use base64::Engine;
+    use hmac::{Hmac, Mac};
+    use sha2::Sha256;
+
+    use super::{parse_sha256, sha256_hex, NonceWindow, UploadSignature};
+    use crate::utils::token_utils::Keyring;
+
+    #[test]
+    pub fn test_upload_signature() {
+        let hash = sha256_hex(b"image");
+        let signature =
+            UploadSignature::parse(Some("1700000000"), Some("n1"), Some(&hash), Some("k.sig"))
+                .unwrap();
+
+        assert_eq!(
+            format!("bp-upload-v1\nPOST\n/v1/bp/u/\n1700000000\nn1\n{}", hash),
+            signature.signed_string("post", "/v1/bp/u/")
+        );
+        assert!(signature.is_fresh(1700000300, 300));
+        assert!(signature.is_fresh(1699999700, 300));
+        assert!(!signature.is_fresh(1700000301, 300));
+
+        assert!(UploadSignature::parse(None, Some("n"), Some(&hash), Some("k.sig")).is_err());
+        assert!(UploadSignature::parse(Some("x"), Some("n"), Some(&hash), Some("k.sig")).is_err());
+        assert!(UploadSignature::parse(Some("1"), Some(""), Some(&hash), Some("k.sig")).is_err());
+        assert!(UploadSignature::parse(Some("1"), Some("n"), Some("abc"), Some("k.sig")).is_err());
+        assert!(UploadSignature::parse(Some("1"), Some("n"), Some(&hash), None).is_err());
+    }
+
+    #[test]
+    pub fn test_verify_upload_signature() {
+        let keyring = Keyring::parse("k2:secret,k1:old secret").unwrap();
+        let hash = sha256_hex(b"image");
+        let sign = |kid: &str, secret: &[u8], path: &str| {
+            let unsigned =
+                UploadSignature::parse(Some("1700000000"), Some("n1"), Some(&hash), Some("x"))
+                    .unwrap();
+            let mut mac = Hmac::<Sha256>::new_from_slice(secret).unwrap();
+            mac.update(unsigned.signed_string("POST", path).as_bytes());
+            let signature = URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes());
+            UploadSignature {
+                signature: format!("{}.{}", kid, signature),
+                ..unsigned
+            }
+        };
+
+        let signature = sign("k1", b"old secret", "/v1/bp/u/");
+        assert_eq!(
+            Ok(()),
+            signature.verify(&keyring, "POST", "/v1/bp/u/", 1700000100, 300)
+        );
+        assert_eq!("k1:n1", signature.scoped_nonce());
+        assert_eq!(
+            Err("Signature expired."),
+            signature.verify(&keyring, "POST", "/v1/bp/u/", 1700000301, 300)
+        );
+        assert_eq!(
+            Err("Signature mismatch."),
+            signature.verify(&keyring, "POST", "/v2/bp/u/", 1700000000, 300)
+        );
+        assert_eq!(
+            Err("Signature mismatch."),
+            sign("k2", b"other", "/v1/bp/u/").verify(
+                &keyring,
+                "POST",
+                "/v1/bp/u/",
+                1700000000,
+                300
+            )
+        );
+    }
+
+    #[test]
+    pub fn test_nonce_window() {
+        let mut nonces = NonceWindow::default();
+        let now = Instant::now();
+        let ttl = Duration::from_secs(600);
+
+        assert!(nonces.insert("k1:n1", ttl, now));
+        assert!(!nonces.insert("k1:n1", ttl, now + Duration::from_secs(599)));
+        assert!(nonces.insert("k2:n1", ttl, now));
+
+        // Expired nonces may be used again.
+        assert!(nonces.insert("k1:n1", ttl, now + ttl));
+    }
+
+    #[test]
+    pub fn test_sha256_hex() {
+        assert_eq!(
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+            sha256_hex(b"")
+        );
+        assert_eq!(
+            Some(sha256_hex(b"")),
+            parse_sha256(" E3B0C44298FC1C149AFBF4C8996FB92427AE41E4649B934CA495991B7852B855 ")
+        );
+        assert_eq!(None, parse_sha256("e3b0c442"));
+        assert_eq!(None, parse_sha256(&"g".repeat(64)));
+    }
+}
@@ -0,0 +1,348 @@
+use std::collections::HashSet;
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::utils::ingest_utils;
+
+/// Admin REST API version of Shopify requests.
+pub const SHOPIFY_API_VERSION: &str = "2024-07";
+
+/// Start of the alt text of pushed cutouts, followed by the id of their source image. See
+/// `cutout_alt`.
+const CUTOUT_ALT: &str = "Background removed from image ";
+
+/// Appended to the filename of pushed cutouts.
+const CUTOUT_SUFFIX: &str = "-cutout";
+
+///
+/// Store and credentials of a sync, tagged by `platform`. Never logged, as it holds secrets.
+///
+#[derive(Deserialize)]
+#[serde(tag = "platform", rename_all = "lowercase")]
+pub enum Store {
+    /// Custom app with the `read_products` and `write_products` scopes, e.g. `acme.myshopify.com`.
+    Shopify { shop: String, access_token: String },
+    /// REST API key with read and write permissions of the store at `url`.
+    Woocommerce {
+        url: String,
+        consumer_key: String,
+        consumer_secret: String,
+    },
+}
+
+impl Store {
+    pub fn platform(&self) -> &'static str {
+        match self {
+            Store::Shopify { .. } => "shopify",
+            Store::Woocommerce { .. } => "woocommerce",
+        }
+    }
+
+    ///
+    /// Checks the store address, so credentials are only sent to stores over HTTPS. Shopify
+    /// shops must be given by their `myshopify.com` domain.
+    ///
+    pub fn check(&self) -> Result<(), String> {
+        match self {
+            Store::Shopify { shop, access_token } => {
+                let name = shop.strip_suffix(".myshopify.com").unwrap_or_default();
+                let is_valid = !name.is_empty()
+                    && name
+                        .chars()
+                        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+                if !is_valid {
+                    return Err(
+                        "shop must be a myshopify.com domain, e.g. acme.myshopify.com.".to_string(),
+                    );
+                }
+                if access_token.is_empty() {
+                    return Err("access_token is required.".to_string());
+                }
+            }
+            Store::Woocommerce {
+                url,
+                consumer_key,
+                consumer_secret,
+            } => {
+                if !url.starts_with("https://") || ingest_utils::check_image_url(url).is_err() {
+                    return Err("url must be an https:// URL.".to_string());
+                }
+                if consumer_key.is_empty() || consumer_secret.is_empty() {
+                    return Err("consumer_key and consumer_secret are required.".to_string());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    ///
+    /// Base URL of the product endpoints.
+    ///
+    pub fn api_base(&self) -> String {
+        match self {
+            Store::Shopify { shop, .. } => {
+                format!("https://{}/admin/api/{}", shop, SHOPIFY_API_VERSION)
+            }
+            Store::Woocommerce { url, .. } => {
+                format!("{}/wp-json/wc/v3", url.trim_end_matches('/'))
+            }
+        }
+    }
+}
+
+///
+/// Image of a product as listed by the store.
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProductImage {
+    pub product_id: u64,
+    pub image_id: u64,
+    pub src: String,
+    pub alt: Option<String>,
+}
+
+impl ProductImage {
+    pub fn filename(&self) -> Option<String> {
+        ingest_utils::filename_from_url(&self.src)
+    }
+
+    ///
+    /// Id of the source image if this image is a cutout pushed by a sync.
+    ///
+    pub fn cutout_of(&self) -> Option<u64> {
+        self.alt.as_deref()?.strip_prefix(CUTOUT_ALT)?.parse().ok()
+    }
+}
+
+///
+/// Alt text of the cutout of image `image_id`, by which later syncs recognize it.
+///
+pub fn cutout_alt(image_id: u64) -> String {
+    format!("{}{}", CUTOUT_ALT, image_id)
+}
+
+///
+/// Images of `images` to process: neither cutouts nor images a cutout was pushed for, so
+/// syncing a store again only processes new images.
+///
+pub fn pending_images(images: Vec<ProductImage>) -> Vec<ProductImage> {
+    let cutouts: HashSet<u64> = images.iter().filter_map(ProductImage::cutout_of).collect();
+    images
+        .into_iter()
+        .filter(|image| image.cutout_of().is_none() && !cutouts.contains(&image.image_id))
+        .collect()
+}
+
+///
+/// Images of the products of a Shopify `products.json` response.
+///
+pub fn shopify_images(body: &Value) -> Vec<ProductImage> {
+    let products = body.get("products").and_then(Value::as_array);
+    products
+        .into_iter()
+        .flatten()
+        .filter_map(|product| product.get("images")?.as_array())
+        .flatten()
+        .filter_map(|image| {
+            Some(ProductImage {
+                product_id: image.get("product_id")?.as_u64()?,
+                image_id: image.get("id")?.as_u64()?,
+                src: image.get("src")?.as_str()?.to_string(),
+                alt: image.get("alt").and_then(Value::as_str).map(str::to_string),
+            })
+        })
+        .collect()
+}
+
+///
+/// Images of the products of a WooCommerce `products` response.
+///
+pub fn woocommerce_images(body: &Value) -> Vec<ProductImage> {
+    let products = body.as_array();
+    products
+        .into_iter()
+        .flatten()
+        .filter_map(|product| {
+            let product_id = product.get("id")?.as_u64()?;
+            let images = product.get("images")?.as_array()?;
+            Some(images.iter().filter_map(move |image| {
+                Some(ProductImage {
+                    product_id,
+                    image_id: image.get("id")?.as_u64()?,
+                    src: image.get("src")?.as_str()?.to_string(),
+                    alt: image.get("alt").and_then(Value::as_str).map(str::to_string),
+                })
+            }))
+        })
+        .flatten()
+        .collect()
+}
+
+///
+/// URL of the next page in a `Link` header, e.g. `<https://...&page_info=abc>; rel="next"`.
+///
+pub fn next_link(link: &str) -> Option<String> {
+    link.split(',').find_map(|part| {
+        let (url, params) = part.split_once(';')?;
+        let is_next = params
+            .split(';')
+            .any(|param| param.trim().replace(' ', "") == "rel=\"next\"");
+        if !is_next {
+            return None;
+        }
+        let url = url.trim().strip_prefix('<')?.strip_suffix('>')?;
+        Some(url.to_string())
+    })
+}
+
+///
+/// Filename of the cutout of image `filename`, e.g. `shirt-cutout.png` for `shirt.jpg` and
+/// `png`.
+///
+pub fn cutout_filename(filename: &str, extension: &str) -> String {
+    let stem = match filename.rsplit_once('.') {
+        Some((stem, _)) if !stem.is_empty() => stem,
+        _ => filename,
+    };
+    format!("{}{}.{}", stem, CUTOUT_SUFFIX, extension)
+}
+
+#[cfg(test)]
+pub mod test {
+    use serde_json::json;
+
+    use super::{
+        cutout_alt, cutout_filename, next_link, pending_images, shopify_images, woocommerce_images,
+        ProductImage, Store,
+    };
+
+    fn store(value: serde_json::Value) -> Store {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    pub fn test_check_store() {
+        let shopify = store(json!({
+            "platform": "shopify",
+            "shop": "acme-shop.myshopify.com",
+            "access_token": "shpat_1",
+        }));
+        assert!(shopify.check().is_ok());
+        assert_eq!(
+            "https://acme-shop.myshopify.com/admin/api/2024-07",
+            shopify.api_base()
+        );
+
+        let woocommerce = store(json!({
+            "platform": "woocommerce",
+            "url": "https://shop.example.com/",
+            "consumer_key": "ck_1",
+            "consumer_secret": "cs_1",
+        }));
+        assert!(woocommerce.check().is_ok());
+        assert_eq!(
+            "https://shop.example.com/wp-json/wc/v3",
+            woocommerce.api_base()
+        );
+
+        for shop in [
+            "example.com",
+            ".myshopify.com",
+            "a.b.myshopify.com",
+            "evil.com/.myshopify.com",
+        ] {
+            let shopify =
+                store(json!({ "platform": "shopify", "shop": shop, "access_token": "1" }));
+            assert!(shopify.check().is_err(), "{}", shop);
+        }
+        let woocommerce = store(json!({
+            "platform": "woocommerce",
+            "url": "http://shop.example.com",
+            "consumer_key": "ck_1",
+            "consumer_secret": "cs_1",
+        }));
+        assert!(woocommerce.check().is_err());
+        assert!(serde_json::from_value::<Store>(json!({ "platform": "magento" })).is_err());
+    }
+
+    #[test]
+    pub fn test_images() {
+        let shopify = json!({
+            "products": [
+                {
+                    "id": 1,
+                    "images": [
+                        { "id": 10, "product_id": 1, "src": "https://cdn.shop/shirt.jpg?v=1" },
+                        {
+                            "id": 11,
+                            "product_id": 1,
+                            "src": "https://cdn.shop/shirt-cutout.png?v=2",
+                            "alt": cutout_alt(10),
+                        },
+                        { "id": 12, "product_id": 1, "src": "https://cdn.shop/back.jpg" },
+                    ]
+                },
+                { "id": 2, "images": [] },
+            ]
+        });
+        let images = shopify_images(&shopify);
+        assert_eq!(3, images.len());
+        assert_eq!(Some("shirt.jpg".to_string()), images[0].filename());
+        assert_eq!(None, images[0].cutout_of());
+        assert_eq!(Some(10), images[1].cutout_of());
+        assert_eq!(None, images[2].cutout_of());
+
+        // Only the image without cutout is left.
+        let pending = pending_images(images);
+        assert_eq!(1, pending.len());
+        assert_eq!(12, pending[0].image_id);
+
+        let woocommerce = json!([
+            {
+                "id": 5,
+                "images": [
+                    { "id": 50, "src": "https://shop.example.com/mug.png", "alt": "" },
+                    {
+                        "id": 51,
+                        "src": "https://shop.example.com/processed.png",
+                        "alt": "Background removed from image 50",
+                    },
+                ]
+            }
+        ]);
+        let images = woocommerce_images(&woocommerce);
+        assert_eq!(
+            ProductImage {
+                product_id: 5,
+                image_id: 50,
+                src: "https://shop.example.com/mug.png".to_string(),
+                alt: Some("".to_string()),
+            },
+            images[0]
+        );
+        assert_eq!(Some(50), images[1].cutout_of());
+    }
+
+    #[test]
+    pub fn test_next_link() {
+        let link = "<https://acme.myshopify.com/products.json?page_info=a>; rel=\"previous\", \
+            <https://acme.myshopify.com/products.json?page_info=b>; rel=\"next\"";
+        assert_eq!(
+            Some("https://acme.myshopify.com/products.json?page_info=b".to_string()),
+            next_link(link)
+        );
+        assert_eq!(
+            None,
+            next_link("<https://a/?page_info=a>; rel=\"previous\"")
+        );
+        assert_eq!(None, next_link(""));
+    }
+
+    #[test]
+    pub fn test_cutout_filename() {
+        assert_eq!("shirt-cutout.png", cutout_filename("shirt.jpg", "png"));
+        assert_eq!("a.b-cutout.webp", cutout_filename("a.b.jpg", "webp"));
+        assert_eq!("image-cutout.png", cutout_filename("image", "png"));
+    }
+}
@@ -0,0 +1,306 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use nix::errno::Errno;
+
+tokio::task_local! {
+    /// Guards of the files uploaded while validating a form. See `guard_uploads`.
+    static UPLOAD_GUARDS: RefCell<Vec<TempFileGuard>>;
+}
+
+/// Prefix of upload temp files. Racoon writes uploaded files with `async-tempfile`, which names
+/// them `atmp_<uuid>` in the temporary directory.
+pub const UPLOAD_TEMP_PREFIX: &str = "atmp_";
+
+///
+/// Whether `name` is the name of an upload temp file.
+///
+pub fn is_upload_temp_file(name: &str) -> bool {
+    match name.strip_prefix(UPLOAD_TEMP_PREFIX) {
+        Some(rest) => !rest.is_empty() && !rest.contains(['/', '\\']),
+        None => false,
+    }
+}
+
+///
+/// Removes an upload temp file when dropped, so every return path of a view cleans it up. Temp
+/// files moved away with `move_file` are already gone, which is ignored.
+///
+pub struct TempFileGuard {
+    path: PathBuf,
+}
+
+impl TempFileGuard {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+}
+
+impl Drop for TempFileGuard {
+    fn drop(&mut self) {
+        if let Err(error) = std::fs::remove_file(&self.path) {
+            if error.kind() != std::io::ErrorKind::NotFound {
+                eprintln!("Failed to remove {:?}. Error: {}", self.path, error);
+            }
+        }
+    }
+}
+
+///
+/// Runs form validation `future` and returns its output with guards of the files passed to
+/// `guard_upload` meanwhile, so uploads are removed when validation fails too.
+///
+pub async fn guard_uploads<F: Future>(future: F) -> (F::Output, Vec<TempFileGuard>) {
+    UPLOAD_GUARDS
+        .scope(RefCell::new(vec![]), async move {
+            let output = future.await;
+            (output, UPLOAD_GUARDS.with(RefCell::take))
+        })
+        .await
+}
+
+///
+/// Guards the uploaded file at `path` until the `guard_uploads` it is validated in returns. Called
+/// by validators of file fields, the first to see the temp path. Outside of `guard_uploads` the
+/// file isn't guarded.
+///
+pub fn guard_upload(path: &Path) {
+    let _ = UPLOAD_GUARDS.try_with(|guards| guards.borrow_mut().push(TempFileGuard::new(path)));
+}
+
+///
+/// Why moving a file with `move_file` failed.
+///
+#[derive(Debug)]
+pub enum MoveError {
+    /// The source file can't be read.
+    Source(io::Error),
+    /// Renaming failed for another reason than the destination being on another filesystem.
+    Rename(io::Error),
+    /// Copying to another filesystem failed.
+    Copy(io::Error),
+    /// Flushing the copy to disk failed.
+    Sync(io::Error),
+    /// The destination can't be read after moving.
+    Verify(io::Error),
+    /// The destination has a different size than the source had.
+    SizeMismatch { expected: u64, actual: u64 },
+}
+
+impl MoveError {
+    ///
+    /// Machine readable failure category, sent as `reason` of error responses.
+    ///
+    pub fn reason(&self) -> &'static str {
+        match self {
+            MoveError::Source(_) => "source_unreadable",
+            MoveError::Rename(_) => "rename_failed",
+            MoveError::Copy(_) => "copy_failed",
+            MoveError::Sync(_) => "sync_failed",
+            MoveError::Verify(_) => "destination_unreadable",
+            MoveError::SizeMismatch { .. } => "size_mismatch",
+        }
+    }
+}
+
+impl fmt::Display for MoveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MoveError::Source(error)
+            | MoveError::Rename(error)
+            | MoveError::Copy(error)
+            | MoveError::Sync(error)
+            | MoveError::Verify(error) => write!(f, "{}: {}", self.reason(), error),
+            MoveError::SizeMismatch { expected, actual } => write!(
+                f,
+                "{}: expected {} bytes, found {}",
+                self.reason(),
+                expected,
+                actual
+            ),
+        }
+    }
+}
+
+///
+/// Moves `source` to `destination` with an atomic rename. Across filesystems, where renaming is
+/// not possible, `source` is copied, flushed to disk and then removed. Either way the size of
+/// `destination` is checked against the size of `source`, and a mismatching `destination` is
+/// removed.
+///
+/// Returns the size of the moved file in bytes.
+///
+pub async fn move_file<S, D>(source: S, destination: D) -> Result<u64, MoveError>
+where
+    S: AsRef<Path>,
+    D: AsRef<Path>,
+{
+    let (source, destination) = (source.as_ref(), destination.as_ref());
+    let expected = tokio::fs::metadata(source)
+        .await
+        .map_err(MoveError::Source)?
+        .len();
+
+    match tokio::fs::rename(source, destination).await {
+        Ok(()) => {}
+        Err(error) if error.raw_os_error() == Some(Errno::EXDEV as i32) => {
+            copy_across_filesystems(source, destination).await?;
+        }
+        Err(error) => return Err(MoveError::Rename(error)),
+    }
+
+    verify_size(destination, expected).await
+}
+
+///
+/// Checks that `destination` has `expected` bytes, removing it otherwise. Returns its size.
+///
+async fn verify_size(destination: &Path, expected: u64) -> Result<u64, MoveError> {
+    let actual = tokio::fs::metadata(destination)
+        .await
+        .map_err(MoveError::Verify)?
+        .len();
+    if actual != expected {
+        let _ = tokio::fs::remove_file(destination).await;
+        return Err(MoveError::SizeMismatch { expected, actual });
+    }
+
+    Ok(actual)
+}
+
+async fn copy_across_filesystems(source: &Path, destination: &Path) -> Result<(), MoveError> {
+    if let Err(error) = tokio::fs::copy(source, destination).await {
+        let _ = tokio::fs::remove_file(destination).await;
+        return Err(MoveError::Copy(error));
+    }
+
+    let synced = match tokio::fs::File::open(destination).await {
+        Ok(file) => file.sync_all().await,
+        Err(error) => Err(error),
+    };
+    if let Err(error) = synced {
+        let _ = tokio::fs::remove_file(destination).await;
+        return Err(MoveError::Sync(error));
+    }
+
+    // The copy is complete, so a source left behind is only cleaned up later.
+    if let Err(error) = tokio::fs::remove_file(source).await {
+        eprintln!("Failed to remove {:?}. Error: {}", source, error);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::path::PathBuf;
+
+    use super::{
+        copy_across_filesystems, guard_upload, guard_uploads, is_upload_temp_file, move_file,
+        verify_size, MoveError, TempFileGuard,
+    };
+
+    fn temp_path() -> PathBuf {
+        std::env::temp_dir().join(format!("bp-temp-utils-{}", uuid::Uuid::new_v4()))
+    }
+
+    #[test]
+    pub fn test_is_upload_temp_file() {
+        assert!(is_upload_temp_file(
+            "atmp_6d3c5a1e-2f4b-4a8e-9c1d-0b7e3f2a5c94"
+        ));
+        assert!(!is_upload_temp_file("atmp_"));
+        assert!(!is_upload_temp_file("bp-user-exports"));
+        assert!(!is_upload_temp_file("systemd-private-atmp_1"));
+    }
+
+    #[test]
+    pub fn test_temp_file_guard() {
+        let path = std::env::temp_dir().join(format!("atmp_{}", uuid::Uuid::new_v4()));
+        std::fs::write(&path, b"upload").unwrap();
+
+        drop(TempFileGuard::new(&path));
+        assert!(!path.exists());
+
+        // Already removed files are ignored.
+        drop(TempFileGuard::new(&path));
+    }
+
+    #[tokio::test]
+    pub async fn test_guard_uploads() {
+        let path = std::env::temp_dir().join(format!("atmp_{}", uuid::Uuid::new_v4()));
+        std::fs::write(&path, b"upload").unwrap();
+
+        // Not guarded outside of validation.
+        guard_upload(&path);
+        assert!(path.exists());
+
+        // Guarded when validation fails after seeing the file.
+        let (validated, guards) = guard_uploads(async {
+            guard_upload(&path);
+            Err::<(), _>("task_group is missing.")
+        })
+        .await;
+        assert!(validated.is_err());
+        assert!(path.exists());
+        drop(guards);
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    pub async fn test_move_file() {
+        let (source, destination) = (temp_path(), temp_path());
+        std::fs::write(&source, b"upload").unwrap();
+
+        assert_eq!(6, move_file(&source, &destination).await.unwrap());
+        assert!(!source.exists());
+        assert_eq!(b"upload".to_vec(), std::fs::read(&destination).unwrap());
+
+        // The source is gone now.
+        let moved = move_file(&source, temp_path()).await;
+        assert!(matches!(moved, Err(MoveError::Source(_))));
+        std::fs::remove_file(&destination).unwrap();
+    }
+
+    #[tokio::test]
+    pub async fn test_copy_across_filesystems() {
+        let (source, destination) = (temp_path(), temp_path());
+        std::fs::write(&source, b"upload").unwrap();
+
+        copy_across_filesystems(&source, &destination)
+            .await
+            .unwrap();
+        assert!(!source.exists());
+        assert_eq!(b"upload".to_vec(), std::fs::read(&destination).unwrap());
+        std::fs::remove_file(&destination).unwrap();
+
+        // A failed copy leaves no partial destination behind.
+        let copied = copy_across_filesystems(&source, &destination).await;
+        assert!(matches!(copied, Err(MoveError::Copy(_))));
+        assert!(!destination.exists());
+    }
+
+    #[tokio::test]
+    pub async fn test_verify_size() {
+        let destination = temp_path();
+        std::fs::write(&destination, b"upl").unwrap();
+        assert_eq!(3, verify_size(&destination, 3).await.unwrap());
+
+        let verified = verify_size(&destination, 6).await;
+        assert!(matches!(
+            verified,
+            Err(MoveError::SizeMismatch {
+                expected: 6,
+                actual: 3
+            })
+        ));
+        assert!(!destination.exists());
+
+        let verified = verify_size(&destination, 6).await;
+        assert!(matches!(verified, Err(MoveError::Verify(_))));
+    }
+}
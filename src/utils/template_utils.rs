@@ -0,0 +1,51 @@
+///
+/// Replaces `{name}` placeholders of `template` with the matching value of `values`. Unknown
+/// placeholders are kept as they are.
+///
+pub fn render(template: &str, values: &[(&str, &str)]) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let value = rest.find('}').and_then(|end| {
+            let name = &rest[1..end];
+            values
+                .iter()
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| (end, *value))
+        });
+
+        match value {
+            Some((end, value)) => {
+                rendered.push_str(value);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                rendered.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    rendered.push_str(rest);
+    rendered
+}
+
+#[cfg(test)]
+pub mod test {
+    #[test]
+    pub fn test_render() {
+        let values = [("total", "3"), ("link", "https://example.com/{total}")];
+
+        assert_eq!(
+            "3 images: https://example.com/{total}",
+            super::render("{total} images: {link}", &values)
+        );
+        assert_eq!("{unknown} 3", super::render("{unknown} {total}", &values));
+        assert_eq!("{ {total", super::render("{ {total", &values));
+        assert_eq!("no placeholders", super::render("no placeholders", &values));
+    }
+}
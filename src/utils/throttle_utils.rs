@@ -0,0 +1,167 @@
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+///
+/// Limits of a background job deleting files, so it can't saturate the disk shared with uploads.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct DeleteThrottle {
+    /// Files removed per second. `None` for no limit.
+    pub max_files_per_sec: Option<u64>,
+    /// Bytes removed per second. `None` for no limit.
+    pub max_bytes_per_sec: Option<u64>,
+    /// Removes files in the idle I/O scheduling class, like `ionice -c 3`. Only on Linux, and only
+    /// effective with an I/O scheduler honoring priorities such as BFQ.
+    pub idle_io: bool,
+}
+
+///
+/// Removes files at the pace of a `DeleteThrottle`. One pacer covers one run of a job.
+///
+pub struct Pacer {
+    throttle: DeleteThrottle,
+    started: Instant,
+    files: u64,
+    bytes: u64,
+}
+
+impl Pacer {
+    pub fn new(throttle: DeleteThrottle) -> Self {
+        Self {
+            throttle,
+            started: Instant::now(),
+            files: 0,
+            bytes: 0,
+        }
+    }
+
+    ///
+    /// Counts a removed file of `size` bytes and returns how long to wait before the next one, so
+    /// the run stays within the limits `elapsed` after it started.
+    ///
+    pub fn record(&mut self, size: u64, elapsed: Duration) -> Duration {
+        self.files += 1;
+        self.bytes += size;
+
+        let budget = |amount: u64, limit: Option<u64>| match limit {
+            Some(limit) if limit > 0 => Duration::from_secs_f64(amount as f64 / limit as f64),
+            _ => Duration::ZERO,
+        };
+        let files_time = budget(self.files, self.throttle.max_files_per_sec);
+        let bytes_time = budget(self.bytes, self.throttle.max_bytes_per_sec);
+        files_time.max(bytes_time).saturating_sub(elapsed)
+    }
+
+    ///
+    /// Removes the file at `path` of `size` bytes, then waits as long as the limits require.
+    ///
+    pub async fn remove_file(&mut self, path: &Path, size: u64) -> std::io::Result<()> {
+        let idle_io = self.throttle.idle_io;
+        let owned_path = path.to_path_buf();
+        tokio::task::spawn_blocking(move || {
+            with_io_priority(idle_io, || std::fs::remove_file(owned_path))
+        })
+        .await
+        .map_err(std::io::Error::other)??;
+
+        let delay = self.record(size, self.started.elapsed());
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+        Ok(())
+    }
+
+    ///
+    /// Removes directory `path` with its contents like `tokio::fs::remove_dir_all`, file by file
+    /// so every file counts against the limits.
+    ///
+    pub async fn remove_dir_all(&mut self, path: &Path) -> std::io::Result<()> {
+        let mut files: Vec<(PathBuf, u64)> = vec![];
+        let mut pending = vec![path.to_path_buf()];
+        while let Some(dir) = pending.pop() {
+            let mut entries = tokio::fs::read_dir(&dir).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let metadata = entry.metadata().await?;
+                if metadata.is_dir() {
+                    pending.push(entry.path());
+                } else {
+                    files.push((entry.path(), metadata.len()));
+                }
+            }
+        }
+
+        for (file, size) in files {
+            self.remove_file(&file, size).await?;
+        }
+        // Only empty directories are left.
+        tokio::fs::remove_dir_all(path).await
+    }
+}
+
+///
+/// Runs blocking `work` in the idle I/O scheduling class when `idle_io` is set, restoring the
+/// priority of the thread afterwards as it belongs to the shared blocking pool.
+///
+#[cfg(target_os = "linux")]
+fn with_io_priority<T>(idle_io: bool, work: impl FnOnce() -> T) -> T {
+    use nix::libc;
+
+    // From linux/ioprio.h. `0` as who selects the calling thread.
+    const IOPRIO_WHO_PROCESS: libc::c_long = 1;
+    const IOPRIO_CLASS_IDLE: libc::c_long = 3;
+    const IOPRIO_CLASS_SHIFT: libc::c_long = 13;
+
+    if !idle_io {
+        return work();
+    }
+
+    // SAFETY: ioprio_get and ioprio_set only read their integer arguments.
+    let previous = unsafe { libc::syscall(libc::SYS_ioprio_get, IOPRIO_WHO_PROCESS, 0) };
+    let lowered = unsafe {
+        libc::syscall(
+            libc::SYS_ioprio_set,
+            IOPRIO_WHO_PROCESS,
+            0,
+            IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+        )
+    } == 0;
+
+    let output = work();
+    if lowered && previous >= 0 {
+        unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, previous) };
+    }
+    output
+}
+
+#[cfg(not(target_os = "linux"))]
+fn with_io_priority<T>(_idle_io: bool, work: impl FnOnce() -> T) -> T {
+    work()
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::time::Duration;
+
+    use super::{DeleteThrottle, Pacer};
+
+    #[test]
+    pub fn test_pacer() {
+        let mut unlimited = Pacer::new(DeleteThrottle::default());
+        assert_eq!(Duration::ZERO, unlimited.record(1 << 30, Duration::ZERO));
+
+        let mut pacer = Pacer::new(DeleteThrottle {
+            max_files_per_sec: Some(10),
+            max_bytes_per_sec: Some(1000),
+            idle_io: false,
+        });
+        // 1 file takes 100 ms of the file limit, 50 bytes 50 ms of the byte limit.
+        assert_eq!(Duration::from_millis(100), pacer.record(50, Duration::ZERO));
+        // 2 files take 200 ms, 2050 bytes 2050 ms.
+        assert_eq!(
+            Duration::from_millis(1950),
+            pacer.record(2000, Duration::from_millis(100))
+        );
+        // Time spent removing counts against the wait.
+        assert_eq!(Duration::ZERO, pacer.record(0, Duration::from_secs(5)));
+    }
+}
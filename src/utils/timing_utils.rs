@@ -0,0 +1,316 @@
+use std::fmt::Write;
+use std::time::Duration;
+
+use serde_json::{Map, Value};
+
+/// Upper bounds in seconds of the request latency buckets.
+pub const LATENCY_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Route label of requests matching no registered route, so unknown paths can't add series.
+pub const UNMATCHED_ROUTE: &str = "unmatched";
+
+/// Path params whose values identify users or grant access, masked in logs.
+const SENSITIVE_PARAMS: [&str; 4] = ["user_identifier", "token", "signature", "secret"];
+
+/// Longest param value written to logs, in characters.
+const MAX_PARAM_LENGTH: usize = 64;
+
+///
+/// Prometheus histogram with fixed buckets.
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct Histogram {
+    bounds: &'static [f64],
+    /// Observations per bucket, not cumulative. The last one counts values above all bounds.
+    counts: Vec<u64>,
+    sum: f64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            counts: vec![0; bounds.len() + 1],
+            sum: 0.0,
+        }
+    }
+
+    pub fn observe(&mut self, value: f64) {
+        let index = self
+            .bounds
+            .iter()
+            .position(|bound| value <= *bound)
+            .unwrap_or(self.bounds.len());
+        self.counts[index] += 1;
+        self.sum += value;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    ///
+    /// Appends the cumulative buckets, sum and count in Prometheus text format. `labels` are
+    /// without braces, e.g. `route="/v1/status/",method="GET"`.
+    ///
+    pub fn render(&self, output: &mut String, name: &str, labels: &str) {
+        let separator = if labels.is_empty() { "" } else { "," };
+        let mut cumulative = 0;
+        for (bound, count) in self.bounds.iter().zip(&self.counts) {
+            cumulative += count;
+            let _ = writeln!(
+                output,
+                "{}_bucket{{{}{}le=\"{}\"}} {}",
+                name, labels, separator, bound, cumulative
+            );
+        }
+        let _ = writeln!(
+            output,
+            "{}_bucket{{{}{}le=\"+Inf\"}} {}",
+            name,
+            labels,
+            separator,
+            self.count()
+        );
+        let _ = writeln!(output, "{}_sum{{{}}} {}", name, labels, self.sum);
+        let _ = writeln!(output, "{}_count{{{}}} {}", name, labels, self.count());
+    }
+}
+
+///
+/// First of `patterns` matching `path`, with the values of its params. Patterns are registered
+/// URLs like `/v1/remove-background/details/{task_id}/`.
+///
+pub fn match_route<'a>(
+    patterns: &[&'a str],
+    path: &str,
+) -> Option<(&'a str, Vec<(String, String)>)> {
+    let segments: Vec<&str> = path.split('/').collect();
+    patterns.iter().find_map(|pattern| {
+        let pattern_segments: Vec<&str> = pattern.split('/').collect();
+        if pattern_segments.len() != segments.len() {
+            return None;
+        }
+
+        let mut params = vec![];
+        for (pattern_segment, segment) in pattern_segments.iter().zip(&segments) {
+            match pattern_segment
+                .strip_prefix('{')
+                .and_then(|name| name.strip_suffix('}'))
+            {
+                Some(name) if !segment.is_empty() => {
+                    params.push((name.to_string(), segment.to_string()))
+                }
+                Some(_) => return None,
+                None if pattern_segment == segment => {}
+                None => return None,
+            }
+        }
+        Some((*pattern, params))
+    })
+}
+
+///
+/// Params as `name=value` for logs, with sensitive values masked and long ones truncated.
+///
+pub fn sanitize_params(params: &[(String, String)]) -> String {
+    let params: Vec<String> = params
+        .iter()
+        .map(|(name, value)| {
+            let is_sensitive = SENSITIVE_PARAMS
+                .iter()
+                .any(|sensitive| name.contains(sensitive));
+            if is_sensitive {
+                format!("{}=***", name)
+            } else if value.chars().count() > MAX_PARAM_LENGTH {
+                let value: String = value.chars().take(MAX_PARAM_LENGTH).collect();
+                format!("{}={}...", name, value)
+            } else {
+                format!("{}={}", name, value)
+            }
+        })
+        .collect();
+    params.join(", ")
+}
+
+///
+/// Stage of a task whose duration is stored in column `timings`, in milliseconds by stage name.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskStage {
+    /// Reading and validating the upload form, including the uploaded file.
+    UploadParse,
+    /// Generating the preview of the uploaded image.
+    PreviewOriginal,
+    /// Generating the preview of the transparent output.
+    PreviewProcessed,
+    /// From the request to process the task, or its ingestion, until it was sent to a BP server.
+    QueueWait,
+    /// From sending the task to a BP server until its result was received.
+    BpRoundTrip,
+    /// Storing the outputs received from the BP server.
+    Save,
+    /// Delivering the result to websocket clients and webhooks.
+    Broadcast,
+}
+
+impl TaskStage {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TaskStage::UploadParse => "upload_parse",
+            TaskStage::PreviewOriginal => "preview_original",
+            TaskStage::PreviewProcessed => "preview_processed",
+            TaskStage::QueueWait => "queue_wait",
+            TaskStage::BpRoundTrip => "bp_round_trip",
+            TaskStage::Save => "save",
+            TaskStage::Broadcast => "broadcast",
+        }
+    }
+}
+
+///
+/// Value of column `timings` holding `stages`. A later duration of the same stage wins.
+///
+pub fn timings_json(stages: &[(TaskStage, Duration)]) -> Value {
+    let mut timings = Map::new();
+    for (stage, duration) in stages {
+        timings.insert(
+            stage.as_str().to_string(),
+            Value::from(duration.as_millis() as u64),
+        );
+    }
+    Value::Object(timings)
+}
+
+///
+/// Exponential moving average in milliseconds after adding `sample`, giving it `smoothing` of the
+/// weight. `average_millis` of `0` means no sample yet, so the first sample becomes the average.
+/// Never `0` afterwards.
+///
+pub fn rolling_average_millis(average_millis: u64, sample: Duration, smoothing: f64) -> u64 {
+    let sample = sample.as_millis() as f64;
+    let updated = match average_millis {
+        0 => sample,
+        average => average as f64 * (1.0 - smoothing) + sample * smoothing,
+    };
+    (updated as u64).max(1)
+}
+
+///
+/// Estimated seconds, rounded up, until a task processes when `queue_depth` tasks taking
+/// `average` each, including itself, are ahead of it.
+///
+pub fn estimate_seconds(average: Duration, queue_depth: usize) -> u64 {
+    let millis = average.as_millis() as u64 * queue_depth.max(1) as u64;
+    millis.div_ceil(1000)
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::time::Duration;
+
+    use serde_json::json;
+
+    use super::{
+        estimate_seconds, match_route, rolling_average_millis, sanitize_params, timings_json,
+        Histogram, TaskStage,
+    };
+
+    #[test]
+    pub fn test_histogram() {
+        let mut histogram = Histogram::new(&[0.1, 1.0]);
+        histogram.observe(0.05);
+        histogram.observe(0.1);
+        histogram.observe(0.5);
+        histogram.observe(3.0);
+
+        let mut output = String::new();
+        histogram.render(&mut output, "latency_seconds", "route=\"/v1/status/\"");
+        assert_eq!(
+            "latency_seconds_bucket{route=\"/v1/status/\",le=\"0.1\"} 2\n\
+             latency_seconds_bucket{route=\"/v1/status/\",le=\"1\"} 3\n\
+             latency_seconds_bucket{route=\"/v1/status/\",le=\"+Inf\"} 4\n\
+             latency_seconds_sum{route=\"/v1/status/\"} 3.65\n\
+             latency_seconds_count{route=\"/v1/status/\"} 4\n",
+            output
+        );
+    }
+
+    #[test]
+    pub fn test_match_route() {
+        let patterns = [
+            "/v1/status/",
+            "/v1/remove-background/revisions/{task_id}/",
+            "/v1/remove-background/revisions/{task_id}/{revision}/",
+        ];
+
+        assert_eq!(
+            Some(("/v1/status/", vec![])),
+            match_route(&patterns, "/v1/status/")
+        );
+        assert_eq!(
+            Some((
+                "/v1/remove-background/revisions/{task_id}/{revision}/",
+                vec![
+                    ("task_id".to_string(), "abc".to_string()),
+                    ("revision".to_string(), "2".to_string())
+                ]
+            )),
+            match_route(&patterns, "/v1/remove-background/revisions/abc/2/")
+        );
+        assert_eq!(
+            None,
+            match_route(&patterns, "/v1/remove-background/revisions//")
+        );
+        assert_eq!(None, match_route(&patterns, "/v1/status"));
+        assert_eq!(None, match_route(&patterns, "/v1/unknown/"));
+
+        let params = [
+            (
+                "user_identifier".to_string(),
+                "someone@example.com".to_string(),
+            ),
+            ("task_id".to_string(), "a".repeat(70)),
+        ];
+        assert_eq!(
+            format!("user_identifier=***, task_id={}...", "a".repeat(64)),
+            sanitize_params(&params)
+        );
+    }
+
+    #[test]
+    pub fn test_timings_json() {
+        assert_eq!(json!({}), timings_json(&[]));
+        assert_eq!(
+            json!({"queue_wait": 1500, "save": 2}),
+            timings_json(&[
+                (TaskStage::QueueWait, Duration::from_secs(1)),
+                (TaskStage::Save, Duration::from_micros(2900)),
+                (TaskStage::QueueWait, Duration::from_millis(1500)),
+            ])
+        );
+    }
+
+    #[test]
+    pub fn test_rolling_average_millis() {
+        assert_eq!(1000, rolling_average_millis(0, Duration::from_secs(1), 0.2));
+        assert_eq!(
+            1200,
+            rolling_average_millis(1000, Duration::from_secs(2), 0.2)
+        );
+        assert_eq!(800, rolling_average_millis(1000, Duration::ZERO, 0.2));
+        // Instant results don't read as no sample.
+        assert_eq!(1, rolling_average_millis(0, Duration::from_micros(10), 0.2));
+    }
+
+    #[test]
+    pub fn test_estimate_seconds() {
+        let average = Duration::from_millis(1500);
+        assert_eq!(2, estimate_seconds(average, 0));
+        assert_eq!(2, estimate_seconds(average, 1));
+        assert_eq!(5, estimate_seconds(average, 3));
+        assert_eq!(0, estimate_seconds(Duration::ZERO, 3));
+    }
+}
@@ -0,0 +1,379 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use uuid::Uuid;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Purposes keep a signature made for one use from being valid for another.
+const TASK_TOKEN_PURPOSE: &[u8] = b"task-token:";
+const USER_IDENTIFIER_PURPOSE: &[u8] = b"user-identifier:";
+const USER_EXPORT_PURPOSE: &[u8] = b"user-export:";
+const PLUGIN_TOKEN_PURPOSE: &[u8] = b"plugin-token:";
+
+/// Key id of a keyring made of the legacy `TASK_TOKEN_SECRET`.
+pub const LEGACY_KEY_ID: &str = "0";
+
+fn hmac(secret: &[u8], parts: &[&[u8]]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC accepts any key length");
+    for part in parts {
+        mac.update(part);
+    }
+    mac
+}
+
+fn sign_with(kid: &str, secret: &[u8], parts: &[&[u8]]) -> String {
+    let signature = hmac(secret, parts).finalize().into_bytes();
+    format!("{}.{}", kid, URL_SAFE_NO_PAD.encode(signature))
+}
+
+///
+/// Signing keys identified by a key id. The first key signs; every key verifies, so a new key
+/// can be put first while signatures of the previous keys stay valid until they are removed.
+/// Signatures are `<kid>.<URL safe base64 HMAC-SHA256>`.
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct Keyring {
+    keys: Vec<(String, Vec<u8>)>,
+}
+
+impl Keyring {
+    ///
+    /// Parses comma separated `<kid>:<secret>` pairs, e.g. `2024b:s3cret,2024a:old`. Key ids may
+    /// not contain `.`, `:` or `,` and must be unique.
+    ///
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let mut keys: Vec<(String, Vec<u8>)> = vec![];
+        for pair in value
+            .split(',')
+            .map(str::trim)
+            .filter(|pair| !pair.is_empty())
+        {
+            let (kid, secret) = match pair.split_once(':') {
+                Some((kid, secret)) if !kid.is_empty() && !secret.is_empty() => (kid, secret),
+                _ => return Err(format!("Expected <kid>:<secret>, found {:?}.", pair)),
+            };
+
+            if kid.contains('.') {
+                return Err(format!("Key id {:?} contains '.'.", kid));
+            }
+            if keys.iter().any(|(existing, _)| existing == kid) {
+                return Err(format!("Key id {:?} is used twice.", kid));
+            }
+            keys.push((kid.to_string(), secret.as_bytes().to_vec()));
+        }
+
+        if keys.is_empty() {
+            return Err("Keyring has no keys.".to_string());
+        }
+        Ok(Self { keys })
+    }
+
+    ///
+    /// Keyring of a single secret, as configured before keyrings existed.
+    ///
+    pub fn single(secret: &str) -> Self {
+        Self {
+            keys: vec![(LEGACY_KEY_ID.to_string(), secret.as_bytes().to_vec())],
+        }
+    }
+
+    ///
+    /// Whether the keyring has a key with id `kid`.
+    ///
+    pub fn contains(&self, kid: &str) -> bool {
+        self.keys.iter().any(|(id, _)| id == kid)
+    }
+
+    fn sign(&self, parts: &[&[u8]]) -> String {
+        let (kid, secret) = &self.keys[0];
+        sign_with(kid, secret, parts)
+    }
+
+    fn verify(&self, parts: &[&[u8]], signature: &str) -> bool {
+        let (kid, signature) = match signature.split_once('.') {
+            Some(split) => split,
+            None => return false,
+        };
+        let secret = match self.keys.iter().find(|(id, _)| id == kid) {
+            Some((_, secret)) => secret,
+            None => return false,
+        };
+
+        match URL_SAFE_NO_PAD.decode(signature) {
+            Ok(signature) => hmac(secret, parts).verify_slice(&signature).is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    ///
+    /// Returns access token of the task with `key`. Lets a task be shared without revealing its
+    /// task group.
+    ///
+    pub fn sign_task_key(&self, key: &Uuid) -> String {
+        self.sign(&[TASK_TOKEN_PURPOSE, key.as_bytes()])
+    }
+
+    ///
+    /// Checks `token` against the task `key` in constant time. Tokens issued before keyrings,
+    /// which have no key id, are checked against every key.
+    ///
+    pub fn verify_task_key(&self, key: &Uuid, token: &str) -> bool {
+        if !token.contains('.') {
+            return self
+                .keys
+                .iter()
+                .any(|(_, secret)| verify_legacy_task_key(secret, key, token));
+        }
+
+        self.verify(&[TASK_TOKEN_PURPOSE, key.as_bytes()], token)
+    }
+
+    ///
+    /// Pseudonymizes a user identifier sent by clients before it is stored. The same identifier
+    /// maps to the same value as long as the signing key doesn't change.
+    ///
+    pub fn hash_user_identifier(&self, user_identifier: &str) -> String {
+        self.sign(&[USER_IDENTIFIER_PURPOSE, user_identifier.as_bytes()])
+    }
+
+    ///
+    /// Pseudonyms of a user identifier under every key, as stored before and after rotations.
+    /// Used to find every task of a user.
+    ///
+    pub fn user_identifier_hashes(&self, user_identifier: &str) -> Vec<String> {
+        self.keys
+            .iter()
+            .map(|(kid, secret)| {
+                sign_with(
+                    kid,
+                    secret,
+                    &[USER_IDENTIFIER_PURPOSE, user_identifier.as_bytes()],
+                )
+            })
+            .collect()
+    }
+
+    ///
+    /// Signature of the temporary download link of user export `export_id`, valid until unix
+    /// timestamp `expires`.
+    ///
+    pub fn sign_user_export(&self, export_id: &Uuid, expires: i64) -> String {
+        let expires = expires.to_string();
+        self.sign(&[
+            USER_EXPORT_PURPOSE,
+            export_id.as_bytes(),
+            expires.as_bytes(),
+        ])
+    }
+
+    pub fn verify_user_export(&self, export_id: &Uuid, expires: i64, signature: &str) -> bool {
+        let expires = expires.to_string();
+        self.verify(
+            &[
+                USER_EXPORT_PURPOSE,
+                export_id.as_bytes(),
+                expires.as_bytes(),
+            ],
+            signature,
+        )
+    }
+
+    ///
+    /// Returns short-lived token of design tool plugins acting for upload signing key id
+    /// `key_id`, valid until unix timestamp `expires`: `<key id>:<expires>:<signature>`.
+    ///
+    pub fn sign_plugin_token(&self, key_id: &str, expires: i64) -> String {
+        let expires = expires.to_string();
+        let signature = self.sign(&[
+            PLUGIN_TOKEN_PURPOSE,
+            key_id.as_bytes(),
+            b":",
+            expires.as_bytes(),
+        ]);
+        format!("{}:{}:{}", key_id, expires, signature)
+    }
+
+    ///
+    /// Returns the key id of plugin `token` when its signature is valid and it is not expired at
+    /// unix timestamp `now`.
+    ///
+    pub fn verify_plugin_token(&self, token: &str, now: i64) -> Option<String> {
+        let mut parts = token.splitn(3, ':');
+        let (key_id, expires, signature) = (parts.next()?, parts.next()?, parts.next()?);
+        if expires.parse::<i64>().ok()? <= now {
+            return None;
+        }
+
+        let parts: [&[u8]; 4] = [
+            PLUGIN_TOKEN_PURPOSE,
+            key_id.as_bytes(),
+            b":",
+            expires.as_bytes(),
+        ];
+        self.verify(&parts, signature).then(|| key_id.to_string())
+    }
+
+    ///
+    /// Checks the signature of a signed upload request. `signed_string` starts with its own
+    /// version line, so it is signed as is and clients need no purpose prefix.
+    ///
+    pub fn verify_upload_request(&self, signed_string: &str, signature: &str) -> bool {
+        self.verify(&[signed_string.as_bytes()], signature)
+    }
+}
+
+///
+/// Checks a token without key id: the URL safe base64 HMAC-SHA256 of the task key alone.
+///
+fn verify_legacy_task_key(secret: &[u8], key: &Uuid, token: &str) -> bool {
+    match URL_SAFE_NO_PAD.decode(token) {
+        Ok(signature) => hmac(secret, &[key.as_bytes()])
+            .verify_slice(&signature)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use base64::Engine;
+    use hmac::Mac;
+    use uuid::Uuid;
+
+    use super::{hmac, Keyring};
+
+    #[test]
+    pub fn test_task_token() {
+        let keyring = Keyring::parse("new:secret, old:old secret").unwrap();
+        let key = Uuid::new_v4();
+        let token = keyring.sign_task_key(&key);
+
+        assert!(token.starts_with("new."));
+        assert!(keyring.verify_task_key(&key, &token));
+        assert!(!keyring.verify_task_key(&Uuid::new_v4(), &token));
+        assert!(!keyring.verify_task_key(&key, "new.not a token"));
+        assert!(!keyring.verify_task_key(&key, ""));
+
+        // Tokens of a key still in the keyring stay valid after rotation.
+        let old_token = Keyring::parse("old:old secret")
+            .unwrap()
+            .sign_task_key(&key);
+        assert!(keyring.verify_task_key(&key, &old_token));
+        assert!(!Keyring::parse("new:secret")
+            .unwrap()
+            .verify_task_key(&key, &old_token));
+
+        // Key ids can't be swapped to check a signature with another key.
+        let forged = old_token.replacen("old.", "new.", 1);
+        assert!(!keyring.verify_task_key(&key, &forged));
+
+        // Tokens without key id.
+        let signature = hmac(b"old secret", &[key.as_bytes()])
+            .finalize()
+            .into_bytes();
+        let legacy_token = URL_SAFE_NO_PAD.encode(signature);
+        assert!(keyring.verify_task_key(&key, &legacy_token));
+    }
+
+    #[test]
+    pub fn test_user_identifier() {
+        let keyring = Keyring::parse("b:secret,a:old secret").unwrap();
+        let hashed = keyring.hash_user_identifier("user@example.com");
+
+        assert!(hashed.starts_with("b."));
+        assert!(!hashed.contains("user@example.com"));
+        assert_eq!(hashed, keyring.hash_user_identifier("user@example.com"));
+        assert_ne!(hashed, keyring.hash_user_identifier("other@example.com"));
+
+        // Pseudonyms made before the rotation are found too.
+        let old_hashed = Keyring::parse("a:old secret")
+            .unwrap()
+            .hash_user_identifier("user@example.com");
+        assert_eq!(
+            vec![hashed, old_hashed],
+            keyring.user_identifier_hashes("user@example.com")
+        );
+
+        // User identifier pseudonyms are not task tokens.
+        let key = Uuid::new_v4();
+        assert!(!keyring.verify_task_key(&key, &keyring.hash_user_identifier(&key.to_string())));
+    }
+
+    #[test]
+    pub fn test_user_export() {
+        let keyring = Keyring::parse("b:secret,a:old secret").unwrap();
+        let export_id = Uuid::new_v4();
+        let signature = keyring.sign_user_export(&export_id, 1700000000);
+
+        assert!(keyring.verify_user_export(&export_id, 1700000000, &signature));
+        assert!(!keyring.verify_user_export(&export_id, 1700000001, &signature));
+        assert!(!keyring.verify_user_export(&Uuid::new_v4(), 1700000000, &signature));
+
+        // Export links are not task tokens.
+        assert!(!keyring.verify_task_key(&export_id, &signature));
+    }
+
+    #[test]
+    pub fn test_plugin_token() {
+        let keyring = Keyring::parse("b:secret,a:old secret").unwrap();
+        let token = keyring.sign_plugin_token("acme", 1700000900);
+
+        assert!(token.starts_with("acme:1700000900:b."));
+        assert_eq!(
+            Some("acme".to_string()),
+            keyring.verify_plugin_token(&token, 1700000000)
+        );
+        assert_eq!(None, keyring.verify_plugin_token(&token, 1700000900));
+
+        // Neither the key id nor the expiry can be changed.
+        let other = token.replacen("acme:", "other:", 1);
+        assert_eq!(None, keyring.verify_plugin_token(&other, 1700000000));
+        let extended = token.replacen(":1700000900:", ":1800000000:", 1);
+        assert_eq!(None, keyring.verify_plugin_token(&extended, 1700000000));
+        assert_eq!(None, keyring.verify_plugin_token("acme", 1700000000));
+
+        // Plugin tokens are not task tokens.
+        let key = Uuid::new_v4();
+        let signature = token.rsplit(':').next().unwrap();
+        assert!(!keyring.verify_task_key(&key, signature));
+    }
+
+    #[test]
+    pub fn test_upload_request() {
+        let keyring = Keyring::parse("customer:secret").unwrap();
+        let signed_string = "bp-upload-v1\nPOST\n/v1/bp/u/\n1700000000\nn1\nabc";
+
+        let signature = hmac(b"secret", &[signed_string.as_bytes()])
+            .finalize()
+            .into_bytes();
+        let signature = format!("customer.{}", URL_SAFE_NO_PAD.encode(signature));
+
+        assert!(keyring.verify_upload_request(signed_string, &signature));
+        assert!(!keyring.verify_upload_request("bp-upload-v1\nPOST", &signature));
+        assert!(
+            !keyring.verify_upload_request(signed_string, &signature.replace("customer.", "x."))
+        );
+    }
+
+    #[test]
+    pub fn test_parse() {
+        let key = Uuid::new_v4();
+        let keyring = Keyring::parse("b:secret,a:old:secret").unwrap();
+        assert!(keyring.sign_task_key(&key).starts_with("b."));
+        assert!(Keyring::single("secret")
+            .sign_task_key(&key)
+            .starts_with("0."));
+
+        assert!(keyring.contains("a"));
+        assert!(!keyring.contains("c"));
+
+        assert!(Keyring::parse("").is_err());
+        assert!(Keyring::parse("secret").is_err());
+        assert!(Keyring::parse("a:").is_err());
+        assert!(Keyring::parse("a.b:secret").is_err());
+        assert!(Keyring::parse("a:one,a:two").is_err());
+    }
+}
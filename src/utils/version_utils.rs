@@ -0,0 +1,171 @@
+use serde_json::{Map, Value};
+
+///
+/// Versions of the public API. Handlers are shared between versions; only the serialization
+/// differs, so breaking changes of the response shape ship under a new version while older
+/// clients keep the legacy field names.
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ApiVersion {
+    V1,
+    V2,
+}
+
+impl ApiVersion {
+    ///
+    /// Url path prefix, e.g. `/v1`.
+    ///
+    pub fn prefix(&self) -> &'static str {
+        match self {
+            ApiVersion::V1 => "/v1",
+            ApiVersion::V2 => "/v2",
+        }
+    }
+
+    ///
+    /// Converts a task serialized by `BackgroundRemoverTask::serialize` or `serialize_full` to
+    /// the shape of this version. The serialization of the models is the v1 shape.
+    ///
+    pub fn serialize_task(&self, serialized: Value) -> Value {
+        match self {
+            ApiVersion::V1 => serialized,
+            ApiVersion::V2 => task_v2(serialized),
+        }
+    }
+
+    ///
+    /// Name of the task id field in upload responses.
+    ///
+    pub fn task_id_field(&self) -> &'static str {
+        match self {
+            ApiVersion::V1 => "key",
+            ApiVersion::V2 => "id",
+        }
+    }
+}
+
+///
+/// Single status of a task replacing `processing` and `result_status` of v1.
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TaskStatus {
+    Pending,
+    Processing,
+    Succeeded,
+    Failed,
+    TimedOut,
+}
+
+impl TaskStatus {
+    pub fn from_v1(processing: Option<bool>, result_status: Option<&str>) -> Self {
+        match result_status {
+            Some("success") => TaskStatus::Succeeded,
+            Some("failed") => TaskStatus::Failed,
+            Some("timeout") => TaskStatus::TimedOut,
+            _ if processing.unwrap_or(false) => TaskStatus::Processing,
+            _ => TaskStatus::Pending,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TaskStatus::Pending => "pending",
+            TaskStatus::Processing => "processing",
+            TaskStatus::Succeeded => "succeeded",
+            TaskStatus::Failed => "failed",
+            TaskStatus::TimedOut => "timed_out",
+        }
+    }
+}
+
+///
+/// v2 task shape: `key` is renamed to `id`, `processing` and `result_status` are replaced by
+/// `status`, and media urls are grouped under `images`. Other fields are kept as they are.
+///
+fn task_v2(serialized: Value) -> Value {
+    let mut map = match serialized {
+        Value::Object(map) => map,
+        other => return other,
+    };
+
+    let processing = map.remove("processing").and_then(|value| value.as_bool());
+    let result_status = map.remove("result_status");
+    let status = TaskStatus::from_v1(processing, result_status.as_ref().and_then(Value::as_str));
+
+    // (v1 field, v2 field in `images`)
+    const IMAGE_FIELDS: [(&str, &str); 5] = [
+        ("original_image", "original"),
+        ("preview_original_image", "preview_original"),
+        ("processed_image", "processed"),
+        ("preview_processed_image", "preview_processed"),
+        ("mask_image", "mask"),
+    ];
+    let mut images = Map::new();
+    for (v1_field, v2_field) in IMAGE_FIELDS {
+        if let Some(value) = map.remove(v1_field) {
+            images.insert(v2_field.to_string(), value);
+        }
+    }
+
+    if let Some(key) = map.remove("key") {
+        map.insert("id".to_string(), key);
+    }
+    map.insert("status".to_string(), Value::from(status.as_str()));
+    map.insert("images".to_string(), Value::Object(images));
+    Value::Object(map)
+}
+
+#[cfg(test)]
+pub mod test {
+    use serde_json::json;
+
+    use super::{ApiVersion, TaskStatus};
+
+    #[test]
+    pub fn test_serialize_task() {
+        let v1 = json!({
+            "key": "b5e4a6c0-4a8e-4c52-9d4f-1f6b0f0f6a11",
+            "original_image": "https://example.com/media/original.jpg",
+            "mask_image": null,
+            "processing": true,
+            "result_status": null,
+            "revision": 0,
+        });
+
+        assert_eq!(v1, ApiVersion::V1.serialize_task(v1.clone()));
+        assert_eq!(
+            json!({
+                "id": "b5e4a6c0-4a8e-4c52-9d4f-1f6b0f0f6a11",
+                "status": "processing",
+                "images": {
+                    "original": "https://example.com/media/original.jpg",
+                    "mask": null,
+                },
+                "revision": 0,
+            }),
+            ApiVersion::V2.serialize_task(v1)
+        );
+    }
+
+    #[test]
+    pub fn test_task_status() {
+        assert_eq!(TaskStatus::Pending, TaskStatus::from_v1(None, None));
+        assert_eq!(TaskStatus::Pending, TaskStatus::from_v1(Some(false), None));
+        assert_eq!(
+            TaskStatus::Processing,
+            TaskStatus::from_v1(Some(true), None)
+        );
+        assert_eq!(
+            TaskStatus::Succeeded,
+            TaskStatus::from_v1(Some(false), Some("success"))
+        );
+        assert_eq!(
+            TaskStatus::Failed,
+            TaskStatus::from_v1(None, Some("failed"))
+        );
+        assert_eq!(
+            TaskStatus::TimedOut,
+            TaskStatus::from_v1(None, Some("timeout"))
+        );
+    }
+}